@@ -11,4 +11,19 @@ fn main() {
         .generate()
         .expect("Unable to generate bindings")
         .write_to_file("lib.h");
+
+    #[cfg(feature = "grpc")]
+    {
+        env::set_var(
+            "PROTOC",
+            protoc_bin_vendored::protoc_bin_path().expect("No vendored protoc for this platform"),
+        );
+        // Features of a namespace in the order of their names, so VW lines don't depend on hashing.
+        // Without the connect() of clients, which needs the prelude of edition 2021
+        tonic_build::configure()
+            .btree_map(["."])
+            .build_transport(false)
+            .compile_protos(&["proto/serving.proto"], &["proto"])
+            .expect("Unable to generate the gRPC server");
+    }
 }
@@ -0,0 +1,312 @@
+use std::error::Error;
+use std::time::Instant;
+
+use crate::block_ffm;
+use crate::block_neural;
+use crate::model_instance::ModelInstance;
+
+// Kernel variants of the blocks. Which one is fastest depends on the machine and the shape of
+// the model, so --autotune measures them at startup. The choice is not a property of the model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FFMKernel {
+    Sse, // portable 4 lanes, NEON on aarch64
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "x86_64")]
+    Avx512,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NeuronKernel {
+    Blas,
+    Scalar,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KernelChoice {
+    pub ffm: FFMKernel,
+    pub neuron: NeuronKernel,
+}
+
+impl Default for KernelChoice {
+    fn default() -> KernelChoice {
+        KernelChoice {
+            ffm: FFMKernel::Sse,
+            neuron: NeuronKernel::Blas,
+        }
+    }
+}
+
+// Each benchmark runs a few rounds of about this many multiply-adds, the best round counts
+const BENCHMARK_FLOPS: usize = 2_000_000;
+const BENCHMARK_ROUNDS: usize = 5;
+// Neuron layer width when the model does not set one, same as in regressor
+const DEFAULT_NN_WIDTH: usize = 20;
+
+// AVX-512 is only used when asked for with --avx512: it sums FFM in another order, so
+// predictions differ in the last bits from those of the models trained without it, and some
+// cpus lower their clock while running it
+#[cfg(target_arch = "x86_64")]
+pub fn ffm_kernels_available(avx512: bool) -> Vec<FFMKernel> {
+    let mut kernels = vec![FFMKernel::Sse];
+    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        kernels.push(FFMKernel::Avx2);
+    }
+    if avx512 && is_x86_feature_detected!("avx512f") {
+        kernels.push(FFMKernel::Avx512);
+    }
+    kernels
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn ffm_kernels_available(_avx512: bool) -> Vec<FFMKernel> {
+    vec![FFMKernel::Sse]
+}
+
+// Kernels of --avx512 and --autotune, the defaults otherwise
+pub fn kernels_from_cmdline(
+    cl: &clap::ArgMatches,
+    mi: &ModelInstance,
+) -> Result<KernelChoice, Box<dyn Error>> {
+    let avx512 = cl.is_present("avx512");
+    if cl.is_present("autotune") {
+        return Ok(autotune(mi, avx512));
+    }
+    let mut choice = KernelChoice::default();
+    if avx512 {
+        #[cfg(target_arch = "x86_64")]
+        if ffm_kernels_available(true).contains(&FFMKernel::Avx512) {
+            choice.ffm = FFMKernel::Avx512;
+        }
+        if choice.ffm == FFMKernel::Sse {
+            return Err("--avx512 needs a cpu with avx512f")?;
+        }
+    }
+    Ok(choice)
+}
+
+pub fn neuron_kernels_available() -> Vec<NeuronKernel> {
+    vec![NeuronKernel::Blas, NeuronKernel::Scalar]
+}
+
+// Deterministic inputs, the values only need to be ordinary floats
+fn benchmark_data(len: usize) -> Vec<f32> {
+    (0..len).map(|i| ((i % 97) as f32 - 48.0) * 0.01).collect()
+}
+
+// Best time of a round of iterations, in nanoseconds per iteration
+fn time_ns(iterations: usize, mut f: impl FnMut()) -> f64 {
+    let mut best = f64::INFINITY;
+    for _ in 0..BENCHMARK_ROUNDS {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            f();
+        }
+        best = best.min(start.elapsed().as_nanos() as f64 / iterations as f64);
+    }
+    best
+}
+
+fn fastest<K: Copy>(timings: &[(K, f64)]) -> K {
+    let mut best = timings[0];
+    for timing in timings.iter() {
+        if timing.1 < best.1 {
+            best = *timing;
+        }
+    }
+    best.0
+}
+
+fn format_timings<K: std::fmt::Debug>(timings: &[(K, f64)]) -> String {
+    timings
+        .iter()
+        .map(|(kernel, ns)| format!("{:?}: {:.1} ns", kernel, ns))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn benchmark_ffm_kernel(kernel: FFMKernel, ffm_k: usize, ffm_fields: usize) -> f64 {
+    let field_embedding_len = ffm_k * ffm_fields;
+    let contra_fields = benchmark_data(field_embedding_len * ffm_fields);
+    let mut ffm_slice = vec![0.0; ffm_fields * ffm_fields];
+    let iterations = (BENCHMARK_FLOPS / contra_fields.len().max(1)).max(1);
+    time_ns(iterations, || unsafe {
+        match kernel {
+            FFMKernel::Sse => block_ffm::calculate_interactions_sse(
+                &mut ffm_slice,
+                &contra_fields,
+                ffm_k,
+                ffm_fields,
+                field_embedding_len,
+            ),
+            #[cfg(target_arch = "x86_64")]
+            FFMKernel::Avx2 => block_ffm::calculate_interactions_avx2(
+                &mut ffm_slice,
+                &contra_fields,
+                ffm_k,
+                ffm_fields,
+                field_embedding_len,
+            ),
+            #[cfg(target_arch = "x86_64")]
+            FFMKernel::Avx512 => block_ffm::calculate_interactions_avx512(
+                &mut ffm_slice,
+                &contra_fields,
+                ffm_k,
+                ffm_fields,
+                field_embedding_len,
+            ),
+        }
+    })
+}
+
+pub fn benchmark_neuron_kernel(kernel: NeuronKernel, num_inputs: usize, num_neurons: usize) -> f64 {
+    let weights = benchmark_data(num_inputs * num_neurons);
+    let input = benchmark_data(num_inputs);
+    let mut output = vec![0.0; num_neurons];
+    let iterations = (BENCHMARK_FLOPS / weights.len().max(1)).max(1);
+    time_ns(iterations, || match kernel {
+        NeuronKernel::Blas => {
+            block_neural::forward_blas(&weights, &input, &mut output, 1.0, num_inputs, num_neurons)
+        }
+        NeuronKernel::Scalar => block_neural::forward_scalar(
+            &weights,
+            &input,
+            &mut output,
+            1.0,
+            num_inputs,
+            num_neurons,
+        ),
+    })
+}
+
+// Benchmarks the kernel variants available on this machine with the shapes of the model's
+// blocks and picks the fastest for each block. Blocks the model does not have keep the default.
+pub fn autotune(mi: &ModelInstance, avx512: bool) -> KernelChoice {
+    let mut choice = KernelChoice::default();
+    let ffm_k = mi.ffm_k as usize;
+    let ffm_fields = mi.ffm_fields.len();
+
+    if ffm_k > 0 && ffm_fields > 0 {
+        let timings: Vec<(FFMKernel, f64)> = ffm_kernels_available(avx512)
+            .into_iter()
+            .map(|kernel| (kernel, benchmark_ffm_kernel(kernel, ffm_k, ffm_fields)))
+            .collect();
+        choice.ffm = fastest(&timings);
+        log::info!(
+            "Autotune: FFM kernel {:?} ({})",
+            choice.ffm,
+            format_timings(&timings)
+        );
+    }
+
+    if let Some(layer) = mi.nn_config.layers.first() {
+        // First layer takes the FFM interactions as input
+        let num_inputs = (ffm_fields * ffm_fields).max(1);
+        let num_neurons = layer
+            .get("width")
+            .and_then(|width| width.parse().ok())
+            .unwrap_or(DEFAULT_NN_WIDTH);
+        let timings: Vec<(NeuronKernel, f64)> = neuron_kernels_available()
+            .into_iter()
+            .map(|kernel| {
+                (
+                    kernel,
+                    benchmark_neuron_kernel(kernel, num_inputs, num_neurons),
+                )
+            })
+            .collect();
+        choice.neuron = fastest(&timings);
+        log::info!(
+            "Autotune: neuron layer kernel {:?} ({})",
+            choice.neuron,
+            format_timings(&timings)
+        );
+    }
+
+    choice
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_kernels_agree() {
+        let (ffm_k, ffm_fields) = (12, 5);
+        let contra_fields = benchmark_data(ffm_k * ffm_fields * ffm_fields);
+        let mut expected = vec![0.0; ffm_fields * ffm_fields];
+        unsafe {
+            block_ffm::calculate_interactions_sse(
+                &mut expected,
+                &contra_fields,
+                ffm_k,
+                ffm_fields,
+                ffm_k * ffm_fields,
+            );
+        }
+        #[cfg(target_arch = "x86_64")]
+        if ffm_kernels_available(false).contains(&FFMKernel::Avx2) {
+            let mut result = vec![0.0; ffm_fields * ffm_fields];
+            unsafe {
+                block_ffm::calculate_interactions_avx2(
+                    &mut result,
+                    &contra_fields,
+                    ffm_k,
+                    ffm_fields,
+                    ffm_k * ffm_fields,
+                );
+            }
+            for (r, e) in result.iter().zip(expected.iter()) {
+                assert!((r - e).abs() < 1e-5);
+            }
+        }
+        #[cfg(target_arch = "x86_64")]
+        if ffm_kernels_available(true).contains(&FFMKernel::Avx512) {
+            // ffm_k of 12 goes through the masked tail only
+            let mut result = vec![0.0; ffm_fields * ffm_fields];
+            unsafe {
+                block_ffm::calculate_interactions_avx512(
+                    &mut result,
+                    &contra_fields,
+                    ffm_k,
+                    ffm_fields,
+                    ffm_k * ffm_fields,
+                );
+            }
+            for (r, e) in result.iter().zip(expected.iter()) {
+                assert!((r - e).abs() < 1e-5);
+            }
+        }
+
+        let weights = benchmark_data(7 * 3);
+        let input = benchmark_data(7);
+        let mut blas = vec![1.0; 3];
+        let mut scalar = vec![1.0; 3];
+        block_neural::forward_blas(&weights, &input, &mut blas, 0.5, 7, 3);
+        block_neural::forward_scalar(&weights, &input, &mut scalar, 0.5, 7, 3);
+        for (s, b) in scalar.iter().zip(blas.iter()) {
+            assert!((s - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_autotune() {
+        assert_eq!(fastest(&[(1, 3.0), (2, 1.0), (3, 2.0)]), 2);
+
+        let mut mi = ModelInstance::new_empty().unwrap();
+        // Nothing to tune
+        assert_eq!(autotune(&mi, true), KernelChoice::default());
+        assert_eq!(KernelChoice::default().ffm, FFMKernel::Sse);
+
+        mi.ffm_k = 4;
+        mi.ffm_fields = vec![vec![], vec![], vec![]];
+        let mut layer = HashMap::new();
+        layer.insert("width".to_string(), "8".to_string());
+        mi.nn_config.layers.push(layer);
+        let choice = autotune(&mi, false);
+        assert!(ffm_kernels_available(false).contains(&choice.ffm));
+        assert!(neuron_kernels_available().contains(&choice.neuron));
+    }
+}
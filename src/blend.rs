@@ -0,0 +1,763 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+
+use crate::block_loss_functions::logistic;
+use crate::model_instance::{ModelInstance, Optimizer};
+use crate::persistence;
+use crate::regressor::Regressor;
+use crate::vwmap::VwNamespaceMap;
+
+// How two models are blended by --blend_mode
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    // Both models are kept and evaluated, their predictions are blended in logit space
+    Output,
+    // Weights are blended into a single model, only for models of identical structure
+    Weights,
+}
+
+impl BlendMode {
+    pub fn parse(s: &str) -> Result<BlendMode, Box<dyn Error>> {
+        match s {
+            "output" => Ok(BlendMode::Output),
+            "weights" => Ok(BlendMode::Weights),
+            _ => Err(format!(
+                "--blend_mode has to be one of output or weights, got: \"{}\"",
+                s
+            ))?,
+        }
+    }
+}
+
+fn default_blend_mode_weights() -> BlendMode {
+    // Blends were weight averages before there were modes
+    BlendMode::Weights
+}
+
+// Where a blended model came from, stored in its model instance so the artifact describes itself
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlendConfig {
+    pub base: String,
+    pub delta: String,
+    pub alpha: f32,
+    #[serde(default = "default_blend_mode_weights")]
+    pub mode: BlendMode,
+}
+
+// The delta model of an output blend, it is evaluated on the feature buffer of the base model.
+// Its model instance and weights are stored after the weights of the base model.
+pub struct OutputBlend {
+    pub mi: ModelInstance,
+    pub member: Regressor,
+    pub alpha: f32,
+}
+
+impl OutputBlend {
+    pub fn write_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+        quantize_weights: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.mi.save_to_buf(output_bufwriter)?;
+        self.member
+            .write_weights_to_buf(output_bufwriter, quantize_weights)
+    }
+
+    // The member is an inference-only regressor, like the base model it is blended with
+    pub fn new_from_buf(
+        input_bufreader: &mut dyn io::Read,
+        alpha: f32,
+        use_quantization: bool,
+    ) -> Result<OutputBlend, Box<dyn Error>> {
+        let mi = ModelInstance::new_from_buf(input_bufreader)?;
+        if mi.optimizer != Optimizer::SGD {
+            return Err("Blended model is not an inference model")?;
+        }
+        let mut re = Regressor::new_without_weights(&mi);
+        let mut member = re.immutable_regressor_without_weights(&mi)?;
+        member.allocate_and_init_weights(&mi);
+        re.into_immutable_regressor_from_buf(&mut member, input_bufreader, use_quantization)?;
+        if let Some(alpha) = output_blend_alpha(&mi) {
+            member.blend = Some(Box::new(OutputBlend::new_from_buf(
+                input_bufreader,
+                alpha,
+                use_quantization,
+            )?));
+        }
+        Ok(OutputBlend { mi, member, alpha })
+    }
+
+    // Hot reload of the member weights in place, e.g. by hogwild_load
+    pub fn overwrite_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<(), Box<dyn Error>> {
+        let mi = ModelInstance::new_from_buf(input_bufreader)?;
+        let mut re = Regressor::new_without_weights(&mi);
+        if !persistence::same_weights_shape(&re, &self.member) {
+            return Err(
+                "Weights of the blended model have a different shape than the served ones",
+            )?;
+        }
+        re.into_immutable_regressor_from_buf(&mut self.member, input_bufreader, false)?;
+        match self.member.blend.as_mut() {
+            Some(blend) => blend.overwrite_from_buf(input_bufreader),
+            None => Ok(()),
+        }
+    }
+}
+
+// Alpha of the output blend stored with a model, if it is one
+pub fn output_blend_alpha(mi: &ModelInstance) -> Option<f32> {
+    match &mi.blend {
+        Some(BlendConfig {
+            alpha,
+            mode: BlendMode::Output,
+            ..
+        }) => Some(*alpha),
+        _ => None,
+    }
+}
+
+// Probabilities are blended as (1 - alpha) * logit(base) + alpha * logit(delta), which keeps the
+// calibration of both models. Probabilities of 0 and 1 are clamped to keep logits finite.
+#[inline(always)]
+pub fn blend_probabilities(base: f32, delta: f32, alpha: f32) -> f32 {
+    logistic((1.0 - alpha) * logit(base) + alpha * logit(delta))
+}
+
+#[inline(always)]
+fn logit(p: f32) -> f32 {
+    let p = p.clamp(1e-7, 1.0 - 1e-7);
+    (p / (1.0 - p)).ln()
+}
+
+// Blends two models into an inference model. With BlendMode::Output the artifact holds both models
+// and blends their predictions, models only have to read the same features. With
+// BlendMode::Weights weights are blended as (1 - alpha) * base + alpha * delta into a single
+// model of identical structure; for the linear part this is exactly the blend of the logits, for
+// ffm and nn parts it is the usual weight averaging, which only makes sense for models fine-tuned
+// from each other.
+pub fn blend_models(
+    base_filename: &str,
+    delta_filename: &str,
+    alpha: f32,
+    mode: BlendMode,
+    out_filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    if !(0.0..=1.0).contains(&alpha) {
+        return Err(format!(
+            "Blend alpha has to be in range [0.0, 1.0], got: {}",
+            alpha
+        ))?;
+    }
+    // Loading as immutable gives us SGD regressors, which serialize as plain f32 weights
+    let (mut mi, vw, mut re_base) =
+        persistence::new_regressor_from_filename(base_filename, true, None)?;
+    let (mi_delta, vw_delta, re_delta) =
+        persistence::new_regressor_from_filename(delta_filename, true, None)?;
+    let blend = Some(BlendConfig {
+        base: base_filename.to_string(),
+        delta: delta_filename.to_string(),
+        alpha,
+        mode,
+    });
+    match mode {
+        BlendMode::Output => {
+            check_same_features(&mi, &vw, &mi_delta, &vw_delta)?;
+            if re_base.blend.is_some() {
+                return Err("Base model is an output blend already, it can only be blended as --blend_delta")?;
+            }
+            if mi.oaa > 0 || mi.heads > 0 || mi_delta.oaa > 0 || mi_delta.heads > 0 {
+                return Err("Only models that predict a single probability can be blended, not --oaa or --heads models")?;
+            }
+            re_base.blend = Some(Box::new(OutputBlend {
+                mi: mi_delta,
+                member: re_delta,
+                alpha,
+            }));
+            mi.blend = blend;
+            persistence::save_regressor_to_filename(out_filename, &mi, &vw, re_base, false)?;
+        }
+        BlendMode::Weights => {
+            check_same_structure(&mi, &vw, &mi_delta, &vw_delta)?;
+            if re_base.blend.is_some() || re_delta.blend.is_some() {
+                return Err("Weights of output blends can't be blended, use --blend_mode output")?;
+            }
+            let mut base_weights: Vec<u8> = Vec::new();
+            re_base.write_weights_to_buf(&mut base_weights, false)?;
+            let mut delta_weights: Vec<u8> = Vec::new();
+            re_delta.write_weights_to_buf(&mut delta_weights, false)?;
+            let blended_weights =
+                average_weight_frames(&[base_weights, delta_weights], &[1.0 - alpha, alpha])?;
+
+            mi.optimizer = Optimizer::SGD;
+            mi.blend = blend;
+            persistence::save_weights_buf_to_filename(out_filename, &mi, &vw, &blended_weights)?;
+        }
+    }
+    log::info!(
+        "Blended {} and {} with alpha {} into {}",
+        base_filename,
+        delta_filename,
+        alpha,
+        out_filename
+    );
+    Ok(())
+}
+
+// Differences of two models in how they turn examples into feature buffers
+fn feature_differences(
+    mi1: &ModelInstance,
+    vw1: &VwNamespaceMap,
+    mi2: &ModelInstance,
+    vw2: &VwNamespaceMap,
+) -> Vec<&'static str> {
+    let mut differences: Vec<&str> = Vec::new();
+    if vw1.vw_source != vw2.vw_source {
+        differences.push("namespace map");
+    }
+    if mi1.bit_precision != mi2.bit_precision {
+        differences.push("bit_precision");
+    }
+    if mi1.add_constant_feature != mi2.add_constant_feature {
+        differences.push("constant feature");
+    }
+    if mi1.feature_combo_descs != mi2.feature_combo_descs {
+        differences.push("feature combos");
+    }
+    if mi1.transform_namespaces != mi2.transform_namespaces {
+        differences.push("transformed namespaces");
+    }
+    if mi1.ffm_fields != mi2.ffm_fields
+        || mi1.ffm_k != mi2.ffm_k
+        || mi1.fm_k != mi2.fm_k
+        || mi1.ffm_bit_precision != mi2.ffm_bit_precision
+        || mi1.ffm_layout != mi2.ffm_layout
+    {
+        differences.push("ffm fields");
+    }
+    if mi1.dense_inputs != mi2.dense_inputs || mi1.embedding_lookups != mi2.embedding_lookups {
+        differences.push("nn inputs");
+    }
+    if mi1.dup_policy != mi2.dup_policy
+        || mi1.namespace_topks != mi2.namespace_topks
+        || mi1.tenant_namespace != mi2.tenant_namespace
+    {
+        differences.push("rewriting of examples");
+    }
+    differences
+}
+
+fn check_same_features(
+    mi1: &ModelInstance,
+    vw1: &VwNamespaceMap,
+    mi2: &ModelInstance,
+    vw2: &VwNamespaceMap,
+) -> Result<(), Box<dyn Error>> {
+    let differences = feature_differences(mi1, vw1, mi2, vw2);
+    if !differences.is_empty() {
+        return Err(format!(
+            "Only models that read the same features can be blended, models differ in: {}",
+            differences.join(", ")
+        ))?;
+    }
+    Ok(())
+}
+
+fn check_same_structure(
+    mi1: &ModelInstance,
+    vw1: &VwNamespaceMap,
+    mi2: &ModelInstance,
+    vw2: &VwNamespaceMap,
+) -> Result<(), Box<dyn Error>> {
+    let mut differences = feature_differences(mi1, vw1, mi2, vw2);
+    if mi1.ffm_field_attention != mi2.ffm_field_attention {
+        differences.push("ffm field attention");
+    }
+    if mi1.nn_config != mi2.nn_config || mi1.cross_layers != mi2.cross_layers {
+        differences.push("nn layers");
+    }
+    if !differences.is_empty() {
+        return Err(format!(
+            "Only models of identical structure can be blended, models differ in: {}",
+            differences.join(", ")
+        ))?;
+    }
+    if mi1.dequantize_weights.unwrap_or(false) || mi2.dequantize_weights.unwrap_or(false) {
+        return Err("Quantized models can't be blended, blend the original models instead")?;
+    }
+    Ok(())
+}
+
+// Where a merged model came from, like BlendConfig for blends
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MergeConfig {
+    pub models: Vec<String>,
+    pub weights: Vec<f32>,
+}
+
+pub fn parse_merge_weights(s: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+    let mut weights: Vec<f32> = Vec::new();
+    for w in s.split(',') {
+        match w.trim().parse::<f32>() {
+            Ok(w) if w >= 0.0 => weights.push(w),
+            _ => {
+                return Err(format!(
+                    "Merge weights have to be non-negative numbers, got: {}",
+                    s
+                ))?
+            }
+        }
+    }
+    Ok(weights)
+}
+
+// Merges models of identical structure into an inference model with their element-wise weighted
+// average, e.g. for ensembling hogwild shards trained on disjoint data. Weights are normalized to
+// sum to one; without them all models weigh the same.
+pub fn merge_models(
+    filenames: &[&str],
+    weights: Option<Vec<f32>>,
+    out_filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    if filenames.len() < 2 {
+        return Err("At least two models are needed for a merge")?;
+    }
+    let weights = weights.unwrap_or_else(|| vec![1.0; filenames.len()]);
+    if weights.len() != filenames.len() {
+        return Err(format!(
+            "Got {} merge weights for {} models",
+            weights.len(),
+            filenames.len()
+        ))?;
+    }
+    let sum: f32 = weights.iter().sum();
+    if sum <= 0.0 {
+        return Err("Merge weights have to sum to more than zero")?;
+    }
+    let weights: Vec<f32> = weights.iter().map(|w| w / sum).collect();
+
+    // A running weighted sum, so only it and the model being added are held in memory
+    let (mut mi, vw, re) = persistence::new_regressor_from_filename(filenames[0], true, None)?;
+    let mut frame: Vec<u8> = Vec::new();
+    re.write_weights_to_buf(&mut frame, false)?;
+    drop(re);
+    let mut merged_weights = average_weight_frames(&[frame], &weights[..1])?;
+    for (filename, weight) in filenames[1..].iter().zip(weights[1..].iter()) {
+        let (mi_other, vw_other, re_other) =
+            persistence::new_regressor_from_filename(filename, true, None)?;
+        check_same_structure(&mi, &vw, &mi_other, &vw_other)?;
+        let mut frame: Vec<u8> = Vec::new();
+        re_other.write_weights_to_buf(&mut frame, false)?;
+        drop(re_other);
+        merged_weights = average_weight_frames(&[merged_weights, frame], &[1.0, *weight])?;
+    }
+
+    mi.optimizer = Optimizer::SGD;
+    mi.merge = Some(MergeConfig {
+        models: filenames.iter().map(|f| f.to_string()).collect(),
+        weights,
+    });
+    persistence::save_weights_buf_to_filename(out_filename, &mi, &vw, &merged_weights)?;
+    log::info!("Merged {} models into {}", filenames.len(), out_filename);
+    Ok(())
+}
+
+// All buffers are outputs of Regressor::write_weights_to_buf of SGD regressors, so each
+// block frame is a plain array of f32 weights
+fn average_weight_frames(frames: &[Vec<u8>], weights: &[f32]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut readers: Vec<Cursor<&[u8]>> = frames.iter().map(|f| Cursor::new(&f[..])).collect();
+    let mut output: Vec<u8> = Vec::with_capacity(frames[0].len());
+    let num_blocks = readers[0].read_u32::<LittleEndian>()?;
+    for reader in readers[1..].iter_mut() {
+        if reader.read_u32::<LittleEndian>()? != num_blocks {
+            return Err("Models have different number of blocks")?;
+        }
+    }
+    output.write_u32::<LittleEndian>(num_blocks)?;
+    let mut frame: Vec<u8> = Vec::new();
+    let mut averaged: Vec<f32> = Vec::new();
+    for _ in 0..num_blocks {
+        let block_id = readers[0].read_u32::<LittleEndian>()?;
+        let len = readers[0].read_u64::<LittleEndian>()?;
+        if len % 4 != 0 {
+            return Err(format!(
+                "Block id {} has length {} that is not of f32 weights",
+                block_id, len
+            ))?;
+        }
+        averaged.clear();
+        averaged.resize(len as usize / 4, 0.0);
+        for (i, (reader, weight)) in readers.iter_mut().zip(weights.iter()).enumerate() {
+            if i > 0 {
+                let other_block_id = reader.read_u32::<LittleEndian>()?;
+                let other_len = reader.read_u64::<LittleEndian>()?;
+                if block_id != other_block_id || len != other_len {
+                    return Err(format!(
+                        "Models have different blocks: id {} of length {} vs id {} of length {}",
+                        block_id, len, other_block_id, other_len
+                    ))?;
+                }
+            }
+            frame.resize(len as usize, 0);
+            reader.read_exact(&mut frame)?;
+            for (a, w) in averaged.iter_mut().zip(frame.chunks_exact(4)) {
+                *a += weight * f32::from_ne_bytes([w[0], w[1], w[2], w[3]]);
+            }
+        }
+        output.write_u32::<LittleEndian>(block_id)?;
+        output.write_u64::<LittleEndian>(len)?;
+        for a in averaged.iter() {
+            output.extend_from_slice(&a.to_ne_bytes());
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::feature_buffer;
+    use crate::feature_buffer::{FeatureBufferTranslator, HashAndValue};
+    use crate::parser::VowpalParser;
+    use crate::regressor;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    fn lr_vec(v: Vec<HashAndValue>) -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: v,
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_blend_lr() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = Optimizer::AdagradFlex;
+        mi.init_acc_gradient = 0.0;
+        let fbuf = &lr_vec(vec![
+            HashAndValue {
+                hash: 1,
+                value: 1.0,
+                combo_index: 0,
+            },
+            HashAndValue {
+                hash: 2,
+                value: 1.0,
+                combo_index: 0,
+            },
+        ]);
+
+        let dir = tempdir().unwrap();
+        let base_filename = dir.path().join("base.fw");
+        let delta_filename = dir.path().join("delta.fw");
+        let out_filename = dir.path().join("blended.fw");
+        let base_filename = base_filename.to_str().unwrap();
+        let delta_filename = delta_filename.to_str().unwrap();
+        let out_filename = out_filename.to_str().unwrap();
+
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        re.learn(fbuf, &mut pb, true);
+        let p_base = re.learn(fbuf, &mut pb, false);
+        persistence::save_regressor_to_filename(base_filename, &mi, &vw, re, false).unwrap();
+
+        let mut re = regressor::Regressor::new(&mi);
+        for _ in 0..5 {
+            re.learn(fbuf, &mut pb, true);
+        }
+        let p_delta = re.learn(fbuf, &mut pb, false);
+        persistence::save_regressor_to_filename(delta_filename, &mi, &vw, re, false).unwrap();
+
+        blend_models(
+            base_filename,
+            delta_filename,
+            0.25,
+            BlendMode::Weights,
+            out_filename,
+        )
+        .unwrap();
+        let (mi_blended, _, re_blended) =
+            persistence::new_regressor_from_filename(out_filename, true, None).unwrap();
+        assert_eq!(
+            mi_blended.blend,
+            Some(BlendConfig {
+                base: base_filename.to_string(),
+                delta: delta_filename.to_string(),
+                alpha: 0.25,
+                mode: BlendMode::Weights,
+            })
+        );
+        let p_blended = re_blended.predict(fbuf, &mut pb);
+        assert_epsilon!(
+            logit(p_blended),
+            0.75 * logit(p_base) + 0.25 * logit(p_delta)
+        );
+
+        // Alpha of zero gives back the base model
+        blend_models(
+            base_filename,
+            delta_filename,
+            0.0,
+            BlendMode::Weights,
+            out_filename,
+        )
+        .unwrap();
+        let (_, _, re_blended) =
+            persistence::new_regressor_from_filename(out_filename, true, None).unwrap();
+        assert_eq!(re_blended.predict(fbuf, &mut pb), p_base);
+
+        assert!(blend_models(
+            base_filename,
+            delta_filename,
+            1.5,
+            BlendMode::Weights,
+            out_filename
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_blend_different_structure() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 18;
+        mi.optimizer = Optimizer::AdagradFlex;
+        let dir = tempdir().unwrap();
+        let base_filename = dir.path().join("base.fw");
+        let delta_filename = dir.path().join("delta.fw");
+        let base_filename = base_filename.to_str().unwrap();
+        let delta_filename = delta_filename.to_str().unwrap();
+
+        let re = regressor::Regressor::new(&mi);
+        persistence::save_regressor_to_filename(base_filename, &mi, &vw, re, false).unwrap();
+        mi.bit_precision = 17;
+        let re = regressor::Regressor::new(&mi);
+        persistence::save_regressor_to_filename(delta_filename, &mi, &vw, re, false).unwrap();
+
+        let out_filename = dir.path().join("blended.fw");
+        let out_filename = out_filename.to_str().unwrap();
+        let result = blend_models(
+            base_filename,
+            delta_filename,
+            0.5,
+            BlendMode::Weights,
+            out_filename,
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Only models of identical structure can be blended, models differ in: bit_precision"
+        );
+        let result = blend_models(
+            base_filename,
+            delta_filename,
+            0.5,
+            BlendMode::Output,
+            out_filename,
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Only models that read the same features can be blended, models differ in: bit_precision"
+        );
+    }
+
+    #[test]
+    fn test_blend_output() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let nd = |vwname: &str| vw.map_vwname_to_namespace_descriptor[vwname.as_bytes()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.nn_learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.ffm_power_t = 0.5;
+        mi.nn_power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_init_width = 1.0;
+        mi.optimizer = Optimizer::AdagradFlex;
+        mi.ffm_fields = vec![vec![nd("A")], vec![nd("B")]];
+        // Delta has nn layers on top of the same features, so their weights can't be averaged
+        let mut mi_delta = mi.clone();
+        mi_delta.nn_config.layers = vec![HashMap::new()];
+
+        let mut pa = VowpalParser::new(&vw);
+        let records: Vec<Vec<u32>> = (0..100)
+            .map(|i| {
+                let line = format!(
+                    "{} |A a{} |B b{}\n",
+                    if i % 3 == 0 { "1" } else { "-1" },
+                    i % 7,
+                    i % 5
+                );
+                pa.next_vowpal_from_bytes(line.as_bytes()).unwrap().to_vec()
+            })
+            .collect();
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+
+        let dir = tempdir().unwrap();
+        let base_filename = dir.path().join("base.fw");
+        let delta_filename = dir.path().join("delta.fw");
+        let out_filename = dir.path().join("blended.fw");
+        let base_filename = base_filename.to_str().unwrap();
+        let delta_filename = delta_filename.to_str().unwrap();
+        let out_filename = out_filename.to_str().unwrap();
+        for (mi, filename) in [(&mi, base_filename), (&mi_delta, delta_filename)].iter() {
+            let mut re = regressor::Regressor::new(mi);
+            let mut pb = re.new_portbuffer();
+            for (i, record) in records.iter().enumerate() {
+                fbt.translate(record, i as u64);
+                re.learn(&fbt.feature_buffer, &mut pb, true);
+            }
+            persistence::save_regressor_to_filename(filename, mi, &vw, re, false).unwrap();
+        }
+
+        let result = blend_models(
+            base_filename,
+            delta_filename,
+            0.3,
+            BlendMode::Weights,
+            out_filename,
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Only models of identical structure can be blended, models differ in: nn layers"
+        );
+
+        blend_models(
+            base_filename,
+            delta_filename,
+            0.3,
+            BlendMode::Output,
+            out_filename,
+        )
+        .unwrap();
+        let (_, _, re_base) =
+            persistence::new_regressor_from_filename(base_filename, true, None).unwrap();
+        let (_, _, re_delta) =
+            persistence::new_regressor_from_filename(delta_filename, true, None).unwrap();
+        let (mi_blended, _, re_blended) =
+            persistence::new_regressor_from_filename(out_filename, true, None).unwrap();
+        assert_eq!(mi_blended.blend.unwrap().mode, BlendMode::Output);
+        let mut pb_base = re_base.new_portbuffer();
+        let mut pb_delta = re_delta.new_portbuffer();
+        let mut pb_blended = re_blended.new_portbuffer();
+        for (i, record) in records.iter().enumerate().take(10) {
+            fbt.translate(record, i as u64);
+            let fb = &fbt.feature_buffer;
+            let p_base = re_base.predict(fb, &mut pb_base);
+            let p_delta = re_delta.predict(fb, &mut pb_delta);
+            assert!(p_base != p_delta);
+            assert_eq!(
+                re_blended.predict(fb, &mut pb_blended),
+                blend_probabilities(p_base, p_delta, 0.3)
+            );
+        }
+
+        // Both models are reloaded in place, and blended models are inference only
+        let (_, _, mut re_reloaded) =
+            persistence::new_regressor_from_filename(out_filename, true, None).unwrap();
+        persistence::hogwild_load(&mut re_reloaded, out_filename).unwrap();
+        let mut pb_reloaded = re_reloaded.new_portbuffer();
+        assert_eq!(
+            re_reloaded.predict(&fbt.feature_buffer, &mut pb_reloaded),
+            re_blended.predict(&fbt.feature_buffer, &mut pb_blended)
+        );
+        assert!(persistence::new_regressor_from_filename(out_filename, false, None).is_err());
+        assert!(blend_models(
+            out_filename,
+            delta_filename,
+            0.3,
+            BlendMode::Output,
+            out_filename
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_blend_probabilities() {
+        assert_epsilon!(blend_probabilities(0.2, 0.9, 0.0), 0.2);
+        assert_epsilon!(blend_probabilities(0.2, 0.9, 1.0), 0.9);
+        assert_epsilon!(
+            logit(blend_probabilities(0.2, 0.9, 0.5)),
+            0.5 * logit(0.2) + 0.5 * logit(0.9)
+        );
+        assert!(blend_probabilities(1.0, 0.0, 0.5).is_finite());
+    }
+
+    #[test]
+    fn test_merge_lr() {
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = Optimizer::AdagradFlex;
+        mi.init_acc_gradient = 0.0;
+        let fbuf = &lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+
+        let dir = tempdir().unwrap();
+        let filenames: Vec<String> = (0..3)
+            .map(|i| {
+                dir.path()
+                    .join(format!("shard{}.fw", i))
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        let filenames: Vec<&str> = filenames.iter().map(|f| f.as_str()).collect();
+        let out_filename = dir.path().join("merged.fw");
+        let out_filename = out_filename.to_str().unwrap();
+
+        let mut logits: Vec<f32> = Vec::new();
+        for (i, filename) in filenames.iter().enumerate() {
+            let mut re = regressor::Regressor::new(&mi);
+            let mut pb = re.new_portbuffer();
+            for _ in 0..=i {
+                re.learn(fbuf, &mut pb, true);
+            }
+            logits.push(logit(re.learn(fbuf, &mut pb, false)));
+            persistence::save_regressor_to_filename(filename, &mi, &vw, re, false).unwrap();
+        }
+
+        // Weights are normalized, so these are 0.5, 0.25 and 0.25
+        merge_models(&filenames, Some(vec![2.0, 1.0, 1.0]), out_filename).unwrap();
+        let (mi_merged, _, re_merged) =
+            persistence::new_regressor_from_filename(out_filename, true, None).unwrap();
+        assert_eq!(mi_merged.merge.unwrap().weights, vec![0.5, 0.25, 0.25]);
+        let mut pb = re_merged.new_portbuffer();
+        assert_epsilon!(
+            logit(re_merged.predict(fbuf, &mut pb)),
+            0.5 * logits[0] + 0.25 * logits[1] + 0.25 * logits[2]
+        );
+
+        assert!(merge_models(&filenames[..1], None, out_filename).is_err());
+        assert!(merge_models(&filenames, Some(vec![1.0, 1.0]), out_filename).is_err());
+        assert!(parse_merge_weights("0.7,0.3").unwrap() == vec![0.7, 0.3]);
+        assert!(parse_merge_weights("0.7,-0.3").is_err());
+    }
+}
@@ -0,0 +1,457 @@
+use std::any::Any;
+use std::error::Error;
+use std::io;
+
+use optimizer::OptimizerTrait;
+use regressor::BlockTrait;
+
+use crate::block_helpers;
+use crate::block_helpers::OptimizerData;
+use crate::feature_buffer;
+use crate::graph;
+use crate::model_instance;
+use crate::optimizer;
+use crate::port_buffer;
+use crate::regressor;
+use crate::regressor::BlockCache;
+
+// --cross_layers: DCN style explicit feature crossing over the dense input x0. Each of the depth layers
+// computes x_{l+1} = x0 * (w_l . x_l) + b_l + x_l, so layer l adds crosses of degree l + 2.
+// Weights start at zero, so the block starts as the identity of its input.
+pub struct BlockCrossLayer<L: OptimizerTrait> {
+    pub num_inputs: usize,
+    pub depth: usize,
+    pub input_offset: usize,
+    pub output_offset: usize,
+    pub weights_len: u32,
+    // w_l and then b_l, for each layer
+    pub weights: Vec<f32>,
+    pub weights_optimizer: Vec<OptimizerData<L>>,
+    pub optimizer: L,
+    lr_schedule: Option<model_instance::LRSchedule>,
+    grad_clip: f32,
+    weight_decay: f32,
+}
+
+pub fn new_cross_block(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+    input: graph::BlockPtrOutput,
+    depth: usize,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input]);
+    assert_ne!(num_inputs, 0);
+    assert_ne!(depth, 0);
+    let block = match mi.nn_optimizer.unwrap_or(mi.optimizer) {
+        model_instance::Optimizer::AdagradLUT => {
+            new_cross_block_without_weights::<optimizer::OptimizerAdagradLUT>(mi, num_inputs, depth)
+        }
+        model_instance::Optimizer::AdagradFlex => new_cross_block_without_weights::<
+            optimizer::OptimizerAdagradFlex,
+        >(mi, num_inputs, depth),
+        model_instance::Optimizer::SGD => {
+            new_cross_block_without_weights::<optimizer::OptimizerSGD>(mi, num_inputs, depth)
+        }
+        model_instance::Optimizer::Adam => {
+            new_cross_block_without_weights::<optimizer::OptimizerAdam>(mi, num_inputs, depth)
+        }
+        model_instance::Optimizer::SGDMomentum => new_cross_block_without_weights::<
+            optimizer::OptimizerSGDMomentum<false>,
+        >(mi, num_inputs, depth),
+        model_instance::Optimizer::SGDNesterov => new_cross_block_without_weights::<
+            optimizer::OptimizerSGDMomentum<true>,
+        >(mi, num_inputs, depth),
+        model_instance::Optimizer::COCOB | model_instance::Optimizer::FTRL => {
+            return Err("COCOB and FTRL are only supported for the LR block, see --lr_optimizer")?
+        }
+    }
+    .unwrap();
+    let mut block_outputs = bg.add_node(block, vec![input]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+fn new_cross_block_without_weights<L: OptimizerTrait + 'static>(
+    mi: &model_instance::ModelInstance,
+    num_inputs: usize,
+    depth: usize,
+) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+    let mut rg = BlockCrossLayer::<L> {
+        num_inputs,
+        depth,
+        input_offset: usize::MAX,
+        output_offset: usize::MAX,
+        weights_len: (2 * num_inputs * depth) as u32,
+        weights: Vec::new(),
+        weights_optimizer: Vec::new(),
+        optimizer: L::new(),
+        lr_schedule: mi.lr_schedule.clone(),
+        grad_clip: mi.grad_clip,
+        weight_decay: mi.nn_weight_decay,
+    };
+    rg.optimizer
+        .init(mi.nn_learning_rate, mi.nn_power_t, mi.nn_init_acc_gradient);
+    rg.optimizer.init_momentum(mi.momentum);
+    Ok(Box::new(rg))
+}
+
+impl<L: OptimizerTrait + 'static> BlockCrossLayer<L> {
+    // Keeps the input of each layer in pb.cross_layers for the backward pass
+    #[inline(always)]
+    fn internal_forward(&self, pb: &mut port_buffer::PortBuffer) {
+        let d = self.num_inputs;
+        let layers = &mut pb.cross_layers;
+        let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+            &mut pb.tape,
+            self.input_offset,
+            d,
+            self.output_offset,
+            d,
+        );
+        output_tape.copy_from_slice(input_tape);
+        for l in 0..self.depth {
+            let x = &mut layers[l * d..(l + 1) * d];
+            x.copy_from_slice(output_tape);
+            let (w, b) = self.weights[2 * l * d..2 * (l + 1) * d].split_at(d);
+            let s: f32 = w.iter().zip(x.iter()).map(|(w, x)| w * x).sum();
+            for ((output, x0), b) in output_tape.iter_mut().zip(input_tape.iter()).zip(b.iter()) {
+                *output += x0 * s + b;
+            }
+        }
+    }
+
+    // Backpropagates the gradient of the output through the layers, from the last one. Returns the
+    // squared norm of the gradients of the weights, and only with a clip factor updates the weights
+    // and leaves the gradient of the input on the tape.
+    fn backward(&mut self, pb: &mut port_buffer::PortBuffer, clip_factor: Option<f32>) -> f32 {
+        let d = self.num_inputs;
+        let (layers, gradient) = pb.cross_layers.split_at_mut(self.depth * d);
+        let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+            &mut pb.tape,
+            self.input_offset,
+            d,
+            self.output_offset,
+            d,
+        );
+        // gradient is by x_l as we go down, input_tape collects the gradient by x0 through the crosses
+        gradient.copy_from_slice(output_tape);
+        if clip_factor.is_some() {
+            input_tape.fill(0.0);
+        }
+        let x0 = &layers[0..d];
+        let mut gradient_norm_squared = 0.0;
+        unsafe {
+            for l in (0..self.depth).rev() {
+                let x = &layers[l * d..(l + 1) * d];
+                let w_offset = 2 * l * d;
+                let b_offset = w_offset + d;
+                let mut s = 0.0;
+                let mut gs = 0.0;
+                let mut x_squared = 0.0;
+                for i in 0..d {
+                    s += self.weights.get_unchecked(w_offset + i) * x.get_unchecked(i);
+                    gs += gradient.get_unchecked(i) * x0.get_unchecked(i);
+                    x_squared += x.get_unchecked(i) * x.get_unchecked(i);
+                    gradient_norm_squared += gradient.get_unchecked(i) * gradient.get_unchecked(i);
+                }
+                gradient_norm_squared += gs * gs * x_squared;
+
+                for i in 0..d {
+                    let g = *gradient.get_unchecked(i);
+                    let w = *self.weights.get_unchecked(w_offset + i);
+                    *gradient.get_unchecked_mut(i) = g + gs * w;
+                    if let Some(clip_factor) = clip_factor {
+                        *input_tape.get_unchecked_mut(i) += g * s;
+
+                        let update = self.optimizer.calculate_update(
+                            gs * x.get_unchecked(i) * clip_factor,
+                            &mut self
+                                .weights_optimizer
+                                .get_unchecked_mut(w_offset + i)
+                                .optimizer_data,
+                        );
+                        *self.weights.get_unchecked_mut(w_offset + i) -=
+                            update + self.weight_decay * w;
+
+                        let update = self.optimizer.calculate_update(
+                            g * clip_factor,
+                            &mut self
+                                .weights_optimizer
+                                .get_unchecked_mut(b_offset + i)
+                                .optimizer_data,
+                        );
+                        *self.weights.get_unchecked_mut(b_offset + i) -= update;
+                    }
+                }
+            }
+        }
+        if clip_factor.is_some() {
+            for (input, g) in input_tape.iter_mut().zip(gradient.iter()) {
+                *input += g;
+            }
+        }
+        gradient_norm_squared
+    }
+}
+
+impl<L: OptimizerTrait + 'static> BlockTrait for BlockCrossLayer<L> {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        debug_assert!(self.output_offset != usize::MAX);
+        debug_assert!(self.input_offset != usize::MAX);
+
+        if update {
+            if let Some(schedule) = self.lr_schedule.as_ref() {
+                self.optimizer
+                    .set_learning_rate_multiplier(schedule.multiplier_at(fb.example_number));
+            }
+        }
+
+        self.internal_forward(pb);
+
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+        if update {
+            // Errors passed to the input are not clipped, only the updates of this block
+            let clip_factor = if self.grad_clip > 0.0 {
+                optimizer::clip_factor(self.grad_clip, self.backward(pb, None))
+            } else {
+                1.0
+            };
+            self.backward(pb, Some(clip_factor));
+        }
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        self.internal_forward(pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        self.internal_forward(pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+
+    fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {
+        self.weights = vec![0.0; self.weights_len as usize];
+        self.weights_optimizer = vec![
+            OptimizerData::<L> {
+                optimizer_data: self.optimizer.initial_data()
+            };
+            self.weights_len as usize
+        ];
+    }
+
+    fn init_port_buffer(&self, pb: &mut port_buffer::PortBuffer) {
+        // Inputs of the layers, then the gradient that backward() passes down
+        pb.cross_layers
+            .resize((self.depth + 1) * self.num_inputs, 0.0);
+    }
+
+    fn set_lr_schedule(&mut self, lr_schedule: &Option<model_instance::LRSchedule>) {
+        self.lr_schedule = lr_schedule.clone();
+    }
+
+    fn get_serialized_len(&self) -> usize {
+        self.weights_len as usize
+    }
+
+    fn get_serialized_block_id(&self) -> u32 {
+        regressor::SERIALIZED_BLOCK_ID_CROSS
+    }
+
+    fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter, false)?;
+        block_helpers::write_weights_to_buf(&self.weights_optimizer, output_bufwriter, false)?;
+        Ok(())
+    }
+
+    fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader, false)?;
+        block_helpers::read_weights_from_buf(&mut self.weights_optimizer, input_bufreader, false)?;
+        Ok(())
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        self.num_inputs
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert_eq!(input.get_input_index(), 0);
+        self.input_offset = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(output.get_output_index(), 0);
+        self.output_offset = offset;
+    }
+
+    fn read_weights_from_buf_into_forward_only(
+        &self,
+        input_bufreader: &mut dyn io::Read,
+        forward: &mut Box<dyn BlockTrait>,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockCrossLayer<optimizer::OptimizerSGD>>()
+            .unwrap();
+        block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader, false)?;
+        block_helpers::skip_weights_from_buf::<OptimizerData<L>>(
+            self.weights_len as usize,
+            input_bufreader,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_misc;
+    use crate::block_misc::Observe;
+    use crate::graph::BlockGraph;
+    use crate::model_instance::Optimizer;
+    use block_helpers::slearn2;
+
+    fn fb_vec() -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
+    }
+
+    // Graph of input -> observe backward -> cross -> observe forward with a gradient of 1.0 for
+    // each output, so observations are the outputs and then the gradient of their sum by the input
+    fn cross_graph(
+        mi: &model_instance::ModelInstance,
+        input: Vec<f32>,
+        depth: usize,
+    ) -> BlockGraph {
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, input).unwrap();
+        let observe_input =
+            block_misc::new_observe_block(&mut bg, input_block, Observe::Backward, None).unwrap();
+        let cross_block = new_cross_block(&mut bg, mi, observe_input, depth).unwrap();
+        block_misc::new_observe_block(&mut bg, cross_block, Observe::Forward, Some(1.0)).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(mi);
+        bg
+    }
+
+    fn cross_weights(bg: &mut BlockGraph) -> &mut [f32] {
+        &mut bg
+            .blocks_final
+            .iter_mut()
+            .find_map(|block| {
+                block
+                    .as_any()
+                    .downcast_mut::<BlockCrossLayer<optimizer::OptimizerSGD>>()
+            })
+            .unwrap()
+            .weights
+    }
+
+    #[test]
+    fn test_cross_layer() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.nn_learning_rate = 0.1;
+        mi.nn_power_t = 0.0;
+        mi.optimizer = Optimizer::SGD;
+        let mut bg = cross_graph(&mi, vec![1.0, 2.0], 1);
+        let mut pb = bg.new_port_buffer();
+        let fb = fb_vec();
+
+        // Identity at first, gradient by w is (1 + 2) * x0 and by b is 1
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.observations, vec![1.0, 2.0, 1.0, 1.0]);
+        let weights = cross_weights(&mut bg);
+        assert_epsilon!(weights[0], -0.3);
+        assert_epsilon!(weights[1], -0.6);
+        assert_epsilon!(weights[2], -0.1);
+        assert_epsilon!(weights[3], -0.1);
+
+        // w . x0 is -1.5, the gradient of the input is (w . x0) + (1 + 2) * w + 1
+        slearn2(&mut bg, &fb, &mut pb, true);
+        let expected = [-0.6, -1.1, -1.4, -2.3];
+        for (observation, expected) in pb.observations.iter().zip(expected.iter()) {
+            assert_epsilon!(*observation, *expected);
+        }
+    }
+
+    #[test]
+    fn test_cross_layer_depth() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = Optimizer::SGD;
+        let input = vec![0.5, -1.0, 2.0];
+        let weights = vec![
+            0.1, 0.2, -0.3, 0.05, 0.0, -0.1, // layer 0
+            -0.2, 0.4, 0.1, 0.0, 0.3, 0.2, // layer 1
+        ];
+        // Sum of the outputs, computed directly
+        let outputs_sum = |x0: &[f32]| -> f32 {
+            let mut x = x0.to_vec();
+            for layer in weights.chunks(6) {
+                let s: f32 = layer[..3].iter().zip(x.iter()).map(|(w, x)| w * x).sum();
+                for ((x, x0), b) in x.iter_mut().zip(x0.iter()).zip(layer[3..].iter()) {
+                    *x += x0 * s + b;
+                }
+            }
+            x.iter().sum()
+        };
+
+        let mut bg = cross_graph(&mi, input.clone(), 2);
+        cross_weights(&mut bg).copy_from_slice(&weights);
+        let mut pb = bg.new_port_buffer();
+        slearn2(&mut bg, &fb_vec(), &mut pb, true);
+        let outputs: f32 = pb.observations[..3].iter().sum();
+        assert!((outputs - outputs_sum(&input)).abs() < 1e-5);
+        // Gradient by the input against central differences
+        for i in 0..3 {
+            let mut plus = input.clone();
+            plus[i] += 1e-2;
+            let mut minus = input.clone();
+            minus[i] -= 1e-2;
+            let numeric = (outputs_sum(&plus) - outputs_sum(&minus)) / 2e-2;
+            assert!((pb.observations[3 + i] - numeric).abs() < 1e-3);
+        }
+    }
+}
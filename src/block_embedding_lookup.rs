@@ -0,0 +1,647 @@
+use std::any::Any;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::block_helpers;
+use crate::feature_buffer;
+use crate::graph;
+use crate::model_instance;
+use crate::model_instance::EmbeddingPooling;
+use crate::optimizer;
+use crate::parser;
+use crate::port_buffer;
+use crate::regressor;
+use block_helpers::OptimizerData;
+use optimizer::OptimizerTrait;
+use regressor::BlockTrait;
+
+use crate::feature_buffer::FeatureBuffer;
+use crate::port_buffer::PortBuffer;
+use crate::regressor::BlockCache;
+
+// Embeddings read from a file of lines "feature v1 .. vN", keys are the feature hashes
+pub struct EmbeddingTable {
+    pub keys: Vec<u32>, // sorted
+    pub weights: Vec<f32>,
+    pub dim: usize,
+}
+
+pub fn read_embedding_file(
+    filename: &str,
+    namespace_vwname: &str,
+) -> Result<EmbeddingTable, Box<dyn Error>> {
+    let input = BufReader::new(File::open(filename).map_err(|e| {
+        IOError::new(
+            ErrorKind::Other,
+            format!("Cannot open embedding file {}: {}", filename, e),
+        )
+    })?);
+    let mut rows: Vec<(u32, Vec<f32>)> = Vec::new();
+    let mut dim: usize = 0;
+    for (line_num, line) in input.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        let feature = match fields.next() {
+            Some(feature) => feature,
+            None => continue,
+        };
+        let values: Vec<f32> = fields
+            .map(|v| v.parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| {
+                IOError::new(
+                    ErrorKind::Other,
+                    format!("{}, line {}: {}", filename, line_num + 1, e),
+                )
+            })?;
+        if dim == 0 {
+            dim = values.len();
+        }
+        if values.is_empty() || values.len() != dim {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "{}, line {}: expected an embedding of {} values, got {}",
+                    filename,
+                    line_num + 1,
+                    dim.max(1),
+                    values.len()
+                ),
+            )));
+        }
+        rows.push((
+            parser::feature_hash(namespace_vwname, feature.as_bytes()),
+            values,
+        ));
+    }
+    if rows.is_empty() {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!("No embeddings in {}", filename),
+        )));
+    }
+    rows.sort_by_key(|(key, _)| *key);
+    if let Some(w) = rows.windows(2).find(|w| w[0].0 == w[1].0) {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!(
+                "{} has two embeddings for the feature hash {}, features are listed twice or they collide",
+                filename, w[0].0
+            ),
+        )));
+    }
+    Ok(EmbeddingTable {
+        keys: rows.iter().map(|(key, _)| *key).collect(),
+        weights: rows.into_iter().flat_map(|(_, values)| values).collect(),
+        dim,
+    })
+}
+
+// Writes the embeddings of the features of a namespace (--embedding_lookup) to its output.
+// Features without an embedding contribute nothing, embeddings are scaled by the feature value.
+// With sum pooling the output is their sum, otherwise it is a variable length region for
+// block_misc::BlockPooling: the number of embeddings, followed by up to region_len embeddings.
+// Known features beyond region_len are dropped, counted and logged (see --embedding_lookup max_len).
+// The table only learns when the lookup is fine-tunable.
+pub struct BlockEmbeddingLookup<L: OptimizerTrait> {
+    pub keys: Vec<u32>, // sorted feature hashes, one row of weights each
+    pub weights: Vec<f32>,
+    pub weights_optimizer: Vec<OptimizerData<L>>,
+    pub optimizer: L,
+    lookup_index: usize, // index in mi.embedding_lookups, combo_index of its features in embedding_buffer
+    rows: usize,
+    dim: usize,
+    region_len: usize, // 0 when the block sums the embeddings itself
+    finetune: bool,
+    output_offset: usize,
+    found_rows: Vec<(usize, f32)>, // row and feature value of the current example
+    namespace_vwname: String,
+    dropped_features: AtomicU64, // known features beyond region_len, forward passes only get &self
+}
+
+pub fn new_embedding_lookup_block(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+    lookup_index: usize,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let desc = &mi.embedding_lookups[lookup_index];
+    // Fixed tables need no optimizer state
+    let optimizer = if desc.finetune {
+        mi.nn_optimizer.unwrap_or(mi.optimizer)
+    } else {
+        model_instance::Optimizer::SGD
+    };
+    let block: Box<dyn BlockTrait> = match optimizer {
+        model_instance::Optimizer::AdagradLUT => Box::new(new_embedding_lookup_without_weights::<
+            optimizer::OptimizerAdagradLUT,
+        >(mi, lookup_index)),
+        model_instance::Optimizer::AdagradFlex => Box::new(new_embedding_lookup_without_weights::<
+            optimizer::OptimizerAdagradFlex,
+        >(mi, lookup_index)),
+        model_instance::Optimizer::SGD => Box::new(new_embedding_lookup_without_weights::<
+            optimizer::OptimizerSGD,
+        >(mi, lookup_index)),
+        model_instance::Optimizer::Adam => Box::new(new_embedding_lookup_without_weights::<
+            optimizer::OptimizerAdam,
+        >(mi, lookup_index)),
+        model_instance::Optimizer::SGDMomentum => Box::new(new_embedding_lookup_without_weights::<
+            optimizer::OptimizerSGDMomentum<false>,
+        >(mi, lookup_index)),
+        model_instance::Optimizer::SGDNesterov => Box::new(new_embedding_lookup_without_weights::<
+            optimizer::OptimizerSGDMomentum<true>,
+        >(mi, lookup_index)),
+        model_instance::Optimizer::COCOB | model_instance::Optimizer::FTRL => {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                "COCOB and FTRL are only supported for the LR block, see --lr_optimizer",
+            )))
+        }
+    };
+    let mut block_outputs = bg.add_node(block, vec![])?;
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+fn new_embedding_lookup_without_weights<L: OptimizerTrait + 'static>(
+    mi: &model_instance::ModelInstance,
+    lookup_index: usize,
+) -> BlockEmbeddingLookup<L> {
+    let desc = &mi.embedding_lookups[lookup_index];
+    assert_ne!(desc.rows, 0);
+    assert_ne!(desc.dim, 0);
+    let mut block = BlockEmbeddingLookup::<L> {
+        keys: Vec::new(),
+        weights: Vec::new(),
+        weights_optimizer: Vec::new(),
+        optimizer: L::new(),
+        lookup_index,
+        rows: desc.rows as usize,
+        dim: desc.dim as usize,
+        region_len: match desc.pooling {
+            EmbeddingPooling::Sum => 0,
+            _ => desc.max_len as usize,
+        },
+        finetune: desc.finetune,
+        output_offset: usize::MAX,
+        found_rows: Vec::new(),
+        namespace_vwname: desc.namespace_vwname.clone(),
+        dropped_features: AtomicU64::new(0),
+    };
+    block
+        .optimizer
+        .init(mi.nn_learning_rate, mi.nn_power_t, mi.nn_init_acc_gradient);
+    block.optimizer.init_momentum(mi.momentum);
+    block
+}
+
+impl<L: OptimizerTrait + 'static> BlockEmbeddingLookup<L> {
+    fn find_rows(&self, fb: &FeatureBuffer, found_rows: &mut Vec<(usize, f32)>) {
+        found_rows.truncate(0);
+        let mut dropped: u64 = 0;
+        for hash_and_value in fb.embedding_buffer.iter() {
+            if hash_and_value.combo_index as usize != self.lookup_index {
+                continue;
+            }
+            if let Ok(row) = self.keys.binary_search(&hash_and_value.hash) {
+                if found_rows.len() == self.region_len && self.region_len != 0 {
+                    dropped += 1;
+                } else {
+                    found_rows.push((row, hash_and_value.value));
+                }
+            }
+        }
+        if dropped > 0 {
+            self.count_dropped(dropped);
+        }
+    }
+
+    // Logs when the total crosses a power of two, so a steady stream of drops doesn't flood the log
+    fn count_dropped(&self, dropped: u64) {
+        let previous = self.dropped_features.fetch_add(dropped, Ordering::Relaxed);
+        let total = previous + dropped;
+        if previous.leading_zeros() != total.leading_zeros() {
+            log::warn!(
+                "--embedding_lookup {}: dropped {} features so far beyond max_len {} of an example",
+                self.namespace_vwname,
+                total,
+                self.region_len
+            );
+        }
+    }
+
+    pub fn get_dropped_features(&self) -> u64 {
+        self.dropped_features.load(Ordering::Relaxed)
+    }
+
+    fn internal_forward(&self, found_rows: &[(usize, f32)], pb: &mut PortBuffer) {
+        debug_assert!(self.output_offset != usize::MAX);
+        let output = &mut pb.tape[self.output_offset..(self.output_offset + self.num_outputs())];
+        if self.region_len == 0 {
+            output.fill(0.0);
+            for &(row, value) in found_rows.iter() {
+                let embedding = &self.weights[row * self.dim..(row + 1) * self.dim];
+                for (o, w) in output.iter_mut().zip(embedding.iter()) {
+                    *o += w * value;
+                }
+            }
+        } else {
+            output[0] = found_rows.len() as f32;
+            for (&(row, value), o) in found_rows
+                .iter()
+                .zip(output[1..].chunks_exact_mut(self.dim))
+            {
+                let embedding = &self.weights[row * self.dim..(row + 1) * self.dim];
+                for (o, w) in o.iter_mut().zip(embedding.iter()) {
+                    *o = w * value;
+                }
+            }
+        }
+    }
+
+    fn internal_backward(&mut self, found_rows: &[(usize, f32)], pb: &PortBuffer) {
+        for (j, &(row, value)) in found_rows.iter().enumerate() {
+            // Summed embeddings all get the same gradient, vectors of a region each their own
+            let gradient_offset = match self.region_len {
+                0 => self.output_offset,
+                _ => self.output_offset + 1 + j * self.dim,
+            };
+            for i in 0..self.dim {
+                let general_gradient = pb.tape[gradient_offset + i];
+                if general_gradient == 0.0 {
+                    continue;
+                }
+                let index = row * self.dim + i;
+                let update = unsafe {
+                    self.optimizer.calculate_update(
+                        general_gradient * value,
+                        &mut self.weights_optimizer[index].optimizer_data,
+                    )
+                };
+                self.weights[index] -= update;
+            }
+        }
+    }
+
+    fn num_outputs(&self) -> usize {
+        match self.region_len {
+            0 => self.dim,
+            _ => 1 + self.region_len * self.dim,
+        }
+    }
+}
+
+impl<L: OptimizerTrait + 'static> BlockTrait for BlockEmbeddingLookup<L> {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        let mut found_rows = std::mem::take(&mut self.found_rows);
+        self.find_rows(fb, &mut found_rows);
+        self.internal_forward(&found_rows, pb);
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+        if update && self.finetune {
+            self.internal_backward(&found_rows, pb);
+        }
+        self.found_rows = found_rows;
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        let mut found_rows: Vec<(usize, f32)> = Vec::new();
+        self.find_rows(fb, &mut found_rows);
+        self.internal_forward(&found_rows, pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        let mut found_rows: Vec<(usize, f32)> = Vec::new();
+        self.find_rows(fb, &mut found_rows);
+        self.internal_forward(&found_rows, pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+
+    // The table is filled by load_pretrained_weights() or read with the rest of the model
+    fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {
+        self.keys = vec![0; self.rows];
+        self.weights = vec![0.0; self.rows * self.dim];
+        self.weights_optimizer = vec![
+            OptimizerData::<L> {
+                optimizer_data: self.optimizer.initial_data()
+            };
+            self.rows * self.dim
+        ];
+    }
+
+    fn load_pretrained_weights(
+        &mut self,
+        mi: &model_instance::ModelInstance,
+    ) -> Result<(), Box<dyn Error>> {
+        let desc = &mi.embedding_lookups[self.lookup_index];
+        let table = read_embedding_file(&desc.filename, &desc.namespace_vwname)?;
+        if table.keys.len() != self.rows || table.dim != self.dim {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Embedding file {} changed, it has {} embeddings of {} values, expected {} of {}",
+                    desc.filename,
+                    table.keys.len(),
+                    table.dim,
+                    self.rows,
+                    self.dim
+                ),
+            )));
+        }
+        self.keys = table.keys;
+        self.weights = table.weights;
+        log::info!(
+            "Loaded {} embeddings of {} values from {}",
+            self.rows,
+            self.dim,
+            desc.filename
+        );
+        Ok(())
+    }
+
+    fn get_serialized_len(&self) -> usize {
+        self.rows * self.dim
+    }
+
+    fn get_serialized_block_id(&self) -> u32 {
+        regressor::SERIALIZED_BLOCK_ID_EMBEDDING_LOOKUP
+    }
+
+    fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::write_weights_to_buf(&self.keys, output_bufwriter, false)?;
+        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter, false)?;
+        block_helpers::write_weights_to_buf(&self.weights_optimizer, output_bufwriter, false)?;
+        Ok(())
+    }
+
+    fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::read_weights_from_buf(&mut self.keys, input_bufreader, false)?;
+        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader, false)?;
+        block_helpers::read_weights_from_buf(&mut self.weights_optimizer, input_bufreader, false)?;
+        Ok(())
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        self.num_outputs()
+    }
+
+    fn set_input_offset(&mut self, _input: graph::InputSlot, _offset: usize) {
+        panic!("You cannot set input_tape_index for BlockEmbeddingLookup");
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(
+            output.get_output_index(),
+            0,
+            "Only supports a single output for BlockEmbeddingLookup"
+        );
+        self.output_offset = offset;
+    }
+
+    fn read_weights_from_buf_into_forward_only(
+        &self,
+        input_bufreader: &mut dyn io::Read,
+        forward: &mut Box<dyn BlockTrait>,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockEmbeddingLookup<optimizer::OptimizerSGD>>()
+            .unwrap();
+        block_helpers::read_weights_from_buf(&mut forward.keys, input_bufreader, false)?;
+        block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader, false)?;
+        block_helpers::skip_weights_from_buf::<OptimizerData<L>>(
+            self.rows * self.dim,
+            input_bufreader,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_helpers::{slearn2, spredict2};
+    use crate::block_misc;
+    use crate::block_misc::Observe;
+    use crate::feature_buffer::HashAndValue;
+    use crate::graph::BlockGraph;
+    use crate::model_instance::{EmbeddingLookupDesc, ModelInstance};
+    use crate::vwmap::VwNamespaceMap;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn fb_vec() -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
+    }
+
+    fn hv(feature: &str, value: f32) -> HashAndValue {
+        HashAndValue {
+            hash: parser::feature_hash("A", feature.as_bytes()),
+            value,
+            combo_index: 0,
+        }
+    }
+
+    // Embedding lookup of namespace A, pooled as in the regressor, followed by an observe block
+    // and a sink that leaves the output on the tape, so the gradient equals the output
+    fn setup(
+        embeddings: &str,
+        pooling: EmbeddingPooling,
+        finetune: bool,
+    ) -> (ModelInstance, BlockGraph) {
+        setup_with_max_len(embeddings, pooling, finetune, 32)
+    }
+
+    fn setup_with_max_len(
+        embeddings: &str,
+        pooling: EmbeddingPooling,
+        finetune: bool,
+        max_len: u32,
+    ) -> (ModelInstance, BlockGraph) {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("embeddings.txt");
+        let filename = filename.to_str().unwrap();
+        fs::write(filename, embeddings).unwrap();
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        let table = read_embedding_file(filename, "A").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::SGD;
+        mi.nn_learning_rate = 0.1;
+        mi.embedding_lookups.push(EmbeddingLookupDesc {
+            namespace_descriptor: vw.map_verbose_to_namespace_descriptor["featureA"],
+            namespace_vwname: "A".to_string(),
+            filename: filename.to_string(),
+            pooling,
+            finetune,
+            rows: table.keys.len() as u32,
+            dim: table.dim as u32,
+            max_len,
+        });
+
+        let mut bg = BlockGraph::new();
+        let mut embedding_block = new_embedding_lookup_block(&mut bg, &mi, 0).unwrap();
+        if pooling != EmbeddingPooling::Sum {
+            embedding_block =
+                block_misc::new_pooling_block(&mut bg, embedding_block, pooling, table.dim)
+                    .unwrap();
+        }
+        let observe_block_forward =
+            block_misc::new_observe_block(&mut bg, embedding_block, Observe::Forward, None)
+                .unwrap();
+        block_misc::new_sink_block(
+            &mut bg,
+            observe_block_forward,
+            block_misc::SinkType::Untouched,
+        )
+        .unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        for block in bg.blocks_final.iter_mut() {
+            block.load_pretrained_weights(&mi).unwrap();
+        }
+        (mi, bg)
+    }
+
+    #[test]
+    fn test_read_embedding_file() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("embeddings.txt");
+        let filename = filename.to_str().unwrap();
+        fs::write(filename, "b 3 4\n\na 1 2\n").unwrap();
+        let table = read_embedding_file(filename, "A").unwrap();
+        assert_eq!(table.dim, 2);
+        let a = table
+            .keys
+            .binary_search(&parser::feature_hash("A", b"a"))
+            .unwrap();
+        assert_eq!(table.weights[a * 2..a * 2 + 2], [1.0, 2.0]);
+
+        fs::write(filename, "a 1 2\nb 3\n").unwrap();
+        assert!(read_embedding_file(filename, "A").is_err());
+        fs::write(filename, "a 1 2\na 3 4\n").unwrap();
+        assert!(read_embedding_file(filename, "A").is_err());
+        fs::write(filename, "a 1 x\n").unwrap();
+        assert!(read_embedding_file(filename, "A").is_err());
+        fs::write(filename, "").unwrap();
+        assert!(read_embedding_file(filename, "A").is_err());
+    }
+
+    #[test]
+    fn test_pooling() {
+        let embeddings = "a 1 -2\nb 3 4\n";
+        let mut fb = fb_vec();
+        fb.embedding_buffer = vec![hv("a", 1.0), hv("b", 2.0), hv("unknown", 1.0)];
+
+        let (_, mut bg) = setup(embeddings, EmbeddingPooling::Sum, false);
+        let mut pb = bg.new_port_buffer();
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.observations, vec![7.0, 6.0]);
+        // Fixed table doesn't learn
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_eq!(pb.observations, vec![7.0, 6.0]);
+
+        let (_, mut bg) = setup(embeddings, EmbeddingPooling::Mean, false);
+        let mut pb = bg.new_port_buffer();
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_eq!(pb.observations, vec![3.5, 3.0]);
+
+        let (_, mut bg) = setup(embeddings, EmbeddingPooling::Max, false);
+        let mut pb = bg.new_port_buffer();
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_eq!(pb.observations, vec![6.0, 8.0]);
+
+        // No known features
+        fb.embedding_buffer = vec![hv("unknown", 1.0)];
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_eq!(pb.observations, vec![0.0, 0.0]);
+
+        // Only the first max_len known features are pooled, the rest are counted
+        fb.embedding_buffer = vec![hv("unknown", 1.0), hv("b", 1.0), hv("a", 1.0)];
+        let (_, mut bg) = setup_with_max_len(embeddings, EmbeddingPooling::Mean, false, 1);
+        let mut pb = bg.new_port_buffer();
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_eq!(pb.observations, vec![3.0, 4.0]);
+        spredict2(&mut bg, &fb, &mut pb);
+        let block = bg.blocks_final[0]
+            .as_any()
+            .downcast_mut::<BlockEmbeddingLookup<optimizer::OptimizerSGD>>()
+            .unwrap();
+        assert_eq!(block.get_dropped_features(), 2);
+    }
+
+    #[test]
+    fn test_finetune() {
+        let embeddings = "a 1 -2\nb 3 4\n";
+        let mut fb = fb_vec();
+        fb.embedding_buffer = vec![hv("a", 1.0), hv("b", 1.0)];
+
+        // Gradient is the output [4, 2], both embeddings move by 0.1 * gradient
+        let (_, mut bg) = setup(embeddings, EmbeddingPooling::Sum, true);
+        let mut pb = bg.new_port_buffer();
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.observations, vec![4.0, 2.0]);
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_epsilon!(pb.observations[0], 3.2);
+        assert_epsilon!(pb.observations[1], 1.6);
+
+        // Only the embedding that gave the max gets the gradient [3, 4]
+        let (_, mut bg) = setup(embeddings, EmbeddingPooling::Max, true);
+        let mut pb = bg.new_port_buffer();
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.observations, vec![3.0, 4.0]);
+        fb.embedding_buffer = vec![hv("a", 1.0)];
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_eq!(pb.observations, vec![1.0, -2.0]);
+        fb.embedding_buffer = vec![hv("b", 1.0)];
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_epsilon!(pb.observations[0], 2.7);
+        assert_epsilon!(pb.observations[1], 3.6);
+    }
+}
@@ -1,62 +1,144 @@
 #![allow(invalid_value, unused_mut)]
 
+#[cfg(target_arch = "x86_64")]
 use core::arch::x86_64::*;
 use rustc_hash::FxHashSet;
 use std::any::Any;
 use std::error::Error;
-use std::mem::{self, MaybeUninit};
-use std::sync::Mutex;
+use std::mem;
+use std::ops::Range;
 use std::{io, ptr};
 
-use merand48::*;
+use rand::Rng;
 
 use optimizer::OptimizerTrait;
 use regressor::BlockTrait;
 
+use crate::autotune::FFMKernel;
 use crate::block_helpers;
 use crate::block_helpers::OptimizerData;
+use crate::delta;
 use crate::feature_buffer;
 use crate::feature_buffer::{FeatureBuffer, HashAndValueAndSeq};
 use crate::graph;
 use crate::model_instance;
+use crate::onnx;
 use crate::optimizer;
 use crate::port_buffer;
-use crate::port_buffer::PortBuffer;
+use crate::port_buffer::{MinibatchGradients, PortBuffer};
 use crate::quantization;
 use crate::regressor;
-use crate::regressor::{BlockCache, FFM_CONTRA_BUF_LEN};
-
-const FFM_STACK_BUF_LEN: usize = 170393;
-const STEP: usize = 4;
+use crate::regressor::BlockCache;
+use crate::rng;
+use crate::simd;
+use crate::simd::F32x4;
+use crate::weight_storage::WeightStorage;
+
+// Features of an example that the gradient scratch of a port buffer is sized for, it grows for more
+const FFM_SCRATCH_FEATURES: usize = 2048;
+const STEP: usize = simd::LANES;
 const ZEROES: [f32; STEP] = [0.0; STEP];
 
+// Where the ffm_k weights of a feature towards a field are in the weight table, by --ffm_layout.
+// Masked hashes of features are multiples of ffm_k rounded up to a power of two. Feature-major, the
+// embeddings of a feature towards all fields follow each other from its hash on. Field-major, each
+// field has a region of 1 << (ffm_bit_precision - field_bits) weights, field_bits enough to count
+// the fields, in which the embedding of a feature is at its hash shifted by field_bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FfmIndexing {
+    shift: u32,
+    mask: u32,
+    field_stride: usize,
+}
+
+impl FfmIndexing {
+    pub fn new(mi: &model_instance::ModelInstance) -> FfmIndexing {
+        match mi.ffm_layout {
+            model_instance::FfmLayout::FeatureMajor => FfmIndexing {
+                shift: 0,
+                mask: u32::MAX,
+                field_stride: mi.ffm_k as usize,
+            },
+            model_instance::FfmLayout::FieldMajor => {
+                let field_bits = (mi.ffm_fields.len() as u32)
+                    .next_power_of_two()
+                    .trailing_zeros();
+                let dimensions_mask = mi.ffm_k.max(mi.fm_k).next_power_of_two() - 1;
+                FfmIndexing {
+                    shift: field_bits,
+                    mask: !dimensions_mask,
+                    field_stride: 1 << mi.ffm_bit_precision.saturating_sub(field_bits),
+                }
+            }
+        }
+    }
+
+    // Index of the embedding of the feature with this masked hash towards the first field
+    #[inline(always)]
+    pub fn base(&self, hash: u32) -> usize {
+        ((hash >> self.shift) & self.mask) as usize
+    }
+
+    // How far the embedding towards the next field is
+    #[inline(always)]
+    pub fn field_stride(&self) -> usize {
+        self.field_stride
+    }
+
+    #[inline(always)]
+    pub fn index(&self, hash: u32, field: usize) -> usize {
+        self.base(hash) + field * self.field_stride
+    }
+}
+
 pub struct BlockFFM<L: OptimizerTrait> {
     pub optimizer_ffm: L,
-    pub local_data_ffm_values: Vec<f32>,
     pub ffm_k: u32,
     pub ffm_weights_len: u32,
     pub ffm_num_fields: u32,
     pub field_embedding_len: u32,
-    pub weights: Vec<f32>,
+    pub indexing: FfmIndexing,
+    pub weights: WeightStorage,
     pub optimizer: Vec<OptimizerData<L>>,
     pub output_offset: usize,
-    mutex: Mutex<()>,
+    dp: Option<optimizer::DPGradient>,
+    accurate_accumulation: bool,
+    ffm_kernel: FFMKernel,
+    lr_schedule: Option<model_instance::LRSchedule>,
+    l2: f32,
+    grad_clip: f32,
+    weight_decay: f32,
+    minibatch: u32,
+    quantization: quantization::WeightQuantization,
+    dirty: Option<delta::DirtyChunks>, // --delta_base
 }
 
 pub fn new_ffm_block(
     bg: &mut graph::BlockGraph,
     mi: &model_instance::ModelInstance,
 ) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
-    let block = match mi.optimizer {
-	model_instance::Optimizer::AdagradLUT => {
-	    new_ffm_block_without_weights::<optimizer::OptimizerAdagradLUT>(mi)
-	}
-	model_instance::Optimizer::AdagradFlex => {
-	    new_ffm_block_without_weights::<optimizer::OptimizerAdagradFlex>(mi)
-	}
-	model_instance::Optimizer::SGD => {
-	    new_ffm_block_without_weights::<optimizer::OptimizerSGD>(mi)
-	}
+    let block = match mi.ffm_optimizer.unwrap_or(mi.optimizer) {
+        model_instance::Optimizer::AdagradLUT => {
+            new_ffm_block_without_weights::<optimizer::OptimizerAdagradLUT>(mi)
+        }
+        model_instance::Optimizer::AdagradFlex => {
+            new_ffm_block_without_weights::<optimizer::OptimizerAdagradFlex>(mi)
+        }
+        model_instance::Optimizer::SGD => {
+            new_ffm_block_without_weights::<optimizer::OptimizerSGD>(mi)
+        }
+        model_instance::Optimizer::Adam => {
+            new_ffm_block_without_weights::<optimizer::OptimizerAdam>(mi)
+        }
+        model_instance::Optimizer::SGDMomentum => {
+            new_ffm_block_without_weights::<optimizer::OptimizerSGDMomentum<false>>(mi)
+        }
+        model_instance::Optimizer::SGDNesterov => {
+            new_ffm_block_without_weights::<optimizer::OptimizerSGDMomentum<true>>(mi)
+        }
+        model_instance::Optimizer::COCOB | model_instance::Optimizer::FTRL => {
+            return Err("COCOB and FTRL are only supported for the LR block, see --lr_optimizer")?
+        }
     }
     .unwrap();
     let mut block_outputs = bg.add_node(block, vec![]).unwrap();
@@ -71,65 +153,107 @@ fn new_ffm_block_without_weights<L: OptimizerTrait + 'static>(
     let field_embedding_len = mi.ffm_k * ffm_num_fields as u32;
 
     let mut reg_ffm = BlockFFM::<L> {
-	weights: Vec::new(),
-	optimizer: Vec::new(),
-	ffm_weights_len: 0,
-	local_data_ffm_values: Vec::with_capacity(1024),
-	ffm_k: mi.ffm_k,
-	ffm_num_fields,
-	field_embedding_len,
-	optimizer_ffm: L::new(),
-	output_offset: usize::MAX,
-	mutex: Mutex::new(()),
+        weights: WeightStorage::new(),
+        optimizer: Vec::new(),
+        ffm_weights_len: 0,
+        ffm_k: mi.ffm_k,
+        ffm_num_fields,
+        field_embedding_len,
+        indexing: FfmIndexing::new(mi),
+        optimizer_ffm: L::new(),
+        output_offset: usize::MAX,
+        dp: mi
+            .dp
+            .map(|dp| optimizer::DPGradient::new(dp.clip, dp.noise_multiplier)),
+        accurate_accumulation: mi.accurate_accumulation,
+        ffm_kernel: mi.kernels.ffm,
+        lr_schedule: mi.lr_schedule.clone(),
+        l2: mi.ffm_l2,
+        grad_clip: mi.grad_clip,
+        weight_decay: mi.ffm_weight_decay,
+        minibatch: mi.minibatch,
+        quantization: mi.quantization,
+        dirty: None,
     };
 
     if mi.ffm_k > 0 {
-	reg_ffm.optimizer_ffm.init(
-	    mi.ffm_learning_rate,
-	    mi.ffm_power_t,
-	    mi.ffm_init_acc_gradient,
-	);
-	// At the end we add "spillover buffer", so we can do modulo only on the base address and add offset
-	reg_ffm.ffm_weights_len =
-	    (1 << mi.ffm_bit_precision) + (mi.ffm_fields.len() as u32 * reg_ffm.ffm_k);
-    }
-
-    // Verify that forward pass will have enough stack for temporary buffer
-    if reg_ffm.ffm_k as usize * mi.ffm_fields.len() * mi.ffm_fields.len() > FFM_CONTRA_BUF_LEN {
-	return Err(format!("FFM_CONTRA_BUF_LEN is {}. It needs to be at least ffm_k * number_of_fields^2. number_of_fields: {}, ffm_k: {}, please recompile with larger constant",
-			   FFM_CONTRA_BUF_LEN, mi.ffm_fields.len(), reg_ffm.ffm_k))?;
+        reg_ffm.optimizer_ffm.init(
+            mi.ffm_learning_rate,
+            mi.ffm_power_t,
+            mi.ffm_init_acc_gradient,
+        );
+        reg_ffm.optimizer_ffm.init_momentum(mi.momentum);
+        // At the end we add "spillover buffer", so we can do modulo only on the base address and add offset
+        reg_ffm.ffm_weights_len =
+            (1 << mi.ffm_bit_precision) + (mi.ffm_fields.len() as u32 * reg_ffm.ffm_k);
     }
 
     Ok(Box::new(reg_ffm))
 }
 
-#[inline(always)]
-unsafe fn hadd_ps(r4: __m128) -> f32 {
-    let r2 = _mm_add_ps(r4, _mm_movehl_ps(r4, r4));
-    // Add 2 lower values into the final result
-    let r1 = _mm_add_ss(r2, _mm_movehdup_ps(r2));
-    // Return the lowest lane of the result vector.
-    // The intrinsic below compiles into noop, modern compilers return floats in the lowest lane of xmm0 register.
-    _mm_cvtss_f32(r1)
+// --minibatch: one optimizer step per touched weight, with its gradients summed over the mini-batch
+unsafe fn apply_minibatch_gradients<L: OptimizerTrait>(
+    optimizer_ffm: &L,
+    weights: &mut [f32],
+    optimizer: &mut [OptimizerData<L>],
+    l2: f32,
+    weight_decay: f32,
+    gradients: &mut MinibatchGradients,
+    dirty: Option<&delta::DirtyChunks>,
+) {
+    gradients
+        .sparse
+        .sort_unstable_by_key(|(feature_index, _)| *feature_index);
+    let mut i = 0;
+    while i < gradients.sparse.len() {
+        let feature_index = gradients.sparse.get_unchecked(i).0 as usize;
+        let mut gradient = 0.0;
+        while i < gradients.sparse.len()
+            && gradients.sparse.get_unchecked(i).0 as usize == feature_index
+        {
+            gradient += gradients.sparse.get_unchecked(i).1;
+            i += 1;
+        }
+        let weight = weights.get_unchecked_mut(feature_index);
+        gradient += l2 * *weight;
+        let update = optimizer_ffm.calculate_update(
+            gradient,
+            &mut optimizer.get_unchecked_mut(feature_index).optimizer_data,
+        );
+        *weight -= update + weight_decay * *weight;
+        if let Some(dirty) = dirty {
+            dirty.mark(feature_index);
+        }
+    }
+    gradients.sparse.truncate(0);
+    gradients.examples = 0;
 }
 
 impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
     fn as_any(&mut self) -> &mut dyn Any {
-	self
+        self
     }
 
     #[inline(always)]
     fn forward_backward(
-	&mut self,
-	further_blocks: &mut [Box<dyn BlockTrait>],
-	fb: &feature_buffer::FeatureBuffer,
-	pb: &mut port_buffer::PortBuffer,
-	update: bool,
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
     ) {
-	debug_assert!(self.output_offset != usize::MAX);
-
-	unsafe {
-	    macro_rules! core_macro {
+        debug_assert!(self.output_offset != usize::MAX);
+        self.ensure_contra_fields(pb);
+
+        if update {
+            if let Some(schedule) = self.lr_schedule.as_ref() {
+                self.optimizer_ffm
+                    .set_learning_rate_multiplier(schedule.multiplier_at(fb.example_number));
+            }
+        }
+
+        unsafe {
+            macro_rules! core_macro {
 		(
 		$local_data_ffm_values:ident
 		) => {
@@ -140,6 +264,7 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
 
 		    let mut local_data_ffm_values = $local_data_ffm_values;
 
+		    let ffm_kernel = self.ffm_kernel;
 		    let ffm_weights = &mut self.weights;
 
 		    let ffmk: u32 = self.ffm_k;
@@ -150,7 +275,12 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
 
 		    let fc: usize = ffm_fields_count_as_usize * ffmk_as_usize;
 
-		    let mut contra_fields: [f32; FFM_CONTRA_BUF_LEN] = MaybeUninit::uninit().assume_init();
+		    let indexing = self.indexing;
+		    let field_stride = indexing.field_stride();
+		    // the embedding towards the next field is prefetched, the last one has no next
+		    let last_weight_index = self.ffm_weights_len as usize - 1;
+
+		    let contra_fields = &mut pb.ffm_contra_fields;
 
 		    /* first prepare two things:
 		       - transposed contra vectors in contra_fields -
@@ -160,7 +290,7 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
 			   - we will use these gradients later in backward pass
 		    */
 
-		    _mm_prefetch(mem::transmute::<&f32, &i8>(&contra_fields.get_unchecked(fb.ffm_buffer.get_unchecked(0).contra_field_index as usize)), _MM_HINT_T0);
+		    simd::prefetch(contra_fields.get_unchecked(fb.ffm_buffer.get_unchecked(0).contra_field_index as usize));
 		    let mut ffm_buffer_index = 0;
 		    for field_index in 0..ffm_fields_count {
 			let field_index_ffmk = field_index * ffmk;
@@ -181,34 +311,33 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
 
 			let mut is_first_feature = true;
 			while ffm_buffer_index < fb.ffm_buffer.len() && fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index == field_index_ffmk {
-			    _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(ffm_buffer_index + 1).hash as usize)), _MM_HINT_T0);
+			    // the last feature has no next one to prefetch
+			    if let Some(next_feature) = fb.ffm_buffer.get(ffm_buffer_index + 1) {
+				simd::prefetch(ffm_weights.get_unchecked(indexing.base(next_feature.hash)));
+			    }
 
 			    let feature = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
 			    let feature_value = feature.value as f32;
 
-			    let mut feature_index = feature.hash as usize;
+			    let mut feature_index = indexing.base(feature.hash);
 			    let mut offset: usize = field_index_ffmk as usize;
 
 			    if is_first_feature {
 				for _z in 0..ffm_fields_count_as_usize {
-				    _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(feature_index + ffmk_as_usize)), _MM_HINT_T0);
-				    for k in 0..ffmk_as_usize {
-					*contra_fields.get_unchecked_mut(offset + k) = ffm_weights.get_unchecked(feature_index + k) * feature_value;
-				    }
+				    simd::prefetch(ffm_weights.get_unchecked((feature_index + field_stride).min(last_weight_index)));
+				    accumulate_contra_field(ffm_kernel, contra_fields.as_mut_ptr().add(offset), ffm_weights.as_ptr().add(feature_index), ffmk_as_usize, feature_value, true);
 
 				    offset += fc;
-				    feature_index += ffmk_as_usize;
+				    feature_index += field_stride;
 				}
 				is_first_feature = false;
 			    } else {
 				for _z in 0..ffm_fields_count_as_usize {
-				    _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(feature_index + ffmk_as_usize)), _MM_HINT_T0);
-				    for k in 0..ffmk_as_usize {
-					*contra_fields.get_unchecked_mut(offset + k) += ffm_weights.get_unchecked(feature_index + k) * feature_value;
-				    }
+				    simd::prefetch(ffm_weights.get_unchecked((feature_index + field_stride).min(last_weight_index)));
+				    accumulate_contra_field(ffm_kernel, contra_fields.as_mut_ptr().add(offset), ffm_weights.as_ptr().add(feature_index), ffmk_as_usize, feature_value, false);
 
 				    offset += fc;
-				    feature_index += ffmk_as_usize;
+				    feature_index += field_stride;
 				}
 			    }
 
@@ -219,7 +348,7 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
 		    let mut ffm_values_offset = 0;
 		    for feature in &fb.ffm_buffer {
 			let feature_value = feature.value;
-			let feature_index = feature.hash as usize;
+			let mut feature_index = indexing.base(feature.hash);
 			let feature_contra_field_index = feature.contra_field_index as usize;
 
 			let contra_offset = feature_contra_field_index * ffm_fields_count_as_usize;
@@ -228,977 +357,1603 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
 
 			let mut vv = 0;
 			for z in 0..ffm_fields_count_as_usize {
-			    let mut correction = 0.0;
-
-			    let vv_feature_index = feature_index + vv;
 			    let vv_contra_offset = contra_offset + vv;
 
-			    if vv == feature_contra_field_index {
-				for k in 0..ffmk_as_usize {
-				    let ffm_weight = ffm_weights.get_unchecked(vv_feature_index + k);
-				    let contra_weight = *contra_fields.get_unchecked(vv_contra_offset + k) - ffm_weight * feature_value;
-				    let gradient = feature_value * contra_weight;
-				    *local_data_ffm_values.get_unchecked_mut(ffm_values_offset + k) = gradient;
-
-				    correction += ffm_weight * gradient;
-				}
-			    } else {
-				for k in 0..ffmk_as_usize {
-				    let contra_weight = *contra_fields.get_unchecked(vv_contra_offset + k);
-				    let gradient = feature_value * contra_weight;
-
-				    *local_data_ffm_values.get_unchecked_mut(ffm_values_offset + k) = gradient;
-
-				    let ffm_weight = ffm_weights.get_unchecked(vv_feature_index + k);
-				    correction += ffm_weight * gradient;
-				}
-			    }
+			    let correction = backward_correction(
+				ffm_kernel,
+				ffm_weights.as_ptr().add(feature_index),
+				contra_fields.as_ptr().add(vv_contra_offset),
+				local_data_ffm_values.as_mut_ptr().add(ffm_values_offset),
+				ffmk_as_usize,
+				feature_value,
+				vv == feature_contra_field_index,
+			    );
 
 			    *myslice.get_unchecked_mut(contra_offset2 + z) += correction * 0.5;
 			    vv += ffmk_as_usize;
+			    feature_index += field_stride;
 			    ffm_values_offset += ffmk_as_usize;
 			}
 		    }
 
+		    // --invariant: the gradients by the weights are the local values, the output gradient is the same for all
+		    if update {
+			if let Some(gradient) = pb.invariant_gradient {
+			    let mut local_index: usize = 0;
+			    for feature in &fb.ffm_buffer {
+				for z in 0..ffm_fields_count_as_usize {
+				    let mut feature_index = indexing.index(feature.hash, z);
+				    for _ in 0..ffmk_as_usize {
+					let local_value = *local_data_ffm_values.get_unchecked(local_index);
+					pb.pred_per_update += optimizer::step_size(&self.optimizer_ffm, gradient * local_value,
+					    &self.optimizer.get_unchecked(feature_index).optimizer_data) * local_value * local_value;
+					local_index += 1;
+					feature_index += 1;
+				    }
+				}
+			    }
+			}
+		    }
+
 		    block_helpers::forward_backward(further_blocks, fb, pb, update);
 
 		    if update {
 			let mut local_index: usize = 0;
 			let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
 
+			let clip_factor = if self.dp.is_some() || self.grad_clip > 0.0 {
+			    let mut gradient_norm_squared = 0.0;
+			    for feature in &fb.ffm_buffer {
+				let contra_offset = (feature.contra_field_index * ffm_fields_count) as usize / ffmk_as_usize;
+				for z in 0..ffm_fields_count_as_usize {
+				    let general_gradient = myslice.get_unchecked(contra_offset + z);
+				    for _ in 0.. ffmk_as_usize {
+					let gradient = general_gradient * *local_data_ffm_values.get_unchecked(local_index);
+					gradient_norm_squared += gradient * gradient;
+					local_index += 1;
+				    }
+				}
+			    }
+			    local_index = 0;
+			    let mut clip_factor = match self.dp.as_ref() {
+				Some(dp) => dp.clip_factor(gradient_norm_squared),
+				None => 1.0,
+			    };
+			    if self.grad_clip > 0.0 {
+				clip_factor = clip_factor.min(optimizer::clip_factor(self.grad_clip, gradient_norm_squared));
+			    }
+			    clip_factor
+			} else {
+			    1.0
+			};
+
+			let mut minibatch_gradients = if self.minibatch > 1 {
+			    Some(pb.minibatch_gradients.entry(self.output_offset).or_default())
+			} else {
+			    None
+			};
+
 			for feature in &fb.ffm_buffer {
-			    let mut feature_index = feature.hash as usize;
 			    let contra_offset = (feature.contra_field_index * ffm_fields_count) as usize / ffmk_as_usize;
 
 			    for z in 0..ffm_fields_count_as_usize {
 				let general_gradient = myslice.get_unchecked(contra_offset + z);
+				let mut feature_index = indexing.index(feature.hash, z);
 
 				for _ in 0.. ffmk_as_usize {
 				    let feature_value = *local_data_ffm_values.get_unchecked(local_index);
-				    let gradient = general_gradient * feature_value;
-				    let update = self.optimizer_ffm.calculate_update(gradient,
-					&mut self.optimizer.get_unchecked_mut(feature_index).optimizer_data);
-
-				    *ffm_weights.get_unchecked_mut(feature_index) -= update;
+				    let mut gradient = general_gradient * feature_value;
+				    match self.dp.as_mut() {
+					Some(dp) => gradient = dp.privatize(gradient, clip_factor),
+					None => gradient *= clip_factor,
+				    }
+				    if let Some(gradients) = minibatch_gradients.as_mut() {
+					gradients.sparse.push((feature_index as u32, gradient));
+				    } else {
+					gradient += self.l2 * *ffm_weights.get_unchecked(feature_index);
+					let update = self.optimizer_ffm.calculate_update(gradient,
+					    &mut self.optimizer.get_unchecked_mut(feature_index).optimizer_data);
+
+					let weight = ffm_weights.get_unchecked_mut(feature_index);
+					*weight -= update + self.weight_decay * *weight;
+					if let Some(dirty) = &self.dirty {
+					    dirty.mark(feature_index);
+					}
+				    }
 				    local_index += 1;
 				    feature_index += 1;
 				}
 			    }
 			}
+
+			if let Some(gradients) = minibatch_gradients {
+			    gradients.examples += 1;
+			    if gradients.examples >= self.minibatch {
+				apply_minibatch_gradients(&self.optimizer_ffm, ffm_weights, &mut self.optimizer, self.l2, self.weight_decay, gradients, self.dirty.as_ref());
+			    }
+			}
 		    }
-		    // The only exit point
-		    return
 		}
 	    } // End of macro
 
-	    let local_data_ffm_len =
-		fb.ffm_buffer.len() * (self.ffm_k * self.ffm_num_fields) as usize;
-	    if local_data_ffm_len < FFM_STACK_BUF_LEN {
-		// Fast-path - using on-stack data structures
-		let local_data_ffm_values: [f32; FFM_STACK_BUF_LEN as usize] =
-		    MaybeUninit::uninit().assume_init();
-		core_macro!(local_data_ffm_values);
-	    } else {
-		// Slow-path - using heap data structures
-		log::warn!("FFM data too large, allocating on the heap (slow path)!");
-		let _guard = self.mutex.lock().unwrap(); // following operations are not thread safe
-		if local_data_ffm_len > self.local_data_ffm_values.len() {
-		    self.local_data_ffm_values
-			.reserve(local_data_ffm_len - self.local_data_ffm_values.len() + 1024);
-		}
-		let local_data_ffm_values = &mut self.local_data_ffm_values;
-
-		core_macro!(local_data_ffm_values);
-	    }
-	}
+            // The gradient scratch is per port buffer, so hogwild workers never share it. It is taken out,
+            // since further blocks get the whole port buffer
+            let local_data_ffm_len =
+                fb.ffm_buffer.len() * (self.ffm_k * self.ffm_num_fields) as usize;
+            if local_data_ffm_len > pb.ffm_gradients.len() {
+                pb.ffm_gradients.resize(local_data_ffm_len, 0.0);
+            }
+            let mut ffm_gradients = mem::take(&mut pb.ffm_gradients);
+            let local_data_ffm_values = &mut ffm_gradients;
+            core_macro!(local_data_ffm_values);
+            pb.ffm_gradients = ffm_gradients;
+        }
     }
 
-    fn forward(
-	&self,
-	further_blocks: &[Box<dyn BlockTrait>],
-	fb: &feature_buffer::FeatureBuffer,
-	pb: &mut port_buffer::PortBuffer,
-    ) {
-	debug_assert!(self.output_offset != usize::MAX);
-
-	let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
-	let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
-	myslice.fill(0.0);
-
-	unsafe {
-	    let ffm_weights = &self.weights;
-	    _mm_prefetch(
-		mem::transmute::<&f32, &i8>(
-		    &ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(0).hash as usize),
-		),
-		_MM_HINT_T0,
-	    );
-
-	    /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
-	      We need to be careful to:
-	      - handle fields with zero features present
-	      - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
-	      - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
-	      - optimize for very common case of value of the feature being 1.0 - avoid multiplications
-	    */
-
-	    let ffmk: u32 = self.ffm_k;
-	    let ffmk_as_usize: usize = ffmk as usize;
-
-	    let ffm_fields_count: u32 = self.ffm_num_fields;
-	    let ffm_fields_count_as_usize: usize = ffm_fields_count as usize;
-	    let ffm_fields_count_plus_one = ffm_fields_count + 1;
-
-	    let field_embedding_len_as_usize = self.field_embedding_len as usize;
-	    let field_embedding_len_end =
-		field_embedding_len_as_usize - field_embedding_len_as_usize % STEP;
-
-	    let mut contra_fields: [f32; FFM_CONTRA_BUF_LEN] = MaybeUninit::uninit().assume_init();
-
-	    let mut ffm_buffer_index = 0;
-
-	    for field_index in 0..ffm_fields_count {
-		let field_index_ffmk = field_index * ffmk;
-		let field_index_ffmk_as_usize = field_index_ffmk as usize;
-		let offset = (field_index_ffmk * ffm_fields_count) as usize;
-		// first we handle fields with no features
-		if ffm_buffer_index >= fb.ffm_buffer.len()
-		    || fb
-			.ffm_buffer
-			.get_unchecked(ffm_buffer_index)
-			.contra_field_index
-			> field_index_ffmk
-		{
-		    // first feature of the field - just overwrite
-		    for z in (offset..offset + field_embedding_len_end).step_by(STEP) {
-			contra_fields
-			    .get_unchecked_mut(z..z + STEP)
-			    .copy_from_slice(&ZEROES);
-		    }
-
-		    for z in offset + field_embedding_len_end..offset + field_embedding_len_as_usize
-		    {
-			*contra_fields.get_unchecked_mut(z) = 0.0;
-		    }
-
-		    continue;
-		}
-
-		let ffm_index = (field_index * ffm_fields_count_plus_one) as usize;
-
-		let mut is_first_feature = true;
-		while ffm_buffer_index < fb.ffm_buffer.len()
-		    && fb
-			.ffm_buffer
-			.get_unchecked(ffm_buffer_index)
-			.contra_field_index
-			== field_index_ffmk
-		{
-		    _mm_prefetch(
-			mem::transmute::<&f32, &i8>(ffm_weights.get_unchecked(
-			    fb.ffm_buffer.get_unchecked(ffm_buffer_index + 1).hash as usize,
-			)),
-			_MM_HINT_T0,
-		    );
-		    let feature = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
-		    let feature_index = feature.hash as usize;
-		    let feature_value = feature.value;
-
-		    self.prepare_contra_fields(
-			feature,
-			contra_fields.as_mut_slice(),
-			ffm_weights,
-			offset,
-			field_embedding_len_as_usize,
-			&mut is_first_feature,
-		    );
-
-		    let feature_field_index = feature_index + field_index_ffmk_as_usize;
-
-		    let mut correction = 0.0;
-		    for k in feature_field_index..feature_field_index + ffmk_as_usize {
-			correction += ffm_weights.get_unchecked(k) * ffm_weights.get_unchecked(k);
-		    }
+    fn init_port_buffer(&self, pb: &mut port_buffer::PortBuffer) {
+        self.ensure_contra_fields(pb);
+        let gradients_len = self.field_embedding_len as usize * FFM_SCRATCH_FEATURES;
+        if pb.ffm_gradients.len() < gradients_len {
+            pb.ffm_gradients.resize(gradients_len, 0.0);
+        }
+    }
 
-		    *myslice.get_unchecked_mut(ffm_index) -=
-			correction * 0.5 * feature_value * feature_value;
+    fn prefetch(&self, fb: &feature_buffer::FeatureBuffer) {
+        for feature in fb.ffm_buffer.iter() {
+            if let Some(weight) = self.weights.get(self.indexing.base(feature.hash)) {
+                simd::prefetch(weight);
+            }
+        }
+    }
 
-		    ffm_buffer_index += 1;
-		}
-	    }
+    fn set_lr_schedule(&mut self, lr_schedule: &Option<model_instance::LRSchedule>) {
+        self.lr_schedule = lr_schedule.clone();
+    }
 
-	    self.calculate_interactions(
-		myslice,
-		contra_fields.as_slice(),
-		ffmk_as_usize,
-		ffm_fields_count_as_usize,
-		field_embedding_len_as_usize,
-	    );
-	}
+    fn apply_minibatch(&mut self, pb: &mut port_buffer::PortBuffer) {
+        if let Some(gradients) = pb.minibatch_gradients.get_mut(&self.output_offset) {
+            if gradients.examples > 0 {
+                unsafe {
+                    apply_minibatch_gradients(
+                        &self.optimizer_ffm,
+                        &mut self.weights,
+                        &mut self.optimizer,
+                        self.l2,
+                        self.weight_decay,
+                        gradients,
+                        self.dirty.as_ref(),
+                    );
+                }
+            }
+        }
+    }
 
-	block_helpers::forward(further_blocks, fb, pb);
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        debug_assert!(self.output_offset != usize::MAX);
+        self.ensure_contra_fields(pb);
+
+        let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
+        let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+        myslice.fill(0.0);
+
+        unsafe {
+            let ffm_weights = &self.weights;
+            simd::prefetch(
+                ffm_weights.get_unchecked(self.indexing.base(fb.ffm_buffer.get_unchecked(0).hash)),
+            );
+
+            /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
+              We need to be careful to:
+              - handle fields with zero features present
+              - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
+              - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
+              - optimize for very common case of value of the feature being 1.0 - avoid multiplications
+            */
+
+            let ffmk: u32 = self.ffm_k;
+            let ffmk_as_usize: usize = ffmk as usize;
+
+            let ffm_fields_count: u32 = self.ffm_num_fields;
+            let ffm_fields_count_as_usize: usize = ffm_fields_count as usize;
+            let ffm_fields_count_plus_one = ffm_fields_count + 1;
+
+            let field_embedding_len_as_usize = self.field_embedding_len as usize;
+            let field_embedding_len_end =
+                field_embedding_len_as_usize - field_embedding_len_as_usize % STEP;
+
+            let contra_fields = &mut pb.ffm_contra_fields;
+
+            let mut ffm_buffer_index = 0;
+
+            for field_index in 0..ffm_fields_count {
+                let field_index_ffmk = field_index * ffmk;
+                let offset = (field_index_ffmk * ffm_fields_count) as usize;
+                // first we handle fields with no features
+                if ffm_buffer_index >= fb.ffm_buffer.len()
+                    || fb
+                        .ffm_buffer
+                        .get_unchecked(ffm_buffer_index)
+                        .contra_field_index
+                        > field_index_ffmk
+                {
+                    // first feature of the field - just overwrite
+                    for z in (offset..offset + field_embedding_len_end).step_by(STEP) {
+                        contra_fields
+                            .get_unchecked_mut(z..z + STEP)
+                            .copy_from_slice(&ZEROES);
+                    }
+
+                    for z in offset + field_embedding_len_end..offset + field_embedding_len_as_usize
+                    {
+                        *contra_fields.get_unchecked_mut(z) = 0.0;
+                    }
+
+                    continue;
+                }
+
+                let ffm_index = (field_index * ffm_fields_count_plus_one) as usize;
+
+                let mut is_first_feature = true;
+                while ffm_buffer_index < fb.ffm_buffer.len()
+                    && fb
+                        .ffm_buffer
+                        .get_unchecked(ffm_buffer_index)
+                        .contra_field_index
+                        == field_index_ffmk
+                {
+                    if let Some(next_feature) = fb.ffm_buffer.get(ffm_buffer_index + 1) {
+                        simd::prefetch(
+                            ffm_weights.get_unchecked(self.indexing.base(next_feature.hash)),
+                        );
+                    }
+                    let feature = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
+                    let feature_value = feature.value;
+
+                    self.prepare_contra_fields(
+                        feature,
+                        contra_fields.as_mut_slice(),
+                        ffm_weights,
+                        offset,
+                        field_embedding_len_as_usize,
+                        &mut is_first_feature,
+                    );
+
+                    let feature_field_index =
+                        self.indexing.index(feature.hash, field_index as usize);
+
+                    let correction =
+                        self.self_correction(ffm_weights, feature_field_index, ffmk_as_usize);
+
+                    *myslice.get_unchecked_mut(ffm_index) -=
+                        correction * 0.5 * feature_value * feature_value;
+
+                    ffm_buffer_index += 1;
+                }
+            }
+
+            self.calculate_interactions(
+                myslice,
+                contra_fields.as_slice(),
+                ffmk_as_usize,
+                ffm_fields_count_as_usize,
+                field_embedding_len_as_usize,
+            );
+        }
+
+        block_helpers::forward(further_blocks, fb, pb);
     }
 
     fn forward_with_cache(
-	&self,
-	further_blocks: &[Box<dyn BlockTrait>],
-	fb: &FeatureBuffer,
-	pb: &mut PortBuffer,
-	caches: &[BlockCache],
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+        caches: &[BlockCache],
     ) {
-	debug_assert!(self.output_offset != usize::MAX);
-
-	let Some((next_cache, further_caches)) = caches.split_first() else {
-	    log::warn!("Expected caches, but non available, executing forward pass without cache");
-	    self.forward(further_blocks, fb, pb);
-	    return;
-	};
-
-	let BlockCache::FFM {
-	    contra_fields,
-	    features_present,
-	    ffm,
-	} = next_cache
-	else {
-	    log::warn!(
-		"Unable to downcast cache to BlockFFMCache, executing forward pass without cache"
-	    );
-	    self.forward(further_blocks, fb, pb);
-	    return;
-	};
-
-	unsafe {
-	    let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
-	    let ffm_slice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
-	    ptr::copy_nonoverlapping(ffm.as_ptr(), ffm_slice.as_mut_ptr(), num_outputs);
-
-	    let cached_contra_fields = contra_fields;
-
-	    let ffm_weights = &self.weights;
-	    _mm_prefetch(
-		mem::transmute::<&f32, &i8>(
-		    ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(0).hash as usize),
-		),
-		_MM_HINT_T0,
-	    );
-
-	    /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
-	      We need to be careful to:
-	      - handle fields with zero features present
-	      - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
-	      - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
-	      - optimize for very common case of value of the feature being 1.0 - avoid multiplications
-	    */
-
-	    let ffmk: u32 = self.ffm_k;
-	    let ffmk_as_usize: usize = ffmk as usize;
-
-	    let ffm_fields_count: u32 = self.ffm_num_fields;
-	    let ffm_fields_count_as_usize: usize = ffm_fields_count as usize;
-	    let ffm_fields_count_plus_one = ffm_fields_count + 1;
-
-	    let field_embedding_len_as_usize = self.field_embedding_len as usize;
-	    let field_embedding_len_end =
-		field_embedding_len_as_usize - field_embedding_len_as_usize % STEP;
-
-	    let mut contra_fields: [f32; FFM_CONTRA_BUF_LEN] = MaybeUninit::uninit().assume_init();
-
-	    let mut ffm_buffer_index = 0;
-
-	    for field_index in 0..ffm_fields_count {
-		let field_index_ffmk = field_index * ffmk;
-		let field_index_ffmk_as_usize = field_index_ffmk as usize;
-		let offset = field_index_ffmk_as_usize * ffm_fields_count_as_usize;
-		// first we handle fields with no features
-		if ffm_buffer_index >= fb.ffm_buffer.len()
-		    || fb
-			.ffm_buffer
-			.get_unchecked(ffm_buffer_index)
-			.contra_field_index
-			> field_index_ffmk
-		{
-		    // first feature of the field - just overwrite
-		    for z in (offset..offset + field_embedding_len_end).step_by(STEP) {
-			contra_fields
-			    .get_unchecked_mut(z..z + STEP)
-			    .copy_from_slice(&ZEROES);
-		    }
-
-		    for z in offset + field_embedding_len_end..offset + field_embedding_len_as_usize
-		    {
-			*contra_fields.get_unchecked_mut(z) = 0.0;
-		    }
-
-		    continue;
-		}
-
-		let ffm_index = (field_index * ffm_fields_count_plus_one) as usize;
-
-		let mut contra_fields_copied = false;
-		let mut is_first_feature = true;
-		while ffm_buffer_index < fb.ffm_buffer.len()
-		    && fb
-			.ffm_buffer
-			.get_unchecked(ffm_buffer_index)
-			.contra_field_index
-			== field_index_ffmk
-		{
-		    _mm_prefetch(
-			mem::transmute::<&f32, &i8>(ffm_weights.get_unchecked(
-			    fb.ffm_buffer.get_unchecked(ffm_buffer_index + 1).hash as usize,
-			)),
-			_MM_HINT_T0,
-		    );
-		    let feature = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
-
-		    let ffm_feature = feature.into();
-		    if features_present.contains(&ffm_feature) {
-			if is_first_feature {
-			    is_first_feature = false;
-			    contra_fields_copied = true;
-			    // Copy only once, skip other copying as the data for all features of that contra_index is already calculated
-			    ptr::copy_nonoverlapping(
-				cached_contra_fields.as_ptr().add(offset),
-				contra_fields.as_mut_ptr().add(offset),
-				field_embedding_len_as_usize,
-			    );
-			} else if !contra_fields_copied {
-			    contra_fields_copied = true;
-
-			    const LANES: usize = STEP * 4;
-			    let field_embedding_len_end = field_embedding_len_as_usize
-				- (field_embedding_len_as_usize % LANES);
-
-			    let mut contra_fields_ptr = contra_fields.as_mut_ptr().add(offset);
-			    let mut cached_contra_fields_ptr =
-				cached_contra_fields.as_ptr().add(offset);
-
-			    for _ in (0..field_embedding_len_end).step_by(LANES) {
-				add_cached_contra_field(
-				    contra_fields_ptr,
-				    cached_contra_fields_ptr,
-				);
-				contra_fields_ptr = contra_fields_ptr.add(STEP);
-				cached_contra_fields_ptr = cached_contra_fields_ptr.add(STEP);
-
-				add_cached_contra_field(
-				    contra_fields_ptr,
-				    cached_contra_fields_ptr,
-				);
-				contra_fields_ptr = contra_fields_ptr.add(STEP);
-				cached_contra_fields_ptr = cached_contra_fields_ptr.add(STEP);
-
-				add_cached_contra_field(
-				    contra_fields_ptr,
-				    cached_contra_fields_ptr,
-				);
-				contra_fields_ptr = contra_fields_ptr.add(STEP);
-				cached_contra_fields_ptr = cached_contra_fields_ptr.add(STEP);
-
-				add_cached_contra_field(
-				    contra_fields_ptr,
-				    cached_contra_fields_ptr,
-				);
-				contra_fields_ptr = contra_fields_ptr.add(STEP);
-				cached_contra_fields_ptr = cached_contra_fields_ptr.add(STEP);
-			    }
-
-			    for z in field_embedding_len_end..field_embedding_len_as_usize {
-				*contra_fields.get_unchecked_mut(offset + z) +=
-				    cached_contra_fields.get_unchecked(offset + z);
-			    }
-			}
-		    } else {
-			let feature_index = feature.hash as usize;
-			let feature_value = feature.value;
-
-			self.prepare_contra_fields(
-			    feature,
-			    contra_fields.as_mut_slice(),
-			    ffm_weights,
-			    offset,
-			    field_embedding_len_as_usize,
-			    &mut is_first_feature,
-			);
-
-			let feature_field_index = feature_index + field_index_ffmk_as_usize;
-
-			let mut correction = 0.0;
-			for k in feature_field_index..feature_field_index + ffmk_as_usize {
-			    correction +=
-				ffm_weights.get_unchecked(k) * ffm_weights.get_unchecked(k);
-			}
-
-			*ffm_slice.get_unchecked_mut(ffm_index) -=
-			    correction * 0.5 * feature_value * feature_value;
-		    }
-		    ffm_buffer_index += 1;
-		}
-	    }
-
-	    self.calculate_interactions(
-		ffm_slice,
-		contra_fields.as_slice(),
-		ffmk_as_usize,
-		ffm_fields_count_as_usize,
-		field_embedding_len_as_usize,
-	    );
-	}
-	block_helpers::forward_with_cache(further_blocks, fb, pb, further_caches);
+        debug_assert!(self.output_offset != usize::MAX);
+        self.ensure_contra_fields(pb);
+
+        let Some((next_cache, further_caches)) = caches.split_first() else {
+            log::warn!("Expected caches, but non available, executing forward pass without cache");
+            self.forward(further_blocks, fb, pb);
+            return;
+        };
+
+        let BlockCache::FFM {
+            contra_fields,
+            features_present,
+            ffm,
+        } = next_cache
+        else {
+            log::warn!(
+                "Unable to downcast cache to BlockFFMCache, executing forward pass without cache"
+            );
+            self.forward(further_blocks, fb, pb);
+            return;
+        };
+
+        unsafe {
+            let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
+            let ffm_slice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+            ptr::copy_nonoverlapping(ffm.as_ptr(), ffm_slice.as_mut_ptr(), num_outputs);
+
+            let cached_contra_fields = contra_fields;
+
+            let ffm_weights = &self.weights;
+            simd::prefetch(
+                ffm_weights.get_unchecked(self.indexing.base(fb.ffm_buffer.get_unchecked(0).hash)),
+            );
+
+            /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
+              We need to be careful to:
+              - handle fields with zero features present
+              - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
+              - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
+              - optimize for very common case of value of the feature being 1.0 - avoid multiplications
+            */
+
+            let ffmk: u32 = self.ffm_k;
+            let ffmk_as_usize: usize = ffmk as usize;
+
+            let ffm_fields_count: u32 = self.ffm_num_fields;
+            let ffm_fields_count_as_usize: usize = ffm_fields_count as usize;
+            let ffm_fields_count_plus_one = ffm_fields_count + 1;
+
+            let field_embedding_len_as_usize = self.field_embedding_len as usize;
+            let field_embedding_len_end =
+                field_embedding_len_as_usize - field_embedding_len_as_usize % STEP;
+
+            let contra_fields = &mut pb.ffm_contra_fields;
+
+            let mut ffm_buffer_index = 0;
+
+            for field_index in 0..ffm_fields_count {
+                let field_index_ffmk = field_index * ffmk;
+                let field_index_ffmk_as_usize = field_index_ffmk as usize;
+                let offset = field_index_ffmk_as_usize * ffm_fields_count_as_usize;
+                // first we handle fields with no features
+                if ffm_buffer_index >= fb.ffm_buffer.len()
+                    || fb
+                        .ffm_buffer
+                        .get_unchecked(ffm_buffer_index)
+                        .contra_field_index
+                        > field_index_ffmk
+                {
+                    // first feature of the field - just overwrite
+                    for z in (offset..offset + field_embedding_len_end).step_by(STEP) {
+                        contra_fields
+                            .get_unchecked_mut(z..z + STEP)
+                            .copy_from_slice(&ZEROES);
+                    }
+
+                    for z in offset + field_embedding_len_end..offset + field_embedding_len_as_usize
+                    {
+                        *contra_fields.get_unchecked_mut(z) = 0.0;
+                    }
+
+                    continue;
+                }
+
+                let ffm_index = (field_index * ffm_fields_count_plus_one) as usize;
+
+                let mut contra_fields_copied = false;
+                let mut is_first_feature = true;
+                while ffm_buffer_index < fb.ffm_buffer.len()
+                    && fb
+                        .ffm_buffer
+                        .get_unchecked(ffm_buffer_index)
+                        .contra_field_index
+                        == field_index_ffmk
+                {
+                    if let Some(next_feature) = fb.ffm_buffer.get(ffm_buffer_index + 1) {
+                        simd::prefetch(
+                            ffm_weights.get_unchecked(self.indexing.base(next_feature.hash)),
+                        );
+                    }
+                    let feature = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
+
+                    let ffm_feature = feature.into();
+                    if features_present.contains(&ffm_feature) {
+                        if is_first_feature {
+                            is_first_feature = false;
+                            contra_fields_copied = true;
+                            // Copy only once, skip other copying as the data for all features of that contra_index is already calculated
+                            ptr::copy_nonoverlapping(
+                                cached_contra_fields.as_ptr().add(offset),
+                                contra_fields.as_mut_ptr().add(offset),
+                                field_embedding_len_as_usize,
+                            );
+                        } else if !contra_fields_copied {
+                            contra_fields_copied = true;
+
+                            const LANES: usize = STEP * 4;
+                            let field_embedding_len_end = field_embedding_len_as_usize
+                                - (field_embedding_len_as_usize % LANES);
+
+                            let mut contra_fields_ptr = contra_fields.as_mut_ptr().add(offset);
+                            let mut cached_contra_fields_ptr =
+                                cached_contra_fields.as_ptr().add(offset);
+
+                            for _ in (0..field_embedding_len_end).step_by(LANES) {
+                                add_cached_contra_field(
+                                    contra_fields_ptr,
+                                    cached_contra_fields_ptr,
+                                );
+                                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                                cached_contra_fields_ptr = cached_contra_fields_ptr.add(STEP);
+
+                                add_cached_contra_field(
+                                    contra_fields_ptr,
+                                    cached_contra_fields_ptr,
+                                );
+                                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                                cached_contra_fields_ptr = cached_contra_fields_ptr.add(STEP);
+
+                                add_cached_contra_field(
+                                    contra_fields_ptr,
+                                    cached_contra_fields_ptr,
+                                );
+                                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                                cached_contra_fields_ptr = cached_contra_fields_ptr.add(STEP);
+
+                                add_cached_contra_field(
+                                    contra_fields_ptr,
+                                    cached_contra_fields_ptr,
+                                );
+                                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                                cached_contra_fields_ptr = cached_contra_fields_ptr.add(STEP);
+                            }
+
+                            for z in field_embedding_len_end..field_embedding_len_as_usize {
+                                *contra_fields.get_unchecked_mut(offset + z) +=
+                                    cached_contra_fields.get_unchecked(offset + z);
+                            }
+                        }
+                    } else {
+                        let feature_value = feature.value;
+
+                        self.prepare_contra_fields(
+                            feature,
+                            contra_fields.as_mut_slice(),
+                            ffm_weights,
+                            offset,
+                            field_embedding_len_as_usize,
+                            &mut is_first_feature,
+                        );
+
+                        let feature_field_index =
+                            self.indexing.index(feature.hash, field_index as usize);
+
+                        let correction =
+                            self.self_correction(ffm_weights, feature_field_index, ffmk_as_usize);
+
+                        *ffm_slice.get_unchecked_mut(ffm_index) -=
+                            correction * 0.5 * feature_value * feature_value;
+                    }
+                    ffm_buffer_index += 1;
+                }
+            }
+
+            self.calculate_interactions(
+                ffm_slice,
+                contra_fields.as_slice(),
+                ffmk_as_usize,
+                ffm_fields_count_as_usize,
+                field_embedding_len_as_usize,
+            );
+        }
+        block_helpers::forward_with_cache(further_blocks, fb, pb, further_caches);
     }
 
     fn create_forward_cache(
-	&mut self,
-	further_blocks: &mut [Box<dyn BlockTrait>],
-	caches: &mut Vec<BlockCache>,
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        caches: &mut Vec<BlockCache>,
     ) {
-	unsafe {
-	    caches.push(BlockCache::FFM {
-		contra_fields: MaybeUninit::uninit().assume_init(),
-		features_present: FxHashSet::default(),
-		ffm: vec![0.0; (self.ffm_num_fields * self.ffm_num_fields) as usize],
-	    });
-	}
+        caches.push(BlockCache::FFM {
+            contra_fields: vec![0.0; self.contra_fields_len()],
+            features_present: FxHashSet::default(),
+            ffm: vec![0.0; (self.ffm_num_fields * self.ffm_num_fields) as usize],
+        });
 
-	block_helpers::create_forward_cache(further_blocks, caches);
+        block_helpers::create_forward_cache(further_blocks, caches);
     }
 
     fn prepare_forward_cache(
-	&mut self,
-	further_blocks: &mut [Box<dyn BlockTrait>],
-	fb: &feature_buffer::FeatureBuffer,
-	caches: &mut [BlockCache],
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        caches: &mut [BlockCache],
     ) {
-	let Some((next_cache, further_caches)) = caches.split_first_mut() else {
-	    log::warn!(
-		"Expected BlockFFMCache caches, but non available, skipping cache preparation"
-	    );
-	    return;
-	};
-
-	let BlockCache::FFM {
-	    contra_fields,
-	    features_present,
-	    ffm,
-	} = next_cache
-	else {
-	    log::warn!("Unable to downcast cache to BlockFFMCache, skipping cache preparation");
-	    return;
-	};
-
-	unsafe {
-	    let ffm_slice = ffm.as_mut_slice();
-	    ffm_slice.fill(0.0);
-
-	    features_present.clear();
-
-	    let ffm_weights = &self.weights;
-	    _mm_prefetch(
-		mem::transmute::<&f32, &i8>(
-		    ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(0).hash as usize),
-		),
-		_MM_HINT_T0,
-	    );
-
-	    /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
-	      We need to be careful to:
-	      - handle fields with zero features present
-	      - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
-	      - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
-	      - optimize for very common case of value of the feature being 1.0 - avoid multiplications
-	    */
-
-	    let ffmk: u32 = self.ffm_k;
-	    let ffmk_as_usize: usize = ffmk as usize;
-
-	    let ffm_fields_count: u32 = self.ffm_num_fields;
-	    let ffm_fields_count_plus_one = ffm_fields_count + 1;
-
-	    let field_embedding_len_as_usize = self.field_embedding_len as usize;
-
-	    let mut ffm_buffer_index = 0;
-
-	    for field_index in 0..ffm_fields_count {
-		let field_index_ffmk = field_index * ffmk;
-		let field_index_ffmk_as_usize = field_index_ffmk as usize;
-		let offset = (field_index_ffmk * ffm_fields_count) as usize;
-		// first we handle fields with no features
-		if ffm_buffer_index >= fb.ffm_buffer.len()
-		    || fb
-			.ffm_buffer
-			.get_unchecked(ffm_buffer_index)
-			.contra_field_index
-			> field_index_ffmk
-		{
-		    continue;
-		}
+        let Some((next_cache, further_caches)) = caches.split_first_mut() else {
+            log::warn!(
+                "Expected BlockFFMCache caches, but non available, skipping cache preparation"
+            );
+            return;
+        };
+
+        let BlockCache::FFM {
+            contra_fields,
+            features_present,
+            ffm,
+        } = next_cache
+        else {
+            log::warn!("Unable to downcast cache to BlockFFMCache, skipping cache preparation");
+            return;
+        };
+
+        unsafe {
+            let ffm_slice = ffm.as_mut_slice();
+            ffm_slice.fill(0.0);
+
+            features_present.clear();
+
+            let ffm_weights = &self.weights;
+            simd::prefetch(
+                ffm_weights.get_unchecked(self.indexing.base(fb.ffm_buffer.get_unchecked(0).hash)),
+            );
+
+            /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
+              We need to be careful to:
+              - handle fields with zero features present
+              - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
+              - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
+              - optimize for very common case of value of the feature being 1.0 - avoid multiplications
+            */
+
+            let ffmk: u32 = self.ffm_k;
+            let ffmk_as_usize: usize = ffmk as usize;
+
+            let ffm_fields_count: u32 = self.ffm_num_fields;
+            let ffm_fields_count_plus_one = ffm_fields_count + 1;
+
+            let field_embedding_len_as_usize = self.field_embedding_len as usize;
+
+            let mut ffm_buffer_index = 0;
+
+            for field_index in 0..ffm_fields_count {
+                let field_index_ffmk = field_index * ffmk;
+                let offset = (field_index_ffmk * ffm_fields_count) as usize;
+                // first we handle fields with no features
+                if ffm_buffer_index >= fb.ffm_buffer.len()
+                    || fb
+                        .ffm_buffer
+                        .get_unchecked(ffm_buffer_index)
+                        .contra_field_index
+                        > field_index_ffmk
+                {
+                    continue;
+                }
+
+                let ffm_index = (field_index * ffm_fields_count_plus_one) as usize;
+
+                let mut is_first_feature = true;
+                while ffm_buffer_index < fb.ffm_buffer.len()
+                    && fb
+                        .ffm_buffer
+                        .get_unchecked(ffm_buffer_index)
+                        .contra_field_index
+                        == field_index_ffmk
+                {
+                    if let Some(next_feature) = fb.ffm_buffer.get(ffm_buffer_index + 1) {
+                        simd::prefetch(
+                            ffm_weights.get_unchecked(self.indexing.base(next_feature.hash)),
+                        );
+                    }
+                    let feature = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
+                    features_present.insert(feature.into());
+                    let feature_value = feature.value;
+
+                    self.prepare_contra_fields(
+                        feature,
+                        contra_fields.as_mut_slice(),
+                        ffm_weights,
+                        offset,
+                        field_embedding_len_as_usize,
+                        &mut is_first_feature,
+                    );
+
+                    let feature_field_index =
+                        self.indexing.index(feature.hash, field_index as usize);
+
+                    let correction =
+                        self.self_correction(ffm_weights, feature_field_index, ffmk_as_usize);
+
+                    *ffm_slice.get_unchecked_mut(ffm_index) -=
+                        correction * 0.5 * feature_value * feature_value;
+
+                    ffm_buffer_index += 1;
+                }
+            }
+        }
+
+        block_helpers::prepare_forward_cache(further_blocks, fb, further_caches);
+    }
 
-		let ffm_index = (field_index * ffm_fields_count_plus_one) as usize;
-
-		let mut is_first_feature = true;
-		while ffm_buffer_index < fb.ffm_buffer.len()
-		    && fb
-			.ffm_buffer
-			.get_unchecked(ffm_buffer_index)
-			.contra_field_index
-			== field_index_ffmk
-		{
-		    _mm_prefetch(
-			mem::transmute::<&f32, &i8>(ffm_weights.get_unchecked(
-			    fb.ffm_buffer.get_unchecked(ffm_buffer_index + 1).hash as usize,
-			)),
-			_MM_HINT_T0,
-		    );
-		    let feature = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
-		    features_present.insert(feature.into());
-		    let feature_index = feature.hash as usize;
-		    let feature_value = feature.value;
-
-		    self.prepare_contra_fields(
-			feature,
-			contra_fields.as_mut_slice(),
-			ffm_weights,
-			offset,
-			field_embedding_len_as_usize,
-			&mut is_first_feature,
-		    );
-
-		    let feature_field_index = feature_index + field_index_ffmk_as_usize;
-
-		    let mut correction = 0.0;
-		    for k in feature_field_index..feature_field_index + ffmk_as_usize {
-			correction += ffm_weights.get_unchecked(k) * ffm_weights.get_unchecked(k);
-		    }
+    fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
+        self.weights = vec![0.0; self.ffm_weights_len as usize].into();
+        self.optimizer = vec![
+            OptimizerData::<L> {
+                optimizer_data: self.optimizer_ffm.initial_data(),
+            };
+            self.ffm_weights_len as usize
+        ];
+
+        let mut init_rng =
+            rng::new_stream(mi.seed, rng::RngStream::WeightsInit, rng::FFM_STREAM_INDEX);
+        match mi.ffm_initialization_type.as_str() {
+            "default" => {
+                if mi.ffm_k > 0 {
+                    if mi.ffm_init_width == 0.0 {
+                        // Initialization that has showed to work ok for us, like in ffm.pdf, but centered around zero and further divided by 50
+                        let ffm_one_over_k_root = 1.0 / (self.ffm_k as f32).sqrt() / 50.0;
+                        for i in 0..self.ffm_weights_len {
+                            self.weights[i as usize] =
+                                (init_rng.gen::<f32>() - 0.5) * ffm_one_over_k_root;
+                            self.optimizer[i as usize].optimizer_data =
+                                self.optimizer_ffm.initial_data();
+                        }
+                    } else {
+                        let zero_half_band_width = mi.ffm_init_width * mi.ffm_init_zero_band * 0.5;
+                        let band_width = mi.ffm_init_width * (1.0 - mi.ffm_init_zero_band);
+                        for i in 0..self.ffm_weights_len {
+                            let mut w = init_rng.gen::<f32>() * band_width - band_width * 0.5;
+                            if w > 0.0 {
+                                w += zero_half_band_width;
+                            } else {
+                                w -= zero_half_band_width;
+                            }
+                            w += mi.ffm_init_center;
+                            self.weights[i as usize] = w;
+                            self.optimizer[i as usize].optimizer_data =
+                                self.optimizer_ffm.initial_data();
+                        }
+                    }
+                }
+            }
+            _ => {
+                panic!("Please select a valid activation function.")
+            }
+        }
+    }
 
-		    *ffm_slice.get_unchecked_mut(ffm_index) -=
-			correction * 0.5 * feature_value * feature_value;
+    fn get_serialized_len(&self) -> usize {
+        self.ffm_weights_len as usize
+    }
 
-		    ffm_buffer_index += 1;
-		}
-	    }
-	}
+    fn get_serialized_block_id(&self) -> u32 {
+        regressor::SERIALIZED_BLOCK_ID_FFM
+    }
 
-	block_helpers::prepare_forward_cache(further_blocks, fb, further_caches);
+    fn track_dirty_weights(&mut self) {
+        self.dirty = Some(delta::DirtyChunks::new(self.weights.len()));
     }
 
-    fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
-	self.weights = vec![0.0; self.ffm_weights_len as usize];
-	self.optimizer = vec![
-	    OptimizerData::<L> {
-		optimizer_data: self.optimizer_ffm.initial_data(),
-	    };
-	    self.ffm_weights_len as usize
-	];
-
-	match mi.ffm_initialization_type.as_str() {
-	    "default" => {
-		if mi.ffm_k > 0 {
-		    if mi.ffm_init_width == 0.0 {
-			// Initialization that has showed to work ok for us, like in ffm.pdf, but centered around zero and further divided by 50
-			let ffm_one_over_k_root = 1.0 / (self.ffm_k as f32).sqrt() / 50.0;
-			for i in 0..self.ffm_weights_len {
-			    self.weights[i as usize] = (1.0
-				* merand48((self.ffm_weights_len as usize + i as usize) as u64)
-				- 0.5)
-				* ffm_one_over_k_root;
-			    self.optimizer[i as usize].optimizer_data =
-				self.optimizer_ffm.initial_data();
-			}
-		    } else {
-			let zero_half_band_width = mi.ffm_init_width * mi.ffm_init_zero_band * 0.5;
-			let band_width = mi.ffm_init_width * (1.0 - mi.ffm_init_zero_band);
-			for i in 0..self.ffm_weights_len {
-			    let mut w = merand48(i as u64) * band_width - band_width * 0.5;
-			    if w > 0.0 {
-				w += zero_half_band_width;
-			    } else {
-				w -= zero_half_band_width;
-			    }
-			    w += mi.ffm_init_center;
-			    self.weights[i as usize] = w;
-			    self.optimizer[i as usize].optimizer_data =
-				self.optimizer_ffm.initial_data();
-			}
-		    }
-		}
-	    }
-	    _ => {
-		panic!("Please select a valid activation function.")
-	    }
-	}
+    // The optimizer state follows the weights, and is dirty where they are
+    fn dirty_weight_ranges(&self) -> Option<Vec<Range<usize>>> {
+        self.dirty.as_ref().map(|dirty| {
+            let num_weights = self.weights.len();
+            let mut ranges = dirty.byte_ranges(0, mem::size_of::<f32>(), num_weights);
+            ranges.extend(dirty.byte_ranges(
+                num_weights * mem::size_of::<f32>(),
+                mem::size_of::<OptimizerData<L>>(),
+                num_weights,
+            ));
+            ranges
+        })
     }
 
-    fn get_serialized_len(&self) -> usize {
-	self.ffm_weights_len as usize
+    fn map_weights(&mut self, dir: &str) -> Result<(), Box<dyn Error>> {
+        self.weights.map_through_file(dir)
     }
 
     fn write_weights_to_buf(
-	&self,
-	output_bufwriter: &mut dyn io::Write,
-	use_quantization: bool,
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+        use_quantization: bool,
     ) -> Result<(), Box<dyn Error>> {
-	if use_quantization {
-	    let quantized_weights = quantization::quantize_ffm_weights(&self.weights);
-	    block_helpers::write_weights_to_buf(&quantized_weights, output_bufwriter, false)?;
-	} else {
-	    block_helpers::write_weights_to_buf(&self.weights, output_bufwriter, false)?;
-	}
-	block_helpers::write_weights_to_buf(&self.optimizer, output_bufwriter, false)?;
-	Ok(())
+        if use_quantization {
+            quantization::write_quantized_weights(
+                self.quantization,
+                &self.weights,
+                output_bufwriter,
+            )?;
+        } else {
+            block_helpers::write_weights_to_buf(&self.weights, output_bufwriter, false)?;
+        }
+        block_helpers::write_weights_to_buf(&self.optimizer, output_bufwriter, false)?;
+        Ok(())
     }
 
     fn read_weights_from_buf(
-	&mut self,
-	input_bufreader: &mut dyn io::Read,
-	use_quantization: bool,
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+        use_quantization: bool,
     ) -> Result<(), Box<dyn Error>> {
-	if use_quantization {
-	    quantization::dequantize_ffm_weights(input_bufreader, &mut self.weights);
-	} else {
-	    block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader, false)?;
-	}
-
-	block_helpers::read_weights_from_buf(&mut self.optimizer, input_bufreader, false)?;
-	Ok(())
+        if use_quantization {
+            quantization::read_quantized_weights(
+                self.quantization,
+                input_bufreader,
+                &mut self.weights,
+            )?;
+        } else {
+            block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader, false)?;
+        }
+
+        block_helpers::read_weights_from_buf(&mut self.optimizer, input_bufreader, false)?;
+        Ok(())
     }
 
     fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
-	assert_eq!(output.get_output_index(), 0);
-	(self.ffm_num_fields * self.ffm_num_fields) as usize
+        assert_eq!(output.get_output_index(), 0);
+        (self.ffm_num_fields * self.ffm_num_fields) as usize
     }
 
     fn set_input_offset(&mut self, _input: graph::InputSlot, _offset: usize) {
-	panic!("You cannot set_input_offset() for BlockFFM");
+        panic!("You cannot set_input_offset() for BlockFFM");
     }
 
     fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
-	assert_eq!(output.get_output_index(), 0);
-	self.output_offset = offset;
+        assert_eq!(output.get_output_index(), 0);
+        self.output_offset = offset;
     }
 
     fn read_weights_from_buf_into_forward_only(
-	&self,
-	input_bufreader: &mut dyn io::Read,
-	forward: &mut Box<dyn BlockTrait>,
-	use_quantization: bool,
+        &self,
+        input_bufreader: &mut dyn io::Read,
+        forward: &mut Box<dyn BlockTrait>,
+        use_quantization: bool,
     ) -> Result<(), Box<dyn Error>> {
-	let forward = forward
-	    .as_any()
-	    .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
-	    .unwrap();
-
-	if use_quantization {
-	    quantization::dequantize_ffm_weights(input_bufreader, &mut forward.weights);
-	} else {
-	    block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader, false)?;
-	}
-	block_helpers::skip_weights_from_buf::<OptimizerData<L>>(
-	    self.ffm_weights_len as usize,
-	    input_bufreader,
-	)?;
-	Ok(())
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+
+        forward.weights.overwrite(|weights| {
+            if use_quantization {
+                quantization::read_quantized_weights(self.quantization, input_bufreader, weights)
+            } else {
+                block_helpers::read_weights_from_buf(weights, input_bufreader, false)
+            }
+        })?;
+        block_helpers::skip_weights_from_buf::<OptimizerData<L>>(
+            self.ffm_weights_len as usize,
+            input_bufreader,
+        )?;
+        Ok(())
+    }
+
+    fn get_output_decomposition<'a>(
+        &self,
+        pb: &'a port_buffer::PortBuffer,
+    ) -> Option<regressor::OutputDecomposition<'a>> {
+        let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
+        Some(regressor::OutputDecomposition::FFMFields(
+            &pb.tape[self.output_offset..(self.output_offset + num_outputs)],
+        ))
+    }
+
+    // The same fields x fields halves of interactions as forward(), from matrix products of the
+    // embeddings of all features instead of the contra fields loop
+    fn to_onnx(&self, graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        let fields = self.ffm_num_fields as i64;
+        let k = self.ffm_k as i64;
+        let weights = graph.initializer_f32(
+            "ffm_weights",
+            vec![self.weights.len() as i64],
+            &self.weights,
+        );
+        let hash = graph.input("ffm_hash", onnx::ONNX_INT64);
+        let value = graph.input("ffm_value", onnx::ONNX_FLOAT);
+        let field = graph.input("ffm_field", onnx::ONNX_INT64);
+
+        // Embeddings of features against all fields times feature values, features x (fields * k)
+        let column = graph.initializer_i64("column", vec![2], &[-1, 1]);
+        let indexing = self.indexing;
+        let embedding_range: Vec<i64> = (0..fields * k)
+            .map(|i| indexing.index(0, (i / k) as usize) as i64 + i % k)
+            .collect();
+        let embedding_range =
+            graph.initializer_i64("embedding_range", vec![fields * k], &embedding_range);
+        let mut hash_column = graph.node("Reshape", &[&hash, &column], Vec::new());
+        if indexing.shift > 0 {
+            // Field-major, the hash shifted and aligned to the embedding again
+            let alignment = (!indexing.mask as i64) + 1;
+            let divisor =
+                graph.initializer_i64("row_divisor", Vec::new(), &[alignment << indexing.shift]);
+            let alignment = graph.initializer_i64("row_alignment", Vec::new(), &[alignment]);
+            let rows = graph.node("Div", &[&hash_column, &divisor], Vec::new());
+            hash_column = graph.node("Mul", &[&rows, &alignment], Vec::new());
+        }
+        let indexes = graph.node("Add", &[&hash_column, &embedding_range], Vec::new());
+        let embeddings = graph.node("Gather", &[&weights, &indexes], Vec::new());
+        let value_column = graph.node("Reshape", &[&value, &column], Vec::new());
+        let embeddings = graph.node("Mul", &[&embeddings, &value_column], Vec::new());
+
+        // Contra fields are the embeddings summed by the field of the features, fields x fields x k
+        let depth = graph.initializer_i64("depth", vec![1], &[fields]);
+        let one_hot_values = graph.initializer_f32("one_hot_values", vec![2], &[0.0, 1.0]);
+        let one_hot = graph.node("OneHot", &[&field, &depth, &one_hot_values], Vec::new());
+        let one_hot_t = graph.node(
+            "Transpose",
+            &[&one_hot],
+            vec![onnx::OnnxAttribute::Ints("perm", vec![1, 0])],
+        );
+        let contra_fields = graph.node("MatMul", &[&one_hot_t, &embeddings], Vec::new());
+        let cube = graph.initializer_i64("cube", vec![3], &[fields, fields, k]);
+        let contra_fields = graph.node("Reshape", &[&contra_fields, &cube], Vec::new());
+        let contra_fields_t = graph.node(
+            "Transpose",
+            &[&contra_fields],
+            vec![onnx::OnnxAttribute::Ints("perm", vec![1, 0, 2])],
+        );
+        let products = graph.node("Mul", &[&contra_fields, &contra_fields_t], Vec::new());
+        let last_axis = graph.initializer_i64("last_axis", vec![1], &[2]);
+        let keepdims = || vec![onnx::OnnxAttribute::Int("keepdims", 0)];
+        let interactions = graph.node("ReduceSum", &[&products, &last_axis], keepdims());
+
+        // Interactions of features with themselves are on the diagonal, they are subtracted
+        let per_feature = graph.initializer_i64("per_feature", vec![3], &[-1, fields, k]);
+        let embeddings = graph.node("Reshape", &[&embeddings, &per_feature], Vec::new());
+        let squares = graph.node("Mul", &[&embeddings, &embeddings], Vec::new());
+        let squares = graph.node("ReduceSum", &[&squares, &last_axis], keepdims());
+        let own_squares = graph.node("Mul", &[&squares, &one_hot], Vec::new());
+        let corrections = graph.node("MatMul", &[&one_hot_t, &own_squares], Vec::new());
+        let interactions = graph.node("Sub", &[&interactions, &corrections], Vec::new());
+
+        let half = graph.initializer_f32("half", Vec::new(), &[0.5]);
+        let interactions = graph.node("Mul", &[&interactions, &half], Vec::new());
+        let flat = graph.initializer_i64("flat", vec![1], &[fields * fields]);
+        let output = graph.node("Reshape", &[&interactions, &flat], Vec::new());
+        graph.set_tape(self.output_offset, (fields * fields) as usize, &output);
+        Ok(())
     }
 }
 
+// Adds len weights times feature_value to contra fields, or stores them for the first feature of a field
 #[inline(always)]
-unsafe fn add_cached_contra_field(
+unsafe fn accumulate_contra_field(
+    ffm_kernel: FFMKernel,
     contra_fields_ptr: *mut f32,
-    cached_contra_fields_ptr: *const f32,
+    ffm_weights_ptr: *const f32,
+    len: usize,
+    feature_value: f32,
+    is_first_feature: bool,
 ) {
-    let contra_fields = _mm_loadu_ps(contra_fields_ptr);
-    let cached_contra_fields = _mm_loadu_ps(cached_contra_fields_ptr);
-    _mm_storeu_ps(
-	contra_fields_ptr,
-	_mm_add_ps(cached_contra_fields, contra_fields),
-    );
+    match ffm_kernel {
+        #[cfg(target_arch = "x86_64")]
+        FFMKernel::Avx512 => accumulate_contra_field_avx512(
+            contra_fields_ptr,
+            ffm_weights_ptr,
+            len,
+            feature_value,
+            is_first_feature,
+        ),
+        _ => {
+            if is_first_feature {
+                for k in 0..len {
+                    *contra_fields_ptr.add(k) = *ffm_weights_ptr.add(k) * feature_value;
+                }
+            } else {
+                for k in 0..len {
+                    *contra_fields_ptr.add(k) += *ffm_weights_ptr.add(k) * feature_value;
+                }
+            }
+        }
+    }
 }
 
+// Gradients of len weights of a feature against a contra field, stored at gradients_ptr, returns their
+// dot product with the weights. For the feature's own field its contribution is taken out of the contra field
 #[inline(always)]
-unsafe fn prepare_first_contra_field(
-    contra_fields_ptr: *mut f32,
+unsafe fn backward_correction(
+    ffm_kernel: FFMKernel,
     ffm_weights_ptr: *const f32,
-    feature_value: __m128,
+    contra_fields_ptr: *const f32,
+    gradients_ptr: *mut f32,
+    len: usize,
+    feature_value: f32,
+    own_field: bool,
+) -> f32 {
+    match ffm_kernel {
+        #[cfg(target_arch = "x86_64")]
+        FFMKernel::Avx512 => backward_correction_avx512(
+            ffm_weights_ptr,
+            contra_fields_ptr,
+            gradients_ptr,
+            len,
+            feature_value,
+            if own_field { feature_value } else { 0.0 },
+        ),
+        _ => {
+            let mut correction = 0.0;
+            if own_field {
+                for k in 0..len {
+                    let ffm_weight = *ffm_weights_ptr.add(k);
+                    let contra_weight = *contra_fields_ptr.add(k) - ffm_weight * feature_value;
+                    let gradient = feature_value * contra_weight;
+                    *gradients_ptr.add(k) = gradient;
+
+                    correction += ffm_weight * gradient;
+                }
+            } else {
+                for k in 0..len {
+                    let contra_weight = *contra_fields_ptr.add(k);
+                    let gradient = feature_value * contra_weight;
+
+                    *gradients_ptr.add(k) = gradient;
+
+                    let ffm_weight = *ffm_weights_ptr.add(k);
+                    correction += ffm_weight * gradient;
+                }
+            }
+            correction
+        }
+    }
+}
+
+#[inline(always)]
+unsafe fn add_cached_contra_field(
+    contra_fields_ptr: *mut f32,
+    cached_contra_fields_ptr: *const f32,
 ) {
-    let acc = _mm_mul_ps(_mm_loadu_ps(ffm_weights_ptr), feature_value);
-    _mm_storeu_ps(contra_fields_ptr, acc);
+    let contra_fields = F32x4::load(contra_fields_ptr);
+    let cached_contra_fields = F32x4::load(cached_contra_fields_ptr);
+    (cached_contra_fields + contra_fields).store(contra_fields_ptr);
 }
 
 #[inline(always)]
-unsafe fn prepare_contra_field_without_feature_value(
+unsafe fn prepare_first_contra_field(
     contra_fields_ptr: *mut f32,
     ffm_weights_ptr: *const f32,
+    feature_value: F32x4,
 ) {
-    let contra_fields = _mm_loadu_ps(contra_fields_ptr);
-    let ffm_weights = _mm_loadu_ps(ffm_weights_ptr);
-    _mm_storeu_ps(contra_fields_ptr, _mm_add_ps(ffm_weights, contra_fields));
+    (F32x4::load(ffm_weights_ptr) * feature_value).store(contra_fields_ptr);
 }
 
 #[inline(always)]
-#[cfg(not(target_feature = "fma"))]
-unsafe fn prepare_contra_field_with_feature_value(
+unsafe fn prepare_contra_field_without_feature_value(
     contra_fields_ptr: *mut f32,
     ffm_weights_ptr: *const f32,
-    feature_value: __m128,
 ) {
-    let contra_fields = _mm_loadu_ps(contra_fields_ptr);
-    let ffm_weights = _mm_loadu_ps(ffm_weights_ptr);
-    let acc = _mm_add_ps(_mm_mul_ps(ffm_weights, feature_value), contra_fields);
-    _mm_storeu_ps(contra_fields_ptr, acc);
+    let contra_fields = F32x4::load(contra_fields_ptr);
+    let ffm_weights = F32x4::load(ffm_weights_ptr);
+    (ffm_weights + contra_fields).store(contra_fields_ptr);
 }
 
 #[inline(always)]
-#[cfg(target_feature = "fma")]
 unsafe fn prepare_contra_field_with_feature_value(
     contra_fields_ptr: *mut f32,
     ffm_weights_ptr: *const f32,
-    feature_value: __m128,
+    feature_value: F32x4,
 ) {
-    let contra_fields = _mm_loadu_ps(contra_fields_ptr);
-    let ffm_weights = _mm_loadu_ps(ffm_weights_ptr);
-    let acc = _mm_fmadd_ps(ffm_weights, feature_value, contra_fields);
-    _mm_storeu_ps(contra_fields_ptr, acc);
+    let contra_fields = F32x4::load(contra_fields_ptr);
+    let ffm_weights = F32x4::load(ffm_weights_ptr);
+    ffm_weights
+        .mul_add(feature_value, contra_fields)
+        .store(contra_fields_ptr);
 }
 
 impl<L: OptimizerTrait + 'static> BlockFFM<L> {
+    // Embeddings of the feature with this (masked) hash, ffm_k weights towards each field in turn
+    pub fn feature_embedding(&self, hash: u32) -> impl Iterator<Item = &f32> {
+        let ffm_k = self.ffm_k as usize;
+        (0..self.ffm_num_fields as usize).flat_map(move |field| {
+            let index = self.indexing.index(hash, field);
+            &self.weights[index..index + ffm_k]
+        })
+    }
+
+    // Masked hashes of all features, the table is 1 << ffm_bit_precision weights past which embeddings
+    // of the highest hashes spill
+    pub fn feature_hashes(&self) -> impl Iterator<Item = u32> {
+        (0..self.ffm_weights_len - self.field_embedding_len)
+            .step_by(self.ffm_k.next_power_of_two() as usize)
+    }
+
+    // Field embeddings summed over the features of each field, fields x fields x ffm_k
+    fn contra_fields_len(&self) -> usize {
+        (self.field_embedding_len * self.ffm_num_fields) as usize
+    }
+
+    // Port buffers are sized by init_port_buffer(), but may come from a graph without this block
+    #[inline(always)]
+    fn ensure_contra_fields(&self, pb: &mut PortBuffer) {
+        let contra_fields_len = self.contra_fields_len();
+        if pb.ffm_contra_fields.len() < contra_fields_len {
+            pb.ffm_contra_fields.resize(contra_fields_len, 0.0);
+        }
+    }
+
+    // Squared norm of the weights of a feature against its own field
+    #[inline(always)]
+    unsafe fn self_correction(
+        &self,
+        ffm_weights: &[f32],
+        feature_field_index: usize,
+        ffmk_as_usize: usize,
+    ) -> f32 {
+        #[cfg(target_arch = "x86_64")]
+        if self.ffm_kernel == FFMKernel::Avx512 {
+            let ffm_weights_ptr = ffm_weights.as_ptr().add(feature_field_index);
+            return dot_avx512(ffm_weights_ptr, ffm_weights_ptr, ffmk_as_usize);
+        }
+        let mut correction = 0.0;
+        for k in feature_field_index..feature_field_index + ffmk_as_usize {
+            correction += ffm_weights.get_unchecked(k) * ffm_weights.get_unchecked(k);
+        }
+        correction
+    }
+
     #[inline(always)]
     unsafe fn prepare_contra_fields(
-	&self,
-	feature: &HashAndValueAndSeq,
-	contra_fields: &mut [f32],
-	ffm_weights: &[f32],
-	offset: usize,
-	field_embedding_len: usize,
-	is_first_feature: &mut bool,
+        &self,
+        feature: &HashAndValueAndSeq,
+        contra_fields: &mut [f32],
+        ffm_weights: &[f32],
+        offset: usize,
+        field_embedding_len: usize,
+        is_first_feature: &mut bool,
     ) {
-	let feature_index = feature.hash as usize;
-	let feature_value = feature.value;
-	const LANES: usize = STEP * 4;
-	if *is_first_feature {
-	    *is_first_feature = false;
-	    if feature_value == 1.0 {
-		ptr::copy_nonoverlapping(
-		    ffm_weights.as_ptr().add(feature_index),
-		    contra_fields.as_mut_ptr().add(offset),
-		    field_embedding_len,
-		);
-	    } else {
-		let feature_value_mm_128 = _mm_set1_ps(feature_value);
-
-		let field_embedding_len_end = field_embedding_len - (field_embedding_len % LANES);
-
-		let mut contra_fields_ptr = contra_fields.as_mut_ptr().add(offset);
-		let mut ffm_weights_ptr = ffm_weights.as_ptr().add(feature_index);
-		for _ in (0..field_embedding_len_end).step_by(LANES) {
-		    prepare_first_contra_field(
-			contra_fields_ptr,
-			ffm_weights_ptr,
-			feature_value_mm_128,
-		    );
-		    contra_fields_ptr = contra_fields_ptr.add(STEP);
-		    ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		    prepare_first_contra_field(
-			contra_fields_ptr,
-			ffm_weights_ptr,
-			feature_value_mm_128,
-		    );
-		    contra_fields_ptr = contra_fields_ptr.add(STEP);
-		    ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		    prepare_first_contra_field(
-			contra_fields_ptr,
-			ffm_weights_ptr,
-			feature_value_mm_128,
-		    );
-		    contra_fields_ptr = contra_fields_ptr.add(STEP);
-		    ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		    prepare_first_contra_field(
-			contra_fields_ptr,
-			ffm_weights_ptr,
-			feature_value_mm_128,
-		    );
-		    contra_fields_ptr = contra_fields_ptr.add(STEP);
-		    ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-		}
+        let feature_index = self.indexing.base(feature.hash);
+        let feature_value = feature.value;
+        // Field-major, the embeddings towards the fields are not next to each other
+        let field_stride = self.indexing.field_stride();
+        let ffmk_as_usize = self.ffm_k as usize;
+        if field_stride != ffmk_as_usize {
+            for z in 0..self.ffm_num_fields as usize {
+                accumulate_contra_field(
+                    self.ffm_kernel,
+                    contra_fields.as_mut_ptr().add(offset + z * ffmk_as_usize),
+                    ffm_weights.as_ptr().add(feature_index + z * field_stride),
+                    ffmk_as_usize,
+                    feature_value,
+                    *is_first_feature,
+                );
+            }
+            *is_first_feature = false;
+            return;
+        }
+        #[cfg(target_arch = "x86_64")]
+        if self.ffm_kernel == FFMKernel::Avx512 {
+            accumulate_contra_field_avx512(
+                contra_fields.as_mut_ptr().add(offset),
+                ffm_weights.as_ptr().add(feature_index),
+                field_embedding_len,
+                feature_value,
+                *is_first_feature,
+            );
+            *is_first_feature = false;
+            return;
+        }
+        const LANES: usize = STEP * 4;
+        if *is_first_feature {
+            *is_first_feature = false;
+            if feature_value == 1.0 {
+                ptr::copy_nonoverlapping(
+                    ffm_weights.as_ptr().add(feature_index),
+                    contra_fields.as_mut_ptr().add(offset),
+                    field_embedding_len,
+                );
+            } else {
+                let feature_value_x4 = F32x4::splat(feature_value);
+
+                let field_embedding_len_end = field_embedding_len - (field_embedding_len % LANES);
+
+                let mut contra_fields_ptr = contra_fields.as_mut_ptr().add(offset);
+                let mut ffm_weights_ptr = ffm_weights.as_ptr().add(feature_index);
+                for _ in (0..field_embedding_len_end).step_by(LANES) {
+                    prepare_first_contra_field(
+                        contra_fields_ptr,
+                        ffm_weights_ptr,
+                        feature_value_x4,
+                    );
+                    contra_fields_ptr = contra_fields_ptr.add(STEP);
+                    ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                    prepare_first_contra_field(
+                        contra_fields_ptr,
+                        ffm_weights_ptr,
+                        feature_value_x4,
+                    );
+                    contra_fields_ptr = contra_fields_ptr.add(STEP);
+                    ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                    prepare_first_contra_field(
+                        contra_fields_ptr,
+                        ffm_weights_ptr,
+                        feature_value_x4,
+                    );
+                    contra_fields_ptr = contra_fields_ptr.add(STEP);
+                    ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                    prepare_first_contra_field(
+                        contra_fields_ptr,
+                        ffm_weights_ptr,
+                        feature_value_x4,
+                    );
+                    contra_fields_ptr = contra_fields_ptr.add(STEP);
+                    ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+                }
+
+                for z in field_embedding_len_end..field_embedding_len {
+                    *contra_fields.get_unchecked_mut(offset + z) =
+                        ffm_weights.get_unchecked(feature_index + z) * feature_value;
+                }
+            }
+        } else if feature_value == 1.0 {
+            let field_embedding_len_end = field_embedding_len - (field_embedding_len % LANES);
+
+            let mut contra_fields_ptr = contra_fields.as_mut_ptr().add(offset);
+            let mut ffm_weights_ptr = ffm_weights.as_ptr().add(feature_index);
+
+            for _ in (0..field_embedding_len_end).step_by(LANES) {
+                prepare_contra_field_without_feature_value(contra_fields_ptr, ffm_weights_ptr);
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                prepare_contra_field_without_feature_value(contra_fields_ptr, ffm_weights_ptr);
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                prepare_contra_field_without_feature_value(contra_fields_ptr, ffm_weights_ptr);
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                prepare_contra_field_without_feature_value(contra_fields_ptr, ffm_weights_ptr);
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+            }
+
+            for z in field_embedding_len_end..field_embedding_len {
+                *contra_fields.get_unchecked_mut(offset + z) +=
+                    *ffm_weights.get_unchecked(feature_index + z);
+            }
+        } else {
+            let feature_value_x4 = F32x4::splat(feature_value);
+
+            let field_embedding_len_end = field_embedding_len - (field_embedding_len % LANES);
+
+            let mut contra_fields_ptr = contra_fields.as_mut_ptr().add(offset);
+            let mut ffm_weights_ptr = ffm_weights.as_ptr().add(feature_index);
+            for _ in (0..field_embedding_len_end).step_by(LANES) {
+                prepare_contra_field_with_feature_value(
+                    contra_fields_ptr,
+                    ffm_weights_ptr,
+                    feature_value_x4,
+                );
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                prepare_contra_field_with_feature_value(
+                    contra_fields_ptr,
+                    ffm_weights_ptr,
+                    feature_value_x4,
+                );
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                prepare_contra_field_with_feature_value(
+                    contra_fields_ptr,
+                    ffm_weights_ptr,
+                    feature_value_x4,
+                );
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+
+                prepare_contra_field_with_feature_value(
+                    contra_fields_ptr,
+                    ffm_weights_ptr,
+                    feature_value_x4,
+                );
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                ffm_weights_ptr = ffm_weights_ptr.add(STEP);
+            }
+
+            for z in field_embedding_len_end..field_embedding_len {
+                *contra_fields.get_unchecked_mut(offset + z) +=
+                    ffm_weights.get_unchecked(feature_index + z) * feature_value;
+            }
+        }
+    }
 
-		for z in field_embedding_len_end..field_embedding_len {
-		    *contra_fields.get_unchecked_mut(offset + z) =
-			ffm_weights.get_unchecked(feature_index + z) * feature_value;
-		}
-	    }
-	} else if feature_value == 1.0 {
-	    let field_embedding_len_end = field_embedding_len - (field_embedding_len % LANES);
-
-	    let mut contra_fields_ptr = contra_fields.as_mut_ptr().add(offset);
-	    let mut ffm_weights_ptr = ffm_weights.as_ptr().add(feature_index);
-
-	    for _ in (0..field_embedding_len_end).step_by(LANES) {
-		prepare_contra_field_without_feature_value(contra_fields_ptr, ffm_weights_ptr);
-		contra_fields_ptr = contra_fields_ptr.add(STEP);
-		ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		prepare_contra_field_without_feature_value(contra_fields_ptr, ffm_weights_ptr);
-		contra_fields_ptr = contra_fields_ptr.add(STEP);
-		ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		prepare_contra_field_without_feature_value(contra_fields_ptr, ffm_weights_ptr);
-		contra_fields_ptr = contra_fields_ptr.add(STEP);
-		ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		prepare_contra_field_without_feature_value(contra_fields_ptr, ffm_weights_ptr);
-		contra_fields_ptr = contra_fields_ptr.add(STEP);
-		ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-	    }
-
-	    for z in field_embedding_len_end..field_embedding_len {
-		*contra_fields.get_unchecked_mut(offset + z) +=
-		    *ffm_weights.get_unchecked(feature_index + z);
-	    }
-	} else {
-	    let feature_value_mm_128 = _mm_set1_ps(feature_value);
-
-	    let field_embedding_len_end = field_embedding_len - (field_embedding_len % LANES);
-
-	    let mut contra_fields_ptr = contra_fields.as_mut_ptr().add(offset);
-	    let mut ffm_weights_ptr = ffm_weights.as_ptr().add(feature_index);
-	    for _ in (0..field_embedding_len_end).step_by(LANES) {
-		prepare_contra_field_with_feature_value(
-		    contra_fields_ptr,
-		    ffm_weights_ptr,
-		    feature_value_mm_128,
-		);
-		contra_fields_ptr = contra_fields_ptr.add(STEP);
-		ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		prepare_contra_field_with_feature_value(
-		    contra_fields_ptr,
-		    ffm_weights_ptr,
-		    feature_value_mm_128,
-		);
-		contra_fields_ptr = contra_fields_ptr.add(STEP);
-		ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		prepare_contra_field_with_feature_value(
-		    contra_fields_ptr,
-		    ffm_weights_ptr,
-		    feature_value_mm_128,
-		);
-		contra_fields_ptr = contra_fields_ptr.add(STEP);
-		ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-
-		prepare_contra_field_with_feature_value(
-		    contra_fields_ptr,
-		    ffm_weights_ptr,
-		    feature_value_mm_128,
-		);
-		contra_fields_ptr = contra_fields_ptr.add(STEP);
-		ffm_weights_ptr = ffm_weights_ptr.add(STEP);
-	    }
-
-	    for z in field_embedding_len_end..field_embedding_len {
-		*contra_fields.get_unchecked_mut(offset + z) +=
-		    ffm_weights.get_unchecked(feature_index + z) * feature_value;
-	    }
-	}
+    // Same as calculate_interactions, but the k-dimensional dot products are summed in f64
+    unsafe fn calculate_interactions_f64(
+        &self,
+        ffm_slice: &mut [f32],
+        contra_fields: &[f32],
+        ffmk_as_usize: usize,
+        ffm_fields_count_as_usize: usize,
+        field_embedding_len_as_usize: usize,
+    ) {
+        for f1 in 0..ffm_fields_count_as_usize {
+            let f1_offset = f1 * field_embedding_len_as_usize;
+            for f2 in f1..ffm_fields_count_as_usize {
+                let f2_offset = f2 * field_embedding_len_as_usize;
+                let contra_field = block_helpers::dot_f64(
+                    contra_fields.get_unchecked(
+                        f1_offset + f2 * ffmk_as_usize..f1_offset + (f2 + 1) * ffmk_as_usize,
+                    ),
+                    contra_fields.get_unchecked(
+                        f2_offset + f1 * ffmk_as_usize..f2_offset + (f1 + 1) * ffmk_as_usize,
+                    ),
+                ) * 0.5;
+                *ffm_slice.get_unchecked_mut(f1 * ffm_fields_count_as_usize + f2) += contra_field;
+                if f1 != f2 {
+                    *ffm_slice.get_unchecked_mut(f2 * ffm_fields_count_as_usize + f1) +=
+                        contra_field;
+                }
+            }
+        }
     }
 
     #[inline(always)]
     unsafe fn calculate_interactions(
-	&self,
-	ffm_slice: &mut [f32],
-	contra_fields: &[f32],
-	ffmk_as_usize: usize,
-	ffm_fields_count_as_usize: usize,
-	field_embedding_len_as_usize: usize,
+        &self,
+        ffm_slice: &mut [f32],
+        contra_fields: &[f32],
+        ffmk_as_usize: usize,
+        ffm_fields_count_as_usize: usize,
+        field_embedding_len_as_usize: usize,
     ) {
-	const LANES: usize = STEP * 2;
-
-	let ffmk_end_as_usize = ffmk_as_usize - ffmk_as_usize % LANES;
-
-	for f1 in 0..ffm_fields_count_as_usize {
-	    let f1_offset = f1 * field_embedding_len_as_usize;
-	    let f1_ffmk = f1 * ffmk_as_usize;
+        if self.accurate_accumulation {
+            self.calculate_interactions_f64(
+                ffm_slice,
+                contra_fields,
+                ffmk_as_usize,
+                ffm_fields_count_as_usize,
+                field_embedding_len_as_usize,
+            );
+            return;
+        }
+        match self.ffm_kernel {
+            FFMKernel::Sse => calculate_interactions_sse(
+                ffm_slice,
+                contra_fields,
+                ffmk_as_usize,
+                ffm_fields_count_as_usize,
+                field_embedding_len_as_usize,
+            ),
+            #[cfg(target_arch = "x86_64")]
+            FFMKernel::Avx2 => calculate_interactions_avx2(
+                ffm_slice,
+                contra_fields,
+                ffmk_as_usize,
+                ffm_fields_count_as_usize,
+                field_embedding_len_as_usize,
+            ),
+            #[cfg(target_arch = "x86_64")]
+            FFMKernel::Avx512 => calculate_interactions_avx512(
+                ffm_slice,
+                contra_fields,
+                ffmk_as_usize,
+                ffm_fields_count_as_usize,
+                field_embedding_len_as_usize,
+            ),
+        }
+    }
+}
 
-	    let mut f1_offset_ffmk = f1_offset + f1_ffmk;
-	    // This is self-interaction
-	    let mut contra_field = 0.0;
-	    let mut contra_fields_ptr = contra_fields.as_ptr().add(f1_offset_ffmk);
-	    if ffmk_as_usize == LANES {
-		let contra_field_0 = _mm_loadu_ps(contra_fields_ptr);
-		let contra_field_1 = _mm_loadu_ps(contra_fields_ptr.add(STEP));
+/// Adds halves of pairwise dot products of field embeddings to ffm_slice (fields x fields).
+/// Embedding of field f1 against f2 is at contra_fields[f1 * field_embedding_len + f2 * ffmk..]
+///
+/// # Safety
+///
+/// Bounds are not checked: `ffm_slice` has to hold at least fields * fields values and
+/// `contra_fields` at least fields * field_embedding_len, with field_embedding_len at least
+/// fields * ffmk. Loads are unaligned, so there is no alignment requirement.
+#[inline(always)]
+pub unsafe fn calculate_interactions_sse(
+    ffm_slice: &mut [f32],
+    contra_fields: &[f32],
+    ffmk_as_usize: usize,
+    ffm_fields_count_as_usize: usize,
+    field_embedding_len_as_usize: usize,
+) {
+    const LANES: usize = STEP * 2;
+
+    let ffmk_end_as_usize = ffmk_as_usize - ffmk_as_usize % LANES;
+
+    for f1 in 0..ffm_fields_count_as_usize {
+        let f1_offset = f1 * field_embedding_len_as_usize;
+        let f1_ffmk = f1 * ffmk_as_usize;
+
+        let mut f1_offset_ffmk = f1_offset + f1_ffmk;
+        // This is self-interaction
+        let mut contra_field = 0.0;
+        let mut contra_fields_ptr = contra_fields.as_ptr().add(f1_offset_ffmk);
+        if ffmk_as_usize == LANES {
+            let contra_field_0 = F32x4::load(contra_fields_ptr);
+            let contra_field_1 = F32x4::load(contra_fields_ptr.add(STEP));
+
+            let acc_0 = contra_field_0 * contra_field_0;
+            let acc_1 = contra_field_1 * contra_field_1;
+
+            contra_field = (acc_0 + acc_1).reduce_sum();
+        } else {
+            for _ in (0..ffmk_end_as_usize).step_by(LANES) {
+                let contra_field_0 = F32x4::load(contra_fields_ptr);
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+                let contra_field_1 = F32x4::load(contra_fields_ptr);
+                contra_fields_ptr = contra_fields_ptr.add(STEP);
+
+                let acc_0 = contra_field_0 * contra_field_0;
+                let acc_1 = contra_field_1 * contra_field_1;
+
+                contra_field += (acc_0 + acc_1).reduce_sum();
+            }
+
+            for k in ffmk_end_as_usize..ffmk_as_usize {
+                contra_field += contra_fields.get_unchecked(f1_offset_ffmk + k)
+                    * contra_fields.get_unchecked(f1_offset_ffmk + k);
+            }
+        }
+        *ffm_slice.get_unchecked_mut(f1 * ffm_fields_count_as_usize + f1) += contra_field * 0.5;
+
+        let mut f2_offset_ffmk = f1_offset + f1_ffmk;
+        for f2 in f1 + 1..ffm_fields_count_as_usize {
+            f2_offset_ffmk += field_embedding_len_as_usize;
+            f1_offset_ffmk += ffmk_as_usize;
+
+            let mut contra_field = 0.0;
+            let mut contra_fields_ptr_1 = contra_fields.as_ptr().add(f1_offset_ffmk);
+            let mut contra_fields_ptr_2 = contra_fields.as_ptr().add(f2_offset_ffmk);
+            if ffmk_as_usize == LANES {
+                let contra_field_0 = F32x4::load(contra_fields_ptr_1);
+                let contra_field_1 = F32x4::load(contra_fields_ptr_2);
+                let acc_0 = contra_field_0 * contra_field_1;
+
+                let contra_field_2 = F32x4::load(contra_fields_ptr_1.add(STEP));
+                let contra_field_3 = F32x4::load(contra_fields_ptr_2.add(STEP));
+                let acc_1 = contra_field_2 * contra_field_3;
+
+                contra_field = (acc_0 + acc_1).reduce_sum();
+            } else {
+                for _ in (0..ffmk_end_as_usize).step_by(LANES) {
+                    let contra_field_0 = F32x4::load(contra_fields_ptr_1);
+                    let contra_field_1 = F32x4::load(contra_fields_ptr_2);
+                    let acc_0 = contra_field_0 * contra_field_1;
+                    contra_fields_ptr_1 = contra_fields_ptr_1.add(STEP);
+                    contra_fields_ptr_2 = contra_fields_ptr_2.add(STEP);
+
+                    let contra_field_2 = F32x4::load(contra_fields_ptr_1);
+                    let contra_field_3 = F32x4::load(contra_fields_ptr_2);
+                    let acc_1 = contra_field_2 * contra_field_3;
+                    contra_fields_ptr_1 = contra_fields_ptr_1.add(STEP);
+                    contra_fields_ptr_2 = contra_fields_ptr_2.add(STEP);
+
+                    contra_field += (acc_0 + acc_1).reduce_sum();
+                }
+
+                for k in ffmk_end_as_usize..ffmk_as_usize {
+                    contra_field += contra_fields.get_unchecked(f1_offset_ffmk + k)
+                        * contra_fields.get_unchecked(f2_offset_ffmk + k);
+                }
+            }
+            contra_field *= 0.5;
+
+            *ffm_slice.get_unchecked_mut(f1 * ffm_fields_count_as_usize + f2) += contra_field;
+            *ffm_slice.get_unchecked_mut(f2 * ffm_fields_count_as_usize + f1) += contra_field;
+        }
+    }
+}
 
-		let acc_0 = _mm_mul_ps(contra_field_0, contra_field_0);
-		let acc_1 = _mm_mul_ps(contra_field_1, contra_field_1);
+#[inline(always)]
+#[cfg(target_arch = "x86_64")]
+unsafe fn hadd256_ps(r8: __m256) -> f32 {
+    let r4 = _mm_add_ps(_mm256_castps256_ps128(r8), _mm256_extractf128_ps(r8, 1));
+    let r2 = _mm_add_ps(r4, _mm_movehl_ps(r4, r4));
+    _mm_cvtss_f32(_mm_add_ss(r2, _mm_movehdup_ps(r2)))
+}
 
-		contra_field = hadd_ps(_mm_add_ps(acc_0, acc_1));
-	    } else {
-		for _ in (0..ffmk_end_as_usize).step_by(LANES) {
-		    let contra_field_0 = _mm_loadu_ps(contra_fields_ptr);
-		    contra_fields_ptr = contra_fields_ptr.add(STEP);
-		    let contra_field_1 = _mm_loadu_ps(contra_fields_ptr);
-		    contra_fields_ptr = contra_fields_ptr.add(STEP);
+/// Same as calculate_interactions_sse, with 8 lanes and fused multiply-add.
+///
+/// # Safety
+///
+/// The cpu has to have avx2 and fma, see autotune::ffm_kernels_available(). Slice lengths are
+/// not checked, they are the same as for calculate_interactions_sse.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+pub unsafe fn calculate_interactions_avx2(
+    ffm_slice: &mut [f32],
+    contra_fields: &[f32],
+    ffmk_as_usize: usize,
+    ffm_fields_count_as_usize: usize,
+    field_embedding_len_as_usize: usize,
+) {
+    const LANES: usize = 8;
+    let ffmk_end_as_usize = ffmk_as_usize - ffmk_as_usize % LANES;
+
+    for f1 in 0..ffm_fields_count_as_usize {
+        let f1_offset = f1 * field_embedding_len_as_usize;
+        for f2 in f1..ffm_fields_count_as_usize {
+            let f1_offset_ffmk = f1_offset + f2 * ffmk_as_usize;
+            let f2_offset_ffmk = f2 * field_embedding_len_as_usize + f1 * ffmk_as_usize;
+            let contra_fields_ptr_1 = contra_fields.as_ptr().add(f1_offset_ffmk);
+            let contra_fields_ptr_2 = contra_fields.as_ptr().add(f2_offset_ffmk);
+
+            let mut acc = _mm256_setzero_ps();
+            for k in (0..ffmk_end_as_usize).step_by(LANES) {
+                acc = _mm256_fmadd_ps(
+                    _mm256_loadu_ps(contra_fields_ptr_1.add(k)),
+                    _mm256_loadu_ps(contra_fields_ptr_2.add(k)),
+                    acc,
+                );
+            }
+            let mut contra_field = hadd256_ps(acc);
+            for k in ffmk_end_as_usize..ffmk_as_usize {
+                contra_field += contra_fields.get_unchecked(f1_offset_ffmk + k)
+                    * contra_fields.get_unchecked(f2_offset_ffmk + k);
+            }
+            contra_field *= 0.5;
+
+            *ffm_slice.get_unchecked_mut(f1 * ffm_fields_count_as_usize + f2) += contra_field;
+            if f1 != f2 {
+                *ffm_slice.get_unchecked_mut(f2 * ffm_fields_count_as_usize + f1) += contra_field;
+            }
+        }
+    }
+}
 
-		    let acc_0 = _mm_mul_ps(contra_field_0, contra_field_0);
-		    let acc_1 = _mm_mul_ps(contra_field_1, contra_field_1);
+// Mask of the first len lanes of a 16 lane vector, for the tails of the AVX-512 loops
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn lanes_mask16(len: usize) -> __mmask16 {
+    ((1u32 << len) - 1) as __mmask16
+}
 
-		    contra_field += hadd_ps(_mm_add_ps(acc_0, acc_1));
-		}
+/// Same as calculate_interactions_sse, with 16 lanes, fused multiply-add and masked tails.
+///
+/// # Safety
+///
+/// The cpu has to have avx512f, see autotune::ffm_kernels_available(). Slice lengths are not
+/// checked, they are the same as for calculate_interactions_sse. Masked tail loads don't read
+/// past the end of an embedding.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+pub unsafe fn calculate_interactions_avx512(
+    ffm_slice: &mut [f32],
+    contra_fields: &[f32],
+    ffmk_as_usize: usize,
+    ffm_fields_count_as_usize: usize,
+    field_embedding_len_as_usize: usize,
+) {
+    for f1 in 0..ffm_fields_count_as_usize {
+        let f1_offset = f1 * field_embedding_len_as_usize;
+        for f2 in f1..ffm_fields_count_as_usize {
+            let f1_offset_ffmk = f1_offset + f2 * ffmk_as_usize;
+            let f2_offset_ffmk = f2 * field_embedding_len_as_usize + f1 * ffmk_as_usize;
+            let contra_field = dot_avx512(
+                contra_fields.as_ptr().add(f1_offset_ffmk),
+                contra_fields.as_ptr().add(f2_offset_ffmk),
+                ffmk_as_usize,
+            ) * 0.5;
+
+            *ffm_slice.get_unchecked_mut(f1 * ffm_fields_count_as_usize + f2) += contra_field;
+            if f1 != f2 {
+                *ffm_slice.get_unchecked_mut(f2 * ffm_fields_count_as_usize + f1) += contra_field;
+            }
+        }
+    }
+}
 
-		for k in ffmk_end_as_usize..ffmk_as_usize {
-		    contra_field += contra_fields.get_unchecked(f1_offset_ffmk + k)
-			* contra_fields.get_unchecked(f1_offset_ffmk + k);
-		}
-	    }
-	    *ffm_slice.get_unchecked_mut(f1 * ffm_fields_count_as_usize + f1) += contra_field * 0.5;
-
-	    let mut f2_offset_ffmk = f1_offset + f1_ffmk;
-	    for f2 in f1 + 1..ffm_fields_count_as_usize {
-		f2_offset_ffmk += field_embedding_len_as_usize;
-		f1_offset_ffmk += ffmk_as_usize;
-
-		let mut contra_field = 0.0;
-		let mut contra_fields_ptr_1 = contra_fields.as_ptr().add(f1_offset_ffmk);
-		let mut contra_fields_ptr_2 = contra_fields.as_ptr().add(f2_offset_ffmk);
-		if ffmk_as_usize == LANES {
-		    let contra_field_0 = _mm_loadu_ps(contra_fields_ptr_1);
-		    let contra_field_1 = _mm_loadu_ps(contra_fields_ptr_2);
-		    let acc_0 = _mm_mul_ps(contra_field_0, contra_field_1);
-
-		    let contra_field_2 = _mm_loadu_ps(contra_fields_ptr_1.add(STEP));
-		    let contra_field_3 = _mm_loadu_ps(contra_fields_ptr_2.add(STEP));
-		    let acc_1 = _mm_mul_ps(contra_field_2, contra_field_3);
-
-		    contra_field = hadd_ps(_mm_add_ps(acc_0, acc_1));
-		} else {
-		    for _ in (0..ffmk_end_as_usize).step_by(LANES) {
-			let contra_field_0 = _mm_loadu_ps(contra_fields_ptr_1);
-			let contra_field_1 = _mm_loadu_ps(contra_fields_ptr_2);
-			let acc_0 = _mm_mul_ps(contra_field_0, contra_field_1);
-			contra_fields_ptr_1 = contra_fields_ptr_1.add(STEP);
-			contra_fields_ptr_2 = contra_fields_ptr_2.add(STEP);
-
-			let contra_field_2 = _mm_loadu_ps(contra_fields_ptr_1);
-			let contra_field_3 = _mm_loadu_ps(contra_fields_ptr_2);
-			let acc_1 = _mm_mul_ps(contra_field_2, contra_field_3);
-			contra_fields_ptr_1 = contra_fields_ptr_1.add(STEP);
-			contra_fields_ptr_2 = contra_fields_ptr_2.add(STEP);
-
-			contra_field += hadd_ps(_mm_add_ps(acc_0, acc_1));
-		    }
+// Dot product of len floats
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn dot_avx512(a_ptr: *const f32, b_ptr: *const f32, len: usize) -> f32 {
+    const LANES: usize = 16;
+    let len_end = len - len % LANES;
+
+    let mut acc = _mm512_setzero_ps();
+    for k in (0..len_end).step_by(LANES) {
+        acc = _mm512_fmadd_ps(
+            _mm512_loadu_ps(a_ptr.add(k)),
+            _mm512_loadu_ps(b_ptr.add(k)),
+            acc,
+        );
+    }
+    if len_end < len {
+        let mask = lanes_mask16(len - len_end);
+        acc = _mm512_fmadd_ps(
+            _mm512_maskz_loadu_ps(mask, a_ptr.add(len_end)),
+            _mm512_maskz_loadu_ps(mask, b_ptr.add(len_end)),
+            acc,
+        );
+    }
+    _mm512_reduce_add_ps(acc)
+}
 
-		    for k in ffmk_end_as_usize..ffmk_as_usize {
-			contra_field += contra_fields.get_unchecked(f1_offset_ffmk + k)
-			    * contra_fields.get_unchecked(f2_offset_ffmk + k);
-		    }
-		}
-		contra_field *= 0.5;
+// Adds len weights times feature_value to contra fields, or stores them for the first feature of a field
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn accumulate_contra_field_avx512(
+    contra_fields_ptr: *mut f32,
+    ffm_weights_ptr: *const f32,
+    len: usize,
+    feature_value: f32,
+    is_first_feature: bool,
+) {
+    const LANES: usize = 16;
+    let len_end = len - len % LANES;
+    let feature_value_mm_512 = _mm512_set1_ps(feature_value);
+
+    for k in (0..len_end).step_by(LANES) {
+        let ffm_weights = _mm512_loadu_ps(ffm_weights_ptr.add(k));
+        let acc = if is_first_feature {
+            _mm512_mul_ps(ffm_weights, feature_value_mm_512)
+        } else {
+            _mm512_fmadd_ps(
+                ffm_weights,
+                feature_value_mm_512,
+                _mm512_loadu_ps(contra_fields_ptr.add(k)),
+            )
+        };
+        _mm512_storeu_ps(contra_fields_ptr.add(k), acc);
+    }
+    if len_end < len {
+        let mask = lanes_mask16(len - len_end);
+        let ffm_weights = _mm512_maskz_loadu_ps(mask, ffm_weights_ptr.add(len_end));
+        let acc = if is_first_feature {
+            _mm512_mul_ps(ffm_weights, feature_value_mm_512)
+        } else {
+            _mm512_fmadd_ps(
+                ffm_weights,
+                feature_value_mm_512,
+                _mm512_maskz_loadu_ps(mask, contra_fields_ptr.add(len_end)),
+            )
+        };
+        _mm512_mask_storeu_ps(contra_fields_ptr.add(len_end), mask, acc);
+    }
+}
 
-		*ffm_slice.get_unchecked_mut(f1 * ffm_fields_count_as_usize + f2) += contra_field;
-		*ffm_slice.get_unchecked_mut(f2 * ffm_fields_count_as_usize + f1) += contra_field;
-	    }
-	}
+// Gradients of len weights of a feature against the contra field, stored at gradients_ptr,
+// returns their dot product with the weights. own_value is the feature value when the contra field
+// is of the feature's own field (the feature's contribution is taken out of it), otherwise 0.0
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn backward_correction_avx512(
+    ffm_weights_ptr: *const f32,
+    contra_fields_ptr: *const f32,
+    gradients_ptr: *mut f32,
+    len: usize,
+    feature_value: f32,
+    own_value: f32,
+) -> f32 {
+    const LANES: usize = 16;
+    let len_end = len - len % LANES;
+    let feature_value_mm_512 = _mm512_set1_ps(feature_value);
+    let own_value_mm_512 = _mm512_set1_ps(-own_value);
+
+    let mut acc = _mm512_setzero_ps();
+    for k in (0..len_end).step_by(LANES) {
+        let ffm_weights = _mm512_loadu_ps(ffm_weights_ptr.add(k));
+        let contra_weights = _mm512_fmadd_ps(
+            ffm_weights,
+            own_value_mm_512,
+            _mm512_loadu_ps(contra_fields_ptr.add(k)),
+        );
+        let gradients = _mm512_mul_ps(feature_value_mm_512, contra_weights);
+        _mm512_storeu_ps(gradients_ptr.add(k), gradients);
+        acc = _mm512_fmadd_ps(ffm_weights, gradients, acc);
+    }
+    if len_end < len {
+        let mask = lanes_mask16(len - len_end);
+        let ffm_weights = _mm512_maskz_loadu_ps(mask, ffm_weights_ptr.add(len_end));
+        let contra_weights = _mm512_fmadd_ps(
+            ffm_weights,
+            own_value_mm_512,
+            _mm512_maskz_loadu_ps(mask, contra_fields_ptr.add(len_end)),
+        );
+        let gradients = _mm512_mul_ps(feature_value_mm_512, contra_weights);
+        _mm512_mask_storeu_ps(gradients_ptr.add(len_end), mask, gradients);
+        acc = _mm512_fmadd_ps(ffm_weights, gradients, acc);
     }
+    _mm512_reduce_add_ps(acc)
 }
 
 #[cfg(test)]
@@ -1206,6 +1961,8 @@ mod tests {
     use block_helpers::{slearn2, spredict2, spredict2_with_cache};
 
     use crate::assert_epsilon;
+    #[cfg(target_arch = "x86_64")]
+    use crate::autotune;
     use crate::block_helpers::ssetup_cache2;
     use crate::block_loss_functions;
     use crate::feature_buffer;
@@ -1217,822 +1974,1163 @@ mod tests {
     use super::*;
 
     fn ffm_vec(v: Vec<feature_buffer::HashAndValueAndSeq>) -> feature_buffer::FeatureBuffer {
-	feature_buffer::FeatureBuffer {
-	    label: 0.0,
-	    example_importance: 1.0,
-	    example_number: 0,
-	    lr_buffer: Vec::new(),
-	    ffm_buffer: v,
-	}
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: v,
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
     }
 
     fn ffm_init<T: OptimizerTrait + 'static>(block_ffm: &mut Box<dyn BlockTrait>) {
-	let block_ffm = block_ffm.as_any().downcast_mut::<BlockFFM<T>>().unwrap();
+        let block_ffm = block_ffm.as_any().downcast_mut::<BlockFFM<T>>().unwrap();
 
-	for i in 0..block_ffm.weights.len() {
-	    block_ffm.weights[i] = 1.0;
-	    block_ffm.optimizer[i].optimizer_data = block_ffm.optimizer_ffm.initial_data();
-	}
+        for i in 0..block_ffm.weights.len() {
+            block_ffm.weights[i] = 1.0;
+            block_ffm.optimizer[i].optimizer_data = block_ffm.optimizer_ffm.initial_data();
+        }
     }
 
-    #[test] #[ignore]
+    #[test]
+    #[ignore]
     fn test_ffm_k1() {
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.ffm_learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.ffm_power_t = 0.0;
-	mi.bit_precision = 18;
-	mi.ffm_k = 1;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
-	mi.optimizer = Optimizer::AdagradLUT;
-
-	// Nothing can be learned from a single field in FFMs
-	let mut bg = BlockGraph::new();
-	let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
-	let _loss_block = block_loss_functions::new_logloss_block(&mut bg, ffm_block, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-	let mut pb = bg.new_port_buffer();
-
-	let fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 1,
-	    value: 1.0,
-	    contra_field_index: 0,
-	}]);
-	// saying we have 1 field isn't entirely correct
-	assert_epsilon!(spredict2(&mut bg, &fb, &mut pb), 0.5);
-	assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
-
-	// With two fields, things start to happen
-	// Since fields depend on initial randomization, these tests are ... peculiar.
-	mi.optimizer = Optimizer::AdagradFlex;
-	let mut bg = BlockGraph::new();
-
-	let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, ffm_block, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-	let mut pb = bg.new_port_buffer();
-
-	ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 1.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	assert_epsilon!(spredict2(&mut bg, &fb, &mut pb), 0.7310586);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
-
-	assert_epsilon!(spredict2(&mut bg, &fb, &mut pb), 0.7024794);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7024794);
-
-	// Two fields, use values
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 2.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.98201376);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.98201376);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.81377685);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.81377685);
-    }
-
-    #[test] #[ignore]
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.optimizer = Optimizer::AdagradLUT;
+
+        // Nothing can be learned from a single field in FFMs
+        let mut bg = BlockGraph::new();
+        let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
+        let _loss_block = block_loss_functions::new_logloss_block(&mut bg, ffm_block, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        let fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 1,
+            value: 1.0,
+            contra_field_index: 0,
+        }]);
+        // saying we have 1 field isn't entirely correct
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb), 0.5);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+
+        // With two fields, things start to happen
+        // Since fields depend on initial randomization, these tests are ... peculiar.
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut bg = BlockGraph::new();
+
+        let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, ffm_block, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb), 0.7310586);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
+
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb), 0.7024794);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7024794);
+
+        // Two fields, use values
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 2.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.98201376);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.98201376);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.81377685);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.81377685);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_k1_with_cache() {
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.ffm_learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.ffm_power_t = 0.0;
-	mi.bit_precision = 18;
-	mi.ffm_k = 1;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
-	mi.optimizer = Optimizer::AdagradLUT;
-
-	// Nothing can be learned from a single field in FFMs
-	let mut bg = BlockGraph::new();
-	let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
-	let _loss_block = block_loss_functions::new_logloss_block(&mut bg, ffm_block, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-	let mut pb = bg.new_port_buffer();
-
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 1,
-	    value: 1.0,
-	    contra_field_index: 0,
-	}]); // saying we have 1 field isn't entirely correct
-
-	let fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 1,
-	    value: 1.0,
-	    contra_field_index: 0,
-	}]); // saying we have 1 field isn't entirely correct
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches), 0.5);
-	assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
-
-	// With two fields, things start to happen
-	// Since fields depend on initial randomization, these tests are ... peculiar.
-	mi.optimizer = Optimizer::AdagradFlex;
-	let mut bg = BlockGraph::new();
-
-	let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, ffm_block, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-	let mut pb = bg.new_port_buffer();
-
-	ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 1,
-	    value: 1.0,
-	    contra_field_index: 0,
-	}]);
-
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 1.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.7310586
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.7024794
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7024794);
-
-	// Two fields, use values
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 100,
-	    value: 2.0,
-	    contra_field_index: mi.ffm_k,
-	}]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 2.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.98201376
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.98201376);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.81377685
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.81377685);
-    }
-
-    #[test] #[ignore]
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.optimizer = Optimizer::AdagradLUT;
+
+        // Nothing can be learned from a single field in FFMs
+        let mut bg = BlockGraph::new();
+        let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
+        let _loss_block = block_loss_functions::new_logloss_block(&mut bg, ffm_block, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 1,
+            value: 1.0,
+            contra_field_index: 0,
+        }]); // saying we have 1 field isn't entirely correct
+
+        let fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 1,
+            value: 1.0,
+            contra_field_index: 0,
+        }]); // saying we have 1 field isn't entirely correct
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches), 0.5);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+
+        // With two fields, things start to happen
+        // Since fields depend on initial randomization, these tests are ... peculiar.
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut bg = BlockGraph::new();
+
+        let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, ffm_block, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 1,
+            value: 1.0,
+            contra_field_index: 0,
+        }]);
+
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.7310586
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.7024794
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7024794);
+
+        // Two fields, use values
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 100,
+            value: 2.0,
+            contra_field_index: mi.ffm_k,
+        }]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 2.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.98201376
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.98201376);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.81377685
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.81377685);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_k4() {
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.ffm_learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.ffm_power_t = 0.0;
-	mi.ffm_k = 4;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
-
-	// Nothing can be learned from a single field in FFMs
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	let mut pb = bg.new_port_buffer();
-
-	let fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 1,
-	    value: 1.0,
-	    contra_field_index: 0,
-	}]);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.5);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.5);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
-
-	// With two fields, things start to happen
-	// Since fields depend on initial randomization, these tests are ... peculiar.
-	mi.optimizer = Optimizer::AdagradFlex;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 1.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.98201376);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.98201376);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.96277946);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.96277946);
-
-	// Two fields, use values
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 2.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.9999999);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.9999999);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.99685884);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.99685884);
-    }
-
-    #[test] #[ignore]
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+
+        // Nothing can be learned from a single field in FFMs
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+
+        let fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 1,
+            value: 1.0,
+            contra_field_index: 0,
+        }]);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.5);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.5);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+
+        // With two fields, things start to happen
+        // Since fields depend on initial randomization, these tests are ... peculiar.
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.98201376);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.98201376);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.96277946);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.96277946);
+
+        // Two fields, use values
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 2.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.9999999);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.9999999);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.99685884);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.99685884);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_k4_with_cache() {
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.ffm_learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.ffm_power_t = 0.0;
-	mi.ffm_k = 4;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
-
-	// Nothing can be learned from a single field in FFMs
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	let mut pb = bg.new_port_buffer();
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 1,
-	    value: 1.0,
-	    contra_field_index: 0,
-	}]);
-	let fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 1,
-	    value: 1.0,
-	    contra_field_index: 0,
-	}]);
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches), 0.5);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches), 0.5);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
-
-	// With two fields, things start to happen
-	// Since fields depend on initial randomization, these tests are ... peculiar.
-	mi.optimizer = Optimizer::AdagradFlex;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 100,
-	    value: 1.0,
-	    contra_field_index: mi.ffm_k,
-	}]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 1.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.98201376
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.98201376);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.96277946
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.96277946);
-
-	// Two fields, use values
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 100,
-	    value: 2.0,
-	    contra_field_index: mi.ffm_k,
-	}]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 2.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.9999999
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.9999999);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.99685884
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.99685884);
-    }
-
-    #[test] #[ignore]
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+
+        // Nothing can be learned from a single field in FFMs
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 1,
+            value: 1.0,
+            contra_field_index: 0,
+        }]);
+        let fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 1,
+            value: 1.0,
+            contra_field_index: 0,
+        }]);
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches), 0.5);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches), 0.5);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+
+        // With two fields, things start to happen
+        // Since fields depend on initial randomization, these tests are ... peculiar.
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 100,
+            value: 1.0,
+            contra_field_index: mi.ffm_k,
+        }]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.98201376
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.98201376);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.96277946
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.96277946);
+
+        // Two fields, use values
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 100,
+            value: 2.0,
+            contra_field_index: mi.ffm_k,
+        }]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 2.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.9999999
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.9999999);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.99685884
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.99685884);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_multivalue() {
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.ffm_k = 1;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_power_t = 0.0;
-	mi.ffm_learning_rate = 0.1;
-	mi.ffm_fields = vec![vec![], vec![]];
-
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	let mut pb = bg.new_port_buffer();
-
-	ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
-	let fbuf = &ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 3 * 1000,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-	assert_epsilon!(spredict2(&mut bg, fbuf, &mut pb), 0.9933072);
-	assert_eq!(slearn2(&mut bg, fbuf, &mut pb, true), 0.9933072);
-	assert_epsilon!(spredict2(&mut bg, fbuf, &mut pb), 0.9395168);
-	assert_eq!(slearn2(&mut bg, fbuf, &mut pb, false), 0.9395168);
-	assert_epsilon!(spredict2(&mut bg, fbuf, &mut pb), 0.9395168);
-	assert_eq!(slearn2(&mut bg, fbuf, &mut pb, false), 0.9395168);
-    }
-
-    #[test] #[ignore]
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+
+        ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
+        let fbuf = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 3 * 1000,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+        assert_epsilon!(spredict2(&mut bg, fbuf, &mut pb), 0.9933072);
+        assert_eq!(slearn2(&mut bg, fbuf, &mut pb, true), 0.9933072);
+        assert_epsilon!(spredict2(&mut bg, fbuf, &mut pb), 0.9395168);
+        assert_eq!(slearn2(&mut bg, fbuf, &mut pb, false), 0.9395168);
+        assert_epsilon!(spredict2(&mut bg, fbuf, &mut pb), 0.9395168);
+        assert_eq!(slearn2(&mut bg, fbuf, &mut pb, false), 0.9395168);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_multivalue_with_cache() {
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.ffm_k = 1;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_power_t = 0.0;
-	mi.ffm_learning_rate = 0.1;
-	mi.ffm_fields = vec![vec![], vec![]];
-
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	let mut pb = bg.new_port_buffer();
-
-	ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = &ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 3 * 1000,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	]);
-
-	let fb = &ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 3 * 1000,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: mi.ffm_k * 1,
-	    },
-	]);
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.9933072
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.9933072);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.9395168
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.9395168);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.9395168
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.9395168);
-    }
-
-    #[test] #[ignore]
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+
+        ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 3 * 1000,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+        ]);
+
+        let fb = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 3 * 1000,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k * 1,
+            },
+        ]);
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.9933072
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.9933072);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.9395168
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.9395168);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.9395168
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.9395168);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_multivalue_k4_nonzero_powert() {
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.ffm_k = 4;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_fields = vec![vec![], vec![]];
-
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	let mut pb = bg.new_port_buffer();
-
-	ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
-	let fbuf = &ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 3 * 1000,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-
-	assert_eq!(spredict2(&mut bg, fbuf, &mut pb), 1.0);
-	assert_eq!(slearn2(&mut bg, fbuf, &mut pb, true), 1.0);
-	assert_eq!(spredict2(&mut bg, fbuf, &mut pb), 0.9949837);
-	assert_eq!(slearn2(&mut bg, fbuf, &mut pb, false), 0.9949837);
-	assert_eq!(slearn2(&mut bg, fbuf, &mut pb, false), 0.9949837);
-    }
-
-    #[test] #[ignore]
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]];
+
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+
+        ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
+        let fbuf = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 3 * 1000,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+
+        assert_eq!(spredict2(&mut bg, fbuf, &mut pb), 1.0);
+        assert_eq!(slearn2(&mut bg, fbuf, &mut pb, true), 1.0);
+        assert_eq!(spredict2(&mut bg, fbuf, &mut pb), 0.9949837);
+        assert_eq!(slearn2(&mut bg, fbuf, &mut pb, false), 0.9949837);
+        assert_eq!(slearn2(&mut bg, fbuf, &mut pb, false), 0.9949837);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_multivalue_k4_nonzero_powert_with_cache() {
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.ffm_k = 4;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_fields = vec![vec![], vec![]];
-
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	let mut pb = bg.new_port_buffer();
-
-	ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = &ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 3 * 1000,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	]);
-
-	let fb = &ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 3 * 1000,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	]);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches), 1.0);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 1.0);
-
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.9949837
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.9949837);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.9949837);
-    }
-
-    #[test] #[ignore]
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]];
+
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+
+        ffm_init::<optimizer::OptimizerAdagradLUT>(&mut bg.blocks_final[0]);
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 3 * 1000,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+        ]);
+
+        let fb = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 3 * 1000,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches), 1.0);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 1.0);
+
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.9949837
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.9949837);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.9949837);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_missing_field() {
-	// This test is useful to check if we don't by accient forget to initialize any of the collapsed
-	// embeddings for the field, when field has no instances of a feature in it
-	// We do by having three-field situation where only the middle field has features
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.ffm_learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.ffm_power_t = 0.0;
-	mi.bit_precision = 18;
-	mi.ffm_k = 1;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_fields = vec![vec![], vec![], vec![]]; // This isn't really used
-
-	// Nothing can be learned from a single field in FFMs
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	let mut pb = bg.new_port_buffer();
-
-	// With two fields, things start to happen
-	// Since fields depend on initial randomization, these tests are ... peculiar.
-	mi.optimizer = Optimizer::AdagradFlex;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 5,
-		value: 1.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 1.0,
-		contra_field_index: mi.ffm_k * 2,
-	    },
-	]);
-	assert_epsilon!(spredict2(&mut bg, &fb, &mut pb), 0.95257413);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.95257413);
-
-	// here we intentionally have just the middle field
-	let fb = ffm_vec(vec![HashAndValueAndSeq {
-	    hash: 5,
-	    value: 1.0,
-	    contra_field_index: mi.ffm_k,
-	}]);
-	assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.5);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.62245935);
-    }
-
-    #[test] #[ignore]
+        // This test is useful to check if we don't by accient forget to initialize any of the collapsed
+        // embeddings for the field, when field has no instances of a feature in it
+        // We do by having three-field situation where only the middle field has features
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![], vec![]]; // This isn't really used
+
+        // Nothing can be learned from a single field in FFMs
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+
+        // With two fields, things start to happen
+        // Since fields depend on initial randomization, these tests are ... peculiar.
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 5,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k * 2,
+            },
+        ]);
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb), 0.95257413);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.95257413);
+
+        // here we intentionally have just the middle field
+        let fb = ffm_vec(vec![HashAndValueAndSeq {
+            hash: 5,
+            value: 1.0,
+            contra_field_index: mi.ffm_k,
+        }]);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.5);
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.62245935);
+    }
+
+    #[test]
+    fn test_ffm_l2() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.optimizer = Optimizer::SGD;
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+
+        let mut learned_weights = Vec::new();
+        for ffm_l2 in [0.0, 0.5] {
+            mi.ffm_l2 = ffm_l2;
+            let mut bg = BlockGraph::new();
+            let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+            let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+            bg.finalize();
+            bg.allocate_and_init_weights(&mi);
+            let mut pb = bg.new_port_buffer();
+            ffm_init::<optimizer::OptimizerSGD>(&mut bg.blocks_final[0]);
+            slearn2(&mut bg, &fb, &mut pb, true);
+            let block_ffm = bg.blocks_final[0]
+                .as_any()
+                .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
+                .unwrap();
+            learned_weights.push((block_ffm.weights[1], block_ffm.weights[5]));
+        }
+        // Touched weights shrink by learning rate * l2 * weight, the others stay
+        assert_epsilon!(learned_weights[0].0 - learned_weights[1].0, 0.1 * 0.5 * 1.0);
+        assert_eq!(learned_weights[0].1, 1.0);
+        assert_eq!(learned_weights[1].1, 1.0);
+    }
+
+    #[test]
+    fn test_ffm_scratch_grows() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.optimizer = Optimizer::SGD;
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+
+        let mut predictions = Vec::new();
+        for presized in [true, false] {
+            let mut bg = BlockGraph::new();
+            let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+            let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+            bg.finalize();
+            bg.allocate_and_init_weights(&mi);
+            let mut pb = bg.new_port_buffer();
+            assert_eq!(pb.ffm_contra_fields.len(), 2 * 2 * 1);
+            assert_eq!(pb.ffm_gradients.len(), 2 * FFM_SCRATCH_FEATURES);
+            if !presized {
+                pb.ffm_contra_fields.truncate(0);
+                pb.ffm_gradients.truncate(0);
+            }
+            ffm_init::<optimizer::OptimizerSGD>(&mut bg.blocks_final[0]);
+            slearn2(&mut bg, &fb, &mut pb, true);
+            predictions.push(slearn2(&mut bg, &fb, &mut pb, false));
+            assert_eq!(pb.ffm_contra_fields.len(), 2 * 2 * 1);
+            assert!(pb.ffm_gradients.len() >= 2 * 2);
+        }
+        assert_eq!(predictions[0], predictions[1]);
+    }
+
+    #[test]
+    fn test_ffm_accurate_accumulation() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_k = 4096;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.optimizer = Optimizer::SGD;
+        let k = mi.ffm_k as usize;
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 0,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100000,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+
+        // The only interaction is the dot product of 16 ones and 4080 products of 1e-8, each of
+        // them lost in an f32 lane that holds at least 1.0
+        let mut interactions = Vec::new();
+        for accurate in [false, true] {
+            mi.accurate_accumulation = accurate;
+            let mut bg = BlockGraph::new();
+            let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+            let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+            bg.finalize();
+            bg.allocate_and_init_weights(&mi);
+            let mut pb = bg.new_port_buffer();
+            let block_ffm = bg.blocks_final[0]
+                .as_any()
+                .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
+                .unwrap();
+            for i in 0..block_ffm.weights.len() {
+                block_ffm.weights[i] = 0.0;
+            }
+            for i in 0..k {
+                block_ffm.weights[k + i] = 1.0; // feature of field 0, weights for field 1
+                block_ffm.weights[100000 + i] = if i < 16 { 1.0 } else { 1e-8 };
+            }
+            let output_offset = block_ffm.output_offset;
+            spredict2(&mut bg, &fb, &mut pb);
+            interactions.push(
+                pb.tape[output_offset..output_offset + 4]
+                    .iter()
+                    .sum::<f32>(),
+            );
+        }
+        assert_eq!(interactions[0], 16.0);
+        assert!((interactions[1] - (16.0 + 4080.0 * 1e-8)).abs() < 2e-6);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_ffm_avx512() {
+        if !autotune::ffm_kernels_available(true).contains(&FFMKernel::Avx512) {
+            return;
+        }
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 20; // a full vector of 16 lanes and a masked tail
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![], vec![]];
+        mi.optimizer = Optimizer::SGD;
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 3 * 1000,
+                value: 0.5,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: mi.ffm_k * 2,
+            },
+        ]);
+
+        let mut predictions = Vec::new();
+        for kernel in [FFMKernel::Sse, FFMKernel::Avx512] {
+            mi.kernels.ffm = kernel;
+            let mut bg = BlockGraph::new();
+            let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+            let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+            bg.finalize();
+            bg.allocate_and_init_weights(&mi);
+            let mut pb = bg.new_port_buffer();
+            {
+                let block_ffm = bg.blocks_final[0]
+                    .as_any()
+                    .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
+                    .unwrap();
+                for (i, w) in block_ffm.weights.iter_mut().enumerate() {
+                    *w = ((i % 7) as f32 - 3.0) * 0.05;
+                }
+            }
+            let mut kernel_predictions = Vec::new();
+            for _ in 0..3 {
+                kernel_predictions.push(slearn2(&mut bg, &fb, &mut pb, true));
+                kernel_predictions.push(spredict2(&mut bg, &fb, &mut pb));
+            }
+            let mut caches: Vec<BlockCache> = Vec::default();
+            ssetup_cache2(&mut bg, &ffm_vec(fb.ffm_buffer[..2].to_vec()), &mut caches);
+            kernel_predictions.push(spredict2_with_cache(&mut bg, &fb, &mut pb, &caches));
+            predictions.push(kernel_predictions);
+        }
+        // Training moves the prediction, both kernels follow the same path
+        assert!((predictions[0][0] - predictions[0][4]).abs() > 0.01);
+        for (avx512, sse) in predictions[1].iter().zip(predictions[0].iter()) {
+            assert_epsilon!(*avx512, *sse);
+        }
+    }
+
+    #[test]
+    fn test_ffm_weight_decay() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.optimizer = Optimizer::Adam;
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+        ]);
+
+        let mut learned = Vec::new();
+        for ffm_weight_decay in [0.0, 0.01] {
+            mi.ffm_weight_decay = ffm_weight_decay;
+            let mut bg = BlockGraph::new();
+            let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+            let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+            bg.finalize();
+            bg.allocate_and_init_weights(&mi);
+            let mut pb = bg.new_port_buffer();
+            ffm_init::<optimizer::OptimizerAdam>(&mut bg.blocks_final[0]);
+            slearn2(&mut bg, &fb, &mut pb, true);
+            let block_ffm = bg.blocks_final[0]
+                .as_any()
+                .downcast_mut::<BlockFFM<optimizer::OptimizerAdam>>()
+                .unwrap();
+            learned.push((block_ffm.weights[1], block_ffm.optimizer[1].optimizer_data));
+        }
+        // Decay takes the fraction of the weight on top of the optimizer step, which does not see it
+        assert_epsilon!(learned[0].0 - learned[1].0, 0.01 * 1.0);
+        assert_eq!(learned[0].1, learned[1].1);
+    }
+
+    #[test]
+    #[ignore]
     fn test_ffm_missing_field_with_cache() {
-	// This test is useful to check if we don't by accient forget to initialize any of the collapsed
-	// embeddings for the field, when field has no instances of a feature in it
-	// We do by having three-field situation where only the middle field has features
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.ffm_learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.ffm_power_t = 0.0;
-	mi.bit_precision = 18;
-	mi.ffm_k = 1;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_fields = vec![vec![], vec![], vec![]]; // This isn't really used
-
-	// Nothing can be learned from a single field in FFMs
-	mi.optimizer = Optimizer::AdagradLUT;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	let mut pb = bg.new_port_buffer();
-
-	// With two fields, things start to happen
-	// Since fields depend on initial randomization, these tests are ... peculiar.
-	mi.optimizer = Optimizer::AdagradFlex;
-	let mut bg = BlockGraph::new();
-	let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
-	let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
-	bg.finalize();
-	bg.allocate_and_init_weights(&mi);
-
-	ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
-	let mut caches: Vec<BlockCache> = Vec::default();
-	let cache_fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 1.0,
-		contra_field_index: mi.ffm_k * 2,
-	    },
-	]);
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 5,
-		value: 1.0,
-		contra_field_index: mi.ffm_k,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 1.0,
-		contra_field_index: mi.ffm_k * 2,
-	    },
-	]);
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_epsilon!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.95257413
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.95257413);
-
-	// here we intentionally have missing fields
-	let fb = ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 1.0,
-		contra_field_index: mi.ffm_k * 2,
-	    },
-	]);
-	ssetup_cache2(&mut bg, &cache_fb, &mut caches);
-	assert_eq!(
-	    spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
-	    0.7310586
-	);
-	assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
+        // This test is useful to check if we don't by accient forget to initialize any of the collapsed
+        // embeddings for the field, when field has no instances of a feature in it
+        // We do by having three-field situation where only the middle field has features
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![], vec![]]; // This isn't really used
+
+        // Nothing can be learned from a single field in FFMs
+        mi.optimizer = Optimizer::AdagradLUT;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+
+        // With two fields, things start to happen
+        // Since fields depend on initial randomization, these tests are ... peculiar.
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, &mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
+        let mut caches: Vec<BlockCache> = Vec::default();
+        let cache_fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k * 2,
+            },
+        ]);
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 5,
+                value: 1.0,
+                contra_field_index: mi.ffm_k,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k * 2,
+            },
+        ]);
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_epsilon!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.95257413
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, false), 0.95257413);
+
+        // here we intentionally have missing fields
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 1.0,
+                contra_field_index: mi.ffm_k * 2,
+            },
+        ]);
+        ssetup_cache2(&mut bg, &cache_fb, &mut caches);
+        assert_eq!(
+            spredict2_with_cache(&mut bg, &fb, &mut pb, &caches),
+            0.7310586
+        );
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
+    }
+
+    fn ffm_layout_graph(mi: &model_instance::ModelInstance) -> BlockGraph {
+        let mut bg = BlockGraph::new();
+        let re_ffm = new_ffm_block(&mut bg, mi).unwrap();
+        let _lossf = block_loss_functions::new_logloss_block(&mut bg, re_ffm, true);
+        bg.finalize();
+        bg.allocate_and_init_weights(mi);
+        ffm_init::<optimizer::OptimizerAdagradFlex>(&mut bg.blocks_final[0]);
+        bg
+    }
+
+    #[test]
+    fn test_ffm_field_major() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![], vec![]];
+        mi.optimizer = Optimizer::AdagradFlex;
+
+        let mut bg_feature = ffm_layout_graph(&mi);
+        mi.ffm_layout = model_instance::FfmLayout::FieldMajor;
+        let mut bg_field = ffm_layout_graph(&mi);
+
+        let indexing = FfmIndexing::new(&mi);
+        assert_eq!(indexing.field_stride(), 1 << 16);
+        assert_eq!(indexing.index(400, 2), 100 + 2 * (1 << 16));
+
+        let mut pb = bg_feature.new_port_buffer();
+        let fb = ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 4,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 12000,
+                value: 0.5,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 400,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+            HashAndValueAndSeq {
+                hash: 8000,
+                value: 1.0,
+                contra_field_index: mi.ffm_k * 2,
+            },
+        ]);
+        // The same embeddings are only placed elsewhere, so both layouts learn the same
+        for _ in 0..3 {
+            let p = spredict2(&mut bg_feature, &fb, &mut pb);
+            assert_eq!(spredict2(&mut bg_field, &fb, &mut pb), p);
+            let p = slearn2(&mut bg_feature, &fb, &mut pb, true);
+            assert_eq!(slearn2(&mut bg_field, &fb, &mut pb, true), p);
+        }
+
+        let cache_fb = ffm_vec(fb.ffm_buffer[..2].to_vec());
+        let mut caches_feature: Vec<BlockCache> = Vec::default();
+        let mut caches_field: Vec<BlockCache> = Vec::default();
+        ssetup_cache2(&mut bg_feature, &cache_fb, &mut caches_feature);
+        ssetup_cache2(&mut bg_field, &cache_fb, &mut caches_field);
+        let p = spredict2_with_cache(&mut bg_feature, &fb, &mut pb, &caches_feature);
+        assert_eq!(
+            spredict2_with_cache(&mut bg_field, &fb, &mut pb, &caches_field),
+            p
+        );
+        assert_eq!(spredict2(&mut bg_field, &fb, &mut pb), p);
     }
 }
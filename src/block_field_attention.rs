@@ -0,0 +1,362 @@
+use std::any::Any;
+use std::error::Error;
+use std::io;
+
+use optimizer::OptimizerTrait;
+use regressor::BlockTrait;
+
+use crate::block_helpers;
+use crate::block_helpers::OptimizerData;
+use crate::feature_buffer;
+use crate::graph;
+use crate::model_instance;
+use crate::optimizer;
+use crate::port_buffer;
+use crate::regressor;
+use crate::regressor::BlockCache;
+
+// --ffm_field_attention: a learned weight for each pair of fields, applied to the field x field matrix
+// of interactions that BlockFFM outputs, as in FwFM. Interactions (i, j) and (j, i) share the weight
+// of pair i * (i + 1) / 2 + j with j <= i, which is the order of the outputs of the triangle block.
+// Weights start at 1.0, so the block starts as plain FFM.
+pub struct BlockFieldAttention<L: OptimizerTrait> {
+    pub num_fields: usize,
+    pub input_offset: usize,
+    pub output_offset: usize,
+    pub weights_len: u32,
+    pub weights: Vec<f32>,
+    pub weights_optimizer: Vec<OptimizerData<L>>,
+    pub optimizer: L,
+    lr_schedule: Option<model_instance::LRSchedule>,
+    grad_clip: f32,
+}
+
+pub fn new_field_attention_block(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+    input: graph::BlockPtrOutput,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input]);
+    let num_fields = (num_inputs as f32).sqrt() as usize;
+    if num_fields == 0 || num_fields * num_fields != num_inputs {
+        return Err(format!(
+            "Field attention needs a square matrix of interactions, got {} inputs",
+            num_inputs
+        ))?;
+    }
+    let block = match mi.ffm_optimizer.unwrap_or(mi.optimizer) {
+        model_instance::Optimizer::AdagradLUT => new_field_attention_block_without_weights::<
+            optimizer::OptimizerAdagradLUT,
+        >(mi, num_fields),
+        model_instance::Optimizer::AdagradFlex => new_field_attention_block_without_weights::<
+            optimizer::OptimizerAdagradFlex,
+        >(mi, num_fields),
+        model_instance::Optimizer::SGD => {
+            new_field_attention_block_without_weights::<optimizer::OptimizerSGD>(mi, num_fields)
+        }
+        model_instance::Optimizer::Adam => {
+            new_field_attention_block_without_weights::<optimizer::OptimizerAdam>(mi, num_fields)
+        }
+        model_instance::Optimizer::SGDMomentum => new_field_attention_block_without_weights::<
+            optimizer::OptimizerSGDMomentum<false>,
+        >(mi, num_fields),
+        model_instance::Optimizer::SGDNesterov => new_field_attention_block_without_weights::<
+            optimizer::OptimizerSGDMomentum<true>,
+        >(mi, num_fields),
+        model_instance::Optimizer::COCOB | model_instance::Optimizer::FTRL => {
+            return Err("COCOB and FTRL are only supported for the LR block, see --lr_optimizer")?
+        }
+    }
+    .unwrap();
+    let mut block_outputs = bg.add_node(block, vec![input]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+fn new_field_attention_block_without_weights<L: OptimizerTrait + 'static>(
+    mi: &model_instance::ModelInstance,
+    num_fields: usize,
+) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+    let mut rg = BlockFieldAttention::<L> {
+        num_fields,
+        input_offset: usize::MAX,
+        output_offset: usize::MAX,
+        weights_len: (num_fields * (num_fields + 1) / 2) as u32,
+        weights: Vec::new(),
+        weights_optimizer: Vec::new(),
+        optimizer: L::new(),
+        lr_schedule: mi.lr_schedule.clone(),
+        grad_clip: mi.grad_clip,
+    };
+    rg.optimizer.init(
+        mi.ffm_learning_rate,
+        mi.ffm_power_t,
+        mi.ffm_init_acc_gradient,
+    );
+    rg.optimizer.init_momentum(mi.momentum);
+    Ok(Box::new(rg))
+}
+
+impl<L: OptimizerTrait + 'static> BlockFieldAttention<L> {
+    #[inline(always)]
+    fn internal_forward(&self, pb: &mut port_buffer::PortBuffer) {
+        let num_inputs = self.num_fields * self.num_fields;
+        let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+            &mut pb.tape,
+            self.input_offset,
+            num_inputs,
+            self.output_offset,
+            num_inputs,
+        );
+        let mut pair = 0;
+        for i in 0..self.num_fields {
+            for j in 0..i + 1 {
+                let weight = self.weights[pair];
+                output_tape[i * self.num_fields + j] = weight * input_tape[i * self.num_fields + j];
+                output_tape[j * self.num_fields + i] = weight * input_tape[j * self.num_fields + i];
+                pair += 1;
+            }
+        }
+    }
+
+    // Gradient of the weight of pair (i, j), from both of its interactions
+    #[inline(always)]
+    fn pair_gradient(&self, input_tape: &[f32], output_tape: &[f32], i: usize, j: usize) -> f32 {
+        let ij = i * self.num_fields + j;
+        let ji = j * self.num_fields + i;
+        if i == j {
+            output_tape[ij] * input_tape[ij]
+        } else {
+            output_tape[ij] * input_tape[ij] + output_tape[ji] * input_tape[ji]
+        }
+    }
+}
+
+impl<L: OptimizerTrait + 'static> BlockTrait for BlockFieldAttention<L> {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        debug_assert!(self.output_offset != usize::MAX);
+        debug_assert!(self.input_offset != usize::MAX);
+
+        if update {
+            if let Some(schedule) = self.lr_schedule.as_ref() {
+                self.optimizer
+                    .set_learning_rate_multiplier(schedule.multiplier_at(fb.example_number));
+            }
+        }
+
+        self.internal_forward(pb);
+
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+        if update {
+            let num_inputs = self.num_fields * self.num_fields;
+            let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+                &mut pb.tape,
+                self.input_offset,
+                num_inputs,
+                self.output_offset,
+                num_inputs,
+            );
+
+            // Errors passed to the input are not clipped, only the updates of this block
+            let clip_factor = if self.grad_clip > 0.0 {
+                let mut gradient_norm_squared = 0.0;
+                for i in 0..self.num_fields {
+                    for j in 0..i + 1 {
+                        let gradient = self.pair_gradient(input_tape, output_tape, i, j);
+                        gradient_norm_squared += gradient * gradient;
+                    }
+                }
+                optimizer::clip_factor(self.grad_clip, gradient_norm_squared)
+            } else {
+                1.0
+            };
+
+            let mut pair = 0;
+            for i in 0..self.num_fields {
+                for j in 0..i + 1 {
+                    let gradient = self.pair_gradient(input_tape, output_tape, i, j);
+                    let weight = self.weights[pair];
+                    let ij = i * self.num_fields + j;
+                    let ji = j * self.num_fields + i;
+                    input_tape[ij] = weight * output_tape[ij];
+                    input_tape[ji] = weight * output_tape[ji];
+                    unsafe {
+                        let update = self.optimizer.calculate_update(
+                            gradient * clip_factor,
+                            &mut self.weights_optimizer[pair].optimizer_data,
+                        );
+                        self.weights[pair] -= update;
+                    }
+                    pair += 1;
+                }
+            }
+        }
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        self.internal_forward(pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        self.internal_forward(pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+
+    fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {
+        self.weights = vec![1.0; self.weights_len as usize];
+        self.weights_optimizer = vec![
+            OptimizerData::<L> {
+                optimizer_data: self.optimizer.initial_data()
+            };
+            self.weights_len as usize
+        ];
+    }
+
+    fn set_lr_schedule(&mut self, lr_schedule: &Option<model_instance::LRSchedule>) {
+        self.lr_schedule = lr_schedule.clone();
+    }
+
+    fn get_serialized_len(&self) -> usize {
+        self.weights_len as usize
+    }
+
+    fn get_serialized_block_id(&self) -> u32 {
+        regressor::SERIALIZED_BLOCK_ID_FIELD_ATTENTION
+    }
+
+    fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter, false)?;
+        block_helpers::write_weights_to_buf(&self.weights_optimizer, output_bufwriter, false)?;
+        Ok(())
+    }
+
+    fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader, false)?;
+        block_helpers::read_weights_from_buf(&mut self.weights_optimizer, input_bufreader, false)?;
+        Ok(())
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        self.num_fields * self.num_fields
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert_eq!(input.get_input_index(), 0);
+        self.input_offset = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(output.get_output_index(), 0);
+        self.output_offset = offset;
+    }
+
+    fn read_weights_from_buf_into_forward_only(
+        &self,
+        input_bufreader: &mut dyn io::Read,
+        forward: &mut Box<dyn BlockTrait>,
+        _use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockFieldAttention<optimizer::OptimizerSGD>>()
+            .unwrap();
+        block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader, false)?;
+        block_helpers::skip_weights_from_buf::<OptimizerData<L>>(
+            self.weights_len as usize,
+            input_bufreader,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_misc;
+    use crate::block_misc::Observe;
+    use crate::graph::BlockGraph;
+    use crate::model_instance::Optimizer;
+    use block_helpers::slearn2;
+
+    fn fb_vec() -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_field_attention() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.optimizer = Optimizer::SGD;
+
+        // Interactions of two fields, observations are the outputs and then the gradient of their sum
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![1.0, 2.0, 3.0, 4.0]).unwrap();
+        let observe_input =
+            block_misc::new_observe_block(&mut bg, input_block, Observe::Backward, None).unwrap();
+        let attention_block = new_field_attention_block(&mut bg, &mi, observe_input).unwrap();
+        block_misc::new_observe_block(&mut bg, attention_block, Observe::Forward, Some(1.0))
+            .unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+        let fb = fb_vec();
+
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(
+            pb.observations,
+            vec![1.0, 2.0, 3.0, 4.0, 1.0, 1.0, 1.0, 1.0]
+        );
+
+        // Pair (0, 1) learns from both of its interactions: weights are 1 - 0.1 * [1, 2 + 3, 4]
+        slearn2(&mut bg, &fb, &mut pb, true);
+        let expected = [0.9, 1.0, 1.5, 2.4, 0.9, 0.5, 0.5, 0.6];
+        for (observation, expected) in pb.observations.iter().zip(expected.iter()) {
+            assert_epsilon!(*observation, *expected);
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use std::any::Any;
+use std::error::Error;
+use std::io;
+
+use rand::Rng;
+
+use optimizer::OptimizerTrait;
+use regressor::BlockTrait;
+
+use crate::block_helpers;
+use crate::block_helpers::OptimizerData;
+use crate::feature_buffer;
+use crate::graph;
+use crate::model_instance;
+use crate::optimizer;
+use crate::port_buffer;
+use crate::quantization;
+use crate::regressor;
+use crate::regressor::BlockCache;
+use crate::rng;
+use crate::simd;
+
+// --fm_k: factorization machine over the features of the --ffm_field namespaces, with one embedding
+// per feature instead of one per field, so the cost grows with the number of features only.
+// The sum of <v_i, v_j> x_i x_j over all pairs is computed as 0.5 * sum_f (s_f^2 - q_f), where s_f is
+// the sum of v_if x_i and q_f the sum of (v_if x_i)^2. Weights live in the FFM hash space.
+pub struct BlockFM<L: OptimizerTrait> {
+    pub optimizer_fm: L,
+    pub fm_k: u32,
+    pub fm_weights_len: u32,
+    pub weights: Vec<f32>,
+    pub optimizer: Vec<OptimizerData<L>>,
+    pub output_offset: usize,
+    lr_schedule: Option<model_instance::LRSchedule>,
+    l2: f32,
+    grad_clip: f32,
+    weight_decay: f32,
+    quantization: quantization::WeightQuantization,
+}
+
+pub fn new_fm_block(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let block = match mi.ffm_optimizer.unwrap_or(mi.optimizer) {
+        model_instance::Optimizer::AdagradLUT => {
+            new_fm_block_without_weights::<optimizer::OptimizerAdagradLUT>(mi)
+        }
+        model_instance::Optimizer::AdagradFlex => {
+            new_fm_block_without_weights::<optimizer::OptimizerAdagradFlex>(mi)
+        }
+        model_instance::Optimizer::SGD => {
+            new_fm_block_without_weights::<optimizer::OptimizerSGD>(mi)
+        }
+        model_instance::Optimizer::Adam => {
+            new_fm_block_without_weights::<optimizer::OptimizerAdam>(mi)
+        }
+        model_instance::Optimizer::SGDMomentum => {
+            new_fm_block_without_weights::<optimizer::OptimizerSGDMomentum<false>>(mi)
+        }
+        model_instance::Optimizer::SGDNesterov => {
+            new_fm_block_without_weights::<optimizer::OptimizerSGDMomentum<true>>(mi)
+        }
+        model_instance::Optimizer::COCOB | model_instance::Optimizer::FTRL => {
+            return Err("COCOB and FTRL are only supported for the LR block, see --lr_optimizer")?
+        }
+    }
+    .unwrap();
+    let mut block_outputs = bg.add_node(block, vec![]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+fn new_fm_block_without_weights<L: OptimizerTrait + 'static>(
+    mi: &model_instance::ModelInstance,
+) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+    let mut reg_fm = BlockFM::<L> {
+        optimizer_fm: L::new(),
+        fm_k: mi.fm_k,
+        // Hashes of features are aligned to the power of two above fm_k, so all k weights fit
+        fm_weights_len: 1 << mi.ffm_bit_precision,
+        weights: Vec::new(),
+        optimizer: Vec::new(),
+        output_offset: usize::MAX,
+        lr_schedule: mi.lr_schedule.clone(),
+        l2: mi.ffm_l2,
+        grad_clip: mi.grad_clip,
+        weight_decay: mi.ffm_weight_decay,
+        quantization: mi.quantization,
+    };
+    reg_fm.optimizer_fm.init(
+        mi.ffm_learning_rate,
+        mi.ffm_power_t,
+        mi.ffm_init_acc_gradient,
+    );
+    reg_fm.optimizer_fm.init_momentum(mi.momentum);
+    Ok(Box::new(reg_fm))
+}
+
+impl<L: OptimizerTrait + 'static> BlockFM<L> {
+    // Fills pb.fm_sums with s_f and returns the output
+    #[inline(always)]
+    fn internal_forward(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) -> f32 {
+        debug_assert!(self.output_offset != usize::MAX);
+        let k = self.fm_k as usize;
+        let sums = &mut pb.fm_sums;
+        sums.truncate(0);
+        sums.resize(k, 0.0);
+        let mut squares_sum = 0.0;
+        unsafe {
+            for feature in fb.ffm_buffer.iter() {
+                let embedding = self
+                    .weights
+                    .get_unchecked(feature.hash as usize..feature.hash as usize + k);
+                for (sum, weight) in sums.iter_mut().zip(embedding.iter()) {
+                    let vx = weight * feature.value;
+                    *sum += vx;
+                    squares_sum += vx * vx;
+                }
+            }
+        }
+        let output = 0.5 * (sums.iter().map(|sum| sum * sum).sum::<f32>() - squares_sum);
+        pb.tape[self.output_offset] = output;
+        output
+    }
+}
+
+impl<L: OptimizerTrait + 'static> BlockTrait for BlockFM<L> {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
+        // Like the default initialization of FFM
+        let mut init_rng =
+            rng::new_stream(mi.seed, rng::RngStream::WeightsInit, rng::FFM_STREAM_INDEX);
+        let one_over_k_root = 1.0 / (self.fm_k as f32).sqrt() / 50.0;
+        self.weights = (0..self.fm_weights_len)
+            .map(|_| (init_rng.gen::<f32>() - 0.5) * one_over_k_root)
+            .collect();
+        self.optimizer = vec![
+            OptimizerData::<L> {
+                optimizer_data: self.optimizer_fm.initial_data(),
+            };
+            self.fm_weights_len as usize
+        ];
+    }
+
+    fn init_port_buffer(&self, pb: &mut port_buffer::PortBuffer) {
+        pb.fm_sums.resize(self.fm_k as usize, 0.0);
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        1
+    }
+
+    fn set_input_offset(&mut self, _input: graph::InputSlot, _offset: usize) {
+        panic!("You cannot set_input_offset() for BlockFM");
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(output.get_output_index(), 0);
+        debug_assert!(self.output_offset == usize::MAX); // We only allow a single call
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        if update {
+            if let Some(schedule) = self.lr_schedule.as_ref() {
+                self.optimizer_fm
+                    .set_learning_rate_multiplier(schedule.multiplier_at(fb.example_number));
+            }
+        }
+        self.internal_forward(fb, pb);
+
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+        if update {
+            let k = self.fm_k as usize;
+            let general_gradient = pb.tape[self.output_offset];
+            let sums = &pb.fm_sums;
+            unsafe {
+                // Gradient by v_if is x_i * (s_f - v_if x_i), with the sums from before the update
+                let clip_factor = if self.grad_clip > 0.0 {
+                    let mut gradient_norm_squared = 0.0;
+                    for feature in fb.ffm_buffer.iter() {
+                        let feature_index = feature.hash as usize;
+                        for (f, sum) in sums.iter().enumerate() {
+                            let weight = *self.weights.get_unchecked(feature_index + f);
+                            let gradient =
+                                general_gradient * feature.value * (sum - weight * feature.value);
+                            gradient_norm_squared += gradient * gradient;
+                        }
+                    }
+                    optimizer::clip_factor(self.grad_clip, gradient_norm_squared)
+                } else {
+                    1.0
+                };
+
+                for feature in fb.ffm_buffer.iter() {
+                    let feature_index = feature.hash as usize;
+                    for f in 0..k {
+                        let weight_index = feature_index + f;
+                        let weight = *self.weights.get_unchecked(weight_index);
+                        let mut gradient = general_gradient
+                            * feature.value
+                            * (sums.get_unchecked(f) - weight * feature.value)
+                            * clip_factor;
+                        gradient += self.l2 * weight;
+                        let update = self.optimizer_fm.calculate_update(
+                            gradient,
+                            &mut self
+                                .optimizer
+                                .get_unchecked_mut(weight_index)
+                                .optimizer_data,
+                        );
+                        let weight = self.weights.get_unchecked_mut(weight_index);
+                        *weight -= update + self.weight_decay * *weight;
+                    }
+                }
+            }
+        }
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+
+    fn prefetch(&self, fb: &feature_buffer::FeatureBuffer) {
+        for feature in fb.ffm_buffer.iter() {
+            if let Some(weight) = self.weights.get(feature.hash as usize) {
+                simd::prefetch(weight);
+            }
+        }
+    }
+
+    fn set_lr_schedule(&mut self, lr_schedule: &Option<model_instance::LRSchedule>) {
+        self.lr_schedule = lr_schedule.clone();
+    }
+
+    fn get_serialized_len(&self) -> usize {
+        self.fm_weights_len as usize
+    }
+
+    fn get_serialized_block_id(&self) -> u32 {
+        regressor::SERIALIZED_BLOCK_ID_FM
+    }
+
+    // Same layout as the FFM block: weights, quantized like those of FFM, then the optimizer data
+    fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+        use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if use_quantization {
+            quantization::write_quantized_weights(
+                self.quantization,
+                &self.weights,
+                output_bufwriter,
+            )?;
+        } else {
+            block_helpers::write_weights_to_buf(&self.weights, output_bufwriter, false)?;
+        }
+        block_helpers::write_weights_to_buf(&self.optimizer, output_bufwriter, false)?;
+        Ok(())
+    }
+
+    fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+        use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if use_quantization {
+            quantization::read_quantized_weights(
+                self.quantization,
+                input_bufreader,
+                &mut self.weights,
+            )?;
+        } else {
+            block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader, false)?;
+        }
+        block_helpers::read_weights_from_buf(&mut self.optimizer, input_bufreader, false)?;
+        Ok(())
+    }
+
+    fn read_weights_from_buf_into_forward_only(
+        &self,
+        input_bufreader: &mut dyn io::Read,
+        forward: &mut Box<dyn BlockTrait>,
+        use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+        if use_quantization {
+            quantization::read_quantized_weights(
+                self.quantization,
+                input_bufreader,
+                &mut forward.weights,
+            )?;
+        } else {
+            block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader, false)?;
+        }
+        block_helpers::skip_weights_from_buf::<OptimizerData<L>>(
+            self.fm_weights_len as usize,
+            input_bufreader,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::block_helpers::{slearn2, spredict2};
+    use crate::block_loss_functions;
+    use crate::feature_buffer::HashAndValueAndSeq;
+    use crate::graph::BlockGraph;
+
+    fn fm_vec(v: Vec<HashAndValueAndSeq>) -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 1.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: v,
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
+    }
+
+    fn feature(hash: u32, value: f32) -> HashAndValueAndSeq {
+        HashAndValueAndSeq {
+            hash,
+            value,
+            contra_field_index: 0,
+        }
+    }
+
+    #[test]
+    fn test_fm() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.fm_k = 2;
+        mi.ffm_bit_precision = 4;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let mut bg = BlockGraph::new();
+        let fm_block = new_fm_block(&mut bg, &mi).unwrap();
+        block_loss_functions::new_logloss_block(&mut bg, fm_block, true).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        let fm = bg.blocks_final[0]
+            .as_any()
+            .downcast_mut::<BlockFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+        fm.weights[0..6].copy_from_slice(&[1.0, 2.0, 0.5, -1.0, 3.0, 0.0]);
+
+        // A single feature doesn't interact with anything
+        let fb = fm_vec(vec![feature(0, 2.0)]);
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.5);
+
+        // <v_0, v_2> * 2 + <v_0, v_4> * 1 + <v_2, v_4> * 2, as the sum over the pairs
+        let fb = fm_vec(vec![feature(0, 1.0), feature(2, 2.0), feature(4, 1.0)]);
+        let output = -1.5 * 2.0 + 3.0 * 1.0 + 1.5 * 2.0;
+        assert_eq!(
+            spredict2(&mut bg, &fb, &mut pb),
+            block_loss_functions::logistic(output)
+        );
+
+        // One step of SGD on v_0 is -lr * gradient of the loss * x_0 * (v_2 x_2 + v_4 x_4)
+        let logloss_gradient = block_loss_functions::logistic(output) - 1.0;
+        slearn2(&mut bg, &fb, &mut pb, true);
+        let fm = bg.blocks_final[0]
+            .as_any()
+            .downcast_mut::<BlockFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+        let expected = [
+            1.0 - 0.1 * logloss_gradient * (0.5 * 2.0 + 3.0),
+            2.0 - 0.1 * logloss_gradient * (-1.0 * 2.0 + 0.0),
+        ];
+        assert!((fm.weights[0] - expected[0]).abs() < 1e-6);
+        assert!((fm.weights[1] - expected[1]).abs() < 1e-6);
+    }
+}
@@ -39,9 +39,24 @@ macro_rules! assert_epsilon {
     };
 }
 
+// --accurate_accumulation sums in double precision. With hundreds of FFM fields the sigmoid sums tens
+// of thousands of pairwise terms, and the f32 sum drifts measurably from the exact one.
+#[inline(always)]
+pub fn sum_f64(values: &[f32]) -> f32 {
+    values.iter().map(|v| *v as f64).sum::<f64>() as f32
+}
+
+#[inline(always)]
+pub fn dot_f64(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| *x as f64 * *y as f64)
+        .sum::<f64>() as f32
+}
+
 // It's OK! I am a limo driver!
 pub fn read_weights_from_buf<L>(
-    weights: &mut Vec<L>,
+    weights: &mut [L],
     input_bufreader: &mut dyn io::Read,
     _use_quantization: bool,
 ) -> Result<(), Box<dyn Error>> {
@@ -73,7 +88,7 @@ pub fn skip_weights_from_buf<L>(
 }
 
 pub fn write_weights_to_buf<L>(
-    weights: &Vec<L>,
+    weights: &[L],
     output_bufwriter: &mut dyn io::Write,
     _use_quantization: bool,
 ) -> Result<(), Box<dyn Error>> {
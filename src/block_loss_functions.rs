@@ -5,6 +5,8 @@ use crate::block_helpers;
 use crate::feature_buffer;
 use crate::feature_buffer::FeatureBuffer;
 use crate::graph;
+use crate::onnx;
+use crate::parser;
 use crate::port_buffer;
 use crate::port_buffer::PortBuffer;
 use crate::regressor;
@@ -16,17 +18,79 @@ pub fn logistic(t: f32) -> f32 {
     (1.0 + (-t).exp()).recip()
 }
 
+// --invariant: gradient that moves the logit as far as integrating the logloss gradient over the importance
+// of the example would (Karampatziakis & Langford), instead of one step of importance times the gradient,
+// which overshoots with large importances. pred_per_update is how much the logit moves per unit of gradient
+fn invariant_gradient(wsum: f32, positive: bool, importance: f32, pred_per_update: f32) -> f32 {
+    let y = if positive { 1.0 } else { -1.0 };
+    let t0 = y * wsum as f64;
+    let u = pred_per_update as f64 * importance as f64;
+    // With t = y * logit, t + e^t grows by u. Solved with Newton's method, starting past the solution so
+    // the iterations approach it from one side: at the single step, or at ln(target) when that is closer
+    let target = t0 + t0.exp() + u;
+    let mut t = t0 + u / (1.0 + t0.exp());
+    if target > 1.0 {
+        t = t.min(target.ln());
+    }
+    for _ in 0..50 {
+        let step = (t + t.exp() - target) / (1.0 + t.exp());
+        t -= step;
+        if step.abs() < 1e-9 {
+            break;
+        }
+    }
+    (-y * (t - t0) / pred_per_update as f64) as f32
+}
+
+// Derivative of -(1 - p_t)^gamma * ln(p_t) by the logit. Probabilities round to 0 or 1 in f32
+// well within the logit range, the logarithms are kept finite so that x * ln(x) goes to 0 there.
+#[inline(always)]
+fn focal_gradient(p: f32, positive: bool, gamma: f32) -> f32 {
+    if positive {
+        (1.0 - p).powf(gamma) * (p - 1.0 + gamma * p * p.max(f32::MIN_POSITIVE).ln())
+    } else {
+        let q = 1.0 - p;
+        p.powf(gamma) * (p - gamma * q * q.max(f32::MIN_POSITIVE).ln())
+    }
+}
+
 pub struct BlockSigmoid {
     num_inputs: usize,
     input_offset: usize,
     output_offset: usize,
     copy_to_result: bool,
+    accurate_accumulation: bool,
+    focal_gamma: f32,
+    label_smoothing: f32,
 }
 
 pub fn new_logloss_block(
     bg: &mut graph::BlockGraph,
     input: graph::BlockPtrOutput,
     copy_to_result: bool,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    new_logloss_block_with_accumulation(bg, input, copy_to_result, false)
+}
+
+pub fn new_logloss_block_with_accumulation(
+    bg: &mut graph::BlockGraph,
+    input: graph::BlockPtrOutput,
+    copy_to_result: bool,
+    accurate_accumulation: bool,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    new_focal_logloss_block(bg, input, copy_to_result, accurate_accumulation, 0.0, 0.0)
+}
+
+// --focal_gamma: the loss of each example is scaled by (1 - p_t)^gamma, where p_t is the probability
+// of its label, so the many easy negatives of an imbalanced dataset weigh less. Gamma of 0 is the logloss
+// --label_smoothing: the 0/1 label is mixed toward 0.5 by eps, so the model is trained to be less confident
+pub fn new_focal_logloss_block(
+    bg: &mut graph::BlockGraph,
+    input: graph::BlockPtrOutput,
+    copy_to_result: bool,
+    accurate_accumulation: bool,
+    focal_gamma: f32,
+    label_smoothing: f32,
 ) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
     let num_inputs = bg.get_num_output_values(vec![&input]);
     let block = Box::new(BlockSigmoid {
@@ -34,6 +98,9 @@ pub fn new_logloss_block(
         input_offset: usize::MAX,
         output_offset: usize::MAX,
         copy_to_result,
+        accurate_accumulation,
+        focal_gamma,
+        label_smoothing,
     });
     let mut block_outputs = bg.add_node(block, vec![input]).unwrap();
     assert_eq!(block_outputs.len(), 1);
@@ -41,40 +108,48 @@ pub fn new_logloss_block(
 }
 
 impl BlockSigmoid {
+    #[inline(always)]
+    fn wsum(&self, pb: &port_buffer::PortBuffer) -> f32 {
+        unsafe {
+            let inputs = pb
+                .tape
+                .get_unchecked(self.input_offset..(self.input_offset + self.num_inputs));
+            if self.accurate_accumulation {
+                block_helpers::sum_f64(inputs)
+            } else {
+                inputs.iter().sum()
+            }
+        }
+    }
+
     #[inline(always)]
     fn internal_forward(
         &self,
         fb: &feature_buffer::FeatureBuffer,
         pb: &mut port_buffer::PortBuffer,
     ) {
-        unsafe {
-            debug_assert!(self.input_offset != usize::MAX);
-            debug_assert!(self.output_offset != usize::MAX);
-            let wsum: f32 = pb
-                .tape
-                .get_unchecked(self.input_offset..(self.input_offset + self.num_inputs))
-                .iter()
-                .sum();
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+        let wsum: f32 = self.wsum(pb);
 
-            let prediction_probability: f32;
-            if wsum.is_nan() {
-                log::warn!(
-                    "NAN prediction in example {}, forcing 0.0",
-                    fb.example_number
-                );
-                prediction_probability = logistic(0.0);
-            } else if wsum < -50.0 {
-                prediction_probability = logistic(-50.0);
-            } else if wsum > 50.0 {
-                prediction_probability = logistic(50.0);
-            } else {
-                prediction_probability = logistic(wsum);
-            }
+        let prediction_probability: f32;
+        if wsum.is_nan() {
+            log::warn!(
+                "NAN prediction in example {}, forcing 0.0",
+                fb.example_number
+            );
+            prediction_probability = logistic(0.0);
+        } else if wsum < -50.0 {
+            prediction_probability = logistic(-50.0);
+        } else if wsum > 50.0 {
+            prediction_probability = logistic(50.0);
+        } else {
+            prediction_probability = logistic(wsum);
+        }
 
-            pb.tape[self.output_offset] = prediction_probability;
-            if self.copy_to_result {
-                pb.observations.push(prediction_probability);
-            }
+        pb.tape[self.output_offset] = prediction_probability;
+        if self.copy_to_result {
+            pb.observations.push(prediction_probability);
         }
     }
 }
@@ -89,6 +164,32 @@ impl BlockTrait for BlockSigmoid {
         1
     }
 
+    fn get_output_decomposition<'a>(
+        &self,
+        pb: &'a port_buffer::PortBuffer,
+    ) -> Option<regressor::OutputDecomposition<'a>> {
+        Some(regressor::OutputDecomposition::Logit(self.wsum(pb)))
+    }
+
+    // Logistic of the clamped sum of inputs, like forward() of any prediction that isn't NaN
+    fn to_onnx(&self, graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        let input = graph.get_tape(self.input_offset, self.num_inputs)?;
+        let wsum = graph.node(
+            "ReduceSum",
+            &[&input],
+            vec![onnx::OnnxAttribute::Int("keepdims", 1)],
+        );
+        let min = graph.initializer_f32("min", Vec::new(), &[-50.0]);
+        let max = graph.initializer_f32("max", Vec::new(), &[50.0]);
+        let wsum = graph.node("Clip", &[&wsum, &min, &max], Vec::new());
+        let prediction = graph.node("Sigmoid", &[&wsum], Vec::new());
+        graph.set_tape(self.output_offset, 1, &prediction);
+        if self.copy_to_result {
+            graph.output = Some(prediction);
+        }
+        Ok(())
+    }
+
     fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
         assert_eq!(input.get_input_index(), 0);
         assert_eq!(self.input_offset, usize::MAX); // We only allow a single call
@@ -113,11 +214,7 @@ impl BlockTrait for BlockSigmoid {
         debug_assert!(self.output_offset != usize::MAX);
 
         unsafe {
-            let wsum: f32 = pb
-                .tape
-                .get_unchecked(self.input_offset..(self.input_offset + self.num_inputs))
-                .iter()
-                .sum();
+            let wsum: f32 = self.wsum(pb);
 
             let prediction_probability: f32;
             let general_gradient: f32;
@@ -137,7 +234,23 @@ impl BlockTrait for BlockSigmoid {
                 general_gradient = 0.0;
             } else {
                 prediction_probability = logistic(wsum);
-                general_gradient = -(fb.label - prediction_probability) * fb.example_importance;
+                general_gradient = if pb.invariant_gradient.is_some() && pb.pred_per_update > 0.0 {
+                    invariant_gradient(
+                        wsum,
+                        fb.label == 1.0,
+                        fb.example_importance,
+                        pb.pred_per_update,
+                    )
+                } else if self.label_smoothing > 0.0 {
+                    let label = if fb.label == 1.0 { 1.0 } else { 0.0 };
+                    let target = label * (1.0 - self.label_smoothing) + 0.5 * self.label_smoothing;
+                    (prediction_probability - target) * fb.example_importance
+                } else if self.focal_gamma == 0.0 {
+                    -(fb.label - prediction_probability) * fb.example_importance
+                } else {
+                    focal_gradient(prediction_probability, fb.label == 1.0, self.focal_gamma)
+                        * fb.example_importance
+                };
             }
 
             *pb.tape.get_unchecked_mut(self.output_offset) = prediction_probability;
@@ -173,3 +286,654 @@ impl BlockTrait for BlockSigmoid {
         block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
     }
 }
+
+// Pairwise ranking loss of --bpr: -ln(logistic(positive logit - negative logit)) of a pair of examples.
+// Each example of the pair is learned on its own, with the logit of its partner in the port buffer.
+// Predictions are logistic of the logit, so they rank the same way as the logits.
+pub struct BlockBPRLoss {
+    num_inputs: usize,
+    input_offset: usize,
+    output_offset: usize,
+    copy_to_result: bool,
+    accurate_accumulation: bool,
+}
+
+pub fn new_bpr_block(
+    bg: &mut graph::BlockGraph,
+    input: graph::BlockPtrOutput,
+    copy_to_result: bool,
+    accurate_accumulation: bool,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input]);
+    let block = Box::new(BlockBPRLoss {
+        num_inputs,
+        input_offset: usize::MAX,
+        output_offset: usize::MAX,
+        copy_to_result,
+        accurate_accumulation,
+    });
+    let mut block_outputs = bg.add_node(block, vec![input]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+impl BlockBPRLoss {
+    #[inline(always)]
+    fn internal_forward(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) -> f32 {
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+        let inputs = &pb.tape[self.input_offset..(self.input_offset + self.num_inputs)];
+        let mut wsum: f32 = if self.accurate_accumulation {
+            block_helpers::sum_f64(inputs)
+        } else {
+            inputs.iter().sum()
+        };
+        if wsum.is_nan() {
+            log::warn!(
+                "NAN prediction in example {}, forcing 0.0",
+                fb.example_number
+            );
+            wsum = 0.0;
+        }
+        let wsum = wsum.clamp(-50.0, 50.0);
+
+        let prediction = logistic(wsum);
+        pb.tape[self.output_offset] = prediction;
+        pb.bpr_logit = wsum;
+        if self.copy_to_result {
+            pb.observations.push(prediction);
+        }
+        wsum
+    }
+}
+
+impl BlockTrait for BlockBPRLoss {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        1
+    }
+
+    fn get_output_decomposition<'a>(
+        &self,
+        pb: &'a port_buffer::PortBuffer,
+    ) -> Option<regressor::OutputDecomposition<'a>> {
+        Some(regressor::OutputDecomposition::Logit(pb.bpr_logit))
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert_eq!(input.get_input_index(), 0);
+        assert_eq!(self.input_offset, usize::MAX); // We only allow a single call
+        self.input_offset = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(self.output_offset, usize::MAX); // We only allow a single call
+        assert_eq!(output.get_output_index(), 0);
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        self.internal_forward(fb, pb);
+        // Examples outside of a pair don't learn, in a pair the positive is pushed up and the
+        // negative down by the same gradient
+        let general_gradient = match pb.bpr_pair_gradient {
+            Some(pair_gradient) => {
+                let sign = if fb.label == 1.0 { 1.0 } else { -1.0 };
+                -sign * pair_gradient * fb.example_importance
+            }
+            None => 0.0,
+        };
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+        // replace inputs with their gradients
+        pb.tape[self.input_offset..(self.input_offset + self.num_inputs)].fill(general_gradient);
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+}
+
+// Multiclass head of --oaa: softmax over one input per class, labels are class numbers from 1 to num_inputs
+pub struct BlockSoftmaxLoss {
+    num_inputs: usize,
+    input_offset: usize,
+    output_offset: usize,
+    copy_to_result: bool,
+}
+
+pub fn new_softmax_block(
+    bg: &mut graph::BlockGraph,
+    input: graph::BlockPtrOutput,
+    copy_to_result: bool,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input]);
+    let block = Box::new(BlockSoftmaxLoss {
+        num_inputs,
+        input_offset: usize::MAX,
+        output_offset: usize::MAX,
+        copy_to_result,
+    });
+    let mut block_outputs = bg.add_node(block, vec![input]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+impl BlockSoftmaxLoss {
+    // Writes the class probabilities to the output, false when they could not be computed
+    #[inline(always)]
+    fn internal_forward(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) -> bool {
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+        debug_assert!(self.input_offset + self.num_inputs <= self.output_offset);
+        let (inputs, outputs) = pb.tape.split_at_mut(self.output_offset);
+        let inputs = &inputs[self.input_offset..(self.input_offset + self.num_inputs)];
+        let outputs = &mut outputs[..self.num_inputs];
+
+        let max_input = inputs.iter().cloned().fold(f32::MIN, f32::max);
+        let valid = !inputs.iter().any(|x| x.is_nan());
+        if valid {
+            // Shifted by the largest input, so exp() can't overflow
+            let mut sum = 0.0;
+            for (output, input) in outputs.iter_mut().zip(inputs.iter()) {
+                *output = (input - max_input).exp();
+                sum += *output;
+            }
+            outputs.iter_mut().for_each(|output| *output /= sum);
+        } else {
+            log::warn!(
+                "NAN prediction in example {}, forcing uniform class probabilities",
+                fb.example_number
+            );
+            outputs.fill(1.0 / self.num_inputs as f32);
+        }
+
+        if self.copy_to_result {
+            let mut best_class = 0;
+            for (i, output) in outputs.iter().enumerate() {
+                if *output > outputs[best_class] {
+                    best_class = i;
+                }
+            }
+            pb.class_probabilities.truncate(0);
+            pb.class_probabilities.extend_from_slice(outputs);
+            pb.observations.push((best_class + 1) as f32);
+        }
+        valid
+    }
+}
+
+impl BlockTrait for BlockSoftmaxLoss {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        self.num_inputs
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert_eq!(input.get_input_index(), 0);
+        assert_eq!(self.input_offset, usize::MAX); // We only allow a single call
+        self.input_offset = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(self.output_offset, usize::MAX); // We only allow a single call
+        assert_eq!(output.get_output_index(), 0);
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        let valid = self.internal_forward(fb, pb);
+        // replace inputs with their gradients, examples without a known class don't learn.
+        // Done before further blocks run, as they overwrite the outputs with their own gradients
+        let class = fb.label as usize;
+        let learn = valid && class >= 1 && class <= self.num_inputs;
+        for i in 0..self.num_inputs {
+            pb.tape[self.input_offset + i] = if learn {
+                let target = if i + 1 == class { 1.0 } else { 0.0 };
+                (pb.tape[self.output_offset + i] - target) * fb.example_importance
+            } else {
+                0.0
+            };
+        }
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+}
+
+// --heads: a logloss per input, each with its own label. The first head is the prediction of the
+// model, the probabilities of all of them go to pb.head_predictions
+pub struct BlockHeadsLoss {
+    num_inputs: usize,
+    input_offset: usize,
+    output_offset: usize,
+    copy_to_result: bool,
+    focal_gamma: f32,
+    label_smoothing: f32,
+}
+
+pub fn new_heads_block(
+    bg: &mut graph::BlockGraph,
+    input: graph::BlockPtrOutput,
+    copy_to_result: bool,
+    focal_gamma: f32,
+    label_smoothing: f32,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input]);
+    let block = Box::new(BlockHeadsLoss {
+        num_inputs,
+        input_offset: usize::MAX,
+        output_offset: usize::MAX,
+        copy_to_result,
+        focal_gamma,
+        label_smoothing,
+    });
+    let mut block_outputs = bg.add_node(block, vec![input]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+impl BlockHeadsLoss {
+    #[inline(always)]
+    fn internal_forward(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+        debug_assert!(self.input_offset + self.num_inputs <= self.output_offset);
+        let (inputs, outputs) = pb.tape.split_at_mut(self.output_offset);
+        let inputs = &inputs[self.input_offset..(self.input_offset + self.num_inputs)];
+        let outputs = &mut outputs[..self.num_inputs];
+        for (output, input) in outputs.iter_mut().zip(inputs.iter()) {
+            *output = if input.is_nan() {
+                log::warn!(
+                    "NAN prediction in example {}, forcing 0.0",
+                    fb.example_number
+                );
+                logistic(0.0)
+            } else {
+                logistic(input.clamp(-50.0, 50.0))
+            };
+        }
+        if self.copy_to_result {
+            pb.head_predictions.truncate(0);
+            pb.head_predictions.extend_from_slice(outputs);
+            pb.observations.push(outputs[0]);
+        }
+    }
+
+    // Label of the head as in fb.label, NO_LABEL when the example has none for it
+    #[inline(always)]
+    fn head_label(fb: &feature_buffer::FeatureBuffer, head: usize) -> f32 {
+        if head == 0 {
+            fb.label
+        } else {
+            *fb.head_labels
+                .get(head - 1)
+                .unwrap_or(&(parser::NO_LABEL as f32))
+        }
+    }
+}
+
+impl BlockTrait for BlockHeadsLoss {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        self.num_inputs
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert_eq!(input.get_input_index(), 0);
+        assert_eq!(self.input_offset, usize::MAX); // We only allow a single call
+        self.input_offset = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(self.output_offset, usize::MAX); // We only allow a single call
+        assert_eq!(output.get_output_index(), 0);
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        self.internal_forward(fb, pb);
+        // replace inputs with their gradients, heads without a label don't learn from the example.
+        // Done before further blocks run, as they overwrite the outputs with their own gradients
+        for head in 0..self.num_inputs {
+            let label = Self::head_label(fb, head);
+            let input = pb.tape[self.input_offset + head];
+            let p = pb.tape[self.output_offset + head];
+            // Saturated and NaN logits don't learn, like in BlockSigmoid
+            let learn = (label == 0.0 || label == 1.0) && input.abs() <= 50.0;
+            pb.tape[self.input_offset + head] = if !learn {
+                0.0
+            } else if self.focal_gamma > 0.0 {
+                focal_gradient(p, label == 1.0, self.focal_gamma) * fb.example_importance
+            } else {
+                let target = label * (1.0 - self.label_smoothing) + 0.5 * self.label_smoothing;
+                (p - target) * fb.example_importance
+            };
+        }
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::block_helpers::{slearn2, spredict2};
+    use crate::block_misc;
+    use crate::graph::BlockGraph;
+    use crate::model_instance;
+    use crate::parser;
+
+    fn fb_vec() -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 1.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
+    }
+
+    fn predict_sum(inputs: Vec<f32>, accurate_accumulation: bool) -> (f32, f32) {
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, inputs).unwrap();
+        new_logloss_block_with_accumulation(&mut bg, input_block, true, accurate_accumulation)
+            .unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+        let fb = fb_vec();
+        (
+            spredict2(&mut bg, &fb, &mut pb),
+            slearn2(&mut bg, &fb, &mut pb, false),
+        )
+    }
+
+    #[test]
+    fn test_accurate_accumulation() {
+        // As many inputs as pairwise terms of 300 FFM fields, each too small to change an f32 sum of 1.0
+        let mut inputs = vec![1.0f32];
+        inputs.extend(vec![1e-8f32; 45000]);
+        let exact_wsum = 1.0 + 45000.0 * 1e-8;
+        assert_eq!(inputs.iter().sum::<f32>(), 1.0);
+        assert!((block_helpers::sum_f64(&inputs) as f64 - exact_wsum).abs() < 1e-7);
+        assert!(
+            (block_helpers::dot_f64(&inputs, &vec![2.0; inputs.len()]) as f64 - 2.0 * exact_wsum)
+                .abs()
+                < 1e-6
+        );
+
+        // The drift of 4.5e-4 in wsum shows in the prediction of the fast path only
+        let exact_prediction = logistic(exact_wsum as f32);
+        let (fast_prediction, fast_learn_prediction) = predict_sum(inputs.clone(), false);
+        let (accurate_prediction, accurate_learn_prediction) = predict_sum(inputs, true);
+        assert_eq!(fast_prediction, logistic(1.0));
+        assert!((exact_prediction - fast_prediction).abs() > 5e-5);
+        assert!((exact_prediction - accurate_prediction).abs() < 1e-7);
+        assert_eq!(fast_prediction, fast_learn_prediction);
+        assert_eq!(accurate_prediction, accurate_learn_prediction);
+    }
+
+    #[test]
+    fn test_focal_gradient() {
+        fn focal_loss(logit: f64, positive: bool, gamma: f64) -> f64 {
+            let p = 1.0 / (1.0 + (-logit).exp());
+            let p_t = if positive { p } else { 1.0 - p };
+            -(1.0 - p_t).powf(gamma) * p_t.ln()
+        }
+        for &gamma in [0.0f32, 0.5, 2.0].iter() {
+            for &logit in [-3.0f32, -0.2, 0.0, 1.5, 4.0].iter() {
+                for &positive in [true, false].iter() {
+                    let h = 1e-4;
+                    let numeric = (focal_loss(logit as f64 + h, positive, gamma as f64)
+                        - focal_loss(logit as f64 - h, positive, gamma as f64))
+                        / (2.0 * h);
+                    let gradient = focal_gradient(logistic(logit), positive, gamma);
+                    assert!((gradient as f64 - numeric).abs() < 1e-4);
+                }
+            }
+        }
+        // Gamma of 0 is the logloss gradient
+        assert!((focal_gradient(0.3, true, 0.0) - (0.3 - 1.0)).abs() < 1e-7);
+        assert!((focal_gradient(0.3, false, 0.0) - 0.3).abs() < 1e-7);
+        assert_eq!(focal_gradient(logistic(-40.0), true, 2.0), -1.0);
+        assert_eq!(focal_gradient(logistic(40.0), false, 2.0), 1.0);
+        // Easy examples weigh less
+        assert!(focal_gradient(0.05, false, 2.0).abs() < 0.05 * 0.01);
+    }
+
+    #[test]
+    fn test_label_smoothing() {
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![0.0]).unwrap();
+        new_focal_logloss_block(&mut bg, input_block, true, false, 0.0, 0.2).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+        let mut fb = fb_vec();
+        fb.example_importance = 2.0;
+        // Inputs are replaced with the gradient, toward 0.9 instead of 1 and 0.1 instead of 0
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+        assert!((pb.tape[0] - (0.5 - 0.9) * 2.0).abs() < 1e-6);
+        fb.label = -1.0;
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+        assert!((pb.tape[0] - (0.5 - 0.1) * 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_heads() {
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![0.0, 1.0, -60.0]).unwrap();
+        new_heads_block(&mut bg, input_block, true, 0.0, 0.0).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+        let mut fb = fb_vec();
+        fb.head_labels = vec![0.0, 1.0];
+
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 0.5);
+        assert_eq!(
+            pb.head_predictions,
+            vec![0.5, logistic(1.0), logistic(-50.0)]
+        );
+        // Each head learns toward its own label, saturated ones don't
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+        assert_eq!(pb.tape[..3], [0.5 - 1.0, logistic(1.0), 0.0]);
+
+        // Heads without a label don't learn
+        fb.head_labels = vec![parser::NO_LABEL as f32];
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.tape[..3], [0.5 - 1.0, 0.0, 0.0]);
+        fb.label = parser::NO_LABEL as f32;
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.tape[..3], [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_invariant_gradient() {
+        // Small importance is one step of the logloss gradient
+        for &positive in [true, false].iter() {
+            let label = if positive { 1.0 } else { 0.0 };
+            let gradient = invariant_gradient(0.5, positive, 0.01, 0.1) / 0.01;
+            assert!((gradient - (logistic(0.5) - label)).abs() < 1e-3);
+        }
+        // Large importance moves the logit towards the label, but never past it, unlike the single step
+        let gradient = invariant_gradient(0.0, true, 1000.0, 0.1);
+        let single_step = (logistic(0.0) - 1.0) * 1000.0;
+        assert!(gradient < 0.0 && gradient > single_step);
+        let logit = 0.0 - gradient * 0.1;
+        assert!(logit > 0.0 && logit < 0.5 * 1000.0 * 0.1);
+        // Same as importance one applied twice
+        let first = invariant_gradient(-1.0, true, 1.0, 0.5);
+        let logit = -1.0 - first * 0.5;
+        let second = invariant_gradient(logit, true, 1.0, 0.5);
+        let twice = invariant_gradient(-1.0, true, 2.0, 0.5);
+        assert!((first + second - twice).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_bpr() {
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![0.5, 1.0]).unwrap();
+        new_bpr_block(&mut bg, input_block, true, false).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+        let mut fb = fb_vec();
+
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), logistic(1.5));
+        assert_eq!(pb.bpr_logit, 1.5);
+        // Outside of a pair there is no gradient
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.tape[0..2], [0.0, 0.0]);
+
+        // The positive is pushed above its partner, the negative below by the same gradient
+        pb.bpr_pair_gradient = Some(logistic(0.5));
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), logistic(1.5));
+        assert_eq!(pb.tape[0], -logistic(0.5));
+        fb.label = 0.0;
+        fb.example_importance = 2.0;
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.tape[0], logistic(0.5) * 2.0);
+    }
+
+    #[test]
+    fn test_softmax() {
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![1.0, 2.0, 3.0]).unwrap();
+        new_softmax_block(&mut bg, input_block, true).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+        let mut fb = fb_vec();
+        fb.label = 2.0;
+        fb.example_importance = 0.5;
+
+        let sum = 1.0f32.exp() + 2.0f32.exp() + 3.0f32.exp();
+        let expected = [1.0f32.exp() / sum, 2.0f32.exp() / sum, 3.0f32.exp() / sum];
+        assert_eq!(spredict2(&mut bg, &fb, &mut pb), 3.0); // the most likely class
+        for (p, e) in pb.class_probabilities.iter().zip(expected.iter()) {
+            assert!((p - e).abs() < 1e-6);
+        }
+        assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 3.0);
+        // Gradients of the inputs, (probability - target) * importance
+        let gradients = &pb.tape[0..3];
+        assert!((gradients[0] - expected[0] * 0.5).abs() < 1e-6);
+        assert!((gradients[1] - (expected[1] - 1.0) * 0.5).abs() < 1e-6);
+        assert!((gradients[2] - expected[2] * 0.5).abs() < 1e-6);
+
+        // Without a known class, nothing is learned
+        fb.label = parser::NO_LABEL as f32;
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.tape[0..3], [0.0, 0.0, 0.0]);
+    }
+}
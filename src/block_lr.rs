@@ -2,16 +2,22 @@ use std::any::Any;
 
 use crate::graph;
 use crate::model_instance;
+use crate::onnx;
 use crate::optimizer;
 use crate::regressor;
 use crate::{feature_buffer, parser};
 
 use std::error::Error;
 use std::io;
+use std::mem;
+use std::ops::Range;
 
 use crate::block_helpers;
+use crate::delta;
 use crate::port_buffer;
 use crate::regressor::BlockCache;
+use crate::simd;
+use crate::vw_import;
 use block_helpers::WeightAndOptimizerData;
 use optimizer::OptimizerTrait;
 use regressor::BlockTrait;
@@ -22,9 +28,50 @@ pub struct BlockLR<L: OptimizerTrait> {
     pub optimizer_lr: L,
     pub output_offset: usize,
     pub num_combos: u32,
+    // --namespace_ttl: per combo decay rate (0.0 means no decay) and example number of the last touch of each weight
+    combo_decay_rates: Vec<f32>,
+    last_touch: Vec<u32>,
+    dp: Option<optimizer::DPGradient>,
+    lr_schedule: Option<model_instance::LRSchedule>,
+    grad_clip: f32,
+    weight_decay: f32,
+    dirty: Option<delta::DirtyChunks>, // --delta_base
 }
 
 impl<L: OptimizerTrait + 'static> BlockLR<L> {
+    fn tracks_last_touch(&self) -> bool {
+        self.combo_decay_rates.iter().any(|r| *r != 0.0)
+    }
+
+    // Lazily applies decay for all the examples in which the touched weights were not seen
+    #[inline(always)]
+    fn decay_touched_weights(&mut self, fb: &feature_buffer::FeatureBuffer) {
+        // Wrapping clock, it only needs to be right for gaps shorter than 4G examples
+        let now = fb.example_number as u32;
+        unsafe {
+            for feature in fb.lr_buffer.iter() {
+                let decay_rate = *self
+                    .combo_decay_rates
+                    .get_unchecked(feature.combo_index as usize);
+                if decay_rate == 0.0 {
+                    continue;
+                }
+                let feature_index = feature.hash as usize;
+                let last_touch = self.last_touch.get_unchecked_mut(feature_index);
+                let elapsed = now.wrapping_sub(*last_touch);
+                if let Some(dirty) = &self.dirty {
+                    dirty.mark(feature_index);
+                }
+                if elapsed > 1 {
+                    // One example is the current one, it does not count as unseen
+                    self.weights.get_unchecked_mut(feature_index).weight *=
+                        (-decay_rate * (elapsed - 1) as f32).exp();
+                }
+                *last_touch = now;
+            }
+        }
+    }
+
     fn internal_forward(
         &self,
         fb: &feature_buffer::FeatureBuffer,
@@ -54,16 +101,34 @@ fn new_lr_block_without_weights<L: OptimizerTrait + 'static>(
     if mi.add_constant_feature {
         num_combos += 1;
     }
+    // Half-life of ttl examples, constant feature never decays
+    let mut combo_decay_rates: Vec<f32> = vec![0.0; num_combos as usize];
+    for (combo_index, combo) in mi.feature_combo_descs.iter().enumerate() {
+        if let Some(ttl) = mi.get_feature_combo_ttl(combo) {
+            combo_decay_rates[combo_index] = std::f32::consts::LN_2 / ttl as f32;
+        }
+    }
     let mut reg_lr = BlockLR::<L> {
         weights: Vec::new(),
         weights_len: 0,
         optimizer_lr: L::new(),
         output_offset: usize::MAX,
         num_combos,
+        combo_decay_rates,
+        last_touch: Vec::new(),
+        dp: mi
+            .dp
+            .map(|dp| optimizer::DPGradient::new(dp.clip, dp.noise_multiplier)),
+        lr_schedule: mi.lr_schedule.clone(),
+        grad_clip: mi.grad_clip,
+        weight_decay: mi.weight_decay,
+        dirty: None,
     };
     reg_lr
         .optimizer_lr
         .init(mi.learning_rate, mi.power_t, mi.init_acc_gradient);
+    reg_lr.optimizer_lr.init_regularization(mi.l1, mi.l2);
+    reg_lr.optimizer_lr.init_momentum(mi.momentum);
     reg_lr.weights_len = 1 << mi.bit_precision;
     Ok(Box::new(reg_lr))
 }
@@ -72,16 +137,31 @@ pub fn new_lr_block(
     bg: &mut graph::BlockGraph,
     mi: &model_instance::ModelInstance,
 ) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
-    let block = match mi.optimizer {
+    let block = match mi.lr_optimizer.unwrap_or(mi.optimizer) {
         model_instance::Optimizer::AdagradLUT => {
             new_lr_block_without_weights::<optimizer::OptimizerAdagradLUT>(mi)
         }
+        model_instance::Optimizer::COCOB => {
+            new_lr_block_without_weights::<optimizer::OptimizerCOCOB>(mi)
+        }
         model_instance::Optimizer::AdagradFlex => {
             new_lr_block_without_weights::<optimizer::OptimizerAdagradFlex>(mi)
         }
         model_instance::Optimizer::SGD => {
             new_lr_block_without_weights::<optimizer::OptimizerSGD>(mi)
         }
+        model_instance::Optimizer::Adam => {
+            new_lr_block_without_weights::<optimizer::OptimizerAdam>(mi)
+        }
+        model_instance::Optimizer::FTRL => {
+            new_lr_block_without_weights::<optimizer::OptimizerFTRL>(mi)
+        }
+        model_instance::Optimizer::SGDMomentum => {
+            new_lr_block_without_weights::<optimizer::OptimizerSGDMomentum<false>>(mi)
+        }
+        model_instance::Optimizer::SGDNesterov => {
+            new_lr_block_without_weights::<optimizer::OptimizerSGDMomentum<true>>(mi)
+        }
     }
     .unwrap();
     let mut block_outputs = bg.add_node(block, vec![])?;
@@ -102,6 +182,43 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
             };
             self.weights_len as usize
         ];
+        if self.tracks_last_touch() {
+            self.last_touch = vec![0; self.weights_len as usize];
+        }
+    }
+
+    fn load_pretrained_weights(
+        &mut self,
+        mi: &model_instance::ModelInstance,
+    ) -> Result<(), Box<dyn Error>> {
+        let filename = match &mi.vw_initial_regressor {
+            Some(filename) => filename,
+            None => return Ok(()),
+        };
+        let model = vw_import::read_vw_readable_model(filename)?;
+        if model.bits != mi.bit_precision {
+            return Err(format!(
+                "VW model {} has {} bits, hashes only match with the same --bit_precision, got: {}",
+                filename, model.bits, mi.bit_precision
+            ))?;
+        }
+        for (index, weight) in model.weights.iter() {
+            match self.weights.get_mut(*index) {
+                Some(w) => w.weight = *weight,
+                None => {
+                    return Err(format!(
+                        "VW model {} has a weight at index {}, past the {} LR weights",
+                        filename, index, self.weights_len
+                    ))?
+                }
+            }
+        }
+        log::info!(
+            "Loaded {} LR weights from VW model {}",
+            model.weights.len(),
+            filename
+        );
+        Ok(())
     }
 
     fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
@@ -128,7 +245,32 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         update: bool,
     ) {
         unsafe {
+            if update && !self.last_touch.is_empty() {
+                self.decay_touched_weights(fb);
+            }
+            if update {
+                if let Some(schedule) = self.lr_schedule.as_ref() {
+                    self.optimizer_lr
+                        .set_learning_rate_multiplier(schedule.multiplier_at(fb.example_number));
+                }
+            }
             self.internal_forward(fb, pb);
+            if update {
+                if let Some(gradient) = pb.invariant_gradient {
+                    for feature in fb.lr_buffer.iter() {
+                        let optimizer_data = &self
+                            .weights
+                            .get_unchecked(feature.hash as usize)
+                            .optimizer_data;
+                        pb.pred_per_update += optimizer::step_size(
+                            &self.optimizer_lr,
+                            gradient * feature.value,
+                            optimizer_data,
+                        ) * feature.value
+                            * feature.value;
+                    }
+                }
+            }
 
             block_helpers::forward_backward(further_blocks, fb, pb, update);
 
@@ -137,21 +279,63 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
                     self.output_offset..(self.output_offset + self.num_combos as usize),
                 );
 
+                let clip_factor = if self.dp.is_some() || self.grad_clip > 0.0 {
+                    let mut gradient_norm_squared = 0.0;
+                    for feature in fb.lr_buffer.iter() {
+                        let gradient =
+                            myslice.get_unchecked(feature.combo_index as usize) * feature.value;
+                        gradient_norm_squared += gradient * gradient;
+                    }
+                    let mut clip_factor = match self.dp.as_ref() {
+                        Some(dp) => dp.clip_factor(gradient_norm_squared),
+                        None => 1.0,
+                    };
+                    if self.grad_clip > 0.0 {
+                        clip_factor = clip_factor.min(optimizer::clip_factor(
+                            self.grad_clip,
+                            gradient_norm_squared,
+                        ));
+                    }
+                    clip_factor
+                } else {
+                    1.0
+                };
+
                 for feature in fb.lr_buffer.iter() {
                     let feature_index = feature.hash as usize;
                     let feature_value = feature.value;
-                    let gradient =
+                    let mut gradient =
                         myslice.get_unchecked(feature.combo_index as usize) * feature_value;
+                    match self.dp.as_mut() {
+                        Some(dp) => gradient = dp.privatize(gradient, clip_factor),
+                        None => gradient *= clip_factor,
+                    }
                     let update = self.optimizer_lr.calculate_update(
                         gradient,
                         &mut self.weights.get_unchecked_mut(feature_index).optimizer_data,
                     );
-                    self.weights.get_unchecked_mut(feature_index).weight -= update;
+                    let weight = &mut self.weights.get_unchecked_mut(feature_index).weight;
+                    *weight -= update + self.weight_decay * *weight;
+                    if let Some(dirty) = &self.dirty {
+                        dirty.mark(feature_index);
+                    }
                 }
             }
         }
     }
 
+    fn prefetch(&self, fb: &feature_buffer::FeatureBuffer) {
+        for feature in fb.lr_buffer.iter() {
+            if let Some(weight) = self.weights.get(feature.hash as usize) {
+                simd::prefetch(&weight.weight);
+            }
+        }
+    }
+
+    fn set_lr_schedule(&mut self, lr_schedule: &Option<model_instance::LRSchedule>) {
+        self.lr_schedule = lr_schedule.clone();
+    }
+
     fn forward(
         &self,
         further_blocks: &[Box<dyn BlockTrait>],
@@ -258,12 +442,57 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         self.weights_len as usize
     }
 
+    fn get_serialized_block_id(&self) -> u32 {
+        regressor::SERIALIZED_BLOCK_ID_LR
+    }
+
+    fn track_dirty_weights(&mut self) {
+        self.dirty = Some(delta::DirtyChunks::new(self.weights.len()));
+    }
+
+    // Last touches follow the weights, and are dirty where they are
+    fn dirty_weight_ranges(&self) -> Option<Vec<Range<usize>>> {
+        self.dirty.as_ref().map(|dirty| {
+            let weights_len = mem::size_of::<WeightAndOptimizerData<L>>();
+            let mut ranges = dirty.byte_ranges(0, weights_len, self.weights.len());
+            if !self.last_touch.is_empty() {
+                ranges.extend(dirty.byte_ranges(
+                    self.weights.len() * weights_len,
+                    mem::size_of::<u32>(),
+                    self.last_touch.len(),
+                ));
+            }
+            ranges
+        })
+    }
+
     fn read_weights_from_buf(
         &mut self,
         input_bufreader: &mut dyn io::Read,
         _use_quantization: bool,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader, false)
+        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader, false)?;
+        if !self.last_touch.is_empty() {
+            // Blocks of files before version 11 end after the weights, their last touches stay 0
+            let mut last_touch: Vec<u8> = Vec::new();
+            input_bufreader.read_to_end(&mut last_touch)?;
+            if last_touch.is_empty() {
+                log::warn!("Regressor file has no last touches of --namespace_ttl weights, they decay as if untouched since the first example");
+            } else if last_touch.len() != self.last_touch.len() * mem::size_of::<u32>() {
+                return Err(format!(
+                    "Regressor file has {} bytes of last touches of --namespace_ttl weights, expected {}",
+                    last_touch.len(),
+                    self.last_touch.len() * mem::size_of::<u32>()
+                ))?;
+            } else {
+                block_helpers::read_weights_from_buf(
+                    &mut self.last_touch,
+                    &mut last_touch.as_slice(),
+                    false,
+                )?;
+            }
+        }
+        Ok(())
     }
 
     fn write_weights_to_buf(
@@ -271,7 +500,12 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         output_bufwriter: &mut dyn io::Write,
         _use_quantization: bool,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter, false)
+        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter, false)?;
+        // --namespace_ttl: decay continues where it stopped
+        if !self.last_touch.is_empty() {
+            block_helpers::write_weights_to_buf(&self.last_touch, output_bufwriter, false)?;
+        }
+        Ok(())
     }
 
     fn read_weights_from_buf_into_forward_only(
@@ -288,6 +522,43 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
             self.weights_len as usize,
             &mut forward.weights,
             input_bufreader,
-        )
+        )?;
+        // Predictions don't decay weights
+        if self.tracks_last_touch() {
+            block_helpers::skip_weights_from_buf::<u32>(
+                self.weights_len as usize,
+                input_bufreader,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_output_decomposition<'a>(
+        &self,
+        pb: &'a port_buffer::PortBuffer,
+    ) -> Option<regressor::OutputDecomposition<'a>> {
+        Some(regressor::OutputDecomposition::LRCombos(
+            &pb.tape[self.output_offset..(self.output_offset + self.num_combos as usize)],
+        ))
+    }
+
+    // Contributions of features are summed by their combo
+    fn to_onnx(&self, graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        let num_combos = self.num_combos as usize;
+        let weights: Vec<f32> = self.weights.iter().map(|w| w.weight).collect();
+        let weights = graph.initializer_f32("lr_weights", vec![weights.len() as i64], &weights);
+        let hash = graph.input("lr_hash", onnx::ONNX_INT64);
+        let value = graph.input("lr_value", onnx::ONNX_FLOAT);
+        let combo = graph.input("lr_combo", onnx::ONNX_INT64);
+        let feature_weights = graph.node("Gather", &[&weights, &hash], Vec::new());
+        let contributions = graph.node("Mul", &[&feature_weights, &value], Vec::new());
+        // Summed by combo with a features x combos one-hot matrix, not every runtime supports
+        // ScatterElements with reduction (tract keeps the last feature of a combo)
+        let depth = graph.initializer_i64("depth", vec![1], &[num_combos as i64]);
+        let one_hot_values = graph.initializer_f32("one_hot_values", vec![2], &[0.0, 1.0]);
+        let one_hot = graph.node("OneHot", &[&combo, &depth, &one_hot_values], Vec::new());
+        let output = graph.node("MatMul", &[&contributions, &one_hot], Vec::new());
+        graph.set_tape(self.output_offset, num_combos, &output);
+        Ok(())
     }
 }
@@ -4,6 +4,9 @@ use std::error::Error;
 use crate::block_helpers;
 use crate::feature_buffer;
 use crate::graph;
+use crate::model_instance;
+use crate::model_instance::EmbeddingPooling;
+use crate::onnx;
 use crate::port_buffer;
 use crate::regressor;
 
@@ -16,6 +19,7 @@ use regressor::BlockTrait;
 pub enum Observe {
     Forward,
     Backward,
+    Named(String), // leaves the values on the tape, predict_decomposed() returns them by name
 }
 
 pub struct BlockObserve {
@@ -75,6 +79,24 @@ impl BlockTrait for BlockObserve {
         Ok(self.input_offset)
     }
 
+    fn get_output_decomposition<'a>(
+        &self,
+        pb: &'a port_buffer::PortBuffer,
+    ) -> Option<regressor::OutputDecomposition<'a>> {
+        match &self.observe {
+            Observe::Named(name) => Some(regressor::OutputDecomposition::Block(
+                name.clone(),
+                &pb.tape[self.input_offset..(self.input_offset + self.num_inputs)],
+            )),
+            _ => None,
+        }
+    }
+
+    // Output is the input, at the same place of the tape
+    fn to_onnx(&self, _graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     #[inline(always)]
     fn forward_backward(
         &mut self,
@@ -223,6 +245,10 @@ impl BlockTrait for BlockSink {
         assert!(false, "No outputs in BlockSink");
     }
 
+    fn to_onnx(&self, _graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     #[inline(always)]
     fn forward_backward(
         &mut self,
@@ -351,6 +377,93 @@ impl BlockTrait for BlockConsts {
     }
 }
 
+// Writes the dense input values of the example (--dense_input) to its output, there is nothing to learn
+pub struct BlockDenseInput {
+    pub output_offset: usize,
+    num_values: usize,
+}
+
+pub fn new_dense_input_block(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_values = mi.get_dense_inputs_len();
+    assert_ne!(num_values, 0);
+    let block = Box::new(BlockDenseInput {
+        output_offset: usize::MAX,
+        num_values,
+    });
+    let mut block_outputs = bg.add_node(block, vec![])?;
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+impl BlockDenseInput {
+    fn internal_forward(&self, fb: &FeatureBuffer, pb: &mut port_buffer::PortBuffer) {
+        debug_assert!(self.output_offset != usize::MAX);
+        debug_assert_eq!(fb.dense_buffer.len(), self.num_values);
+        pb.tape[self.output_offset..(self.output_offset + self.num_values)]
+            .copy_from_slice(&fb.dense_buffer);
+    }
+}
+
+impl BlockTrait for BlockDenseInput {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        self.num_values
+    }
+
+    fn set_input_offset(&mut self, _input: graph::InputSlot, _offset: usize) {
+        panic!("You cannot set input_tape_index for BlockDenseInput");
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(
+            output.get_output_index(),
+            0,
+            "Only supports a single output for BlockDenseInput"
+        );
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        self.internal_forward(fb, pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+}
+
 pub struct BlockCopy {
     pub num_inputs: usize,
     pub input_offset: usize,
@@ -431,6 +544,15 @@ impl BlockTrait for BlockCopy {
         }
     }
 
+    // Copies are the same tensor at all the outputs
+    fn to_onnx(&self, graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        let input = graph.get_tape(self.input_offset, self.num_inputs)?;
+        for output_offset in self.output_offsets.iter() {
+            graph.set_tape(*output_offset, self.num_inputs, &input);
+        }
+        Ok(())
+    }
+
     #[inline(always)]
     fn forward_backward(
         &mut self,
@@ -589,6 +711,11 @@ impl BlockTrait for BlockJoin {
         self.output_offset = offset;
     }
 
+    // Inputs are already next to each other on the tape, readers of the output concatenate them
+    fn to_onnx(&self, _graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     // WARNING: These two functions are automatically removed from the graph when executing, since they are a no-op
     #[inline(always)]
     fn forward_backward(
@@ -794,6 +921,25 @@ impl BlockTrait for BlockTriangle {
         self.output_offset = offset;
     }
 
+    fn to_onnx(&self, graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        let input = graph.get_tape(self.input_offset, self.num_inputs)?;
+        let mut indexes: Vec<i64> = Vec::with_capacity(self.num_outputs);
+        let mut scales: Vec<f32> = Vec::with_capacity(self.num_outputs);
+        for i in 0..self.square_width {
+            for j in 0..i + 1 {
+                indexes.push((i * self.square_width + j) as i64);
+                scales.push(if j < i { 2.0 } else { 1.0 });
+            }
+        }
+        let indexes = graph.initializer_i64("triangle", vec![self.num_outputs as i64], &indexes);
+        let scales =
+            graph.initializer_f32("triangle_scales", vec![self.num_outputs as i64], &scales);
+        let triangle = graph.node("Gather", &[&input, &indexes], Vec::new());
+        let output = graph.node("Mul", &[&triangle, &scales], Vec::new());
+        graph.set_tape(self.output_offset, self.num_outputs, &output);
+        Ok(())
+    }
+
     #[inline(always)]
     fn forward_backward(
         &mut self,
@@ -883,6 +1029,191 @@ impl BlockTriangle {
     }
 }
 
+// Pools a variable length region of vectors into a single vector of dim values.
+// The region is the number of vectors n, followed by room for max_len vectors of dim values,
+// of which only the first n are used. With no vectors the output is zeros.
+// Max pooling passes the gradient only to the vector that the max came from.
+pub struct BlockPooling {
+    pub pooling: EmbeddingPooling,
+    pub dim: usize,
+    pub max_len: usize,
+    pub num_inputs: usize,
+    pub input_offset: usize,
+    pub output_offset: usize,
+}
+
+pub fn new_pooling_block(
+    bg: &mut graph::BlockGraph,
+    input: graph::BlockPtrOutput,
+    pooling: EmbeddingPooling,
+    dim: usize,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input]);
+    assert_ne!(dim, 0);
+    if num_inputs == 0 || (num_inputs - 1) % dim != 0 {
+        return Err(format!(
+            "Pooling of vectors of {} values needs an input of 1 + n * {} values, got {}",
+            dim, dim, num_inputs
+        ))?;
+    }
+    let block = Box::new(BlockPooling {
+        pooling,
+        dim,
+        max_len: (num_inputs - 1) / dim,
+        num_inputs,
+        output_offset: usize::MAX,
+        input_offset: usize::MAX,
+    });
+    let mut block_outputs = bg.add_node(block, vec![input])?;
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+impl BlockTrait for BlockPooling {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert_eq!(output.get_output_index(), 0);
+        self.dim
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert_eq!(input.get_input_index(), 0);
+        assert_eq!(self.input_offset, usize::MAX); // We only allow a single call
+        self.input_offset = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert_eq!(output.get_output_index(), 0);
+        assert_eq!(self.output_offset, usize::MAX); // We only allow a single call
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+
+        self.internal_forward(pb);
+
+        block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+        if update {
+            let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+                &mut pb.tape,
+                self.input_offset,
+                self.num_inputs,
+                self.output_offset,
+                self.dim,
+            );
+            let n = self.region_len(input_tape);
+            let scale = match self.pooling {
+                EmbeddingPooling::Mean if n > 0 => 1.0 / n as f32,
+                _ => 1.0,
+            };
+            input_tape[0] = 0.0;
+            for (i, general_gradient) in output_tape.iter().enumerate() {
+                // Overwriting value i of the vectors doesn't change the argmax of the others
+                let best = match self.pooling {
+                    EmbeddingPooling::Max => Some(self.argmax(input_tape, n, i)),
+                    _ => None,
+                };
+                for j in 0..n {
+                    input_tape[1 + j * self.dim + i] = match best {
+                        None => general_gradient * scale,
+                        Some(best) if best == j => *general_gradient,
+                        Some(_) => 0.0,
+                    };
+                }
+            }
+        }
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        self.internal_forward(pb);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn forward_with_cache(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+        caches: &[BlockCache],
+    ) {
+        self.internal_forward(pb);
+        block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
+    }
+}
+
+impl BlockPooling {
+    #[inline(always)]
+    fn region_len(&self, input_tape: &[f32]) -> usize {
+        (input_tape[0] as usize).min(self.max_len)
+    }
+
+    // Vector of the region that has the largest value i
+    #[inline(always)]
+    fn argmax(&self, input_tape: &[f32], n: usize, i: usize) -> usize {
+        let mut best = 0;
+        for j in 1..n {
+            if input_tape[1 + j * self.dim + i] > input_tape[1 + best * self.dim + i] {
+                best = j;
+            }
+        }
+        best
+    }
+
+    #[inline(always)]
+    fn internal_forward(&self, pb: &mut port_buffer::PortBuffer) {
+        let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+            &mut pb.tape,
+            self.input_offset,
+            self.num_inputs,
+            self.output_offset,
+            self.dim,
+        );
+        output_tape.fill(0.0);
+        let n = self.region_len(input_tape);
+        if n == 0 {
+            return;
+        }
+        match self.pooling {
+            EmbeddingPooling::Sum | EmbeddingPooling::Mean => {
+                for j in 0..n {
+                    let vector = &input_tape[1 + j * self.dim..1 + (j + 1) * self.dim];
+                    for (o, v) in output_tape.iter_mut().zip(vector.iter()) {
+                        *o += v;
+                    }
+                }
+                if self.pooling == EmbeddingPooling::Mean {
+                    for o in output_tape.iter_mut() {
+                        *o /= n as f32;
+                    }
+                }
+            }
+            EmbeddingPooling::Max => {
+                for (i, o) in output_tape.iter_mut().enumerate() {
+                    *o = input_tape[1 + self.argmax(input_tape, n, i) * self.dim + i];
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -901,6 +1232,9 @@ mod tests {
             example_number: 0,
             lr_buffer: Vec::new(),
             ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
         }
     }
 
@@ -938,6 +1272,85 @@ mod tests {
         ); // backward part -- nothing gets updated
     }
 
+    #[test]
+    fn test_pooling_block() {
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        for (pooling, forward, backward) in [
+            (
+                EmbeddingPooling::Mean,
+                vec![2.0, 1.0],
+                vec![0.0, 1.0, 0.5, 1.0, 0.5, 9.0, 9.0],
+            ),
+            (
+                EmbeddingPooling::Max,
+                vec![3.0, 4.0],
+                vec![0.0, 0.0, 0.0, 3.0, 4.0, 9.0, 9.0],
+            ),
+        ] {
+            let mut bg = BlockGraph::new();
+            // Two vectors of 2 values, room for a third one that is not used
+            let input_block =
+                block_misc::new_const_block(&mut bg, vec![2.0, 1.0, -2.0, 3.0, 4.0, 9.0, 9.0])
+                    .unwrap();
+            let observe_block_backward =
+                block_misc::new_observe_block(&mut bg, input_block, Observe::Backward, None)
+                    .unwrap();
+            let pooling_block =
+                new_pooling_block(&mut bg, observe_block_backward, pooling, 2).unwrap();
+            let observe_block_forward =
+                block_misc::new_observe_block(&mut bg, pooling_block, Observe::Forward, None)
+                    .unwrap();
+            block_misc::new_sink_block(
+                &mut bg,
+                observe_block_forward,
+                block_misc::SinkType::Untouched,
+            )
+            .unwrap();
+            bg.finalize();
+            bg.allocate_and_init_weights(&mi);
+
+            let mut pb = bg.new_port_buffer();
+            let fb = fb_vec();
+            slearn2(&mut bg, &fb, &mut pb, true);
+            // Gradient is the output
+            assert_eq!(pb.observations, [forward, backward].concat());
+        }
+
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![0.0, 1.0, 2.0, 3.0]).unwrap();
+        assert!(new_pooling_block(&mut bg, input_block, EmbeddingPooling::Max, 2).is_err());
+    }
+
+    #[test]
+    fn test_dense_input_block() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        let vw = crate::vwmap::VwNamespaceMap::new("A,featureA,f32vec:3\n").unwrap();
+        mi.dense_inputs.push(model_instance::DenseInputDesc {
+            namespace_descriptor: vw.map_verbose_to_namespace_descriptor["featureA"],
+            len: 3,
+        });
+        let mut bg = BlockGraph::new();
+        let dense_block = new_dense_input_block(&mut bg, &mi).unwrap();
+        let observe_block_forward =
+            block_misc::new_observe_block(&mut bg, dense_block, Observe::Forward, None).unwrap();
+        block_misc::new_sink_block(
+            &mut bg,
+            observe_block_forward,
+            block_misc::SinkType::Untouched,
+        )
+        .unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+        let mut fb = fb_vec();
+        fb.dense_buffer = vec![0.5, -1.0, 2.0];
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.observations, vec![0.5, -1.0, 2.0]);
+        spredict2(&mut bg, &fb, &mut pb);
+        assert_eq!(pb.observations, vec![0.5, -1.0, 2.0]);
+    }
+
     #[test]
     fn test_triangle_block() {
         let mi = model_instance::ModelInstance::new_empty().unwrap();
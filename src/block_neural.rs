@@ -1,5 +1,5 @@
 use rand_distr::{Distribution, Normal, Uniform};
-use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
 use rand_xoshiro::Xoshiro256PlusPlus;
 use std::any::Any;
 use std::error::Error;
@@ -7,21 +7,25 @@ use std::io;
 use std::io::Error as IOError;
 use std::io::ErrorKind;
 
+use crate::autotune::NeuronKernel;
 use crate::block_helpers;
 use crate::block_misc;
 use crate::feature_buffer;
 use crate::graph;
 use crate::model_instance;
+use crate::onnx;
 use crate::optimizer;
 use crate::port_buffer;
 use crate::regressor;
+use crate::rng;
 use block_helpers::OptimizerData;
 use optimizer::OptimizerTrait;
 use regressor::BlockTrait;
 
 use crate::feature_buffer::FeatureBuffer;
-use crate::port_buffer::PortBuffer;
+use crate::port_buffer::{MinibatchGradients, PortBuffer};
 use crate::regressor::BlockCache;
+use crate::weight_storage::WeightStorage;
 use blas::*;
 
 const MAX_NUM_INPUTS: usize = 16000;
@@ -49,7 +53,7 @@ pub struct BlockNeuronLayer<L: OptimizerTrait> {
     pub weights_len: u32,
     // While FFM part keeps weight and accumulation together (since memory locality is the issue)
     // for NN part it is actually preferrable to have it separately
-    pub weights: Vec<f32>,
+    pub weights: WeightStorage,
     pub weights_optimizer: Vec<OptimizerData<L>>,
     pub optimizer: L,
     pub neuron_type: NeuronType,
@@ -57,12 +61,18 @@ pub struct BlockNeuronLayer<L: OptimizerTrait> {
     pub init_type: InitType,
     pub dropout: f32,
     pub dropout_inv: f32,
+    pub dropout_schedule: Option<model_instance::DropoutSchedule>,
     pub max_norm: f32,
     pub layer_norm: bool,
     rng: Xoshiro256PlusPlus,
     rng_scratchpad: Vec<u32>,
     dropout_threshold: u32,
     bias_offset: usize,
+    neuron_kernel: NeuronKernel,
+    lr_schedule: Option<model_instance::LRSchedule>,
+    grad_clip: f32,
+    weight_decay: f32,
+    minibatch: u32,
 }
 
 fn new_neuronlayer_without_weights<L: OptimizerTrait + 'static>(
@@ -72,6 +82,7 @@ fn new_neuronlayer_without_weights<L: OptimizerTrait + 'static>(
     num_neurons: usize,
     init_type: InitType,
     dropout: f32,
+    dropout_schedule: Option<model_instance::DropoutSchedule>,
     max_norm: f32,
     layer_norm: bool,
 ) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
@@ -79,16 +90,12 @@ fn new_neuronlayer_without_weights<L: OptimizerTrait + 'static>(
     assert!(num_inputs < MAX_NUM_INPUTS);
     assert_ne!(num_inputs, 0);
 
-    if dropout != 0.0 {
-        panic!("Dropout in this binary is not supported due to bizzare side effects on inner loop unrolling");
-    }
-
     let weights_len = ((num_inputs + 1) * num_neurons) as u32; // +1 is for bias term
 
     let bias_offset = num_inputs * num_neurons;
 
     let mut rg = BlockNeuronLayer::<L> {
-        weights: Vec::new(),
+        weights: WeightStorage::new(),
         weights_optimizer: Vec::new(),
         output_offset: usize::MAX,
         input_offset: usize::MAX,
@@ -100,16 +107,24 @@ fn new_neuronlayer_without_weights<L: OptimizerTrait + 'static>(
         init_type,
         dropout,
         dropout_inv: 1.0 / (1.0 - dropout),
+        dropout_schedule,
         max_norm,
         layer_norm,
         rng: Xoshiro256PlusPlus::seed_from_u64(0_u64),
         rng_scratchpad: Vec::new(),
         dropout_threshold: ((u32::MAX as f64) * (dropout as f64)) as u32,
         bias_offset,
+        neuron_kernel: mi.kernels.neuron,
+        lr_schedule: mi.lr_schedule.clone(),
+        grad_clip: mi.grad_clip,
+        weight_decay: mi.nn_weight_decay,
+        minibatch: mi.minibatch,
     };
 
+    rg.set_dropout(dropout);
     rg.optimizer
         .init(mi.nn_learning_rate, mi.nn_power_t, mi.nn_init_acc_gradient);
+    rg.optimizer.init_momentum(mi.momentum);
     Ok(Box::new(rg))
 }
 
@@ -121,6 +136,7 @@ pub fn new_neuronlayer_block(
     num_neurons: usize,
     init_type: InitType,
     dropout: f32,
+    dropout_schedule: Option<model_instance::DropoutSchedule>,
     max_norm: f32,
     layer_norm: bool,
 ) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
@@ -128,7 +144,7 @@ pub fn new_neuronlayer_block(
     if ntype == NeuronType::Sum {
         return Err(Box::new(IOError::new(ErrorKind::Other, "You should not use new_neuronlayer_block with the type NeuronType::Sum, it makes no sense - use block_misc::new_sum_block()")));
     }
-    let block = match mi.optimizer {
+    let block = match mi.nn_optimizer.unwrap_or(mi.optimizer) {
         model_instance::Optimizer::AdagradLUT => {
             new_neuronlayer_without_weights::<optimizer::OptimizerAdagradLUT>(
                 mi,
@@ -137,6 +153,7 @@ pub fn new_neuronlayer_block(
                 num_neurons,
                 init_type,
                 dropout,
+                dropout_schedule,
                 max_norm,
                 layer_norm,
             )
@@ -149,6 +166,7 @@ pub fn new_neuronlayer_block(
                 num_neurons,
                 init_type,
                 dropout,
+                dropout_schedule,
                 max_norm,
                 layer_norm,
             )
@@ -161,10 +179,56 @@ pub fn new_neuronlayer_block(
                 num_neurons,
                 init_type,
                 dropout,
+                dropout_schedule,
+                max_norm,
+                layer_norm,
+            )
+        }
+        model_instance::Optimizer::Adam => {
+            new_neuronlayer_without_weights::<optimizer::OptimizerAdam>(
+                mi,
+                num_inputs,
+                ntype,
+                num_neurons,
+                init_type,
+                dropout,
+                dropout_schedule,
+                max_norm,
+                layer_norm,
+            )
+        }
+        model_instance::Optimizer::SGDMomentum => {
+            new_neuronlayer_without_weights::<optimizer::OptimizerSGDMomentum<false>>(
+                mi,
+                num_inputs,
+                ntype,
+                num_neurons,
+                init_type,
+                dropout,
+                dropout_schedule,
                 max_norm,
                 layer_norm,
             )
         }
+        model_instance::Optimizer::SGDNesterov => {
+            new_neuronlayer_without_weights::<optimizer::OptimizerSGDMomentum<true>>(
+                mi,
+                num_inputs,
+                ntype,
+                num_neurons,
+                init_type,
+                dropout,
+                dropout_schedule,
+                max_norm,
+                layer_norm,
+            )
+        }
+        model_instance::Optimizer::COCOB | model_instance::Optimizer::FTRL => {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                "COCOB and FTRL are only supported for the LR block, see --lr_optimizer",
+            )))
+        }
     }
     .unwrap();
 
@@ -185,6 +249,7 @@ pub fn new_neuron_block(
         _ => new_neuronlayer_block(
             bg, mi, input, ntype, 1, // a single neuron
             init_type, 0.0,   // dropout
+            None,  // dropout schedule
             0.0,   // maxnorm
             false, // layer norm
         ),
@@ -192,6 +257,36 @@ pub fn new_neuron_block(
 }
 
 impl<L: OptimizerTrait + 'static> BlockNeuronLayer<L> {
+    fn set_dropout(&mut self, dropout: f32) {
+        self.dropout = dropout;
+        self.dropout_inv = 1.0 / (1.0 - dropout);
+        self.dropout_threshold = ((u32::MAX as f64) * (dropout as f64)) as u32;
+    }
+
+    // One optimizer step per weight with the gradients summed over the mini-batch
+    fn apply_minibatch_gradients(&mut self, gradients: &mut MinibatchGradients) {
+        unsafe {
+            for (k, gradient) in gradients.dense.iter_mut().enumerate() {
+                // Weights of dropped neurons and of zero inputs
+                if *gradient == 0.0 {
+                    continue;
+                }
+                let update = self.optimizer.calculate_update(
+                    *gradient,
+                    &mut self.weights_optimizer.get_unchecked_mut(k).optimizer_data,
+                );
+                let weight = self.weights.get_unchecked_mut(k);
+                if k < self.bias_offset {
+                    *weight -= update + self.weight_decay * *weight;
+                } else {
+                    *weight -= update;
+                }
+                *gradient = 0.0;
+            }
+        }
+        gradients.examples = 0;
+    }
+
     #[inline(always)]
     fn internal_forward(&self, pb: &mut port_buffer::PortBuffer, alpha: f32) {
         unsafe {
@@ -205,23 +300,79 @@ impl<L: OptimizerTrait + 'static> BlockNeuronLayer<L> {
 
             // This is actually speed things up considerably.
             output_tape.copy_from_slice(self.weights.get_unchecked(self.bias_offset..));
-            sgemv(
-                b'T',                               //   trans: u8,
-                self.num_inputs as i32,             //   m: i32,
-                self.num_neurons as i32,            //   n: i32,
-                alpha,                              //   alpha: f32,
-                self.weights.get_unchecked(0..),    //  a: &[f32],
-                self.num_inputs as i32,             //lda: i32,
-                input_tape.get_unchecked(0..),      //   x: &[f32],
-                1,                                  //incx: i32,
-                1.0,                                // beta: f32,
-                output_tape.get_unchecked_mut(0..), //y: &mut [f32],
-                1,                                  //incy: i32
-            );
+            match self.neuron_kernel {
+                NeuronKernel::Blas => forward_blas(
+                    &self.weights,
+                    input_tape,
+                    output_tape,
+                    alpha,
+                    self.num_inputs,
+                    self.num_neurons,
+                ),
+                NeuronKernel::Scalar => forward_scalar(
+                    &self.weights,
+                    input_tape,
+                    output_tape,
+                    alpha,
+                    self.num_inputs,
+                    self.num_neurons,
+                ),
+            }
         }
     }
 }
 
+// Adds alpha * (weights of the neuron . input) to the output of each neuron.
+// Weights of neuron j are weights[j * num_inputs..(j + 1) * num_inputs]
+pub fn forward_blas(
+    weights: &[f32],
+    input: &[f32],
+    output: &mut [f32],
+    alpha: f32,
+    num_inputs: usize,
+    num_neurons: usize,
+) {
+    unsafe {
+        sgemv(
+            b'T',               //   trans: u8,
+            num_inputs as i32,  //   m: i32,
+            num_neurons as i32, //   n: i32,
+            alpha,              //   alpha: f32,
+            weights,            //  a: &[f32],
+            num_inputs as i32,  //lda: i32,
+            input,              //   x: &[f32],
+            1,                  //incx: i32,
+            1.0,                // beta: f32,
+            output,             //y: &mut [f32],
+            1,                  //incy: i32
+        );
+    }
+}
+
+// Same as forward_blas, a loop the compiler vectorizes, which can beat BLAS on narrow layers
+pub fn forward_scalar(
+    weights: &[f32],
+    input: &[f32],
+    output: &mut [f32],
+    alpha: f32,
+    num_inputs: usize,
+    num_neurons: usize,
+) {
+    let input = &input[..num_inputs];
+    for (neuron_weights, out) in weights
+        .chunks_exact(num_inputs)
+        .take(num_neurons)
+        .zip(output.iter_mut())
+    {
+        let wsum: f32 = neuron_weights
+            .iter()
+            .zip(input.iter())
+            .map(|(w, x)| w * x)
+            .sum();
+        *out += alpha * wsum;
+    }
+}
+
 impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
     fn as_any(&mut self) -> &mut dyn Any {
         self
@@ -239,6 +390,16 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
         debug_assert!(self.output_offset != usize::MAX);
         debug_assert!(self.input_offset != usize::MAX);
 
+        if update {
+            if let Some(schedule) = self.dropout_schedule {
+                self.set_dropout(schedule.rate_at(fb.example_number));
+            }
+            if let Some(schedule) = self.lr_schedule.as_ref() {
+                self.optimizer
+                    .set_learning_rate_multiplier(schedule.multiplier_at(fb.example_number));
+            }
+        }
+
         // If we are in pure prediction mode (
         let dropout_inv = match update {
             true => self.dropout_inv,
@@ -247,6 +408,22 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
 
         self.internal_forward(pb, dropout_inv);
 
+        if update && self.dropout != 0.0 {
+            // Draw which neurons are dropped for this example and silence their outputs
+            unsafe {
+                let output_tape = pb
+                    .tape
+                    .get_unchecked_mut(self.output_offset..self.output_offset + self.num_neurons);
+                for j in 0..self.num_neurons {
+                    let r = self.rng.next_u32();
+                    *self.rng_scratchpad.get_unchecked_mut(j) = r;
+                    if r < self.dropout_threshold {
+                        *output_tape.get_unchecked_mut(j) = 0.0;
+                    }
+                }
+            }
+        }
+
         block_helpers::forward_backward(further_blocks, fb, pb, update);
 
         unsafe {
@@ -263,6 +440,40 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
                     self.num_neurons,
                 );
 
+                // Errors passed to the inputs are not clipped, only the updates of this layer
+                let clip_factor = if self.grad_clip > 0.0 {
+                    // Gradient of weight (i, j) is the error of neuron j times input i, bias input is 1.0
+                    let mut inputs_squared: f32 = 1.0;
+                    for i in 0..self.num_inputs {
+                        inputs_squared += input_tape.get_unchecked(i) * input_tape.get_unchecked(i);
+                    }
+                    let mut gradient_norm_squared = 0.0;
+                    for j in 0..self.num_neurons {
+                        if self.dropout != 0.0
+                            && *self.rng_scratchpad.get_unchecked(j) < self.dropout_threshold
+                        {
+                            continue;
+                        }
+                        let general_gradient = output_tape.get_unchecked(j) * self.dropout_inv;
+                        gradient_norm_squared +=
+                            general_gradient * general_gradient * inputs_squared;
+                    }
+                    optimizer::clip_factor(self.grad_clip, gradient_norm_squared)
+                } else {
+                    1.0
+                };
+
+                let mut minibatch_gradients = if self.minibatch > 1 {
+                    let gradients = pb
+                        .minibatch_gradients
+                        .entry(self.output_offset)
+                        .or_default();
+                    gradients.dense.resize(self.weights_len as usize, 0.0);
+                    Some(gradients)
+                } else {
+                    None
+                };
+
                 for j in 0..self.num_neurons {
                     if self.dropout != 0.0
                         && *self.rng_scratchpad.get_unchecked(j) < self.dropout_threshold
@@ -276,10 +487,17 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
                         continue;
                     }
 
+                    let clipped_gradient = general_gradient * clip_factor;
                     let j_offset = j * self.num_inputs;
                     for i in 0..self.num_inputs {
                         let feature_value = input_tape.get_unchecked(i);
-                        let gradient = general_gradient * feature_value;
+                        let gradient = clipped_gradient * feature_value;
+                        *output_errors.get_unchecked_mut(i) +=
+                            self.weights.get_unchecked(i + j_offset) * general_gradient;
+                        if let Some(gradients) = minibatch_gradients.as_mut() {
+                            *gradients.dense.get_unchecked_mut(i + j_offset) += gradient;
+                            continue;
+                        }
                         let update = self.optimizer.calculate_update(
                             gradient,
                             &mut self
@@ -287,21 +505,24 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
                                 .get_unchecked_mut(i + j_offset)
                                 .optimizer_data,
                         );
-                        *output_errors.get_unchecked_mut(i) +=
-                            self.weights.get_unchecked(i + j_offset) * general_gradient;
-                        *self.weights.get_unchecked_mut(i + j_offset) -= update;
+                        let weight = self.weights.get_unchecked_mut(i + j_offset);
+                        *weight -= update + self.weight_decay * *weight;
                     }
                     {
                         // Updating bias term:
-                        let gradient = general_gradient * 1.0;
-                        let update = self.optimizer.calculate_update(
-                            gradient,
-                            &mut self
-                                .weights_optimizer
-                                .get_unchecked_mut(self.bias_offset + j)
-                                .optimizer_data,
-                        );
-                        *self.weights.get_unchecked_mut(self.bias_offset + j) -= update;
+                        let gradient = clipped_gradient * 1.0;
+                        if let Some(gradients) = minibatch_gradients.as_mut() {
+                            *gradients.dense.get_unchecked_mut(self.bias_offset + j) += gradient;
+                        } else {
+                            let update = self.optimizer.calculate_update(
+                                gradient,
+                                &mut self
+                                    .weights_optimizer
+                                    .get_unchecked_mut(self.bias_offset + j)
+                                    .optimizer_data,
+                            );
+                            *self.weights.get_unchecked_mut(self.bias_offset + j) -= update;
+                        }
                     }
 
                     if self.max_norm != 0.0 && fb.example_number % 10 == 0 {
@@ -336,11 +557,30 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
                     }
                 }
 
+                if let Some(gradients) = minibatch_gradients {
+                    gradients.examples += 1;
+                    if gradients.examples >= self.minibatch {
+                        self.apply_minibatch_gradients(gradients);
+                    }
+                }
+
                 input_tape.copy_from_slice(output_errors.get_unchecked(0..self.num_inputs));
             }
         }
     }
 
+    fn apply_minibatch(&mut self, pb: &mut port_buffer::PortBuffer) {
+        if let Some(gradients) = pb.minibatch_gradients.get_mut(&self.output_offset) {
+            if gradients.examples > 0 {
+                self.apply_minibatch_gradients(gradients);
+            }
+        }
+    }
+
+    fn set_lr_schedule(&mut self, lr_schedule: &Option<model_instance::LRSchedule>) {
+        self.lr_schedule = lr_schedule.clone();
+    }
+
     fn forward(
         &self,
         further_blocks: &[Box<dyn BlockTrait>],
@@ -364,7 +604,25 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
         block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
     }
 
-    fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {
+    fn to_onnx(&self, graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        let input = graph.get_tape(self.input_offset, self.num_inputs)?;
+        let weights = graph.initializer_f32(
+            "nn_weights",
+            vec![self.num_neurons as i64, self.num_inputs as i64],
+            &self.weights[..self.bias_offset],
+        );
+        let bias = graph.initializer_f32(
+            "nn_bias",
+            vec![self.num_neurons as i64],
+            &self.weights[self.bias_offset..],
+        );
+        let products = graph.node("MatMul", &[&weights, &input], Vec::new());
+        let output = graph.node("Add", &[&products, &bias], Vec::new());
+        graph.set_tape(self.output_offset, self.num_neurons, &output);
+        Ok(())
+    }
+
+    fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
         debug_assert!(self.output_offset != usize::MAX);
         debug_assert!(self.input_offset != usize::MAX);
 
@@ -372,7 +630,7 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
             self.weights_len, 0,
             "allocate_and_init_weights(): Have you forgotten to call set_num_inputs()?"
         );
-        self.weights = vec![1.0; self.weights_len as usize];
+        self.weights = vec![1.0; self.weights_len as usize].into();
         self.weights_optimizer = vec![
             OptimizerData::<L> {
                 optimizer_data: self.optimizer.initial_data()
@@ -380,12 +638,11 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
             self.weights_len as usize
         ];
         self.rng_scratchpad = vec![0; self.num_neurons];
-        // We need to seed each layer with a separate seed... how?
-        // by the time we call this function input_offset and output_offset are set and are unique. L
-        self.rng = Xoshiro256PlusPlus::seed_from_u64(
-            (self.input_offset * self.output_offset + self.num_inputs + self.weights_len as usize)
-                as u64,
-        );
+        // Each layer needs its own streams, by the time we call this function output_offset is set
+        // and unique
+        let stream_index = self.output_offset as u64;
+        self.rng = rng::new_stream(mi.seed, rng::RngStream::Dropout, stream_index);
+        let mut init_rng = rng::new_stream(mi.seed, rng::RngStream::WeightsInit, stream_index);
 
         self.bias_offset = self.num_inputs * self.num_neurons;
 
@@ -395,14 +652,14 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
                 let normal = Uniform::new(-bound, bound);
 
                 for i in 0..self.bias_offset {
-                    self.weights[i] = normal.sample(&mut self.rng) as f32;
+                    self.weights[i] = normal.sample(&mut init_rng) as f32;
                 }
             }
             InitType::Hu => {
                 let normal = Normal::new(0.0, (2.0 / self.num_inputs as f64).sqrt()).unwrap();
 
                 for i in 0..self.bias_offset {
-                    self.weights[i] = normal.sample(&mut self.rng) as f32;
+                    self.weights[i] = normal.sample(&mut init_rng) as f32;
                 }
             }
             InitType::One => {
@@ -427,6 +684,14 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
         return self.weights_len as usize;
     }
 
+    fn get_serialized_block_id(&self) -> u32 {
+        regressor::SERIALIZED_BLOCK_ID_NEURAL
+    }
+
+    fn map_weights(&mut self, dir: &str) -> Result<(), Box<dyn Error>> {
+        self.weights.map_through_file(dir)
+    }
+
     fn write_weights_to_buf(
         &self,
         output_bufwriter: &mut dyn io::Write,
@@ -472,7 +737,9 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
             .as_any()
             .downcast_mut::<BlockNeuronLayer<optimizer::OptimizerSGD>>()
             .unwrap();
-        block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader, false)?;
+        forward.weights.overwrite(|weights| {
+            block_helpers::read_weights_from_buf(weights, input_bufreader, false)
+        })?;
         block_helpers::skip_weights_from_buf::<OptimizerData<L>>(
             self.weights_len as usize,
             input_bufreader,
@@ -500,6 +767,9 @@ mod tests {
             example_number: 0,
             lr_buffer: Vec::new(),
             ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
         }
     }
 
@@ -519,8 +789,9 @@ mod tests {
             NeuronType::WeightedSum,
             1,
             InitType::One,
-            0.0, // dropout
-            0.0, // max norm
+            0.0,  // dropout
+            None, // dropout schedule
+            0.0,  // max norm
             false,
         )
         .unwrap();
@@ -537,6 +808,60 @@ mod tests {
         assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 1.5);
     }
 
+    #[test]
+    fn test_minibatch() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.nn_learning_rate = 0.1;
+        mi.nn_power_t = 0.0;
+        mi.optimizer = Optimizer::SGD;
+        mi.minibatch = 2;
+
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![2.0]).unwrap();
+        let neuron_block = new_neuronlayer_block(
+            &mut bg,
+            &mi,
+            input_block,
+            NeuronType::WeightedSum,
+            1,
+            InitType::One,
+            0.0,  // dropout
+            None, // dropout schedule
+            0.0,  // max norm
+            false,
+        )
+        .unwrap();
+        let _observe_block =
+            block_misc::new_observe_block(&mut bg, neuron_block, Observe::Forward, Some(1.0))
+                .unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+
+        let fb = fb_vec();
+        // Weights only move once the second example has been seen, by the sum of both gradients
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 2.0);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 2.0);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 1.0);
+
+        // A partial mini-batch is applied on request
+        let mut blocks = bg.take_blocks();
+        for block in blocks.iter_mut() {
+            block.apply_minibatch(&mut pb);
+        }
+        let layer = blocks
+            .iter_mut()
+            .find_map(|block| {
+                block
+                    .as_any()
+                    .downcast_mut::<BlockNeuronLayer<optimizer::OptimizerSGD>>()
+            })
+            .unwrap();
+        assert_epsilon!(layer.weights[0], 0.4);
+        assert_epsilon!(layer.weights[1], -0.3);
+    }
+
     #[test]
     fn test_two_neurons() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
@@ -555,6 +880,7 @@ mod tests {
             num_neurons,
             InitType::One,
             0.0,   // dropout
+            None,  // dropout schedule
             0.0,   // max norm
             false, // layer norm
         )
@@ -579,4 +905,59 @@ mod tests {
 
         assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, false), 1.5);
     }
+
+    #[test]
+    fn test_dropout_schedule() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.nn_learning_rate = 0.1;
+        mi.nn_power_t = 0.0;
+        mi.optimizer = Optimizer::SGD;
+
+        let num_neurons = 100;
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![2.0]).unwrap();
+        let neuron_block = new_neuronlayer_block(
+            &mut bg,
+            &mi,
+            input_block,
+            NeuronType::WeightedSum,
+            num_neurons,
+            InitType::One,
+            0.0, // dropout
+            Some(model_instance::DropoutSchedule::parse("linear:0.5:0.0:1K").unwrap()),
+            0.0,   // max norm
+            false, // layer norm
+        )
+        .unwrap();
+        let _observe_block =
+            block_misc::new_observe_block(&mut bg, neuron_block, Observe::Forward, Some(1.0))
+                .unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+        let mut fb = fb_vec();
+
+        // Prediction never drops anything
+        slearn2(&mut bg, &fb, &mut pb, false);
+        assert!(pb.observations.iter().all(|x| *x == 2.0));
+
+        // At the start of the schedule roughly half of the neurons are dropped, the rest is scaled up
+        slearn2(&mut bg, &fb, &mut pb, true);
+        let dropped = pb.observations.iter().filter(|x| **x == 0.0).count();
+        assert!(dropped > 30 && dropped < 70, "dropped: {}", dropped);
+        assert!(pb.observations.iter().all(|x| *x == 0.0 || *x == 4.0));
+
+        // Once the schedule has run out, nothing is dropped anymore.
+        // Dropped neurons were not updated, the rest moved their weight by 0.1 * (1.0 * 2.0) * 2.0
+        // and their bias by 0.1 * (1.0 * 2.0)
+        fb.example_number = 1000;
+        slearn2(&mut bg, &fb, &mut pb, true);
+        let unchanged = pb.observations.iter().filter(|x| **x == 2.0).count();
+        assert_eq!(unchanged, dropped);
+        assert!(pb
+            .observations
+            .iter()
+            .all(|x| *x == 2.0 || (*x - 1.0).abs() < 0.00001));
+    }
 }
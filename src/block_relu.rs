@@ -6,6 +6,7 @@ use crate::feature_buffer;
 use crate::feature_buffer::FeatureBuffer;
 use crate::graph::{BlockGraph, BlockPtrOutput, InputSlot, OutputSlot};
 use crate::model_instance;
+use crate::onnx;
 use crate::port_buffer;
 use crate::port_buffer::PortBuffer;
 use crate::regressor;
@@ -75,6 +76,13 @@ impl BlockTrait for BlockRELU {
         self.output_offset = offset;
     }
 
+    fn to_onnx(&self, graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        let input = graph.get_tape(self.input_offset, self.num_inputs)?;
+        let output = graph.node("Relu", &[&input], Vec::new());
+        graph.set_tape(self.output_offset, self.num_inputs, &output);
+        Ok(())
+    }
+
     #[inline(always)]
     fn forward_backward(
         &mut self,
@@ -149,6 +157,9 @@ mod tests {
             example_number: 0,
             lr_buffer: Vec::new(),
             ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
         }
     }
 
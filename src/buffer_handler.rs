@@ -1,8 +1,10 @@
 use flate2::read::MultiGzDecoder;
+use std::cell::Cell;
 use std::fs::File;
 use std::io;
-use std::io::BufRead;
+use std::io::{BufRead, Read};
 use std::path::Path;
+use std::rc::Rc;
 use zstd::stream::read::Decoder as ZstdDecoder;
 
 pub fn create_buffered_input(input_filename: &str) -> Box<dyn BufRead> {
@@ -36,6 +38,68 @@ pub fn create_buffered_input(input_filename: &str) -> Box<dyn BufRead> {
     }
 }
 
+// Number of bytes of the (decompressed) input consumed so far, shared with the reader
+#[derive(Clone, Default)]
+pub struct InputPosition(Rc<Cell<u64>>);
+
+impl InputPosition {
+    pub fn get(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+struct PositionTrackingReader {
+    inner: Box<dyn BufRead>,
+    position: InputPosition,
+}
+
+impl Read for PositionTrackingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position.0.set(self.position.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl BufRead for PositionTrackingReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position.0.set(self.position.get() + amt as u64);
+        self.inner.consume(amt)
+    }
+}
+
+// Same as create_buffered_input, but also tracks how far into the input we are, so that
+// interrupted training can be resumed from there (--resume)
+pub fn create_buffered_input_with_position(
+    input_filename: &str,
+) -> (Box<dyn BufRead>, InputPosition) {
+    let position = InputPosition::default();
+    let reader = PositionTrackingReader {
+        inner: create_buffered_input(input_filename),
+        position: position.clone(),
+    };
+    (Box::new(reader), position)
+}
+
+// Skips the first offset bytes of the input, the input has to be at least this long
+pub fn skip_input(input: &mut dyn BufRead, offset: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut input.take(offset), &mut io::sink())?;
+    if skipped < offset {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "Input ended after {} bytes, before the offset {} to skip to",
+                skipped, offset
+            ),
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -126,6 +190,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_input_position() {
+        let contents = b"line one\nline two\nline three\n";
+        let temp_file = create_gzipped_temp_file(contents).expect("Failed to create temp file");
+        let (mut reader, position) =
+            create_buffered_input_with_position(temp_file.path().to_str().unwrap());
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line).unwrap();
+        assert_eq!(position.get(), 9);
+
+        // A second reader picks up after the first line
+        let (mut reader2, position2) =
+            create_buffered_input_with_position(temp_file.path().to_str().unwrap());
+        skip_input(&mut reader2, position.get()).unwrap();
+        line.truncate(0);
+        reader2.read_until(b'\n', &mut line).unwrap();
+        assert_eq!(line, b"line two\n");
+        assert_eq!(position2.get(), 18);
+
+        assert!(skip_input(&mut reader2, 100).is_err());
+    }
+
     // Test for unsupported file format
     #[test]
     #[should_panic(expected = "Please specify a valid input format (.vw, .zst, .gz)")]
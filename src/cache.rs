@@ -2,17 +2,25 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::error::Error;
 use std::fs;
 use std::io;
+use std::io::BufRead;
+use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
 use std::path;
+use std::sync::mpsc;
+use std::thread;
 use std::{mem, slice};
 
+use crate::buffer_handler;
+use crate::parser;
 use crate::vwmap;
 
 const CACHE_HEADER_MAGIC_STRING: &[u8; 4] = b"FWCA"; // Fwumious Wabbit CAche
-const CACHE_HEADER_VERSION: u32 = 11;
+const CACHE_HEADER_VERSION: u32 = 13;
 /*
 Version incompatibilites:
+12->13: records can carry labels of --heads after their features
+11->12: records can carry a tag at their end
 10->11: float namespaces cannot have a weight attached
 9->10: enable binning
 8->9: enabled multi-byte feature names in vw files
@@ -27,6 +35,11 @@ Version incompatibilites:
 
 const READBUF_LEN: usize = 1024 * 100;
 
+// Sharded cache: input lines are split into chunks of SHARD_CHUNK_LINES, which are handed out to
+// shards round-robin. Since every line is exactly one record, reading a chunk from each shard in
+// turn gives back the original order of examples.
+const SHARD_CHUNK_LINES: usize = 1024;
+
 // This is super ugly hack around the fact that we need to call finish() before closing the lz4 stream
 // Effectively lz4 implementation we're using is kind of bad
 // More info (and where workaround comes from): https://github.com/bozaro/lz4-rs/issues/9
@@ -62,13 +75,30 @@ pub struct RecordCache {
     start_pointer: usize,
     end_pointer: usize,
     total_read: usize,
+    shards: Vec<RecordCache>, // when reading a sharded cache, records come from these
+    current_shard: usize,
+    records_left_in_chunk: usize,
+}
+
+pub fn shard_filename(input_filename: &str, shard: usize, num_shards: usize) -> String {
+    format!("{}.fwcache.shard{}of{}", input_filename, shard, num_shards)
 }
 
 impl RecordCache {
     pub fn new(input_filename: &str, enabled: bool, vw_map: &vwmap::VwNamespaceMap) -> RecordCache {
-        let temporary_filename: String = format!("{}.fwcache.writing", input_filename);
         let final_filename: String = format!("{}.fwcache", input_filename);
         let gz = input_filename.ends_with("gz");
+        RecordCache::new_with_filename(&final_filename, gz, enabled, vw_map)
+    }
+
+    fn new_with_filename(
+        final_filename: &str,
+        gz: bool,
+        enabled: bool,
+        vw_map: &vwmap::VwNamespaceMap,
+    ) -> RecordCache {
+        let temporary_filename: String = format!("{}.writing", final_filename);
+        let final_filename: String = final_filename.to_string();
 
         let mut rc = RecordCache {
             output_bufwriter: Box::new(io::BufWriter::new(io::sink())),
@@ -81,55 +111,121 @@ impl RecordCache {
             start_pointer: 0,
             end_pointer: 0,
             total_read: 0,
+            shards: Vec::new(),
+            current_shard: 0,
+            records_left_in_chunk: 0,
         };
 
         if enabled {
             if path::Path::new(&final_filename).exists() {
-                rc.reading = true;
-                if !gz {
-                    // we buffer ourselves, otherwise i would be wise to use bufreader
-                    rc.input_bufreader = Box::new(fs::File::open(&final_filename).unwrap());
-                } else {
-                    rc.input_bufreader = Box::new(
-                        lz4::Decoder::new(fs::File::open(&final_filename).unwrap()).unwrap(),
-                    );
-                }
                 log::info!("using cache_file = {}", final_filename);
                 log::info!("ignoring text input in favor of cache input");
-                match rc.verify_header(vw_map) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        log::error!("Couldn't use the existing cache file: {:?}", e);
-                        rc.reading = false;
-                    }
-                }
-                rc.byte_buffer.resize(READBUF_LEN, 0);
+                rc.open_for_reading(gz, vw_map);
             }
 
             if !rc.reading {
-                rc.writing = true;
                 log::info!("creating cache file = {}", final_filename);
-                if !gz {
-                    rc.output_bufwriter = Box::new(io::BufWriter::new(
-                        fs::File::create(temporary_filename).unwrap(),
-                    ));
-                } else {
-                    let w = Wrapper {
-                        s: Some(
-                            lz4::EncoderBuilder::new()
-                                .level(3)
-                                .build(fs::File::create(temporary_filename).unwrap())
-                                .unwrap(),
-                        ),
-                    };
-                    rc.output_bufwriter = Box::new(io::BufWriter::new(w));
-                }
-                rc.write_header(vw_map).unwrap();
+                rc.open_for_writing(gz, vw_map);
             }
         }
         rc
     }
 
+    // Opens a cache split into num_shards files for reading. If shards are missing or stale,
+    // they are first built from the input file by num_shards parser threads.
+    pub fn new_sharded(
+        input_filename: &str,
+        num_shards: usize,
+        vw_map: &vwmap::VwNamespaceMap,
+    ) -> Result<RecordCache, Box<dyn Error>> {
+        if num_shards == 0 {
+            return Err("--cache_shards has to be at least 1")?;
+        }
+        let gz = input_filename.ends_with("gz");
+        let mut shards = RecordCache::open_shards(input_filename, num_shards, gz, vw_map);
+        if shards.iter().any(|shard| !shard.reading) {
+            drop(shards);
+            log::info!(
+                "creating {} cache shards for {}",
+                num_shards,
+                input_filename
+            );
+            build_shards(input_filename, num_shards, gz, vw_map)?;
+            shards = RecordCache::open_shards(input_filename, num_shards, gz, vw_map);
+            if shards.iter().any(|shard| !shard.reading) {
+                return Err("Could not read cache shards that were just written")?;
+            }
+        }
+
+        let mut rc = RecordCache::new_with_filename("", gz, false, vw_map);
+        rc.reading = true;
+        rc.shards = shards;
+        rc.current_shard = num_shards - 1;
+        rc.records_left_in_chunk = 0;
+        Ok(rc)
+    }
+
+    fn open_shards(
+        input_filename: &str,
+        num_shards: usize,
+        gz: bool,
+        vw_map: &vwmap::VwNamespaceMap,
+    ) -> Vec<RecordCache> {
+        (0..num_shards)
+            .map(|shard| {
+                let filename = shard_filename(input_filename, shard, num_shards);
+                let mut rc = RecordCache::new_with_filename(&filename, gz, false, vw_map);
+                if path::Path::new(&filename).exists() {
+                    rc.open_for_reading(gz, vw_map);
+                }
+                rc
+            })
+            .collect()
+    }
+
+    fn open_for_reading(&mut self, gz: bool, vw_map: &vwmap::VwNamespaceMap) {
+        self.reading = true;
+        if !gz {
+            // we buffer ourselves, otherwise i would be wise to use bufreader
+            self.input_bufreader = Box::new(fs::File::open(&self.final_filename).unwrap());
+        } else {
+            self.input_bufreader =
+                Box::new(lz4::Decoder::new(fs::File::open(&self.final_filename).unwrap()).unwrap());
+        }
+        match self.verify_header(vw_map) {
+            Ok(()) => {}
+            Err(e) => {
+                log::error!(
+                    "Couldn't use the existing cache file {}: {:?}",
+                    self.final_filename,
+                    e
+                );
+                self.reading = false;
+            }
+        }
+        self.byte_buffer.resize(READBUF_LEN, 0);
+    }
+
+    fn open_for_writing(&mut self, gz: bool, vw_map: &vwmap::VwNamespaceMap) {
+        self.writing = true;
+        if !gz {
+            self.output_bufwriter = Box::new(io::BufWriter::new(
+                fs::File::create(&self.temporary_filename).unwrap(),
+            ));
+        } else {
+            let w = Wrapper {
+                s: Some(
+                    lz4::EncoderBuilder::new()
+                        .level(3)
+                        .build(fs::File::create(&self.temporary_filename).unwrap())
+                        .unwrap(),
+                ),
+            };
+            self.output_bufwriter = Box::new(io::BufWriter::new(w));
+        }
+        self.write_header(vw_map).unwrap();
+    }
+
     pub fn push_record(&mut self, record_buf: &[u32]) -> Result<(), Box<dyn Error>> {
         if self.writing {
             let element_size = mem::size_of::<u32>();
@@ -152,6 +248,17 @@ impl RecordCache {
         Ok(())
     }
 
+    // Stops writing the cache and removes the partial file, used when the input is not read from the start
+    pub fn abandon_writing(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.writing {
+            self.writing = false;
+            self.output_bufwriter = Box::new(io::sink());
+            fs::remove_file(&self.temporary_filename)?;
+            log::info!("not creating cache file = {}", self.final_filename);
+        }
+        Ok(())
+    }
+
     pub fn write_header(&mut self, vw_map: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
         self.output_bufwriter.write_all(CACHE_HEADER_MAGIC_STRING)?;
         self.output_bufwriter
@@ -188,6 +295,15 @@ impl RecordCache {
         if !self.reading {
             return Err("next_recrod() called on reading cache, when not opened in reading mode")?;
         }
+        if !self.shards.is_empty() {
+            if self.records_left_in_chunk == 0 {
+                self.current_shard = (self.current_shard + 1) % self.shards.len();
+                self.records_left_in_chunk = SHARD_CHUNK_LINES;
+            }
+            self.records_left_in_chunk -= 1;
+            // Only the last chunk can be short, so the first shard to run out ends the input
+            return self.shards[self.current_shard].get_next_record();
+        }
         unsafe {
             // We're going to cast another view over the data, so we can read it as u32
             // This requires that the allocator we're using gives us sufficiently-aligned bytes,
@@ -231,3 +347,146 @@ impl RecordCache {
         }
     }
 }
+
+fn build_shards(
+    input_filename: &str,
+    num_shards: usize,
+    gz: bool,
+    vw_map: &vwmap::VwNamespaceMap,
+) -> Result<(), Box<dyn Error>> {
+    let mut senders = Vec::with_capacity(num_shards);
+    let mut workers = Vec::with_capacity(num_shards);
+    for shard in 0..num_shards {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(2);
+        let filename = shard_filename(input_filename, shard, num_shards);
+        let vw_map = vw_map.clone();
+        senders.push(sender);
+        workers.push(thread::spawn(move || -> Result<(), String> {
+            // Stale shards get overwritten, so we don't let the constructor open them for reading
+            let mut rc = RecordCache::new_with_filename(&filename, gz, false, &vw_map);
+            rc.open_for_writing(gz, &vw_map);
+            let mut pa = parser::VowpalParser::new(&vw_map);
+            // Chunks are lines joined together, we parse until the chunk is exhausted
+            for chunk in receiver.iter() {
+                let mut chunk_reader = Cursor::new(chunk);
+                loop {
+                    match pa.next_vowpal(&mut chunk_reader) {
+                        Ok([]) => break,
+                        Ok(record) => rc.push_record(record).map_err(|e| e.to_string())?,
+                        Err(e) => return Err(e.to_string()),
+                    }
+                }
+            }
+            rc.write_finish().map_err(|e| e.to_string())
+        }));
+    }
+
+    let mut input = buffer_handler::create_buffered_input(input_filename);
+    let mut chunk: Vec<u8> = Vec::new();
+    let mut chunk_lines = 0;
+    let mut chunk_num = 0;
+    let mut send_failed = false;
+    loop {
+        let read_len = input.read_until(0x0a, &mut chunk)?;
+        if read_len > 0 {
+            chunk_lines += 1;
+        }
+        if (read_len == 0 && chunk_lines > 0) || chunk_lines == SHARD_CHUNK_LINES {
+            if senders[chunk_num % num_shards]
+                .send(mem::take(&mut chunk))
+                .is_err()
+            {
+                // Worker has stopped on an error, which we pick up when joining
+                send_failed = true;
+                break;
+            }
+            chunk_lines = 0;
+            chunk_num += 1;
+        }
+        if read_len == 0 {
+            break;
+        }
+    }
+    drop(senders);
+
+    let mut first_error: Option<String> = None;
+    for worker in workers {
+        let result = match worker.join() {
+            Ok(result) => result,
+            Err(_) => Err("cache shard worker panicked".to_string()),
+        };
+        if let Err(e) = result {
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(format!("Building cache shards failed: {}", e))?,
+        None if send_failed => Err("Building cache shards failed")?,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sharded_cache() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let dir = tempdir().unwrap();
+        let input_path = dir.path().join("input.vw");
+        let input_filename = input_path.to_str().unwrap();
+        // Enough lines for chunks to wrap around the shards, with a partial chunk at the end
+        let num_lines = SHARD_CHUNK_LINES * 5 + 17;
+        let mut input = String::new();
+        for i in 0..num_lines {
+            input.push_str(&format!(
+                "{} |A a{} |B b{}\n",
+                1 - 2 * (i % 2) as i32,
+                i,
+                i % 7
+            ));
+        }
+        fs::write(&input_path, &input).unwrap();
+
+        let mut expected: Vec<Vec<u32>> = Vec::new();
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut reader = Cursor::new(input.as_bytes());
+        loop {
+            match pa.next_vowpal(&mut reader).unwrap() {
+                [] => break,
+                record => expected.push(record.to_vec()),
+            }
+        }
+        assert_eq!(expected.len(), num_lines);
+
+        // First run builds the shards, second one reuses them, third one rebuilds a stale shard
+        for run in 0..3 {
+            if run == 2 {
+                fs::write(shard_filename(input_filename, 1, 3), "stale").unwrap();
+            }
+            let mut cache = RecordCache::new_sharded(input_filename, 3, &vw).unwrap();
+            assert!(cache.reading);
+            assert!(!cache.writing);
+            for expected_record in expected.iter() {
+                assert_eq!(cache.get_next_record().unwrap(), &expected_record[..]);
+            }
+            assert_eq!(cache.get_next_record().unwrap().len(), 0);
+            for shard in 0..3 {
+                assert!(path::Path::new(&shard_filename(input_filename, shard, 3)).exists());
+            }
+        }
+    }
+
+    #[test]
+    fn test_sharded_cache_parse_error() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let dir = tempdir().unwrap();
+        let input_path = dir.path().join("input.vw");
+        fs::write(&input_path, "1 |A a\n1 |Z b\n").unwrap();
+        let result = RecordCache::new_sharded(input_path.to_str().unwrap(), 2, &vw);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Fault injection for the daemon, built only with --features chaos, so that client side retries and
+// fallbacks can be rehearsed against a misbehaving fw. Rates are probabilities per request (per load
+// for model loads), read from the environment when a worker thread handles its first request:
+//
+// FW_CHAOS_SLOW_RATE, FW_CHAOS_SLOW_MS   - delay the response by FW_CHAOS_SLOW_MS (default 100)
+// FW_CHAOS_DROP_RATE                     - close the connection instead of responding
+// FW_CHAOS_LOAD_FAIL_RATE                - fail hogwild_load as if the model could not be loaded
+// FW_CHAOS_PARSE_ERROR_RATE              - respond with a parse error to a valid request
+// FW_CHAOS_SEED                          - seed for reproducible runs
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    SlowResponse,
+    DropConnection,
+    ModelLoadFailure,
+    ParseError,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChaosConfig {
+    pub slow_rate: f64,
+    pub slow_ms: u64,
+    pub drop_rate: f64,
+    pub load_fail_rate: f64,
+    pub parse_error_rate: f64,
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct InjectedFault {
+    pub fault: Fault,
+}
+
+impl Error for InjectedFault {}
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "chaos: injected {:?}", self.fault)
+    }
+}
+
+fn parse_var<T: std::str::FromStr>(
+    get: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<T>, Box<dyn Error>> {
+    match get(name) {
+        Some(value) => match value.parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Can't parse {}=\"{}\"", name, value),
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_rate(get: &impl Fn(&str) -> Option<String>, name: &str) -> Result<f64, Box<dyn Error>> {
+    let rate: f64 = parse_var(get, name)?.unwrap_or(0.0);
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!("{} has to be between 0.0 and 1.0, got: {}", name, rate),
+        )));
+    }
+    Ok(rate)
+}
+
+impl ChaosConfig {
+    pub fn new_from_vars(
+        get: impl Fn(&str) -> Option<String>,
+    ) -> Result<ChaosConfig, Box<dyn Error>> {
+        Ok(ChaosConfig {
+            slow_rate: parse_rate(&get, "FW_CHAOS_SLOW_RATE")?,
+            slow_ms: parse_var(&get, "FW_CHAOS_SLOW_MS")?.unwrap_or(100),
+            drop_rate: parse_rate(&get, "FW_CHAOS_DROP_RATE")?,
+            load_fail_rate: parse_rate(&get, "FW_CHAOS_LOAD_FAIL_RATE")?,
+            parse_error_rate: parse_rate(&get, "FW_CHAOS_PARSE_ERROR_RATE")?,
+            seed: parse_var(&get, "FW_CHAOS_SEED")?,
+        })
+    }
+
+    pub fn new_from_env() -> Result<ChaosConfig, Box<dyn Error>> {
+        ChaosConfig::new_from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn rate(&self, fault: Fault) -> f64 {
+        match fault {
+            Fault::SlowResponse => self.slow_rate,
+            Fault::DropConnection => self.drop_rate,
+            Fault::ModelLoadFailure => self.load_fail_rate,
+            Fault::ParseError => self.parse_error_rate,
+        }
+    }
+}
+
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: Xoshiro256PlusPlus,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig, stream: u64) -> Chaos {
+        let rng = match config.seed {
+            Some(seed) => Xoshiro256PlusPlus::seed_from_u64(seed.wrapping_add(stream)),
+            None => Xoshiro256PlusPlus::seed_from_u64(rand::random()),
+        };
+        Chaos { config, rng }
+    }
+
+    pub fn should_inject(&mut self, fault: Fault) -> bool {
+        let rate = self.config.rate(fault);
+        rate > 0.0 && self.rng.gen::<f64>() < rate
+    }
+}
+
+// Each thread gets its own random stream, so seeded runs are reproducible per thread
+static NEXT_STREAM: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static THREAD_CHAOS: RefCell<Option<Chaos>> = const { RefCell::new(None) };
+}
+
+// Decides whether the fault happens now, using the chaos state of the current thread
+pub fn inject(fault: Fault) -> bool {
+    THREAD_CHAOS.with(|chaos| {
+        let mut chaos = chaos.borrow_mut();
+        let chaos = chaos.get_or_insert_with(|| {
+            let config = ChaosConfig::new_from_env().unwrap_or_else(|e| panic!("{}", e));
+            Chaos::new(config, NEXT_STREAM.fetch_add(1, Ordering::Relaxed))
+        });
+        chaos.should_inject(fault)
+    })
+}
+
+pub fn slow_response() {
+    if inject(Fault::SlowResponse) {
+        let ms = THREAD_CHAOS.with(|chaos| chaos.borrow().as_ref().unwrap().config.slow_ms);
+        thread::sleep(Duration::from_millis(ms));
+    }
+}
+
+pub fn parse_result(result: Result<&[u32], Box<dyn Error>>) -> Result<&[u32], Box<dyn Error>> {
+    match result {
+        Ok(buffer) if !buffer.is_empty() && inject(Fault::ParseError) => {
+            Err(Box::new(InjectedFault {
+                fault: Fault::ParseError,
+            }))
+        }
+        result => result,
+    }
+}
+
+// Fails a model load before the model file is read, so the served model stays as it was
+pub fn before_load() -> Result<(), Box<dyn Error>> {
+    if inject(Fault::ModelLoadFailure) {
+        return Err(Box::new(InjectedFault {
+            fault: Fault::ModelLoadFailure,
+        }));
+    }
+    Ok(())
+}
+
+// Replaces the chaos state of the current thread, instead of reading it from the environment
+pub fn set_thread_config(config: ChaosConfig) {
+    THREAD_CHAOS.with(|chaos| {
+        *chaos.borrow_mut() = Some(Chaos::new(
+            config,
+            NEXT_STREAM.fetch_add(1, Ordering::Relaxed),
+        ))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<ChaosConfig, Box<dyn Error>> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        ChaosConfig::new_from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(
+            config_from(&[]).unwrap(),
+            ChaosConfig {
+                slow_ms: 100,
+                ..ChaosConfig::default()
+            }
+        );
+        let config = config_from(&[
+            ("FW_CHAOS_DROP_RATE", "0.25"),
+            ("FW_CHAOS_SLOW_MS", "5"),
+            ("FW_CHAOS_SEED", "7"),
+        ])
+        .unwrap();
+        assert_eq!(config.drop_rate, 0.25);
+        assert_eq!(config.slow_ms, 5);
+        assert_eq!(config.seed, Some(7));
+        assert!(config_from(&[("FW_CHAOS_PARSE_ERROR_RATE", "1.5")]).is_err());
+        assert!(config_from(&[("FW_CHAOS_SLOW_MS", "fast")]).is_err());
+    }
+
+    #[test]
+    fn test_injection_rates() {
+        let config = config_from(&[
+            ("FW_CHAOS_DROP_RATE", "1.0"),
+            ("FW_CHAOS_PARSE_ERROR_RATE", "0.3"),
+            ("FW_CHAOS_SEED", "1"),
+        ])
+        .unwrap();
+        let mut chaos = Chaos::new(config.clone(), 0);
+        assert!(chaos.should_inject(Fault::DropConnection));
+        assert!(!chaos.should_inject(Fault::SlowResponse));
+        let injected = (0..10000)
+            .filter(|_| chaos.should_inject(Fault::ParseError))
+            .count();
+        assert!((2700..3300).contains(&injected));
+
+        // Same seed and stream give the same faults
+        let mut chaos1 = Chaos::new(config.clone(), 3);
+        let mut chaos2 = Chaos::new(config, 3);
+        for _ in 0..100 {
+            assert_eq!(
+                chaos1.should_inject(Fault::ParseError),
+                chaos2.should_inject(Fault::ParseError)
+            );
+        }
+    }
+}
@@ -1,9 +1,20 @@
+use crate::config_file;
 use crate::version;
 use clap::{App, AppSettings, Arg};
 
 pub fn parse<'a>() -> clap::ArgMatches<'a> {
-    let matches = create_expected_args().get_matches();
-    matches
+    parse_from(std::env::args().collect()).unwrap_or_else(|e| e.exit())
+}
+
+// Parses the command line extended with options from the --config file. Options can be repeated,
+// so the ones given on the command line override those from the file
+pub fn parse_from<'a>(args: Vec<String>) -> Result<clap::ArgMatches<'a>, clap::Error> {
+    let args = config_file::expand_config_args(args).map_err(|e| {
+        clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue)
+    })?;
+    create_expected_args()
+        .setting(AppSettings::AllArgsOverrideSelf)
+        .get_matches_from_safe(args)
 }
 
 pub fn create_expected_args<'a>() -> App<'a, 'a> {
@@ -12,17 +23,30 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
         .author("Andraz Tori <atori@outbrain.com>")
         .about("Superfast Logistic Regression & Field Aware Factorization Machines")
         .setting(AppSettings::DeriveDisplayOrder)
+        .arg(Arg::with_name("config")
+             .long("config")
+             .value_name("fw.toml")
+             .help("Read options from a toml file, options given on the command line override them")
+             .takes_value(true))
         .arg(Arg::with_name("data")
              .long("data")
              .short("d")
              .value_name("filename")
              .help("File with input examples")
              .takes_value(true))
+        .arg(Arg::with_name("source")
+             .long("source")
+             .value_name("filename:importance")
+             .conflicts_with_all(&["data", "cache"])
+             .help("Train from several input files at once instead of --data, interleaving their examples. Example importance is multiplied by the importance of the source, metrics are reported per source. Namespace map is read from the directory of the first source")
+             .multiple(true)
+             .takes_value(true))
         .arg(Arg::with_name("quiet")
              .long("quiet")
              .help("Quiet mode, does nothing currently (as we don't output diagnostic data anyway)")
              .takes_value(false))
         .arg(Arg::with_name("predictions")
+             .long("predictions")
              .short("p")
              .value_name("output predictions file")
              .help("Output predictions file")
@@ -32,6 +56,12 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .long("cache")
              .help("Use cache file")
              .takes_value(false))
+        .arg(Arg::with_name("cache_shards")
+             .long("cache_shards")
+             .value_name("N")
+             .requires("cache")
+             .help("Split cache into N shard files, built by N parallel parser threads")
+             .takes_value(true))
         .arg(Arg::with_name("save_resume")
              .long("save_resume")
              .help("save extra state so learning can be resumed later with new data")
@@ -92,6 +122,36 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("0.5")
              .help("How to apply Adagrad (0.5 = sqrt)")
              .takes_value(true))
+        .arg(Arg::with_name("grad_clip")
+             .long("grad_clip")
+             .value_name("norm")
+             .help("Clip the per example gradient of the LR, FFM and nn layer weights to this L2 norm, each block on its own, before the optimizer update")
+             .takes_value(true))
+        .arg(Arg::with_name("minibatch")
+             .long("minibatch")
+             .value_name("1")
+             .help("Sum the FFM and nn layer gradients of this many examples and apply them at once, with one optimizer step per touched weight. Each hogwild worker has its own mini-batches")
+             .takes_value(true))
+        .arg(Arg::with_name("weight_decay")
+             .long("weight_decay")
+             .value_name("0.0")
+             .help("Decoupled weight decay (as in AdamW): fraction of a weight taken away on each of its updates, apart from the optimizer step. Also the default of --ffm_weight_decay")
+             .takes_value(true))
+        .arg(Arg::with_name("ffm_weight_decay")
+             .long("ffm_weight_decay")
+             .value_name("0.0")
+             .help("Decoupled weight decay of the FFM embeddings, also the default of --nn_weight_decay")
+             .takes_value(true))
+        .arg(Arg::with_name("nn_weight_decay")
+             .long("nn_weight_decay")
+             .value_name("0.0")
+             .help("Decoupled weight decay of nn layer weights (biases don't decay)")
+             .takes_value(true))
+        .arg(Arg::with_name("ffm_l2")
+             .long("ffm_l2")
+             .value_name("0.0")
+             .help("L2 regularization of FFM embeddings, applied to the embeddings each example touches")
+             .takes_value(true))
         .arg(Arg::with_name("nn_power_t")
              .long("nn_power_t")
              .value_name("0.5")
@@ -100,7 +160,7 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
         .arg(Arg::with_name("l2")
              .long("l2")
              .value_name("0.0")
-             .help("Regularization is not supported (only 0.0 will work)")
+             .help("L2 regularization of FTRL (default 0), other optimizers reject it, for FFM see --ffm_l2")
              .takes_value(true))
 
         .arg(Arg::with_name("sgd")
@@ -113,11 +173,60 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("")
              .help("Use Adagrad")
              .takes_value(false))
+        .arg(Arg::with_name("adam")
+             .long("adam")
+             .conflicts_with("sgd")
+             .conflicts_with("adaptive")
+             .help("Use Adam, with bias-corrected moment estimates of each weight")
+             .takes_value(false))
+        .arg(Arg::with_name("momentum")
+             .long("momentum")
+             .value_name("0.9")
+             .conflicts_with("sgd")
+             .conflicts_with("adaptive")
+             .conflicts_with("adam")
+             .help("Use SGD with momentum, keeping this fraction of the velocity of each weight between updates")
+             .takes_value(true))
+        .arg(Arg::with_name("nesterov")
+             .long("nesterov")
+             .requires("momentum")
+             .help("Use Nesterov momentum instead of the classic one")
+             .takes_value(false))
+        .arg(Arg::with_name("lr_optimizer")
+             .long("lr_optimizer")
+             .value_name("sgd|adagrad|cocob|adam|ftrl|momentum|nesterov")
+             .help("Optimizer of the LR block, when different from the others. cocob needs no learning rate, for long running online training where any fixed learning rate is eventually wrong")
+             .takes_value(true))
+        .arg(Arg::with_name("ffm_optimizer")
+             .long("ffm_optimizer")
+             .value_name("sgd|adagrad|adam|momentum|nesterov")
+             .help("Optimizer of the FFM block, when different from the others")
+             .takes_value(true))
+        .arg(Arg::with_name("nn_optimizer")
+             .long("nn_optimizer")
+             .value_name("sgd|adagrad|adam|momentum|nesterov")
+             .help("Optimizer of the neural layers and embedding lookups, when different from the others")
+             .takes_value(true))
+        .arg(Arg::with_name("ftrl")
+             .long("ftrl")
+             .conflicts_with("lr_optimizer")
+             .help("Use FTRL-Proximal in the LR block, with --l1 it zeroes weights of rare or useless features (same as --lr_optimizer ftrl)")
+             .takes_value(false))
+        .arg(Arg::with_name("l1")
+             .long("l1")
+             .value_name("strength")
+             .help("L1 regularization of FTRL (default 0)")
+             .takes_value(true))
         .arg(Arg::with_name("noconstant")
              .long("noconstant")
              .value_name("")
              .help("No intercept")
              .takes_value(false))
+        .arg(Arg::with_name("graph_paranoia")
+             .long("graph_paranoia")
+             .value_name("")
+             .help("Validate tape offsets and wiring of the block graph at start and on the first example")
+             .takes_value(false))
         .arg(Arg::with_name("link")
              .long("link")
              .value_name("logistic")
@@ -128,6 +237,39 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("logistic")
              .help("What loss function to use")
              .takes_value(true))
+        .arg(Arg::with_name("oaa")
+             .long("oaa")
+             .value_name("num_classes")
+             .help("Multiclass model with a softmax head, labels are class numbers from 1 to num_classes, predictions are the most likely class")
+             .takes_value(true))
+        .arg(Arg::with_name("bpr")
+             .long("bpr")
+             .conflicts_with("oaa")
+             .help("Pairwise ranking (BPR) loss: consecutive examples with the same tag, one labeled 1 and the other -1, are learned as a pair, on the difference of their scores")
+             .takes_value(false))
+        .arg(Arg::with_name("heads")
+             .long("heads")
+             .value_name("num_heads")
+             .conflicts_with_all(&["oaa", "bpr"])
+             .help("Multi-task model: num_heads logloss heads on the shared LR/FFM/NN model, each with its own label, given as \"label1,label2,...\" on the example line (1, -1 or empty when unknown)")
+             .takes_value(true))
+        .arg(Arg::with_name("focal_gamma")
+             .long("focal_gamma")
+             .value_name("gamma")
+             .conflicts_with_all(&["oaa", "bpr"])
+             .help("Focal loss: scale the logloss of each example by (1 - probability of its label)^gamma, down-weighting the easy examples of imbalanced data (default 0, the logloss)")
+             .takes_value(true))
+        .arg(Arg::with_name("label_smoothing")
+             .long("label_smoothing")
+             .value_name("eps")
+             .conflicts_with_all(&["oaa", "bpr", "focal_gamma", "invariant"])
+             .help("Label smoothing: train the logloss toward labels of eps/2 and 1 - eps/2 instead of 0 and 1, for less over-confident predictions (default 0)")
+             .takes_value(true))
+        .arg(Arg::with_name("invariant")
+             .long("invariant")
+             .conflicts_with_all(&["oaa", "bpr", "focal_gamma", "label_smoothing", "heads", "embedding_lookup"])
+             .help("Importance weight aware updates of LR and FFM: an example of importance h moves the prediction as h examples in a row would, instead of overshooting with a step h times as large")
+             .takes_value(false))
         .arg(Arg::with_name("bit_precision")
              .short("b")
              .long("bit_precision")
@@ -147,12 +289,155 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("arg")
              .help("Final regressor to save (arg is filename)")
              .takes_value(true))
+        .arg(Arg::with_name("namespace_ttl")
+             .long("namespace_ttl")
+             .value_name("A:10M")
+             .help("LR weights of features of this namespace are halved for every given number of examples (K/M/G suffixes allowed) in which they are not seen. Example numbers are the clock, so it restarts with every run and does not advance in hogwild workers")
+             .multiple(true)
+             .takes_value(true))
+        .arg(Arg::with_name("namespace_topk")
+             .long("namespace_topk")
+             .value_name("T:64")
+             .help("Keep only the k features with the largest weights from this namespace, to protect latency against examples with huge namespaces")
+             .multiple(true)
+             .takes_value(true))
+        .arg(Arg::with_name("dup_policy")
+             .long("dup_policy")
+             .value_name("sum|max|first")
+             .help("Merge features that appear more than once in the same namespace of an example: sum their weights, keep the max or keep the first one")
+             .takes_value(true))
+        .arg(Arg::with_name("record_value_ranges")
+             .long("record_value_ranges")
+             .help("Record the ranges of values of f32 namespaces seen in training and save them with the model")
+             .takes_value(false))
+        .arg(Arg::with_name("check_value_ranges")
+             .long("check_value_ranges")
+             .help("Count values of f32 namespaces outside of the ranges recorded in training, warn about them in testing and serving")
+             .takes_value(false))
+        .arg(Arg::with_name("parity_check")
+             .long("parity_check")
+             .value_name("fraction")
+             .help("Daemon: translate this fraction of requests also with a slow reference implementation and warn when the feature buffers differ")
+             .takes_value(true))
+        .arg(Arg::with_name("lofo")
+             .long("lofo")
+             .conflicts_with_all(&["nn", "nn_layers"])
+             .help("Daemon: answer each request with the prediction followed by <namespace>:<approximate prediction without the namespace> for each namespace of the model, which can't have neural layers")
+             .takes_value(false))
+        .arg(Arg::with_name("tenant_namespace")
+             .long("tenant_namespace")
+             .value_name("T")
+             .help("Namespace with the tenant id, mixed into the hashes of all other namespaces so each tenant gets its own weights (passthrough and transformed namespaces stay shared)")
+             .takes_value(true))
+        .arg(Arg::with_name("dp_clip")
+             .long("dp_clip")
+             .value_name("norm")
+             .help("Differential privacy: clip the L2 norm of per example gradients of LR and FFM to this value")
+             .takes_value(true))
+        .arg(Arg::with_name("dp_noise")
+             .long("dp_noise")
+             .value_name("multiplier")
+             .requires("dp_clip")
+             .help("Differential privacy: add gaussian noise with standard deviation of multiplier * dp_clip to updates")
+             .takes_value(true))
+        .arg(Arg::with_name("dp_delta")
+             .long("dp_delta")
+             .value_name("delta")
+             .requires("dp_clip")
+             .help("Differential privacy: delta for which the spent epsilon is reported (default 1e-6)")
+             .takes_value(true))
+        .arg(Arg::with_name("accurate_accumulation")
+             .long("accurate_accumulation")
+             .help("Sum FFM pairwise interactions and the logloss input in double precision, slower but without the rounding drift of many fields")
+             .takes_value(false))
+        .arg(Arg::with_name("autotune")
+             .long("autotune")
+             .help("Benchmark the kernel variants of FFM and neural layers on this machine at startup and use the fastest ones")
+             .takes_value(false))
+        .arg(Arg::with_name("avx512")
+             .long("avx512")
+             .help("Use the AVX-512 FFM kernel, with --autotune only if it is the fastest. Predictions differ in the last bits from those of the other kernels")
+             .takes_value(false))
+        .arg(Arg::with_name("hash_usage")
+             .long("hash_usage")
+             .help("Track which LR and FFM hash buckets the training data touches, report the saturation and estimated collision rate at the end")
+             .takes_value(false))
+        .arg(Arg::with_name("hash_saturation_warning")
+             .long("hash_saturation_warning")
+             .value_name("fraction")
+             .requires("hash_usage")
+             .help("Warn that bit precision is too low when more than this fraction of a hash space is touched (default 0.5)")
+             .takes_value(true))
+        .arg(Arg::with_name("feature_importance")
+             .long("feature_importance")
+             .value_name("out.json")
+             .help("After training, write the LR weight mass and FFM embedding norms of the model by feature combo, field pair and namespace as json")
+             .takes_value(true))
+        .arg(Arg::with_name("invert_hash")
+             .long("invert_hash")
+             .value_name("filename")
+             .help("Write the weights of the trained model by feature name, of features seen in the examples (human readable, like vw's --invert_hash)")
+             .takes_value(true))
+        .arg(Arg::with_name("audit")
+             .long("audit")
+             .help("Print the features of each example to stdout as name:hash:value")
+             .takes_value(false))
+        .arg(Arg::with_name("score_map")
+             .long("score_map")
+             .value_name("map.json")
+             .help("Piecewise linear mapping of the output predictions (--predictions, daemon responses), logloss and evaluations use the probabilities; saved with the regressor (arg is json filename)")
+             .takes_value(true))
+        .arg(Arg::with_name("snapshot_regressor")
+             .long("snapshot_regressor")
+             .value_name("arg")
+             .help("Periodically save regressor to this file in the background, while training continues (arg is filename)")
+             .takes_value(true))
+        .arg(Arg::with_name("snapshot_every")
+             .long("snapshot_every")
+             .value_name("examples")
+             .requires("snapshot_regressor")
+             .help("Number of examples between two snapshots of the regressor")
+             .takes_value(true))
+        .arg(Arg::with_name("checkpoint_dir")
+             .long("checkpoint_dir")
+             .value_name("dir")
+             .requires("checkpoint_every")
+             .help("Save resumable checkpoints of the regressor to numbered files in this directory, in the background, while training continues")
+             .takes_value(true))
+        .arg(Arg::with_name("checkpoint_every")
+             .long("checkpoint_every")
+             .value_name("examples")
+             .requires("checkpoint_dir")
+             .help("Number of examples between two checkpoints")
+             .takes_value(true))
+        .arg(Arg::with_name("checkpoint_keep")
+             .long("checkpoint_keep")
+             .value_name("arg (=3)")
+             .requires("checkpoint_dir")
+             .help("Number of newest checkpoints kept, older ones are removed")
+             .takes_value(true))
+        .arg(Arg::with_name("signals")
+             .long("signals")
+             .help("While training, snapshot to --snapshot_regressor and flush predictions on SIGUSR1, switch learning off and on with SIGUSR2. In daemon mode SIGUSR1 saves the state of stateful transforms")
+             .takes_value(false))
+        .arg(Arg::with_name("resume")
+             .long("resume")
+             .requires("snapshot_regressor")
+             .conflicts_with("source")
+             .help("If the snapshot regressor exists, continue training from it, at the example where it was taken. The input has to be the same as the snapshot was trained on")
+             .takes_value(false))
         .arg(Arg::with_name("initial_regressor")
              .short("i")
              .long("initial_regressor")
              .value_name("arg")
              .help("Initial regressor(s) to load into memory (arg is filename)")
              .takes_value(true))
+        .arg(Arg::with_name("vw_initial_regressor")
+             .long("vw_initial_regressor")
+             .value_name("filename")
+             .conflicts_with("initial_regressor")
+             .help("Start LR weights from a Vowpal Wabbit model saved with --readable_model, trained with the same namespaces, --bit_precision and hash seed 0")
+             .takes_value(true))
         .arg(Arg::with_name("testonly")
              .short("t")
              .long("testonly")
@@ -170,12 +455,144 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .help("Inference regressor to save (arg is filename)")
              .takes_value(true))
 
+        .arg(Arg::with_name("blend_base")
+             .long("blend_base")
+             .value_name("filename")
+             .requires_all(&["blend_delta", "blend_alpha", "blend_out"])
+             .help("Blend this model with --blend_delta into an inference model, in logit space: (1 - alpha) * base + alpha * delta. See --blend_mode")
+             .takes_value(true))
+        .arg(Arg::with_name("blend_delta")
+             .long("blend_delta")
+             .value_name("filename")
+             .requires("blend_base")
+             .help("Model blended into --blend_base")
+             .takes_value(true))
+        .arg(Arg::with_name("blend_alpha")
+             .long("blend_alpha")
+             .value_name("0.2")
+             .requires("blend_base")
+             .help("Weight of --blend_delta in the blend, between 0.0 and 1.0")
+             .takes_value(true))
+        .arg(Arg::with_name("blend_mode")
+             .long("blend_mode")
+             .value_name("output")
+             .requires("blend_base")
+             .help("output: the blended model evaluates both models and blends their predictions, models need to read the same features. weights: blends the weights into a single model, models need identical structure")
+             .takes_value(true))
+        .arg(Arg::with_name("blend_out")
+             .long("blend_out")
+             .value_name("filename")
+             .requires("blend_base")
+             .help("Filename of the blended model")
+             .takes_value(true))
+
+        .arg(Arg::with_name("merge_model")
+             .long("merge_model")
+             .value_name("filename")
+             .requires("merge_out")
+             .help("Model to merge into an inference model with the element-wise weighted average of all of them, given at least twice. Models need identical structure")
+             .multiple(true)
+             .takes_value(true))
+        .arg(Arg::with_name("merge_weights")
+             .long("merge_weights")
+             .value_name("0.7,0.3")
+             .requires("merge_model")
+             .help("Weights of the merged models in order, normalized to sum to one. Default is equal weights")
+             .takes_value(true))
+        .arg(Arg::with_name("merge_out")
+             .long("merge_out")
+             .value_name("filename")
+             .requires("merge_model")
+             .help("Filename of the merged model")
+             .takes_value(true))
+
+        .arg(Arg::with_name("delta_base")
+             .long("delta_base")
+             .value_name("filename")
+             .help("Full model the delta is relative to: training has to start from it (--initial_regressor), with --final_regressor only the weight chunks written since are saved, with --apply_delta the delta is applied onto it")
+             .takes_value(true))
+        .arg(Arg::with_name("apply_delta")
+             .long("apply_delta")
+             .value_name("filename")
+             .requires_all(&["delta_base", "apply_delta_out"])
+             .help("Apply this model delta onto --delta_base and save the full model to --apply_delta_out")
+             .takes_value(true))
+        .arg(Arg::with_name("apply_delta_out")
+             .long("apply_delta_out")
+             .value_name("filename")
+             .requires("apply_delta")
+             .help("Filename of the model with the delta applied")
+             .takes_value(true))
+
+        .arg(Arg::with_name("reset_model")
+             .long("reset_model")
+             .value_name("filename")
+             .requires_all(&["reset_namespaces", "reset_data", "reset_out"])
+             .help("Re-initialize weights of --reset_namespaces in this model, keeping everything else")
+             .takes_value(true))
+        .arg(Arg::with_name("reset_namespaces")
+             .long("reset_namespaces")
+             .value_name("A,B")
+             .requires("reset_model")
+             .help("Comma separated namespaces whose lr weights and ffm embeddings are reset")
+             .takes_value(true))
+        .arg(Arg::with_name("reset_data")
+             .long("reset_data")
+             .value_name("filename")
+             .requires("reset_model")
+             .help("Examples with the features to reset, their hashes identify the weights")
+             .takes_value(true))
+        .arg(Arg::with_name("reset_out")
+             .long("reset_out")
+             .value_name("filename")
+             .requires("reset_model")
+             .help("Filename of the reset model")
+             .takes_value(true))
+
+        .arg(Arg::with_name("inspect")
+             .long("inspect")
+             .requires("initial_regressor")
+             .help("Print metadata of the --initial_regressor model and statistics of the weights of each block")
+             .takes_value(false))
+        .arg(Arg::with_name("inspect_histogram")
+             .long("inspect_histogram")
+             .value_name("bins")
+             .requires("inspect")
+             .help("Also print a histogram of the weights of each block with this many bins")
+             .takes_value(true))
+
+        .arg(Arg::with_name("export_onnx")
+             .long("export_onnx")
+             .value_name("filename")
+             .requires("initial_regressor")
+             .help("Export the --initial_regressor model to an ONNX model of a single example, its inputs are the hashed lr and ffm features")
+             .takes_value(true))
+
+        .arg(Arg::with_name("dump_embeddings")
+             .long("dump_embeddings")
+             .value_name("filename")
+             .requires("initial_regressor")
+             .help("Write ffm embeddings of the --initial_regressor model by feature hash, as npz for a .npz filename and tsv otherwise")
+             .takes_value(true))
+        .arg(Arg::with_name("dump_embeddings_data")
+             .long("dump_embeddings_data")
+             .value_name("filename")
+             .requires("dump_embeddings")
+             .help("Examples whose features name the dumped embeddings, only those are dumped")
+             .takes_value(true))
+
         .arg(Arg::with_name("transform")
              .long("transform")
              .value_name("target_namespace=func(source_namespaces)(parameters)")
              .help("Create new namespace by transforming one or more other namespaces")
              .multiple(true)
              .takes_value(true))
+        .arg(Arg::with_name("transform_state")
+             .long("transform_state")
+             .value_name("filename")
+             .conflicts_with("hogwild_training")
+             .help("State of stateful transforms (RollingCount): loaded at start if the file exists, saved with snapshots, at the end of training and when the daemon shuts down. Without it the state is kept in <regressor>.transform_state next to --initial_regressor and --final_regressor")
+             .takes_value(true))
 
         .arg(Arg::with_name("ffm_field")
              .long("ffm_field")
@@ -194,11 +611,34 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("k")
              .help("Lenght of a vector to use for FFM")
              .takes_value(true))
+        .arg(Arg::with_name("fm_k")
+             .long("fm_k")
+             .value_name("k")
+             .conflicts_with_all(&["ffm_k", "invariant"])
+             .help("Length of the embeddings of a plain factorization machine over the --ffm_field namespaces, one embedding per feature instead of one per field. It uses the ffm_* hyperparameters and hash space")
+             .takes_value(true))
+        .arg(Arg::with_name("ffm_field_attention")
+             .long("ffm_field_attention")
+             .requires("ffm_k")
+             .conflicts_with("invariant")
+             .help("Learn a weight for each pair of FFM fields that scales their interaction, as in FwFM. It uses the ffm_* hyperparameters")
+             .takes_value(false))
+        .arg(Arg::with_name("ffm_field_pooling")
+             .long("ffm_field_pooling")
+             .value_name("field_index:sum|mean")
+             .help("Combine the features of a multi-valued FFM field by their mean instead of their sum, i.e. scale their values by 1/number of features of the field in the example. Fields are numbered from 0 in the order of --ffm_field and then --ffm_field_verbose")
+             .multiple(true)
+             .takes_value(true))
         .arg(Arg::with_name("ffm_bit_precision")
              .long("ffm_bit_precision")
              .value_name("N")
              .help("Bits to use for ffm hash space")
              .takes_value(true))
+        .arg(Arg::with_name("ffm_layout")
+             .long("ffm_layout")
+             .value_name("feature_major|field_major")
+             .help("Layout of the FFM weight table, chosen when training starts: the embeddings of a feature towards all fields together (default) or a region of the table for each field")
+             .takes_value(true))
         .arg(Arg::with_name("ffm_k_threshold")
              .long("ffm_k_threshold")
              .help("A minum gradient on left and right side to increase k")
@@ -250,12 +690,65 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .multiple(true)
              .takes_value(true))
 
+        .arg(Arg::with_name("cross_layers")
+             .long("cross_layers")
+             .value_name("N")
+             .conflicts_with("invariant")
+             .help("Stack N DCN cross layers on the LR and FFM outputs x0, each computing x0 * (w . x) + b + x. They use the nn_* hyperparameters")
+             .takes_value(true))
+
         .arg(Arg::with_name("nn_topology")
              .long("nn_topology")
              .help("How should connections be organized - possiblities 'one' and 'two'")
              .multiple(false)
              .takes_value(true))
+        .arg(Arg::with_name("nn_dropout_schedule")
+             .long("nn_dropout_schedule")
+             .value_name("linear:0.3:0.0:10M")
+             .help("Decay dropout of hidden nn layers linearly from start to end rate over the given number of examples (K/M/G suffixes allowed)")
+             .takes_value(true))
+        .arg(Arg::with_name("lr_schedule")
+             .long("lr_schedule")
+             .value_name("warmup:100K,cosine:10M:0.1")
+             .help("Multiply learning rates of all blocks by a schedule over examples: warmup:num_examples, exp:factor:num_examples, cosine:num_examples[:min_multiplier], combined with commas (K/M/G suffixes allowed). Continuing training from a model continues its schedule")
+             .takes_value(true))
+        .arg(Arg::with_name("dense_input")
+             .long("dense_input")
+             .value_name("verbose_namespace")
+             .requires("nn_layers")
+             .help("Appends the values of an f32vec namespace to the input of the nn layers, as dense inputs")
+             .multiple(true)
+             .takes_value(true))
+        .arg(Arg::with_name("embedding_lookup")
+             .long("embedding_lookup")
+             .value_name("verbose_namespace:filename[:sum|mean|max[:max_len]][:finetune]")
+             .requires("nn_layers")
+             .help("Appends pooled pre-trained embeddings of the features of a namespace to the input of the nn layers. File lines are \"feature v1 .. vN\", mean and max pool up to max_len features (default 32) and count and log the dropped rest, the table is fixed unless finetune is given")
+             .multiple(true)
+             .takes_value(true))
+
 
+    // Soak testing
+        .arg(Arg::with_name("soak_hours")
+             .long("soak_hours")
+             .value_name("hours")
+             .help("Run a soak test on synthetic traffic for this many hours instead of training on --data. Model is set up by the usual flags, namespaces are A, B, C, ...")
+             .takes_value(true))
+        .arg(Arg::with_name("soak_cardinalities")
+             .long("soak_cardinalities")
+             .value_name("10,1000,100000")
+             .help("Number of distinct values of each synthetic namespace in soak test")
+             .takes_value(true))
+        .arg(Arg::with_name("soak_drift")
+             .long("soak_drift")
+             .value_name("0.01")
+             .help("Fraction of hidden weights of synthetic traffic that change every 100K examples")
+             .takes_value(true))
+        .arg(Arg::with_name("soak_report_seconds")
+             .long("soak_report_seconds")
+             .value_name("60")
+             .help("How often soak test verifies its invariants and reports")
+             .takes_value(true))
 
     // Daemon parameterts
         .arg(Arg::with_name("daemon")
@@ -267,20 +760,147 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .help("Which weight initialization to consider")
              .multiple(false)
              .takes_value(true))
+        .arg(Arg::with_name("seed")
+             .long("seed")
+             .value_name("seed")
+             .help("Seed of all random numbers of the model (weight initialization, dropout), saved with the model (default 0)")
+             .takes_value(true))
         .arg(Arg::with_name("port")
              .long("port")
              .value_name("arg")
              .help("port to listen on")
              .takes_value(true))
+        .arg(Arg::with_name("http_port")
+             .long("http_port")
+             .value_name("arg")
+             .help("Also serve predictions over HTTP on this port: POST /predict with JSON examples {namespace: {feature: value}}, add ?stats=true for stats of the predictions")
+             .takes_value(true))
+        .arg(Arg::with_name("grpc_port")
+             .long("grpc_port")
+             .value_name("arg")
+             .help("Also serve the gRPC interface of proto/serving.proto on this port, needs fw built with the grpc feature")
+             .takes_value(true))
+        .arg(Arg::with_name("tls_cert")
+             .long("tls_cert")
+             .value_name("filename")
+             .help("In daemon mode, serve over TLS with this PEM certificate chain, needs --tls_key. Applies to --http_port too")
+             .takes_value(true))
+        .arg(Arg::with_name("tls_key")
+             .long("tls_key")
+             .value_name("filename")
+             .help("PEM private key of the --tls_cert certificate")
+             .takes_value(true))
         .arg(Arg::with_name("num_children")
              .long("num_children")
              .value_name("arg (=10")
              .help("number of children for persistent daemon mode")
              .takes_value(true))
+        .arg(Arg::with_name("max_batch")
+             .long("max_batch")
+             .value_name("arg (=1000)")
+             .help("Maximum number of examples of a \"batch <n>\" request to the daemon, larger batches are refused")
+             .takes_value(true))
+        .arg(Arg::with_name("mmap_weights")
+             .long("mmap_weights")
+             .value_name("dir")
+             .help("In daemon mode, keep FFM and neural weights in files in this directory mapped into memory, daemons serving the same model share them")
+             .takes_value(true))
+        .arg(Arg::with_name("explain_top_k")
+             .long("explain_top_k")
+             .value_name("arg (=5)")
+             .help("In daemon mode, number of the largest contributions of LR combos and FFM field pairs to the logit that \"explain <example>\" returns with the prediction")
+             .takes_value(true))
+        .arg(Arg::with_name("model_canary")
+             .long("model_canary")
+             .value_name("example")
+             .help("In daemon mode, an example in VW format that models loaded with \"model_load\" have to predict before they are switched to")
+             .takes_value(true))
+        .arg(Arg::with_name("shutdown_timeout")
+             .long("shutdown_timeout")
+             .value_name("seconds (=30)")
+             .help("On SIGTERM the daemon stops accepting connections and waits this long for open ones to be served, then saves the served model to --final_regressor if given")
+             .takes_value(true))
         .arg(Arg::with_name("foreground")
              .long("foreground")
              .help("in daemon mode, do not fork and run and run fw process in the foreground")
              .takes_value(false))
+        .arg(Arg::with_name("rate_limit_connection")
+             .long("rate_limit_connection")
+             .value_name("examples/s")
+             .help("In daemon mode, limit of examples per second of each connection, requests of several examples are admitted or limited as a whole")
+             .takes_value(true))
+        .arg(Arg::with_name("rate_limit_client")
+             .long("rate_limit_client")
+             .value_name("examples/s")
+             .help("In daemon mode, limit of examples per second of each client ip, over all its connections")
+             .takes_value(true))
+        .arg(Arg::with_name("rate_limit_mode")
+             .long("rate_limit_mode")
+             .value_name("queue|reject")
+             .help("Over the rate limit, wait until within the limit (queue, default) or answer \"ERR: rate limited\" instead of a prediction (reject). Counters are returned by the \"stats\" command")
+             .takes_value(true))
+        .arg(Arg::with_name("alert_max_logloss")
+             .long("alert_max_logloss")
+             .value_name("logloss")
+             .help("In daemon mode, alert when the logloss of labeled requests over the alert interval is above this")
+             .takes_value(true))
+        .arg(Arg::with_name("alert_min_prediction_rate")
+             .long("alert_min_prediction_rate")
+             .value_name("predictions/s")
+             .help("In daemon mode, alert when fewer predictions per second are served over the alert interval")
+             .takes_value(true))
+        .arg(Arg::with_name("alert_max_parse_error_rate")
+             .long("alert_max_parse_error_rate")
+             .value_name("fraction")
+             .help("In daemon mode, alert when a larger fraction of requests over the alert interval can't be parsed")
+             .takes_value(true))
+        .arg(Arg::with_name("alert_max_model_age")
+             .long("alert_max_model_age")
+             .value_name("seconds")
+             .help("In daemon mode, alert when the served model file was written longer ago than this")
+             .takes_value(true))
+        .arg(Arg::with_name("alert_interval")
+             .long("alert_interval")
+             .value_name("seconds (60)")
+             .help("How often alert thresholds are checked, rates and logloss are over this interval")
+             .takes_value(true))
+        .arg(Arg::with_name("alert_status_file")
+             .long("alert_status_file")
+             .value_name("filename")
+             .help("Write the outcome of each alert check to this file, it is returned by the \"stats\" command as well")
+             .takes_value(true))
+        .arg(Arg::with_name("alert_exit")
+             .long("alert_exit")
+             .help("Exit when an alert threshold is breached, with exit code 10 (logloss), 11 (prediction rate), 12 (parse error rate) or 13 (model age)")
+             .takes_value(false))
+        .arg(Arg::with_name("prediction_log")
+             .long("prediction_log")
+             .value_name("filename")
+             .help("Log every served prediction with its timestamp, request hash and model version to this binary file")
+             .takes_value(true))
+        .arg(Arg::with_name("prediction_log_rotate_mb")
+             .long("prediction_log_rotate_mb")
+             .value_name("1024")
+             .requires("prediction_log")
+             .help("Size in MB after which the prediction log is rotated to <filename>.<timestamp>")
+             .takes_value(true))
+        .arg(Arg::with_name("golden_set")
+             .long("golden_set")
+             .value_name("filename")
+             .help("in daemon mode, labeled examples that the served model is periodically evaluated on, history is returned by the \"stats\" command")
+             .takes_value(true))
+        .arg(Arg::with_name("golden_eval_minutes")
+             .long("golden_eval_minutes")
+             .value_name("minutes (10)")
+             .requires("golden_set")
+             .help("How often to evaluate the served model on the golden set")
+             .takes_value(true))
+        .arg(Arg::with_name("golden_block_margin")
+             .long("golden_block_margin")
+             .value_name("logloss margin")
+             .requires("golden_set")
+             .help("Refuse hogwild_load of models whose golden set logloss is worse than the served model's by more than this")
+             .takes_value(true))
         .arg(Arg::with_name("prediction_model_delay")
              .conflicts_with("test_only")
              .long("prediction_model_delay")
@@ -299,6 +919,29 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("examples")
              .help("After how many examples stop updating weights")
              .takes_value(true))
+        .arg(Arg::with_name("holdout_period")
+             .conflicts_with("testonly")
+             .long("holdout_period")
+             .value_name("N")
+             .help("Hold out every Nth example from training, it is still predicted and its logloss is reported")
+             .takes_value(true))
+        .arg(Arg::with_name("passes")
+             .long("passes")
+             .value_name("N (=1)")
+             .help("Number of passes over the input, passes after the first read the cache with --cache")
+             .takes_value(true))
+        .arg(Arg::with_name("decay_learning_rate")
+             .long("decay_learning_rate")
+             .value_name("factor (=1.0)")
+             .requires("passes")
+             .help("Multiply learning rates by the factor after each pass")
+             .takes_value(true))
+        .arg(Arg::with_name("early_terminate")
+             .long("early_terminate")
+             .value_name("passes")
+             .requires("passes")
+             .help("Stop after this many passes without improvement of the logloss of the examples held out with --holdout_after or --holdout_period")
+             .takes_value(true))
         .arg(Arg::with_name("hogwild_training")
              .long("hogwild_training")
              .required(false)
@@ -309,11 +952,56 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("num_threads")
              .help("Number of threads to use with hogwild training")
              .takes_value(true))
+        .arg(Arg::with_name("hogwild_queue")
+             .long("hogwild_queue")
+             .value_name("examples")
+             .requires("hogwild_training")
+             .help("Capacity of the queue of parsed examples for the hogwild workers (default 100000)")
+             .takes_value(true))
+        .arg(Arg::with_name("hogwild_queue_full")
+             .long("hogwild_queue_full")
+             .value_name("block|shed")
+             .requires("hogwild_training")
+             .help("When the hogwild queue is full, wait for the workers (block, the default) or drop the example without learning from it (shed)")
+             .takes_value(true))
+        .arg(Arg::with_name("hogwild_shutdown_timeout")
+             .long("hogwild_shutdown_timeout")
+             .value_name("seconds")
+             .requires("hogwild_training")
+             .help("Fail if hogwild workers don't finish the queued examples in this time after the input ended (default: wait for them)")
+             .takes_value(true))
+        .arg(Arg::with_name("deterministic")
+             .long("deterministic")
+             .conflicts_with("hogwild_training")
+             .conflicts_with("prediction_model_delay")
+             .help("Translate examples in parallel but learn from them in order on a single thread, giving the same model on every run")
+             .takes_value(false))
+        .arg(Arg::with_name("deterministic_threads")
+             .long("deterministic_threads")
+             .value_name("num_threads")
+             .requires("deterministic")
+             .help("Number of threads translating examples with --deterministic (default 4)")
+             .takes_value(true))
+	.arg(Arg::with_name("compress_model")
+	     .long("compress_model")
+	     .value_name("level")
+	     .help("Write weights of saved regressors through zstd with this compression level (1-22)")
+	     .takes_value(true))
+	.arg(Arg::with_name("quantize_model")
+	     .long("quantize_model")
+	     .value_name("buckets16|fp16|int8")
+	     .help("How FFM and FM weights are quantized in the saved regressor, implies --weight_quantization (default buckets16)")
+	     .takes_value(true))
 	.arg(Arg::with_name("weight_quantization")
 	     .long("weight_quantization")
              .value_name("Whether to consider weight quantization when reading/writing weights.")
              .help("Half-float quantization trigger (inference only is the suggested use).")
              .takes_value(false))
+        .arg(Arg::with_name("labels_out")
+             .long("labels_out")
+             .value_name("filename")
+             .help("Output label, importance and tag of every example, in the same order as predictions")
+             .takes_value(true))
 	.arg(Arg::with_name("predictions_stdout")
 	     .long("predictions_stdout")
              .value_name("Output predictions to stdout")
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+// Options can be given in a toml file with --config fw.toml, instead of on the command line.
+// Keys are the long names of command line options, values are strings or numbers, true for flags
+// and arrays for options that can be given multiple times. Tables can be used to group the options,
+// their names are not significant:
+//
+// data = "train.vw"
+// [features]
+// keep = ["A", "B"]
+// interactions = ["AB"]
+// [optimizer]
+// adaptive = true
+// learning_rate = 0.1
+//
+// Options given on the command line override the ones from the file, except for options that
+// can be given multiple times, where command line values are added to the ones from the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigFile {
+    pub options: BTreeMap<String, Vec<String>>, // flags have no values
+}
+
+impl ConfigFile {
+    pub fn new_from_toml(data: &str) -> Result<ConfigFile, Box<dyn Error>> {
+        let table: toml::value::Table = toml::from_str(data)?;
+        let mut cf = ConfigFile {
+            options: BTreeMap::new(),
+        };
+        cf.add_table(&table)?;
+        Ok(cf)
+    }
+
+    pub fn new_from_filename(filename: &str) -> Result<ConfigFile, Box<dyn Error>> {
+        let data = fs::read_to_string(filename)?;
+        ConfigFile::new_from_toml(&data).map_err(|e| {
+            Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Error in config file {}: {}", filename, e),
+            )) as Box<dyn Error>
+        })
+    }
+
+    fn add_table(&mut self, table: &toml::value::Table) -> Result<(), Box<dyn Error>> {
+        for (key, value) in table.iter() {
+            if let toml::Value::Table(section) = value {
+                self.add_table(section)?;
+                continue;
+            }
+            if key == "config" {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    "Config files can not include other config files",
+                )));
+            }
+            if self.options.contains_key(key) {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("Option \"{}\" is specified more than once", key),
+                )));
+            }
+            let values = match value {
+                toml::Value::Boolean(false) => continue,
+                toml::Value::Boolean(true) => Vec::new(),
+                toml::Value::Array(array) => {
+                    let mut values = Vec::new();
+                    for v in array.iter() {
+                        values.push(value_to_string(key, v)?);
+                    }
+                    values
+                }
+                v => vec![value_to_string(key, v)?],
+            };
+            self.options.insert(key.clone(), values);
+        }
+        Ok(())
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = Vec::new();
+        for (key, values) in self.options.iter() {
+            if values.is_empty() {
+                args.push(format!("--{}", key));
+            }
+            for value in values.iter() {
+                args.push(format!("--{}", key));
+                args.push(value.clone());
+            }
+        }
+        args
+    }
+}
+
+fn value_to_string(key: &str, value: &toml::Value) -> Result<String, Box<dyn Error>> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        _ => Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!(
+                "Option \"{}\" has to be a string, a number, true or an array of those, got: {}",
+                key, value
+            ),
+        ))),
+    }
+}
+
+// Returns the filename given with --config, if any
+pub fn find_config_filename(args: &[String]) -> Option<String> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            return iter.next().cloned();
+        }
+        if let Some(filename) = arg.strip_prefix("--config=") {
+            return Some(filename.to_string());
+        }
+    }
+    None
+}
+
+// Puts the options from the config file in front of the command line ones, so they can be
+// overriden by them
+pub fn expand_config_args(args: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let filename = match find_config_filename(&args) {
+        Some(filename) => filename,
+        None => return Ok(args),
+    };
+    let cf = ConfigFile::new_from_filename(&filename)?;
+    let mut expanded: Vec<String> = args.iter().take(1).cloned().collect();
+    expanded.extend(cf.to_args());
+    expanded.extend(args.into_iter().skip(1));
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::cmdline;
+
+    fn args(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_config_parsing() {
+        let cf = ConfigFile::new_from_toml(
+            r#"
+data = "train.vw"
+hogwild_training = false
+[features]
+keep = ["A", "B"]
+[optimizer]
+adaptive = true
+learning_rate = 0.1
+bit_precision = 18
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            cf.to_args(),
+            args(&[
+                "--adaptive",
+                "--bit_precision",
+                "18",
+                "--data",
+                "train.vw",
+                "--keep",
+                "A",
+                "--keep",
+                "B",
+                "--learning_rate",
+                "0.1",
+            ])
+        );
+
+        assert!(
+            ConfigFile::new_from_toml("learning_rate = 0.1\n[o]\nlearning_rate = 0.2").is_err()
+        );
+        assert!(ConfigFile::new_from_toml("config = \"other.toml\"").is_err());
+        assert!(ConfigFile::new_from_toml("keep = [true]").is_err());
+        assert!(ConfigFile::new_from_toml("keep = ").is_err());
+    }
+
+    #[test]
+    fn test_config_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_filepath = dir.path().join("fw.toml");
+        let config_filename = config_filepath.to_str().unwrap();
+        fs::write(
+            config_filename,
+            "learning_rate = 0.1\npower_t = 0.3\nkeep = [\"A\"]\nadaptive = true\n",
+        )
+        .unwrap();
+
+        let cl = cmdline::parse_from(args(&[
+            "fw",
+            "--config",
+            config_filename,
+            "--learning_rate",
+            "0.5",
+            "--keep",
+            "B",
+        ]))
+        .unwrap();
+        assert_eq!(cl.value_of("config"), Some(config_filename));
+        assert_eq!(cl.value_of("learning_rate"), Some("0.5"));
+        assert_eq!(cl.value_of("power_t"), Some("0.3"));
+        assert!(cl.is_present("adaptive"));
+        assert_eq!(
+            cl.values_of("keep").unwrap().collect::<Vec<&str>>(),
+            vec!["A", "B"]
+        );
+
+        fs::write(config_filename, "no_such_option = 1\n").unwrap();
+        assert!(cmdline::parse_from(args(&["fw", "--config", config_filename])).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use serde::Serialize;
+
+use crate::feature_buffer::FeatureBuffer;
+use crate::model_instance::ModelInstance;
+use crate::parser;
+use crate::vwmap::{NamespaceFormat, NamespaceType, VwNamespaceMap};
+
+// Response of the daemon to "debug <example>": how the example was parsed and translated,
+// so serving discrepancies can be debugged without reproducing the parse offline
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EchoFeature {
+    pub hash: u32,
+    pub value: f32,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EchoNamespace {
+    pub name: String,
+    pub vwname: String,
+    pub format: NamespaceFormat,
+    pub ffm_fields: Vec<usize>,
+    pub features: Vec<EchoFeature>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EchoLrFeature {
+    pub hash: u32,
+    pub value: f32,
+    pub combo_index: u32,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EchoFfmFeature {
+    pub hash: u32,
+    pub value: f32,
+    pub field: u32,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DebugEcho {
+    pub label: Option<u32>,
+    pub importance: f32,
+    pub namespaces: Vec<EchoNamespace>, // only namespaces present in the example
+    pub lr_features: Vec<EchoLrFeature>,
+    pub ffm_features: Vec<EchoFfmFeature>,
+    pub dense_inputs: Vec<f32>,
+    pub prediction: f32,
+}
+
+impl DebugEcho {
+    // record is the parsed example and fb its translation
+    pub fn new(
+        record: &[u32],
+        vw: &VwNamespaceMap,
+        mi: &ModelInstance,
+        fb: &FeatureBuffer,
+        prediction: f32,
+    ) -> DebugEcho {
+        let label = record[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK;
+        let mut namespaces: Vec<EchoNamespace> = Vec::new();
+        for (vwname, name) in vw.map_vwname_to_name.iter() {
+            let namespace_descriptor = vw.map_vwname_to_namespace_descriptor[vwname];
+            if namespace_descriptor.namespace_type != NamespaceType::Primitive {
+                continue;
+            }
+            let token =
+                record[parser::HEADER_LEN as usize + namespace_descriptor.namespace_index as usize];
+            let features = if token == parser::NO_FEATURES {
+                continue;
+            } else if token & parser::IS_NOT_SINGLE_MASK == 0 {
+                vec![EchoFeature {
+                    hash: token,
+                    value: 1.0,
+                }]
+            } else {
+                let start = ((token >> 16) & 0x3fff) as usize;
+                let end = (token & 0xffff) as usize;
+                record[start..end]
+                    .chunks(2)
+                    .map(|pair| EchoFeature {
+                        hash: pair[0],
+                        value: f32::from_bits(pair[1]),
+                    })
+                    .collect()
+            };
+            let ffm_fields = mi
+                .ffm_fields
+                .iter()
+                .enumerate()
+                .filter(|(_, field)| field.contains(&namespace_descriptor))
+                .map(|(field_index, _)| field_index)
+                .collect();
+            namespaces.push(EchoNamespace {
+                name: name.clone(),
+                vwname: String::from_utf8_lossy(vwname).to_string(),
+                format: namespace_descriptor.namespace_format,
+                ffm_fields,
+                features,
+            });
+        }
+        namespaces.sort_by(|a, b| a.vwname.cmp(&b.vwname));
+
+        DebugEcho {
+            label: if label == parser::NO_LABEL {
+                None
+            } else {
+                Some(label)
+            },
+            importance: f32::from_bits(record[parser::EXAMPLE_IMPORTANCE_OFFSET]),
+            namespaces,
+            lr_features: fb
+                .lr_buffer
+                .iter()
+                .map(|f| EchoLrFeature {
+                    hash: f.hash,
+                    value: f.value,
+                    combo_index: f.combo_index,
+                })
+                .collect(),
+            ffm_features: fb
+                .ffm_buffer
+                .iter()
+                .map(|f| EchoFfmFeature {
+                    hash: f.hash,
+                    value: f.value,
+                    field: f.contra_field_index / mi.ffm_k.max(1),
+                })
+                .collect(),
+            dense_inputs: fb.dense_buffer.clone(),
+            prediction,
+        }
+    }
+
+    // Single line of JSON, terminated by a newline
+    pub fn to_json_line(&self) -> String {
+        let mut s = serde_json::to_string(self).unwrap();
+        s.push('\n');
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::model_instance::FeatureComboDesc;
+    use crate::parser::VowpalParser;
+
+    #[test]
+    fn test_debug_echo() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB,f32\nC,featureC\n").unwrap();
+        let a = vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()];
+        let b = vw.map_vwname_to_namespace_descriptor[&b"B".to_vec()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = false;
+        mi.feature_combo_descs.push(FeatureComboDesc {
+            namespace_descriptors: vec![a],
+            weight: 1.0,
+        });
+        mi.ffm_k = 2;
+        mi.ffm_fields = vec![vec![b], vec![a]];
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+
+        let record = pa
+            .next_vowpal_from_bytes(b"-1 2.0 |A a |B 3.5\n")
+            .unwrap()
+            .to_vec();
+        fbt.translate(&record, 0);
+        let echo = DebugEcho::new(&record, &vw, &mi, &fbt.feature_buffer, 0.25);
+        assert_eq!(echo.label, Some(0));
+        assert_eq!(echo.importance, 2.0);
+        assert_eq!(echo.prediction, 0.25);
+        // C is not in the example
+        assert_eq!(echo.namespaces.len(), 2);
+        assert_eq!(echo.namespaces[0].name, "featureA");
+        assert_eq!(echo.namespaces[0].ffm_fields, vec![1]);
+        assert_eq!(echo.namespaces[1].format, NamespaceFormat::F32);
+        assert_eq!(echo.namespaces[1].features[0].value, 3.5);
+        assert_eq!(echo.lr_features.len(), 1);
+        assert_eq!(
+            echo.ffm_features
+                .iter()
+                .map(|f| f.field)
+                .collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        let json = echo.to_json_line();
+        assert!(json.ends_with("\"prediction\":0.25}\n"));
+        assert_eq!(json.matches('\n').count(), 1);
+    }
+}
@@ -0,0 +1,401 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::{CrcReader, CrcWriter};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::model_instance::ModelInstance;
+use crate::persistence;
+use crate::prediction_log;
+use crate::regressor::Regressor;
+use crate::vwmap::VwNamespaceMap;
+
+// Delta saves for continuous training (--delta_base): instead of the full model only the chunks
+// of weights (and optimizer state) that the blocks wrote since the last full save was loaded are
+// written. --apply_delta patches them onto that full save to get the new model.
+// Blocks that don't keep track of what they write are stored whole.
+//
+// File layout: magic, version, then checksummed: namespace map, model instance, model version of
+// the base, number of blocks and for each block its id, serialized length, number of dirty byte
+// ranges and the ranges (offset, length, bytes).
+const DELTA_HEADER_MAGIC_STRING: &[u8; 4] = b"FWDL"; // Fwumious Wabbit DeLta
+const DELTA_HEADER_VERSION: u32 = 2;
+pub const DELTA_CHUNK_WEIGHTS: usize = 1024;
+
+// Chunks of a weight array written since the block started tracking them. Hogwild workers mark
+// them concurrently, hence atomic flags
+pub struct DirtyChunks {
+    dirty: Vec<AtomicBool>,
+}
+
+impl DirtyChunks {
+    pub fn new(num_weights: usize) -> DirtyChunks {
+        DirtyChunks {
+            dirty: (0..num_weights.div_ceil(DELTA_CHUNK_WEIGHTS))
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn mark(&self, index: usize) {
+        if let Some(dirty) = self.dirty.get(index / DELTA_CHUNK_WEIGHTS) {
+            // Hot chunks are marked all the time, reading first keeps their cache line shared
+            if !dirty.load(Ordering::Relaxed) {
+                dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn count_dirty(&self) -> usize {
+        self.dirty
+            .iter()
+            .filter(|dirty| dirty.load(Ordering::Relaxed))
+            .count()
+    }
+
+    // Byte ranges of the dirty chunks in a serialization of num_weights weights of weight_len
+    // bytes that starts at offset, adjacent chunks are merged
+    pub fn byte_ranges(
+        &self,
+        offset: usize,
+        weight_len: usize,
+        num_weights: usize,
+    ) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        if weight_len == 0 {
+            return ranges;
+        }
+        for (chunk, dirty) in self.dirty.iter().enumerate() {
+            if !dirty.load(Ordering::Relaxed) {
+                continue;
+            }
+            let start = offset + chunk * DELTA_CHUNK_WEIGHTS * weight_len;
+            let end = offset + num_weights.min((chunk + 1) * DELTA_CHUNK_WEIGHTS) * weight_len;
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+        }
+        ranges
+    }
+}
+
+// Dirty chunks are relative to the weights training started from, so that has to be the base
+pub fn check_delta_base(
+    base_filename: &str,
+    initial_filename: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    match initial_filename {
+        Some(filename)
+            if prediction_log::model_version(filename)?
+                == prediction_log::model_version(base_filename)? =>
+        {
+            Ok(())
+        }
+        Some(filename) => Err(format!(
+            "Training starts from {}, but deltas can only be saved against the model training starts from, not --delta_base {}",
+            filename, base_filename
+        ))?,
+        None => Err(format!(
+            "Deltas are saved against the model training starts from, give --delta_base {} as --initial_regressor",
+            base_filename
+        ))?,
+    }
+}
+
+// Serialized weights of a full model, as a regressor loaded for training writes them
+fn base_weights(base_filename: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (_, _, re) = persistence::new_regressor_from_filename(base_filename, false, None)?;
+    let mut weights: Vec<u8> = Vec::new();
+    re.write_weights_to_buf(&mut weights, false)?;
+    Ok(weights)
+}
+
+// Block id and byte range of a block in serialized weights, after its id and length
+type BlockFrame = (u32, Range<usize>);
+
+fn block_frames(weights: &[u8]) -> Result<Vec<BlockFrame>, Box<dyn Error>> {
+    let mut reader = io::Cursor::new(weights);
+    let num_blocks = reader.read_u32::<LittleEndian>()?;
+    let mut frames = Vec::with_capacity(num_blocks as usize);
+    for _ in 0..num_blocks {
+        let block_id = reader.read_u32::<LittleEndian>()?;
+        let len = reader.read_u64::<LittleEndian>()? as usize;
+        let start = reader.position() as usize;
+        if start + len > weights.len() {
+            return Err("Serialized weights are truncated")?;
+        }
+        frames.push((block_id, start..start + len));
+        reader.set_position((start + len) as u64);
+    }
+    Ok(frames)
+}
+
+// The regressor has to track dirty weights since it was loaded from base_filename, see
+// check_delta_base()
+pub fn save_delta_to_filename(
+    filename: &str,
+    base_filename: &str,
+    mi: &ModelInstance,
+    vw: &VwNamespaceMap,
+    re: &Regressor,
+) -> Result<(), Box<dyn Error>> {
+    if re.blend.is_some() {
+        return Err("Blended models can't be saved as deltas, save a full model instead")?;
+    }
+    let output_bufwriter = &mut io::BufWriter::new(fs::File::create(filename)?);
+    output_bufwriter.write_all(DELTA_HEADER_MAGIC_STRING)?;
+    output_bufwriter.write_u32::<LittleEndian>(DELTA_HEADER_VERSION)?;
+    let mut crc_writer = CrcWriter::new(&mut *output_bufwriter);
+    vw.save_to_buf(&mut crc_writer)?;
+    mi.save_to_buf(&mut crc_writer)?;
+    crc_writer.write_u64::<LittleEndian>(prediction_log::model_version(base_filename)?)?;
+    let block_indexes = re.serialized_block_indexes();
+    crc_writer.write_u32::<LittleEndian>(block_indexes.len() as u32)?;
+    let (mut changed, mut total) = (0, 0);
+    let mut block_buf: Vec<u8> = Vec::new();
+    for i in block_indexes {
+        let block = &re.blocks_boxes[i];
+        block_buf.truncate(0);
+        block.write_weights_to_buf(&mut block_buf, false)?;
+        let whole_block = 0..block_buf.len();
+        let ranges = block
+            .dirty_weight_ranges()
+            .unwrap_or_else(|| vec![whole_block]);
+        crc_writer.write_u32::<LittleEndian>(block.get_serialized_block_id())?;
+        crc_writer.write_u64::<LittleEndian>(block_buf.len() as u64)?;
+        crc_writer.write_u32::<LittleEndian>(ranges.len() as u32)?;
+        for range in ranges {
+            crc_writer.write_u64::<LittleEndian>(range.start as u64)?;
+            crc_writer.write_u64::<LittleEndian>(range.len() as u64)?;
+            changed += range.len();
+            crc_writer.write_all(&block_buf[range])?;
+        }
+        total += block_buf.len();
+    }
+    let checksum = crc_writer.crc().sum();
+    output_bufwriter.write_u32::<LittleEndian>(checksum)?;
+    output_bufwriter.flush()?;
+    log::info!(
+        "Saved delta {} against {}: {} of {} bytes of weights changed",
+        filename,
+        base_filename,
+        changed,
+        total
+    );
+    Ok(())
+}
+
+pub fn apply_delta(
+    base_filename: &str,
+    delta_filename: &str,
+    out_filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    let input_bufreader = &mut io::BufReader::new(fs::File::open(delta_filename)?);
+    let mut magic_string: [u8; 4] = [0; 4];
+    input_bufreader.read_exact(&mut magic_string)?;
+    if &magic_string != DELTA_HEADER_MAGIC_STRING {
+        return Err(format!("File {} is not a model delta", delta_filename))?;
+    }
+    let version = input_bufreader.read_u32::<LittleEndian>()?;
+    if version != DELTA_HEADER_VERSION {
+        return Err(format!(
+            "Model delta {} has version {}, only version {} is supported",
+            delta_filename, version, DELTA_HEADER_VERSION
+        ))?;
+    }
+    let mut crc_reader = CrcReader::new(&mut *input_bufreader);
+    let vw = VwNamespaceMap::new_from_buf(&mut crc_reader)?;
+    let mi = ModelInstance::new_from_buf(&mut crc_reader)?;
+    let base_version = crc_reader.read_u64::<LittleEndian>()?;
+    if base_version != prediction_log::model_version(base_filename)? {
+        return Err(format!(
+            "Model delta {} was not saved against {}",
+            delta_filename, base_filename
+        ))?;
+    }
+    let mut weights = base_weights(base_filename)?;
+    let frames = block_frames(&weights)?;
+    let num_blocks = crc_reader.read_u32::<LittleEndian>()? as usize;
+    if num_blocks != frames.len() {
+        return Err(format!(
+            "Model delta {} has {} blocks, base {} has {}",
+            delta_filename,
+            num_blocks,
+            base_filename,
+            frames.len()
+        ))?;
+    }
+    let (mut changed, mut total) = (0, 0);
+    for (block_id, frame) in frames {
+        let delta_block_id = crc_reader.read_u32::<LittleEndian>()?;
+        let len = crc_reader.read_u64::<LittleEndian>()? as usize;
+        if delta_block_id != block_id || len != frame.len() {
+            return Err(format!(
+                "Model delta {} has a different structure than base {}",
+                delta_filename, base_filename
+            ))?;
+        }
+        let num_ranges = crc_reader.read_u32::<LittleEndian>()?;
+        for _ in 0..num_ranges {
+            let offset = crc_reader.read_u64::<LittleEndian>()? as usize;
+            let range_len = crc_reader.read_u64::<LittleEndian>()? as usize;
+            if offset + range_len > len {
+                return Err(format!(
+                    "Model delta {} has a range past the end of block {}",
+                    delta_filename, block_id
+                ))?;
+            }
+            let start = frame.start + offset;
+            crc_reader.read_exact(&mut weights[start..start + range_len])?;
+            changed += range_len;
+        }
+        total += len;
+    }
+    let computed = crc_reader.crc().sum();
+    let stored = input_bufreader.read_u32::<LittleEndian>().map_err(|_| {
+        persistence::ModelFileError::Truncated {
+            filename: delta_filename.to_string(),
+        }
+    })?;
+    if stored != computed {
+        return Err(persistence::ModelFileError::ChecksumMismatch {
+            filename: delta_filename.to_string(),
+            stored,
+            computed,
+        })?;
+    }
+    persistence::save_weights_buf_to_filename(out_filename, &mi, &vw, &weights)?;
+    log::info!(
+        "Applied delta {} ({} of {} bytes of weights) onto {} into {}",
+        delta_filename,
+        changed,
+        total,
+        base_filename,
+        out_filename
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer;
+    use crate::feature_buffer::HashAndValue;
+    use crate::model_instance::Optimizer;
+    use crate::regressor;
+    use tempfile::tempdir;
+
+    fn lr_vec(v: Vec<HashAndValue>) -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 1.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: v,
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_dirty_chunks() {
+        let num_weights = 3 * DELTA_CHUNK_WEIGHTS + 10;
+        let dirty = DirtyChunks::new(num_weights);
+        assert_eq!(dirty.count_dirty(), 0);
+        assert!(dirty.byte_ranges(0, 4, num_weights).is_empty());
+        dirty.mark(5);
+        dirty.mark(num_weights - 1);
+        dirty.mark(num_weights); // out of range, ignored
+        assert_eq!(dirty.count_dirty(), 2);
+        assert_eq!(
+            dirty.byte_ranges(100, 4, num_weights),
+            vec![
+                100..100 + DELTA_CHUNK_WEIGHTS * 4,
+                100 + 3 * DELTA_CHUNK_WEIGHTS * 4..100 + num_weights * 4
+            ]
+        );
+        // Adjacent chunks make one range
+        dirty.mark(DELTA_CHUNK_WEIGHTS);
+        assert_eq!(dirty.count_dirty(), 3);
+        assert_eq!(
+            dirty.byte_ranges(0, 2, num_weights),
+            vec![
+                0..2 * DELTA_CHUNK_WEIGHTS * 2,
+                3 * DELTA_CHUNK_WEIGHTS * 2..num_weights * 2
+            ]
+        );
+        assert!(dirty.byte_ranges(0, 0, num_weights).is_empty());
+    }
+
+    #[test]
+    fn test_save_and_apply_delta() {
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = Optimizer::AdagradFlex;
+        mi.init_acc_gradient = 0.0;
+        let fbuf = &lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+
+        let dir = tempdir().unwrap();
+        let base_filename = dir.path().join("base.fw");
+        let delta_filename = dir.path().join("model.fwd");
+        let out_filename = dir.path().join("model.fw");
+        let base_filename = base_filename.to_str().unwrap();
+        let delta_filename = delta_filename.to_str().unwrap();
+        let out_filename = out_filename.to_str().unwrap();
+
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        re.learn(fbuf, &mut pb, true);
+        let mut base: Vec<u8> = Vec::new();
+        re.write_weights_to_buf(&mut base, false).unwrap();
+        persistence::save_regressor_to_filename(base_filename, &mi, &vw, re, false).unwrap();
+
+        let (_, _, mut re) =
+            persistence::new_regressor_from_filename(base_filename, false, None).unwrap();
+        re.track_dirty_weights();
+        for _ in 0..5 {
+            re.learn(fbuf, &mut pb, true);
+        }
+        let p = re.learn(fbuf, &mut pb, false);
+        save_delta_to_filename(delta_filename, base_filename, &mi, &vw, &re).unwrap();
+        // Only the chunk of the single trained weight is stored
+        let delta_len = fs::metadata(delta_filename).unwrap().len() as usize;
+        assert!(delta_len < base.len() / 10);
+
+        apply_delta(base_filename, delta_filename, out_filename).unwrap();
+        let (_, _, re_applied) =
+            persistence::new_regressor_from_filename(out_filename, true, None).unwrap();
+        assert_eq!(re_applied.predict(fbuf, &mut pb), p);
+
+        // Deltas only apply onto the model they were saved against
+        assert!(apply_delta(out_filename, delta_filename, out_filename).is_err());
+        assert!(check_delta_base(base_filename, Some(base_filename)).is_ok());
+        assert!(check_delta_base(base_filename, Some(out_filename)).is_err());
+        assert!(check_delta_base(base_filename, None).is_err());
+
+        // Damaged deltas are refused
+        let mut bytes = fs::read(delta_filename).unwrap();
+        let last = bytes.len() - 10;
+        bytes[last] ^= 0xff;
+        fs::write(delta_filename, &bytes).unwrap();
+        let result = apply_delta(base_filename, delta_filename, out_filename);
+        assert!(result.unwrap_err().to_string().starts_with(&format!(
+            "Weights of regressor file {} are corrupted",
+            delta_filename
+        )));
+    }
+}
@@ -0,0 +1,296 @@
+use std::error::Error;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::feature_buffer::{FeatureBuffer, FeatureBufferTranslator};
+use crate::model_instance::ModelInstance;
+use crate::multithread_helpers::BoxedRegressorTrait;
+
+// Parallel training that gives the same model on every run (--deterministic), unlike hogwild
+// training, where workers race each other to update weights. Examples are handed round-robin to
+// several threads that translate (hash) them, and a single trainer thread takes the feature
+// buffers back in the same round-robin order. It learns from them in input order, so the model
+// is bit for bit the one training on the main thread would give. Only translation is parallel,
+// updates are as fast as one thread applies them.
+// Examples that are only predicted (holdout, --testonly) go the same way, the main thread waits
+// for their prediction, so it comes from the weights that learned from all examples before them.
+// The same wait drains the trainer before a snapshot is taken.
+
+pub const DEFAULT_TRANSLATORS: usize = 4;
+const TRANSLATOR_QUEUE_CAPACITY: usize = 1000; // examples, both before and after translation
+
+// What the trainer thread does, in the order the main thread asked for it
+enum Job {
+    Learn(Vec<u32>, u64),
+    Predict(Vec<u32>, u64),
+    Drain,
+}
+
+enum Translated {
+    Learn(FeatureBuffer),
+    Predict(FeatureBuffer),
+    Drain,
+}
+
+// The prediction with those of --heads
+type Prediction = (f32, Vec<f32>);
+
+pub struct DeterministicTrainer {
+    senders: Vec<SyncSender<Job>>,
+    next: usize, // translator of the next job
+    translators: Vec<JoinHandle<()>>,
+    trainer: JoinHandle<u64>,
+    answers: Receiver<Option<Prediction>>, // None once drained
+}
+
+impl DeterministicTrainer {
+    pub fn new(
+        regressor: BoxedRegressorTrait,
+        model_instance: &ModelInstance,
+        num_translators: usize,
+    ) -> Result<DeterministicTrainer, Box<dyn Error>> {
+        if num_translators == 0 {
+            return Err("--deterministic needs at least one translating thread")?;
+        }
+        let feature_buffer_translator = FeatureBufferTranslator::new(model_instance);
+        let mut senders = Vec::with_capacity(num_translators);
+        let mut translators = Vec::with_capacity(num_translators);
+        let mut translated = Vec::with_capacity(num_translators);
+        for _ in 0..num_translators {
+            let (sender, receiver) = mpsc::sync_channel::<Job>(TRANSLATOR_QUEUE_CAPACITY);
+            let (translated_sender, translated_receiver) =
+                mpsc::sync_channel(TRANSLATOR_QUEUE_CAPACITY);
+            let mut fbt = feature_buffer_translator.clone();
+            translators.push(thread::spawn(move || {
+                for job in receiver.iter() {
+                    let translated = match job {
+                        Job::Learn(record, example_num) => {
+                            fbt.translate(&record, example_num);
+                            Translated::Learn(fbt.feature_buffer.clone())
+                        }
+                        Job::Predict(record, example_num) => {
+                            fbt.translate(&record, example_num);
+                            Translated::Predict(fbt.feature_buffer.clone())
+                        }
+                        Job::Drain => Translated::Drain,
+                    };
+                    if translated_sender.send(translated).is_err() {
+                        break; // the trainer is gone
+                    }
+                }
+            }));
+            senders.push(sender);
+            translated.push(translated_receiver);
+        }
+        let (answer_sender, answers) = mpsc::channel();
+        let trainer = thread::spawn(move || train(regressor, translated, answer_sender));
+        Ok(DeterministicTrainer {
+            senders,
+            next: 0,
+            translators,
+            trainer,
+            answers,
+        })
+    }
+
+    fn send(&mut self, job: Job) -> Result<(), Box<dyn Error>> {
+        if self.senders[self.next].send(job).is_err() {
+            return Err("Deterministic training stopped, a translating thread is gone")?;
+        }
+        self.next = (self.next + 1) % self.senders.len();
+        Ok(())
+    }
+
+    fn answer(&mut self) -> Result<Option<Prediction>, Box<dyn Error>> {
+        match self.answers.recv() {
+            Ok(answer) => Ok(answer),
+            Err(_) => Err("Deterministic training stopped, the trainer thread is gone")?,
+        }
+    }
+
+    pub fn digest_example(
+        &mut self,
+        record: Vec<u32>,
+        example_num: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.send(Job::Learn(record, example_num))
+    }
+
+    // Prediction of an example that is not learned from, once all examples before it were
+    pub fn predict_example(
+        &mut self,
+        record: Vec<u32>,
+        example_num: u64,
+    ) -> Result<Prediction, Box<dyn Error>> {
+        self.send(Job::Predict(record, example_num))?;
+        match self.answer()? {
+            Some(prediction) => Ok(prediction),
+            None => Err("Deterministic trainer answered a prediction with a drain")?,
+        }
+    }
+
+    // Waits until the trainer learned from all examples given so far and applied the pending
+    // mini-batch, so the weights can be saved
+    pub fn drain(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send(Job::Drain)?;
+        match self.answer()? {
+            None => Ok(()),
+            Some(_) => Err("Deterministic trainer answered a drain with a prediction")?,
+        }
+    }
+
+    // Waits until the trainer learned from all examples, returns their number
+    pub fn block_until_finished(self) -> Result<u64, Box<dyn Error>> {
+        drop(self.senders);
+        for translator in self.translators {
+            if translator.join().is_err() {
+                return Err("Translating examples for deterministic training panicked")?;
+            }
+        }
+        match self.trainer.join() {
+            Ok(examples) => Ok(examples),
+            Err(_) => Err("Deterministic trainer panicked")?,
+        }
+    }
+}
+
+fn train(
+    mut regressor: BoxedRegressorTrait,
+    translated: Vec<Receiver<Translated>>,
+    answers: Sender<Option<Prediction>>,
+) -> u64 {
+    let mut port_buffer = regressor.new_portbuffer();
+    let mut examples: u64 = 0;
+    // In the order jobs were handed out, the first closed queue is the end of the input
+    'learning: loop {
+        for receiver in translated.iter() {
+            let answer = match receiver.recv() {
+                Ok(Translated::Learn(feature_buffer)) => {
+                    regressor.learn(&feature_buffer, &mut port_buffer, true);
+                    examples += 1;
+                    continue;
+                }
+                Ok(Translated::Predict(feature_buffer)) => {
+                    let prediction = regressor.learn(&feature_buffer, &mut port_buffer, false);
+                    Some((prediction, port_buffer.head_predictions.clone()))
+                }
+                Ok(Translated::Drain) => {
+                    regressor.apply_minibatch(&mut port_buffer);
+                    None
+                }
+                Err(_) => break 'learning,
+            };
+            if answers.send(answer).is_err() {
+                break 'learning; // the main thread is gone
+            }
+        }
+    }
+    regressor.apply_minibatch(&mut port_buffer);
+    examples
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::model_instance::{FeatureComboDesc, Optimizer};
+    use crate::parser::VowpalParser;
+    use crate::regressor::Regressor;
+    use crate::vwmap::VwNamespaceMap;
+
+    #[test]
+    fn test_deterministic_trainer() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let nd = |vwname: &str| vw.map_vwname_to_namespace_descriptor[vwname.as_bytes()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.ffm_power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_init_width = 1.0;
+        mi.optimizer = Optimizer::AdagradFlex;
+        mi.feature_combo_descs.push(FeatureComboDesc {
+            namespace_descriptors: vec![nd("A"), nd("B")],
+            weight: 1.0,
+        });
+        mi.ffm_fields = vec![vec![nd("A")], vec![nd("B")]];
+
+        let mut pa = VowpalParser::new(&vw);
+        let records: Vec<Vec<u32>> = (0..200)
+            .map(|i| {
+                let line = format!(
+                    "{} |A a{} |B b{}\n",
+                    if i % 3 == 0 { "1" } else { "-1" },
+                    i % 7,
+                    i % 5
+                );
+                pa.next_vowpal_from_bytes(line.as_bytes()).unwrap().to_vec()
+            })
+            .collect();
+
+        // Learning on this thread, every fifth example is held out and only predicted
+        let holdout = |i: usize| i % 5 == 4;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let mut holdout_predictions = Vec::new();
+        for (i, record) in records.iter().enumerate() {
+            fbt.translate(record, i as u64 + 1);
+            let prediction = re.learn(&fbt.feature_buffer, &mut pb, !holdout(i));
+            if holdout(i) {
+                holdout_predictions.push(prediction.to_bits());
+            }
+            if i == 100 {
+                re.apply_minibatch(&mut pb); // as before a snapshot
+            }
+        }
+
+        // Twice with the same number of translating threads, and with another
+        for num_translators in [1, 3, 3].iter() {
+            let re_deterministic = BoxedRegressorTrait::new(Box::new(Regressor::new(&mi)));
+            let mut trainer =
+                DeterministicTrainer::new(re_deterministic.clone(), &mi, *num_translators).unwrap();
+            let mut deterministic_predictions = Vec::new();
+            for (i, record) in records.iter().enumerate() {
+                if holdout(i) {
+                    let (prediction, _) = trainer
+                        .predict_example(record.clone(), i as u64 + 1)
+                        .unwrap();
+                    deterministic_predictions.push(prediction.to_bits());
+                } else {
+                    trainer
+                        .digest_example(record.clone(), i as u64 + 1)
+                        .unwrap();
+                }
+                if i == 100 {
+                    trainer.drain().unwrap();
+                }
+            }
+            assert_eq!(deterministic_predictions, holdout_predictions);
+            assert_eq!(trainer.block_until_finished().unwrap(), 160);
+
+            let mut pb_deterministic = re_deterministic.new_portbuffer();
+            for (i, record) in records.iter().enumerate().take(10) {
+                fbt.translate(record, i as u64 + 1);
+                assert_eq!(
+                    re.predict(&fbt.feature_buffer, &mut pb).to_bits(),
+                    re_deterministic
+                        .predict(&fbt.feature_buffer, &mut pb_deterministic)
+                        .to_bits()
+                );
+            }
+        }
+
+        assert!(DeterministicTrainer::new(
+            BoxedRegressorTrait::new(Box::new(Regressor::new(&mi))),
+            &mi,
+            0
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::block_ffm::BlockFFM;
+use crate::buffer_handler::create_buffered_input;
+use crate::model_instance::ModelInstance;
+use crate::optimizer::OptimizerSGD;
+use crate::parser;
+use crate::persistence;
+use crate::regressor;
+use crate::regressor::Regressor;
+use crate::vwmap::{NamespaceFormat, VwNamespaceMap};
+
+// Export of the FFM embeddings of a model (--dump_embeddings), one row per feature hash with its
+// ffm_k weights towards each field. The model alone has no feature names, they come from hashing
+// the features of example data the same way training does.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmbeddingsFormat {
+    // hash, feature name (empty when unknown) and the weights, tab separated
+    Tsv,
+    // numpy archive with hashes.npy (n), embeddings.npy (n x fields x ffm_k) and features.npy (n)
+    Npz,
+}
+
+impl EmbeddingsFormat {
+    pub fn from_filename(filename: &str) -> EmbeddingsFormat {
+        if filename.ends_with(".npz") {
+            EmbeddingsFormat::Npz
+        } else {
+            EmbeddingsFormat::Tsv
+        }
+    }
+}
+
+// Names of the categorical features of the FFM namespaces in the examples, "namespace^feature" by
+// masked FFM hash. Of features whose hashes collide the first one keeps the name.
+pub fn ffm_feature_names(
+    mi: &ModelInstance,
+    vw: &VwNamespaceMap,
+    input: &mut impl BufRead,
+) -> Result<HashMap<u32, String>, Box<dyn Error>> {
+    let ffm_hash_mask = ffm_hash_mask(mi);
+    let mut names: HashMap<u32, String> = HashMap::new();
+    let mut line = String::new();
+    loop {
+        line.truncate(0);
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        let body = &line[line.find('|').unwrap_or(line.len())..];
+        let mut namespace: Option<&str> = None;
+        for token in body.split_ascii_whitespace() {
+            if let Some(namespace_token) = token.strip_prefix('|') {
+                let vwname = namespace_token.split(':').next().unwrap();
+                namespace = match vw.map_vwname_to_namespace_descriptor.get(vwname.as_bytes()) {
+                    Some(descriptor)
+                        if descriptor.namespace_format == NamespaceFormat::Categorical
+                            && mi.ffm_fields.iter().flatten().any(|d| d == descriptor) =>
+                    {
+                        Some(vwname)
+                    }
+                    _ => None,
+                };
+                continue;
+            }
+            if let Some(vwname) = namespace {
+                let feature = token.split(':').next().unwrap();
+                let hash = parser::feature_hash(vwname, feature.as_bytes()) & ffm_hash_mask;
+                names
+                    .entry(hash)
+                    .or_insert_with(|| format!("{}^{}", vwname, feature));
+            }
+        }
+    }
+    Ok(names)
+}
+
+// Like the feature buffer translator, the lower bits of hashes are spared for the k dimensions
+fn ffm_hash_mask(mi: &ModelInstance) -> u32 {
+    let dimensions_mask = mi.ffm_k.max(mi.fm_k).next_power_of_two() - 1;
+    ((1 << mi.ffm_bit_precision) - 1) ^ dimensions_mask
+}
+
+fn ffm_block(re: &mut Regressor) -> Result<&BlockFFM<OptimizerSGD>, Box<dyn Error>> {
+    for block in re.blocks_boxes.iter_mut() {
+        if block.get_serialized_block_id() == regressor::SERIALIZED_BLOCK_ID_FFM {
+            return match block.as_any().downcast_mut::<BlockFFM<OptimizerSGD>>() {
+                Some(block) => Ok(block),
+                None => Err("Embeddings can only be dumped from a model loaded for inference")?,
+            };
+        }
+    }
+    Err("Model has no FFM embeddings to dump")?
+}
+
+// Embeddings of the features in names, or of every hash of the table without names
+pub fn dump_embeddings(
+    mi: &ModelInstance,
+    re: &mut Regressor,
+    names: Option<&HashMap<u32, String>>,
+    format: EmbeddingsFormat,
+    output: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    if mi.ffm_k == 0 || mi.ffm_fields.is_empty() {
+        return Err("Model has no FFM embeddings to dump")?;
+    }
+    let ffm = ffm_block(re)?;
+    let hashes: Vec<u32> = match names {
+        Some(names) => {
+            let mut hashes: Vec<u32> = names.keys().copied().collect();
+            hashes.sort_unstable();
+            hashes
+        }
+        None => ffm.feature_hashes().collect(),
+    };
+    let name_of = |hash: &u32| -> &str {
+        names
+            .and_then(|names| names.get(hash))
+            .map(|name| name.as_str())
+            .unwrap_or("")
+    };
+
+    match format {
+        EmbeddingsFormat::Tsv => {
+            for hash in hashes.iter() {
+                write!(output, "{}\t{}", hash, name_of(hash))?;
+                for weight in ffm.feature_embedding(*hash) {
+                    write!(output, "\t{}", weight)?;
+                }
+                writeln!(output)?;
+            }
+        }
+        EmbeddingsFormat::Npz => {
+            let mut archive = NpzWriter::new();
+            let mut data: Vec<u8> = Vec::new();
+            for hash in hashes.iter() {
+                data.write_u32::<LittleEndian>(*hash)?;
+            }
+            archive.add_array("hashes", "<u4", &[hashes.len()], &data)?;
+            data.truncate(0);
+            for hash in hashes.iter() {
+                for weight in ffm.feature_embedding(*hash) {
+                    data.write_f32::<LittleEndian>(*weight)?;
+                }
+            }
+            let shape = [hashes.len(), mi.ffm_fields.len(), mi.ffm_k as usize];
+            archive.add_array("embeddings", "<f4", &shape, &data)?;
+            if names.is_some() {
+                // Fixed width UTF-32 strings, as numpy stores unicode arrays
+                let width = hashes
+                    .iter()
+                    .map(|hash| name_of(hash).chars().count())
+                    .max()
+                    .unwrap_or(0)
+                    .max(1);
+                data.truncate(0);
+                for hash in hashes.iter() {
+                    let name = name_of(hash);
+                    for c in name.chars() {
+                        data.write_u32::<LittleEndian>(c as u32)?;
+                    }
+                    for _ in name.chars().count()..width {
+                        data.write_u32::<LittleEndian>(0)?;
+                    }
+                }
+                archive.add_array("features", &format!("<U{}", width), &[hashes.len()], &data)?;
+            }
+            archive.finish(output)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn dump_embeddings_from_model(
+    model_filename: &str,
+    output_filename: &str,
+    data_filename: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let (mi, vw, mut re) = persistence::new_regressor_from_filename(model_filename, true, None)?;
+    let names = match data_filename {
+        Some(data_filename) => Some(ffm_feature_names(
+            &mi,
+            &vw,
+            &mut create_buffered_input(data_filename),
+        )?),
+        None => None,
+    };
+    let mut output = BufWriter::new(File::create(output_filename)?);
+    dump_embeddings(
+        &mi,
+        &mut re,
+        names.as_ref(),
+        EmbeddingsFormat::from_filename(output_filename),
+        &mut output,
+    )?;
+    output.flush()?;
+    log::info!(
+        "Dumped FFM embeddings of {} to {}",
+        model_filename,
+        output_filename
+    );
+    Ok(())
+}
+
+// Header of a version 1.0 .npy file, padded so the data starts at a multiple of 64 bytes
+fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape_str = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    };
+    let mut dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape_str
+    );
+    let unpadded_len = 10 + dict.len() + 1;
+    dict.push_str(&" ".repeat((64 - unpadded_len % 64) % 64));
+    dict.push('\n');
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header
+}
+
+// Uncompressed zip archive of .npy files, which is what numpy.savez writes
+struct NpzWriter {
+    archive: Vec<u8>,
+    central_directory: Vec<u8>,
+    entries: u16,
+}
+
+impl NpzWriter {
+    fn new() -> NpzWriter {
+        NpzWriter {
+            archive: Vec::new(),
+            central_directory: Vec::new(),
+            entries: 0,
+        }
+    }
+
+    fn add_array(
+        &mut self,
+        name: &str,
+        descr: &str,
+        shape: &[usize],
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut file = npy_header(descr, shape);
+        file.extend_from_slice(data);
+        let filename = format!("{}.npy", name);
+        let offset = self.archive.len();
+        if file.len() > u32::MAX as usize || offset > u32::MAX as usize {
+            return Err("Embeddings are too large for a zip archive without zip64")?;
+        }
+        let mut crc = flate2::Crc::new();
+        crc.update(&file);
+
+        // Local file header, then the central directory entry that points to it
+        let a = &mut self.archive;
+        a.write_u32::<LittleEndian>(0x04034b50)?;
+        a.write_u16::<LittleEndian>(20)?; // version needed to extract
+        a.write_u16::<LittleEndian>(0)?; // flags
+        a.write_u16::<LittleEndian>(0)?; // stored
+        a.write_u32::<LittleEndian>(0)?; // time and date
+        a.write_u32::<LittleEndian>(crc.sum())?;
+        a.write_u32::<LittleEndian>(file.len() as u32)?;
+        a.write_u32::<LittleEndian>(file.len() as u32)?;
+        a.write_u16::<LittleEndian>(filename.len() as u16)?;
+        a.write_u16::<LittleEndian>(0)?; // extra field
+        a.extend_from_slice(filename.as_bytes());
+        a.extend_from_slice(&file);
+
+        let c = &mut self.central_directory;
+        c.write_u32::<LittleEndian>(0x02014b50)?;
+        c.write_u16::<LittleEndian>(20)?; // version made by
+        c.write_u16::<LittleEndian>(20)?; // version needed to extract
+        c.write_u16::<LittleEndian>(0)?; // flags
+        c.write_u16::<LittleEndian>(0)?; // stored
+        c.write_u32::<LittleEndian>(0)?; // time and date
+        c.write_u32::<LittleEndian>(crc.sum())?;
+        c.write_u32::<LittleEndian>(file.len() as u32)?;
+        c.write_u32::<LittleEndian>(file.len() as u32)?;
+        c.write_u16::<LittleEndian>(filename.len() as u16)?;
+        c.write_u16::<LittleEndian>(0)?; // extra field
+        c.write_u16::<LittleEndian>(0)?; // comment
+        c.write_u16::<LittleEndian>(0)?; // disk number
+        c.write_u16::<LittleEndian>(0)?; // internal attributes
+        c.write_u32::<LittleEndian>(0)?; // external attributes
+        c.write_u32::<LittleEndian>(offset as u32)?;
+        c.extend_from_slice(filename.as_bytes());
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn finish(self, output: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        if self.archive.len() + self.central_directory.len() > u32::MAX as usize {
+            return Err("Embeddings are too large for a zip archive without zip64")?;
+        }
+        output.write_all(&self.archive)?;
+        output.write_all(&self.central_directory)?;
+        // End of central directory
+        output.write_u32::<LittleEndian>(0x06054b50)?;
+        output.write_u16::<LittleEndian>(0)?; // disk number
+        output.write_u16::<LittleEndian>(0)?; // disk with the central directory
+        output.write_u16::<LittleEndian>(self.entries)?;
+        output.write_u16::<LittleEndian>(self.entries)?;
+        output.write_u32::<LittleEndian>(self.central_directory.len() as u32)?;
+        output.write_u32::<LittleEndian>(self.archive.len() as u32)?;
+        output.write_u16::<LittleEndian>(0)?; // comment
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::parser::VowpalParser;
+
+    fn test_model(vw: &VwNamespaceMap) -> ModelInstance {
+        let a = vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()];
+        let b = vw.map_vwname_to_namespace_descriptor[&b"B".to_vec()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.ffm_k = 3;
+        mi.ffm_bit_precision = 8;
+        mi.ffm_fields = vec![vec![a], vec![b]];
+        mi
+    }
+
+    #[test]
+    fn test_ffm_feature_names() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\nC,featureC\n").unwrap();
+        let mi = test_model(&vw);
+        let example = "1 |A a:2 |B b |C c\n";
+        let names = ffm_feature_names(&mi, &vw, &mut example.as_bytes()).unwrap();
+        // Hashes are those of the translated example, C is not in a FFM field
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(pa.next_vowpal_from_bytes(example.as_bytes()).unwrap(), 0);
+        let hashes: Vec<u32> = fbt
+            .feature_buffer
+            .ffm_buffer
+            .iter()
+            .map(|f| f.hash)
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[&hashes[0]], "A^a");
+        assert_eq!(names[&hashes[1]], "B^b");
+    }
+
+    #[test]
+    fn test_dump_embeddings() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mi = test_model(&vw);
+        let mut re = Regressor::new(&mi)
+            .immutable_regressor_without_weights(&mi)
+            .unwrap();
+        re.allocate_and_init_weights(&mi);
+        let ffm = re
+            .blocks_boxes
+            .iter_mut()
+            .find_map(|block| block.as_any().downcast_mut::<BlockFFM<OptimizerSGD>>())
+            .unwrap();
+        ffm.weights[8..14].copy_from_slice(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.5]);
+
+        let names: HashMap<u32, String> = [(8, "A^a".to_string())].iter().cloned().collect();
+        let mut output: Vec<u8> = Vec::new();
+        dump_embeddings(
+            &mi,
+            &mut re,
+            Some(&names),
+            EmbeddingsFormat::Tsv,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "8\tA^a\t1\t2\t3\t4\t5\t6.5\n"
+        );
+
+        // Without names every hash of the table, 4 apart for ffm_k of 3
+        let mut output: Vec<u8> = Vec::new();
+        dump_embeddings(&mi, &mut re, None, EmbeddingsFormat::Tsv, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), 64);
+        assert!(output.lines().nth(2).unwrap().starts_with("8\t\t1\t2\t"));
+
+        let mut output: Vec<u8> = Vec::new();
+        dump_embeddings(
+            &mi,
+            &mut re,
+            Some(&names),
+            EmbeddingsFormat::Npz,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(&output[..4], b"PK\x03\x04");
+        assert_eq!(&output[output.len() - 22..output.len() - 18], b"PK\x05\x06");
+        // Three arrays, the embeddings one with its data right after the padded header
+        assert_eq!(
+            u16::from_le_bytes([output[output.len() - 12], output[output.len() - 11]]),
+            3
+        );
+        let header = npy_header("<f4", &[1, 2, 3]);
+        assert_eq!(header.len() % 64, 0);
+        let start = output
+            .windows(header.len())
+            .position(|window| window == &header[..])
+            .unwrap();
+        assert_eq!(
+            &output[start + header.len()..start + header.len() + 4],
+            &1.0f32.to_le_bytes()
+        );
+    }
+}
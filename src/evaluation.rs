@@ -0,0 +1,262 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::buffer_handler::create_buffered_input;
+use crate::feature_buffer::FeatureBufferTranslator;
+use crate::golden_set::{GoldenMetrics, MetricsAccumulator};
+use crate::multithread_helpers::BoxedRegressorTrait;
+use crate::parser;
+use crate::parser::VowpalParser;
+use crate::regressor::Regressor;
+
+// How many past evaluations the daemon keeps for the "stats" command
+pub const EVALUATION_HISTORY_LEN: usize = 20;
+
+// Prediction-only pass over a file with the given model. Examples without a label are skipped.
+// Stateful transforms of the translator don't count the examples, the clone of the served
+// translator shares their state with the workers.
+pub fn evaluate_file(
+    filename: &str,
+    re: &Regressor,
+    fbt: &mut FeatureBufferTranslator,
+    pa: &mut VowpalParser,
+) -> Result<GoldenMetrics, Box<dyn Error>> {
+    // create_buffered_input panics on inputs it can't read, they have to be errors here
+    File::open(filename)?;
+    match Path::new(filename).extension().and_then(|ext| ext.to_str()) {
+        Some("vw") | Some("gz") | Some("zst") => {}
+        _ => {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("{} is not a .vw, .gz or .zst file", filename),
+            )))
+        }
+    }
+    let mut input = create_buffered_input(filename);
+    let mut pb = re.new_portbuffer();
+    let mut accumulator = MetricsAccumulator::default();
+    let mut example_num: u64 = 0;
+    loop {
+        let record = pa.next_vowpal(&mut input)?;
+        if record.is_empty() {
+            break;
+        }
+        let label = record[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK;
+        if label == parser::NO_LABEL {
+            continue;
+        }
+        parser::check_label(record, fbt.model_instance.oaa)?;
+        fbt.translate_without_observing(record, example_num);
+        accumulator.add(re.predict(&fbt.feature_buffer, &mut pb), label == 1);
+        example_num += 1;
+    }
+    if example_num == 0 {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!("No labeled examples in {}", filename),
+        )));
+    }
+    Ok(accumulator.metrics())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvaluationStatus {
+    Running,
+    Done(GoldenMetrics),
+    Failed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    pub id: u64,
+    pub filename: String,
+    pub started: u64, // seconds since unix epoch
+    pub status: EvaluationStatus,
+}
+
+// Evaluations asked for with the daemon command "evaluate <filename>". They run one at a time
+// in a background thread with the weights served at the time, results are returned by "stats".
+#[derive(Default)]
+pub struct Evaluations {
+    next_id: Mutex<u64>,
+    history: Mutex<VecDeque<Evaluation>>,
+}
+
+impl Evaluations {
+    pub fn new() -> Evaluations {
+        Evaluations::default()
+    }
+
+    // Returns the id of the started evaluation and its thread
+    pub fn start(
+        self: &Arc<Self>,
+        filename: &str,
+        re: BoxedRegressorTrait,
+        mut fbt: FeatureBufferTranslator,
+        mut pa: VowpalParser,
+    ) -> Result<(u64, thread::JoinHandle<()>), Box<dyn Error>> {
+        let id = {
+            let mut history = self.history.lock().unwrap();
+            if history
+                .iter()
+                .any(|evaluation| evaluation.status == EvaluationStatus::Running)
+            {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    "Another evaluation is running",
+                )));
+            }
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            if history.len() == EVALUATION_HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(Evaluation {
+                id: *next_id,
+                filename: filename.to_string(),
+                started: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                status: EvaluationStatus::Running,
+            });
+            *next_id
+        };
+        log::info!("Evaluation {} of {} started", id, filename);
+
+        let evaluations = Arc::clone(self);
+        let filename = filename.to_string();
+        let handle = thread::spawn(move || {
+            let status = match evaluate_file(&filename, &re, &mut fbt, &mut pa) {
+                Ok(metrics) => {
+                    log::info!("Evaluation {} of {}: {}", id, filename, metrics);
+                    EvaluationStatus::Done(metrics)
+                }
+                Err(e) => {
+                    log::warn!("Evaluation {} of {} failed: {}", id, filename, e);
+                    EvaluationStatus::Failed(e.to_string())
+                }
+            };
+            evaluations.finish(id, status);
+        });
+        Ok((id, handle))
+    }
+
+    fn finish(&self, id: u64, status: EvaluationStatus) {
+        let mut history = self.history.lock().unwrap();
+        if let Some(evaluation) = history.iter_mut().find(|evaluation| evaluation.id == id) {
+            evaluation.status = status;
+        }
+    }
+
+    pub fn history(&self) -> Vec<Evaluation> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn format_history(&self) -> String {
+        let mut s = String::new();
+        for evaluation in self.history.lock().unwrap().iter() {
+            s.push_str(&format!(
+                "evaluation id:{} time:{} file:{} ",
+                evaluation.id, evaluation.started, evaluation.filename
+            ));
+            match &evaluation.status {
+                EvaluationStatus::Running => s.push_str("running\n"),
+                EvaluationStatus::Done(metrics) => s.push_str(&format!("{}\n", metrics)),
+                EvaluationStatus::Failed(e) => s.push_str(&format!("failed: {}\n", e)),
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_transform_parser::NamespaceTransformsParser;
+    use crate::model_instance::{FeatureComboDesc, ModelInstance, Optimizer};
+    use crate::vwmap::VwNamespaceMap;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_evaluations() {
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.optimizer = Optimizer::AdagradFlex;
+        mi.feature_combo_descs.push(FeatureComboDesc {
+            namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()]],
+            weight: 1.0,
+        });
+        let mut transforms = NamespaceTransformsParser::new();
+        transforms
+            .add_transform_namespace(&vw, "counted=RollingCount(featureA)(100,1000)")
+            .unwrap();
+        mi.transform_namespaces = transforms.resolve(&vw).unwrap();
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut pa = VowpalParser::new(&vw);
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        for line in ["1 |A a\n", "-1 |A b\n"].iter() {
+            for _ in 0..10 {
+                fbt.translate(pa.next_vowpal_from_bytes(line.as_bytes()).unwrap(), 0);
+                re.learn(&fbt.feature_buffer, &mut pb, true);
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        let state_filename = dir.path().join("state.json");
+        let state_filename = state_filename.to_str().unwrap();
+        fbt.transform_executors
+            .save_state_to_filename(state_filename)
+            .unwrap();
+        let state = fs::read(state_filename).unwrap();
+        let holdout_filename = dir.path().join("holdout.vw");
+        let holdout_filename = holdout_filename.to_str().unwrap();
+        fs::write(holdout_filename, "1 |A a\n|A a\n-1 |A b\n").unwrap();
+        let metrics = evaluate_file(holdout_filename, &re, &mut fbt, &mut pa).unwrap();
+        let missing_filename = dir.path().join("missing.vw");
+        assert!(evaluate_file(missing_filename.to_str().unwrap(), &re, &mut fbt, &mut pa).is_err());
+        assert_eq!(metrics.examples, 2);
+        assert_eq!(metrics.auc, 1.0);
+        assert!(metrics.logloss < 0.5);
+
+        let evaluations = Arc::new(Evaluations::new());
+        let re = BoxedRegressorTrait::new(Box::new(re));
+        let (_, handle) = evaluations
+            .start(holdout_filename, re.clone(), fbt.clone(), pa.clone())
+            .unwrap();
+        handle.join().unwrap();
+        // Evaluated examples are not counted by the served translator
+        fbt.transform_executors
+            .save_state_to_filename(state_filename)
+            .unwrap();
+        assert_eq!(fs::read(state_filename).unwrap(), state);
+        let unlabeled_filename = dir.path().join("unlabeled.vw");
+        let unlabeled_filename = unlabeled_filename.to_str().unwrap();
+        fs::write(unlabeled_filename, "|A a\n").unwrap();
+        let (_, handle) = evaluations
+            .start(unlabeled_filename, re.clone(), fbt.clone(), pa.clone())
+            .unwrap();
+        handle.join().unwrap();
+
+        let history = evaluations.history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].id, 1);
+        assert_eq!(history[0].status, EvaluationStatus::Done(metrics));
+        assert!(matches!(history[1].status, EvaluationStatus::Failed(_)));
+        let formatted = evaluations.format_history();
+        assert!(formatted.contains(&format!("file:{} examples:2", holdout_filename)));
+        assert!(formatted.contains("failed: No labeled examples"));
+    }
+}
@@ -0,0 +1,283 @@
+use std::error::Error;
+
+use crate::feature_buffer::FeatureBuffer;
+use crate::model_instance::ModelInstance;
+use crate::port_buffer::PortBuffer;
+use crate::regressor::{OutputDecomposition, Regressor};
+use crate::vwmap::{NamespaceDescriptor, NamespaceType, VwNamespaceMap};
+
+// Explanations of daemon predictions ("explain <example>"): the prediction followed by the top K
+// contributions to its logit of LR feature combos and FFM field pairs, largest in absolute value
+// first. They are the per-combo sums and fields x fields interactions that the forward pass
+// leaves on the tape, so they cost no extra passes. With neural layers on top they are what the
+// layers got as input, not exact shares of the logit.
+//
+// "outputs <example>" answers with the raw outputs of the forward pass instead: the logit before
+// the sigmoid, for calibration layers downstream, the sums of the LR and FFM blocks, and the
+// outputs of the neural layers, as named by the observe blocks behind them.
+
+pub const DEFAULT_EXPLAIN_TOP_K: usize = 5;
+
+pub struct Explainer {
+    combo_names: Vec<String>, // the constant feature last
+    field_names: Vec<String>,
+    top_k: usize,
+}
+
+fn namespace_name(mi: &ModelInstance, vw: &VwNamespaceMap, nd: &NamespaceDescriptor) -> String {
+    match nd.namespace_type {
+        NamespaceType::Primitive => vw
+            .map_vwname_to_namespace_descriptor
+            .iter()
+            .find(|(_, descriptor)| *descriptor == nd)
+            .map(|(vwname, _)| String::from_utf8_lossy(vwname).to_string())
+            .unwrap_or_else(|| nd.namespace_index.to_string()),
+        NamespaceType::Transformed => mi.transform_namespaces.v[nd.namespace_index as usize]
+            .to_namespace
+            .namespace_verbose
+            .clone(),
+    }
+}
+
+fn namespaces_name(mi: &ModelInstance, vw: &VwNamespaceMap, nds: &[NamespaceDescriptor]) -> String {
+    nds.iter()
+        .map(|nd| namespace_name(mi, vw, nd))
+        .collect::<Vec<String>>()
+        .join("+")
+}
+
+impl Explainer {
+    pub fn new(
+        mi: &ModelInstance,
+        vw: &VwNamespaceMap,
+        top_k: usize,
+    ) -> Result<Explainer, Box<dyn Error>> {
+        if top_k == 0 {
+            return Err("Explanations need at least one contribution, --explain_top_k is 0")?;
+        }
+        if !mi.nn_config.layers.is_empty() {
+            log::warn!("Explanations of models with neural layers are only approximate");
+        }
+        let mut combo_names: Vec<String> = mi
+            .feature_combo_descs
+            .iter()
+            .map(|combo| {
+                format!(
+                    "lr:{}",
+                    namespaces_name(mi, vw, &combo.namespace_descriptors)
+                )
+            })
+            .collect();
+        if mi.add_constant_feature {
+            combo_names.push("lr:constant".to_string());
+        }
+        let field_names = mi
+            .ffm_fields
+            .iter()
+            .map(|field| namespaces_name(mi, vw, field))
+            .collect();
+        Ok(Explainer {
+            combo_names,
+            field_names,
+            top_k,
+        })
+    }
+
+    // Every contribution, in no particular order. Interactions of two fields are the sum of
+    // the two halves that the FFM block keeps
+    pub fn contributions(&self, decompositions: &[OutputDecomposition]) -> Vec<(String, f32)> {
+        let mut contributions: Vec<(String, f32)> = Vec::new();
+        for decomposition in decompositions.iter() {
+            match decomposition {
+                OutputDecomposition::LRCombos(combos) => {
+                    for (name, value) in self.combo_names.iter().zip(combos.iter()) {
+                        contributions.push((name.clone(), *value));
+                    }
+                }
+                OutputDecomposition::FFMFields(fields) => {
+                    let num_fields = self.field_names.len();
+                    if fields.len() != num_fields * num_fields {
+                        continue;
+                    }
+                    for f1 in 0..num_fields {
+                        for f2 in f1..num_fields {
+                            let mut value = fields[f1 * num_fields + f2];
+                            if f1 != f2 {
+                                value += fields[f2 * num_fields + f1];
+                            }
+                            contributions.push((
+                                format!("ffm:{}*{}", self.field_names[f1], self.field_names[f2]),
+                                value,
+                            ));
+                        }
+                    }
+                }
+                OutputDecomposition::Logit(_) | OutputDecomposition::Block(..) => {}
+            }
+        }
+        contributions
+    }
+
+    pub fn top_contributions(&self, decompositions: &[OutputDecomposition]) -> Vec<(String, f32)> {
+        let mut contributions = self.contributions(decompositions);
+        contributions.retain(|(_, value)| *value != 0.0);
+        contributions.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()).then(a.0.cmp(&b.0)));
+        contributions.truncate(self.top_k);
+        contributions
+    }
+
+    // Returns the prediction and its top contributions
+    pub fn explain(
+        &self,
+        re: &Regressor,
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+    ) -> (f32, Vec<(String, f32)>) {
+        let (prediction, decompositions) = re.predict_decomposed(fb, pb);
+        let contributions = self.top_contributions(&decompositions);
+        (prediction, contributions)
+    }
+
+    // Daemon response, the prediction followed by <name>:<contribution to the logit>
+    pub fn format_explanation(&self, prediction: f32, contributions: &[(String, f32)]) -> String {
+        let mut s = format!("{:.6}", prediction);
+        for (name, value) in contributions.iter() {
+            s.push_str(&format!(" {}:{:.6}", name, value));
+        }
+        s.push('\n');
+        s
+    }
+}
+
+// Daemon response to "outputs", the prediction followed by margin:<logit>, lr:<sum>, ffm:<sum>
+// and <block name>:<comma separated outputs>
+pub fn format_outputs(prediction: f32, decompositions: &[OutputDecomposition]) -> String {
+    let mut s = format!("{:.6}", prediction);
+    for decomposition in decompositions.iter() {
+        if let OutputDecomposition::Logit(logit) = decomposition {
+            s.push_str(&format!(" margin:{:.6}", logit));
+        }
+    }
+    for decomposition in decompositions.iter() {
+        match decomposition {
+            OutputDecomposition::LRCombos(combos) => {
+                s.push_str(&format!(" lr:{:.6}", combos.iter().sum::<f32>()))
+            }
+            OutputDecomposition::FFMFields(fields) => {
+                s.push_str(&format!(" ffm:{:.6}", fields.iter().sum::<f32>()))
+            }
+            OutputDecomposition::Block(name, outputs) => {
+                let outputs: Vec<String> = outputs.iter().map(|o| format!("{:.6}", o)).collect();
+                s.push_str(&format!(" {}:{}", name, outputs.join(",")));
+            }
+            OutputDecomposition::Logit(_) => {}
+        }
+    }
+    s.push('\n');
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::model_instance::{FeatureComboDesc, Optimizer};
+    use crate::parser::VowpalParser;
+
+    #[test]
+    fn test_explain() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\nC,featureC\n").unwrap();
+        let nd = |vwname: &str| vw.map_vwname_to_namespace_descriptor[vwname.as_bytes()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_init_width = 1.0;
+        mi.optimizer = Optimizer::AdagradFlex;
+        for combo in [vec![nd("A")], vec![nd("A"), nd("B")]].iter() {
+            mi.feature_combo_descs.push(FeatureComboDesc {
+                namespace_descriptors: combo.clone(),
+                weight: 1.0,
+            });
+        }
+        mi.ffm_fields = vec![vec![nd("A")], vec![nd("B"), nd("C")]];
+
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        for i in 0..50 {
+            let line = format!(
+                "{} |A a{} |B b{} |C c{}\n",
+                if i % 2 == 0 { "1" } else { "-1" },
+                i % 3,
+                i % 5,
+                i % 2
+            );
+            fbt.translate(pa.next_vowpal_from_bytes(line.as_bytes()).unwrap(), 0);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+
+        let explainer = Explainer::new(&mi, &vw, 3).unwrap();
+        assert!(Explainer::new(&mi, &vw, 0).is_err());
+        fbt.translate(
+            pa.next_vowpal_from_bytes(b"1 |A a1 |B b2 |C c1\n").unwrap(),
+            0,
+        );
+        let p = re.predict(&fbt.feature_buffer, &mut pb);
+        let (p_explained, top) = explainer.explain(&re, &fbt.feature_buffer, &mut pb);
+        assert_eq!(p, p_explained);
+        assert_eq!(top.len(), 3);
+        assert!(top[0].1.abs() >= top[1].1.abs() && top[1].1.abs() >= top[2].1.abs());
+
+        // All contributions add up to the logit
+        let (_, decompositions) = re.predict_decomposed(&fbt.feature_buffer, &mut pb);
+        let names: Vec<String> = explainer
+            .contributions(&decompositions)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "lr:A",
+                "lr:A+B",
+                "lr:constant",
+                "ffm:A*A",
+                "ffm:A*B+C",
+                "ffm:B+C*B+C"
+            ]
+        );
+        let sum: f32 = explainer
+            .contributions(&decompositions)
+            .iter()
+            .map(|(_, value)| value)
+            .sum();
+        let logit = -(1.0 / p - 1.0).ln();
+        assert!((sum - logit).abs() < 1e-4);
+
+        assert_eq!(
+            explainer.format_explanation(0.5, &[("lr:A".to_string(), -0.25)]),
+            "0.500000 lr:A:-0.250000\n"
+        );
+    }
+
+    #[test]
+    fn test_format_outputs() {
+        let decompositions = vec![
+            OutputDecomposition::LRCombos(&[0.25, 0.5]),
+            OutputDecomposition::FFMFields(&[0.125, -0.25, -0.25, 0.0]),
+            OutputDecomposition::Block("nn:0".to_string(), &[1.0, 0.0]),
+            OutputDecomposition::Logit(0.375),
+        ];
+        assert_eq!(
+            format_outputs(0.6, &decompositions),
+            "0.600000 margin:0.375000 lr:0.750000 ffm:-0.375000 nn:0:1.000000,0.000000\n"
+        );
+    }
+}
@@ -1,11 +1,12 @@
 use crate::feature_transform_executor;
 use crate::model_instance;
 use crate::parser;
-use crate::vwmap::{NamespaceFormat, NamespaceType};
+use crate::vwmap::{NamespaceDescriptor, NamespaceFormat, NamespaceType};
+use std::cmp::Ordering;
 
-const VOWPAL_FNV_PRIME: u32 = 16777619; // vowpal magic number
-                                        //const CONSTANT_NAMESPACE:usize = 128;
-const CONSTANT_HASH: u32 = 11650396;
+pub const VOWPAL_FNV_PRIME: u32 = 16777619; // vowpal magic number
+                                            //const CONSTANT_NAMESPACE:usize = 128;
+pub const CONSTANT_HASH: u32 = 11650396;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct HashAndValue {
@@ -28,17 +29,25 @@ pub struct FeatureBuffer {
     pub example_number: u64,
     pub lr_buffer: Vec<HashAndValue>,
     pub ffm_buffer: Vec<HashAndValueAndSeq>,
+    pub dense_buffer: Vec<f32>, // values of --dense_input namespaces, one after another
+    pub embedding_buffer: Vec<HashAndValue>, // features of --embedding_lookup namespaces, combo_index is the lookup index
+    pub head_labels: Vec<f32>, // --heads: labels of the heads after the first, NO_LABEL when unknown
 }
 
 #[derive(Clone)]
 pub struct FeatureBufferTranslator {
-    model_instance: model_instance::ModelInstance,
+    pub model_instance: model_instance::ModelInstance,
     // we don't want to keep allocating buffers
     hashes_vec_in: Vec<HashAndValue>,
     hashes_vec_out: Vec<HashAndValue>,
+    record_rewriter: RecordRewriter,
+    dedup_namespace_descriptors: Vec<NamespaceDescriptor>,
+    tenant_namespace_descriptors: Vec<NamespaceDescriptor>,
+    pub duplicate_features: u64,
     pub feature_buffer: FeatureBuffer,
     pub lr_hash_mask: u32,
     pub ffm_hash_mask: u32,
+    pub ffm_bits_for_dimensions: u32,
     pub transform_executors: feature_transform_executor::TransformExecutors,
 }
 
@@ -134,13 +143,28 @@ macro_rules! feature_reader_float_namespace {
     };
 }
 
+// Mean pooling of a multi-valued FFM field scales the values of its features, so the gradients
+// of each feature are scaled the same way. field_len includes features of the field not in buffer.
+fn pool_ffm_field(
+    buffer: &mut [HashAndValueAndSeq],
+    pooling: Option<&model_instance::EmbeddingPooling>,
+    field_len: usize,
+) {
+    if pooling == Some(&model_instance::EmbeddingPooling::Mean) && field_len > 1 {
+        let scale = 1.0 / field_len as f32;
+        for hash_and_value in buffer.iter_mut() {
+            hash_and_value.value *= scale;
+        }
+    }
+}
+
 impl FeatureBufferTranslator {
     pub fn new(mi: &model_instance::ModelInstance) -> FeatureBufferTranslator {
         // Calculate lr_hash_mask
         let lr_hash_mask = (1 << mi.bit_precision) - 1;
         // Calculate ffm_hash_mask
         let mut ffm_bits_for_dimensions = 0;
-        while mi.ffm_k > (1 << (ffm_bits_for_dimensions)) {
+        while mi.ffm_k.max(mi.fm_k) > (1 << (ffm_bits_for_dimensions)) {
             ffm_bits_for_dimensions += 1;
         }
         let dimensions_mask = (1 << ffm_bits_for_dimensions) - 1;
@@ -153,17 +177,58 @@ impl FeatureBufferTranslator {
             example_number: 0,
             lr_buffer: Vec::new(),
             ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
         };
 
+        // Duplicates are merged in primitive namespaces that the model reads directly,
+        // f32 namespaces carry values instead of weights, so they are left alone
+        let mut dedup_namespace_descriptors: Vec<NamespaceDescriptor> = Vec::new();
+        let used_namespace_descriptors = mi
+            .feature_combo_descs
+            .iter()
+            .flat_map(|combo| combo.namespace_descriptors.iter())
+            .chain(mi.ffm_fields.iter().flatten());
+        for namespace_descriptor in used_namespace_descriptors.clone() {
+            if namespace_descriptor.namespace_type == NamespaceType::Primitive
+                && namespace_descriptor.namespace_format != NamespaceFormat::F32
+                && !dedup_namespace_descriptors.contains(namespace_descriptor)
+            {
+                dedup_namespace_descriptors.push(*namespace_descriptor);
+            }
+        }
+
+        // With --tenant_namespace the tenant is mixed into hashes of the other primitive namespaces,
+        // passthrough and vector namespaces are indices instead of hashes, so they stay shared
+        let mut tenant_namespace_descriptors: Vec<NamespaceDescriptor> = Vec::new();
+        if let Some(tenant_namespace) = mi.tenant_namespace {
+            for namespace_descriptor in used_namespace_descriptors {
+                if namespace_descriptor.namespace_type == NamespaceType::Primitive
+                    && namespace_descriptor.namespace_format != NamespaceFormat::Passthrough
+                    && namespace_descriptor.namespace_format != NamespaceFormat::F32Vec
+                    && namespace_descriptor.namespace_index != tenant_namespace.namespace_index
+                    && !tenant_namespace_descriptors.contains(namespace_descriptor)
+                {
+                    tenant_namespace_descriptors.push(*namespace_descriptor);
+                }
+            }
+        }
+
         // avoid doing any allocations in translate
 
         FeatureBufferTranslator {
             model_instance: mi.clone(), // not the nicest option
             hashes_vec_in: Vec::with_capacity(100),
             hashes_vec_out: Vec::with_capacity(100),
+            record_rewriter: RecordRewriter::default(),
+            dedup_namespace_descriptors,
+            tenant_namespace_descriptors,
+            duplicate_features: 0,
             feature_buffer: fb,
             lr_hash_mask,
             ffm_hash_mask,
+            ffm_bits_for_dimensions,
             transform_executors:
                 feature_transform_executor::TransformExecutors::from_namespace_transforms(
                     &mi.transform_namespaces,
@@ -171,20 +236,81 @@ impl FeatureBufferTranslator {
         }
     }
 
+    // For serving a model whose FFM weight table was resized
+    pub fn set_ffm_bit_precision(&mut self, ffm_bit_precision: u32) {
+        let dimensions_mask = (1 << self.ffm_bits_for_dimensions) - 1;
+        self.model_instance.ffm_bit_precision = ffm_bit_precision;
+        self.ffm_hash_mask = ((1 << ffm_bit_precision) - 1) ^ dimensions_mask;
+    }
+
     pub fn translate(&mut self, record_buffer: &[u32], example_number: u64) {
         self.translate_and_filter(record_buffer, example_number, None);
     }
 
+    // For another look at an example that is (or was) translated for learning elsewhere,
+    // stateful transforms don't count it a second time
+    pub fn translate_without_observing(&mut self, record_buffer: &[u32], example_number: u64) {
+        self.translate_(record_buffer, example_number, None, false);
+    }
+
     pub fn translate_and_filter(
         &mut self,
         record_buffer: &[u32],
         example_number: u64,
         ffm_filtered_namespace_type: Option<NamespaceType>,
     ) {
+        // Stateful transforms count each example once, cache setup translates only a part of it
+        let observe = ffm_filtered_namespace_type.is_none();
+        self.translate_(
+            record_buffer,
+            example_number,
+            ffm_filtered_namespace_type,
+            observe,
+        );
+    }
+
+    fn translate_(
+        &mut self,
+        record_buffer: &[u32],
+        example_number: u64,
+        ffm_filtered_namespace_type: Option<NamespaceType>,
+        observe: bool,
+    ) {
+        self.record_rewriter.copied = false;
+        if let Some(dup_policy) = self.model_instance.dup_policy {
+            self.duplicate_features += self.record_rewriter.merge_duplicates(
+                record_buffer,
+                &self.dedup_namespace_descriptors,
+                dup_policy,
+            );
+        }
+        if !self.model_instance.namespace_topks.is_empty() {
+            self.record_rewriter
+                .truncate_to_topk(record_buffer, &self.model_instance.namespace_topks);
+        }
+        if let Some(tenant_namespace) = self.model_instance.tenant_namespace {
+            self.record_rewriter.mix_tenant(
+                record_buffer,
+                tenant_namespace,
+                &self.tenant_namespace_descriptors,
+            );
+        }
+        let record_buffer: &[u32] = if self.record_rewriter.copied {
+            &self.record_rewriter.record
+        } else {
+            record_buffer
+        };
         {
             let lr_buffer = &mut self.feature_buffer.lr_buffer;
             lr_buffer.truncate(0);
-            self.feature_buffer.label = record_buffer[parser::LABEL_OFFSET] as f32; // copy label
+            self.feature_buffer.label =
+                (record_buffer[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK) as f32; // copy label
+            self.feature_buffer.head_labels.truncate(0);
+            self.feature_buffer.head_labels.extend(
+                parser::get_head_labels(record_buffer)
+                    .iter()
+                    .map(|label| *label as f32),
+            );
             self.feature_buffer.example_importance =
                 f32::from_bits(record_buffer[parser::EXAMPLE_IMPORTANCE_OFFSET]);
             self.feature_buffer.example_number = example_number;
@@ -276,7 +402,7 @@ impl FeatureBufferTranslator {
             }
 
             // FFM loops have not been optimized yet
-            if self.model_instance.ffm_k > 0 {
+            if self.model_instance.ffm_k > 0 || self.model_instance.fm_k > 0 {
                 // currently we only support primitive features as namespaces, (from --lrqfa command)
                 // this is for compatibility with vowpal
                 // but in theory we could support also combo features
@@ -287,7 +413,17 @@ impl FeatureBufferTranslator {
                     for (contra_field_index, ffm_field) in
                         self.model_instance.ffm_fields.iter().enumerate()
                     {
+                        let field_start = ffm_buffer.len();
+                        let mut field_len: usize = 0;
                         for namespace_descriptor in ffm_field {
+                            // passthrough indices are dense, so we move them out of the way of the k dimensions
+                            let ffm_hash_shift = if namespace_descriptor.namespace_format
+                                == NamespaceFormat::Passthrough
+                            {
+                                self.ffm_bits_for_dimensions
+                            } else {
+                                0
+                            };
                             feature_reader!(
                                 record_buffer,
                                 self.transform_executors,
@@ -295,13 +431,15 @@ impl FeatureBufferTranslator {
                                 hash_index,
                                 hash_value,
                                 {
+                                    // Filtered out features still count for the mean of the field
+                                    field_len += 1;
                                     if ffm_filtered_namespace_type
                                         != namespace_descriptor.namespace_type
                                     {
                                         continue;
                                     }
                                     ffm_buffer.push(HashAndValueAndSeq {
-                                        hash: hash_index & self.ffm_hash_mask,
+                                        hash: (hash_index << ffm_hash_shift) & self.ffm_hash_mask,
                                         value: hash_value,
                                         contra_field_index: contra_field_index as u32
                                             * self.model_instance.ffm_k,
@@ -309,12 +447,29 @@ impl FeatureBufferTranslator {
                                 }
                             );
                         }
+                        pool_ffm_field(
+                            &mut ffm_buffer[field_start..],
+                            self.model_instance
+                                .ffm_field_pooling
+                                .get(contra_field_index),
+                            field_len,
+                        );
                     }
                 } else {
                     for (contra_field_index, ffm_field) in
                         self.model_instance.ffm_fields.iter().enumerate()
                     {
+                        let field_start = ffm_buffer.len();
+                        let mut field_len: usize = 0;
                         for namespace_descriptor in ffm_field {
+                            // passthrough indices are dense, so we move them out of the way of the k dimensions
+                            let ffm_hash_shift = if namespace_descriptor.namespace_format
+                                == NamespaceFormat::Passthrough
+                            {
+                                self.ffm_bits_for_dimensions
+                            } else {
+                                0
+                            };
                             feature_reader!(
                                 record_buffer,
                                 self.transform_executors,
@@ -322,8 +477,9 @@ impl FeatureBufferTranslator {
                                 hash_index,
                                 hash_value,
                                 {
+                                    field_len += 1;
                                     ffm_buffer.push(HashAndValueAndSeq {
-                                        hash: hash_index & self.ffm_hash_mask,
+                                        hash: (hash_index << ffm_hash_shift) & self.ffm_hash_mask,
                                         value: hash_value,
                                         contra_field_index: contra_field_index as u32
                                             * self.model_instance.ffm_k,
@@ -331,10 +487,247 @@ impl FeatureBufferTranslator {
                                 }
                             );
                         }
+                        pool_ffm_field(
+                            &mut ffm_buffer[field_start..],
+                            self.model_instance
+                                .ffm_field_pooling
+                                .get(contra_field_index),
+                            field_len,
+                        );
                     }
                 }
             }
         }
+        if !self.model_instance.dense_inputs.is_empty() {
+            let dense_buffer = &mut self.feature_buffer.dense_buffer;
+            dense_buffer.truncate(0);
+            for dense_input in self.model_instance.dense_inputs.iter() {
+                let namespace_index = dense_input.namespace_descriptor.namespace_index as usize;
+                let first_token = record_buffer[namespace_index + parser::HEADER_LEN as usize];
+                if first_token == parser::NO_FEATURES {
+                    // Missing vectors, as well as NONE values, are zeros
+                    dense_buffer.resize(dense_buffer.len() + dense_input.len as usize, 0.0);
+                } else {
+                    let start = ((first_token >> 16) & 0x3fff) as usize;
+                    let end = (first_token & 0xffff) as usize;
+                    dense_buffer.extend((start..end).step_by(2).map(|i| {
+                        let value = f32::from_bits(record_buffer[i + 1]);
+                        if value.is_nan() {
+                            0.0
+                        } else {
+                            value
+                        }
+                    }));
+                }
+            }
+        }
+        if !self.model_instance.embedding_lookups.is_empty() {
+            let embedding_buffer = &mut self.feature_buffer.embedding_buffer;
+            embedding_buffer.truncate(0);
+            for (lookup_index, embedding_lookup) in
+                self.model_instance.embedding_lookups.iter().enumerate()
+            {
+                let combo_index = lookup_index as u32;
+                feature_reader!(
+                    record_buffer,
+                    self.transform_executors,
+                    embedding_lookup.namespace_descriptor,
+                    hash_index,
+                    hash_value,
+                    {
+                        embedding_buffer.push(HashAndValue {
+                            hash: hash_index,
+                            value: hash_value,
+                            combo_index,
+                        });
+                    }
+                );
+            }
+        }
+        if observe {
+            self.transform_executors.observe_record(record_buffer);
+        }
+    }
+}
+
+// Rewrites features of namespaces before translation: merges duplicate features, truncates
+// namespaces to top-k and mixes in the tenant. Rewrites go to a copy of the record, which is made
+// only when some namespace actually changes.
+#[derive(Clone, Default)]
+struct RecordRewriter {
+    record: Vec<u32>,
+    copied: bool,
+    features: Vec<(u32, f32)>,
+    sorted_hashes: Vec<(u32, usize)>,
+    keep: Vec<bool>,
+}
+
+impl RecordRewriter {
+    // Reads features of a namespace that are not written in place, returns the offset of its token
+    fn read_features(&mut self, record_buffer: &[u32], namespace_index: u16) -> Option<usize> {
+        let buffer: &[u32] = if self.copied {
+            &self.record
+        } else {
+            record_buffer
+        };
+        let token_offset = namespace_index as usize + parser::HEADER_LEN as usize;
+        let first_token = buffer[token_offset];
+        if (first_token & parser::IS_NOT_SINGLE_MASK) == 0 {
+            return None;
+        }
+        let start = ((first_token >> 16) & 0x3fff) as usize;
+        let end = (first_token & 0xffff) as usize;
+        self.features.truncate(0);
+        self.features.extend(
+            (start..end)
+                .step_by(2)
+                .map(|i| (buffer[i], f32::from_bits(buffer[i + 1]))),
+        );
+        Some(token_offset)
+    }
+
+    // The record is copied before its first rewrite
+    fn copy_record(&mut self, record_buffer: &[u32]) {
+        if !self.copied {
+            self.record.truncate(0);
+            self.record.extend_from_slice(record_buffer);
+            self.copied = true;
+        }
+    }
+
+    // Writes back the features, there can only be fewer of them than were read
+    fn write_features(&mut self, record_buffer: &[u32], token_offset: usize) {
+        self.copy_record(record_buffer);
+        let start = ((self.record[token_offset] >> 16) & 0x3fff) as usize;
+        for (i, (hash, value)) in self.features.iter().enumerate() {
+            self.record[start + 2 * i] = *hash;
+            self.record[start + 2 * i + 1] = value.to_bits();
+        }
+        self.record[token_offset] = parser::IS_NOT_SINGLE_MASK
+            | ((start as u32) << 16)
+            | (start + 2 * self.features.len()) as u32;
+    }
+
+    // Merges features with the same hash within a namespace into the first of them,
+    // returns the number of features that were merged away
+    fn merge_duplicates(
+        &mut self,
+        record_buffer: &[u32],
+        namespace_descriptors: &[NamespaceDescriptor],
+        dup_policy: model_instance::DupPolicy,
+    ) -> u64 {
+        let mut merged = 0;
+        for namespace_descriptor in namespace_descriptors {
+            let token_offset =
+                match self.read_features(record_buffer, namespace_descriptor.namespace_index) {
+                    Some(token_offset) => token_offset,
+                    None => continue,
+                };
+            self.sorted_hashes.truncate(0);
+            self.sorted_hashes
+                .extend(self.features.iter().enumerate().map(|(i, f)| (f.0, i)));
+            self.sorted_hashes.sort_unstable();
+            if !self.sorted_hashes.windows(2).any(|w| w[0].0 == w[1].0) {
+                continue;
+            }
+            self.keep.truncate(0);
+            self.keep.resize(self.features.len(), true);
+            // Sorted by hash and then by position, so the first of equal hashes is the one kept
+            let mut first = 0;
+            for j in 1..self.sorted_hashes.len() {
+                let (hash, i) = self.sorted_hashes[j];
+                let (first_hash, first_i) = self.sorted_hashes[first];
+                if hash != first_hash {
+                    first = j;
+                    continue;
+                }
+                let value = self.features[i].1;
+                let kept_value = &mut self.features[first_i].1;
+                match dup_policy {
+                    model_instance::DupPolicy::Sum => *kept_value += value,
+                    model_instance::DupPolicy::Max => *kept_value = kept_value.max(value),
+                    model_instance::DupPolicy::First => {}
+                }
+                self.keep[i] = false;
+                merged += 1;
+            }
+            let mut i = 0;
+            let keep = &self.keep;
+            self.features.retain(|_| {
+                i += 1;
+                keep[i - 1]
+            });
+            self.write_features(record_buffer, token_offset);
+        }
+        merged
+    }
+
+    // Mixes the hash of the first tenant feature into hashes of features of the given namespaces,
+    // so each tenant gets its own weights. Examples without a tenant use the shared weights.
+    fn mix_tenant(
+        &mut self,
+        record_buffer: &[u32],
+        tenant_namespace: NamespaceDescriptor,
+        namespace_descriptors: &[NamespaceDescriptor],
+    ) {
+        let buffer: &[u32] = if self.copied {
+            &self.record
+        } else {
+            record_buffer
+        };
+        let tenant_token =
+            buffer[tenant_namespace.namespace_index as usize + parser::HEADER_LEN as usize];
+        let tenant_hash = if tenant_token == parser::NO_FEATURES {
+            return;
+        } else if (tenant_token & parser::IS_NOT_SINGLE_MASK) == 0 {
+            tenant_token
+        } else {
+            buffer[((tenant_token >> 16) & 0x3fff) as usize]
+        };
+        let tenant_mix = tenant_hash.overflowing_mul(VOWPAL_FNV_PRIME).0;
+        for namespace_descriptor in namespace_descriptors {
+            let token_offset =
+                namespace_descriptor.namespace_index as usize + parser::HEADER_LEN as usize;
+            let token = if self.copied {
+                self.record[token_offset]
+            } else {
+                record_buffer[token_offset]
+            };
+            if token == parser::NO_FEATURES {
+                continue;
+            }
+            self.copy_record(record_buffer);
+            if (token & parser::IS_NOT_SINGLE_MASK) == 0 {
+                self.record[token_offset] = (token ^ tenant_mix) & parser::MASK31;
+            } else {
+                let start = ((token >> 16) & 0x3fff) as usize;
+                let end = (token & 0xffff) as usize;
+                for hash_offset in (start..end).step_by(2) {
+                    self.record[hash_offset] =
+                        (self.record[hash_offset] ^ tenant_mix) & parser::MASK31;
+                }
+            }
+        }
+    }
+
+    // Keeps only the k features with the largest weights, in the order of descending weight
+    fn truncate_to_topk(&mut self, record_buffer: &[u32], topks: &[model_instance::NamespaceTopK]) {
+        for topk in topks {
+            let token_offset = match self
+                .read_features(record_buffer, topk.namespace_descriptor.namespace_index)
+            {
+                Some(token_offset) => token_offset,
+                None => continue,
+            };
+            if self.features.len() <= topk.k as usize {
+                continue;
+            }
+            // Sort is stable, so among equal weights the first given features win
+            self.features
+                .sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+            self.features.truncate(topk.k as usize);
+            self.write_features(record_buffer, token_offset);
+        }
     }
 }
 
@@ -371,6 +764,14 @@ mod tests {
         }
     }
 
+    fn ns_desc_passthrough(i: u16) -> NamespaceDescriptor {
+        NamespaceDescriptor {
+            namespace_index: i,
+            namespace_type: NamespaceType::Primitive,
+            namespace_format: NamespaceFormat::Passthrough,
+        }
+    }
+
     #[test]
     fn test_constant() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
@@ -741,6 +1142,79 @@ mod tests {
         // one more which we dont test
     }
 
+    #[test]
+    fn test_ffm_field_pooling() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = false;
+        mi.ffm_fields.push(vec![ns_desc(0)]);
+        mi.ffm_fields.push(vec![ns_desc(0), ns_desc(1)]);
+        mi.ffm_fields.push(vec![ns_desc(1)]);
+        mi.ffm_field_pooling = vec![
+            model_instance::EmbeddingPooling::Sum,
+            model_instance::EmbeddingPooling::Mean,
+            model_instance::EmbeddingPooling::Mean,
+        ];
+        mi.ffm_k = 1;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let rb = add_header(vec![
+            parser::IS_NOT_SINGLE_MASK | nd(5, 9),
+            0x1,
+            0xfff,
+            2.0f32.to_bits(),
+            0xfeb,
+            3.0f32.to_bits(),
+        ]);
+        fbt.translate(&rb, 0);
+        let values: Vec<f32> = fbt
+            .feature_buffer
+            .ffm_buffer
+            .iter()
+            .map(|h| h.value)
+            .collect();
+        // The second field has three features, a single feature is its own mean
+        assert_eq!(values, vec![2.0, 3.0, 2.0 / 3.0, 1.0, 1.0 / 3.0, 1.0]);
+    }
+
+    #[test]
+    fn test_ffm_passthrough() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = false;
+        mi.ffm_fields.push(vec![ns_desc_passthrough(0)]);
+        mi.ffm_fields.push(vec![ns_desc(1)]);
+        mi.ffm_k = 3;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        // consecutive passthrough indices must not end up in the same k-aligned slot
+        let rb = add_header(vec![
+            parser::IS_NOT_SINGLE_MASK | nd(5, 9),
+            0x5,
+            5,
+            1.0f32.to_bits(),
+            6,
+            2.0f32.to_bits(),
+        ]);
+        fbt.translate(&rb, 0);
+        assert_eq!(
+            fbt.feature_buffer.ffm_buffer,
+            vec![
+                HashAndValueAndSeq {
+                    hash: 5 << 2,
+                    value: 1.0,
+                    contra_field_index: 0
+                },
+                HashAndValueAndSeq {
+                    hash: 6 << 2,
+                    value: 2.0,
+                    contra_field_index: 0
+                },
+                HashAndValueAndSeq {
+                    hash: 0x4,
+                    value: 1.0,
+                    contra_field_index: 3
+                },
+            ]
+        );
+    }
+
     #[test]
     fn test_example_importance() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
@@ -794,4 +1268,218 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_namespace_topk() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = false;
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![ns_desc(0)],
+                weight: 1.0,
+            });
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![ns_desc(1)],
+                weight: 1.0,
+            });
+        mi.namespace_topks.push(model_instance::NamespaceTopK {
+            namespace_descriptor: ns_desc(0),
+            k: 2,
+        });
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+
+        let rb = add_header(vec![
+            parser::IS_NOT_SINGLE_MASK | nd(5, 13),
+            parser::IS_NOT_SINGLE_MASK | nd(13, 17),
+            0xfa,
+            0.5f32.to_bits(),
+            0xfb,
+            2.0f32.to_bits(),
+            0xfc,
+            1.0f32.to_bits(),
+            0xfd,
+            2.0f32.to_bits(),
+            0xfe,
+            0.1f32.to_bits(),
+            0xff,
+            0.2f32.to_bits(),
+        ]);
+        fbt.translate(&rb, 0);
+        assert_eq!(
+            fbt.feature_buffer.lr_buffer,
+            vec![
+                HashAndValue {
+                    hash: 0xfb,
+                    value: 2.0,
+                    combo_index: 0
+                },
+                HashAndValue {
+                    hash: 0xfd,
+                    value: 2.0,
+                    combo_index: 0
+                },
+                HashAndValue {
+                    hash: 0xfe,
+                    value: 0.1,
+                    combo_index: 1
+                },
+                HashAndValue {
+                    hash: 0xff,
+                    value: 0.2,
+                    combo_index: 1
+                },
+            ]
+        );
+
+        // Namespaces within the limit are left as they are
+        let rb = add_header(vec![
+            parser::IS_NOT_SINGLE_MASK | nd(5, 9),
+            parser::NO_FEATURES,
+            0xfa,
+            0.5f32.to_bits(),
+            0xfb,
+            2.0f32.to_bits(),
+        ]);
+        fbt.translate(&rb, 0);
+        assert_eq!(
+            fbt.feature_buffer.lr_buffer,
+            vec![
+                HashAndValue {
+                    hash: 0xfa,
+                    value: 0.5,
+                    combo_index: 0
+                },
+                HashAndValue {
+                    hash: 0xfb,
+                    value: 2.0,
+                    combo_index: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dup_policy() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = false;
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![ns_desc(0)],
+                weight: 1.0,
+            });
+        let rb = add_header(vec![
+            parser::IS_NOT_SINGLE_MASK | nd(4, 14),
+            0xfa,
+            1.0f32.to_bits(),
+            0xfb,
+            2.0f32.to_bits(),
+            0xfa,
+            3.0f32.to_bits(),
+            0xfc,
+            1.0f32.to_bits(),
+            0xfb,
+            0.5f32.to_bits(),
+        ]);
+        let lr = |v: Vec<(u32, f32)>| -> Vec<HashAndValue> {
+            v.into_iter()
+                .map(|(hash, value)| HashAndValue {
+                    hash,
+                    value,
+                    combo_index: 0,
+                })
+                .collect()
+        };
+
+        mi.dup_policy = Some(model_instance::DupPolicy::Sum);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(&rb, 0);
+        assert_eq!(
+            fbt.feature_buffer.lr_buffer,
+            lr(vec![(0xfa, 4.0), (0xfb, 2.5), (0xfc, 1.0)])
+        );
+        assert_eq!(fbt.duplicate_features, 2);
+
+        mi.dup_policy = Some(model_instance::DupPolicy::Max);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(&rb, 0);
+        assert_eq!(
+            fbt.feature_buffer.lr_buffer,
+            lr(vec![(0xfa, 3.0), (0xfb, 2.0), (0xfc, 1.0)])
+        );
+
+        mi.dup_policy = Some(model_instance::DupPolicy::First);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(&rb, 0);
+        assert_eq!(
+            fbt.feature_buffer.lr_buffer,
+            lr(vec![(0xfa, 1.0), (0xfb, 2.0), (0xfc, 1.0)])
+        );
+        fbt.translate(&rb, 0);
+        assert_eq!(fbt.duplicate_features, 4);
+
+        // Duplicates are merged before the top-k truncation
+        mi.dup_policy = Some(model_instance::DupPolicy::Sum);
+        mi.namespace_topks.push(model_instance::NamespaceTopK {
+            namespace_descriptor: ns_desc(0),
+            k: 2,
+        });
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(&rb, 0);
+        assert_eq!(
+            fbt.feature_buffer.lr_buffer,
+            lr(vec![(0xfa, 4.0), (0xfb, 2.5)])
+        );
+    }
+
+    #[test]
+    fn test_tenant_namespace() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = false;
+        for namespace_descriptor in [ns_desc(0), ns_desc(1), ns_desc_passthrough(2)].iter() {
+            mi.feature_combo_descs
+                .push(model_instance::FeatureComboDesc {
+                    namespace_descriptors: vec![*namespace_descriptor],
+                    weight: 1.0,
+                });
+        }
+        mi.tenant_namespace = Some(ns_desc(0));
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let hashes = |fbt: &FeatureBufferTranslator| -> Vec<u32> {
+            fbt.feature_buffer
+                .lr_buffer
+                .iter()
+                .map(|f| f.hash)
+                .collect()
+        };
+
+        let rb = |tenant: u32| {
+            add_header(vec![
+                tenant,
+                parser::IS_NOT_SINGLE_MASK | nd(6, 10),
+                0x5,
+                0xfa,
+                1.0f32.to_bits(),
+                0xfb,
+                1.0f32.to_bits(),
+            ])
+        };
+        // Without the tenant the shared weights are used, and the record is not copied
+        fbt.translate(&rb(NO_FEATURES), 0);
+        assert_eq!(hashes(&fbt), vec![0xfa, 0xfb, 0x5]);
+        assert!(!fbt.record_rewriter.copied);
+
+        fbt.translate(&rb(0x11), 0);
+        let tenant_1 = hashes(&fbt);
+        assert!(fbt.record_rewriter.copied);
+        fbt.translate(&rb(0x12), 0);
+        let tenant_2 = hashes(&fbt);
+        let mix = |hash: u32, tenant: u32| {
+            ((hash ^ tenant.overflowing_mul(VOWPAL_FNV_PRIME).0) & MASK31) & fbt.lr_hash_mask
+        };
+        // The tenant itself and passthrough features are not mixed
+        assert_eq!(tenant_1, vec![0x11, mix(0xfa, 0x11), mix(0xfb, 0x11), 0x5]);
+        assert_eq!(tenant_2, vec![0x12, mix(0xfa, 0x12), mix(0xfb, 0x12), 0x5]);
+        assert_ne!(tenant_1[1], tenant_2[1]);
+    }
 }
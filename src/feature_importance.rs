@@ -0,0 +1,344 @@
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+
+use crate::feature_buffer::FeatureBuffer;
+use crate::invert_hash::PlainWeights;
+use crate::lofo::primitive_namespaces;
+use crate::model_instance::ModelInstance;
+use crate::regressor::Regressor;
+use crate::vwmap::{NamespaceDescriptor, VwNamespaceMap};
+
+// Post-training report of where the weight mass of the model is (--feature_importance): sums of
+// absolute LR weights by feature combo and L2 norms of FFM embeddings by pair of fields, also
+// summed by input namespace. Weights are attributed to the combo or field whose features touched
+// them first during training, the model alone doesn't know which hashes belong to what.
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ComboImportance {
+    pub combo: usize,
+    pub namespaces: Vec<String>,
+    pub features: u64, // distinct hashes
+    pub weight_mass: f64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldPairImportance {
+    pub field: usize,
+    pub towards_field: usize,
+    pub embedding_norm_mass: f64, // of embeddings of features of field towards towards_field
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldImportance {
+    pub field: usize,
+    pub namespaces: Vec<String>,
+    pub features: u64,
+    pub embedding_norm_mass: f64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct NamespaceImportance {
+    pub namespace: String,
+    pub lr_weight_mass: f64, // of combos with features of the namespace
+    pub ffm_embedding_norm_mass: f64, // of fields with features of the namespace
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FeatureImportanceReport {
+    pub examples: u64,
+    pub constant_weight: Option<f32>,
+    pub lr_combos: Vec<ComboImportance>,
+    pub ffm_fields: Vec<FieldImportance>,
+    pub ffm_field_pairs: Vec<FieldPairImportance>,
+    pub namespaces: Vec<NamespaceImportance>, // most important first
+}
+
+pub struct FeatureImportance {
+    lr_owners: Vec<u16>, // by LR hash, 1 + index of the combo that touched it first, 0 when untouched
+    ffm_owners: Vec<u16>, // by FFM hash >> ffm_bits_for_dimensions, 1 + field index
+    ffm_bits_for_dimensions: u32,
+    pub examples: u64,
+}
+
+impl FeatureImportance {
+    pub fn new(mi: &ModelInstance) -> Result<FeatureImportance, Box<dyn Error>> {
+        if mi.feature_combo_descs.len() >= u16::MAX as usize
+            || mi.ffm_fields.len() >= u16::MAX as usize
+        {
+            return Err("--feature_importance supports up to 65534 feature combos and fields")?;
+        }
+        let mut ffm_bits_for_dimensions = 0;
+        while mi.ffm_k.max(mi.fm_k) > (1 << ffm_bits_for_dimensions) {
+            ffm_bits_for_dimensions += 1;
+        }
+        let ffm_buckets = if mi.ffm_k == 0 || mi.ffm_fields.is_empty() {
+            0
+        } else {
+            1 << mi.ffm_bit_precision.saturating_sub(ffm_bits_for_dimensions)
+        };
+        Ok(FeatureImportance {
+            lr_owners: vec![0; 1 << mi.bit_precision],
+            ffm_owners: vec![0; ffm_buckets],
+            ffm_bits_for_dimensions,
+            examples: 0,
+        })
+    }
+
+    pub fn observe(&mut self, fb: &FeatureBuffer, ffm_k: u32) {
+        self.examples += 1;
+        for feature in fb.lr_buffer.iter() {
+            let owner = &mut self.lr_owners[feature.hash as usize];
+            if *owner == 0 {
+                *owner = feature.combo_index as u16 + 1;
+            }
+        }
+        if !self.ffm_owners.is_empty() {
+            for feature in fb.ffm_buffer.iter() {
+                let owner =
+                    &mut self.ffm_owners[(feature.hash >> self.ffm_bits_for_dimensions) as usize];
+                if *owner == 0 {
+                    *owner = (feature.contra_field_index / ffm_k) as u16 + 1;
+                }
+            }
+        }
+    }
+
+    pub fn report(
+        &self,
+        mi: &ModelInstance,
+        vw: &VwNamespaceMap,
+        re: &Regressor,
+    ) -> Result<FeatureImportanceReport, Box<dyn Error>> {
+        let weights = PlainWeights::new(mi, re)?;
+        let mut vwnames: Vec<String> = vec![String::new(); vw.num_namespaces];
+        for (vwname, namespace_descriptor) in vw.map_vwname_to_namespace_descriptor.iter() {
+            vwnames[namespace_descriptor.namespace_index as usize] =
+                String::from_utf8_lossy(vwname).to_string();
+        }
+        let input_namespaces = |namespace_descriptors: &[NamespaceDescriptor]| -> Vec<usize> {
+            let mut indexes: Vec<usize> = namespace_descriptors
+                .iter()
+                .flat_map(|nd| primitive_namespaces(mi, nd))
+                .map(|index| index as usize)
+                .collect();
+            indexes.sort_unstable();
+            indexes.dedup();
+            indexes
+        };
+        let names_of = |indexes: &[usize]| -> Vec<String> {
+            indexes.iter().map(|i| vwnames[*i].clone()).collect()
+        };
+        let mut namespaces: Vec<NamespaceImportance> = vwnames
+            .iter()
+            .map(|vwname| NamespaceImportance {
+                namespace: vwname.clone(),
+                lr_weight_mass: 0.0,
+                ffm_embedding_norm_mass: 0.0,
+            })
+            .collect();
+
+        // The constant feature is a combo of its own, after the others
+        let num_combos = mi.feature_combo_descs.len();
+        let mut combo_features = vec![0u64; num_combos + 1];
+        let mut combo_mass = vec![0f64; num_combos + 1];
+        let mut constant_weight = None;
+        if !weights.lr.is_empty() {
+            for (hash, owner) in self.lr_owners.iter().enumerate() {
+                if *owner > 0 {
+                    combo_features[*owner as usize - 1] += 1;
+                    combo_mass[*owner as usize - 1] += weights.lr[hash].abs() as f64;
+                    if *owner as usize - 1 == num_combos {
+                        constant_weight = Some(weights.lr[hash]);
+                    }
+                }
+            }
+        }
+        let mut lr_combos: Vec<ComboImportance> = Vec::new();
+        for (combo_index, combo) in mi.feature_combo_descs.iter().enumerate() {
+            let indexes = input_namespaces(&combo.namespace_descriptors);
+            for index in indexes.iter() {
+                namespaces[*index].lr_weight_mass += combo_mass[combo_index];
+            }
+            lr_combos.push(ComboImportance {
+                combo: combo_index,
+                namespaces: names_of(&indexes),
+                features: combo_features[combo_index],
+                weight_mass: combo_mass[combo_index],
+            });
+        }
+
+        let num_fields = mi.ffm_fields.len();
+        let ffm_k = mi.ffm_k as usize;
+        let mut field_features = vec![0u64; num_fields];
+        let mut pair_mass = vec![0f64; num_fields * num_fields];
+        if !weights.ffm.is_empty() {
+            for (bucket, owner) in self.ffm_owners.iter().enumerate() {
+                if *owner > 0 {
+                    let field = *owner as usize - 1;
+                    field_features[field] += 1;
+                    let hash = (bucket << self.ffm_bits_for_dimensions) as u32;
+                    for towards_field in 0..num_fields {
+                        let norm: f32 = weights
+                            .ffm_embedding(hash, towards_field, ffm_k)
+                            .iter()
+                            .map(|w| w * w)
+                            .sum::<f32>()
+                            .sqrt();
+                        pair_mass[field * num_fields + towards_field] += norm as f64;
+                    }
+                }
+            }
+        }
+        let mut ffm_fields: Vec<FieldImportance> = Vec::new();
+        let mut ffm_field_pairs: Vec<FieldPairImportance> = Vec::new();
+        for (field, field_desc) in mi.ffm_fields.iter().enumerate() {
+            let field_mass: f64 = pair_mass[field * num_fields..(field + 1) * num_fields]
+                .iter()
+                .sum();
+            let indexes = input_namespaces(field_desc);
+            for index in indexes.iter() {
+                namespaces[*index].ffm_embedding_norm_mass += field_mass;
+            }
+            ffm_fields.push(FieldImportance {
+                field,
+                namespaces: names_of(&indexes),
+                features: field_features[field],
+                embedding_norm_mass: field_mass,
+            });
+            for towards_field in 0..num_fields {
+                ffm_field_pairs.push(FieldPairImportance {
+                    field,
+                    towards_field,
+                    embedding_norm_mass: pair_mass[field * num_fields + towards_field],
+                });
+            }
+        }
+
+        namespaces.retain(|n| n.lr_weight_mass > 0.0 || n.ffm_embedding_norm_mass > 0.0);
+        namespaces.sort_by(|a, b| {
+            (b.lr_weight_mass + b.ffm_embedding_norm_mass)
+                .partial_cmp(&(a.lr_weight_mass + a.ffm_embedding_norm_mass))
+                .unwrap()
+                .then_with(|| a.namespace.cmp(&b.namespace))
+        });
+        Ok(FeatureImportanceReport {
+            examples: self.examples,
+            constant_weight,
+            lr_combos,
+            ffm_fields,
+            ffm_field_pairs,
+            namespaces,
+        })
+    }
+
+    pub fn write_report(
+        &self,
+        filename: &str,
+        mi: &ModelInstance,
+        vw: &VwNamespaceMap,
+        re: &Regressor,
+    ) -> Result<(), Box<dyn Error>> {
+        let report = self.report(mi, vw, re)?;
+        fs::write(filename, serde_json::to_vec_pretty(&report)?)?;
+        log::info!(
+            "Wrote feature importance of {} examples to {}",
+            self.examples,
+            filename
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::block_ffm::BlockFFM;
+    use crate::block_lr::BlockLR;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::model_instance::FeatureComboDesc;
+    use crate::optimizer::OptimizerSGD;
+    use crate::parser::VowpalParser;
+
+    #[test]
+    fn test_feature_importance() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let a = vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()];
+        let b = vw.map_vwname_to_namespace_descriptor[&b"B".to_vec()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 8;
+        for namespace_descriptors in [vec![a], vec![a, b]].iter() {
+            mi.feature_combo_descs.push(FeatureComboDesc {
+                namespace_descriptors: namespace_descriptors.clone(),
+                weight: 1.0,
+            });
+        }
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 8;
+        mi.ffm_fields = vec![vec![a], vec![b]];
+
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(pa.next_vowpal_from_bytes(b"1 |A a |B b\n").unwrap(), 0);
+        let mut fi = FeatureImportance::new(&mi).unwrap();
+        fi.observe(&fbt.feature_buffer, mi.ffm_k);
+
+        let mut re = Regressor::new(&mi)
+            .immutable_regressor_without_weights(&mi)
+            .unwrap();
+        re.allocate_and_init_weights(&mi);
+        let lr_hashes: Vec<u32> = fbt
+            .feature_buffer
+            .lr_buffer
+            .iter()
+            .map(|f| f.hash)
+            .collect();
+        let ffm_hashes: Vec<u32> = fbt
+            .feature_buffer
+            .ffm_buffer
+            .iter()
+            .map(|f| f.hash)
+            .collect();
+        for block in re.blocks_boxes.iter_mut() {
+            if let Some(lr) = block.as_any().downcast_mut::<BlockLR<OptimizerSGD>>() {
+                lr.weights[lr_hashes[0] as usize].weight = -1.0; // A
+                lr.weights[lr_hashes[1] as usize].weight = 0.5; // A x B
+                lr.weights[lr_hashes[2] as usize].weight = 0.25; // constant
+            } else if let Some(ffm) = block.as_any().downcast_mut::<BlockFFM<OptimizerSGD>>() {
+                let (a, b) = (ffm_hashes[0] as usize, ffm_hashes[1] as usize);
+                ffm.weights[a..a + 4].copy_from_slice(&[3.0, 4.0, 0.0, 0.0]);
+                ffm.weights[b..b + 4].copy_from_slice(&[0.0, 0.0, 0.0, 1.0]);
+            }
+        }
+
+        let report = fi.report(&mi, &vw, &re).unwrap();
+        assert_eq!(report.examples, 1);
+        assert_eq!(report.constant_weight, Some(0.25));
+        assert_eq!(report.lr_combos.len(), 2);
+        assert_eq!(report.lr_combos[0].weight_mass, 1.0);
+        assert_eq!(report.lr_combos[1].namespaces, vec!["A", "B"]);
+        assert_eq!(report.lr_combos[1].weight_mass, 0.5);
+        // Embedding of a towards A has norm 5, of b towards B 1
+        assert_eq!(report.ffm_fields[0].embedding_norm_mass, 5.0);
+        assert_eq!(report.ffm_fields[1].features, 1);
+        assert_eq!(report.ffm_field_pairs[0].embedding_norm_mass, 5.0);
+        assert_eq!(report.ffm_field_pairs[1].embedding_norm_mass, 0.0);
+        assert_eq!(report.ffm_field_pairs[3].embedding_norm_mass, 1.0);
+        assert_eq!(
+            report.namespaces,
+            vec![
+                NamespaceImportance {
+                    namespace: "A".to_string(),
+                    lr_weight_mass: 1.5,
+                    ffm_embedding_norm_mass: 5.0,
+                },
+                NamespaceImportance {
+                    namespace: "B".to_string(),
+                    lr_weight_mass: 0.5,
+                    ffm_embedding_norm_mass: 1.0,
+                },
+            ]
+        );
+    }
+}
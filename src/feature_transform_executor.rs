@@ -1,16 +1,18 @@
+use crate::murmur3;
 use crate::parser;
 use crate::vwmap;
 use std::error::Error;
+use std::fs;
 use std::io::Error as IOError;
 use std::io::ErrorKind;
 
 use std::cell::RefCell;
 
 use dyn_clone::{clone_trait_object, DynClone};
-use fasthash::murmur3;
 
 use crate::feature_transform_implementations::{
-    TransformerBinner, TransformerCombine, TransformerLogRatioBinner, TransformerWeight,
+    TransformerBinner, TransformerCombine, TransformerLogRatioBinner, TransformerRollingCount,
+    TransformerWeight,
 };
 use crate::feature_transform_parser;
 
@@ -185,6 +187,20 @@ impl TransformExecutor {
             TransformerCombine::create_function(function_name, namespaces_from, function_params)
         } else if function_name == "Weight" {
             TransformerWeight::create_function(function_name, namespaces_from, function_params)
+        } else if function_name == "RollingCount" {
+            TransformerRollingCount::create_function(
+                function_name,
+                namespaces_from,
+                function_params,
+                false,
+            )
+        } else if function_name == "RollingPositiveCount" {
+            TransformerRollingCount::create_function(
+                function_name,
+                namespaces_from,
+                function_params,
+                true,
+            )
         } else {
             return Err(Box::new(IOError::new(
                 ErrorKind::Other,
@@ -212,6 +228,60 @@ impl TransformExecutors {
         TransformExecutors { executors }
     }
 
+    // Lets stateful transforms account for the example, once it was translated
+    pub fn observe_record(&self, record_buffer: &[u32]) {
+        for executor in &self.executors {
+            executor
+                .function_executor
+                .observe_record(record_buffer, self);
+        }
+    }
+
+    pub fn has_state(&self) -> bool {
+        self.executors
+            .iter()
+            .any(|executor| executor.function_executor.save_state().is_some())
+    }
+
+    pub fn log_metrics(&self) {
+        for (i, executor) in self.executors.iter().enumerate() {
+            if let Some(metrics) = executor.function_executor.report_metrics() {
+                log::info!("Transform {} state: {}", i, metrics);
+            }
+        }
+    }
+
+    pub fn save_state_to_filename(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let mut states = serde_json::Map::new();
+        for (i, executor) in self.executors.iter().enumerate() {
+            if let Some(state) = executor.function_executor.save_state() {
+                states.insert(i.to_string(), state);
+            }
+        }
+        fs::write(filename, serde_json::to_vec(&states)?)?;
+        Ok(())
+    }
+
+    pub fn load_state_from_filename(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let states: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(&fs::read(filename)?)?;
+        for (i, state) in states.into_iter() {
+            match self.executors.get(i.parse::<usize>()?) {
+                Some(executor) => executor.function_executor.load_state(state)?,
+                None => {
+                    return Err(Box::new(IOError::new(
+                        ErrorKind::Other,
+                        format!(
+                            "Transform state in {} does not match the transforms of the model",
+                            filename
+                        ),
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
     /*
     //  We don't use this function as we have put it into feature_reader! macro
         #[inline(always)]
@@ -229,6 +299,12 @@ impl TransformExecutors {
     */
 }
 
+// Without --transform_state, state of stateful transforms is kept next to the model file,
+// so restarts from a saved model don't reset the counters
+pub fn model_transform_state_filename(model_filename: &str) -> String {
+    format!("{}.transform_state", model_filename)
+}
+
 // Some black magic from: https://stackoverflow.com/questions/30353462/how-to-clone-a-struct-storing-a-boxed-trait-object
 // We need clone() because of serving. There is also an option of doing FeatureBufferTransform from scratch in each thread
 pub trait FunctionExecutorTrait: DynClone + Send {
@@ -238,6 +314,21 @@ pub trait FunctionExecutorTrait: DynClone + Send {
         to_namespace: &mut ExecutorToNamespace,
         transform_executors: &TransformExecutors,
     );
+
+    // Only stateful transforms need to know about examples that passed through them
+    fn observe_record(&self, _record_buffer: &[u32], _transform_executors: &TransformExecutors) {}
+
+    fn save_state(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    fn load_state(&self, _state: serde_json::Value) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn report_metrics(&self) -> Option<String> {
+        None
+    }
 }
 clone_trait_object!(FunctionExecutorTrait);
 
@@ -1,6 +1,11 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::Error as IOError;
 use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
 
 use crate::feature_reader;
 use crate::feature_reader_float_namespace;
@@ -532,10 +537,340 @@ impl TransformerCombine {
     }
 }
 
+// -------------------------------------------------------------------
+// TransformerRollingCount - A stateful per-key rolling counter
+// RollingCount(A)(window, max_keys) emits for each feature (key) of namespace A a single float feature,
+// whose value is the number of examples with that key among the last `window` examples
+// RollingPositiveCount(A)(window, max_keys) only counts examples with a positive label
+// Example of use: clicks of the user in the last 100000 examples - RollingPositiveCount(U)(100000, 1000000)
+// Optional third and fourth parameters: RollingCount(A)(window, max_keys, ttl, eviction)
+//   ttl - keys not updated for this many examples are dropped and count as zero (0 means no ttl)
+//   eviction - 0 evicts the least recently seen keys, 1 the least warm keys (lowest current count),
+//              which keeps frequent keys that were just not seen in the last few examples
+
+// The clock is the number of examples seen by the process, not wall time.
+// Counters decay exponentially with the time constant of the window, which gives the same count
+// as the sliding window for a steady rate, but needs only one counter per key.
+// Counts are emitted before the current example is counted, so labels don't leak into features.
+// State is shared by all clones of the transform (serving threads, hogwild workers). Keys are split
+// among shards by their hash, each shard has its own lock and at most its share of max_keys keys,
+// when full a quarter of them is evicted. Small tables are a single shard, so eviction is exact.
+const ROLLING_COUNTER_MAX_SHARDS: usize = 16;
+const ROLLING_COUNTER_MIN_SHARD_KEYS: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CounterEviction {
+    LeastRecent,
+    LeastWarm,
+}
+
+// Not saved with the state, they describe the current process
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct RollingCounterMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+// Saved state, counters of all shards together
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct RollingCounterState {
+    pub clock: u64,
+    pub counters: HashMap<u32, (f32, u64)>, // key -> (count, clock of the last update)
+}
+
+#[derive(Default)]
+struct RollingCounterShard {
+    counters: HashMap<u32, (f32, u64)>,
+    metrics: RollingCounterMetrics,
+    next_expiry_sweep: u64,
+}
+
+// Another thread may have counted a key after this one read the clock
+#[inline(always)]
+fn decayed(counter: &(f32, u64), clock: u64, window: f32) -> f32 {
+    counter.0 * (-(clock.saturating_sub(counter.1) as f32) / window).exp()
+}
+
+impl RollingCounterShard {
+    #[inline(always)]
+    fn count(&self, key: u32, clock: u64, window: f32, ttl: u64) -> f32 {
+        match self.counters.get(&key) {
+            Some(counter) if ttl == 0 || clock.saturating_sub(counter.1) <= ttl => {
+                decayed(counter, clock, window)
+            }
+            _ => 0.0,
+        }
+    }
+
+    // Like count, but accounts the lookup as a hit or a miss
+    #[inline(always)]
+    fn lookup(&mut self, key: u32, clock: u64, window: f32, ttl: u64) -> f32 {
+        let count = self.count(key, clock, window, ttl);
+        if count > 0.0 {
+            self.metrics.hits += 1;
+        } else {
+            self.metrics.misses += 1;
+        }
+        count
+    }
+
+    fn evict(&mut self, eviction: CounterEviction, clock: u64, window: f32) {
+        let score = |counter: &(f32, u64)| -> f64 {
+            match eviction {
+                CounterEviction::LeastRecent => counter.1 as f64,
+                CounterEviction::LeastWarm => decayed(counter, clock, window) as f64,
+            }
+        };
+        let mut scores: Vec<f64> = self.counters.values().map(score).collect();
+        let quarter = scores.len() / 4;
+        let (_, cutoff, _) =
+            scores.select_nth_unstable_by(quarter, |a, b| a.partial_cmp(b).unwrap());
+        let cutoff = *cutoff;
+        let before = self.counters.len();
+        self.counters.retain(|_, c| score(c) > cutoff);
+        self.metrics.evictions += (before - self.counters.len()) as u64;
+    }
+
+    // Drops keys that were not updated for more than ttl examples, checked a few times per ttl
+    fn expire(&mut self, clock: u64, ttl: u64) {
+        if clock < self.next_expiry_sweep {
+            return;
+        }
+        self.next_expiry_sweep = clock + (ttl / 4).max(1);
+        let before = self.counters.len();
+        self.counters
+            .retain(|_, c| clock.saturating_sub(c.1) <= ttl);
+        self.metrics.expirations += (before - self.counters.len()) as u64;
+    }
+}
+
+struct RollingCounterShards {
+    clock: AtomicU64,
+    shards: Vec<Mutex<RollingCounterShard>>,
+    max_shard_keys: usize,
+}
+
+impl RollingCounterShards {
+    fn new(max_keys: usize) -> RollingCounterShards {
+        let num_shards =
+            (max_keys / ROLLING_COUNTER_MIN_SHARD_KEYS).clamp(1, ROLLING_COUNTER_MAX_SHARDS);
+        RollingCounterShards {
+            clock: AtomicU64::new(0),
+            shards: (0..num_shards).map(|_| Mutex::default()).collect(),
+            max_shard_keys: (max_keys + num_shards - 1) / num_shards,
+        }
+    }
+
+    #[inline(always)]
+    fn shard(&self, key: u32) -> MutexGuard<'_, RollingCounterShard> {
+        self.shards[key as usize % self.shards.len()]
+            .lock()
+            .unwrap()
+    }
+
+    // Shards are locked one after the other, in the same order by all threads
+    fn save(&self) -> RollingCounterState {
+        let mut state = RollingCounterState {
+            clock: self.clock.load(Ordering::Relaxed),
+            counters: HashMap::new(),
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            state.counters.extend(shard.counters.iter());
+        }
+        state
+    }
+
+    fn load(&self, state: RollingCounterState) {
+        for shard in self.shards.iter() {
+            *shard.lock().unwrap() = RollingCounterShard::default();
+        }
+        for (key, counter) in state.counters.into_iter() {
+            self.shard(key).counters.insert(key, counter);
+        }
+        self.clock.store(state.clock, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct TransformerRollingCount {
+    from_namespace: ExecutorFromNamespace,
+    window: f32,
+    ttl: u64,
+    eviction: CounterEviction,
+    positive_only: bool,
+    state: Arc<RollingCounterShards>,
+}
+
+impl FunctionExecutorTrait for TransformerRollingCount {
+    fn execute_function(
+        &self,
+        record_buffer: &[u32],
+        to_namespace: &mut ExecutorToNamespace,
+        transform_executors: &TransformExecutors,
+    ) {
+        let clock = self.state.clock.load(Ordering::Relaxed);
+        feature_reader!(
+            record_buffer,
+            transform_executors,
+            self.from_namespace.namespace_descriptor,
+            hash_index,
+            hash_value,
+            {
+                let count =
+                    self.state
+                        .shard(hash_index)
+                        .lookup(hash_index, clock, self.window, self.ttl);
+                to_namespace.emit_i32::<{ SeedNumber::Default as usize }>(0, hash_value * count);
+            }
+        );
+    }
+
+    fn observe_record(&self, record_buffer: &[u32], transform_executors: &TransformExecutors) {
+        let clock = self.state.clock.load(Ordering::Relaxed);
+        let label = record_buffer[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK;
+        if !self.positive_only || label == 1 {
+            feature_reader!(
+                record_buffer,
+                transform_executors,
+                self.from_namespace.namespace_descriptor,
+                hash_index,
+                _hash_value,
+                {
+                    let mut shard = self.state.shard(hash_index);
+                    let count = shard.count(hash_index, clock, self.window, self.ttl) + 1.0;
+                    if shard.counters.len() >= self.state.max_shard_keys
+                        && !shard.counters.contains_key(&hash_index)
+                    {
+                        shard.evict(self.eviction, clock, self.window);
+                    }
+                    shard.counters.insert(hash_index, (count, clock));
+                }
+            );
+        }
+        let clock = self.state.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.ttl > 0 {
+            // One shard per example is checked for expired keys
+            let shards = &self.state.shards;
+            shards[clock as usize % shards.len()]
+                .lock()
+                .unwrap()
+                .expire(clock, self.ttl);
+        }
+    }
+
+    fn save_state(&self) -> Option<serde_json::Value> {
+        Some(serde_json::to_value(&self.state.save()).unwrap())
+    }
+
+    fn load_state(&self, state: serde_json::Value) -> Result<(), Box<dyn Error>> {
+        self.state.load(serde_json::from_value(state)?);
+        Ok(())
+    }
+
+    fn report_metrics(&self) -> Option<String> {
+        let mut keys = 0;
+        let mut metrics = RollingCounterMetrics::default();
+        for shard in self.state.shards.iter() {
+            let shard = shard.lock().unwrap();
+            keys += shard.counters.len();
+            metrics.hits += shard.metrics.hits;
+            metrics.misses += shard.metrics.misses;
+            metrics.evictions += shard.metrics.evictions;
+            metrics.expirations += shard.metrics.expirations;
+        }
+        let lookups = metrics.hits + metrics.misses;
+        Some(format!(
+            "keys: {}, lookups: {}, hit rate: {:.4}, evictions: {}, expirations: {}",
+            keys,
+            lookups,
+            metrics.hits as f64 / lookups.max(1) as f64,
+            metrics.evictions,
+            metrics.expirations
+        ))
+    }
+}
+
+impl TransformerRollingCount {
+    pub fn create_function(
+        function_name: &str,
+        from_namespaces: &Vec<feature_transform_parser::Namespace>,
+        function_params: &Vec<f32>,
+        positive_only: bool,
+    ) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+        if function_params.len() < 2 || function_params.len() > 4 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes two to four float arguments, example {}(A)(100000, 1000000) or {}(A)(100000, 1000000, 500000, 1)",
+                    function_name, function_name, function_name
+                ),
+            )));
+        }
+        if from_namespaces.len() != 1 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} takes exactly one namespace argument, example {}(A)(100000, 1000000)",
+                    function_name, function_name
+                ),
+            )));
+        }
+        let window = function_params[0];
+        let max_keys = function_params[1];
+        if window < 1.0 || max_keys < 1.0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} needs window and max_keys of at least 1, got {} and {}",
+                    function_name, window, max_keys
+                ),
+            )));
+        }
+        let ttl = function_params.get(2).copied().unwrap_or(0.0);
+        if ttl < 0.0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Function {} needs a non-negative ttl, got {}",
+                    function_name, ttl
+                ),
+            )));
+        }
+        let eviction = match function_params.get(3).copied().unwrap_or(0.0) as i32 {
+            0 => CounterEviction::LeastRecent,
+            1 => CounterEviction::LeastWarm,
+            _ => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Function {} eviction has to be 0 (least recent) or 1 (least warm), got {}",
+                        function_name, function_params[3]
+                    ),
+                )))
+            }
+        };
+
+        Ok(Box::new(Self {
+            from_namespace: ExecutorFromNamespace {
+                namespace_descriptor: from_namespaces[0].namespace_descriptor,
+            },
+            window,
+            ttl: ttl as u64,
+            eviction,
+            positive_only,
+            state: Arc::new(RollingCounterShards::new(max_keys as usize)),
+        }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::assert_epsilon;
     use crate::feature_transform_executor::default_seeds;
     use crate::parser::{IS_NOT_SINGLE_MASK, MASK31};
 
@@ -929,4 +1264,188 @@ mod tests {
             .emit_i32::<{ SeedNumber::Default as usize }>(1775699190 ^ 1775699190, 3.0f32);
         assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
     }
+
+    #[test]
+    fn test_transformerrollingcount() {
+        let from_namespace = feature_transform_parser::Namespace {
+            namespace_descriptor: ns_desc(0),
+            namespace_verbose: "a".to_string(),
+        };
+        let to_namespace_empty = ExecutorToNamespace {
+            namespace_descriptor: ns_desc(1),
+            namespace_seeds: default_seeds(1),
+            tmp_data: Vec::new(),
+        };
+        let transform_executors = TransformExecutors { executors: vec![] }; // not used
+        let record = |key: u32, label: u32| [4, label, (1.0_f32).to_bits(), key & MASK31];
+        let emitted = |transformer: &Box<dyn FunctionExecutorTrait>, record: &[u32]| {
+            let mut to_namespace = to_namespace_empty.clone();
+            transformer.execute_function(record, &mut to_namespace, &transform_executors);
+            to_namespace.tmp_data[0].1
+        };
+
+        assert!(TransformerRollingCount::create_function(
+            "RollingCount",
+            &vec![from_namespace.clone()],
+            &vec![10.0],
+            false
+        )
+        .is_err());
+
+        let transformer = TransformerRollingCount::create_function(
+            "RollingPositiveCount",
+            &vec![from_namespace],
+            &vec![10.0, 2.0],
+            true,
+        )
+        .unwrap();
+        // All keys share a single float feature
+        let mut to_namespace = to_namespace_empty.clone();
+        transformer.execute_function(&record(5, 1), &mut to_namespace, &transform_executors);
+        let mut to_namespace_comparison = to_namespace_empty.clone();
+        to_namespace_comparison.emit_i32::<{ SeedNumber::Default as usize }>(0, 0.0);
+        assert_eq!(to_namespace.tmp_data, to_namespace_comparison.tmp_data);
+
+        // Count is emitted before the example itself is counted
+        transformer.observe_record(&record(5, 1), &transform_executors);
+        assert_eq!(emitted(&transformer, &record(5, 1)), (-0.1_f32).exp());
+        // Negative examples only move the clock
+        transformer.observe_record(&record(5, 0), &transform_executors);
+        assert_eq!(emitted(&transformer, &record(5, 1)), (-0.2_f32).exp());
+        transformer.observe_record(&record(5, 1), &transform_executors);
+        assert_epsilon!(
+            emitted(&transformer, &record(5, 1)),
+            ((-0.2_f32).exp() + 1.0) * (-0.1_f32).exp()
+        );
+
+        // Clones share the state
+        let cloned = transformer.clone();
+        cloned.observe_record(&record(6, 1), &transform_executors);
+        assert_eq!(emitted(&transformer, &record(6, 1)), (-0.1_f32).exp());
+
+        // Third key evicts the least recently seen one
+        let state = transformer.save_state().unwrap();
+        transformer.observe_record(&record(7, 1), &transform_executors);
+        assert_eq!(emitted(&transformer, &record(5, 1)), 0.0);
+        assert_eq!(emitted(&transformer, &record(7, 1)), (-0.1_f32).exp());
+
+        transformer.load_state(state).unwrap();
+        assert_eq!(emitted(&transformer, &record(7, 1)), 0.0);
+        assert_eq!(emitted(&transformer, &record(6, 1)), (-0.1_f32).exp());
+    }
+
+    #[test]
+    fn test_transformerrollingcount_eviction() {
+        let from_namespace = feature_transform_parser::Namespace {
+            namespace_descriptor: ns_desc(0),
+            namespace_verbose: "a".to_string(),
+        };
+        let to_namespace_empty = ExecutorToNamespace {
+            namespace_descriptor: ns_desc(1),
+            namespace_seeds: default_seeds(1),
+            tmp_data: Vec::new(),
+        };
+        let transform_executors = TransformExecutors { executors: vec![] }; // not used
+        let record = |key: u32| [4, 1, (1.0_f32).to_bits(), key & MASK31];
+        let emitted = |transformer: &Box<dyn FunctionExecutorTrait>, key: u32| {
+            let mut to_namespace = to_namespace_empty.clone();
+            transformer.execute_function(&record(key), &mut to_namespace, &transform_executors);
+            to_namespace.tmp_data[0].1
+        };
+        let create = |params: Vec<f32>| {
+            TransformerRollingCount::create_function(
+                "RollingCount",
+                &vec![from_namespace.clone()],
+                &params,
+                false,
+            )
+        };
+        assert!(create(vec![100.0, 2.0, -1.0]).is_err());
+        assert!(create(vec![100.0, 2.0, 0.0, 2.0]).is_err());
+
+        // Key 5 is frequent, key 6 the most recent: least recent eviction drops 5, least warm drops 6
+        for (eviction, evicted, kept) in [(0.0, 5, 6), (1.0, 6, 5)].iter() {
+            let transformer = create(vec![100.0, 2.0, 0.0, *eviction]).unwrap();
+            for key in [5, 5, 5, 6, 7].iter() {
+                transformer.observe_record(&record(*key), &transform_executors);
+            }
+            assert_eq!(emitted(&transformer, *evicted), 0.0);
+            assert!(emitted(&transformer, *kept) > 0.0);
+            assert!(transformer.report_metrics().unwrap().starts_with(
+                "keys: 2, lookups: 2, hit rate: 0.5000, evictions: 1, expirations: 0"
+            ));
+        }
+
+        // Keys not updated for more than ttl examples count as zero and are dropped
+        let transformer = create(vec![100.0, 10.0, 2.0]).unwrap();
+        transformer.observe_record(&record(5), &transform_executors);
+        transformer.observe_record(&record(6), &transform_executors);
+        assert!(emitted(&transformer, 5) > 0.0);
+        transformer.observe_record(&record(6), &transform_executors);
+        assert_eq!(emitted(&transformer, 5), 0.0);
+        assert!(emitted(&transformer, 6) > 0.0);
+        assert!(transformer
+            .report_metrics()
+            .unwrap()
+            .ends_with("evictions: 0, expirations: 1"));
+
+        // Metrics are not a part of the saved state
+        let state = transformer.save_state().unwrap();
+        assert!(state.get("metrics").is_none());
+        transformer.load_state(state).unwrap();
+        assert!(transformer
+            .report_metrics()
+            .unwrap()
+            .starts_with("keys: 1, lookups: 0,"));
+    }
+
+    #[test]
+    fn test_transformerrollingcount_shards() {
+        let from_namespace = feature_transform_parser::Namespace {
+            namespace_descriptor: ns_desc(0),
+            namespace_verbose: "a".to_string(),
+        };
+        let to_namespace_empty = ExecutorToNamespace {
+            namespace_descriptor: ns_desc(1),
+            namespace_seeds: default_seeds(1),
+            tmp_data: Vec::new(),
+        };
+        let transform_executors = TransformExecutors { executors: vec![] }; // not used
+        let record = |key: u32| [4, 1, (1.0_f32).to_bits(), key & MASK31];
+        let emitted = |transformer: &Box<dyn FunctionExecutorTrait>, key: u32| {
+            let mut to_namespace = to_namespace_empty.clone();
+            transformer.execute_function(&record(key), &mut to_namespace, &transform_executors);
+            to_namespace.tmp_data[0].1
+        };
+        let create = |max_keys: f32| {
+            TransformerRollingCount::create_function(
+                "RollingCount",
+                &vec![from_namespace.clone()],
+                &vec![100.0, max_keys],
+                false,
+            )
+            .unwrap()
+        };
+
+        // Sixteen shards of 6250 keys, the saved state has the keys of all of them
+        let sharded = create(100000.0);
+        for key in 0..64 {
+            sharded.observe_record(&record(key), &transform_executors);
+        }
+        let state = sharded.save_state().unwrap();
+        assert_eq!(state["counters"].as_object().unwrap().len(), 64);
+        assert!(sharded
+            .report_metrics()
+            .unwrap()
+            .starts_with("keys: 64, lookups: 0,"));
+
+        // Loaded into a single shard, keys count the same
+        let single = create(1000.0);
+        single.load_state(state).unwrap();
+        for key in [0, 17, 63].iter() {
+            assert!(emitted(&sharded, *key) > 0.0);
+            assert_eq!(emitted(&sharded, *key), emitted(&single, *key));
+        }
+        assert_eq!(emitted(&single, 64), 0.0);
+    }
 }
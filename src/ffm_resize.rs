@@ -0,0 +1,179 @@
+use std::error::Error;
+use std::io::Cursor;
+use std::thread;
+
+use crate::block_ffm::BlockFFM;
+use crate::model_instance::{FfmLayout, ModelInstance};
+use crate::optimizer::OptimizerSGD;
+use crate::regressor;
+use crate::regressor::Regressor;
+
+// Growing the FFM weight table of a running daemon (command "ffm_grow"). The table with one more
+// bit of ffm_bit_precision is filled in a background thread of ModelRegistry while the old one is
+// served, then it is published like a loaded model and all worker threads switch to it, together
+// with a translator that masks hashes to the larger table, before their next request. Models
+// trained with the larger table can be hogwild_load-ed and model_load-ed after that.
+
+// Feature hashes are 31 bits, larger tables would stay half empty
+pub const MAX_FFM_BIT_PRECISION: u32 = 31;
+// Weights copied between yielding to the serving threads
+const GROW_CHUNK_LEN: usize = 1 << 20;
+
+pub fn grown_model_instance(mi: &ModelInstance) -> Result<ModelInstance, Box<dyn Error>> {
+    if mi.ffm_k == 0 || mi.ffm_fields.is_empty() {
+        return Err("Model has no FFM weights to grow")?;
+    }
+    if mi.ffm_layout != FfmLayout::FeatureMajor {
+        return Err("Only feature-major FFM tables can grow")?;
+    }
+    if mi.ffm_bit_precision >= MAX_FFM_BIT_PRECISION {
+        return Err(format!(
+            "FFM bit precision {} can't grow any further",
+            mi.ffm_bit_precision
+        ))?;
+    }
+    let mut mi_grown = mi.clone();
+    mi_grown.ffm_bit_precision += 1;
+    Ok(mi_grown)
+}
+
+// A feature's embedding starts at its hash masked to the table size. With one more bit it is
+// either where it was or 1 << ffm_bit_precision further, and both places get the old weights.
+// Weights past the hashed part of the table (embeddings of the highest hashes spill there) are
+// kept in place, so features hashed to the first ffm_fields * ffm_k weights that move up don't
+// find their spilled weights. Predictions of all other features stay exactly the same.
+pub fn grow_ffm_weights(weights: &[f32], grown: &mut [f32], ffm_bit_precision: u32) {
+    let old_hashed_len = 1usize << ffm_bit_precision;
+    for chunk_start in (0..grown.len()).step_by(GROW_CHUNK_LEN) {
+        let chunk_end = (chunk_start + GROW_CHUNK_LEN).min(grown.len());
+        for (i, w) in grown[chunk_start..chunk_end].iter_mut().enumerate() {
+            let i = chunk_start + i;
+            *w = if i < weights.len() {
+                weights[i]
+            } else {
+                weights[i - old_hashed_len]
+            };
+        }
+        thread::yield_now();
+    }
+}
+
+// Inference-only copy of the served regressor with the FFM table of mi_grown
+pub fn grow_regressor(
+    re: &mut Regressor,
+    mi: &ModelInstance,
+    mi_grown: &ModelInstance,
+) -> Result<Regressor, Box<dyn Error>> {
+    let mut rg = re.immutable_regressor_without_weights(mi_grown)?;
+    rg.allocate_and_init_weights(mi_grown);
+    let mut buf: Vec<u8> = Vec::new();
+    for (block, grown_block) in re.blocks_boxes.iter_mut().zip(rg.blocks_boxes.iter_mut()) {
+        if block.get_serialized_block_id() == regressor::SERIALIZED_BLOCK_ID_FFM {
+            let block = match block.as_any().downcast_mut::<BlockFFM<OptimizerSGD>>() {
+                Some(block) => block,
+                None => return Err("Only FFM tables of served models can grow")?,
+            };
+            let grown_block = grown_block
+                .as_any()
+                .downcast_mut::<BlockFFM<OptimizerSGD>>()
+                .unwrap();
+            grow_ffm_weights(
+                &block.weights,
+                &mut grown_block.weights,
+                mi.ffm_bit_precision,
+            );
+        } else {
+            buf.truncate(0);
+            block.write_weights_to_buf(&mut buf, false)?;
+            block.read_weights_from_buf_into_forward_only(
+                &mut Cursor::new(&buf),
+                grown_block,
+                false,
+            )?;
+        }
+    }
+    Ok(rg)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::model_instance::{FeatureComboDesc, Optimizer};
+    use crate::parser::VowpalParser;
+    use crate::vwmap::VwNamespaceMap;
+
+    #[test]
+    fn test_grow_ffm_weights() {
+        // 2 bits of hash and 2 weights that spill over
+        let weights: Vec<f32> = vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+        let mut grown: Vec<f32> = vec![-1.0; 10];
+        grow_ffm_weights(&weights, &mut grown, 2);
+        assert_eq!(
+            grown,
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 2.0, 3.0, 4.0, 5.0]
+        );
+    }
+
+    #[test]
+    fn test_grow_regressor() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let a = vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()];
+        let b = vw.map_vwname_to_namespace_descriptor[&b"B".to_vec()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 10;
+        mi.ffm_fields = vec![vec![a], vec![b]];
+        mi.optimizer = Optimizer::SGD;
+        mi.feature_combo_descs.push(FeatureComboDesc {
+            namespace_descriptors: vec![a],
+            weight: 1.0,
+        });
+        // FFM weights are initialized randomly, that is enough to tell them apart
+        let mut pa = VowpalParser::new(&vw);
+        let mut re = Regressor::new(&mi);
+        let mut re_fixed = re.immutable_regressor(&mi, false).unwrap();
+        let mi_grown = grown_model_instance(&mi).unwrap();
+        assert_eq!(mi_grown.ffm_bit_precision, 11);
+        let re_grown = grow_regressor(&mut re_fixed, &mi, &mi_grown).unwrap();
+
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut fbt_grown = fbt.clone();
+        fbt_grown.set_ffm_bit_precision(11);
+        let mut pb = re_fixed.new_portbuffer();
+        let mut pb_grown = re_grown.new_portbuffer();
+        let mut moved = 0;
+        for i in 0..20 {
+            let line = format!("1 |A a{} |B b{}\n", i, i % 3);
+            let record = pa.next_vowpal_from_bytes(line.as_bytes()).unwrap().to_vec();
+            fbt.translate(&record, 0);
+            fbt_grown.translate(&record, 0);
+            moved += fbt_grown
+                .feature_buffer
+                .ffm_buffer
+                .iter()
+                .filter(|f| f.hash >= 1 << 10)
+                .count();
+            assert_eq!(
+                re_grown.predict(&fbt_grown.feature_buffer, &mut pb_grown),
+                re_fixed.predict(&fbt.feature_buffer, &mut pb)
+            );
+        }
+        assert!(moved > 0);
+
+        mi.ffm_bit_precision = MAX_FFM_BIT_PRECISION;
+        assert!(grown_model_instance(&mi).is_err());
+        mi.ffm_bit_precision = 10;
+        mi.ffm_layout = FfmLayout::FieldMajor;
+        assert!(grown_model_instance(&mi).is_err());
+        mi.ffm_layout = FfmLayout::FeatureMajor;
+        mi.ffm_k = 0;
+        assert!(grown_model_instance(&mi).is_err());
+    }
+}
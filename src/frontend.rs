@@ -0,0 +1,218 @@
+use std::error::Error;
+use std::io;
+use std::io::{Read, Write};
+use std::iter;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+
+use crate::rate_limit::ConnectionLimiter;
+use crate::signals;
+use crate::tls::TlsStream;
+
+// Frontend of the daemon: a single thread owns the listeners and all connections that wait for
+// their next request, and waits for all of them at once with poll(2). Connections with something
+// to read go to the worker threads through a bounded channel, so the frontend stops accepting
+// when all workers are busy. Workers stay synchronous: they serve a connection until it has
+// nothing more buffered and hand it back. Thousands of mostly idle keep-alive connections then
+// hold a pollfd each instead of a worker thread.
+
+const POLL_TIMEOUT_MS: libc::c_int = 100; // how often SIGTERM is looked for
+
+pub struct Connection {
+    pub tcp_stream: TcpStream,
+    pub http: bool,
+    // Set up by the first worker that serves the connection, kept while it is idle
+    pub started: bool,
+    pub tls_stream: Option<TlsStream>,
+    pub limiter: Option<ConnectionLimiter>,
+}
+
+impl Connection {
+    pub fn new(tcp_stream: TcpStream, http: bool) -> Connection {
+        Connection {
+            tcp_stream,
+            http,
+            started: false,
+            tls_stream: None,
+            limiter: None,
+        }
+    }
+}
+
+// Worker threads hand idle connections back through it
+pub struct FrontendHandle {
+    returned: mpsc::Sender<Connection>,
+    wake: UnixStream,
+}
+
+impl FrontendHandle {
+    pub fn try_clone(&self) -> io::Result<FrontendHandle> {
+        Ok(FrontendHandle {
+            returned: self.returned.clone(),
+            wake: self.wake.try_clone()?,
+        })
+    }
+
+    // False when the frontend is gone since the daemon is shutting down
+    pub fn hand_back(&self, connection: Connection) -> bool {
+        if self.returned.send(connection).is_err() {
+            return false;
+        }
+        // The socket is non-blocking, when it is full the frontend is woken up anyway
+        let _ = (&self.wake).write(&[0]);
+        true
+    }
+}
+
+pub struct Frontend {
+    returned: mpsc::Receiver<Connection>,
+    wake: UnixStream,
+    idle: Vec<Connection>,
+}
+
+impl Frontend {
+    pub fn new() -> io::Result<(Frontend, FrontendHandle)> {
+        let (returned_sender, returned) = mpsc::channel();
+        let (wake_read, wake_write) = UnixStream::pair()?;
+        wake_read.set_nonblocking(true)?;
+        wake_write.set_nonblocking(true)?;
+        let frontend = Frontend {
+            returned,
+            wake: wake_read,
+            idle: Vec::new(),
+        };
+        let handle = FrontendHandle {
+            returned: returned_sender,
+            wake: wake_write,
+        };
+        Ok((frontend, handle))
+    }
+
+    fn accept(&mut self, listener: &TcpListener, http: bool) {
+        loop {
+            match listener.accept() {
+                Ok((tcp_stream, _)) => match tcp_stream.set_nonblocking(false) {
+                    Ok(_) => self.idle.push(Connection::new(tcp_stream, http)),
+                    Err(e) => log::warn!("Setting up accepted connection failed: {}", e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    log::warn!("Accepting connection failed: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Listeners come with whether they are of --http_port. Returns after SIGTERM, the idle
+    // connections are dropped, those passed to workers are answered
+    pub fn run(
+        mut self,
+        listeners: &[(TcpListener, bool)],
+        workers: &mpsc::SyncSender<Connection>,
+    ) -> Result<(), Box<dyn Error>> {
+        for (listener, _) in listeners.iter() {
+            listener.set_nonblocking(true)?;
+        }
+        let mut pollfds: Vec<libc::pollfd> = Vec::new();
+        let mut wake_buf = [0u8; 64];
+        while !signals::shutdown_requested() {
+            while let Ok(connection) = self.returned.try_recv() {
+                self.idle.push(connection);
+            }
+            pollfds.clear();
+            let fds = listeners
+                .iter()
+                .map(|(listener, _)| listener.as_raw_fd())
+                .chain(iter::once(self.wake.as_raw_fd()))
+                .chain(self.idle.iter().map(|c| c.tcp_stream.as_raw_fd()));
+            for fd in fds {
+                pollfds.push(libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            }
+            // Safe since pollfds is valid for the length given
+            let ready = unsafe {
+                libc::poll(
+                    pollfds.as_mut_ptr(),
+                    pollfds.len() as libc::nfds_t,
+                    POLL_TIMEOUT_MS,
+                )
+            };
+            if ready < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Box::new(e));
+            }
+            if ready == 0 {
+                continue;
+            }
+            while let Ok(n) = (&self.wake).read(&mut wake_buf) {
+                if n == 0 {
+                    break;
+                }
+            }
+
+            // Readable, or closed by the client, which the worker finds out
+            let revents = &pollfds[(listeners.len() + 1)..];
+            let mut still_idle = Vec::with_capacity(self.idle.len());
+            for (connection, pollfd) in self.idle.drain(..).zip(revents.iter()) {
+                if pollfd.revents == 0 {
+                    still_idle.push(connection);
+                } else {
+                    workers.send(connection)?;
+                }
+            }
+            self.idle = still_idle;
+
+            for (i, (listener, http)) in listeners.iter().enumerate() {
+                if pollfds[i].revents != 0 {
+                    self.accept(listener, *http);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_frontend() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (frontend, handle) = Frontend::new().unwrap();
+        let (sender, receiver) = mpsc::sync_channel(1);
+        thread::spawn(move || frontend.run(&[(listener, true)], &sender).unwrap());
+
+        // Connections go to workers once they have something to read
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"|A a\n").unwrap();
+        let connection = receiver.recv().unwrap();
+        assert!(connection.http);
+        assert!(!connection.started);
+        let mut line = [0u8; 5];
+        (&connection.tcp_stream).read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"|A a\n");
+
+        // and again after they were handed back and got the next request
+        let mut connection = connection;
+        connection.started = true;
+        assert!(handle.hand_back(connection));
+        assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+        client.write_all(b"|A b\n").unwrap();
+        let connection = receiver.recv().unwrap();
+        assert!(connection.started);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::buffer_handler::create_buffered_input;
+use crate::feature_buffer::FeatureBufferTranslator;
+use crate::model_instance::ModelInstance;
+use crate::parser;
+use crate::parser::VowpalParser;
+use crate::persistence;
+use crate::regressor::Regressor;
+use crate::vwmap::VwNamespaceMap;
+
+// How many past evaluations the daemon keeps for the "stats" command
+pub const GOLDEN_HISTORY_LEN: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GoldenMetrics {
+    pub examples: usize,
+    pub logloss: f64,
+    pub auc: f64,
+}
+
+impl fmt::Display for GoldenMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "examples:{} logloss:{:.6} auc:{:.6}",
+            self.examples, self.logloss, self.auc
+        )
+    }
+}
+
+// Area under ROC curve, ties in predictions count as half ordered correctly.
+// NaN when there are no positive or no negative examples.
+pub fn auc(predictions_and_labels: &mut [(f32, bool)]) -> f64 {
+    predictions_and_labels.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let mut positive_rank_sum = 0.0;
+    let mut positives = 0;
+    let mut i = 0;
+    while i < predictions_and_labels.len() {
+        let mut j = i;
+        while j < predictions_and_labels.len()
+            && predictions_and_labels[j].0 == predictions_and_labels[i].0
+        {
+            j += 1;
+        }
+        // Ranks i + 1 ..= j share the average rank
+        let average_rank = (i + 1 + j) as f64 / 2.0;
+        for (_, label) in predictions_and_labels[i..j].iter() {
+            if *label {
+                positive_rank_sum += average_rank;
+                positives += 1;
+            }
+        }
+        i = j;
+    }
+    let negatives = predictions_and_labels.len() - positives;
+    if positives == 0 || negatives == 0 {
+        return f64::NAN;
+    }
+    let positives = positives as f64;
+    (positive_rank_sum - positives * (positives + 1.0) / 2.0) / (positives * negatives as f64)
+}
+
+// Collects predictions of labeled examples for GoldenMetrics
+#[derive(Default)]
+pub struct MetricsAccumulator {
+    predictions_and_labels: Vec<(f32, bool)>,
+    logloss_sum: f64,
+}
+
+impl MetricsAccumulator {
+    pub fn add(&mut self, prediction: f32, label: bool) {
+        let p = (prediction as f64).clamp(1e-7, 1.0 - 1e-7);
+        self.logloss_sum -= if label { p.ln() } else { (1.0 - p).ln() };
+        self.predictions_and_labels.push((prediction, label));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.predictions_and_labels.is_empty()
+    }
+
+    pub fn metrics(&mut self) -> GoldenMetrics {
+        GoldenMetrics {
+            examples: self.predictions_and_labels.len(),
+            logloss: self.logloss_sum / self.predictions_and_labels.len() as f64,
+            auc: auc(&mut self.predictions_and_labels),
+        }
+    }
+}
+
+// A small pinned labeled validation set that the daemon evaluates the served model on
+// (--golden_set), to keep a history of its quality and to gate hogwild_load of worse models
+pub struct GoldenSet {
+    records: Vec<Vec<u32>>,
+    fbt: Mutex<FeatureBufferTranslator>,
+    pub block_margin: Option<f64>,
+    pub history: Mutex<VecDeque<(u64, GoldenMetrics)>>,
+}
+
+impl GoldenSet {
+    pub fn new_from_filename(
+        filename: &str,
+        mi: &ModelInstance,
+        vw: &VwNamespaceMap,
+        block_margin: Option<f64>,
+    ) -> Result<GoldenSet, Box<dyn Error>> {
+        let mut input = create_buffered_input(filename);
+        let mut pa = VowpalParser::new(vw);
+        let mut records: Vec<Vec<u32>> = Vec::new();
+        loop {
+            let buffer = pa.next_vowpal(&mut input)?;
+            if buffer.is_empty() {
+                break;
+            }
+            if buffer[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK == parser::NO_LABEL {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "All examples of golden set {} need to have labels",
+                        filename
+                    ),
+                )));
+            }
+            parser::check_label(buffer, mi.oaa)?;
+            records.push(buffer.to_vec());
+        }
+        if records.is_empty() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Golden set {} is empty", filename),
+            )));
+        }
+        Ok(GoldenSet {
+            records,
+            fbt: Mutex::new(FeatureBufferTranslator::new(mi)),
+            block_margin,
+            history: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    pub fn evaluate(&self, re: &Regressor) -> GoldenMetrics {
+        let mut fbt = self.fbt.lock().unwrap();
+        let mut pb = re.new_portbuffer();
+        let mut accumulator = MetricsAccumulator::default();
+        for (i, record) in self.records.iter().enumerate() {
+            fbt.translate(record, i as u64);
+            let prediction = re.predict(&fbt.feature_buffer, &mut pb);
+            let label = record[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK == 1;
+            accumulator.add(prediction, label);
+        }
+        accumulator.metrics()
+    }
+
+    // Evaluates the model and adds the result to the history
+    pub fn evaluate_and_record(&self, re: &Regressor) -> GoldenMetrics {
+        let metrics = self.evaluate(re);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut history = self.history.lock().unwrap();
+        if history.len() == GOLDEN_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back((now, metrics));
+        log::info!("Golden set evaluation: {}", metrics);
+        metrics
+    }
+
+    // With --golden_block_margin, models with logloss worse than the served one by more than
+    // the margin are not loaded
+    pub fn check_candidate(
+        &self,
+        current: &Regressor,
+        filename: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.block_margin.is_none() {
+            return Ok(());
+        }
+        let (_, _, candidate) = persistence::new_regressor_from_filename(filename, true, None)?;
+        self.check_candidate_regressor(current, &candidate, filename)
+    }
+
+    // Same for a candidate that is already loaded, filename is only for the message
+    pub fn check_candidate_regressor(
+        &self,
+        current: &Regressor,
+        candidate: &Regressor,
+        filename: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let margin = match self.block_margin {
+            Some(margin) => margin,
+            None => return Ok(()),
+        };
+        let candidate_metrics = self.evaluate(candidate);
+        let current_metrics = self.evaluate(current);
+        if candidate_metrics.logloss > current_metrics.logloss + margin {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Model {} is worse on the golden set than the served one, {} vs {}",
+                    filename, candidate_metrics, current_metrics
+                ),
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn format_history(&self) -> String {
+        let mut s = String::new();
+        for (time, metrics) in self.history.lock().unwrap().iter() {
+            s.push_str(&format!("golden time:{} {}\n", time, metrics));
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::model_instance::{FeatureComboDesc, Optimizer};
+    use crate::regressor;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_auc() {
+        assert_eq!(
+            auc(&mut [(0.1, false), (0.4, true), (0.35, false), (0.8, true)]),
+            1.0
+        );
+        assert_eq!(
+            auc(&mut [(0.9, false), (0.4, true), (0.35, false), (0.2, true)]),
+            0.25
+        );
+        // Ties count as half
+        assert_eq!(auc(&mut [(0.5, false), (0.5, true)]), 0.5);
+        assert_eq!(
+            auc(&mut [(0.1, false), (0.5, false), (0.5, true), (0.7, true)]),
+            0.875
+        );
+        assert!(auc(&mut [(0.5, true), (0.7, true)]).is_nan());
+    }
+
+    #[test]
+    fn test_golden_set() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.optimizer = Optimizer::AdagradFlex;
+        for namespace in ["A", "B"].iter() {
+            mi.feature_combo_descs.push(FeatureComboDesc {
+                namespace_descriptors: vec![
+                    vw.map_vwname_to_namespace_descriptor[namespace.as_bytes()],
+                ],
+                weight: 1.0,
+            });
+        }
+        let dir = tempdir().unwrap();
+        let golden_filename = dir.path().join("golden.vw");
+        let golden_filename = golden_filename.to_str().unwrap();
+        fs::write(golden_filename, "1 |A a |B b\n-1 |A c |B b\n").unwrap();
+
+        // An untrained model predicts 0.5 for everything
+        let mut re = regressor::Regressor::new(&mi);
+        let golden = GoldenSet::new_from_filename(golden_filename, &mi, &vw, Some(0.01)).unwrap();
+        let metrics = golden.evaluate_and_record(&re);
+        assert_eq!(metrics.examples, 2);
+        assert!((metrics.logloss - 2.0f64.ln()).abs() < 1e-6);
+        assert_eq!(metrics.auc, 0.5);
+        assert!(golden
+            .format_history()
+            .ends_with("examples:2 logloss:0.693147 auc:0.500000\n"));
+
+        // A model trained on the golden set is better, one trained on flipped labels is worse
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let mut re_flipped = regressor::Regressor::new(&mi);
+        let mut pa = VowpalParser::new(&vw);
+        for line in ["1 |A a |B b\n", "-1 |A c |B b\n"].iter() {
+            let record = pa.next_vowpal_from_bytes(line.as_bytes()).unwrap().to_vec();
+            fbt.translate(&record, 0);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+            let mut flipped = record.clone();
+            flipped[parser::LABEL_OFFSET] = 1 - flipped[parser::LABEL_OFFSET];
+            fbt.translate(&flipped, 0);
+            re_flipped.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+        let better = dir.path().join("better.fw");
+        let worse = dir.path().join("worse.fw");
+        let better = better.to_str().unwrap();
+        let worse = worse.to_str().unwrap();
+        let current = regressor::Regressor::new(&mi);
+        persistence::save_regressor_to_filename(better, &mi, &vw, re, false).unwrap();
+        persistence::save_regressor_to_filename(worse, &mi, &vw, re_flipped, false).unwrap();
+        assert!(golden.check_candidate(&current, better).is_ok());
+        assert!(golden.check_candidate(&current, worse).is_err());
+
+        fs::write(golden_filename, "|A a\n").unwrap();
+        assert!(GoldenSet::new_from_filename(golden_filename, &mi, &vw, None).is_err());
+    }
+}
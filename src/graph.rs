@@ -27,10 +27,22 @@ pub struct BlockGraph {
     blocks: Vec<Box<dyn BlockTrait>>,
     pub blocks_final: Vec<Box<dyn BlockTrait>>,
     tape_size: usize,
+    tape_allocations: Vec<TapeAllocation>,
+}
+
+// Record of where finalize() placed a single edge on the tape, kept for check_wiring()
+#[derive(Debug)]
+struct TapeAllocation {
+    from: BlockPtrOutput,
+    to: BlockPtrInput,
+    to_block_type: BlockType,
+    offset: usize,
+    len: usize,
+    zero_copy: bool,
 }
 
 // We need to treat join type in a special way - all inputs need to be consequtive
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum BlockType {
     Regular,
     Join,
@@ -96,6 +108,7 @@ impl BlockGraph {
             blocks: Vec::new(),
             blocks_final: Vec::new(),
             tape_size: usize::MAX,
+            tape_allocations: Vec::new(),
         }
     }
 
@@ -186,7 +199,11 @@ impl BlockGraph {
     }
 
     pub fn new_port_buffer(&self) -> port_buffer::PortBuffer {
-        port_buffer::PortBuffer::new(self.get_tape_size())
+        let mut pb = port_buffer::PortBuffer::new(self.get_tape_size());
+        for block in self.blocks_final.iter() {
+            block.init_port_buffer(&mut pb);
+        }
+        pb
     }
     pub fn get_num_input_slots(&self, bp: BlockPtr) -> usize {
         self.nodes[bp.get_node_id()].edges_in.len()
@@ -268,11 +285,27 @@ impl BlockGraph {
                     let fake_offset = self.blocks[bptr].get_input_offset(InputSlot(0)).unwrap();
                     self.blocks[bptr].set_output_offset(bo, fake_offset);
                     self.blocks[i].set_input_offset(InputSlot(input_index), fake_offset);
+                    self.tape_allocations.push(TapeAllocation {
+                        from: BlockPtrOutput(BlockPtr(bptr), bo),
+                        to: BlockPtrInput(BlockPtr(i), InputSlot(input_index)),
+                        to_block_type: current_block_type,
+                        offset: fake_offset,
+                        len: output_len,
+                        zero_copy: true,
+                    });
                 } else if (input_block_type == BlockType::Regular)
                     || (input_block_type == BlockType::Copy)
                 {
                     self.blocks[bptr].set_output_offset(bo, offset);
                     self.blocks[i].set_input_offset(InputSlot(input_index), offset);
+                    self.tape_allocations.push(TapeAllocation {
+                        from: BlockPtrOutput(BlockPtr(bptr), bo),
+                        to: BlockPtrInput(BlockPtr(i), InputSlot(input_index)),
+                        to_block_type: current_block_type,
+                        offset,
+                        len: output_len,
+                        zero_copy: false,
+                    });
                     offset += output_len;
                 } else {
                     panic!(
@@ -292,6 +325,135 @@ impl BlockGraph {
             }
         }
     }
+
+    // Validates the tape layout created by finalize(). Blocks access the tape without bounds checks,
+    // so wiring bugs would otherwise only show up as garbage predictions
+    pub fn check_wiring(&self) -> Result<(), Box<dyn Error>> {
+        if self.tape_size == usize::MAX {
+            return Err("Graph wiring check called before finalize()".to_string())?;
+        }
+        let mut errors: Vec<String> = Vec::new();
+
+        // every consumed slot has to be produced by the block it claims to come from, and vice versa
+        for (i, node) in self.nodes.iter().enumerate() {
+            for (input_index, edge_in) in node.edges_in.iter().enumerate() {
+                let expected = BlockPtrInput(BlockPtr(i), InputSlot(input_index));
+                match self.nodes[edge_in.get_node_id()]
+                    .edges_out
+                    .get(edge_in.get_output_index())
+                {
+                    Some(edge_out) if *edge_out == expected => {}
+                    Some(edge_out) => errors.push(format!(
+                        "Node {} input {} reads output {} of node {}, but that output is wired to {:?}",
+                        i,
+                        input_index,
+                        edge_in.get_output_index(),
+                        edge_in.get_node_id(),
+                        edge_out
+                    )),
+                    None => errors.push(format!(
+                        "Node {} input {} reads output {} of node {}, which was never produced",
+                        i,
+                        input_index,
+                        edge_in.get_output_index(),
+                        edge_in.get_node_id()
+                    )),
+                }
+            }
+            for (output_index, edge_out) in node.edges_out.iter().enumerate() {
+                if *edge_out == BLOCK_PTR_INPUT_DEFAULT {
+                    errors.push(format!(
+                        "Node {} output {} is not consumed by any block",
+                        i, output_index
+                    ));
+                }
+            }
+        }
+
+        let wired_inputs: usize = self.nodes.iter().map(|n| n.edges_in.len()).sum();
+        if wired_inputs != self.tape_allocations.len() {
+            errors.push(format!(
+                "Graph has {} wired inputs, but only {} of them were placed on the tape",
+                wired_inputs,
+                self.tape_allocations.len()
+            ));
+        }
+
+        for a in self.tape_allocations.iter() {
+            if a.offset == usize::MAX || a.offset + a.len > self.tape_size {
+                errors.push(format!(
+                    "Edge {:?} -> {:?} occupies tape [{}, {}), which is outside of tape of size {}",
+                    a.from,
+                    a.to,
+                    a.offset,
+                    a.offset.wrapping_add(a.len),
+                    self.tape_size
+                ));
+            }
+        }
+
+        // Freshly allocated outputs must never share the tape, only zero-copy edges can
+        let mut fresh: Vec<&TapeAllocation> = self
+            .tape_allocations
+            .iter()
+            .filter(|a| !a.zero_copy && a.len > 0)
+            .collect();
+        fresh.sort_by_key(|a| a.offset);
+        for pair in fresh.windows(2) {
+            if pair[0].offset + pair[0].len > pair[1].offset {
+                errors.push(format!(
+                    "Edges {:?} -> {:?} at [{}, {}) and {:?} -> {:?} at [{}, {}) overlap on the tape",
+                    pair[0].from,
+                    pair[0].to,
+                    pair[0].offset,
+                    pair[0].offset + pair[0].len,
+                    pair[1].from,
+                    pair[1].to,
+                    pair[1].offset,
+                    pair[1].offset + pair[1].len
+                ));
+            }
+        }
+
+        // Join is zero-copy, so all its inputs have to be laid out consecutively
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut join_inputs: Vec<&TapeAllocation> = self
+                .tape_allocations
+                .iter()
+                .filter(|a| a.to.get_node_id() == i && a.to_block_type == BlockType::Join)
+                .collect();
+            join_inputs.sort_by_key(|a| a.to.get_input_index());
+            debug_assert!(join_inputs.is_empty() || join_inputs.len() == node.edges_in.len());
+            for pair in join_inputs.windows(2) {
+                if pair[0].offset + pair[0].len != pair[1].offset {
+                    errors.push(format!(
+                        "Join node {} inputs {} at [{}, {}) and {} at [{}, {}) are not consecutive on the tape",
+                        i,
+                        pair[0].to.get_input_index(),
+                        pair[0].offset,
+                        pair[0].offset + pair[0].len,
+                        pair[1].to.get_input_index(),
+                        pair[1].offset,
+                        pair[1].offset + pair[1].len
+                    ));
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(format!(
+                "Graph wiring check found {} problem(s):\n  {}",
+                errors.len(),
+                errors.join("\n  ")
+            ))?;
+        }
+        log::info!(
+            "Graph wiring check passed: {} edges on a tape of size {}",
+            self.tape_allocations.len(),
+            self.tape_size
+        );
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -513,6 +675,7 @@ mod tests {
                                                                                                   // this is zero copy
         let _join_2 = block_misc::new_join_block(&mut bg, vec![const_4, copy_output_2]); // 7
         bg.finalize();
+        bg.check_wiring().unwrap();
         let mut list = bg.take_blocks();
 
         {
@@ -553,7 +716,30 @@ mod tests {
         assert_eq!(bg.nodes[4].edges_in.len(), 3); // now fourth block has 3 inputs, not 2
 
         bg.finalize();
+        bg.check_wiring().unwrap();
         let list = bg.take_blocks();
         assert_eq!(list.len(), 4); // both join blocks are no-op and thus not returned, but sink block is added automatically
     }
+
+    #[test]
+    fn check_wiring_diagnostics() {
+        let mut bg = BlockGraph::new();
+        assert!(bg.check_wiring().is_err()); // not finalized yet
+
+        let const_1 = block_misc::new_const_block(&mut bg, vec![1.0, 2.0]).unwrap();
+        let const_2 = block_misc::new_const_block(&mut bg, vec![3.0]).unwrap();
+        let _join = block_misc::new_join_block(&mut bg, vec![const_1, const_2]).unwrap();
+        bg.finalize();
+        bg.check_wiring().unwrap();
+
+        // Pretend the scheduler placed the second join input over the first one
+        bg.tape_allocations[1].offset = 1;
+        let err = bg.check_wiring().unwrap_err().to_string();
+        assert!(err.contains("overlap on the tape"), "{}", err);
+        assert!(err.contains("are not consecutive on the tape"), "{}", err);
+
+        bg.tape_allocations[1].offset = bg.tape_size;
+        let err = bg.check_wiring().unwrap_err().to_string();
+        assert!(err.contains("outside of tape of size 3"), "{}", err);
+    }
 }
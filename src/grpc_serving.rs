@@ -0,0 +1,429 @@
+use std::error::Error;
+use std::net;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::StreamExt;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::http_serving;
+use crate::serving::{HogwildLoadError, RequestError, WorkerThread};
+use crate::signals;
+
+// gRPC frontend of the daemon (--grpc_port), serving proto/serving.proto. The server runs on a
+// tokio runtime of its own thread, requests are handed to worker threads of their own, like
+// connections of the TCP protocol, and answered by the same code as those of --http_port.
+
+pub mod proto {
+    tonic::include_proto!("fw.serving");
+}
+
+use proto::serving_server::{Serving, ServingServer};
+use proto::{
+    Example, FlushRequest, FlushResponse, HogwildLoadRequest, HogwildLoadResponse, PredictRequest,
+    PredictResponse, PredictionStats,
+};
+
+const SHUTDOWN_POLL_MS: u64 = 100;
+const STREAM_QUEUE_LEN: usize = 16; // responses of a stream not yet sent to the client
+
+// Workers that are not serving a request
+struct WorkerPool {
+    sender: Mutex<mpsc::Sender<WorkerThread>>,
+    receiver: Mutex<mpsc::Receiver<WorkerThread>>,
+}
+
+impl WorkerPool {
+    fn new(workers: Vec<WorkerThread>) -> WorkerPool {
+        let (sender, receiver) = mpsc::channel();
+        for worker in workers {
+            sender.send(worker).unwrap();
+        }
+        WorkerPool {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    // Waits for a free worker, on a thread that may block
+    fn serve<T>(&self, f: impl FnOnce(&mut WorkerThread) -> T) -> T {
+        let mut worker = self.receiver.lock().unwrap().recv().unwrap();
+        let result = f(&mut worker);
+        self.sender.lock().unwrap().send(worker).unwrap();
+        result
+    }
+}
+
+struct GrpcService {
+    workers: Arc<WorkerPool>,
+}
+
+impl GrpcService {
+    async fn serve<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut WorkerThread) -> T + Send + 'static,
+    ) -> Result<T, Status> {
+        let workers = Arc::clone(&self.workers);
+        tokio::task::spawn_blocking(move || workers.serve(f))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+
+    async fn predict(
+        &self,
+        client: Option<net::IpAddr>,
+        request: PredictRequest,
+    ) -> Result<PredictResponse, Status> {
+        let lines = request
+            .examples
+            .iter()
+            .map(example_to_vw_line)
+            .collect::<Result<Vec<String>, Box<dyn Error>>>()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let predictions = self
+            .serve(move |worker| worker.predict_request(client, &lines))
+            .await?
+            .map_err(|e| match e {
+                RequestError::TooLarge(e) => Status::invalid_argument(e),
+                RequestError::RateLimited => Status::resource_exhausted("rate limited"),
+                RequestError::BadExample(e) => Status::invalid_argument(e),
+            })?;
+        let stats = if request.with_stats {
+            let stats = http_serving::PredictionStats::new(&predictions);
+            Some(PredictionStats {
+                count: stats.count as u64,
+                mean: stats.mean,
+                min: stats.min,
+                max: stats.max,
+            })
+        } else {
+            None
+        };
+        Ok(PredictResponse { predictions, stats })
+    }
+}
+
+#[tonic::async_trait]
+impl Serving for GrpcService {
+    async fn predict(
+        &self,
+        request: Request<PredictRequest>,
+    ) -> Result<Response<PredictResponse>, Status> {
+        let client = request.remote_addr().map(|a| a.ip());
+        let response = GrpcService::predict(self, client, request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    type PredictStreamStream = ReceiverStream<Result<PredictResponse, Status>>;
+
+    async fn predict_stream(
+        &self,
+        request: Request<Streaming<PredictRequest>>,
+    ) -> Result<Response<Self::PredictStreamStream>, Status> {
+        let client = request.remote_addr().map(|a| a.ip());
+        let mut requests = request.into_inner();
+        let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_QUEUE_LEN);
+        let service = GrpcService {
+            workers: Arc::clone(&self.workers),
+        };
+        tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                let response = match request {
+                    Ok(request) => service.predict(client, request).await,
+                    Err(e) => Err(e),
+                };
+                if sender.send(response).await.is_err() {
+                    break; // the client is gone
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn flush(
+        &self,
+        _request: Request<FlushRequest>,
+    ) -> Result<Response<FlushResponse>, Status> {
+        self.serve(|worker| worker.flush_prediction_log())
+            .await?
+            .map_err(|e| Status::internal(format!("Flushing prediction log failed: {}", e)))?;
+        Ok(Response::new(FlushResponse {}))
+    }
+
+    async fn hogwild_load(
+        &self,
+        request: Request<HogwildLoadRequest>,
+    ) -> Result<Response<HogwildLoadResponse>, Status> {
+        let filename = request.into_inner().filename;
+        self.serve(move |worker| worker.hogwild_load(&filename))
+            .await?
+            .map_err(|e| match e {
+                HogwildLoadError::Rejected(e) => {
+                    Status::failed_precondition(format!("hogwild_load rejected: {}", e))
+                }
+                HogwildLoadError::Failed(e) => {
+                    Status::internal(format!("hogwild_load fail: {}", e))
+                }
+            })?;
+        Ok(Response::new(HogwildLoadResponse {}))
+    }
+}
+
+// An example as a VW line, with the label when it is given
+pub fn example_to_vw_line(example: &Example) -> Result<String, Box<dyn Error>> {
+    if example.namespaces.is_empty() {
+        return Err("Example has to have namespaces")?;
+    }
+    let mut line = match example.label {
+        Some(label) if label == 1.0 || label == -1.0 => format!("{} ", label),
+        Some(label) => return Err(format!("Label {} has to be 1 or -1", label))?,
+        None => String::new(),
+    };
+    for namespace in &example.namespaces {
+        http_serving::push_namespace(&mut line, &namespace.name)?;
+        for (feature, value) in &namespace.features {
+            http_serving::push_feature(&mut line, feature, *value)?;
+        }
+        line.push(' ');
+    }
+    line.push('\n');
+    Ok(line)
+}
+
+pub struct GrpcServer {
+    listener: net::TcpListener,
+    workers: Arc<WorkerPool>,
+}
+
+impl GrpcServer {
+    // Binds when the daemon starts, so a taken port fails the start rather than the server thread
+    pub fn bind(
+        listening_interface: &str,
+        workers: Vec<WorkerThread>,
+    ) -> Result<GrpcServer, Box<dyn Error>> {
+        let listener = net::TcpListener::bind(listening_interface)?;
+        listener.set_nonblocking(true)?;
+        Ok(GrpcServer {
+            listener,
+            workers: Arc::new(WorkerPool::new(workers)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<net::SocketAddr, Box<dyn Error>> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Serves until shutdown is requested, then answers the RPCs in flight and returns
+    pub fn start(self) -> thread::JoinHandle<u32> {
+        thread::spawn(move || {
+            if let Err(e) = self.run() {
+                log::warn!("gRPC server failed: {}", e);
+            }
+            1u32
+        })
+    }
+
+    fn run(self) -> Result<(), Box<dyn Error>> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(self.listener)?;
+            let service = GrpcService {
+                workers: self.workers,
+            };
+            tonic::transport::Server::builder()
+                .add_service(ServingServer::new(service))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    while !signals::shutdown_requested() {
+                        tokio::time::sleep(Duration::from_millis(SHUTDOWN_POLL_MS)).await;
+                    }
+                })
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::evaluation::Evaluations;
+    use crate::explain::Explainer;
+    use crate::feature_buffer;
+    use crate::model_instance;
+    use crate::model_registry::{ModelRegistry, ModelVersion};
+    use crate::multithread_helpers::BoxedRegressorTrait;
+    use crate::parser;
+    use crate::persistence;
+    use crate::regressor;
+    use crate::vwmap;
+    use proto::serving_client::ServingClient;
+    use proto::Namespace;
+    use std::collections::BTreeMap;
+    use tempfile::tempdir;
+
+    fn example(label: Option<f32>, namespaces: &[(&str, &[(&str, f32)])]) -> Example {
+        Example {
+            namespaces: namespaces
+                .iter()
+                .map(|(name, features)| Namespace {
+                    name: name.to_string(),
+                    features: features
+                        .iter()
+                        .map(|(feature, value)| (feature.to_string(), *value))
+                        .collect::<BTreeMap<String, f32>>(),
+                })
+                .collect(),
+            label,
+        }
+    }
+
+    #[test]
+    fn test_example_to_vw_line() {
+        assert_eq!(
+            example_to_vw_line(&example(
+                None,
+                &[("A", &[("b", 2.5), ("a", 1.0)]), ("B", &[])]
+            ))
+            .unwrap(),
+            "|A a b:2.5 |B \n"
+        );
+        assert_eq!(
+            example_to_vw_line(&example(Some(-1.0), &[("A", &[("a", 1.0)])])).unwrap(),
+            "-1 |A a \n"
+        );
+        assert!(example_to_vw_line(&example(Some(0.5), &[("A", &[("a", 1.0)])])).is_err());
+        assert!(example_to_vw_line(&example(None, &[])).is_err());
+        assert!(example_to_vw_line(&example(None, &[("A|B", &[("a", 1.0)])])).is_err());
+        assert!(example_to_vw_line(&example(None, &[("A", &[("a:b", 1.0)])])).is_err());
+    }
+
+    #[test]
+    fn test_grpc_serving() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("model.fw").to_str().unwrap().to_owned();
+        persistence::save_regressor_to_filename(
+            &filename,
+            &mi,
+            &vw,
+            regressor::Regressor::new(&mi),
+            false,
+        )
+        .unwrap();
+
+        let mut re = regressor::Regressor::new(&mi);
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_fixed =
+            BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi, false).unwrap()));
+        let pb = re_fixed.new_portbuffer();
+        let worker = WorkerThread::new(
+            1,
+            re_fixed,
+            feature_buffer::FeatureBufferTranslator::new(&mi),
+            parser::VowpalParser::new(&vw),
+            pb,
+            None,
+            None,
+            None,
+            None,
+            Arc::new(Explainer::new(&mi, &vw, 2).unwrap()),
+            None,
+            None,
+            None,
+            Arc::new(Evaluations::new()),
+            Arc::new(ModelRegistry::new(None, None, None, None)),
+            Arc::new(ModelVersion {
+                generation: 0,
+                filename: "model.fw".to_string(),
+                checksum: 0,
+            }),
+            2,
+            None,
+            None,
+        );
+        let server = GrpcServer::bind("127.0.0.1:0", vec![worker]).unwrap();
+        let address = format!("http://{}", server.local_addr().unwrap());
+        server.start();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let channel = tonic::transport::Endpoint::from_shared(address)
+                .unwrap()
+                .connect()
+                .await
+                .unwrap();
+            let mut client = ServingClient::new(channel);
+            let a = example(Some(1.0), &[("A", &[("a", 1.0)])]);
+            let response = client
+                .predict(PredictRequest {
+                    examples: vec![a.clone(), example(None, &[("B", &[("b", 2.0)])])],
+                    with_stats: true,
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.predictions, vec![0.5, 0.5]);
+            assert_eq!(response.stats.unwrap().count, 2);
+
+            let status = client
+                .predict(PredictRequest {
+                    examples: vec![a.clone(), a.clone(), a.clone()],
+                    with_stats: false,
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            assert!(status.message().contains("--max_batch 2"));
+            let status = client
+                .predict(PredictRequest {
+                    examples: vec![example(Some(2.0), &[("A", &[("a", 1.0)])])],
+                    with_stats: false,
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+            // One response per request, in order
+            let requests = tokio_stream::iter(vec![
+                PredictRequest {
+                    examples: vec![a.clone()],
+                    with_stats: false,
+                },
+                PredictRequest {
+                    examples: vec![a.clone(), a.clone()],
+                    with_stats: false,
+                },
+            ]);
+            let mut responses = client.predict_stream(requests).await.unwrap().into_inner();
+            let mut lengths = Vec::new();
+            while let Some(response) = responses.next().await {
+                lengths.push(response.unwrap().predictions.len());
+            }
+            assert_eq!(lengths, vec![1, 2]);
+
+            client.flush(FlushRequest {}).await.unwrap();
+            client
+                .hogwild_load(HogwildLoadRequest {
+                    filename: filename.clone(),
+                })
+                .await
+                .unwrap();
+            let status = client
+                .hogwild_load(HogwildLoadRequest {
+                    filename: dir.path().join("missing.fw").to_str().unwrap().to_owned(),
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Internal);
+        });
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+
+use crate::feature_buffer::FeatureBuffer;
+use crate::model_instance::ModelInstance;
+
+// Saturation at which we suggest raising bit precision, if not given with --hash_saturation_warning
+pub const DEFAULT_SATURATION_WARNING: f64 = 0.5;
+
+// Bitmap of hash buckets that were touched at least once
+#[derive(Clone, Debug)]
+pub struct TouchedBitmap {
+    bits: Vec<u64>,
+    pub len: usize,
+    pub touched: usize,
+}
+
+impl TouchedBitmap {
+    pub fn new(len: usize) -> TouchedBitmap {
+        TouchedBitmap {
+            bits: vec![0; len / 64 + 1],
+            len,
+            touched: 0,
+        }
+    }
+
+    // Returns true if the bucket was not touched before
+    #[inline(always)]
+    pub fn touch(&mut self, index: usize) -> bool {
+        let word = &mut self.bits[index / 64];
+        let mask = 1u64 << (index % 64);
+        if *word & mask != 0 {
+            return false;
+        }
+        *word |= mask;
+        self.touched += 1;
+        true
+    }
+
+    pub fn is_touched(&self, index: usize) -> bool {
+        self.bits[index / 64] & (1u64 << (index % 64)) != 0
+    }
+
+    pub fn fraction(&self) -> f64 {
+        if self.len == 0 {
+            return 0.0;
+        }
+        self.touched as f64 / self.len as f64
+    }
+
+    // Number of distinct features that would on average touch this many buckets:
+    // n features hashed into m buckets touch m * (1 - exp(-n / m)) of them
+    pub fn estimate_distinct_features(&self) -> f64 {
+        if self.touched >= self.len {
+            return f64::INFINITY;
+        }
+        -(self.len as f64) * (1.0 - self.fraction()).ln()
+    }
+
+    // Estimated fraction of distinct features that share their bucket with another feature
+    pub fn estimate_collision_rate(&self) -> f64 {
+        if self.touched == 0 {
+            return 0.0;
+        }
+        1.0 - self.touched as f64 / self.estimate_distinct_features()
+    }
+}
+
+// Tracks which of the LR and FFM hash buckets the training data touches (--hash_usage).
+// For FFM a bucket is the block of weights of one feature hash, which spans all fields and k.
+#[derive(Clone, Debug)]
+pub struct HashSpaceUsage {
+    pub lr: TouchedBitmap,
+    pub ffm: TouchedBitmap,
+    ffm_bits_for_dimensions: u32,
+    pub examples: u64,
+    pub lr_features: u64,
+    pub ffm_features: u64,
+}
+
+impl HashSpaceUsage {
+    pub fn new(mi: &ModelInstance) -> HashSpaceUsage {
+        let mut ffm_bits_for_dimensions = 0;
+        while mi.ffm_k > (1 << ffm_bits_for_dimensions) {
+            ffm_bits_for_dimensions += 1;
+        }
+        let ffm_buckets = if mi.ffm_fields.is_empty() {
+            0
+        } else {
+            1 << mi.ffm_bit_precision.saturating_sub(ffm_bits_for_dimensions)
+        };
+        HashSpaceUsage {
+            lr: TouchedBitmap::new(1 << mi.bit_precision),
+            ffm: TouchedBitmap::new(ffm_buckets),
+            ffm_bits_for_dimensions,
+            examples: 0,
+            lr_features: 0,
+            ffm_features: 0,
+        }
+    }
+
+    pub fn observe(&mut self, fb: &FeatureBuffer) {
+        self.examples += 1;
+        self.lr_features += fb.lr_buffer.len() as u64;
+        for feature in fb.lr_buffer.iter() {
+            self.lr.touch(feature.hash as usize);
+        }
+        if self.ffm.len > 0 {
+            self.ffm_features += fb.ffm_buffer.len() as u64;
+            for feature in fb.ffm_buffer.iter() {
+                self.ffm
+                    .touch((feature.hash >> self.ffm_bits_for_dimensions) as usize);
+            }
+        }
+    }
+
+    // Logs the usage and warns about hash spaces more saturated than the threshold
+    pub fn report(&self, saturation_warning: f64) {
+        log::info!("Hash space usage: {}", self);
+        if self.lr.fraction() > saturation_warning {
+            log::warn!(
+                "LR hash space is {:.1}% saturated, consider increasing --bit_precision",
+                self.lr.fraction() * 100.0
+            );
+        }
+        if self.ffm.fraction() > saturation_warning {
+            log::warn!(
+                "FFM hash space is {:.1}% saturated, consider increasing --ffm_bit_precision",
+                self.ffm.fraction() * 100.0
+            );
+        }
+    }
+}
+
+fn fmt_bitmap(
+    f: &mut fmt::Formatter,
+    name: &str,
+    bitmap: &TouchedBitmap,
+    features: u64,
+    examples: u64,
+) -> fmt::Result {
+    write!(
+        f,
+        "{}: {} of {} buckets touched ({:.2}%), {:.1} features per example, estimated collision rate {:.2}%",
+        name,
+        bitmap.touched,
+        bitmap.len,
+        bitmap.fraction() * 100.0,
+        features as f64 / examples.max(1) as f64,
+        bitmap.estimate_collision_rate() * 100.0
+    )
+}
+
+impl fmt::Display for HashSpaceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_bitmap(f, "lr", &self.lr, self.lr_features, self.examples)?;
+        if self.ffm.len > 0 {
+            write!(f, "; ")?;
+            fmt_bitmap(f, "ffm", &self.ffm, self.ffm_features, self.examples)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::{HashAndValue, HashAndValueAndSeq};
+
+    #[test]
+    fn test_touched_bitmap() {
+        let mut bitmap = TouchedBitmap::new(100);
+        assert!(bitmap.touch(0));
+        assert!(bitmap.touch(99));
+        assert!(!bitmap.touch(99));
+        assert!(bitmap.is_touched(0));
+        assert!(!bitmap.is_touched(64));
+        assert_eq!(bitmap.touched, 2);
+        assert_eq!(bitmap.fraction(), 0.02);
+        assert_eq!(TouchedBitmap::new(10).estimate_collision_rate(), 0.0);
+
+        // Half of the buckets touched means ln(2) * len distinct features
+        let mut bitmap = TouchedBitmap::new(1000);
+        for i in 0..500 {
+            bitmap.touch(i);
+        }
+        assert!((bitmap.estimate_distinct_features() - 693.147).abs() < 0.01);
+        assert!((bitmap.estimate_collision_rate() - 0.2787).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_hash_space_usage() {
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 4;
+        mi.ffm_bit_precision = 6;
+        mi.ffm_k = 4;
+        mi.ffm_fields = vec![vec![], vec![]];
+        let mut usage = HashSpaceUsage::new(&mi);
+        assert_eq!(usage.lr.len, 16);
+        assert_eq!(usage.ffm.len, 16);
+
+        let lr = |hash| HashAndValue {
+            hash,
+            value: 1.0,
+            combo_index: 0,
+        };
+        let ffm = |hash| HashAndValueAndSeq {
+            hash,
+            value: 1.0,
+            contra_field_index: 0,
+        };
+        let mut fb = FeatureBuffer {
+            label: 1.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: vec![lr(1), lr(2), lr(3), lr(4)],
+            ffm_buffer: vec![ffm(0), ffm(4), ffm(8)],
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        };
+        usage.observe(&fb);
+        fb.lr_buffer = vec![lr(1), lr(5)];
+        fb.ffm_buffer = vec![ffm(4)];
+        usage.observe(&fb);
+
+        assert_eq!(usage.lr.touched, 5);
+        assert_eq!(usage.ffm.touched, 3);
+        assert!(usage.ffm.is_touched(2));
+        assert_eq!(usage.lr_features, 6);
+        assert_eq!(
+            format!("{}", usage).split(';').next().unwrap(),
+            "lr: 5 of 16 buckets touched (31.25%), 3.0 features per example, estimated collision rate 16.60%"
+        );
+    }
+}
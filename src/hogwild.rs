@@ -1,46 +1,157 @@
-use std::sync::mpsc::{Receiver, SyncSender};
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use crate::feature_buffer::FeatureBufferTranslator;
-use crate::model_instance::ModelInstance;
 use crate::multithread_helpers::BoxedRegressorTrait;
 use crate::port_buffer::PortBuffer;
 
-static CHANNEL_CAPACITY: usize = 100_000;
+pub const DEFAULT_QUEUE_CAPACITY: usize = 100_000;
+
+// What the parser does when the queue of examples for the workers is full (--hogwild_queue_full)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueueFull {
+    Block, // wait for the workers, training is as fast as they are
+    Shed,  // drop the example, it isn't learned from
+}
+
+impl QueueFull {
+    pub fn parse(s: &str) -> Result<QueueFull, Box<dyn Error>> {
+        match s {
+            "block" => Ok(QueueFull::Block),
+            "shed" => Ok(QueueFull::Shed),
+            _ => Err(format!(
+                "Unknown --hogwild_queue_full {}, expected block or shed",
+                s
+            ))?,
+        }
+    }
+}
+
+// Counters of the queue, shared with the workers
+#[derive(Default, Debug)]
+pub struct QueueStats {
+    depth: AtomicUsize, // examples queued, or waiting for room in the queue
+    max_depth: AtomicUsize,
+    queued: AtomicU64,
+    blocked: AtomicU64, // examples that had to wait for room in the queue
+    shed: AtomicU64,
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "queue depth:{} max_depth:{} queued:{} blocked:{} shed:{}",
+            self.depth.load(Ordering::Relaxed),
+            self.max_depth.load(Ordering::Relaxed),
+            self.queued.load(Ordering::Relaxed),
+            self.blocked.load(Ordering::Relaxed),
+            self.shed.load(Ordering::Relaxed)
+        )
+    }
+}
+
+// Why hogwild training didn't finish
+#[derive(Debug, PartialEq)]
+pub enum HogwildError {
+    WorkerPanicked { worker: usize, message: String },
+    // Another worker panicked while it was taking an example from the queue
+    QueuePoisoned,
+    Timeout { unfinished: usize },
+    Stopped, // all workers stopped while examples were still coming
+}
+
+impl Error for HogwildError {}
+impl fmt::Display for HogwildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HogwildError::WorkerPanicked { worker, message } => {
+                write!(f, "Hogwild worker {} panicked: {}", worker, message)
+            }
+            HogwildError::QueuePoisoned => write!(
+                f,
+                "Hogwild workers stopped, the queue of examples is poisoned"
+            ),
+            HogwildError::Timeout { unfinished } => write!(
+                f,
+                "{} hogwild workers didn't finish in time, the model misses their last updates",
+                unfinished
+            ),
+            HogwildError::Stopped => write!(f, "Hogwild workers stopped before training ended"),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Tells the trainer that a worker stopped, also when it panicked
+struct Finished(Sender<()>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
 
 pub struct HogwildTrainer {
-    workers: Vec<JoinHandle<()>>,
+    // Each returns the number of examples it learned from
+    workers: Vec<JoinHandle<Result<u64, HogwildError>>>,
     sender: SyncSender<Vec<u32>>,
+    finished: Receiver<()>,
+    queue_full: QueueFull,
+    stats: Arc<QueueStats>,
 }
 
 pub struct HogwildWorker {
     regressor: BoxedRegressorTrait,
     feature_buffer_translator: FeatureBufferTranslator,
     port_buffer: PortBuffer,
+    stats: Arc<QueueStats>,
 }
 
 impl HogwildTrainer {
     pub fn new(
         sharable_regressor: BoxedRegressorTrait,
-        model_instance: &ModelInstance,
+        feature_buffer_translator: &FeatureBufferTranslator,
         num_workers: u32,
+        queue_capacity: usize,
+        queue_full: QueueFull,
     ) -> HogwildTrainer {
         let (sender, receiver): (SyncSender<Vec<u32>>, Receiver<Vec<u32>>) =
-            mpsc::sync_channel(CHANNEL_CAPACITY);
+            mpsc::sync_channel(queue_capacity);
+        let (finished_sender, finished) = mpsc::channel();
         let mut trainer = HogwildTrainer {
             workers: Vec::with_capacity(num_workers as usize),
             sender,
+            finished,
+            queue_full,
+            stats: Arc::new(QueueStats::default()),
         };
         let receiver: Arc<Mutex<Receiver<Vec<u32>>>> = Arc::new(Mutex::new(receiver));
-        let feature_buffer_translator = FeatureBufferTranslator::new(model_instance);
         let port_buffer = sharable_regressor.new_portbuffer();
+        // Clones of the translator share the state of stateful transforms, e.g. loaded counters
         for _ in 0..num_workers {
             let worker = HogwildWorker::new(
+                finished_sender.clone(),
                 sharable_regressor.clone(),
                 feature_buffer_translator.clone(),
                 port_buffer.clone(),
+                Arc::clone(&trainer.stats),
                 Arc::clone(&receiver),
             );
             trainer.workers.push(worker);
@@ -48,50 +159,134 @@ impl HogwildTrainer {
         trainer
     }
 
-    pub fn digest_example(&self, feature_buffer: Vec<u32>) {
-        self.sender.send(feature_buffer).unwrap();
+    // Fails once all workers stopped, with the error of the first of them
+    pub fn digest_example(&mut self, feature_buffer: Vec<u32>) -> Result<(), HogwildError> {
+        // Counted before it is sent, so that workers never take more than was counted
+        let depth = self.stats.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.stats.max_depth.fetch_max(depth, Ordering::Relaxed);
+        let sent = match self.sender.try_send(feature_buffer) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(feature_buffer)) => match self.queue_full {
+                QueueFull::Block => {
+                    self.stats.blocked.fetch_add(1, Ordering::Relaxed);
+                    self.sender.send(feature_buffer).map_err(|_| ())
+                }
+                QueueFull::Shed => {
+                    self.stats.shed.fetch_add(1, Ordering::Relaxed);
+                    self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+            },
+            Err(TrySendError::Disconnected(_)) => Err(()),
+        };
+        if sent.is_err() {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+            let workers = std::mem::take(&mut self.workers);
+            for (worker, handle) in workers.into_iter().enumerate() {
+                join_worker(worker, handle)?;
+            }
+            return Err(HogwildError::Stopped);
+        }
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn block_until_workers_finished(self) {
+    pub fn stats(&self) -> Arc<QueueStats> {
+        Arc::clone(&self.stats)
+    }
+
+    // Closes the queue, the workers learn from what is left in it and stop. Returns the number of
+    // examples each of them learned from. Workers that don't stop in time are left running
+    pub fn block_until_workers_finished(
+        self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u64>, HogwildError> {
         drop(self.sender);
-        for worker in self.workers {
-            worker.join().unwrap();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        for unfinished in (1..=self.workers.len()).rev() {
+            let finished = match deadline {
+                Some(deadline) => self
+                    .finished
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self
+                    .finished
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            if let Err(RecvTimeoutError::Timeout) = finished {
+                return Err(HogwildError::Timeout { unfinished });
+            }
         }
+        self.workers
+            .into_iter()
+            .enumerate()
+            .map(|(worker, handle)| join_worker(worker, handle))
+            .collect()
+    }
+}
+
+fn join_worker(
+    worker: usize,
+    handle: JoinHandle<Result<u64, HogwildError>>,
+) -> Result<u64, HogwildError> {
+    match handle.join() {
+        Ok(result) => result,
+        Err(payload) => Err(HogwildError::WorkerPanicked {
+            worker,
+            message: panic_message(payload),
+        }),
     }
 }
 
 impl Default for HogwildTrainer {
     fn default() -> Self {
         let (sender, _receiver) = mpsc::sync_channel(0);
+        let (_, finished) = mpsc::channel();
         HogwildTrainer {
             workers: vec![],
             sender,
+            finished,
+            queue_full: QueueFull::Block,
+            stats: Arc::new(QueueStats::default()),
         }
     }
 }
 
 impl HogwildWorker {
     pub fn new(
+        finished: Sender<()>,
         regressor: BoxedRegressorTrait,
         feature_buffer_translator: FeatureBufferTranslator,
         port_buffer: PortBuffer,
+        stats: Arc<QueueStats>,
         receiver: Arc<Mutex<Receiver<Vec<u32>>>>,
-    ) -> JoinHandle<()> {
+    ) -> JoinHandle<Result<u64, HogwildError>> {
         let mut worker = HogwildWorker {
             regressor,
             feature_buffer_translator,
             port_buffer,
+            stats,
         };
 
-        thread::spawn(move || worker.train(receiver))
+        thread::spawn(move || {
+            let _finished = Finished(finished);
+            worker.train(receiver)
+        })
     }
 
-    pub fn train(&mut self, receiver: Arc<Mutex<Receiver<Vec<u32>>>>) {
+    pub fn train(&mut self, receiver: Arc<Mutex<Receiver<Vec<u32>>>>) -> Result<u64, HogwildError> {
+        let mut examples: u64 = 0;
         loop {
-            let buffer = match receiver.lock().unwrap().recv() {
+            let received = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return Err(HogwildError::QueuePoisoned),
+            };
+            let buffer = match received {
                 Ok(feature_buffer) => feature_buffer,
                 Err(_) => break, // channel was closed
             };
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+            examples += 1;
             self.feature_buffer_translator
                 .translate(buffer.as_slice(), 0u64);
             self.regressor.learn(
@@ -100,12 +295,15 @@ impl HogwildWorker {
                 true,
             );
         }
+        self.regressor.apply_minibatch(&mut self.port_buffer);
+        Ok(examples)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model_instance::ModelInstance;
     use crate::regressor::Regressor;
 
     #[test]
@@ -114,8 +312,86 @@ mod tests {
         let model_instance = ModelInstance::new_empty().unwrap();
         let regressor = Regressor::new(&model_instance);
         let sharable_regressor: BoxedRegressorTrait = BoxedRegressorTrait::new(Box::new(regressor));
-        let trainer = HogwildTrainer::new(sharable_regressor, &model_instance, num_workers);
+        let trainer = HogwildTrainer::new(
+            sharable_regressor,
+            &FeatureBufferTranslator::new(&model_instance),
+            num_workers,
+            DEFAULT_QUEUE_CAPACITY,
+            QueueFull::Block,
+        );
 
         assert_eq!(trainer.workers.len(), num_workers as usize);
+        assert_eq!(trainer.block_until_workers_finished(None), Ok(vec![0; 4]));
+    }
+
+    #[test]
+    fn hogwild_trainer_sheds_examples_when_queue_is_full() {
+        // Without workers nothing is taken from the queue
+        let (sender, _receiver) = mpsc::sync_channel(2);
+        let (_, finished) = mpsc::channel();
+        let mut trainer = HogwildTrainer {
+            workers: vec![],
+            sender,
+            finished,
+            queue_full: QueueFull::Shed,
+            stats: Arc::new(QueueStats::default()),
+        };
+        for _ in 0..5 {
+            trainer.digest_example(vec![]).unwrap();
+        }
+        let stats = trainer.stats();
+        assert_eq!(
+            stats.to_string(),
+            "queue depth:2 max_depth:3 queued:2 blocked:0 shed:3"
+        );
+        assert_eq!(trainer.block_until_workers_finished(None), Ok(vec![]));
+
+        assert_eq!(QueueFull::parse("block").unwrap(), QueueFull::Block);
+        assert!(QueueFull::parse("drop").is_err());
+    }
+
+    // A worker that panics and one that learns from 3 examples in 300ms
+    fn stopping_trainer() -> HogwildTrainer {
+        let (sender, _) = mpsc::sync_channel(1);
+        let (finished_sender, finished) = mpsc::channel();
+        let panicking = Finished(finished_sender.clone());
+        let slow = Finished(finished_sender);
+        HogwildTrainer {
+            workers: vec![
+                thread::spawn(move || {
+                    let _finished = panicking;
+                    panic!("weights are NaN")
+                }),
+                thread::spawn(move || {
+                    let _finished = slow;
+                    thread::sleep(Duration::from_millis(300));
+                    Ok(3)
+                }),
+            ],
+            sender,
+            finished,
+            queue_full: QueueFull::Block,
+            stats: Arc::new(QueueStats::default()),
+        }
+    }
+
+    #[test]
+    fn hogwild_trainer_reports_stopped_workers() {
+        assert_eq!(
+            stopping_trainer().block_until_workers_finished(Some(Duration::from_millis(100))),
+            Err(HogwildError::Timeout { unfinished: 1 })
+        );
+        assert_eq!(
+            stopping_trainer().block_until_workers_finished(None),
+            Err(HogwildError::WorkerPanicked {
+                worker: 0,
+                message: "weights are NaN".to_string()
+            })
+        );
+
+        // Examples can't be queued once all workers stopped
+        let mut trainer = stopping_trainer();
+        let _ = trainer.workers.pop().unwrap().join();
+        assert!(trainer.digest_example(vec![]).is_err());
     }
 }
@@ -0,0 +1,294 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::io;
+use std::io::{BufRead, Write};
+
+// HTTP/JSON frontend of the daemon (--http_port), for clients that don't speak the VW text
+// protocol. POST /predict takes an example {namespace: {feature: value}} or an array of them
+// and answers {"predictions": [..]}, with "stats" of the predictions when asked for with
+// /predict?stats=true. Examples are written as VW lines and parsed as those of the TCP protocol.
+// Only as much HTTP/1.1 is implemented as clients need: Content-Length bodies and keep-alive.
+
+const MAX_HEADER_LINES: usize = 100;
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub body: Vec<u8>,
+    pub keep_alive: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PredictionStats {
+    pub count: usize,
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl PredictionStats {
+    pub fn new(predictions: &[f32]) -> PredictionStats {
+        let count = predictions.len();
+        let mean = if count == 0 {
+            0.0
+        } else {
+            predictions.iter().sum::<f32>() / count as f32
+        };
+        PredictionStats {
+            count,
+            mean,
+            min: predictions.iter().cloned().fold(f32::INFINITY, f32::min),
+            max: predictions
+                .iter()
+                .cloned()
+                .fold(f32::NEG_INFINITY, f32::max),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PredictResponse {
+    pub predictions: Vec<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<PredictionStats>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct ErrorResponse<'a> {
+    error: &'a str,
+}
+
+// Ok(None) when the client closed the connection between requests
+pub fn read_request(reader: &mut impl BufRead) -> Result<Option<HttpRequest>, Box<dyn Error>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(format!("Malformed HTTP request line: {}", line.trim_end()))?,
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        body: Vec::new(),
+        keep_alive: version == "HTTP/1.1",
+    };
+
+    let mut content_length = 0;
+    for _ in 0..MAX_HEADER_LINES {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err("HTTP request ended in its headers")?;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if content_length > MAX_BODY_LEN {
+                return Err(format!(
+                    "HTTP request body of {} bytes is over {} bytes",
+                    content_length, MAX_BODY_LEN
+                ))?;
+            }
+            request.body = vec![0u8; content_length];
+            reader.read_exact(&mut request.body)?;
+            return Ok(Some(request));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse()?;
+            } else if name.eq_ignore_ascii_case("connection") {
+                if value.eq_ignore_ascii_case("close") {
+                    request.keep_alive = false;
+                } else if value.eq_ignore_ascii_case("keep-alive") {
+                    request.keep_alive = true;
+                }
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                return Err("Only HTTP requests with Content-Length are supported")?;
+            }
+        }
+    }
+    Err(format!(
+        "HTTP request has over {} headers",
+        MAX_HEADER_LINES
+    ))?
+}
+
+pub fn write_response(
+    writer: &mut impl Write,
+    status: &str,
+    body: &str,
+    keep_alive: bool,
+) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        if keep_alive { "keep-alive" } else { "close" },
+        body
+    )?;
+    writer.flush()
+}
+
+pub fn error_body(error: &str) -> String {
+    serde_json::to_string(&ErrorResponse { error }).unwrap()
+}
+
+pub fn wants_stats(query: &str) -> bool {
+    query
+        .split('&')
+        .any(|param| param == "stats" || param == "stats=true" || param == "stats=1")
+}
+
+fn check_name(name: &str, what: &str) -> Result<(), Box<dyn Error>> {
+    if name.is_empty()
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || c == ':' || c == '|')
+    {
+        return Err(format!(
+            "{} name \"{}\" has to be non-empty, without whitespace, ':' or '|'",
+            what, name
+        ))?;
+    }
+    Ok(())
+}
+
+// Examples of --grpc_port are written as VW lines the same way, a namespace is followed by its
+// features and a space
+pub fn push_namespace(line: &mut String, namespace: &str) -> Result<(), Box<dyn Error>> {
+    check_name(namespace, "Namespace")?;
+    line.push('|');
+    line.push_str(namespace);
+    Ok(())
+}
+
+pub fn push_feature(line: &mut String, feature: &str, value: f32) -> Result<(), Box<dyn Error>> {
+    check_name(feature, "Feature")?;
+    line.push(' ');
+    line.push_str(feature);
+    if value != 1.0 {
+        line.push_str(&format!(":{}", value));
+    }
+    Ok(())
+}
+
+fn example_to_vw_line(example: &Value) -> Result<String, Box<dyn Error>> {
+    let namespaces = match example.as_object() {
+        Some(namespaces) if !namespaces.is_empty() => namespaces,
+        _ => return Err("Example has to be a non-empty object of namespaces")?,
+    };
+    let mut line = String::new();
+    for (namespace, features) in namespaces {
+        let features = match features.as_object() {
+            Some(features) => features,
+            None => {
+                return Err(format!(
+                    "Features of namespace {} have to be an object",
+                    namespace
+                ))?
+            }
+        };
+        push_namespace(&mut line, namespace)?;
+        for (feature, value) in features {
+            let value = match value.as_f64() {
+                Some(value) => value,
+                None => {
+                    return Err(format!(
+                        "Value of feature {} of namespace {} has to be a number",
+                        feature, namespace
+                    ))?
+                }
+            };
+            push_feature(&mut line, feature, value as f32)?;
+        }
+        line.push(' ');
+    }
+    line.push('\n');
+    Ok(line)
+}
+
+// A single example or an array of them, as lines of the VW text format
+pub fn json_to_vw_lines(body: &[u8]) -> Result<Vec<String>, Box<dyn Error>> {
+    let value: Value = serde_json::from_slice(body)?;
+    match value {
+        Value::Array(examples) => examples.iter().map(example_to_vw_line).collect(),
+        example => Ok(vec![example_to_vw_line(&example)?]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_request() {
+        let mut reader = Cursor::new(
+            "POST /predict?stats=true HTTP/1.1\r\nHost: fw\r\nContent-Length: 4\r\n\r\n[{}]GET / HTTP/1.0\r\n\r\n",
+        );
+        assert_eq!(
+            read_request(&mut reader).unwrap(),
+            Some(HttpRequest {
+                method: "POST".to_string(),
+                path: "/predict".to_string(),
+                query: "stats=true".to_string(),
+                body: b"[{}]".to_vec(),
+                keep_alive: true,
+            })
+        );
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(request.path, "/");
+        assert!(!request.keep_alive);
+        assert_eq!(read_request(&mut reader).unwrap(), None);
+
+        let mut reader = Cursor::new("POST /predict HTTP/1.1\r\nContent-Length: 4\r\n");
+        assert!(read_request(&mut reader).is_err());
+        let mut reader = Cursor::new("garbage\r\n\r\n");
+        assert!(read_request(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_json_to_vw_lines() {
+        assert_eq!(
+            json_to_vw_lines(br#"{"A": {"a": 1, "b": 2.5}, "B": {"c": 1}}"#).unwrap(),
+            vec!["|A a b:2.5 |B c \n"]
+        );
+        assert_eq!(
+            json_to_vw_lines(br#"[{"A": {"a": 1}}, {"B": {"c": -1}}]"#).unwrap(),
+            vec!["|A a \n", "|B c:-1 \n"]
+        );
+        assert!(json_to_vw_lines(br#"{}"#).is_err());
+        assert!(json_to_vw_lines(br#"{"A": {"a b": 1}}"#).is_err());
+        assert!(json_to_vw_lines(br#"{"A": {"a": "1"}}"#).is_err());
+        assert!(json_to_vw_lines(br#"{"A|B": {"a": 1}}"#).is_err());
+        assert!(json_to_vw_lines(b"not json").is_err());
+    }
+
+    #[test]
+    fn test_prediction_stats() {
+        assert_eq!(
+            PredictionStats::new(&[0.25, 0.5, 0.75]),
+            PredictionStats {
+                count: 3,
+                mean: 0.5,
+                min: 0.25,
+                max: 0.75,
+            }
+        );
+        assert!(wants_stats("x=1&stats=1"));
+        assert!(!wants_stats("stats=false"));
+    }
+}
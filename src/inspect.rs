@@ -0,0 +1,278 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::error::Error;
+use std::io::{Cursor, Read, Write};
+
+use crate::model_instance::ModelInstance;
+use crate::persistence;
+use crate::regressor;
+use crate::regressor::Regressor;
+use crate::vwmap::VwNamespaceMap;
+
+// Human readable summary of a model file (--inspect): its metadata and statistics of the
+// weights of each block, optionally with a histogram of them.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeightStats {
+    pub block_id: u32,
+    pub weights: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f64,
+    pub zero_fraction: f64,
+    pub histogram: Vec<u64>, // equal width bins from min to max
+}
+
+pub fn block_name(block_id: u32) -> &'static str {
+    match block_id {
+        regressor::SERIALIZED_BLOCK_ID_LR => "lr",
+        regressor::SERIALIZED_BLOCK_ID_FFM => "ffm",
+        regressor::SERIALIZED_BLOCK_ID_NEURAL => "neural",
+        regressor::SERIALIZED_BLOCK_ID_EMBEDDING_LOOKUP => "embedding_lookup",
+        regressor::SERIALIZED_BLOCK_ID_FM => "fm",
+        regressor::SERIALIZED_BLOCK_ID_CROSS => "cross",
+        regressor::SERIALIZED_BLOCK_ID_FIELD_ATTENTION => "field_attention",
+        _ => "unknown",
+    }
+}
+
+impl WeightStats {
+    pub fn new(block_id: u32, weights: &[f32], histogram_bins: usize) -> WeightStats {
+        let mut stats = WeightStats {
+            block_id,
+            weights: weights.len(),
+            min: f32::MAX,
+            max: f32::MIN,
+            mean: 0.0,
+            zero_fraction: 0.0,
+            histogram: vec![0; histogram_bins],
+        };
+        if weights.is_empty() {
+            stats.min = 0.0;
+            stats.max = 0.0;
+            return stats;
+        }
+        let mut sum = 0.0f64;
+        let mut zeros = 0;
+        for w in weights.iter() {
+            stats.min = stats.min.min(*w);
+            stats.max = stats.max.max(*w);
+            sum += *w as f64;
+            if *w == 0.0 {
+                zeros += 1;
+            }
+        }
+        stats.mean = sum / weights.len() as f64;
+        stats.zero_fraction = zeros as f64 / weights.len() as f64;
+        if histogram_bins > 0 {
+            let width = (stats.max - stats.min) as f64 / histogram_bins as f64;
+            for w in weights.iter() {
+                let bin = if width > 0.0 {
+                    ((*w - stats.min) as f64 / width) as usize
+                } else {
+                    0
+                };
+                stats.histogram[bin.min(histogram_bins - 1)] += 1;
+            }
+        }
+        stats
+    }
+}
+
+// Statistics of the weights of each block of a regressor loaded for inference, where blocks
+// serialize just their weights, with sorted keys in front for embedding lookups
+pub fn block_weight_stats(
+    re: &Regressor,
+    histogram_bins: usize,
+) -> Result<Vec<WeightStats>, Box<dyn Error>> {
+    if !re.immutable {
+        return Err("Weights can only be inspected in a regressor loaded for inference")?;
+    }
+    let serialized_lens: Vec<usize> = re
+        .blocks_boxes
+        .iter()
+        .map(|block| block.get_serialized_len())
+        .filter(|len| *len > 0)
+        .collect();
+    let mut buf: Vec<u8> = Vec::new();
+    re.write_weights_to_buf(&mut buf, false)?;
+    let mut reader = Cursor::new(buf);
+    let num_blocks = reader.read_u32::<LittleEndian>()? as usize;
+    if num_blocks != serialized_lens.len() {
+        return Err("Regressor wrote weights of a different number of blocks than it has")?;
+    }
+    let mut stats: Vec<WeightStats> = Vec::new();
+    let mut frame: Vec<u8> = Vec::new();
+    for serialized_len in serialized_lens {
+        let block_id = reader.read_u32::<LittleEndian>()?;
+        let len = reader.read_u64::<LittleEndian>()? as usize;
+        frame.resize(len, 0);
+        reader.read_exact(&mut frame)?;
+        if len < serialized_len * 4 {
+            return Err(format!(
+                "Block {} has {} bytes of weights, less than {} weights",
+                block_name(block_id),
+                len,
+                serialized_len
+            ))?;
+        }
+        let weights: Vec<f32> = frame[len - serialized_len * 4..]
+            .chunks_exact(4)
+            .map(|w| f32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        stats.push(WeightStats::new(block_id, &weights, histogram_bins));
+    }
+    Ok(stats)
+}
+
+pub fn write_metadata(
+    mi: &ModelInstance,
+    vw: &VwNamespaceMap,
+    output: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(output, "Namespaces:")?;
+    for entry in vw.vw_source.entries.iter() {
+        let vwname = entry.namespace_vwname.as_bytes();
+        let descriptor = vw.map_vwname_to_namespace_descriptor[vwname];
+        writeln!(
+            output,
+            "  {} {} {:?}",
+            entry.namespace_vwname, vw.map_vwname_to_name[vwname], descriptor.namespace_format
+        )?;
+    }
+    writeln!(
+        output,
+        "LR: bit_precision {}, {} feature combos, constant feature {}",
+        mi.bit_precision,
+        mi.feature_combo_descs.len(),
+        mi.add_constant_feature
+    )?;
+    if mi.ffm_k > 0 {
+        writeln!(
+            output,
+            "FFM: ffm_k {}, ffm_bit_precision {}, {} fields",
+            mi.ffm_k,
+            mi.ffm_bit_precision,
+            mi.ffm_fields.len()
+        )?;
+    }
+    if mi.fm_k > 0 {
+        writeln!(output, "FM: fm_k {}", mi.fm_k)?;
+    }
+    if !mi.nn_config.layers.is_empty() {
+        writeln!(output, "Neural layers: {}", mi.nn_config.layers.len())?;
+    }
+    writeln!(
+        output,
+        "Optimizer: {:?}, lr {:?}, ffm {:?}, nn {:?}",
+        mi.optimizer, mi.lr_optimizer, mi.ffm_optimizer, mi.nn_optimizer
+    )?;
+    writeln!(
+        output,
+        "Learning rate: {}, ffm learning rate: {}, power_t: {}",
+        mi.learning_rate, mi.ffm_learning_rate, mi.power_t
+    )?;
+    Ok(())
+}
+
+pub fn write_weight_stats(
+    stats: &[WeightStats],
+    output: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    for s in stats.iter() {
+        writeln!(
+            output,
+            "Block {}: {} weights, min {}, max {}, mean {}, zero {:.2}%",
+            block_name(s.block_id),
+            s.weights,
+            s.min,
+            s.max,
+            s.mean,
+            s.zero_fraction * 100.0
+        )?;
+        let width = (s.max - s.min) / s.histogram.len().max(1) as f32;
+        for (bin, count) in s.histogram.iter().enumerate() {
+            writeln!(
+                output,
+                "  [{}, {}) {}",
+                s.min + bin as f32 * width,
+                s.min + (bin + 1) as f32 * width,
+                count
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn inspect_model(
+    model_filename: &str,
+    histogram_bins: usize,
+    cl: &clap::ArgMatches,
+) -> Result<(), Box<dyn Error>> {
+    // Loading for inference forgets the optimizers, they come from the model instance as saved
+    let (mi, vw) = persistence::model_instance_from_filename(model_filename)?;
+    let (_, _, re) = persistence::new_regressor_from_filename(model_filename, true, Some(cl))?;
+    let stats = block_weight_stats(&re, histogram_bins)?;
+    let stdout = std::io::stdout();
+    let mut output = stdout.lock();
+    writeln!(output, "Model {}", model_filename)?;
+    write_metadata(&mi, &vw, &mut output)?;
+    write_weight_stats(&stats, &mut output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::block_lr::BlockLR;
+    use crate::model_instance::FeatureComboDesc;
+    use crate::optimizer::OptimizerSGD;
+
+    #[test]
+    fn test_weight_stats() {
+        let stats = WeightStats::new(1, &[-1.0, 0.0, 0.0, 1.0, 3.0], 2);
+        assert_eq!(stats.weights, 5);
+        assert_eq!(stats.min, -1.0);
+        assert_eq!(stats.max, 3.0);
+        assert_eq!(stats.mean, 0.6);
+        assert_eq!(stats.zero_fraction, 0.4);
+        // Bins [-1, 1) and [1, 3], the maximum goes to the last one
+        assert_eq!(stats.histogram, vec![3, 2]);
+
+        let stats = WeightStats::new(1, &[0.5, 0.5], 3);
+        assert_eq!(stats.histogram, vec![2, 0, 0]);
+        assert_eq!(WeightStats::new(1, &[], 0).weights, 0);
+    }
+
+    #[test]
+    fn test_block_weight_stats() {
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 4;
+        mi.feature_combo_descs.push(FeatureComboDesc {
+            namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()]],
+            weight: 1.0,
+        });
+        let mut re = Regressor::new(&mi);
+        assert!(block_weight_stats(&re, 0).is_err());
+
+        re = re.immutable_regressor_without_weights(&mi).unwrap();
+        re.allocate_and_init_weights(&mi);
+        let lr = re
+            .blocks_boxes
+            .iter_mut()
+            .find_map(|block| block.as_any().downcast_mut::<BlockLR<OptimizerSGD>>())
+            .unwrap();
+        for w in lr.weights.iter_mut() {
+            w.weight = 0.0;
+        }
+        lr.weights[3].weight = 2.0;
+        let stats = block_weight_stats(&re, 0).unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(block_name(stats[0].block_id), "lr");
+        assert_eq!(stats[0].weights, 16);
+        assert_eq!(stats[0].max, 2.0);
+        assert_eq!(stats[0].mean, 0.125);
+        assert_eq!(stats[0].zero_fraction, 15.0 / 16.0);
+    }
+}
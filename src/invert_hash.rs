@@ -0,0 +1,397 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Cursor, Read, Write};
+use std::str;
+
+use crate::block_ffm::FfmIndexing;
+use crate::feature_buffer::{CONSTANT_HASH, VOWPAL_FNV_PRIME};
+use crate::model_instance::ModelInstance;
+use crate::parser;
+use crate::regressor;
+use crate::regressor::Regressor;
+use crate::vwmap::{NamespaceDescriptor, NamespaceFormat, NamespaceType, VwNamespaceMap};
+
+// Vowpal's --invert_hash and --audit. The model only knows hashes, so names of features are
+// remembered from the example lines seen in training, hashed again the same way the translator
+// does. Only feature combos and FFM fields of primitive categorical namespaces get names.
+
+// Feature of an example: hash, value and name, "A^a" or "A^a*B^b" for combos
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamedFeature {
+    pub hash: u32,
+    pub value: f32,
+    pub name: String,
+}
+
+#[derive(Clone)]
+struct NamedCombo {
+    namespace_indexes: Vec<usize>,
+    weight: f32,
+}
+
+pub struct InvertHash {
+    namespace_vwnames: Vec<Option<String>>, // by namespace index, None when features aren't named
+    named_combos: Vec<NamedCombo>,
+    ffm_namespace_indexes: Vec<usize>,
+    lr_hash_mask: u32,
+    ffm_hash_mask: u32,
+    add_constant_feature: bool,
+    // Of features with the same hash, the first one seen keeps the name
+    pub lr_names: HashMap<u32, String>,
+    pub ffm_names: HashMap<u32, String>,
+    // LR features of the last example, for --audit
+    pub lr_features: Vec<NamedFeature>,
+    namespace_features: Vec<Vec<NamedFeature>>, // by namespace index
+}
+
+impl InvertHash {
+    pub fn new(mi: &ModelInstance, vw: &VwNamespaceMap) -> InvertHash {
+        let mut namespace_vwnames: Vec<Option<String>> = vec![None; vw.num_namespaces];
+        for entry in vw.vw_source.entries.iter() {
+            let descriptor =
+                vw.map_vwname_to_namespace_descriptor[entry.namespace_vwname.as_bytes()];
+            if descriptor.namespace_format == NamespaceFormat::Categorical
+                && entry.namespace_hierarchy_weights.is_none()
+            {
+                namespace_vwnames[descriptor.namespace_index as usize] =
+                    Some(entry.namespace_vwname.clone());
+            }
+        }
+        let is_named = |d: &NamespaceDescriptor| {
+            d.namespace_type == NamespaceType::Primitive
+                && namespace_vwnames[d.namespace_index as usize].is_some()
+        };
+        let named_combos: Vec<NamedCombo> = mi
+            .feature_combo_descs
+            .iter()
+            .filter(|combo| combo.namespace_descriptors.iter().all(is_named))
+            .map(|combo| NamedCombo {
+                namespace_indexes: combo
+                    .namespace_descriptors
+                    .iter()
+                    .map(|d| d.namespace_index as usize)
+                    .collect(),
+                weight: combo.weight,
+            })
+            .collect();
+        let mut ffm_namespace_indexes: Vec<usize> = Vec::new();
+        if mi.ffm_k > 0 {
+            for d in mi.ffm_fields.iter().flatten() {
+                if is_named(d) && !ffm_namespace_indexes.contains(&(d.namespace_index as usize)) {
+                    ffm_namespace_indexes.push(d.namespace_index as usize);
+                }
+            }
+        }
+
+        // Like the feature buffer translator, FFM hashes spare the lower bits for the k dimensions
+        let dimensions_mask = mi.ffm_k.max(mi.fm_k).next_power_of_two() - 1;
+        let lr_hash_mask = (1 << mi.bit_precision) - 1;
+        let mut lr_names: HashMap<u32, String> = HashMap::new();
+        if mi.add_constant_feature {
+            lr_names.insert(CONSTANT_HASH & lr_hash_mask, "Constant".to_string());
+        }
+        InvertHash {
+            namespace_features: vec![Vec::new(); namespace_vwnames.len()],
+            namespace_vwnames,
+            named_combos,
+            ffm_namespace_indexes,
+            lr_hash_mask,
+            ffm_hash_mask: ((1 << mi.ffm_bit_precision) - 1) ^ dimensions_mask,
+            add_constant_feature: mi.add_constant_feature,
+            lr_names,
+            ffm_names: HashMap::new(),
+            lr_features: Vec::new(),
+        }
+    }
+
+    // Names the features of the example line, as parsed by VowpalParser::last_line()
+    pub fn observe(&mut self, line: &[u8]) -> Result<(), Box<dyn Error>> {
+        let line = str::from_utf8(line)?.trim_end();
+        for features in self.namespace_features.iter_mut() {
+            features.truncate(0);
+        }
+        let body = &line[line.find('|').unwrap_or(line.len())..];
+        let mut namespace: Option<(usize, &str, f32)> = None;
+        for token in body.split_ascii_whitespace() {
+            if let Some(namespace_token) = token.strip_prefix('|') {
+                let (vwname, weight) = split_weight(namespace_token)?;
+                namespace = self
+                    .namespace_vwnames
+                    .iter()
+                    .position(|n| n.as_deref() == Some(vwname))
+                    .map(|index| (index, vwname, weight));
+                if let Some((index, _, _)) = namespace {
+                    // A namespace given again replaces the earlier features, as in the parser
+                    self.namespace_features[index].truncate(0);
+                }
+                continue;
+            }
+            if let Some((index, vwname, namespace_weight)) = namespace {
+                let (feature, weight) = split_weight(token)?;
+                self.namespace_features[index].push(NamedFeature {
+                    hash: parser::feature_hash(vwname, feature.as_bytes()),
+                    value: namespace_weight * weight,
+                    name: format!("{}^{}", vwname, feature),
+                });
+            }
+        }
+
+        self.lr_features.truncate(0);
+        for combo in self.named_combos.iter() {
+            let mut products: Vec<NamedFeature> =
+                self.namespace_features[combo.namespace_indexes[0]].clone();
+            for namespace_index in combo.namespace_indexes[1..].iter() {
+                let mut next_products: Vec<NamedFeature> = Vec::new();
+                for f in products.iter() {
+                    let half_hash = f.hash.wrapping_mul(VOWPAL_FNV_PRIME);
+                    for f2 in self.namespace_features[*namespace_index].iter() {
+                        next_products.push(NamedFeature {
+                            hash: f2.hash ^ half_hash,
+                            value: f.value * f2.value,
+                            name: format!("{}*{}", f.name, f2.name),
+                        });
+                    }
+                }
+                products = next_products;
+            }
+            for mut f in products {
+                f.hash &= self.lr_hash_mask;
+                f.value *= combo.weight;
+                self.lr_features.push(f);
+            }
+        }
+        if self.add_constant_feature {
+            self.lr_features.push(NamedFeature {
+                hash: CONSTANT_HASH & self.lr_hash_mask,
+                value: 1.0,
+                name: "Constant".to_string(),
+            });
+        }
+        for f in self.lr_features.iter() {
+            self.lr_names
+                .entry(f.hash)
+                .or_insert_with(|| f.name.clone());
+        }
+        for namespace_index in self.ffm_namespace_indexes.iter() {
+            for f in self.namespace_features[*namespace_index].iter() {
+                self.ffm_names
+                    .entry(f.hash & self.ffm_hash_mask)
+                    .or_insert_with(|| f.name.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // Features of the last example as "name:hash:value", tab separated
+    pub fn audit_line(&self) -> String {
+        self.lr_features
+            .iter()
+            .map(|f| format!("{}:{}:{}", f.name, f.hash, f.value))
+            .collect::<Vec<String>>()
+            .join("\t")
+    }
+
+    // Named weights of the regressor, tab separated and by hash: "lr name hash weight" and
+    // "ffm name hash weights", with the ffm_k weights towards each field in turn
+    pub fn write(
+        &self,
+        mi: &ModelInstance,
+        re: &Regressor,
+        output: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let weights = PlainWeights::new(mi, re)?;
+        if !weights.lr.is_empty() {
+            for (hash, name) in sorted(&self.lr_names) {
+                writeln!(
+                    output,
+                    "lr\t{}\t{}\t{}",
+                    name, hash, weights.lr[hash as usize]
+                )?;
+            }
+        }
+        if !weights.ffm.is_empty() {
+            for (hash, name) in sorted(&self.ffm_names) {
+                write!(output, "ffm\t{}\t{}", name, hash)?;
+                for field in 0..mi.ffm_fields.len() {
+                    for weight in weights.ffm_embedding(hash, field, mi.ffm_k as usize) {
+                        write!(output, "\t{}", weight)?;
+                    }
+                }
+                writeln!(output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// LR and FFM weights of a regressor without their optimizer data, empty when the model has no
+// such block. Taken from the serialized weights, so regressors with any optimizer will do.
+pub struct PlainWeights {
+    pub lr: Vec<f32>,  // by hash
+    pub ffm: Vec<f32>, // laid out by --ffm_layout, see ffm_embedding()
+    ffm_indexing: FfmIndexing,
+}
+
+impl PlainWeights {
+    pub fn new(mi: &ModelInstance, re: &Regressor) -> Result<PlainWeights, Box<dyn Error>> {
+        let mut buf: Vec<u8> = Vec::new();
+        re.write_weights_to_buf(&mut buf, false)?;
+        let mut reader = Cursor::new(buf);
+        let mut weights = PlainWeights {
+            lr: Vec::new(),
+            ffm: Vec::new(),
+            ffm_indexing: FfmIndexing::new(mi),
+        };
+        let num_blocks = reader.read_u32::<LittleEndian>()?;
+        let mut frame: Vec<u8> = Vec::new();
+        for _ in 0..num_blocks {
+            let block_id = reader.read_u32::<LittleEndian>()?;
+            let len = reader.read_u64::<LittleEndian>()?;
+            frame.resize(len as usize, 0);
+            reader.read_exact(&mut frame)?;
+            match block_id {
+                regressor::SERIALIZED_BLOCK_ID_LR => {
+                    // Each weight is followed by its optimizer data
+                    let entry_len = frame.len() >> mi.bit_precision;
+                    weights.lr = frame.chunks_exact(entry_len).map(weight_of).collect();
+                }
+                regressor::SERIALIZED_BLOCK_ID_FFM => {
+                    // Weights first, their optimizer data after all of them
+                    let ffm_weights_len =
+                        (1 << mi.ffm_bit_precision) + mi.ffm_fields.len() * mi.ffm_k as usize;
+                    weights.ffm = frame[..ffm_weights_len * 4]
+                        .chunks_exact(4)
+                        .map(weight_of)
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+        Ok(weights)
+    }
+
+    // The ffm_k weights of the feature with this masked hash towards field
+    pub fn ffm_embedding(&self, hash: u32, field: usize, ffm_k: usize) -> &[f32] {
+        let index = self.ffm_indexing.index(hash, field);
+        &self.ffm[index..index + ffm_k]
+    }
+}
+
+// Splits "name:weight" into name and weight, weight is 1.0 when not given
+fn split_weight(token: &str) -> Result<(&str, f32), Box<dyn Error>> {
+    match token.split_once(':') {
+        Some((name, weight)) => match weight.parse() {
+            Ok(weight) => Ok((name, weight)),
+            Err(_) => Err(format!("Bad weight of {}", token))?,
+        },
+        None => Ok((token, 1.0)),
+    }
+}
+
+fn sorted(names: &HashMap<u32, String>) -> Vec<(u32, &str)> {
+    let mut sorted: Vec<(u32, &str)> = names
+        .iter()
+        .map(|(hash, name)| (*hash, name.as_str()))
+        .collect();
+    sorted.sort_unstable();
+    sorted
+}
+
+fn weight_of(entry: &[u8]) -> f32 {
+    f32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::block_lr::BlockLR;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::model_instance::FeatureComboDesc;
+    use crate::optimizer::OptimizerSGD;
+    use crate::parser::VowpalParser;
+
+    fn test_model(vw: &VwNamespaceMap) -> ModelInstance {
+        let a = vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()];
+        let b = vw.map_vwname_to_namespace_descriptor[&b"B".to_vec()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 8;
+        for namespace_descriptors in [vec![a], vec![a, b]].iter() {
+            mi.feature_combo_descs.push(FeatureComboDesc {
+                namespace_descriptors: namespace_descriptors.clone(),
+                weight: 1.0,
+            });
+        }
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 8;
+        mi.ffm_fields = vec![vec![a], vec![b]];
+        mi
+    }
+
+    #[test]
+    fn test_names_match_translation() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\nC,featureC,f32\n").unwrap();
+        let mi = test_model(&vw);
+        let example = b"1 |A a:2 |B b |C 0.5\n";
+        let mut ih = InvertHash::new(&mi, &vw);
+        ih.observe(example).unwrap();
+
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(pa.next_vowpal_from_bytes(example).unwrap(), 0);
+        let lr: Vec<(u32, f32)> = fbt
+            .feature_buffer
+            .lr_buffer
+            .iter()
+            .map(|f| (f.hash, f.value))
+            .collect();
+        let named: Vec<(u32, f32)> = ih.lr_features.iter().map(|f| (f.hash, f.value)).collect();
+        assert_eq!(named, lr);
+        assert_eq!(ih.lr_names[&lr[0].0], "A^a");
+        assert_eq!(ih.lr_names[&lr[1].0], "A^a*B^b");
+        assert_eq!(ih.lr_names[&lr[2].0], "Constant");
+        assert_eq!(
+            ih.audit_line(),
+            format!(
+                "A^a:{}:2\tA^a*B^b:{}:2\tConstant:{}:1",
+                lr[0].0, lr[1].0, lr[2].0
+            )
+        );
+
+        let ffm = &fbt.feature_buffer.ffm_buffer;
+        assert_eq!(ih.ffm_names.len(), 2);
+        assert_eq!(ih.ffm_names[&ffm[0].hash], "A^a");
+        assert_eq!(ih.ffm_names[&ffm[1].hash], "B^b");
+    }
+
+    #[test]
+    fn test_write() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = test_model(&vw);
+        mi.ffm_k = 0;
+        mi.ffm_fields = Vec::new();
+        mi.add_constant_feature = false;
+        let mut ih = InvertHash::new(&mi, &vw);
+        ih.observe(b"1 |A a\n").unwrap();
+        let hash = ih.lr_features[0].hash;
+
+        let mut re = Regressor::new(&mi)
+            .immutable_regressor_without_weights(&mi)
+            .unwrap();
+        re.allocate_and_init_weights(&mi);
+        let lr = re
+            .blocks_boxes
+            .iter_mut()
+            .find_map(|block| block.as_any().downcast_mut::<BlockLR<OptimizerSGD>>())
+            .unwrap();
+        lr.weights[hash as usize].weight = 0.25;
+
+        let mut output: Vec<u8> = Vec::new();
+        ih.write(&mi, &re, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("lr\tA^a\t{}\t0.25\n", hash)
+        );
+    }
+}
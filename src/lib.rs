@@ -1,4 +1,10 @@
+pub mod autotune;
+pub mod blend;
+pub mod block_cross;
+pub mod block_embedding_lookup;
 pub mod block_ffm;
+pub mod block_field_attention;
+pub mod block_fm;
 pub mod block_helpers;
 pub mod block_loss_functions;
 pub mod block_lr;
@@ -8,38 +14,83 @@ pub mod block_normalize;
 pub mod block_relu;
 pub mod buffer_handler;
 pub mod cache;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 pub mod cmdline;
+pub mod config_file;
+pub mod debug_echo;
+pub mod delta;
+pub mod deterministic;
+pub mod embeddings;
+pub mod evaluation;
+pub mod explain;
 pub mod feature_buffer;
+pub mod feature_importance;
 pub mod feature_transform_executor;
 pub mod feature_transform_implementations;
 pub mod feature_transform_parser;
+pub mod ffm_resize;
+pub mod frontend;
+pub mod golden_set;
 pub mod graph;
+#[cfg(feature = "grpc")]
+pub mod grpc_serving;
+pub mod hash_usage;
 pub mod hogwild;
+pub mod http_serving;
+pub mod inspect;
+pub mod invert_hash;
+pub mod lofo;
 pub mod logging_layer;
+pub mod metrics;
 pub mod model_instance;
+pub mod model_registry;
+pub mod monitoring;
+pub mod multi_source;
 pub mod multithread_helpers;
+pub mod murmur3;
+pub mod onnx;
 pub mod optimizer;
+pub mod parity;
 pub mod parser;
 pub mod persistence;
 pub mod port_buffer;
+pub mod prediction_log;
+pub mod progressive_validation;
 pub mod quantization;
 pub mod radix_tree;
+pub mod rate_limit;
 pub mod regressor;
+pub mod reset;
+pub mod resume;
+pub mod rng;
+pub mod score_map;
 pub mod serving;
+pub mod signals;
+pub mod simd;
+pub mod soak;
+pub mod tls;
+pub mod trainer;
+pub mod value_ranges;
 pub mod version;
+pub mod vw_import;
 pub mod vwmap;
+pub mod weight_storage;
 
 extern crate blas;
 extern crate half;
+#[cfg(target_arch = "x86_64")]
 extern crate intel_mkl_src;
+#[cfg(not(target_arch = "x86_64"))]
+extern crate openblas_src;
 
 use crate::feature_buffer::FeatureBufferTranslator;
+use crate::metrics::StreamingMetrics;
 use crate::multithread_helpers::BoxedRegressorTrait;
 use crate::parser::VowpalParser;
 use crate::port_buffer::PortBuffer;
 use crate::regressor::BlockCache;
 use crate::vwmap::NamespaceType;
-use shellwords;
 use std::ffi::CStr;
 use std::io::Cursor;
 use std::os::raw::c_char;
@@ -47,6 +98,15 @@ use std::os::raw::c_char;
 const EOF_ERROR_CODE: f32 = -1.0;
 const EXCEPTION_ERROR_CODE: f32 = -1.0;
 
+// Return codes of fw_predict_bound
+pub const BOUND_PREDICT_OK: i32 = 0;
+pub const BOUND_PREDICT_EOF: i32 = 1;
+pub const BOUND_PREDICT_ERROR: i32 = 2;
+
+// Return codes of fw_metrics_calibration
+pub const METRICS_CALIBRATION_OK: i32 = 0;
+pub const METRICS_CALIBRATION_BAD_BUCKET: i32 = 1;
+
 #[repr(C)]
 pub struct FfiPredictor {
     _marker: core::marker::PhantomData<Predictor>,
@@ -60,6 +120,23 @@ pub struct Predictor {
     cache: PredictorCache,
 }
 
+#[repr(C)]
+pub struct FfiBoundPredictor {
+    _marker: core::marker::PhantomData<BoundPredictor>,
+}
+
+// Predictor with its own parser, port buffer and output slot, set up once, so each prediction
+// only needs the bytes of the line
+pub struct BoundPredictor {
+    predictor: Predictor,
+    output: *mut f32,
+}
+
+#[repr(C)]
+pub struct FfiMetrics {
+    _marker: core::marker::PhantomData<StreamingMetrics>,
+}
+
 pub struct PredictorCache {
     blocks: Vec<BlockCache>,
     input_buffer_size: usize,
@@ -85,6 +162,21 @@ impl Predictor {
             .predict(&self.feature_buffer_translator.feature_buffer, &mut self.pb)
     }
 
+    unsafe fn predict_bytes(&mut self, line: &[u8]) -> Result<f32, i32> {
+        let buffer = match self.vw_parser.next_vowpal_from_bytes(line) {
+            Ok([]) => return Err(BOUND_PREDICT_EOF),
+            Ok(buffer2) => buffer2,
+            Err(e) => {
+                log::error!("Reading result for bound prediction returns error {}", e);
+                return Err(BOUND_PREDICT_ERROR);
+            }
+        };
+        self.feature_buffer_translator.translate(buffer, 0);
+        Ok(self
+            .regressor
+            .predict(&self.feature_buffer_translator.feature_buffer, &mut self.pb))
+    }
+
     unsafe fn predict_with_cache(&mut self, input_buffer: &str) -> f32 {
         let mut buffered_input = Cursor::new(&input_buffer);
         let reading_result = self
@@ -157,7 +249,7 @@ pub extern "C" fn new_fw_predictor_prototype(command: *const c_char) -> *mut Ffi
 
     let str_command = c_char_to_str(command);
     let words = shellwords::split(str_command).unwrap();
-    let cmd_matches = cmdline::create_expected_args().get_matches_from(words);
+    let cmd_matches = cmdline::parse_from(words).unwrap_or_else(|e| e.exit());
     let weights_filename = match cmd_matches.value_of("initial_regressor") {
         Some(filename) => filename,
         None => panic!("Cannot resolve input weights file name"),
@@ -202,6 +294,72 @@ pub unsafe extern "C" fn clone_lite(prototype: *mut FfiPredictor) -> *mut FfiPre
     Box::into_raw(Box::new(lite_predictor)).cast()
 }
 
+/// # Safety
+///
+/// `prototype` has to come from `new_fw_predictor_prototype` and not be freed yet. `output` has to
+/// point to an f32 that stays valid, and is not used by anyone else, until the bound predictor
+/// is freed with `free_bound_predictor`.
+#[no_mangle]
+pub unsafe extern "C" fn fw_bind_predictor(
+    prototype: *mut FfiPredictor,
+    output: *mut f32,
+) -> *mut FfiBoundPredictor {
+    // Creates a predictor for the low latency path: like clone_lite it is cheap and owned by a single
+    // thread, predictions of fw_predict_bound are written to the output slot given here
+    assert!(!output.is_null());
+    let lite_predictor: Box<Predictor> = Box::from_raw(clone_lite(prototype).cast());
+    let bound_predictor = BoundPredictor {
+        predictor: *lite_predictor,
+        output,
+    };
+    Box::into_raw(Box::new(bound_predictor)).cast()
+}
+
+/// # Safety
+///
+/// `ptr` has to come from `fw_bind_predictor` and not be freed yet, and only one thread may use
+/// it at a time. `line` has to point to `line_len` readable bytes, it may be null only when
+/// `line_len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn fw_predict_bound(
+    ptr: *mut FfiBoundPredictor,
+    line: *const u8,
+    line_len: usize,
+) -> i32 {
+    // The line is given with its length, so there is no scanning for the terminator and no utf8 check
+    if ptr.is_null() {
+        log::error!("Fatal error, got NULL `Context` pointer");
+        std::process::abort();
+    }
+    let bound_predictor: &mut BoundPredictor = &mut *(ptr.cast());
+    if line.is_null() && line_len > 0 {
+        log::error!("Got NULL line of length {}", line_len);
+        return BOUND_PREDICT_ERROR;
+    }
+    let line = if line_len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(line, line_len)
+    };
+    match bound_predictor.predictor.predict_bytes(line) {
+        Ok(prediction) => {
+            *bound_predictor.output = prediction;
+            BOUND_PREDICT_OK
+        }
+        Err(code) => code,
+    }
+}
+
+/// # Safety
+///
+/// `ptr` has to be null or come from `fw_bind_predictor`, and be freed only once.
+#[no_mangle]
+pub unsafe extern "C" fn free_bound_predictor(ptr: *mut FfiBoundPredictor) {
+    if !ptr.is_null() {
+        drop::<Box<BoundPredictor>>(Box::from_raw(ptr.cast()));
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn fw_predict(ptr: *mut FfiPredictor, input_buffer: *const c_char) -> f32 {
     let str_buffer = c_char_to_str(input_buffer);
@@ -234,6 +392,88 @@ pub unsafe extern "C" fn free_predictor(ptr: *mut FfiPredictor) {
     drop::<Box<Predictor>>(Box::from_raw(from_ptr(ptr)));
 }
 
+#[no_mangle]
+pub extern "C" fn fw_new_metrics() -> *mut FfiMetrics {
+    // Streaming AUC, logloss and calibration of predictions whose labels become known to the caller
+    Box::into_raw(Box::new(StreamingMetrics::new())).cast()
+}
+
+/// # Safety
+///
+/// `ptr` has to come from `fw_new_metrics` and not be freed yet, and only one thread may use it
+/// at a time.
+#[no_mangle]
+pub unsafe extern "C" fn fw_metrics_add(ptr: *mut FfiMetrics, prediction: f32, label: bool) {
+    metrics_from_ptr(ptr).add(prediction, label);
+}
+
+/// # Safety
+///
+/// `ptr` has to come from `fw_new_metrics` and not be freed yet, and only one thread may use it
+/// at a time.
+#[no_mangle]
+pub unsafe extern "C" fn fw_metrics_auc(ptr: *mut FfiMetrics) -> f64 {
+    metrics_from_ptr(ptr).auc()
+}
+
+/// # Safety
+///
+/// `ptr` has to come from `fw_new_metrics` and not be freed yet, and only one thread may use it
+/// at a time.
+#[no_mangle]
+pub unsafe extern "C" fn fw_metrics_logloss(ptr: *mut FfiMetrics) -> f64 {
+    metrics_from_ptr(ptr).logloss()
+}
+
+/// # Safety
+///
+/// `ptr` has to come from `fw_new_metrics` and not be freed yet, and only one thread may use it
+/// at a time. `examples`, `predicted_ctr` and `observed_ctr` have to point to writable values,
+/// they are not written when the bucket is out of range.
+#[no_mangle]
+pub unsafe extern "C" fn fw_metrics_calibration(
+    ptr: *mut FfiMetrics,
+    bucket: usize,
+    examples: *mut u64,
+    predicted_ctr: *mut f64,
+    observed_ctr: *mut f64,
+) -> i32 {
+    // Buckets are metrics::CALIBRATION_BUCKETS equal ranges of predictions, others are an error
+    let metrics = metrics_from_ptr(ptr);
+    if bucket >= metrics::CALIBRATION_BUCKETS {
+        log::error!(
+            "Calibration bucket {} requested, there are {}",
+            bucket,
+            metrics::CALIBRATION_BUCKETS
+        );
+        return METRICS_CALIBRATION_BAD_BUCKET;
+    }
+    let bucket = metrics.calibration[bucket];
+    *examples = bucket.examples;
+    *predicted_ctr = bucket.predicted_ctr();
+    *observed_ctr = bucket.observed_ctr();
+    METRICS_CALIBRATION_OK
+}
+
+/// # Safety
+///
+/// `ptr` has to be null or come from `fw_new_metrics`, and be freed only once. It can't be used
+/// after that.
+#[no_mangle]
+pub unsafe extern "C" fn free_metrics(ptr: *mut FfiMetrics) {
+    if !ptr.is_null() {
+        drop::<Box<StreamingMetrics>>(Box::from_raw(ptr.cast()));
+    }
+}
+
+unsafe fn metrics_from_ptr<'a>(ptr: *mut FfiMetrics) -> &'a mut StreamingMetrics {
+    if ptr.is_null() {
+        log::error!("Fatal error, got NULL `Context` pointer");
+        std::process::abort();
+    }
+    &mut *(ptr.cast())
+}
+
 unsafe fn from_ptr<'a>(ptr: *mut FfiPredictor) -> &'a mut Predictor {
     if ptr.is_null() {
         log::error!("Fatal error, got NULL `Context` pointer");
@@ -250,3 +490,89 @@ fn c_char_to_str<'a>(input_buffer: *const c_char) -> &'a str {
     let str_buffer = c_str.to_str().unwrap();
     str_buffer
 }
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_metrics_calibration() {
+        unsafe {
+            let metrics = fw_new_metrics();
+            fw_metrics_add(metrics, 0.95, true);
+            let (mut examples, mut predicted_ctr, mut observed_ctr) = (0u64, 0.0f64, 0.0f64);
+            let last_bucket = metrics::CALIBRATION_BUCKETS - 1;
+            assert_eq!(
+                fw_metrics_calibration(
+                    metrics,
+                    last_bucket,
+                    &mut examples,
+                    &mut predicted_ctr,
+                    &mut observed_ctr
+                ),
+                METRICS_CALIBRATION_OK
+            );
+            assert_eq!(examples, 1);
+            assert_eq!(observed_ctr, 1.0);
+            assert_eq!(
+                fw_metrics_calibration(
+                    metrics,
+                    last_bucket + 1,
+                    &mut examples,
+                    &mut predicted_ctr,
+                    &mut observed_ctr
+                ),
+                METRICS_CALIBRATION_BAD_BUCKET
+            );
+            free_metrics(metrics);
+        }
+    }
+
+    #[test]
+    fn test_predict_bound() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::SGD;
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A"[..]]],
+                weight: 1.0,
+            });
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("model.fw");
+        let filename = filename.to_str().unwrap();
+        persistence::save_regressor_to_filename(
+            filename,
+            &mi,
+            &vw,
+            regressor::Regressor::new(&mi),
+            false,
+        )
+        .unwrap();
+        let command = std::ffi::CString::new(format!("fw -i {} -t", filename)).unwrap();
+        unsafe {
+            let prototype = new_fw_predictor_prototype(command.as_ptr());
+            let mut output = 0.0f32;
+            let bound = fw_bind_predictor(prototype, &mut output);
+            let line = b"|A a\n";
+            assert_eq!(
+                fw_predict_bound(bound, line.as_ptr(), line.len()),
+                BOUND_PREDICT_OK
+            );
+            assert_eq!(output, 0.5);
+            // A null line is only fine when it is empty
+            assert_eq!(
+                fw_predict_bound(bound, std::ptr::null(), 5),
+                BOUND_PREDICT_ERROR
+            );
+            assert_eq!(
+                fw_predict_bound(bound, std::ptr::null(), 0),
+                BOUND_PREDICT_EOF
+            );
+            free_bound_predictor(bound);
+            free_predictor(prototype);
+        }
+    }
+}
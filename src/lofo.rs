@@ -0,0 +1,242 @@
+use std::error::Error;
+
+use crate::block_loss_functions::logistic;
+use crate::feature_buffer::FeatureBuffer;
+use crate::model_instance::ModelInstance;
+use crate::port_buffer::PortBuffer;
+use crate::regressor::{OutputDecomposition, Regressor};
+use crate::vwmap::{NamespaceDescriptor, NamespaceType, VwNamespaceMap};
+
+// Leave-one-namespace-out scores of the daemon (--lofo): for each input namespace, the prediction
+// of the request without it. Instead of a forward pass per namespace, the per-combo sums of the LR
+// block and the field x field interactions of the FFM block of the single forward pass are
+// subtracted from the logit. This is exact for models whose FFM fields hold one namespace each, a
+// field with more namespaces is left out as a whole. Neural layers mix the parts, so models with
+// them are refused.
+
+struct LofoNamespace {
+    vwname: String,
+    combo_indexes: Vec<usize>,
+    fields: Vec<bool>, // FFM fields that have features of the namespace
+}
+
+pub struct LofoAttributor {
+    namespaces: Vec<LofoNamespace>, // by vwname
+}
+
+// Input namespaces that features of a namespace are computed from
+pub fn primitive_namespaces(
+    mi: &ModelInstance,
+    namespace_descriptor: &NamespaceDescriptor,
+) -> Vec<u16> {
+    match namespace_descriptor.namespace_type {
+        NamespaceType::Primitive => vec![namespace_descriptor.namespace_index],
+        NamespaceType::Transformed => mi.transform_namespaces.v
+            [namespace_descriptor.namespace_index as usize]
+            .from_namespaces
+            .iter()
+            .flat_map(|from| primitive_namespaces(mi, &from.namespace_descriptor))
+            .collect(),
+    }
+}
+
+impl LofoAttributor {
+    pub fn new(mi: &ModelInstance, vw: &VwNamespaceMap) -> Result<LofoAttributor, Box<dyn Error>> {
+        if !mi.nn_config.layers.is_empty() {
+            return Err("--lofo can't score models with neural layers, their output is not a sum of the LR and FFM parts")?;
+        }
+        let mut namespaces: Vec<LofoNamespace> = Vec::new();
+        for (vwname, namespace_descriptor) in vw.map_vwname_to_namespace_descriptor.iter() {
+            let namespace_index = namespace_descriptor.namespace_index;
+            let combo_indexes: Vec<usize> = mi
+                .feature_combo_descs
+                .iter()
+                .enumerate()
+                .filter(|(_, combo)| {
+                    combo
+                        .namespace_descriptors
+                        .iter()
+                        .any(|nd| primitive_namespaces(mi, nd).contains(&namespace_index))
+                })
+                .map(|(combo_index, _)| combo_index)
+                .collect();
+            let fields: Vec<bool> = mi
+                .ffm_fields
+                .iter()
+                .map(|field| {
+                    field
+                        .iter()
+                        .any(|nd| primitive_namespaces(mi, nd).contains(&namespace_index))
+                })
+                .collect();
+            if combo_indexes.is_empty() && !fields.contains(&true) {
+                continue;
+            }
+            namespaces.push(LofoNamespace {
+                vwname: String::from_utf8_lossy(vwname).to_string(),
+                combo_indexes,
+                fields,
+            });
+        }
+        if namespaces.is_empty() {
+            return Err("No namespaces of the model to leave out")?;
+        }
+        namespaces.sort_by(|a, b| a.vwname.cmp(&b.vwname));
+        Ok(LofoAttributor { namespaces })
+    }
+
+    pub fn vwnames(&self) -> Vec<&str> {
+        self.namespaces
+            .iter()
+            .map(|ns| ns.vwname.as_str())
+            .collect()
+    }
+
+    // Logits without each of the namespaces
+    pub fn leave_one_out_logits(&self, decompositions: &[OutputDecomposition]) -> Vec<f32> {
+        let mut logit = 0.0;
+        let mut lr_combos: &[f32] = &[];
+        let mut ffm_fields: &[f32] = &[];
+        for decomposition in decompositions.iter() {
+            match decomposition {
+                OutputDecomposition::LRCombos(combos) => lr_combos = combos,
+                OutputDecomposition::FFMFields(fields) => ffm_fields = fields,
+                OutputDecomposition::Logit(l) => logit = *l,
+                OutputDecomposition::Block(..) => {}
+            }
+        }
+        self.namespaces
+            .iter()
+            .map(|ns| {
+                let mut left_out = 0.0;
+                for combo_index in ns.combo_indexes.iter() {
+                    left_out += lr_combos[*combo_index];
+                }
+                let num_fields = ns.fields.len();
+                if ffm_fields.len() == num_fields * num_fields {
+                    for f1 in 0..num_fields {
+                        for f2 in 0..num_fields {
+                            if ns.fields[f1] || ns.fields[f2] {
+                                left_out += ffm_fields[f1 * num_fields + f2];
+                            }
+                        }
+                    }
+                }
+                logit - left_out
+            })
+            .collect()
+    }
+
+    // Returns the prediction and the predictions without each of the namespaces
+    pub fn predict(
+        &self,
+        re: &Regressor,
+        fb: &FeatureBuffer,
+        pb: &mut PortBuffer,
+    ) -> (f32, Vec<f32>) {
+        let (prediction, decompositions) = re.predict_decomposed(fb, pb);
+        let lofo = self
+            .leave_one_out_logits(&decompositions)
+            .into_iter()
+            .map(|logit| {
+                // Same bounds as the sigmoid block
+                let logit = if logit.is_nan() {
+                    0.0
+                } else {
+                    logit.clamp(-50.0, 50.0)
+                };
+                logistic(logit)
+            })
+            .collect();
+        (prediction, lofo)
+    }
+
+    // Daemon response, the prediction followed by <vwname>:<prediction without the namespace>
+    pub fn format_predictions(&self, prediction: f32, lofo: &[f32]) -> String {
+        let mut s = format!("{:.6}", prediction);
+        for (ns, p) in self.namespaces.iter().zip(lofo.iter()) {
+            s.push_str(&format!(" {}:{:.6}", ns.vwname, p));
+        }
+        s.push('\n');
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::model_instance::{FeatureComboDesc, Optimizer};
+    use crate::parser::VowpalParser;
+
+    #[test]
+    fn test_lofo() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\nC,featureC\nD,featureD\n").unwrap();
+        let nd = |vwname: &str| vw.map_vwname_to_namespace_descriptor[vwname.as_bytes()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        // Wide enough for FFM fields to make a difference
+        mi.ffm_init_width = 1.0;
+        mi.optimizer = Optimizer::AdagradFlex;
+        for combo in [vec![nd("A")], vec![nd("B")], vec![nd("A"), nd("B")]].iter() {
+            mi.feature_combo_descs.push(FeatureComboDesc {
+                namespace_descriptors: combo.clone(),
+                weight: 1.0,
+            });
+        }
+        mi.ffm_fields = vec![vec![nd("A")], vec![nd("B")], vec![nd("C")]];
+
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        for i in 0..50 {
+            let line = format!(
+                "{} |A a{} |B b{} |C c{} c{}\n",
+                if i % 2 == 0 { "1" } else { "-1" },
+                i % 3,
+                i % 5,
+                i % 2,
+                i % 7
+            );
+            fbt.translate(pa.next_vowpal_from_bytes(line.as_bytes()).unwrap(), 0);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+
+        // D is not used by the model
+        let lofo = LofoAttributor::new(&mi, &vw).unwrap();
+        assert_eq!(lofo.vwnames(), vec!["A", "B", "C"]);
+
+        let mut predict_line = |line: &str| {
+            fbt.translate(pa.next_vowpal_from_bytes(line.as_bytes()).unwrap(), 0);
+            let p = re.predict(&fbt.feature_buffer, &mut pb);
+            (p, lofo.predict(&re, &fbt.feature_buffer, &mut pb))
+        };
+        let (p, (p_lofo, without)) = predict_line("1 |A a1 |B b2 |C c1 c3\n");
+        assert_eq!(p, p_lofo);
+        // Same as predictions of the request without the namespace
+        let expected = [
+            predict_line("1 |B b2 |C c1 c3\n").0,
+            predict_line("1 |A a1 |C c1 c3\n").0,
+            predict_line("1 |A a1 |B b2\n").0,
+        ];
+        for (p_without, p_expected) in without.iter().zip(expected.iter()) {
+            assert!((p_without - p_expected).abs() < 1e-5);
+            assert!((p_without - p).abs() > 1e-5);
+        }
+        assert_eq!(
+            lofo.format_predictions(0.5, &[0.25, 0.125, 1.0]),
+            "0.500000 A:0.250000 B:0.125000 C:1.000000\n"
+        );
+
+        mi.nn_config.layers.push(std::collections::HashMap::new());
+        assert!(LofoAttributor::new(&mi, &vw).is_err());
+    }
+}
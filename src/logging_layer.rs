@@ -18,10 +18,11 @@ pub fn initialize_logging_layer() {
         log::info!("Initialized the logger ..")
     }
 
-    log_detected_x86_features();
+    log_detected_cpu_features();
 }
 
-fn log_detected_x86_features() {
+#[cfg(target_arch = "x86_64")]
+fn log_detected_cpu_features() {
     let mut features: Vec<String> = Vec::new();
     if is_x86_feature_detected!("avx") {
         features.push("AVX".to_string());
@@ -39,9 +40,28 @@ fn log_detected_x86_features() {
         features.push("FMA".to_string());
     }
 
+    log_cpu_features(&features);
+}
+
+#[cfg(target_arch = "aarch64")]
+fn log_detected_cpu_features() {
+    let mut features: Vec<String> = Vec::new();
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        features.push("NEON".to_string());
+    }
+
+    log_cpu_features(&features);
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn log_detected_cpu_features() {
+    log_cpu_features(&[]);
+}
+
+fn log_cpu_features(features: &[String]) {
     if features.is_empty() {
         log::info!("No selected CPU features detected ..");
     } else {
         log::info!("Detected CPU features: {:?}", features.join(", "));
     }
-}
\ No newline at end of file
+}
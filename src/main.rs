@@ -13,30 +13,49 @@ use std::io::BufRead;
 use std::io::BufWriter;
 use std::io::Write;
 use std::path::Path;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 extern crate blas;
 extern crate half;
+#[cfg(target_arch = "x86_64")]
 extern crate intel_mkl_src;
+#[cfg(not(target_arch = "x86_64"))]
+extern crate openblas_src;
 
 #[macro_use]
 extern crate nom;
 extern crate core;
 
+use fw::buffer_handler::{create_buffered_input, create_buffered_input_with_position, skip_input};
 use fw::cache::RecordCache;
+use fw::deterministic;
+use fw::deterministic::DeterministicTrainer;
 use fw::feature_buffer::FeatureBufferTranslator;
+use fw::feature_importance::FeatureImportance;
+use fw::feature_transform_executor::model_transform_state_filename;
+use fw::hash_usage::HashSpaceUsage;
+use fw::hogwild;
 use fw::hogwild::HogwildTrainer;
-use fw::model_instance::{ModelInstance, Optimizer};
+use fw::invert_hash::InvertHash;
+use fw::metrics::StreamingMetrics;
+use fw::model_instance::{LRSchedule, ModelInstance, Optimizer};
+use fw::multi_source::{MultiSource, SourceMetrics};
 use fw::multithread_helpers::BoxedRegressorTrait;
 use fw::parser::VowpalParser;
-use fw::buffer_handler::create_buffered_input;
 use fw::persistence::{
     new_regressor_from_filename, save_regressor_to_filename, save_sharable_regressor_to_filename,
+    save_weights_buf_to_filename, BackgroundSaver,
 };
+use fw::progressive_validation::ProgressiveValidation;
 use fw::regressor::{get_regressor_with_weights, Regressor};
+use fw::resume::{DataFingerprint, ResumePoint};
 use fw::serving::Serving;
+use fw::value_ranges::{ValueRangeChecker, ValueRangeRecorder};
 use fw::vwmap::VwNamespaceMap;
-use fw::{cmdline, feature_buffer, logging_layer, regressor};
+use fw::{
+    blend, cmdline, delta, embeddings, feature_buffer, hash_usage, inspect, logging_layer,
+    multi_source, onnx, optimizer, parser, regressor, reset, signals, soak,
+};
 
 fn main() {
     logging_layer::initialize_logging_layer();
@@ -58,12 +77,16 @@ fn build_cache_without_training(cl: clap::ArgMatches) -> Result<(), Box<dyn Erro
         .join("vw_namespace_map.csv");
 
     let vw: VwNamespaceMap = VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
+    if let Some(num_shards) = cl.value_of("cache_shards") {
+        RecordCache::new_sharded(input_filename, num_shards.parse()?, &vw)?;
+        log::info!("Built cache shards only, exiting.");
+        return Ok(());
+    }
     let mut cache = RecordCache::new(input_filename, true, &vw);
     let input = File::open(input_filename)?;
 
     let mut bufferred_input = create_buffered_input(input_filename);
     let mut pa = VowpalParser::new(&vw);
-    let mut example_num = 0;
     loop {
         let reading_result;
         let buffer: &[u32];
@@ -85,7 +108,6 @@ fn build_cache_without_training(cl: clap::ArgMatches) -> Result<(), Box<dyn Erro
                 Err(_e) => return Err(_e),
             };
         }
-        example_num += 1;
     }
 
     log::info!("Built cache only, exiting.");
@@ -93,28 +115,132 @@ fn build_cache_without_training(cl: clap::ArgMatches) -> Result<(), Box<dyn Erro
     Ok(())
 }
 
+// Writes "label importance tag" of a parsed record, label is 1, -1 or NA when the example has none.
+// Labels of more --heads follow as on the example line, empty when unknown
+fn write_label_line(output: &mut dyn Write, buffer: &[u32]) -> Result<(), Box<dyn Error>> {
+    let label = buffer[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK;
+    let mut label_str = match label {
+        0 => "-1".to_string(),
+        parser::NO_LABEL => "NA".to_string(),
+        label => label.to_string(), // 1 or a class number of --oaa
+    };
+    for head_label in parser::get_head_labels(buffer) {
+        label_str.push_str(match *head_label {
+            0 => ",-1",
+            1 => ",1",
+            _ => ",",
+        });
+    }
+    let importance = f32::from_bits(buffer[parser::EXAMPLE_IMPORTANCE_OFFSET]);
+    let tag = parser::get_tag(buffer).unwrap_or_default();
+    writeln!(
+        output,
+        "{} {} {}",
+        label_str,
+        importance,
+        String::from_utf8_lossy(&tag)
+    )?;
+    Ok(())
+}
+
 fn main_fw_loop() -> Result<(), Box<dyn Error>> {
     // We'll parse once the command line into cl and then different objects will examine it
     let cl = cmdline::parse();
     if cl.is_present("build_cache_without_training") {
         return build_cache_without_training(cl);
     }
+    if cl.is_present("soak_hours") {
+        return soak::run_soak_from_cmdline(&cl);
+    }
+    if let Some(base_filename) = cl.value_of("blend_base") {
+        return blend::blend_models(
+            base_filename,
+            cl.value_of("blend_delta").unwrap(),
+            cl.value_of("blend_alpha").unwrap().parse()?,
+            blend::BlendMode::parse(cl.value_of("blend_mode").unwrap_or("output"))?,
+            cl.value_of("blend_out").unwrap(),
+        );
+    }
+    if let Some(filenames) = cl.values_of("merge_model") {
+        let weights = match cl.value_of("merge_weights") {
+            Some(weights) => Some(blend::parse_merge_weights(weights)?),
+            None => None,
+        };
+        let filenames: Vec<&str> = filenames.collect();
+        return blend::merge_models(&filenames, weights, cl.value_of("merge_out").unwrap());
+    }
+    if let Some(delta_filename) = cl.value_of("apply_delta") {
+        return delta::apply_delta(
+            cl.value_of("delta_base").unwrap(),
+            delta_filename,
+            cl.value_of("apply_delta_out").unwrap(),
+        );
+    }
+    if let Some(model_filename) = cl.value_of("reset_model") {
+        let namespaces: Vec<&str> = cl
+            .value_of("reset_namespaces")
+            .unwrap()
+            .split(',')
+            .collect();
+        return reset::reset_namespaces(
+            model_filename,
+            &namespaces,
+            cl.value_of("reset_data").unwrap(),
+            cl.value_of("reset_out").unwrap(),
+        );
+    }
+    if cl.is_present("inspect") {
+        let histogram_bins: usize = match cl.value_of("inspect_histogram") {
+            Some(bins) => bins.parse()?,
+            None => 0,
+        };
+        return inspect::inspect_model(
+            cl.value_of("initial_regressor").unwrap(),
+            histogram_bins,
+            &cl,
+        );
+    }
+    if let Some(onnx_filename) = cl.value_of("export_onnx") {
+        return onnx::export_model(
+            cl.value_of("initial_regressor").unwrap(),
+            onnx_filename,
+            &cl,
+        );
+    }
+    if let Some(output_filename) = cl.value_of("dump_embeddings") {
+        return embeddings::dump_embeddings_from_model(
+            cl.value_of("initial_regressor").unwrap(),
+            output_filename,
+            cl.value_of("dump_embeddings_data"),
+        );
+    }
     // Where will we be putting perdictions (if at all)
     let mut predictions_file = match cl.value_of("predictions") {
         Some(filename) => Some(BufWriter::new(File::create(filename)?)),
         None => None,
     };
 
+    // Labels are written alongside predictions, so downstream joiners don't need the input file
+    let mut labels_file = match cl.value_of("labels_out") {
+        Some(filename) => Some(BufWriter::new(File::create(filename)?)),
+        None => None,
+    };
+
     let testonly = cl.is_present("testonly");
-    let quantize_weights = cl.is_present("weight_quantization");
+    let quantize_weights = cl.is_present("weight_quantization") || cl.is_present("quantize_model");
     let final_regressor_filename = cl.value_of("final_regressor");
     let output_pred_sto: bool = cl.is_present("predictions_stdout");
     if let Some(filename) = final_regressor_filename {
-        if !cl.is_present("save_resume") {
+        // The daemon saves the forward-only model it served
+        if !cl.is_present("save_resume") && !cl.is_present("daemon") {
             return Err("You need to use --save_resume with --final_regressor, for vowpal wabbit compatibility")?;
         }
         log::info!("final_regressor = {}", filename);
     };
+    let delta_base_filename = cl.value_of("delta_base");
+    if delta_base_filename.is_some() && quantize_weights {
+        return Err("Deltas are saved from full precision weights, --delta_base can't be used with weight quantization")?;
+    }
 
     let inference_regressor_filename = cl.value_of("convert_inference_regressor");
     if let Some(filename) = inference_regressor_filename {
@@ -129,10 +255,22 @@ fn main_fw_loop() -> Result<(), Box<dyn Error>> {
             .value_of("initial_regressor")
             .expect("Daemon mode only supports serving from --initial regressor");
         log::info!("initial_regressor = {}", filename);
-        let (mi2, vw2, re_fixed) = new_regressor_from_filename(filename, true, Option::Some(&cl))?;
+        let (mi2, vw2, mut re_fixed) =
+            new_regressor_from_filename(filename, true, Option::Some(&cl))?;
+        if let Some(dir) = cl.value_of("mmap_weights") {
+            re_fixed.map_weights(dir)?;
+        }
 
         let mut se = Serving::new(&cl, &vw2, Box::new(re_fixed), &mi2)?;
         se.serve()?;
+        if let Some(filename) = final_regressor_filename {
+            let (re_served, mut mi_served) = se.served_model();
+            mi_served.optimizer = Optimizer::SGD;
+            let mut weights: Vec<u8> = Vec::new();
+            re_served.write_weights_to_buf(&mut weights, false)?;
+            save_weights_buf_to_filename(filename, &mi_served, &vw2, &weights)?;
+            log::info!("Saved the served model to {}", filename);
+        }
     } else if cl.is_present("convert_inference_regressor") {
         let filename = cl
             .value_of("initial_regressor")
@@ -140,7 +278,7 @@ fn main_fw_loop() -> Result<(), Box<dyn Error>> {
         let (mut mi2, vw2, re_fixed) =
             new_regressor_from_filename(filename, true, Option::Some(&cl))?;
         mi2.optimizer = Optimizer::SGD;
-        if cl.is_present("weight_quantization") {
+        if quantize_weights {
             mi2.dequantize_weights = Some(true);
         }
         if let Some(filename1) = inference_regressor_filename {
@@ -150,17 +288,40 @@ fn main_fw_loop() -> Result<(), Box<dyn Error>> {
         let vw: VwNamespaceMap;
         let mut re: Regressor;
         let mut sharable_regressor: BoxedRegressorTrait;
-        let mi: ModelInstance;
+        let mut mi: ModelInstance;
+
+        // With --source, the first source takes the place of --data
+        let first_source_filename = match cl.values_of("source") {
+            Some(mut sources) => Some(multi_source::parse_source(sources.next().unwrap())?.0),
+            None => None,
+        };
+        let input_filename = match first_source_filename.as_deref() {
+            Some(filename) => filename,
+            None => cl.value_of("data").expect("--data expected"),
+        };
 
-        if let Some(filename) = cl.value_of("initial_regressor") {
+        // With --resume, an existing snapshot takes the place of the initial regressor
+        let resume_filename = match cl.value_of("snapshot_regressor") {
+            Some(filename) if cl.is_present("resume") && Path::new(filename).exists() => {
+                Some(filename)
+            }
+            _ => None,
+        };
+        let initial_filename = resume_filename.or_else(|| cl.value_of("initial_regressor"));
+        if let Some(base_filename) = delta_base_filename {
+            delta::check_delta_base(base_filename, initial_filename)?;
+        }
+        if let Some(filename) = initial_filename {
             log::info!("initial_regressor = {}", filename);
             (mi, vw, re) = new_regressor_from_filename(filename, testonly, Option::Some(&cl))?;
+            if delta_base_filename.is_some() {
+                re.track_dirty_weights();
+            }
             sharable_regressor = BoxedRegressorTrait::new(Box::new(re));
         } else {
             // We load vw_namespace_map.csv just so we know all the namespaces ahead of time
             // This is one of the major differences from vowpal
 
-            let input_filename = cl.value_of("data").expect("--data expected");
             let vw_namespace_map_filepath = Path::new(input_filename)
                 .parent()
                 .expect("Couldn't access path given by --data")
@@ -168,12 +329,49 @@ fn main_fw_loop() -> Result<(), Box<dyn Error>> {
             vw = VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
             mi = ModelInstance::new_from_cmdline(&cl, &vw)?;
             re = get_regressor_with_weights(&mi);
+            re.load_pretrained_weights(&mi)?;
             sharable_regressor = BoxedRegressorTrait::new(Box::new(re));
         };
 
-        let input_filename = cl.value_of("data").expect("--data expected");
-        let mut cache = RecordCache::new(input_filename, cl.is_present("cache"), &vw);
+        let resume_point = match resume_filename {
+            Some(filename) => match mi.resume_point.take() {
+                Some(resume_point) => {
+                    resume_point.verify(input_filename)?;
+                    Some(resume_point)
+                }
+                None => {
+                    return Err(format!(
+                        "Snapshot {} has no resume point, it was not taken during training",
+                        filename
+                    ))?
+                }
+            },
+            None => None,
+        };
+
+        let open_cache = || -> Result<RecordCache, Box<dyn Error>> {
+            Ok(match cl.value_of("cache_shards") {
+                Some(num_shards) => {
+                    RecordCache::new_sharded(input_filename, num_shards.parse()?, &vw)?
+                }
+                None => RecordCache::new(input_filename, cl.is_present("cache"), &vw),
+            })
+        };
+        let mut cache = open_cache()?;
         let mut fbt = FeatureBufferTranslator::new(&mi);
+        let transform_state_filename = cl.value_of("transform_state");
+        if let Some(filename) = transform_state_filename {
+            if Path::new(filename).exists() {
+                fbt.transform_executors.load_state_from_filename(filename)?;
+            }
+        } else if let Some(filename) = resume_filename.or_else(|| cl.value_of("initial_regressor"))
+        {
+            let filename = model_transform_state_filename(filename);
+            if Path::new(&filename).exists() {
+                fbt.transform_executors
+                    .load_state_from_filename(&filename)?;
+            }
+        }
         let mut pb = sharable_regressor.new_portbuffer();
 
         let predictions_after: u64 = match cl.value_of("predictions_after") {
@@ -183,8 +381,49 @@ fn main_fw_loop() -> Result<(), Box<dyn Error>> {
 
         let holdout_after_option: Option<u64> =
             cl.value_of("holdout_after").map(|s| s.parse().unwrap());
+        let holdout_period: u64 = match cl.value_of("holdout_period") {
+            Some(period) => period.parse()?,
+            None => 0,
+        };
+
+        let passes: u64 = match cl.value_of("passes") {
+            Some(passes) => passes.parse()?,
+            None => 1,
+        };
+        if passes == 0 {
+            return Err("--passes has to be at least 1")?;
+        }
+        if passes > 1 && resume_point.is_some() {
+            return Err("--resume can not continue from a snapshot taken with --passes")?;
+        }
+        let decay_learning_rate: f32 = match cl.value_of("decay_learning_rate") {
+            Some(factor) => factor.parse()?,
+            None => 1.0,
+        };
+        let early_terminate: Option<u64> = match cl.value_of("early_terminate") {
+            Some(passes) => Some(passes.parse()?),
+            None => None,
+        };
+        if early_terminate.is_some() && holdout_after_option.is_none() && holdout_period == 0 {
+            return Err("--early_terminate needs held out examples, from --holdout_after or --holdout_period")?;
+        }
 
         let hogwild_training = cl.is_present("hogwild_training");
+        if hogwild_training && (mi.lr_schedule.is_some() || decay_learning_rate != 1.0) {
+            return Err("--lr_schedule and --decay_learning_rate follow example numbers, which hogwild workers don't have")?;
+        }
+        if mi.bpr && (hogwild_training || cl.is_present("prediction_model_delay")) {
+            return Err("--bpr learns from pairs of consecutive examples, it can't be combined with --hogwild_training or --prediction_model_delay")?;
+        }
+        let deterministic_training = cl.is_present("deterministic");
+        if deterministic_training
+            && (mi.bpr || decay_learning_rate != 1.0 || early_terminate.is_some())
+        {
+            return Err("--deterministic learns on its own thread, behind the main one, it can't be combined with --bpr, --decay_learning_rate or --early_terminate")?;
+        }
+        if deterministic_training && fbt.transform_executors.has_state() {
+            return Err("--deterministic translates examples on several threads, transforms that keep state can't be split among them")?;
+        }
         let mut hogwild_trainer = if hogwild_training {
             let hogwild_threads = match cl.value_of("hogwild_threads") {
                 Some(hogwild_threads) => hogwild_threads
@@ -192,99 +431,581 @@ fn main_fw_loop() -> Result<(), Box<dyn Error>> {
                     .expect("hogwild_threads should be integer"),
                 None => 16,
             };
-            HogwildTrainer::new(sharable_regressor.clone(), &mi, hogwild_threads)
+            let queue_capacity = match cl.value_of("hogwild_queue") {
+                Some(queue_capacity) => queue_capacity.parse()?,
+                None => hogwild::DEFAULT_QUEUE_CAPACITY,
+            };
+            let queue_full = match cl.value_of("hogwild_queue_full") {
+                Some(queue_full) => hogwild::QueueFull::parse(queue_full)?,
+                None => hogwild::QueueFull::Block,
+            };
+            HogwildTrainer::new(
+                sharable_regressor.clone(),
+                &fbt,
+                hogwild_threads,
+                queue_capacity,
+                queue_full,
+            )
         } else {
             HogwildTrainer::default()
         };
+        let mut deterministic_trainer = if deterministic_training {
+            let translators = match cl.value_of("deterministic_threads") {
+                Some(translators) => translators.parse()?,
+                None => deterministic::DEFAULT_TRANSLATORS,
+            };
+            Some(DeterministicTrainer::new(
+                sharable_regressor.clone(),
+                &mi,
+                translators,
+            )?)
+        } else {
+            None
+        };
+        let hogwild_shutdown_timeout = match cl.value_of("hogwild_shutdown_timeout") {
+            Some(seconds) => Some(Duration::from_secs_f64(seconds.parse()?)),
+            None => None,
+        };
 
         let prediction_model_delay: u64 = match cl.value_of("prediction_model_delay") {
             Some(delay) => delay.parse()?,
             None => 0,
         };
 
+        let snapshot_every: u64 = match cl.value_of("snapshot_every") {
+            Some(examples) => examples.parse()?,
+            None => 0,
+        };
+        // With --signals snapshots can also be taken only on SIGUSR1
+        let signals_enabled = cl.is_present("signals");
+        if cl.is_present("snapshot_regressor") && snapshot_every == 0 && !signals_enabled {
+            return Err("--snapshot_every has to be a positive number of examples")?;
+        }
+        let mut snapshot_saver = cl.value_of("snapshot_regressor").map(BackgroundSaver::new);
+        let checkpoint_every: u64 = match cl.value_of("checkpoint_every") {
+            Some(examples) => examples.parse()?,
+            None => 0,
+        };
+        let mut checkpoint_saver = match cl.value_of("checkpoint_dir") {
+            Some(dir) => {
+                if checkpoint_every == 0 {
+                    return Err("--checkpoint_every has to be a positive number of examples")?;
+                }
+                let keep: usize = match cl.value_of("checkpoint_keep") {
+                    Some(keep) => keep.parse()?,
+                    None => 3,
+                };
+                Some(BackgroundSaver::new_rotating(dir, keep)?)
+            }
+            None => None,
+        };
+        if (snapshot_saver.is_some() || checkpoint_saver.is_some()) && hogwild_training {
+            log::warn!("Snapshots taken during hogwild training are not consistent, since workers keep updating weights while they are taken");
+        }
+        if signals_enabled {
+            signals::install()?;
+            if snapshot_saver.is_none() {
+                log::warn!("Without --snapshot_regressor SIGUSR1 only flushes predictions");
+            }
+        }
+        let mut learning_paused = false;
+
         let mut delayed_learning_fbs: VecDeque<feature_buffer::FeatureBuffer> =
             VecDeque::with_capacity(prediction_model_delay as usize);
 
-        let mut bufferred_input = create_buffered_input(input_filename);
+        let (mut bufferred_input, mut input_position) =
+            create_buffered_input_with_position(input_filename);
         let mut pa = VowpalParser::new(&vw);
 
+        let mut multi_source = match cl.values_of("source") {
+            Some(sources) => Some(MultiSource::new(&sources.collect::<Vec<&str>>(), &vw)?),
+            None => None,
+        };
+        let mut source_metrics: Vec<SourceMetrics> = match multi_source.as_ref() {
+            Some(ms) => ms
+                .sources
+                .iter()
+                .map(|_| SourceMetrics::default())
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut source_index = 0;
+
+        // Features are named from example lines, which a cache or other sources don't give back
+        let audit = cl.is_present("audit");
+        let mut invert_hash = if cl.is_present("invert_hash") || audit {
+            if cache.reading || multi_source.is_some() {
+                return Err("--invert_hash and --audit need examples from --data, not from --cache or --source")?;
+            }
+            Some(InvertHash::new(&mi, &vw))
+        } else {
+            None
+        };
+
+        let mut hash_usage = if cl.is_present("hash_usage") {
+            Some(HashSpaceUsage::new(&mi))
+        } else {
+            None
+        };
+        let hash_saturation_warning: f64 = match cl.value_of("hash_saturation_warning") {
+            Some(fraction) => fraction.parse()?,
+            None => hash_usage::DEFAULT_SATURATION_WARNING,
+        };
+        let mut feature_importance = if cl.is_present("feature_importance") {
+            Some(FeatureImportance::new(&mi)?)
+        } else {
+            None
+        };
+
+        let mut value_range_recorder = if cl.is_present("record_value_ranges") {
+            Some(ValueRangeRecorder::new(&vw, &mi.value_ranges))
+        } else {
+            None
+        };
+        let value_range_checker = if cl.is_present("check_value_ranges") {
+            Some(ValueRangeChecker::new(&mi.value_ranges)?)
+        } else {
+            None
+        };
+
+        // Snapshots taken in later passes can't be resumed from, the input was read more than once
+        let data_fingerprint =
+            if (snapshot_saver.is_some() || checkpoint_saver.is_some()) && passes == 1 {
+                Some(DataFingerprint::new(input_filename)?)
+            } else {
+                None
+            };
+
         let now = Instant::now();
         let mut example_num = 0;
-        loop {
-            let reading_result;
-            let buffer: &[u32];
-            if !cache.reading {
-                reading_result = pa.next_vowpal(&mut bufferred_input);
-                buffer = match reading_result {
-                    Ok([]) => break, // EOF
-                    Ok(buffer2) => buffer2,
-                    Err(_e) => return Err(_e),
-                };
-                if cache.writing {
-                    cache.push_record(buffer)?;
+        if let Some(resume_point) = resume_point.as_ref() {
+            if cache.reading {
+                for _ in 0..resume_point.examples {
+                    if cache.get_next_record()?.is_empty() {
+                        return Err("Cache ended before the resume point")?;
+                    }
                 }
             } else {
-                reading_result = cache.get_next_record();
-                buffer = match reading_result {
-                    Ok([]) => break, // EOF
-                    Ok(buffer) => buffer,
-                    Err(_e) => return Err(_e),
-                };
+                skip_input(&mut bufferred_input, resume_point.input_offset)?;
+                // The cache would be missing the examples before the resume point
+                cache.abandon_writing()?;
             }
-            example_num += 1;
-            let mut prediction: f32 = 0.0;
-
-            if prediction_model_delay == 0 {
-                let update = match holdout_after_option {
-                    Some(holdout_after) => !testonly && example_num < holdout_after,
-                    None => !testonly,
-                };
-                if hogwild_training && update {
-                    hogwild_trainer.digest_example(Vec::from(buffer));
+            example_num = resume_point.examples;
+            log::info!("Resuming training after example {}", example_num);
+        }
+        let mut pass_start = 0; // example_num before the first example of the pass
+        let mut holdout_metrics = SourceMetrics::default();
+        let mut progressive_validation = ProgressiveValidation::new();
+        let mut streaming_metrics = StreamingMetrics::new();
+        let mut best_holdout_logloss = f64::MAX;
+        let mut passes_without_improvement = 0;
+        // --bpr: the first example of a pair, with its tag and label, until its partner comes
+        let mut bpr_pending: Option<(feature_buffer::FeatureBuffer, Vec<u8>, u32)> = None;
+        // --heads: predictions of all heads for the example, the port buffer has them until the next learn()
+        let mut head_predictions: Vec<f32> = Vec::new();
+        for pass in 1..=passes {
+            loop {
+                let reading_result;
+                let buffer: &[u32];
+                if let Some(ms) = multi_source.as_mut() {
+                    match ms.next_record()? {
+                        Some((index, record)) => {
+                            source_index = index;
+                            buffer = record;
+                        }
+                        None => break, // all sources exhausted
+                    }
+                } else if !cache.reading {
+                    let (record, line) = pa.next_vowpal_with_line(&mut bufferred_input)?;
+                    if record.is_empty() {
+                        break; // EOF
+                    }
+                    if let Some(ih) = invert_hash.as_mut() {
+                        ih.observe(line)?;
+                        if audit {
+                            println!("{}", ih.audit_line());
+                        }
+                    }
+                    buffer = record;
+                    if cache.writing {
+                        cache.push_record(buffer)?;
+                    }
+                } else {
+                    reading_result = cache.get_next_record();
+                    buffer = match reading_result {
+                        Ok([]) => break, // EOF
+                        Ok(buffer) => buffer,
+                        Err(_e) => return Err(_e),
+                    };
+                }
+                parser::check_label(buffer, mi.oaa)?;
+                example_num += 1;
+                if signals_enabled && signals::learning_paused() != learning_paused {
+                    learning_paused = !learning_paused;
+                    log::info!(
+                        "Learning {} at example {}",
+                        if learning_paused { "paused" } else { "resumed" },
+                        example_num
+                    );
+                }
+                let learning = !testonly && !learning_paused;
+                if let Some(checker) = value_range_checker.as_ref() {
+                    checker.observe(buffer);
+                }
+                if let Some(recorder) = value_range_recorder.as_mut() {
+                    recorder.observe(buffer);
+                }
+                let mut prediction: f32 = 0.0;
+                let mut predicted = false;
+                let holdout = prediction_model_delay == 0
+                    && (holdout_after_option.map_or(false, |holdout_after| {
+                        example_num - pass_start >= holdout_after
+                    }) || (holdout_period > 0
+                        && (example_num - pass_start) % holdout_period == 0));
+
+                if prediction_model_delay == 0 {
+                    let update = learning && !holdout;
+                    if hogwild_training && update {
+                        hogwild_trainer.digest_example(Vec::from(buffer))?;
+                        if hash_usage.is_some() || feature_importance.is_some() {
+                            // workers translate on their own, usage is tracked on a separate translation
+                            fbt.translate_without_observing(buffer, example_num);
+                        }
+                    } else if let Some(trainer) = deterministic_trainer.as_mut() {
+                        if update {
+                            trainer.digest_example(Vec::from(buffer), example_num)?;
+                        } else {
+                            // Predicted by the trainer thread, after it learned from all examples before
+                            let (trainer_prediction, trainer_head_predictions) =
+                                trainer.predict_example(Vec::from(buffer), example_num)?;
+                            prediction = trainer_prediction;
+                            predicted = true;
+                            head_predictions = trainer_head_predictions;
+                        }
+                        if hash_usage.is_some() || feature_importance.is_some() {
+                            fbt.translate_without_observing(buffer, example_num);
+                        }
+                    } else if mi.bpr {
+                        fbt.translate(buffer, example_num);
+                        prediction = sharable_regressor.learn(&fbt.feature_buffer, &mut pb, false);
+                        predicted = true;
+                        let label = buffer[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK;
+                        match (update && label <= 1, parser::get_tag(buffer)) {
+                            (true, Some(tag)) => match bpr_pending.take() {
+                                Some((pending_fb, pending_tag, pending_label))
+                                    if pending_tag == tag && pending_label != label =>
+                                {
+                                    sharable_regressor.learn_pair(
+                                        &pending_fb,
+                                        &fbt.feature_buffer,
+                                        &mut pb,
+                                    );
+                                }
+                                _ => bpr_pending = Some((fbt.feature_buffer.clone(), tag, label)),
+                            },
+                            _ => bpr_pending = None,
+                        }
+                    } else {
+                        fbt.translate(buffer, example_num);
+                        prediction = sharable_regressor.learn(&fbt.feature_buffer, &mut pb, update);
+                        predicted = true;
+                        head_predictions.clone_from(&pb.head_predictions);
+                    }
                 } else {
                     fbt.translate(buffer, example_num);
-                    prediction = sharable_regressor.learn(&fbt.feature_buffer, &mut pb, update);
+                    if example_num > predictions_after {
+                        prediction = sharable_regressor.learn(&fbt.feature_buffer, &mut pb, false);
+                        predicted = true;
+                        head_predictions.clone_from(&pb.head_predictions);
+                    }
+                    delayed_learning_fbs.push_back(fbt.feature_buffer.clone());
+                    if (prediction_model_delay as usize) < delayed_learning_fbs.len() {
+                        let delayed_buffer = delayed_learning_fbs.pop_front().unwrap();
+                        sharable_regressor.learn(&delayed_buffer, &mut pb, learning);
+                    }
                 }
-            } else {
-                fbt.translate(buffer, example_num);
+
+                if let Some(usage) = hash_usage.as_mut() {
+                    usage.observe(&fbt.feature_buffer);
+                }
+                if let Some(importance) = feature_importance.as_mut() {
+                    importance.observe(&fbt.feature_buffer, mi.ffm_k);
+                }
+
                 if example_num > predictions_after {
-                    prediction = sharable_regressor.learn(&fbt.feature_buffer, &mut pb, false);
+                    // --oaa predicts the class number, --heads a probability per head
+                    let prediction_str = if mi.oaa > 0 {
+                        format!("{}", prediction)
+                    } else if mi.heads > 0 && predicted {
+                        head_predictions
+                            .iter()
+                            .map(|p| format!("{:.6}", p))
+                            .collect::<Vec<String>>()
+                            .join(",")
+                    } else {
+                        format!("{:.6}", sharable_regressor.map_score(prediction))
+                    };
+                    if output_pred_sto {
+                        println!("{}", prediction_str);
+                    }
+
+                    if let Some(file) = predictions_file.as_mut() {
+                        writeln!(file, "{}", prediction_str)?;
+                    }
+
+                    if let Some(file) = labels_file.as_mut() {
+                        write_label_line(file, buffer)?;
+                    }
                 }
-                delayed_learning_fbs.push_back(fbt.feature_buffer.clone());
-                if (prediction_model_delay as usize) < delayed_learning_fbs.len() {
-                    let delayed_buffer = delayed_learning_fbs.pop_front().unwrap();
-                    sharable_regressor.learn(&delayed_buffer, &mut pb, !testonly);
+
+                // Metrics are of probabilities of binary labels, not of --oaa classes
+                let evaluated_prediction = (predicted && mi.oaa == 0).then(|| prediction);
+                if let Some(metrics) = source_metrics.get_mut(source_index) {
+                    metrics.add_example(buffer, evaluated_prediction);
+                }
+                streaming_metrics.add_example(buffer, evaluated_prediction);
+                if holdout {
+                    holdout_metrics.add_example(buffer, evaluated_prediction);
+                } else if let Some(line) =
+                    progressive_validation.add_example(example_num, buffer, evaluated_prediction)
+                {
+                    log::info!("{}", line);
                 }
-            }
 
-            if example_num > predictions_after {
-                if output_pred_sto {
-                    println!("{:.6}", prediction);
+                let snapshot_requested = signals_enabled && signals::take_snapshot_request();
+                if snapshot_requested {
+                    if let Some(file) = predictions_file.as_mut() {
+                        file.flush()?;
+                    }
+                    if let Some(file) = labels_file.as_mut() {
+                        file.flush()?;
+                    }
+                    io::stdout().flush()?;
+                    log::info!(
+                        "SIGUSR1 at example {}, elapsed: {:.2?}",
+                        example_num,
+                        now.elapsed()
+                    );
+                    if let Some(ms) = multi_source.as_ref() {
+                        for (source, metrics) in ms.sources.iter().zip(source_metrics.iter()) {
+                            log::info!("Source {} {}", source.filename, metrics);
+                        }
+                    }
+                    if hogwild_training {
+                        log::info!("Hogwild {}", hogwild_trainer.stats());
+                    }
                 }
 
-                match predictions_file.as_mut() {
-                    Some(file) => writeln!(file, "{:.6}", prediction)?,
-                    None => {}
+                let snapshot_due = snapshot_saver.is_some()
+                    && (snapshot_requested
+                        || (snapshot_every > 0 && example_num % snapshot_every == 0));
+                let checkpoint_due =
+                    checkpoint_saver.is_some() && example_num % checkpoint_every == 0;
+                if snapshot_due || checkpoint_due {
+                    mi.resume_point = data_fingerprint.as_ref().map(|fingerprint| ResumePoint {
+                        examples: example_num,
+                        input_offset: input_position.get(),
+                        data_fingerprint: fingerprint.clone(),
+                    });
+                    // The saved weights have to have learned from all examples up to the resume point
+                    if let Some(trainer) = deterministic_trainer.as_mut() {
+                        trainer.drain()?;
+                    }
+                    sharable_regressor.apply_minibatch(&mut pb);
+                    if snapshot_due {
+                        let saver = snapshot_saver.as_mut().unwrap();
+                        saver.snapshot(&mi, &vw, &sharable_regressor, quantize_weights)?;
+                        // Counters of a resumed snapshot continue from where it was taken
+                        if fbt.transform_executors.has_state() {
+                            let filename = match transform_state_filename {
+                                Some(filename) => filename.to_string(),
+                                None => model_transform_state_filename(
+                                    cl.value_of("snapshot_regressor").unwrap(),
+                                ),
+                            };
+                            fbt.transform_executors.save_state_to_filename(&filename)?;
+                        }
+                    }
+                    if checkpoint_due {
+                        let saver = checkpoint_saver.as_mut().unwrap();
+                        saver.snapshot(&mi, &vw, &sharable_regressor, quantize_weights)?;
+                    }
                 }
             }
+
+            if passes > 1 {
+                log::info!(
+                    "Pass {} done after {} examples, holdout {}",
+                    pass,
+                    example_num - pass_start,
+                    holdout_metrics
+                );
+            }
+            if pass == passes || example_num == pass_start {
+                break;
+            }
+            if let Some(early_terminate) = early_terminate {
+                let holdout_logloss =
+                    holdout_metrics.logloss_sum / holdout_metrics.evaluated.max(1) as f64;
+                if holdout_logloss < best_holdout_logloss {
+                    best_holdout_logloss = holdout_logloss;
+                    passes_without_improvement = 0;
+                } else {
+                    passes_without_improvement += 1;
+                }
+                if passes_without_improvement >= early_terminate {
+                    log::info!("Early termination after pass {}, holdout logloss did not improve in {} passes", pass, early_terminate);
+                    break;
+                }
+            }
+            if pass == 1 && decay_learning_rate != 1.0 {
+                LRSchedule::add_pass_decay(&mut mi.lr_schedule, decay_learning_rate, example_num);
+                sharable_regressor.set_lr_schedule(&mi.lr_schedule);
+            }
+
+            // Next pass starts from the beginning of the input, or of the cache written in the first pass
+            if let Some(ms) = multi_source.as_mut() {
+                *ms =
+                    MultiSource::new(&cl.values_of("source").unwrap().collect::<Vec<&str>>(), &vw)?;
+            } else if cache.reading || cache.writing {
+                cache.write_finish()?;
+                drop(cache);
+                cache = open_cache()?;
+            } else {
+                (bufferred_input, input_position) =
+                    create_buffered_input_with_position(input_filename);
+            }
+            pass_start = example_num;
+            holdout_metrics = SourceMetrics::default();
+            bpr_pending = None;
+        }
+        if let Some(saver) = snapshot_saver.as_mut() {
+            saver.wait_for_pending_write()?;
+        }
+        if let Some(saver) = checkpoint_saver.as_mut() {
+            saver.wait_for_pending_write()?;
         }
         cache.write_finish()?;
 
         if hogwild_training {
-            hogwild_trainer.block_until_workers_finished();
+            let stats = hogwild_trainer.stats();
+            let worker_examples =
+                hogwild_trainer.block_until_workers_finished(hogwild_shutdown_timeout)?;
+            log::info!("Hogwild {}", stats);
+            log::info!(
+                "Hogwild workers learned from {:?} examples",
+                worker_examples
+            );
+        }
+        if let Some(trainer) = deterministic_trainer.take() {
+            let examples = trainer.block_until_finished()?;
+            log::info!("Deterministic trainer learned from {} examples", examples);
         }
+        sharable_regressor.apply_minibatch(&mut pb);
         let elapsed = now.elapsed();
         log::info!("Elapsed: {:.2?} rows: {}", elapsed, example_num);
+        if progressive_validation.total.evaluated > 0 {
+            log::info!("Progressive validation {}", progressive_validation.total);
+        }
+        if streaming_metrics.examples > 0 {
+            log::info!("Metrics {}", streaming_metrics);
+        }
+        if passes == 1 && holdout_metrics.evaluated > 0 {
+            log::info!("Holdout {}", holdout_metrics);
+        }
+        if let Some(ms) = multi_source.as_ref() {
+            for (source, metrics) in ms.sources.iter().zip(source_metrics.iter()) {
+                log::info!(
+                    "Source {} (importance {}) {}",
+                    source.filename,
+                    source.importance,
+                    metrics
+                );
+            }
+        }
 
+        if let Some(usage) = hash_usage.as_ref() {
+            usage.report(hash_saturation_warning);
+        }
+        if let (Some(importance), Some(filename)) = (
+            feature_importance.as_ref(),
+            cl.value_of("feature_importance"),
+        ) {
+            importance.write_report(filename, &mi, &vw, &sharable_regressor)?;
+        }
+        if let (Some(ih), Some(filename)) = (invert_hash.as_ref(), cl.value_of("invert_hash")) {
+            let mut output = BufWriter::new(File::create(filename)?);
+            ih.write(&mi, &sharable_regressor, &mut output)?;
+            output.flush()?;
+            log::info!(
+                "Wrote {} lr and {} ffm named weights to {}",
+                ih.lr_names.len(),
+                ih.ffm_names.len(),
+                filename
+            );
+        }
+        if let Some(checker) = value_range_checker.as_ref() {
+            log::info!("Value ranges: {}", checker);
+        }
+        if let Some(recorder) = value_range_recorder.as_ref() {
+            mi.value_ranges = recorder.ranges();
+        }
+
+        let dp_num_blocks = if mi.ffm_fields.is_empty() { 1 } else { 2 };
+        if let Some(dp) = mi.dp.as_mut() {
+            if !testonly {
+                dp.rounds += 1;
+            }
+            let dp_delta: f64 = match cl.value_of("dp_delta") {
+                Some(delta) => delta.parse()?,
+                None => 1e-6,
+            };
+            log::info!(
+                "Differential privacy: epsilon {:.4} at delta {} after {} rounds",
+                optimizer::dp_epsilon(dp.noise_multiplier, dp.rounds, dp_num_blocks, dp_delta),
+                dp_delta,
+                dp.rounds
+            );
+        }
+
+        if let Some(filename) = transform_state_filename {
+            fbt.transform_executors.save_state_to_filename(filename)?;
+        } else if let Some(filename) = final_regressor_filename {
+            if fbt.transform_executors.has_state() {
+                fbt.transform_executors
+                    .save_state_to_filename(&model_transform_state_filename(filename))?;
+            }
+        }
+        fbt.transform_executors.log_metrics();
+        if mi.dup_policy.is_some() {
+            log::info!("Duplicate features merged: {}", fbt.duplicate_features);
+        }
+
+        mi.resume_point = None;
+        if let Some(schedule) = mi.lr_schedule.as_mut() {
+            if !testonly {
+                schedule.examples_done += example_num;
+            }
+        }
         if let Some(filename) = final_regressor_filename {
-            save_sharable_regressor_to_filename(
-                filename,
-                &mi,
-                &vw,
-                sharable_regressor,
-                quantize_weights,
-            )
-            .unwrap()
+            if let Some(base_filename) = delta_base_filename {
+                delta::save_delta_to_filename(
+                    filename,
+                    base_filename,
+                    &mi,
+                    &vw,
+                    &sharable_regressor,
+                )?;
+            } else {
+                save_sharable_regressor_to_filename(
+                    filename,
+                    &mi,
+                    &vw,
+                    sharable_regressor,
+                    quantize_weights,
+                )
+                .unwrap()
+            }
         }
     }
 
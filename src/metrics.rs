@@ -0,0 +1,217 @@
+use crate::parser;
+use std::fmt;
+
+// Streaming AUC, logloss and calibration in constant memory. Predictions are counted in a histogram of
+// AUC_BINS equal bins over [0, 1], so AUC is exact up to ties within a bin, which count as half ordered.
+pub const AUC_BINS: usize = 1 << 14;
+pub const CALIBRATION_BUCKETS: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CalibrationBucket {
+    pub examples: u64,
+    pub prediction_sum: f64,
+    pub positives: u64,
+}
+
+impl CalibrationBucket {
+    pub fn predicted_ctr(&self) -> f64 {
+        self.prediction_sum / self.examples as f64
+    }
+
+    pub fn observed_ctr(&self) -> f64 {
+        self.positives as f64 / self.examples as f64
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamingMetrics {
+    pub examples: u64,
+    pub positives: u64,
+    logloss_sum: f64,
+    positive_bins: Vec<u64>,
+    negative_bins: Vec<u64>,
+    pub calibration: [CalibrationBucket; CALIBRATION_BUCKETS],
+}
+
+impl Default for StreamingMetrics {
+    fn default() -> StreamingMetrics {
+        StreamingMetrics::new()
+    }
+}
+
+fn bin(prediction: f64, bins: usize) -> usize {
+    ((prediction * bins as f64) as usize).min(bins - 1)
+}
+
+impl StreamingMetrics {
+    pub fn new() -> StreamingMetrics {
+        StreamingMetrics {
+            examples: 0,
+            positives: 0,
+            logloss_sum: 0.0,
+            positive_bins: vec![0; AUC_BINS],
+            negative_bins: vec![0; AUC_BINS],
+            calibration: [CalibrationBucket::default(); CALIBRATION_BUCKETS],
+        }
+    }
+
+    pub fn add(&mut self, prediction: f32, label: bool) {
+        let p = (prediction as f64).clamp(1e-7, 1.0 - 1e-7);
+        self.examples += 1;
+        let bucket = &mut self.calibration[bin(p, CALIBRATION_BUCKETS)];
+        bucket.examples += 1;
+        bucket.prediction_sum += p;
+        if label {
+            self.positives += 1;
+            self.logloss_sum -= p.ln();
+            self.positive_bins[bin(p, AUC_BINS)] += 1;
+            bucket.positives += 1;
+        } else {
+            self.logloss_sum -= (1.0 - p).ln();
+            self.negative_bins[bin(p, AUC_BINS)] += 1;
+        }
+    }
+
+    // Examples without a prediction or a label are skipped
+    pub fn add_example(&mut self, record: &[u32], prediction: Option<f32>) {
+        let label = record[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK;
+        if let Some(prediction) = prediction {
+            if label != parser::NO_LABEL {
+                self.add(prediction, label == 1);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &StreamingMetrics) {
+        self.examples += other.examples;
+        self.positives += other.positives;
+        self.logloss_sum += other.logloss_sum;
+        for (a, b) in self
+            .positive_bins
+            .iter_mut()
+            .zip(other.positive_bins.iter())
+        {
+            *a += b;
+        }
+        for (a, b) in self
+            .negative_bins
+            .iter_mut()
+            .zip(other.negative_bins.iter())
+        {
+            *a += b;
+        }
+        for (a, b) in self.calibration.iter_mut().zip(other.calibration.iter()) {
+            a.examples += b.examples;
+            a.prediction_sum += b.prediction_sum;
+            a.positives += b.positives;
+        }
+    }
+
+    pub fn logloss(&self) -> f64 {
+        self.logloss_sum / self.examples as f64
+    }
+
+    // NaN when there are no positive or no negative examples, like golden_set::auc()
+    pub fn auc(&self) -> f64 {
+        let negatives = self.examples - self.positives;
+        if self.positives == 0 || negatives == 0 {
+            return f64::NAN;
+        }
+        // Pairs where the positive is in a higher bin than the negative, plus half of the pairs in the same bin
+        let mut negatives_below = 0u64;
+        let mut ordered_pairs = 0.0;
+        for (positives, negatives) in self.positive_bins.iter().zip(self.negative_bins.iter()) {
+            ordered_pairs += *positives as f64 * (negatives_below as f64 + *negatives as f64 / 2.0);
+            negatives_below += negatives;
+        }
+        ordered_pairs / (self.positives as f64 * negatives as f64)
+    }
+}
+
+impl fmt::Display for StreamingMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "examples: {}, logloss: {:.6}, auc: {:.6}, calibration (predicted/observed ctr):",
+            self.examples,
+            self.logloss(),
+            self.auc()
+        )?;
+        for (i, bucket) in self.calibration.iter().enumerate() {
+            if bucket.examples > 0 {
+                write!(
+                    f,
+                    " [{:.1}-{:.1}) {}: {:.4}/{:.4}",
+                    i as f64 / CALIBRATION_BUCKETS as f64,
+                    (i + 1) as f64 / CALIBRATION_BUCKETS as f64,
+                    bucket.examples,
+                    bucket.predicted_ctr(),
+                    bucket.observed_ctr()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::golden_set;
+    use rand::Rng;
+    use rand_xoshiro::rand_core::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn test_streaming_metrics() {
+        let mut metrics = StreamingMetrics::new();
+        assert!(metrics.auc().is_nan());
+        metrics.add(0.2, false);
+        metrics.add(0.8, true);
+        assert_eq!(metrics.auc(), 1.0);
+        metrics.add(0.8, false);
+        assert_eq!(metrics.auc(), 0.75);
+        assert!(
+            (metrics.logloss() - (-(0.8f64.ln() + 0.8f64.ln() + 0.2f64.ln()) / 3.0)).abs() < 1e-6
+        );
+        assert_eq!(metrics.calibration[2].examples, 1);
+        assert_eq!(metrics.calibration[8].examples, 2);
+        assert_eq!(metrics.calibration[8].positives, 1);
+        assert!((metrics.calibration[8].predicted_ctr() - 0.8).abs() < 1e-6);
+        assert_eq!(metrics.calibration[8].observed_ctr(), 0.5);
+        // Predictions at the ends of the range
+        metrics.add(0.0, false);
+        metrics.add(1.0, true);
+        assert_eq!(metrics.calibration[0].examples, 1);
+        assert_eq!(metrics.calibration[9].examples, 1);
+        assert_eq!(
+            format!("{}", metrics).split(" [").next().unwrap(),
+            "examples: 5, logloss: 0.411145, auc: 0.916667, calibration (predicted/observed ctr):"
+        );
+    }
+
+    #[test]
+    fn test_streaming_auc_matches_exact() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(7);
+        let mut metrics = StreamingMetrics::new();
+        let mut halves = (StreamingMetrics::new(), StreamingMetrics::new());
+        let mut predictions_and_labels = Vec::new();
+        for i in 0..10000 {
+            let prediction = rng.gen::<f32>();
+            let label = rng.gen::<f32>() < prediction;
+            metrics.add(prediction, label);
+            if i % 2 == 0 {
+                halves.0.add(prediction, label);
+            } else {
+                halves.1.add(prediction, label);
+            }
+            predictions_and_labels.push((prediction, label));
+        }
+        let exact = golden_set::auc(&mut predictions_and_labels);
+        assert!((metrics.auc() - exact).abs() < 1e-4);
+        halves.0.merge(&halves.1);
+        assert_eq!(halves.0.auc(), metrics.auc());
+        assert_eq!(halves.0.examples, 10000);
+    }
+}
@@ -3,10 +3,22 @@ use std::io::Error as IOError;
 use std::io::ErrorKind;
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use crate::autotune;
+use crate::autotune::KernelChoice;
+use crate::blend::{BlendConfig, MergeConfig};
+use crate::block_embedding_lookup;
+use crate::config_file::ConfigFile;
 use crate::feature_transform_parser;
-use crate::vwmap::{NamespaceDescriptor, VwNamespaceMap};
+use crate::optimizer;
+use crate::parser;
+use crate::quantization::WeightQuantization;
+use crate::resume::ResumePoint;
+use crate::rng;
+use crate::score_map::ScoreMap;
+use crate::value_ranges::ValueRange;
+use crate::vwmap::{NamespaceDescriptor, NamespaceFormat, NamespaceType, VwNamespaceMap};
 
 const WEIGHT_DELIM: &str = ":";
 const VERBOSE_FIELD_DELIM: &str = ",";
@@ -25,10 +37,406 @@ pub enum Optimizer {
     SGD = 100,
     AdagradFlex = 200,
     AdagradLUT = 300,
+    COCOB = 400, // only for the LR block, see --lr_optimizer
+    Adam = 500,
+    FTRL = 600, // only for the LR block, see --lr_optimizer
+    SGDMomentum = 700,
+    SGDNesterov = 800,
+}
+
+impl Optimizer {
+    pub fn parse(s: &str, fastmath: bool) -> Result<Optimizer, Box<dyn Error>> {
+        match s {
+            "sgd" => Ok(Optimizer::SGD),
+            "adagrad" if fastmath => Ok(Optimizer::AdagradLUT),
+            "adagrad" => Ok(Optimizer::AdagradFlex),
+            "cocob" => Ok(Optimizer::COCOB),
+            "adam" => Ok(Optimizer::Adam),
+            "ftrl" => Ok(Optimizer::FTRL),
+            "momentum" => Ok(Optimizer::SGDMomentum),
+            "nesterov" => Ok(Optimizer::SGDNesterov),
+            _ => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Unknown optimizer {}, expected sgd, adagrad, cocob, adam, ftrl, momentum or nesterov",
+                    s
+                ),
+            ))),
+        }
+    }
 }
 
 pub type FieldDesc = Vec<NamespaceDescriptor>;
 
+// Number of examples, optionally with K/M/G suffix, e.g. 10M
+pub fn parse_example_count(s: &str) -> Result<u64, Box<dyn Error>> {
+    let (digits, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1_000),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1_000_000),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1_000_000_000),
+        _ => (s, 1),
+    };
+    Ok(digits.parse::<u64>()? * multiplier)
+}
+
+// zstd compression level of --compress_model
+pub fn parse_compress_model(s: &str) -> Result<i32, Box<dyn Error>> {
+    let level = s.parse::<i32>()?;
+    if !(1..=22).contains(&level) {
+        return Err(format!(
+            "--compress_model level has to be from 1 to 22, got: {}",
+            level
+        ))?;
+    }
+    Ok(level)
+}
+
+// LR weights of features from this namespace lose half of their value every ttl_examples
+// examples in which they are not seen
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NamespaceTTL {
+    pub namespace_descriptor: NamespaceDescriptor,
+    pub ttl_examples: u64,
+}
+
+// Values of an f32vec namespace that go directly to the nn layers as dense inputs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DenseInputDesc {
+    pub namespace_descriptor: NamespaceDescriptor,
+    pub len: u32,
+}
+
+// How the embeddings of the features of an example are combined into a single vector
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EmbeddingPooling {
+    Sum,
+    Mean,
+    Max,
+}
+
+impl EmbeddingPooling {
+    pub fn parse(s: &str) -> Option<EmbeddingPooling> {
+        match s {
+            "sum" => Some(EmbeddingPooling::Sum),
+            "mean" => Some(EmbeddingPooling::Mean),
+            "max" => Some(EmbeddingPooling::Max),
+            _ => None,
+        }
+    }
+}
+
+// Pre-trained embeddings of the features of a namespace, looked up by feature hash and pooled
+// into the input of the nn layers. The table itself is stored with the weights of the model.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmbeddingLookupDesc {
+    pub namespace_descriptor: NamespaceDescriptor,
+    pub namespace_vwname: String,
+    pub filename: String,
+    pub pooling: EmbeddingPooling,
+    pub finetune: bool, // otherwise the table is fixed
+    pub rows: u32,
+    pub dim: u32,
+    #[serde(default = "default_embedding_max_len")]
+    pub max_len: u32, // mean and max pool at most this many features of an example
+}
+
+pub const DEFAULT_EMBEDDING_MAX_LEN: u32 = 32;
+
+fn default_embedding_max_len() -> u32 {
+    DEFAULT_EMBEDDING_MAX_LEN
+}
+
+// Only the k features with the largest provided weights are kept from this namespace
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NamespaceTopK {
+    pub namespace_descriptor: NamespaceDescriptor,
+    pub k: u32,
+}
+
+// What to do with features that appear more than once in the same namespace of an example
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DupPolicy {
+    Sum,
+    Max,
+    First,
+}
+
+impl DupPolicy {
+    pub fn parse(s: &str) -> Result<DupPolicy, Box<dyn Error>> {
+        match s {
+            "sum" => Ok(DupPolicy::Sum),
+            "max" => Ok(DupPolicy::Max),
+            "first" => Ok(DupPolicy::First),
+            _ => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--dup_policy has to be one of sum, max or first, got: \"{}\"",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
+// How the FFM weight table is laid out, see block_ffm::FfmIndexing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FfmLayout {
+    FeatureMajor, // the embeddings of a feature towards all fields follow each other
+    FieldMajor, // a region of the table for each field, with the embeddings of features towards it
+}
+
+impl FfmLayout {
+    pub fn parse(s: &str) -> Result<FfmLayout, Box<dyn Error>> {
+        match s {
+            "feature_major" => Ok(FfmLayout::FeatureMajor),
+            "field_major" => Ok(FfmLayout::FieldMajor),
+            _ => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--ffm_layout has to be one of feature_major or field_major, got: \"{}\"",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
+// Per example gradient clipping and noise of LR and FFM updates, see optimizer::DPGradient
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DPConfig {
+    pub clip: f32,
+    pub noise_multiplier: f32,
+    pub rounds: u32, // passes over the data the model was already trained with, for the accountant
+}
+
+impl DPConfig {
+    pub fn new_from_cmdline(cl: &clap::ArgMatches) -> Result<Option<DPConfig>, Box<dyn Error>> {
+        let clip: f32 = match cl.value_of("dp_clip") {
+            Some(val) => val.parse()?,
+            None => return Ok(None),
+        };
+        let noise_multiplier: f32 = match cl.value_of("dp_noise") {
+            Some(val) => val.parse()?,
+            None => 0.0,
+        };
+        if !(clip > 0.0 && noise_multiplier >= 0.0) {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--dp_clip has to be positive and --dp_noise non-negative, got: {} and {}",
+                    clip, noise_multiplier
+                ),
+            )));
+        }
+        Ok(Some(DPConfig {
+            clip,
+            noise_multiplier,
+            rounds: 0,
+        }))
+    }
+}
+
+// Dropout rate of the hidden nn layers that moves linearly from start_rate to end_rate
+// over the first num_examples examples and then stays at end_rate
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DropoutSchedule {
+    pub start_rate: f32,
+    pub end_rate: f32,
+    pub num_examples: u64,
+}
+
+impl DropoutSchedule {
+    pub fn parse(s: &str) -> Result<DropoutSchedule, Box<dyn Error>> {
+        let vsplit: Vec<&str> = s.split(':').collect();
+        if vsplit.len() != 4 || vsplit[0] != "linear" {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("--nn_dropout_schedule has to be of the form linear:start_rate:end_rate:num_examples, got: \"{}\"", s),
+            )));
+        }
+        let start_rate: f32 = vsplit[1].parse()?;
+        let end_rate: f32 = vsplit[2].parse()?;
+        for rate in [start_rate, end_rate].iter() {
+            if !(0.0..1.0).contains(rate) {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "--nn_dropout_schedule rates have to be in range [0.0, 1.0), got: {}",
+                        rate
+                    ),
+                )));
+            }
+        }
+        let num_examples = parse_example_count(vsplit[3])?;
+        Ok(DropoutSchedule {
+            start_rate,
+            end_rate,
+            num_examples,
+        })
+    }
+
+    #[inline(always)]
+    pub fn rate_at(&self, example_number: u64) -> f32 {
+        if example_number >= self.num_examples {
+            return self.end_rate;
+        }
+        let progress = example_number as f32 / self.num_examples as f32;
+        self.start_rate + (self.end_rate - self.start_rate) * progress
+    }
+}
+
+// Learning rate multiplier as a function of the example number (--lr_schedule). Phases are
+// separated by commas and their multipliers multiply, e.g. warmup:100K,cosine:10M
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LRSchedulePhase {
+    // linearly from 0.0 to 1.0 over the first num_examples
+    Warmup {
+        num_examples: u64,
+    },
+    // factor^(examples / num_examples), e.g. exp:0.5:1M halves it every million examples
+    Exponential {
+        factor: f32,
+        num_examples: u64,
+    },
+    // half a cosine from 1.0 to min_multiplier over num_examples, then min_multiplier
+    Cosine {
+        num_examples: u64,
+        min_multiplier: f32,
+    },
+    // factor^(whole num_examples since first_example), --decay_learning_rate once per pass of --passes
+    Step {
+        factor: f32,
+        num_examples: u64,
+        first_example: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LRSchedule {
+    pub phases: Vec<LRSchedulePhase>,
+    // Examples the model was trained on in earlier runs, so continuing training continues the curve
+    pub examples_done: u64,
+}
+
+impl LRSchedulePhase {
+    fn parse(s: &str) -> Result<LRSchedulePhase, Box<dyn Error>> {
+        let vsplit: Vec<&str> = s.split(':').collect();
+        let phase = match (vsplit[0], vsplit.len()) {
+            ("warmup", 2) => LRSchedulePhase::Warmup {
+                num_examples: parse_example_count(vsplit[1])?,
+            },
+            ("exp", 3) => LRSchedulePhase::Exponential {
+                factor: vsplit[1].parse()?,
+                num_examples: parse_example_count(vsplit[2])?,
+            },
+            ("cosine", 2) | ("cosine", 3) => LRSchedulePhase::Cosine {
+                num_examples: parse_example_count(vsplit[1])?,
+                min_multiplier: match vsplit.get(2) {
+                    Some(min_multiplier) => min_multiplier.parse()?,
+                    None => 0.0,
+                },
+            },
+            _ => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("--lr_schedule phases have to be one of warmup:num_examples, exp:factor:num_examples or cosine:num_examples[:min_multiplier], got: \"{}\"", s),
+                )))
+            }
+        };
+        let num_examples = match phase {
+            LRSchedulePhase::Warmup { num_examples } => num_examples,
+            LRSchedulePhase::Exponential {
+                factor,
+                num_examples,
+            } => {
+                if factor <= 0.0 {
+                    return Err(format!(
+                        "--lr_schedule factor has to be positive, got: {}",
+                        factor
+                    ))?;
+                }
+                num_examples
+            }
+            LRSchedulePhase::Cosine { num_examples, .. } => num_examples,
+            LRSchedulePhase::Step { num_examples, .. } => num_examples,
+        };
+        if num_examples == 0 {
+            return Err(format!(
+                "--lr_schedule phase \"{}\" has to last at least one example",
+                s
+            ))?;
+        }
+        Ok(phase)
+    }
+
+    fn multiplier_at(&self, example_number: u64) -> f32 {
+        match *self {
+            LRSchedulePhase::Warmup { num_examples } => {
+                (example_number as f32 / num_examples as f32).min(1.0)
+            }
+            LRSchedulePhase::Exponential {
+                factor,
+                num_examples,
+            } => factor.powf(example_number as f32 / num_examples as f32),
+            LRSchedulePhase::Cosine {
+                num_examples,
+                min_multiplier,
+            } => {
+                let progress = (example_number as f32 / num_examples as f32).min(1.0);
+                min_multiplier
+                    + (1.0 - min_multiplier) * 0.5 * (1.0 + (std::f32::consts::PI * progress).cos())
+            }
+            LRSchedulePhase::Step {
+                factor,
+                num_examples,
+                first_example,
+            } => {
+                if example_number < first_example {
+                    1.0
+                } else {
+                    factor.powi(((example_number - first_example) / num_examples) as i32)
+                }
+            }
+        }
+    }
+}
+
+impl LRSchedule {
+    pub fn parse(s: &str) -> Result<LRSchedule, Box<dyn Error>> {
+        let phases = s
+            .split(',')
+            .map(LRSchedulePhase::parse)
+            .collect::<Result<Vec<LRSchedulePhase>, Box<dyn Error>>>()?;
+        Ok(LRSchedule {
+            phases,
+            examples_done: 0,
+        })
+    }
+
+    // Example numbers start again in each run
+    #[inline(always)]
+    pub fn multiplier_at(&self, example_number: u64) -> f32 {
+        let example_number = self.examples_done + example_number;
+        self.phases
+            .iter()
+            .map(|phase| phase.multiplier_at(example_number))
+            .product()
+    }
+
+    // Rates drop by factor after every examples_per_pass examples of this run, example numbers start at 1
+    pub fn add_pass_decay(schedule: &mut Option<LRSchedule>, factor: f32, examples_per_pass: u64) {
+        let schedule = schedule.get_or_insert(LRSchedule {
+            phases: Vec::new(),
+            examples_done: 0,
+        });
+        schedule.phases.push(LRSchedulePhase::Step {
+            factor,
+            num_examples: examples_per_pass,
+            first_example: schedule.examples_done + 1,
+        });
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NNConfig {
     pub layers: Vec<HashMap<String, String>>,
@@ -58,10 +466,47 @@ pub struct ModelInstance {
     pub ffm_k: u32,
     #[serde(default = "default_u32_zero")]
     pub ffm_bit_precision: u32,
+    #[serde(default = "default_ffm_layout_feature_major")]
+    pub ffm_layout: FfmLayout,
+    // --fm_k: length of the embeddings of the plain factorization machine, 0 for none
+    #[serde(default = "default_u32_zero")]
+    pub fm_k: u32,
+    // --ffm_field_attention: a learned weight for each pair of fields on the FFM interactions
+    #[serde(default = "default_bool_false")]
+    pub ffm_field_attention: bool,
+    // --ffm_field_pooling: how the features of each FFM field are combined, empty when all are summed
+    #[serde(default = "default_ffm_field_pooling_empty")]
+    pub ffm_field_pooling: Vec<EmbeddingPooling>,
+    // --cross_layers: number of DCN cross layers on top of the LR and FFM outputs
+    #[serde(default = "default_u32_zero")]
+    pub cross_layers: u32,
     #[serde(default = "default_bool_false")]
     pub fastmath: bool,
+    #[serde(default = "default_bool_false")]
+    pub accurate_accumulation: bool,
+    // --oaa: number of classes of the softmax head, 0 for the binary logloss
+    #[serde(default = "default_u32_zero")]
+    pub oaa: u32,
+    // --heads: number of logloss heads on the shared model, 0 for a single one
+    #[serde(default = "default_u32_zero")]
+    pub heads: u32,
+    // --invariant: importance weight aware updates of the LR and FFM blocks
+    #[serde(default = "default_bool_false")]
+    pub invariant: bool,
+    // --focal_gamma: focusing parameter of the focal loss, 0 for the logloss
+    #[serde(default = "default_f32_zero")]
+    pub focal_gamma: f32,
+    // --label_smoothing: how much the labels of the logloss are mixed toward 0.5
+    #[serde(default = "default_f32_zero")]
+    pub label_smoothing: f32,
+    // --bpr: pairwise ranking loss instead of the logloss
+    #[serde(default = "default_bool_false")]
+    pub bpr: bool,
 
     pub ffm_initialization_type: String,
+    // Seed of the random number streams, see rng.rs
+    #[serde(default = "default_seed")]
+    pub seed: u64,
     #[serde(default = "default_f32_zero")]
     pub ffm_k_threshold: f32,
     #[serde(default = "default_f32_zero")]
@@ -78,6 +523,23 @@ pub struct ModelInstance {
     pub ffm_learning_rate: f32,
     #[serde(default = "default_f32_zero")]
     pub ffm_power_t: f32,
+    // L2 regularization of the touched FFM embeddings, added to their gradients
+    #[serde(default = "default_f32_zero")]
+    pub ffm_l2: f32,
+    // Maximum L2 norm of the per example gradient of each block, 0.0 for no clipping
+    #[serde(default = "default_f32_zero")]
+    pub grad_clip: f32,
+    // Examples whose FFM and nn layer gradients are summed before they are applied, 1 for none
+    #[serde(default = "default_minibatch")]
+    pub minibatch: u32,
+    // Decoupled weight decay (as in AdamW): fraction of a weight taken away on each of its updates,
+    // apart from the optimizer step, so it doesn't end up in Adagrad or Adam accumulators
+    #[serde(default = "default_f32_zero")]
+    pub weight_decay: f32,
+    #[serde(default = "default_f32_zero")]
+    pub ffm_weight_decay: f32,
+    #[serde(default = "default_f32_zero")]
+    pub nn_weight_decay: f32,
 
     #[serde(default = "default_f32_zero")]
     pub nn_init_acc_gradient: f32,
@@ -87,13 +549,96 @@ pub struct ModelInstance {
     pub nn_power_t: f32,
 
     pub nn_config: NNConfig,
+    #[serde(default = "default_dropout_schedule_none")]
+    pub nn_dropout_schedule: Option<DropoutSchedule>,
+
+    #[serde(default = "default_lr_schedule_none")]
+    pub lr_schedule: Option<LRSchedule>,
 
     #[serde(default = "default_optimizer_adagrad")]
     pub optimizer: Optimizer,
 
+    // Optimizers of the LR, FFM and neural blocks, when different from mi.optimizer
+    #[serde(default = "default_optimizer_none")]
+    pub lr_optimizer: Option<Optimizer>,
+    #[serde(default = "default_optimizer_none")]
+    pub ffm_optimizer: Option<Optimizer>,
+    #[serde(default = "default_optimizer_none")]
+    pub nn_optimizer: Option<Optimizer>, // also of embedding lookups
+
+    // Regularization of optimizers that do it on their own (FTRL)
+    #[serde(default = "default_f32_zero")]
+    pub l1: f32,
+    #[serde(default = "default_f32_zero")]
+    pub l2: f32,
+
+    // Of SGD with momentum
+    #[serde(default = "default_momentum")]
+    pub momentum: f32,
+
     pub transform_namespaces: feature_transform_parser::NamespaceTransforms,
 
     pub dequantize_weights: Option<bool>,
+
+    // Of FFM and FM weights, when they are quantized
+    #[serde(default)]
+    pub quantization: WeightQuantization,
+
+    // zstd level of weights in saved regressors, files record themselves whether they are compressed
+    #[serde(default = "default_compress_model_none")]
+    pub compress_model: Option<i32>,
+
+    #[serde(default = "default_score_map_none")]
+    pub score_map: Option<ScoreMap>,
+
+    #[serde(default = "default_namespace_ttls_empty")]
+    pub namespace_ttls: Vec<NamespaceTTL>,
+
+    #[serde(default = "default_namespace_topks_empty")]
+    pub namespace_topks: Vec<NamespaceTopK>,
+
+    #[serde(default = "default_dup_policy_none")]
+    pub dup_policy: Option<DupPolicy>,
+
+    #[serde(default = "default_blend_none")]
+    pub blend: Option<BlendConfig>,
+
+    #[serde(default = "default_merge_none")]
+    pub merge: Option<MergeConfig>,
+
+    #[serde(default = "default_dp_none")]
+    pub dp: Option<DPConfig>,
+
+    #[serde(default = "default_dense_inputs_empty")]
+    pub dense_inputs: Vec<DenseInputDesc>,
+
+    #[serde(default = "default_embedding_lookups_empty")]
+    pub embedding_lookups: Vec<EmbeddingLookupDesc>,
+
+    // Readable VW model that LR weights start from, see vw_import.rs
+    #[serde(default = "default_vw_initial_regressor_none")]
+    pub vw_initial_regressor: Option<String>,
+
+    #[serde(default = "default_tenant_namespace_none")]
+    pub tenant_namespace: Option<NamespaceDescriptor>,
+
+    #[serde(default = "default_value_ranges_empty")]
+    pub value_ranges: Vec<ValueRange>,
+
+    // Only set in snapshots, see --resume
+    #[serde(default = "default_resume_point_none")]
+    pub resume_point: Option<ResumePoint>,
+
+    // Options from the --config file the model was trained with, command line overrides are not
+    // included, they show in the model fields themselves
+    #[serde(default = "default_config_options_none")]
+    pub config_options: Option<BTreeMap<String, Vec<String>>>,
+
+    #[serde(skip)]
+    pub graph_paranoia: bool, // debugging switch, not a property of the model
+
+    #[serde(skip)]
+    pub kernels: KernelChoice, // chosen by --autotune and --avx512 for this machine
 }
 
 fn default_u32_zero() -> u32 {
@@ -105,6 +650,75 @@ fn default_f32_zero() -> f32 {
 fn default_bool_false() -> bool {
     false
 }
+fn default_dropout_schedule_none() -> Option<DropoutSchedule> {
+    None
+}
+fn default_lr_schedule_none() -> Option<LRSchedule> {
+    None
+}
+fn default_score_map_none() -> Option<ScoreMap> {
+    None
+}
+fn default_namespace_ttls_empty() -> Vec<NamespaceTTL> {
+    Vec::new()
+}
+fn default_namespace_topks_empty() -> Vec<NamespaceTopK> {
+    Vec::new()
+}
+fn default_dup_policy_none() -> Option<DupPolicy> {
+    None
+}
+fn default_ffm_layout_feature_major() -> FfmLayout {
+    FfmLayout::FeatureMajor
+}
+fn default_blend_none() -> Option<BlendConfig> {
+    None
+}
+fn default_merge_none() -> Option<MergeConfig> {
+    None
+}
+fn default_dp_none() -> Option<DPConfig> {
+    None
+}
+fn default_dense_inputs_empty() -> Vec<DenseInputDesc> {
+    Vec::new()
+}
+fn default_embedding_lookups_empty() -> Vec<EmbeddingLookupDesc> {
+    Vec::new()
+}
+fn default_ffm_field_pooling_empty() -> Vec<EmbeddingPooling> {
+    Vec::new()
+}
+fn default_vw_initial_regressor_none() -> Option<String> {
+    None
+}
+fn default_tenant_namespace_none() -> Option<NamespaceDescriptor> {
+    None
+}
+fn default_value_ranges_empty() -> Vec<ValueRange> {
+    Vec::new()
+}
+fn default_resume_point_none() -> Option<ResumePoint> {
+    None
+}
+fn default_compress_model_none() -> Option<i32> {
+    None
+}
+fn default_config_options_none() -> Option<BTreeMap<String, Vec<String>>> {
+    None
+}
+fn default_optimizer_none() -> Option<Optimizer> {
+    None
+}
+fn default_seed() -> u64 {
+    rng::DEFAULT_SEED
+}
+fn default_minibatch() -> u32 {
+    1
+}
+fn default_momentum() -> f32 {
+    optimizer::DEFAULT_MOMENTUM
+}
 fn default_optimizer_adagrad() -> Optimizer {
     Optimizer::AdagradFlex
 }
@@ -125,13 +739,32 @@ impl ModelInstance {
             bit_precision: 18, // vw default
             power_t: 0.5,
             ffm_power_t: 0.5,
+            ffm_l2: 0.0,
+            grad_clip: 0.0,
+            minibatch: 1,
+            weight_decay: 0.0,
+            ffm_weight_decay: 0.0,
+            nn_weight_decay: 0.0,
             add_constant_feature: true,
             feature_combo_descs: Vec::new(),
             ffm_fields: Vec::new(),
             ffm_k: 0,
+            fm_k: 0,
+            cross_layers: 0,
+            ffm_field_attention: false,
+            ffm_field_pooling: Vec::new(),
             ffm_bit_precision: 18,
+            ffm_layout: FfmLayout::FeatureMajor,
             fastmath: true,
+            accurate_accumulation: false,
+            oaa: 0,
+            bpr: false,
+            focal_gamma: 0.0,
+            invariant: false,
+            heads: 0,
+            label_smoothing: 0.0,
             ffm_initialization_type: String::from("default"),
+            seed: rng::DEFAULT_SEED,
             ffm_k_threshold: 0.0,
             ffm_init_center: 0.0,
             ffm_init_width: 0.0,
@@ -142,9 +775,35 @@ impl ModelInstance {
             nn_power_t: 0.45,
             init_acc_gradient: 1.0,
             optimizer: Optimizer::SGD,
+            lr_optimizer: None,
+            ffm_optimizer: None,
+            nn_optimizer: None,
+            l1: 0.0,
+            l2: 0.0,
+            momentum: optimizer::DEFAULT_MOMENTUM,
             transform_namespaces: feature_transform_parser::NamespaceTransforms::new(),
             nn_config: NNConfig::new(),
+            nn_dropout_schedule: None,
+            lr_schedule: None,
             dequantize_weights: Some(false),
+            quantization: WeightQuantization::Buckets16,
+            compress_model: None,
+            score_map: None,
+            namespace_ttls: Vec::new(),
+            namespace_topks: Vec::new(),
+            dup_policy: None,
+            blend: None,
+            merge: None,
+            dp: None,
+            dense_inputs: Vec::new(),
+            embedding_lookups: Vec::new(),
+            vw_initial_regressor: None,
+            tenant_namespace: None,
+            value_ranges: Vec::new(),
+            resume_point: None,
+            config_options: None,
+            graph_paranoia: false,
+            kernels: KernelChoice::default(),
         };
         Ok(mi)
     }
@@ -186,6 +845,139 @@ impl ModelInstance {
         })
     }
 
+    fn create_namespace_ttl(
+        &self,
+        vw: &VwNamespaceMap,
+        s: &str,
+    ) -> Result<NamespaceTTL, Box<dyn Error>> {
+        let vsplit: Vec<&str> = s.split(WEIGHT_DELIM).collect();
+        if vsplit.len() != 2 || vsplit[0].chars().count() != 1 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--namespace_ttl has to be of the form namespace:examples, got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        let namespace_descriptor = feature_transform_parser::get_namespace_descriptor(
+            &self.transform_namespaces,
+            vw,
+            vsplit[0].chars().next().unwrap(),
+        )?;
+        let ttl_examples = parse_example_count(vsplit[1])?;
+        if ttl_examples == 0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--namespace_ttl has to be a positive number of examples, got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        Ok(NamespaceTTL {
+            namespace_descriptor,
+            ttl_examples,
+        })
+    }
+
+    fn create_tenant_namespace(
+        &self,
+        vw: &VwNamespaceMap,
+        s: &str,
+    ) -> Result<NamespaceDescriptor, Box<dyn Error>> {
+        if s.chars().count() != 1 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--tenant_namespace has to be a namespace char, got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        let namespace_descriptor = feature_transform_parser::get_namespace_descriptor(
+            &self.transform_namespaces,
+            vw,
+            s.chars().next().unwrap(),
+        )?;
+        // The tenant is taken from the parsed record as a hash, f32 namespaces only carry values
+        if namespace_descriptor.namespace_type != NamespaceType::Primitive
+            || namespace_descriptor.namespace_format == NamespaceFormat::F32
+            || namespace_descriptor.namespace_format == NamespaceFormat::F32Vec
+        {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--tenant_namespace has to be a primitive categorical namespace, got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        Ok(namespace_descriptor)
+    }
+
+    fn create_namespace_topk(
+        &self,
+        vw: &VwNamespaceMap,
+        s: &str,
+    ) -> Result<NamespaceTopK, Box<dyn Error>> {
+        let vsplit: Vec<&str> = s.split(WEIGHT_DELIM).collect();
+        if vsplit.len() != 2 || vsplit[0].chars().count() != 1 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--namespace_topk has to be of the form namespace:k, got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        let namespace_descriptor = feature_transform_parser::get_namespace_descriptor(
+            &self.transform_namespaces,
+            vw,
+            vsplit[0].chars().next().unwrap(),
+        )?;
+        // Truncation works on the parsed record, and f32 namespaces carry values instead of weights
+        if namespace_descriptor.namespace_type != NamespaceType::Primitive
+            || namespace_descriptor.namespace_format == NamespaceFormat::F32
+            || namespace_descriptor.namespace_format == NamespaceFormat::F32Vec
+        {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--namespace_topk is only supported on primitive non-f32 namespaces, got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        let k: u32 = vsplit[1].parse()?;
+        if k == 0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--namespace_topk has to keep at least one feature, got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        Ok(NamespaceTopK {
+            namespace_descriptor,
+            k,
+        })
+    }
+
+    // Shortest ttl among the namespaces of the feature combo, None if they do not decay
+    pub fn get_feature_combo_ttl(&self, combo: &FeatureComboDesc) -> Option<u64> {
+        self.namespace_ttls
+            .iter()
+            .filter(|t| {
+                combo
+                    .namespace_descriptors
+                    .contains(&t.namespace_descriptor)
+            })
+            .map(|t| t.ttl_examples)
+            .min()
+    }
+
     fn create_feature_combo_desc_from_verbose(
         &self,
         vw: &VwNamespaceMap,
@@ -244,22 +1036,187 @@ impl ModelInstance {
             return Err(Box::new(IOError::new(
                 ErrorKind::Other,
                 format!(
-                    "Fields currently do not support passing a value via : {:?}",
+                    "Fields currently do not support passing a value via : {:?}",
+                    s
+                ),
+            )));
+        }
+        let namespaces_verbose: Vec<&str> = s.split(VERBOSE_FIELD_DELIM).collect(); // verbose names are separated by comma
+        let mut field: FieldDesc = Vec::new();
+        for namespace_verbose in namespaces_verbose {
+            let namespace_descriptor = feature_transform_parser::get_namespace_descriptor_verbose(
+                &self.transform_namespaces,
+                vw,
+                namespace_verbose,
+            )?;
+            field.push(namespace_descriptor);
+        }
+        Ok(field)
+    }
+
+    fn create_dense_input_desc(
+        &self,
+        vw: &VwNamespaceMap,
+        s: &str,
+    ) -> Result<DenseInputDesc, Box<dyn Error>> {
+        let namespace_descriptor = feature_transform_parser::get_namespace_descriptor_verbose(
+            &self.transform_namespaces,
+            vw,
+            s,
+        )?;
+        if namespace_descriptor.namespace_format != NamespaceFormat::F32Vec {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--dense_input has to be an f32vec namespace, got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        Ok(DenseInputDesc {
+            namespace_descriptor,
+            len: vw.get_namespace_vector_len(&namespace_descriptor),
+        })
+    }
+
+    // Parses "verbose_namespace:filename[:sum|mean|max[:max_len]][:finetune]", sum pooling is the default
+    fn create_embedding_lookup_desc(
+        &self,
+        vw: &VwNamespaceMap,
+        s: &str,
+    ) -> Result<EmbeddingLookupDesc, Box<dyn Error>> {
+        let mut vsplit: Vec<&str> = s.split(':').collect();
+        let finetune = vsplit.len() > 2 && vsplit.last() == Some(&"finetune");
+        if finetune {
+            vsplit.pop();
+        }
+        let mut max_len = DEFAULT_EMBEDDING_MAX_LEN;
+        if vsplit.len() > 3 && EmbeddingPooling::parse(vsplit[vsplit.len() - 2]).is_some() {
+            max_len = vsplit[vsplit.len() - 1].parse()?;
+            if max_len == 0 {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "--embedding_lookup max_len has to be positive, got: \"{}\"",
+                        s
+                    ),
+                )));
+            }
+            vsplit.pop();
+        }
+        let mut pooling = EmbeddingPooling::Sum;
+        if vsplit.len() > 2 {
+            if let Some(p) = EmbeddingPooling::parse(vsplit[vsplit.len() - 1]) {
+                pooling = p;
+                vsplit.pop();
+            }
+        }
+        if vsplit.len() < 2 || vsplit[0].is_empty() || vsplit[1].is_empty() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--embedding_lookup expects verbose_namespace:filename[:sum|mean|max[:max_len]][:finetune], got: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        let namespace_verbose = vsplit[0];
+        let filename = vsplit[1..].join(":");
+
+        let namespace_descriptor = feature_transform_parser::get_namespace_descriptor_verbose(
+            &self.transform_namespaces,
+            vw,
+            namespace_verbose,
+        )?;
+        // Keys of the table are hashes of the feature names, as the parser computes them
+        if namespace_descriptor.namespace_type != NamespaceType::Primitive
+            || namespace_descriptor.namespace_format == NamespaceFormat::Passthrough
+            || namespace_descriptor.namespace_format == NamespaceFormat::F32Vec
+        {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--embedding_lookup needs a hashed, not transformed namespace, got: \"{}\"",
+                    namespace_verbose
+                ),
+            )));
+        }
+        let namespace_vwname = vw
+            .map_vwname_to_namespace_descriptor
+            .iter()
+            .find(|(_, descriptor)| **descriptor == namespace_descriptor)
+            .map(|(vwname, _)| String::from_utf8_lossy(vwname).to_string())
+            .unwrap();
+
+        let table = block_embedding_lookup::read_embedding_file(&filename, &namespace_vwname)?;
+        Ok(EmbeddingLookupDesc {
+            namespace_descriptor,
+            namespace_vwname,
+            filename,
+            pooling,
+            finetune,
+            rows: table.keys.len() as u32,
+            dim: table.dim as u32,
+            max_len,
+        })
+    }
+
+    // Total number of values the embedding lookups add to the input of the nn layers
+    // Parses "field_index:sum|mean", fields are numbered in the order of --ffm_field and then --ffm_field_verbose
+    fn set_ffm_field_pooling(&mut self, s: &str) -> Result<(), Box<dyn Error>> {
+        let vsplit: Vec<&str> = s.split(':').collect();
+        let (field_index, pooling) = match vsplit[..] {
+            [field_index, pooling] => (
+                field_index.parse::<usize>().ok(),
+                EmbeddingPooling::parse(pooling),
+            ),
+            _ => (None, None),
+        };
+        let (field_index, pooling) = match (field_index, pooling) {
+            (Some(field_index), Some(pooling)) => (field_index, pooling),
+            _ => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "--ffm_field_pooling expects field_index:sum|mean, got: \"{}\"",
+                        s
+                    ),
+                )))
+            }
+        };
+        if field_index >= self.ffm_fields.len() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--ffm_field_pooling: there are only {} FFM fields, got: \"{}\"",
+                    self.ffm_fields.len(),
                     s
                 ),
             )));
         }
-        let namespaces_verbose: Vec<&str> = s.split(VERBOSE_FIELD_DELIM).collect(); // verbose names are separated by comma
-        let mut field: FieldDesc = Vec::new();
-        for namespace_verbose in namespaces_verbose {
-            let namespace_descriptor = feature_transform_parser::get_namespace_descriptor_verbose(
-                &self.transform_namespaces,
-                vw,
-                namespace_verbose,
-            )?;
-            field.push(namespace_descriptor);
+        // The interactions of a field sum over its features, their max can't be split that way
+        if pooling == EmbeddingPooling::Max {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--ffm_field_pooling supports sum and mean, FFM interactions can't be max pooled, got: \"{}\"",
+                    s
+                ),
+            )));
         }
-        Ok(field)
+        self.ffm_field_pooling
+            .resize(self.ffm_fields.len(), EmbeddingPooling::Sum);
+        self.ffm_field_pooling[field_index] = pooling;
+        Ok(())
+    }
+
+    pub fn get_embedding_lookups_len(&self) -> usize {
+        self.embedding_lookups.iter().map(|d| d.dim as usize).sum()
+    }
+
+    // Total number of dense input values
+    pub fn get_dense_inputs_len(&self) -> usize {
+        self.dense_inputs.iter().map(|d| d.len as usize).sum()
     }
 
     fn parse_nn(&mut self, s: &str) -> Result<(), Box<dyn Error>> {
@@ -377,10 +1334,28 @@ impl ModelInstance {
             }
         }
 
+        if cl.is_present("ffm_field_attention") {
+            mi.ffm_field_attention = true;
+        }
+
+        if let Some(val) = cl.value_of("fm_k") {
+            mi.fm_k = val.parse()?;
+            if mi.fm_k > FFM_MAX_K as u32 {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("Maximum fm_k is: {}, passed: {}", FFM_MAX_K, mi.fm_k),
+                )));
+            }
+        }
+
         if let Some(val) = cl.value_of("ffm_initialization_type") {
             mi.ffm_initialization_type = val.parse()?;
         }
 
+        if let Some(val) = cl.value_of("seed") {
+            mi.seed = val.parse()?;
+        }
+
         mi.ffm_init_center = parse_float("ffm_init_center", mi.ffm_init_center, cl);
         mi.ffm_init_width = parse_float("ffm_init_width", mi.ffm_init_width, cl);
         mi.ffm_init_zero_band = parse_float("ffm_init_zero_band", mi.ffm_init_zero_band, cl);
@@ -407,10 +1382,20 @@ impl ModelInstance {
             }
         }
 
+        if let Some(in_v) = cl.values_of("ffm_field_pooling") {
+            for value_str in in_v {
+                mi.set_ffm_field_pooling(value_str)?;
+            }
+        }
+
         if let Some(val) = cl.value_of("ffm_bit_precision") {
             mi.ffm_bit_precision = val.parse()?;
         }
 
+        if let Some(val) = cl.value_of("ffm_layout") {
+            mi.ffm_layout = FfmLayout::parse(val)?;
+        }
+
         if let Some(val) = cl.value_of("bit_precision") {
             mi.bit_precision = val.parse()?;
         }
@@ -422,6 +1407,31 @@ impl ModelInstance {
         mi.ffm_learning_rate = parse_float("ffm_learning_rate", mi.learning_rate, cl);
         mi.ffm_init_acc_gradient = parse_float("ffm_init_acc_gradient", mi.init_acc_gradient, cl);
         mi.ffm_power_t = parse_float("ffm_power_t", mi.power_t, cl);
+        mi.ffm_l2 = parse_float("ffm_l2", mi.ffm_l2, cl);
+        if mi.ffm_l2 < 0.0 {
+            return Err("--ffm_l2 can't be negative")?;
+        }
+        mi.grad_clip = parse_float("grad_clip", mi.grad_clip, cl);
+        if mi.grad_clip < 0.0 {
+            return Err("--grad_clip can't be negative")?;
+        }
+        if let Some(val) = cl.value_of("minibatch") {
+            mi.minibatch = val.parse()?;
+            if mi.minibatch == 0 {
+                return Err("--minibatch has to be at least 1")?;
+            }
+        }
+        mi.weight_decay = parse_float("weight_decay", mi.weight_decay, cl);
+        mi.ffm_weight_decay = parse_float("ffm_weight_decay", mi.weight_decay, cl);
+        mi.nn_weight_decay = parse_float("nn_weight_decay", mi.ffm_weight_decay, cl);
+        for weight_decay in [mi.weight_decay, mi.ffm_weight_decay, mi.nn_weight_decay].iter() {
+            if !(0.0..1.0).contains(weight_decay) {
+                return Err(format!(
+                    "Weight decay has to be in range [0.0, 1.0), got: {}",
+                    weight_decay
+                ))?;
+            }
+        }
 
         mi.nn_learning_rate = parse_float("nn_learning_rate", mi.ffm_learning_rate, cl);
         mi.nn_init_acc_gradient = parse_float("nn_init_acc_gradient", mi.ffm_init_acc_gradient, cl);
@@ -438,12 +1448,121 @@ impl ModelInstance {
             mi.nn_config.topology = val.to_string();
         }
 
+        if let Some(val) = cl.value_of("cross_layers") {
+            mi.cross_layers = val.parse()?;
+        }
+
         if let Some(in_v) = cl.values_of("nn") {
             for value_str in in_v {
                 mi.parse_nn(value_str)?;
             }
         }
 
+        if let Some(val) = cl.value_of("nn_dropout_schedule") {
+            mi.nn_dropout_schedule = Some(DropoutSchedule::parse(val)?);
+        }
+        if let Some(val) = cl.value_of("lr_schedule") {
+            mi.lr_schedule = Some(LRSchedule::parse(val)?);
+        }
+        if let Some(val) = cl.value_of("compress_model") {
+            mi.compress_model = Some(parse_compress_model(val)?);
+        }
+        if let Some(val) = cl.value_of("quantize_model") {
+            mi.quantization = WeightQuantization::parse(val)?;
+        }
+
+        if let Some(in_v) = cl.values_of("dense_input") {
+            for value_str in in_v {
+                let dense_input = mi.create_dense_input_desc(vw, value_str)?;
+                mi.dense_inputs.push(dense_input);
+            }
+            if mi.nn_config.layers.is_empty() {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    "--dense_input requires --nn_layers",
+                )));
+            }
+        }
+
+        if let Some(in_v) = cl.values_of("embedding_lookup") {
+            for value_str in in_v {
+                let embedding_lookup = mi.create_embedding_lookup_desc(vw, value_str)?;
+                mi.embedding_lookups.push(embedding_lookup);
+            }
+            if mi.nn_config.layers.is_empty() {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    "--embedding_lookup requires --nn_layers",
+                )));
+            }
+        }
+
+        // Vectors are dense inputs, they have no hashes that LR or FFM could use
+        let used_namespace_descriptors = mi
+            .feature_combo_descs
+            .iter()
+            .flat_map(|combo| combo.namespace_descriptors.iter())
+            .chain(mi.ffm_fields.iter().flatten());
+        for namespace_descriptor in used_namespace_descriptors {
+            if namespace_descriptor.namespace_format == NamespaceFormat::F32Vec {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    "f32vec namespaces can only be used with --dense_input",
+                )));
+            }
+        }
+
+        if let Some(in_v) = cl.values_of("namespace_ttl") {
+            for value_str in in_v {
+                let namespace_ttl = mi.create_namespace_ttl(vw, value_str)?;
+                mi.namespace_ttls.push(namespace_ttl);
+            }
+        }
+
+        if let Some(in_v) = cl.values_of("namespace_topk") {
+            for value_str in in_v {
+                let namespace_topk = mi.create_namespace_topk(vw, value_str)?;
+                mi.namespace_topks.push(namespace_topk);
+            }
+        }
+
+        if let Some(val) = cl.value_of("score_map") {
+            mi.score_map = Some(ScoreMap::new_from_filename(val)?);
+        }
+
+        if let Some(val) = cl.value_of("dup_policy") {
+            mi.dup_policy = Some(DupPolicy::parse(val)?);
+        }
+
+        if let Some(val) = cl.value_of("vw_initial_regressor") {
+            mi.vw_initial_regressor = Some(val.to_string());
+        }
+
+        if let Some(val) = cl.value_of("tenant_namespace") {
+            mi.tenant_namespace = Some(mi.create_tenant_namespace(vw, val)?);
+        }
+
+        mi.dp = DPConfig::new_from_cmdline(cl)?;
+        if mi.dp.is_some() && !mi.nn_config.layers.is_empty() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                "--dp_clip is only supported for LR and FFM, not for --nn layers",
+            )));
+        }
+        if mi.fm_k > 0 && (mi.dp.is_some() || mi.minibatch > 1) {
+            return Err("--fm_k can't be combined with --dp_clip or --minibatch")?;
+        }
+        if mi.ffm_field_attention && (mi.dp.is_some() || mi.minibatch > 1) {
+            return Err("--ffm_field_attention can't be combined with --dp_clip or --minibatch")?;
+        }
+        if mi.cross_layers > 0 && (mi.dp.is_some() || mi.minibatch > 1) {
+            return Err("--cross_layers can't be combined with --dp_clip or --minibatch")?;
+        }
+
+        if let Some(val) = cl.value_of("config") {
+            mi.config_options = Some(ConfigFile::new_from_filename(val)?.options);
+        }
+
         if let Some(val) = cl.value_of("minimum_learning_rate") {
             mi.minimum_learning_rate = val.parse()?;
         }
@@ -464,20 +1583,71 @@ impl ModelInstance {
                 )));
             }
         }
-        if let Some(val) = cl.value_of("l2") {
-            let v2: f32 = val.parse()?;
-            if v2.abs() > 0.00000001 {
-                return Err(Box::new(IOError::new(
-                    ErrorKind::Other,
-                    "--l2 can only be 0.0".to_string(),
-                )));
-            }
-        }
 
         if cl.is_present("noconstant") {
             mi.add_constant_feature = false;
         }
 
+        if cl.is_present("graph_paranoia") {
+            mi.graph_paranoia = true;
+        }
+
+        if cl.is_present("accurate_accumulation") {
+            mi.accurate_accumulation = true;
+        }
+
+        if let Some(val) = cl.value_of("oaa") {
+            mi.oaa = val.parse()?;
+            // Class numbers are stored in the label of the record, where NO_LABEL is taken
+            if mi.oaa < 2 || mi.oaa >= parser::NO_LABEL {
+                return Err(format!(
+                    "--oaa takes from 2 to {} classes",
+                    parser::NO_LABEL - 1
+                ))?;
+            }
+        }
+
+        if cl.is_present("bpr") {
+            mi.bpr = true;
+        }
+
+        if let Some(val) = cl.value_of("heads") {
+            mi.heads = val.parse()?;
+            if mi.heads < 2 {
+                return Err("--heads takes at least 2 heads")?;
+            }
+        }
+
+        mi.focal_gamma = parse_float("focal_gamma", mi.focal_gamma, cl);
+        if mi.focal_gamma < 0.0 {
+            return Err("--focal_gamma can't be negative")?;
+        }
+
+        mi.label_smoothing = parse_float("label_smoothing", mi.label_smoothing, cl);
+        if !(0.0..1.0).contains(&mi.label_smoothing) {
+            return Err("--label_smoothing has to be in [0, 1)")?;
+        }
+
+        if cl.is_present("invariant") {
+            // The closed form step is for the logloss of a single logit, and how far the logit moves
+            // per unit of update. That is exact for the LR weights and, with the other weights of a
+            // pair held, for the bilinear FFM ones. Each example updates them on its own
+            if !mi.nn_config.layers.is_empty() || mi.minibatch > 1 {
+                return Err("--invariant can't be combined with --nn or --minibatch")?;
+            }
+            if mi.oaa > 0 || mi.bpr || mi.heads > 0 || !mi.embedding_lookups.is_empty() {
+                return Err(
+                    "--invariant can't be combined with --oaa, --bpr, --heads or --embedding_lookup",
+                )?;
+            }
+            if mi.focal_gamma != 0.0 || mi.label_smoothing != 0.0 {
+                return Err("--invariant updates are for the plain logloss, they can't be combined with --focal_gamma or --label_smoothing")?;
+            }
+            mi.invariant = true;
+        }
+
+        mi.kernels = autotune::kernels_from_cmdline(cl, &mi)?;
+
         // We currently only support SGD + adaptive, which means both options have to be specified
         if cl.is_present("sgd") {
             mi.optimizer = Optimizer::SGD;
@@ -487,13 +1657,99 @@ impl ModelInstance {
             mi.optimizer = Optimizer::AdagradFlex;
         }
 
+        if cl.is_present("adam") {
+            mi.optimizer = Optimizer::Adam;
+        }
+
+        if let Some(val) = cl.value_of("momentum") {
+            mi.momentum = val.parse()?;
+            if !(0.0..1.0).contains(&mi.momentum) {
+                return Err(format!(
+                    "--momentum has to be in range [0.0, 1.0), got: {}",
+                    mi.momentum
+                ))?;
+            }
+            mi.optimizer = if cl.is_present("nesterov") {
+                Optimizer::SGDNesterov
+            } else {
+                Optimizer::SGDMomentum
+            };
+        }
+
         if mi.optimizer == Optimizer::AdagradFlex && mi.fastmath {
             mi.optimizer = Optimizer::AdagradLUT;
         }
 
+        if let Some(val) = cl.value_of("lr_optimizer") {
+            mi.lr_optimizer = Some(Optimizer::parse(val, mi.fastmath)?);
+        }
+        if let Some(val) = cl.value_of("ffm_optimizer") {
+            mi.ffm_optimizer = Some(Optimizer::parse(val, mi.fastmath)?);
+        }
+        if let Some(val) = cl.value_of("nn_optimizer") {
+            mi.nn_optimizer = Some(Optimizer::parse(val, mi.fastmath)?);
+        }
+
+        if cl.is_present("ftrl") {
+            mi.lr_optimizer = Some(Optimizer::FTRL);
+        }
+        mi.l1 = parse_float("l1", mi.l1, cl);
+        mi.l2 = parse_float("l2", mi.l2, cl);
+        mi.check_lr_regularization()?;
+        mi.check_ffm_layout()?;
+        // These keep weights in their own per weight store
+        if mi.weight_decay > 0.0
+            && matches!(
+                mi.lr_optimizer.unwrap_or(mi.optimizer),
+                Optimizer::COCOB | Optimizer::FTRL
+            )
+        {
+            return Err("--weight_decay of the LR block can't be used with cocob or ftrl, set --ffm_weight_decay and --nn_weight_decay instead")?;
+        }
+
         Ok(mi)
     }
 
+    // --l1 and --l2 are only implemented by FTRL, other optimizers would silently ignore them
+    fn check_lr_regularization(&self) -> Result<(), Box<dyn Error>> {
+        if self.l1 < 0.0 || self.l2 < 0.0 {
+            return Err("--l1 and --l2 can't be negative")?;
+        }
+        if (self.l1 > 0.0 || self.l2.abs() > 0.00000001)
+            && self.lr_optimizer != Some(Optimizer::FTRL)
+        {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                "--l1 and --l2 can only be used with --ftrl, for FFM use --ffm_l2".to_string(),
+            )));
+        }
+        Ok(())
+    }
+
+    // Each field gets 1 << (ffm_bit_precision - bits to count the fields) weights of a field-major
+    // table, at least one embedding has to fit
+    fn check_ffm_layout(&self) -> Result<(), Box<dyn Error>> {
+        if self.ffm_layout == FfmLayout::FeatureMajor || self.ffm_k == 0 {
+            return Ok(());
+        }
+        let field_bits = (self.ffm_fields.len() as u32)
+            .next_power_of_two()
+            .trailing_zeros();
+        let dimension_bits = self
+            .ffm_k
+            .max(self.fm_k)
+            .next_power_of_two()
+            .trailing_zeros();
+        if self.ffm_bit_precision < field_bits + dimension_bits {
+            return Err(format!(
+                "--ffm_layout field_major needs --ffm_bit_precision of at least {} for {} fields",
+                field_bits + dimension_bits,
+                self.ffm_fields.len()
+            ))?;
+        }
+        Ok(())
+    }
+
     pub fn update_hyperparameters_from_cmd(
         cmd_arguments: &clap::ArgMatches<'_>,
         mi: &mut ModelInstance,
@@ -538,6 +1794,116 @@ impl ModelInstance {
             }
         }
 
+        if let Some(val) = cmd_arguments.value_of("grad_clip") {
+            let hvalue = val.parse::<f32>()?;
+            mi.grad_clip = hvalue;
+            replacement_hyperparam_ids.push(("grad_clip".to_string(), hvalue.to_string()));
+        }
+
+        if let Some(val) = cmd_arguments.value_of("minibatch") {
+            let hvalue = val.parse::<u32>()?;
+            if hvalue == 0 {
+                return Err("--minibatch has to be at least 1")?;
+            }
+            mi.minibatch = hvalue;
+            replacement_hyperparam_ids.push(("minibatch".to_string(), hvalue.to_string()));
+        }
+
+        for hyperparam_id in ["weight_decay", "ffm_weight_decay", "nn_weight_decay"].iter() {
+            if let Some(val) = cmd_arguments.value_of(hyperparam_id) {
+                let hvalue = val.parse::<f32>()?;
+                match *hyperparam_id {
+                    "weight_decay" => mi.weight_decay = hvalue,
+                    "ffm_weight_decay" => mi.ffm_weight_decay = hvalue,
+                    _ => mi.nn_weight_decay = hvalue,
+                }
+                replacement_hyperparam_ids.push((hyperparam_id.to_string(), hvalue.to_string()));
+            }
+        }
+
+        if let Some(val) = cmd_arguments.value_of("ffm_l2") {
+            let hvalue = val.parse::<f32>()?;
+            if hvalue < 0.0 {
+                return Err("--ffm_l2 can't be negative")?;
+            }
+            mi.ffm_l2 = hvalue;
+            replacement_hyperparam_ids.push(("ffm_l2".to_string(), hvalue.to_string()));
+        }
+
+        for hyperparam_id in ["l1", "l2"] {
+            if let Some(val) = cmd_arguments.value_of(hyperparam_id) {
+                let hvalue = val.parse::<f32>()?;
+                match hyperparam_id {
+                    "l1" => mi.l1 = hvalue,
+                    _ => mi.l2 = hvalue,
+                }
+                replacement_hyperparam_ids.push((hyperparam_id.to_string(), hvalue.to_string()));
+            }
+        }
+        mi.check_lr_regularization()?;
+
+        if let Some(val) = cmd_arguments.value_of("nn_dropout_schedule") {
+            mi.nn_dropout_schedule = Some(DropoutSchedule::parse(val)?);
+            replacement_hyperparam_ids.push(("nn_dropout_schedule".to_string(), val.to_string()));
+        }
+
+        // A new schedule starts from its beginning
+        if let Some(val) = cmd_arguments.value_of("lr_schedule") {
+            mi.lr_schedule = Some(LRSchedule::parse(val)?);
+            replacement_hyperparam_ids.push(("lr_schedule".to_string(), val.to_string()));
+        }
+
+        // Applies to regressors saved from now on
+        if let Some(val) = cmd_arguments.value_of("compress_model") {
+            mi.compress_model = Some(parse_compress_model(val)?);
+            replacement_hyperparam_ids.push(("compress_model".to_string(), val.to_string()));
+        }
+
+        // Only when converting, weights of a quantized regressor are read the way they were written
+        if let Some(val) = cmd_arguments.value_of("quantize_model") {
+            if !cmd_arguments.is_present("convert_inference_regressor") {
+                return Err("--quantize_model of a loaded regressor can only be changed with --convert_inference_regressor")?;
+            }
+            mi.quantization = WeightQuantization::parse(val)?;
+            replacement_hyperparam_ids.push(("quantize_model".to_string(), val.to_string()));
+        }
+
+        if let Some(val) = cmd_arguments.value_of("score_map") {
+            mi.score_map = Some(ScoreMap::new_from_filename(val)?);
+            replacement_hyperparam_ids.push(("score_map".to_string(), val.to_string()));
+        }
+
+        if let Some(val) = cmd_arguments.value_of("dup_policy") {
+            mi.dup_policy = Some(DupPolicy::parse(val)?);
+            replacement_hyperparam_ids.push(("dup_policy".to_string(), val.to_string()));
+        }
+
+        // The weights are read the way they were laid out in training
+        if let Some(val) = cmd_arguments.value_of("ffm_layout") {
+            if FfmLayout::parse(val)? != mi.ffm_layout {
+                return Err(format!(
+                    "--ffm_layout of a loaded regressor can't be changed, its FFM weights are laid out {:?}",
+                    mi.ffm_layout
+                ))?;
+            }
+        }
+
+        if let Some(mut dp) = DPConfig::new_from_cmdline(cmd_arguments)? {
+            // Rounds already spent by the model still count
+            if let Some(model_dp) = mi.dp {
+                dp.rounds = model_dp.rounds;
+            }
+            mi.dp = Some(dp);
+            replacement_hyperparam_ids.push(("dp".to_string(), format!("{:?}", dp)));
+        }
+
+        if cmd_arguments.is_present("graph_paranoia") {
+            mi.graph_paranoia = true;
+            replacement_hyperparam_ids.push(("graph_paranoia".to_string(), "true".to_string()));
+        }
+
+        mi.kernels = autotune::kernels_from_cmdline(cmd_arguments, mi)?;
+
         for (hyper_name, hyper_value) in replacement_hyperparam_ids.into_iter() {
             log::warn!(
                 "Warning! Updated hyperparameter {} to value {}",
@@ -672,6 +2038,43 @@ C,featureC
         assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Fields currently do not support passing a value via : \\\"featureA,featureC:3\\\"\" })");
     }
 
+    #[test]
+    fn test_dense_input_parsing() {
+        let vw = VwNamespaceMap::new("A,featureA,f32vec:4\nB,featureB\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        let dense_input = mi.create_dense_input_desc(&vw, "featureA").unwrap();
+        assert_eq!(dense_input.len, 4);
+        assert_eq!(
+            dense_input.namespace_descriptor.namespace_format,
+            NamespaceFormat::F32Vec
+        );
+        mi.dense_inputs = vec![dense_input, dense_input];
+        assert_eq!(mi.get_dense_inputs_len(), 8);
+
+        let result = mi.create_dense_input_desc(&vw, "featureB");
+        assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"--dense_input has to be an f32vec namespace, got: \\\"featureB\\\"\" })");
+    }
+
+    #[test]
+    fn test_namespace_topk_parsing() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB,f32\nC,featureC,f32vec:4\n").unwrap();
+        let mi = ModelInstance::new_empty().unwrap();
+        let namespace_topk = mi.create_namespace_topk(&vw, "A:3").unwrap();
+        assert_eq!(namespace_topk.k, 3);
+        assert!(mi.create_namespace_topk(&vw, "A:0").is_err());
+        assert!(mi.create_namespace_topk(&vw, "A").is_err());
+        for s in ["B:3", "C:3"].iter() {
+            let result = mi.create_namespace_topk(&vw, s);
+            assert_eq!(
+                result.err().unwrap().to_string(),
+                format!(
+                    "--namespace_topk is only supported on primitive non-f32 namespaces, got: \"{}\"",
+                    s
+                )
+            );
+        }
+    }
+
     #[test]
     fn test_nn_parsing() {
         let mut mi = ModelInstance::new_empty().unwrap();
@@ -693,4 +2096,205 @@ C,featureC
         assert!(result.is_err());
         assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"--nn parameter addressing layer 8, but we have only 4 layers\" })");
     }
+
+    #[test]
+    fn test_dropout_schedule_parsing() {
+        let ds = DropoutSchedule::parse("linear:0.3:0.0:10M").unwrap();
+        assert_eq!(
+            ds,
+            DropoutSchedule {
+                start_rate: 0.3,
+                end_rate: 0.0,
+                num_examples: 10_000_000
+            }
+        );
+        assert_eq!(ds.rate_at(0), 0.3);
+        assert!((ds.rate_at(5_000_000) - 0.15).abs() < 0.00001);
+        assert_eq!(ds.rate_at(10_000_000), 0.0);
+        assert_eq!(ds.rate_at(20_000_000), 0.0);
+
+        assert_eq!(
+            DropoutSchedule::parse("linear:0.1:0.2:500")
+                .unwrap()
+                .num_examples,
+            500
+        );
+        assert!(DropoutSchedule::parse("cosine:0.3:0.0:10M").is_err());
+        assert!(DropoutSchedule::parse("linear:0.3:0.0").is_err());
+        assert!(DropoutSchedule::parse("linear:1.0:0.0:10").is_err());
+        assert!(DropoutSchedule::parse("linear:0.3:0.0:10X").is_err());
+    }
+
+    #[test]
+    fn test_lr_schedule() {
+        let s = LRSchedule::parse("warmup:100,exp:0.5:1K").unwrap();
+        assert_eq!(
+            s.phases,
+            vec![
+                LRSchedulePhase::Warmup { num_examples: 100 },
+                LRSchedulePhase::Exponential {
+                    factor: 0.5,
+                    num_examples: 1000
+                }
+            ]
+        );
+        assert_eq!(s.multiplier_at(0), 0.0);
+        assert!((s.multiplier_at(50) - 0.5 * 0.5f32.powf(0.05)).abs() < 1e-6);
+        assert!((s.multiplier_at(1000) - 0.5).abs() < 1e-6);
+        assert!((s.multiplier_at(2000) - 0.25).abs() < 1e-6);
+
+        let mut s = LRSchedule::parse("cosine:1M:0.1").unwrap();
+        assert_eq!(s.multiplier_at(0), 1.0);
+        assert!((s.multiplier_at(500_000) - 0.55).abs() < 1e-6);
+        assert!((s.multiplier_at(1_000_000) - 0.1).abs() < 1e-6);
+        assert!((s.multiplier_at(2_000_000) - 0.1).abs() < 1e-6);
+        // Continuing training continues the curve
+        s.examples_done = 500_000;
+        assert!((s.multiplier_at(0) - 0.55).abs() < 1e-6);
+        assert_eq!(
+            LRSchedule::parse("cosine:10").unwrap().phases,
+            vec![LRSchedulePhase::Cosine {
+                num_examples: 10,
+                min_multiplier: 0.0
+            }]
+        );
+
+        assert!(LRSchedule::parse("linear:10").is_err());
+        assert!(LRSchedule::parse("warmup:10:20").is_err());
+        assert!(LRSchedule::parse("warmup:0").is_err());
+        assert!(LRSchedule::parse("exp:0.0:10").is_err());
+        assert!(LRSchedule::parse("warmup:10,").is_err());
+
+        // --passes 3 --decay_learning_rate 0.5 over 100 examples per pass
+        let mut s = None;
+        LRSchedule::add_pass_decay(&mut s, 0.5, 100);
+        let s = s.unwrap();
+        assert_eq!(s.multiplier_at(1), 1.0);
+        assert_eq!(s.multiplier_at(100), 1.0);
+        assert_eq!(s.multiplier_at(101), 0.5);
+        assert_eq!(s.multiplier_at(300), 0.25);
+        // The steps of a model trained before count from this run
+        let mut s = Some(LRSchedule::parse("warmup:10").unwrap());
+        s.as_mut().unwrap().examples_done = 1000;
+        LRSchedule::add_pass_decay(&mut s, 0.5, 100);
+        let s = s.unwrap();
+        assert_eq!(s.multiplier_at(100), 1.0);
+        assert_eq!(s.multiplier_at(101), 0.5);
+    }
+
+    #[test]
+    fn test_block_optimizers() {
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        let new_mi = |options: &str| {
+            let mut args = vec!["fw".to_string()];
+            args.extend(options.split_whitespace().map(|s| s.to_string()));
+            ModelInstance::new_from_cmdline(&crate::cmdline::parse_from(args).unwrap(), &vw)
+        };
+        let mi = new_mi("--keep A --adaptive --ffm_optimizer adam --nn_optimizer sgd").unwrap();
+        assert_eq!(mi.optimizer, Optimizer::AdagradLUT);
+        assert_eq!(mi.lr_optimizer, None);
+        assert_eq!(mi.ffm_optimizer, Some(Optimizer::Adam));
+        assert_eq!(mi.nn_optimizer, Some(Optimizer::SGD));
+        let mi = new_mi("--keep A --sgd --nn_optimizer adagrad").unwrap();
+        assert_eq!(mi.optimizer, Optimizer::SGD);
+        assert_eq!(mi.nn_optimizer, Some(Optimizer::AdagradLUT));
+        assert!(new_mi("--keep A --ffm_optimizer foo").is_err());
+    }
+
+    #[test]
+    fn test_invariant_conflicts() {
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        // Either the command line or the model instance refuses them
+        let new_mi = |options: &str| -> Result<ModelInstance, Box<dyn Error>> {
+            let mut args = vec!["fw".to_string()];
+            args.extend(options.split_whitespace().map(|s| s.to_string()));
+            ModelInstance::new_from_cmdline(&crate::cmdline::parse_from(args)?, &vw)
+        };
+        assert!(new_mi("--keep A --invariant").unwrap().invariant);
+        assert!(new_mi("--keep A --invariant --oaa 3").is_err());
+        assert!(new_mi("--keep A --invariant --bpr").is_err());
+        assert!(new_mi("--keep A --invariant --heads 2").is_err());
+        assert!(new_mi("--keep A --invariant --focal_gamma 2").is_err());
+        assert!(new_mi("--keep A --invariant --label_smoothing 0.1").is_err());
+        assert!(new_mi("--keep A --invariant --minibatch 4").is_err());
+    }
+
+    #[test]
+    fn test_ffm_field_pooling() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let new_mi = |options: &str| {
+            let mut args = vec!["fw".to_string()];
+            args.extend(options.split_whitespace().map(|s| s.to_string()));
+            ModelInstance::new_from_cmdline(&crate::cmdline::parse_from(args).unwrap(), &vw)
+        };
+        let mi =
+            new_mi("--ffm_k 2 --ffm_field A --ffm_field B --ffm_field_pooling 1:mean").unwrap();
+        assert_eq!(
+            mi.ffm_field_pooling,
+            vec![EmbeddingPooling::Sum, EmbeddingPooling::Mean]
+        );
+        assert!(new_mi("--ffm_k 2 --ffm_field A --ffm_field_pooling 1:mean").is_err());
+        assert!(new_mi("--ffm_k 2 --ffm_field A --ffm_field_pooling 0:max").is_err());
+        assert!(new_mi("--ffm_k 2 --ffm_field A --ffm_field_pooling A:mean").is_err());
+    }
+
+    #[test]
+    fn test_l2_only_with_ftrl() {
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        let args = |options: &str| {
+            let mut args = vec!["fw".to_string()];
+            args.extend(options.split_whitespace().map(|s| s.to_string()));
+            crate::cmdline::parse_from(args).unwrap()
+        };
+        assert_eq!(
+            ModelInstance::new_from_cmdline(&args("--keep A --ftrl --l2 0.5"), &vw)
+                .unwrap()
+                .l2,
+            0.5
+        );
+        assert!(ModelInstance::new_from_cmdline(&args("--keep A --l2 0.5"), &vw).is_err());
+        assert!(
+            ModelInstance::new_from_cmdline(&args("--keep A --adaptive --l2 0.5"), &vw).is_err()
+        );
+        assert!(ModelInstance::new_from_cmdline(&args("--keep A --l2 0.0"), &vw).is_ok());
+
+        // Also when given for a loaded model
+        let mut mi = ModelInstance::new_from_cmdline(&args("--keep A --adaptive"), &vw).unwrap();
+        assert!(
+            ModelInstance::update_hyperparameters_from_cmd(&args("--l2 0.5"), &mut mi).is_err()
+        );
+        let mut mi = ModelInstance::new_from_cmdline(&args("--keep A --ftrl"), &vw).unwrap();
+        ModelInstance::update_hyperparameters_from_cmd(&args("--l2 0.5"), &mut mi).unwrap();
+        assert_eq!(mi.l2, 0.5);
+    }
+
+    #[test]
+    fn test_ffm_layout() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\nC,featureC\n").unwrap();
+        let args = |options: &str| {
+            let mut args = vec!["fw".to_string()];
+            args.extend(options.split_whitespace().map(|s| s.to_string()));
+            crate::cmdline::parse_from(args).unwrap()
+        };
+        let fields = "--ffm_k 4 --ffm_field A --ffm_field B --ffm_field C";
+        let mi = ModelInstance::new_from_cmdline(&args(fields), &vw).unwrap();
+        assert_eq!(mi.ffm_layout, FfmLayout::FeatureMajor);
+        let options = format!("{} --ffm_bit_precision 4 --ffm_layout field_major", fields);
+        let mut mi = ModelInstance::new_from_cmdline(&args(&options), &vw).unwrap();
+        assert_eq!(mi.ffm_layout, FfmLayout::FieldMajor);
+        // Two bits count the three fields and two the dimensions, no embedding fits in 3 bits
+        let options = format!("{} --ffm_bit_precision 3 --ffm_layout field_major", fields);
+        assert!(ModelInstance::new_from_cmdline(&args(&options), &vw).is_err());
+        let options = format!("{} --ffm_layout row_major", fields);
+        assert!(ModelInstance::new_from_cmdline(&args(&options), &vw).is_err());
+
+        // A loaded model keeps its layout
+        ModelInstance::update_hyperparameters_from_cmd(&args("--ffm_layout field_major"), &mut mi)
+            .unwrap();
+        assert!(ModelInstance::update_hyperparameters_from_cmd(
+            &args("--ffm_layout feature_major"),
+            &mut mi
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,460 @@
+use std::error::Error;
+use std::fs;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::ops::DerefMut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::feature_buffer::FeatureBufferTranslator;
+use crate::ffm_resize;
+use crate::golden_set::GoldenSet;
+use crate::model_instance::ModelInstance;
+use crate::monitoring;
+use crate::monitoring::Monitor;
+use crate::multithread_helpers::BoxedRegressorTrait;
+use crate::parser::VowpalParser;
+use crate::persistence;
+use crate::prediction_log;
+use crate::prediction_log::PredictionLog;
+use crate::regressor::Regressor;
+
+// Switching a running daemon to another model (command "model_load"). Unlike "hogwild_load",
+// which overwrites the served weights in place, the new model is loaded in a background thread
+// while the old one is served, and validated: its header and checksum are read, its weights must
+// have the shape of the served ones, and with --model_canary the canary example has to get a
+// finite prediction. Then it is published under a new generation and all worker threads switch
+// to it before their next request. A model grown by "ffm_grow" is published the same way.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelVersion {
+    pub generation: u64,
+    pub filename: String,
+    pub checksum: u64, // of the model file, as written to the prediction log
+}
+
+impl ModelVersion {
+    pub fn new(generation: u64, filename: &str) -> Result<ModelVersion, Box<dyn Error>> {
+        Ok(ModelVersion {
+            generation,
+            filename: filename.to_string(),
+            checksum: prediction_log::model_version(filename)?,
+        })
+    }
+
+    // Answer to "model_version"
+    pub fn format(&self) -> String {
+        format!(
+            "model_version generation:{} checksum:{:016x} filename:{}\n",
+            self.generation, self.checksum, self.filename
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModelLoadStatus {
+    Running(String), // filename
+    Done(u64),       // generation
+    Failed(String),
+    Growing(u32),    // to this ffm_bit_precision
+    Grown(u32, u64), // ffm_bit_precision, generation
+    GrowFailed(String),
+}
+
+// Shared by all worker threads. Each of them keeps the generation of the model it serves and
+// takes the published one when the generation changes.
+pub struct ModelRegistry {
+    status: Mutex<Option<ModelLoadStatus>>,
+    // The translator is that of the served one, or one for the grown FFM table
+    published: Mutex<
+        Option<(
+            BoxedRegressorTrait,
+            Arc<ModelVersion>,
+            FeatureBufferTranslator,
+        )>,
+    >,
+    generation: AtomicU64,
+    hogwild_loads: AtomicU64, // weights copied by "ffm_grow" before one are stale
+    canary: Option<String>,
+    golden: Option<Arc<GoldenSet>>,
+    prediction_log: Option<Arc<PredictionLog>>,
+    monitor: Option<Arc<Monitor>>,
+}
+
+// Model files of the same namespaces and features are translated the same way, and
+// their weights of the same shape can be served with the translator of the served one.
+// So everything the translator reads has to match.
+fn check_compatible(
+    mi: &ModelInstance,
+    mi_new: &ModelInstance,
+    re: &Regressor,
+    re_new: &Regressor,
+) -> Result<(), Box<dyn Error>> {
+    if mi.bit_precision != mi_new.bit_precision
+        || mi.ffm_bit_precision != mi_new.ffm_bit_precision
+        || mi.ffm_layout != mi_new.ffm_layout
+        || mi.ffm_k != mi_new.ffm_k
+        || mi.fm_k != mi_new.fm_k
+        || mi.add_constant_feature != mi_new.add_constant_feature
+        || mi.feature_combo_descs != mi_new.feature_combo_descs
+        || mi.ffm_fields != mi_new.ffm_fields
+        || mi.ffm_field_pooling != mi_new.ffm_field_pooling
+        || mi.dense_inputs != mi_new.dense_inputs
+        || mi.tenant_namespace != mi_new.tenant_namespace
+    {
+        return Err("its features are hashed differently than those of the served one")?;
+    }
+    if mi.transform_namespaces != mi_new.transform_namespaces {
+        return Err("its namespace transforms differ from those of the served one")?;
+    }
+    if mi.dup_policy != mi_new.dup_policy || mi.namespace_topks != mi_new.namespace_topks {
+        return Err("it rewrites examples differently than the served one")?;
+    }
+    let lookup_namespaces = |mi: &ModelInstance| -> Vec<_> {
+        mi.embedding_lookups
+            .iter()
+            .map(|lookup| lookup.namespace_descriptor)
+            .collect()
+    };
+    if lookup_namespaces(mi) != lookup_namespaces(mi_new) {
+        return Err("its embedding lookups are of other namespaces than the served ones")?;
+    }
+    if !persistence::same_weights_shape(re, re_new) {
+        return Err("its weights have a different shape than the served ones")?;
+    }
+    Ok(())
+}
+
+impl ModelRegistry {
+    pub fn new(
+        canary: Option<String>,
+        golden: Option<Arc<GoldenSet>>,
+        prediction_log: Option<Arc<PredictionLog>>,
+        monitor: Option<Arc<Monitor>>,
+    ) -> ModelRegistry {
+        ModelRegistry {
+            status: Mutex::new(None),
+            published: Mutex::new(None),
+            generation: AtomicU64::new(0),
+            hogwild_loads: AtomicU64::new(0),
+            canary,
+            golden,
+            prediction_log,
+            monitor,
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // The last published model, for worker threads that see a new generation
+    pub fn published(
+        &self,
+    ) -> Option<(
+        BoxedRegressorTrait,
+        Arc<ModelVersion>,
+        FeatureBufferTranslator,
+    )> {
+        self.published
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(re, version, fbt)| (re.clone(), Arc::clone(version), fbt.clone()))
+    }
+
+    // Called after "hogwild_load" replaced the served weights in place
+    pub fn model_loaded(&self) {
+        self.hogwild_loads.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn status(&self) -> Option<ModelLoadStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Returns the validated model and the checksum of its file, which is read only once
+    fn load(
+        &self,
+        filename: &str,
+        re: &BoxedRegressorTrait,
+        fbt: &FeatureBufferTranslator,
+        pa: &mut VowpalParser,
+    ) -> Result<(BoxedRegressorTrait, u64), Box<dyn Error>> {
+        let bytes = fs::read(filename)?;
+        let checksum = prediction_log::model_version_of_bytes(&bytes);
+        let (mi_new, vw_new, re_new) =
+            persistence::new_regressor_from_bytes(&bytes, filename, true, None)?;
+        drop(bytes);
+        if vw_new.vw_source != pa.vw_map.vw_source {
+            return Err("its namespaces differ from those of the served one")?;
+        }
+        check_compatible(&fbt.model_instance, &mi_new, re, &re_new)?;
+        if let Some(canary) = &self.canary {
+            // The clone shares the state of stateful transforms (RollingCount) with the served
+            // translator, the canary example must not be counted
+            let mut fbt = fbt.clone();
+            fbt.translate_without_observing(pa.next_vowpal_from_bytes(canary.as_bytes())?, 0);
+            let mut pb = re_new.new_portbuffer();
+            let p = re_new.predict(&fbt.feature_buffer, &mut pb);
+            if !p.is_finite() {
+                return Err(format!("its prediction of the canary example is {}", p))?;
+            }
+        }
+        if let Some(golden) = &self.golden {
+            golden.check_candidate_regressor(re, &re_new, filename)?;
+        }
+        Ok((BoxedRegressorTrait::new(Box::new(re_new)), checksum))
+    }
+
+    // Loads filename in a background thread, re is the served model
+    pub fn start(
+        self: &Arc<Self>,
+        filename: &str,
+        re: BoxedRegressorTrait,
+        fbt: &FeatureBufferTranslator,
+        pa: &VowpalParser,
+    ) -> Result<thread::JoinHandle<()>, Box<dyn Error>> {
+        self.set_running(ModelLoadStatus::Running(filename.to_string()))?;
+        log::info!("Loading model {} to switch to", filename);
+
+        let registry = Arc::clone(self);
+        let filename = filename.to_string();
+        let fbt = fbt.clone();
+        let mut pa = pa.clone();
+        let handle = thread::spawn(move || {
+            let status = match registry.load(&filename, &re, &fbt, &mut pa) {
+                Ok((re_new, checksum)) => {
+                    let generation = registry.generation() + 1;
+                    let version = ModelVersion {
+                        generation,
+                        filename: filename.clone(),
+                        checksum,
+                    };
+                    registry.publish(re_new, version, fbt);
+                    ModelLoadStatus::Done(generation)
+                }
+                Err(e) => ModelLoadStatus::Failed(format!("Model {} rejected, {}", filename, e)),
+            };
+            match &status {
+                ModelLoadStatus::Failed(e) => log::warn!("Loading model failed: {}", e),
+                _ => log::info!("Model {} loaded, switching to it", filename),
+            }
+            *registry.status.lock().unwrap() = Some(status);
+        });
+        Ok(handle)
+    }
+
+    // Grows the FFM table of the served model in a background thread, re, fbt and version are
+    // those of the served one
+    pub fn start_ffm_grow(
+        self: &Arc<Self>,
+        mut re: BoxedRegressorTrait,
+        fbt: &FeatureBufferTranslator,
+        version: &ModelVersion,
+    ) -> Result<thread::JoinHandle<()>, Box<dyn Error>> {
+        let mi = fbt.model_instance.clone();
+        let mi_grown = ffm_resize::grown_model_instance(&mi)?;
+        self.set_running(ModelLoadStatus::Growing(mi_grown.ffm_bit_precision))?;
+        log::info!(
+            "Growing FFM table from {} to {} bits",
+            mi.ffm_bit_precision,
+            mi_grown.ffm_bit_precision
+        );
+
+        let mut fbt_grown = fbt.clone();
+        fbt_grown.set_ffm_bit_precision(mi_grown.ffm_bit_precision);
+        let registry = Arc::clone(self);
+        let version = version.clone();
+        let hogwild_loads = self.hogwild_loads.load(Ordering::Acquire);
+        let handle = thread::spawn(move || {
+            let grown = ffm_resize::grow_regressor(re.deref_mut(), &mi, &mi_grown);
+            let status = match grown {
+                Ok(_) if registry.hogwild_loads.load(Ordering::Acquire) != hogwild_loads => {
+                    ModelLoadStatus::GrowFailed(
+                        "a model was loaded while growing, grow it again".to_string(),
+                    )
+                }
+                Ok(rg) => {
+                    let generation = registry.generation() + 1;
+                    let version = ModelVersion {
+                        generation,
+                        ..version
+                    };
+                    registry.publish(BoxedRegressorTrait::new(Box::new(rg)), version, fbt_grown);
+                    ModelLoadStatus::Grown(mi_grown.ffm_bit_precision, generation)
+                }
+                Err(e) => ModelLoadStatus::GrowFailed(e.to_string()),
+            };
+            match &status {
+                ModelLoadStatus::GrowFailed(e) => log::warn!("Growing FFM table failed: {}", e),
+                _ => log::info!(
+                    "FFM table grown to {} bits, switching to it",
+                    mi_grown.ffm_bit_precision
+                ),
+            }
+            *registry.status.lock().unwrap() = Some(status);
+        });
+        Ok(handle)
+    }
+
+    // Only one model is loaded or grown at a time
+    fn set_running(&self, running: ModelLoadStatus) -> Result<(), Box<dyn Error>> {
+        let mut status = self.status.lock().unwrap();
+        let e = match &*status {
+            Some(ModelLoadStatus::Running(filename)) => {
+                format!("Model {} is already loading", filename)
+            }
+            Some(ModelLoadStatus::Growing(_)) => "FFM table is already growing".to_string(),
+            _ => {
+                *status = Some(running);
+                return Ok(());
+            }
+        };
+        Err(Box::new(IOError::new(ErrorKind::Other, e)))
+    }
+
+    fn publish(
+        &self,
+        re: BoxedRegressorTrait,
+        version: ModelVersion,
+        fbt: FeatureBufferTranslator,
+    ) {
+        if let Some(prediction_log) = &self.prediction_log {
+            prediction_log.set_model_version(version.checksum);
+        }
+        if let Some(monitor) = &self.monitor {
+            match monitoring::model_time(&version.filename) {
+                Ok(model_time) => monitor.set_model_time(model_time),
+                Err(e) => log::warn!(
+                    "Can not get modification time of {}: {}",
+                    version.filename,
+                    e
+                ),
+            }
+        }
+        let generation = version.generation;
+        *self.published.lock().unwrap() = Some((re, Arc::new(version), fbt));
+        self.generation.store(generation, Ordering::Release);
+    }
+
+    pub fn format_status(&self) -> String {
+        match self.status() {
+            None => String::new(),
+            Some(ModelLoadStatus::Running(filename)) => {
+                format!("model_load {} running\n", filename)
+            }
+            Some(ModelLoadStatus::Done(generation)) => {
+                format!("model_load generation:{} done\n", generation)
+            }
+            Some(ModelLoadStatus::Failed(e)) => format!("model_load failed: {}\n", e),
+            Some(ModelLoadStatus::Growing(bits)) => {
+                format!("ffm_grow ffm_bit_precision:{} running\n", bits)
+            }
+            Some(ModelLoadStatus::Grown(bits, generation)) => format!(
+                "ffm_grow ffm_bit_precision:{} generation:{} done\n",
+                bits, generation
+            ),
+            Some(ModelLoadStatus::GrowFailed(e)) => format!("ffm_grow failed: {}\n", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::model_instance::{DupPolicy, FeatureComboDesc, Optimizer};
+    use crate::vwmap::VwNamespaceMap;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_model_registry() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.optimizer = Optimizer::SGD;
+        mi.feature_combo_descs.push(FeatureComboDesc {
+            namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()]],
+            weight: 1.0,
+        });
+        let mut pa = VowpalParser::new(&vw);
+        let fbt = FeatureBufferTranslator::new(&mi);
+
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("model.fw");
+        let filename = filename.to_str().unwrap();
+        let mut re = Regressor::new(&mi);
+        let mut fbt_learn = fbt.clone();
+        let mut pb = re.new_portbuffer();
+        for _ in 0..10 {
+            fbt_learn.translate(pa.next_vowpal_from_bytes(b"1 |A a\n").unwrap(), 0);
+            re.learn(&fbt_learn.feature_buffer, &mut pb, true);
+        }
+        persistence::save_regressor_to_filename(filename, &mi, &vw, re, false).unwrap();
+
+        let mut re_empty = Regressor::new(&mi);
+        let re_served =
+            BoxedRegressorTrait::new(Box::new(re_empty.immutable_regressor(&mi, false).unwrap()));
+        let registry = Arc::new(ModelRegistry::new(
+            Some("|A a\n".to_string()),
+            None,
+            None,
+            None,
+        ));
+        assert_eq!(registry.generation(), 0);
+        assert!(registry.published().is_none());
+        registry
+            .start(filename, re_served.clone(), &fbt, &pa)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(registry.status(), Some(ModelLoadStatus::Done(1)));
+        assert_eq!(registry.generation(), 1);
+        let (re_new, version, _) = registry.published().unwrap();
+        assert_eq!(version.generation, 1);
+        assert_eq!(version.filename, filename);
+        let mut pb = re_new.new_portbuffer();
+        assert!(re_new.predict(&fbt_learn.feature_buffer, &mut pb) > 0.5);
+
+        // Models that hash features differently are rejected, the published one stays
+        let mut mi2 = mi.clone();
+        mi2.bit_precision = 17;
+        let filename2 = dir.path().join("model2.fw");
+        let filename2 = filename2.to_str().unwrap();
+        persistence::save_regressor_to_filename(filename2, &mi2, &vw, Regressor::new(&mi2), false)
+            .unwrap();
+        registry
+            .start(filename2, re_served.clone(), &fbt, &pa)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(registry
+            .format_status()
+            .starts_with(&format!("model_load failed: Model {} rejected", filename2)));
+        assert_eq!(registry.generation(), 1);
+
+        // So are models that rewrite examples differently
+        let mut mi3 = mi.clone();
+        mi3.dup_policy = Some(DupPolicy::Max);
+        let filename3 = dir.path().join("model3.fw");
+        let filename3 = filename3.to_str().unwrap();
+        persistence::save_regressor_to_filename(filename3, &mi3, &vw, Regressor::new(&mi3), false)
+            .unwrap();
+        registry
+            .start(filename3, re_served, &fbt, &pa)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(registry
+            .format_status()
+            .ends_with("it rewrites examples differently than the served one\n"));
+        assert_eq!(registry.generation(), 1);
+        assert_eq!(
+            registry.published().unwrap().1.checksum,
+            prediction_log::model_version(filename).unwrap()
+        );
+    }
+}
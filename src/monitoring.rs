@@ -0,0 +1,412 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::golden_set::{GoldenMetrics, MetricsAccumulator};
+
+// Alert thresholds of the daemon (--alert_*, can be declared in the --config file as well).
+// Every --alert_interval seconds the counters of the past interval are checked against them,
+// the outcome is written to --alert_status_file and returned by the "stats" command. With
+// --alert_exit the process exits on a breach, with a different exit code for each threshold,
+// so an orchestrator can restart it or page someone.
+
+pub const EXIT_CODE_LOGLOSS: i32 = 10;
+pub const EXIT_CODE_PREDICTION_RATE: i32 = 11;
+pub const EXIT_CODE_PARSE_ERROR_RATE: i32 = 12;
+pub const EXIT_CODE_MODEL_AGE: i32 = 13;
+pub const DEFAULT_ALERT_INTERVAL_SECS: u64 = 60;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AlertThresholds {
+    pub max_logloss: Option<f64>,
+    pub min_prediction_rate: Option<f64>, // predictions per second
+    pub max_parse_error_rate: Option<f64>, // fraction of requests
+    pub max_model_age: Option<u64>,       // seconds
+}
+
+impl AlertThresholds {
+    pub fn is_empty(&self) -> bool {
+        *self == AlertThresholds::default()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Breach {
+    Logloss(f64),
+    PredictionRate(f64),
+    ParseErrorRate(f64),
+    ModelAge(u64),
+}
+
+impl Breach {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Breach::Logloss(_) => EXIT_CODE_LOGLOSS,
+            Breach::PredictionRate(_) => EXIT_CODE_PREDICTION_RATE,
+            Breach::ParseErrorRate(_) => EXIT_CODE_PARSE_ERROR_RATE,
+            Breach::ModelAge(_) => EXIT_CODE_MODEL_AGE,
+        }
+    }
+}
+
+impl fmt::Display for Breach {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breach::Logloss(logloss) => write!(f, "logloss:{:.6}", logloss),
+            Breach::PredictionRate(rate) => write!(f, "prediction_rate:{:.3}", rate),
+            Breach::ParseErrorRate(rate) => write!(f, "parse_error_rate:{:.6}", rate),
+            Breach::ModelAge(age) => write!(f, "model_age:{}", age),
+        }
+    }
+}
+
+// Counters of one interval
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WindowCounters {
+    pub predictions: u64,
+    pub parse_errors: u64,
+    pub labeled: Option<GoldenMetrics>, // of the requests that came with a label, if any
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Modification time of the model file, as seconds since unix epoch
+pub fn model_time(filename: &str) -> io::Result<u64> {
+    Ok(fs::metadata(filename)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()))
+}
+
+pub fn check_thresholds(
+    thresholds: &AlertThresholds,
+    window: &WindowCounters,
+    window_secs: u64,
+    model_age: Option<u64>,
+) -> Vec<Breach> {
+    let mut breaches: Vec<Breach> = Vec::new();
+    if let Some(max_logloss) = thresholds.max_logloss {
+        // Only requests that come with a label tell anything about the logloss
+        if let Some(labeled) = &window.labeled {
+            if labeled.logloss > max_logloss {
+                breaches.push(Breach::Logloss(labeled.logloss));
+            }
+        }
+    }
+    if let Some(min_prediction_rate) = thresholds.min_prediction_rate {
+        let rate = window.predictions as f64 / window_secs.max(1) as f64;
+        if rate < min_prediction_rate {
+            breaches.push(Breach::PredictionRate(rate));
+        }
+    }
+    if let Some(max_parse_error_rate) = thresholds.max_parse_error_rate {
+        let requests = window.predictions + window.parse_errors;
+        if requests > 0 {
+            let rate = window.parse_errors as f64 / requests as f64;
+            if rate > max_parse_error_rate {
+                breaches.push(Breach::ParseErrorRate(rate));
+            }
+        }
+    }
+    if let (Some(max_model_age), Some(model_age)) = (thresholds.max_model_age, model_age) {
+        if model_age > max_model_age {
+            breaches.push(Breach::ModelAge(model_age));
+        }
+    }
+    breaches
+}
+
+// Shared by all worker threads of the daemon
+pub struct Monitor {
+    thresholds: AlertThresholds,
+    interval_secs: u64,
+    status_filename: Option<String>,
+    exit_on_breach: bool,
+    predictions: AtomicU64,
+    parse_errors: AtomicU64,
+    labeled: Mutex<MetricsAccumulator>,
+    model_time: AtomicU64, // 0 when not known
+    status: Mutex<String>,
+}
+
+impl Monitor {
+    pub fn new_from_cmdline(cl: &clap::ArgMatches) -> Result<Option<Monitor>, Box<dyn Error>> {
+        let thresholds = AlertThresholds {
+            max_logloss: match cl.value_of("alert_max_logloss") {
+                Some(logloss) => Some(logloss.parse::<f64>()?),
+                None => None,
+            },
+            min_prediction_rate: match cl.value_of("alert_min_prediction_rate") {
+                Some(rate) => Some(rate.parse::<f64>()?),
+                None => None,
+            },
+            max_parse_error_rate: match cl.value_of("alert_max_parse_error_rate") {
+                Some(rate) => Some(rate.parse::<f64>()?),
+                None => None,
+            },
+            max_model_age: match cl.value_of("alert_max_model_age") {
+                Some(age) => Some(age.parse::<u64>()?),
+                None => None,
+            },
+        };
+        if thresholds.is_empty() {
+            return Ok(None);
+        }
+        let interval_secs = match cl.value_of("alert_interval") {
+            Some(secs) => secs.parse::<u64>()?,
+            None => DEFAULT_ALERT_INTERVAL_SECS,
+        };
+        let monitor = Monitor::new(
+            thresholds,
+            interval_secs,
+            cl.value_of("alert_status_file"),
+            cl.is_present("alert_exit"),
+        )?;
+        if let Some(filename) = cl.value_of("initial_regressor") {
+            monitor.set_model_time(model_time(filename)?);
+        }
+        Ok(Some(monitor))
+    }
+
+    pub fn new(
+        thresholds: AlertThresholds,
+        interval_secs: u64,
+        status_filename: Option<&str>,
+        exit_on_breach: bool,
+    ) -> Result<Monitor, Box<dyn Error>> {
+        if interval_secs == 0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                "Alert interval has to be at least one second",
+            )));
+        }
+        for rate in thresholds.max_parse_error_rate.iter() {
+            if !(0.0..=1.0).contains(rate) {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("Parse error rate has to be within [0, 1], got {}", rate),
+                )));
+            }
+        }
+        Ok(Monitor {
+            thresholds,
+            interval_secs,
+            status_filename: status_filename.map(|s| s.to_string()),
+            exit_on_breach,
+            predictions: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            labeled: Mutex::new(MetricsAccumulator::default()),
+            model_time: AtomicU64::new(0),
+            status: Mutex::new("monitor status:starting\n".to_string()),
+        })
+    }
+
+    // label is None for requests without one
+    pub fn observe_prediction(&self, prediction: f32, label: Option<bool>) {
+        self.predictions.fetch_add(1, Ordering::Relaxed);
+        if let Some(label) = label {
+            self.labeled.lock().unwrap().add(prediction, label);
+        }
+    }
+
+    pub fn observe_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Called when the daemon starts serving another model
+    pub fn set_model_time(&self, model_time: u64) {
+        self.model_time.store(model_time, Ordering::Relaxed);
+    }
+
+    // Counters since the previous call, they start again from zero
+    pub fn take_window(&self) -> WindowCounters {
+        let mut labeled = std::mem::take(&mut *self.labeled.lock().unwrap());
+        WindowCounters {
+            predictions: self.predictions.swap(0, Ordering::Relaxed),
+            parse_errors: self.parse_errors.swap(0, Ordering::Relaxed),
+            labeled: if labeled.is_empty() {
+                None
+            } else {
+                Some(labeled.metrics())
+            },
+        }
+    }
+
+    // Checks the past window and records the status, returns the breaches
+    pub fn check(&self, now: u64) -> Vec<Breach> {
+        let window = self.take_window();
+        let model_age = match self.model_time.load(Ordering::Relaxed) {
+            0 => None,
+            model_time => Some(now.saturating_sub(model_time)),
+        };
+        let breaches = check_thresholds(&self.thresholds, &window, self.interval_secs, model_age);
+        let mut status = format!(
+            "monitor status:{} time:{} predictions:{} parse_errors:{} labeled:{}",
+            if breaches.is_empty() {
+                "ok"
+            } else {
+                "breached"
+            },
+            now,
+            window.predictions,
+            window.parse_errors,
+            window.labeled.map_or(0, |labeled| labeled.examples)
+        );
+        // AUC is NaN unless both labels were seen
+        if let Some(labeled) = window.labeled.filter(|labeled| !labeled.auc.is_nan()) {
+            status.push_str(&format!(" auc:{:.6}", labeled.auc));
+        }
+        if let Some(model_age) = model_age {
+            status.push_str(&format!(" model_age:{}", model_age));
+        }
+        if !breaches.is_empty() {
+            let breaches: Vec<String> = breaches.iter().map(|b| b.to_string()).collect();
+            status.push_str(&format!(" breaches:{}", breaches.join(",")));
+        }
+        status.push('\n');
+        *self.status.lock().unwrap() = status;
+        breaches
+    }
+
+    // Written to a temporary file first, readers never see a partial status
+    pub fn write_status_file(&self) -> io::Result<()> {
+        if let Some(filename) = &self.status_filename {
+            let tmp_filename = format!("{}.tmp", filename);
+            fs::write(&tmp_filename, self.format_status())?;
+            fs::rename(&tmp_filename, filename)?;
+        }
+        Ok(())
+    }
+
+    // Checks the thresholds every interval in a background thread
+    pub fn start(self: &Arc<Self>) -> io::Result<thread::JoinHandle<()>> {
+        log::info!(
+            "Checking alert thresholds every {} seconds: {:?}",
+            self.interval_secs,
+            self.thresholds
+        );
+        self.write_status_file()?;
+        let monitor = Arc::clone(self);
+        Ok(thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(monitor.interval_secs));
+            let breaches = monitor.check(now_secs());
+            if let Err(e) = monitor.write_status_file() {
+                log::warn!("Writing alert status file failed: {}", e);
+            }
+            if let Some(breach) = breaches.first() {
+                log::error!(
+                    "Alert threshold breached: {}",
+                    monitor.format_status().trim_end()
+                );
+                if monitor.exit_on_breach {
+                    std::process::exit(breach.exit_code());
+                }
+            }
+        }))
+    }
+
+    // Status line for the "stats" command
+    pub fn format_status(&self) -> String {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_check_thresholds() {
+        let thresholds = AlertThresholds {
+            max_logloss: Some(0.5),
+            min_prediction_rate: Some(2.0),
+            max_parse_error_rate: Some(0.1),
+            max_model_age: Some(3600),
+        };
+        let window = WindowCounters {
+            predictions: 300,
+            parse_errors: 10,
+            labeled: Some(GoldenMetrics {
+                examples: 10,
+                logloss: 0.4,
+                auc: 0.8,
+            }),
+        };
+        assert_eq!(check_thresholds(&thresholds, &window, 60, Some(60)), vec![]);
+        // Nothing tells about the logloss or parse errors without requests
+        assert_eq!(
+            check_thresholds(&thresholds, &WindowCounters::default(), 60, None),
+            vec![Breach::PredictionRate(0.0)]
+        );
+
+        let window = WindowCounters {
+            predictions: 60,
+            parse_errors: 20,
+            labeled: Some(GoldenMetrics {
+                examples: 10,
+                logloss: 0.7,
+                auc: 0.5,
+            }),
+        };
+        let breaches = check_thresholds(&thresholds, &window, 60, Some(7200));
+        assert_eq!(
+            breaches,
+            vec![
+                Breach::Logloss(0.7),
+                Breach::PredictionRate(1.0),
+                Breach::ParseErrorRate(0.25),
+                Breach::ModelAge(7200)
+            ]
+        );
+        let exit_codes: Vec<i32> = breaches.iter().map(|b| b.exit_code()).collect();
+        assert_eq!(exit_codes, vec![10, 11, 12, 13]);
+        assert!(check_thresholds(&AlertThresholds::default(), &window, 60, Some(7200)).is_empty());
+    }
+
+    #[test]
+    fn test_monitor() {
+        let dir = tempdir().unwrap();
+        let status_filename = dir.path().join("status");
+        let status_filename = status_filename.to_str().unwrap();
+        let thresholds = AlertThresholds {
+            max_logloss: Some(0.5),
+            max_model_age: Some(100),
+            ..AlertThresholds::default()
+        };
+        assert!(Monitor::new(thresholds.clone(), 0, None, false).is_err());
+        let monitor = Monitor::new(thresholds, 10, Some(status_filename), false).unwrap();
+        monitor.set_model_time(1000);
+        monitor.observe_prediction(0.9, Some(true));
+        monitor.observe_prediction(0.1, Some(false));
+        monitor.observe_prediction(0.5, None);
+        monitor.observe_parse_error();
+        assert!(monitor.check(1050).is_empty());
+        monitor.write_status_file().unwrap();
+        assert_eq!(
+            fs::read_to_string(status_filename).unwrap(),
+            "monitor status:ok time:1050 predictions:3 parse_errors:1 labeled:2 auc:1.000000 model_age:50\n"
+        );
+
+        // Counters start from zero in each window
+        monitor.observe_prediction(0.1, Some(true));
+        let breaches = monitor.check(1200);
+        assert_eq!(breaches.len(), 2);
+        assert_eq!(breaches[1], Breach::ModelAge(200));
+        assert!(monitor.format_status().starts_with(
+            "monitor status:breached time:1200 predictions:1 parse_errors:0 labeled:1 model_age:200 breaches:logloss:2.302585,model_age:200"
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+use std::error::Error;
+use std::fmt;
+use std::io::BufRead;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+use crate::buffer_handler::create_buffered_input;
+use crate::parser;
+use crate::parser::VowpalParser;
+use crate::vwmap::VwNamespaceMap;
+
+// Training from several sources at once (--source logs_a.vw:1.0 --source logs_b.vw:0.3).
+// Records are interleaved deterministically, one from each source in turn, and sources that run out
+// are skipped. Example importance of each record is multiplied by the importance of its source.
+pub struct RecordSource {
+    pub filename: String,
+    pub importance: f32,
+    input: Box<dyn BufRead>,
+    parser: VowpalParser,
+    exhausted: bool,
+}
+
+// Index of the source and the record
+pub type SourceRecord<'a> = (usize, &'a [u32]);
+
+pub struct MultiSource {
+    pub sources: Vec<RecordSource>,
+    next_source: usize,
+    record: Vec<u32>,
+}
+
+// Parses filename:importance
+pub fn parse_source(s: &str) -> Result<(String, f32), Box<dyn Error>> {
+    let parsed = match s.rfind(':') {
+        Some(i) => s[i + 1..].parse::<f32>().ok().map(|w| (&s[..i], w)),
+        None => None,
+    };
+    match parsed {
+        Some((filename, importance))
+            if !filename.is_empty() && importance.is_finite() && importance >= 0.0 =>
+        {
+            Ok((filename.to_string(), importance))
+        }
+        _ => Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!(
+                "--source has to be of the form filename:importance with non-negative importance, got: \"{}\"",
+                s
+            ),
+        ))),
+    }
+}
+
+impl MultiSource {
+    pub fn new(specs: &[&str], vw: &VwNamespaceMap) -> Result<MultiSource, Box<dyn Error>> {
+        let mut sources: Vec<RecordSource> = Vec::new();
+        for spec in specs {
+            let (filename, importance) = parse_source(spec)?;
+            sources.push(RecordSource {
+                input: create_buffered_input(&filename),
+                filename,
+                importance,
+                parser: VowpalParser::new(vw),
+                exhausted: false,
+            });
+        }
+        Ok(MultiSource {
+            sources,
+            next_source: 0,
+            record: Vec::new(),
+        })
+    }
+
+    // Returns the next record together with the index of its source, None when all are exhausted
+    pub fn next_record(&mut self) -> Result<Option<SourceRecord<'_>>, Box<dyn Error>> {
+        let num_sources = self.sources.len();
+        for _ in 0..num_sources {
+            let source_index = self.next_source;
+            self.next_source = (self.next_source + 1) % num_sources;
+            let source = &mut self.sources[source_index];
+            if source.exhausted {
+                continue;
+            }
+            let buffer = source.parser.next_vowpal(&mut source.input)?;
+            if buffer.is_empty() {
+                source.exhausted = true;
+                continue;
+            }
+            self.record.truncate(0);
+            self.record.extend_from_slice(buffer);
+            let importance = f32::from_bits(self.record[parser::EXAMPLE_IMPORTANCE_OFFSET]);
+            self.record[parser::EXAMPLE_IMPORTANCE_OFFSET] =
+                (importance * source.importance).to_bits();
+            return Ok(Some((source_index, &self.record)));
+        }
+        Ok(None)
+    }
+}
+
+// Per-source progress, predictions are evaluated only for examples that have them
+#[derive(Default, Debug)]
+pub struct SourceMetrics {
+    pub examples: u64,
+    pub evaluated: u64,
+    pub positives: u64,
+    pub prediction_sum: f64,
+    pub logloss_sum: f64,
+}
+
+impl SourceMetrics {
+    pub fn add_example(&mut self, record: &[u32], prediction: Option<f32>) {
+        self.examples += 1;
+        let label = record[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK;
+        if let Some(prediction) = prediction {
+            if label == parser::NO_LABEL {
+                return;
+            }
+            let p = (prediction as f64).clamp(1e-7, 1.0 - 1e-7);
+            self.evaluated += 1;
+            self.prediction_sum += p;
+            if label == 1 {
+                self.positives += 1;
+                self.logloss_sum -= p.ln();
+            } else {
+                self.logloss_sum -= (1.0 - p).ln();
+            }
+        }
+    }
+}
+
+impl fmt::Display for SourceMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "examples: {}", self.examples)?;
+        if self.evaluated > 0 {
+            let n = self.evaluated as f64;
+            write!(
+                f,
+                ", evaluated: {}, logloss: {:.6}, avg prediction: {:.6}, positive rate: {:.6}",
+                self.evaluated,
+                self.logloss_sum / n,
+                self.prediction_sum / n,
+                self.positives as f64 / n
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            parse_source("logs_a.vw:0.3").unwrap(),
+            ("logs_a.vw".to_string(), 0.3)
+        );
+        assert_eq!(
+            parse_source("/data/a:b.vw:1").unwrap(),
+            ("/data/a:b.vw".to_string(), 1.0)
+        );
+        assert!(parse_source("logs_a.vw").is_err());
+        assert!(parse_source("logs_a.vw:").is_err());
+        assert!(parse_source(":1.0").is_err());
+        assert!(parse_source("logs_a.vw:-1.0").is_err());
+    }
+
+    #[test]
+    fn test_interleaving() {
+        let vw = VwNamespaceMap::new("A,featureA\n").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.vw");
+        let b = dir.path().join("b.vw");
+        fs::write(&a, "1 |A a1\n-1 |A a2\n1 |A a3\n").unwrap();
+        fs::write(&b, "-1 2.0 |A b1\n").unwrap();
+        let a_spec = format!("{}:0.5", a.to_str().unwrap());
+        let b_spec = format!("{}:0.25", b.to_str().unwrap());
+        let mut ms = MultiSource::new(&[&a_spec, &b_spec], &vw).unwrap();
+
+        let mut seen: Vec<(usize, u32, f32)> = Vec::new();
+        while let Some((source_index, record)) = ms.next_record().unwrap() {
+            seen.push((
+                source_index,
+                record[parser::LABEL_OFFSET],
+                f32::from_bits(record[parser::EXAMPLE_IMPORTANCE_OFFSET]),
+            ));
+        }
+        assert_eq!(
+            seen,
+            vec![(0, 1, 0.5), (1, 0, 0.5), (0, 0, 0.5), (0, 1, 0.5)]
+        );
+        assert!(ms.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_source_metrics() {
+        let mut metrics = SourceMetrics::default();
+        metrics.add_example(&[3, 1, 1.0f32.to_bits()], Some(0.5));
+        metrics.add_example(&[3, 0, 1.0f32.to_bits()], Some(0.5));
+        metrics.add_example(&[3, parser::NO_LABEL, 1.0f32.to_bits()], Some(0.5));
+        metrics.add_example(&[3, 1, 1.0f32.to_bits()], None);
+        assert_eq!(metrics.examples, 4);
+        assert_eq!(metrics.evaluated, 2);
+        assert_eq!(metrics.positives, 1);
+        assert!((metrics.logloss_sum / 2.0 - 2.0f64.ln()).abs() < 1e-6);
+    }
+}
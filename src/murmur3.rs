@@ -0,0 +1,167 @@
+// MurmurHash3 x86_32, the feature hash of Vowpal Wabbit.
+// Same results as fasthash::murmur3, which wraps the C++ reference and does not build on aarch64.
+
+const C1: u32 = 0xcc9e_2d51;
+const C2: u32 = 0x1b87_3593;
+
+#[inline(always)]
+fn mix_k1(mut k1: u32) -> u32 {
+    k1 = k1.wrapping_mul(C1);
+    k1 = k1.rotate_left(15);
+    k1.wrapping_mul(C2)
+}
+
+#[inline(always)]
+fn fmix32(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+#[inline(always)]
+pub fn hash32_with_seed<T: AsRef<[u8]>>(v: T, seed: u32) -> u32 {
+    let data = v.as_ref();
+    let mut h1 = seed;
+
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let k1 = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        h1 ^= mix_k1(k1);
+        h1 = h1.rotate_left(13);
+        h1 = h1.wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let mut k1 = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k1 ^= (*byte as u32) << (8 * i);
+        }
+        h1 ^= mix_k1(k1);
+    }
+
+    h1 ^= data.len() as u32;
+    fmix32(h1)
+}
+
+// hash32_with_seed_batch() hashes BATCH tokens at once, one per lane, which the compiler vectorizes.
+// Tokens are (start, len) in buf, and buf has to extend BATCH_PADDING bytes past the end of every token,
+// so the blocks can be read whole and masked instead of byte by byte.
+pub const BATCH: usize = 8;
+pub const BATCH_PADDING: usize = 4;
+
+pub fn hash32_with_seed_batch(
+    buf: &[u8],
+    starts: &[u32; BATCH],
+    lens: &[u32; BATCH],
+    seeds: &[u32; BATCH],
+) -> [u32; BATCH] {
+    let mut max_len = 0;
+    let mut max_end = 0;
+    for (start, len) in starts.iter().zip(lens.iter()) {
+        max_len = max_len.max(*len);
+        max_end = max_end.max(*start as usize + *len as usize);
+    }
+    assert!(max_end + BATCH_PADDING <= buf.len());
+
+    let mut h1 = *seeds;
+    let mut offset = 0u32;
+    while offset < max_len {
+        let mut k1 = [0u32; BATCH];
+        let mut full_mask = [0u32; BATCH];
+        let mut tail_mask = [0u32; BATCH];
+        for i in 0..BATCH {
+            let remaining = lens[i].saturating_sub(offset);
+            let pos = starts[i] as usize + offset.min(lens[i]) as usize;
+            // In bounds by the assert above
+            let block = unsafe { (buf.as_ptr().add(pos) as *const u32).read_unaligned() };
+            let bytes_mask = if remaining >= 4 {
+                !0
+            } else {
+                (1u32 << (8 * remaining)).wrapping_sub(1)
+            };
+            k1[i] = u32::from_le(block) & bytes_mask;
+            full_mask[i] = 0u32.wrapping_sub((remaining >= 4) as u32);
+            tail_mask[i] = 0u32.wrapping_sub((remaining > 0 && remaining < 4) as u32);
+        }
+        // Lanes past their end keep their hash, the tail block is mixed in without the rotation
+        for i in 0..BATCH {
+            let mixed = h1[i] ^ mix_k1(k1[i]);
+            let body = mixed
+                .rotate_left(13)
+                .wrapping_mul(5)
+                .wrapping_add(0xe654_6b64);
+            h1[i] = (body & full_mask[i])
+                | (mixed & tail_mask[i])
+                | (h1[i] & !(full_mask[i] | tail_mask[i]));
+        }
+        offset += 4;
+    }
+
+    for i in 0..BATCH {
+        h1[i] = fmix32(h1[i] ^ lens[i]);
+    }
+    h1
+}
+
+#[inline(always)]
+pub fn hash32<T: AsRef<[u8]>>(v: T) -> u32 {
+    hash32_with_seed(v, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_murmur3() {
+        // Reference values of MurmurHash3_x86_32
+        assert_eq!(hash32(""), 0);
+        assert_eq!(hash32_with_seed("", 1), 0x514e_28b7);
+        assert_eq!(hash32_with_seed("", 0xffff_ffff), 0x81f1_6f39);
+        assert_eq!(hash32("test"), 0xba6b_d213);
+        assert_eq!(hash32_with_seed("Hello, world!", 1234), 0xfaf6_cdb3);
+        assert_eq!(
+            hash32("The quick brown fox jumps over the lazy dog"),
+            0x2e4f_f723
+        );
+        // Tails of 1, 2 and 3 bytes
+        assert_eq!(hash32_with_seed([0x21u8], 0), 0x7266_1cf4);
+        assert_eq!(hash32_with_seed([0x21u8, 0x43], 0), 0xa0f7_b07a);
+        assert_eq!(hash32_with_seed([0x21u8, 0x43, 0x65], 0), 0x7e4a_8634);
+    }
+
+    #[test]
+    fn test_murmur3_batch() {
+        let buf = b"a bb ccc dddd eeeee ffffffffffffffff gg  hhhhhhh iii jjjjjjjjj";
+        let mut padded = buf.to_vec();
+        padded.extend_from_slice(&[0xff; BATCH_PADDING]);
+        let tokens: Vec<(u32, u32)> = buf
+            .split(|b| *b == b' ')
+            .scan(0, |start, token| {
+                let r = (*start, token.len() as u32);
+                *start += token.len() as u32 + 1;
+                Some(r)
+            })
+            .collect();
+        // Two batches, the empty token included, with the tokens in the lanes shifted by one
+        for first in 0..2 {
+            let mut starts = [0u32; BATCH];
+            let mut lens = [0u32; BATCH];
+            let mut seeds = [0u32; BATCH];
+            for i in 0..BATCH {
+                starts[i] = tokens[first + i].0;
+                lens[i] = tokens[first + i].1;
+                seeds[i] = (i as u32).wrapping_mul(0x9e37_79b9);
+            }
+            let hashes = hash32_with_seed_batch(&padded, &starts, &lens, &seeds);
+            for i in 0..BATCH {
+                let token = &buf[starts[i] as usize..(starts[i] + lens[i]) as usize];
+                assert_eq!(hashes[i], hash32_with_seed(token, seeds[i]));
+            }
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use std::error::Error;
+use std::fs;
+
+use crate::persistence;
+
+// Export of forward-only regressors to ONNX (--export_onnx), so other serving stacks can load them.
+// Blocks add their operators in the order of the regressor's execution (see BlockTrait::to_onnx),
+// reading and writing tensors by the tape ranges they use, the same way they do in forward().
+// The graph scores a single example, whose inputs are the hashed features of its feature buffer:
+//   lr_hash, lr_combo (int64) and lr_value (float) of lr_buffer
+//   ffm_hash, ffm_field (int64, contra_field_index / ffm_k) and ffm_value (float) of ffm_buffer
+// and its output is the prediction, a tensor of a single probability.
+// Weights become initializers, protobuf limits the whole model to 2GB.
+
+const ONNX_IR_VERSION: i64 = 8;
+const ONNX_OPSET_VERSION: i64 = 13; // ReduceSum with axes as an input
+const ONNX_MAX_MODEL_LEN: usize = 2 << 30;
+
+// TensorProto.DataType
+pub const ONNX_FLOAT: i32 = 1;
+pub const ONNX_INT64: i32 = 7;
+
+pub const PREDICTION_OUTPUT: &str = "prediction";
+
+pub enum OnnxAttribute {
+    Int(&'static str, i64),
+    Ints(&'static str, Vec<i64>),
+    Str(&'static str, &'static str),
+}
+
+pub struct OnnxNode {
+    pub op_type: &'static str,
+    pub inputs: Vec<String>,
+    pub output: String,
+    pub attributes: Vec<OnnxAttribute>,
+}
+
+pub struct OnnxInitializer {
+    pub name: String,
+    pub dims: Vec<i64>,
+    pub data_type: i32,
+    pub raw_data: Vec<u8>,
+}
+
+// Tensor of len values that a block wrote to the tape at offset
+struct TapeTensor {
+    offset: usize,
+    len: usize,
+    name: String,
+}
+
+pub struct OnnxGraph {
+    pub nodes: Vec<OnnxNode>,
+    pub initializers: Vec<OnnxInitializer>,
+    pub inputs: Vec<(String, i32)>,
+    pub output: Option<String>,
+    tape: Vec<TapeTensor>,
+}
+
+impl OnnxGraph {
+    pub fn new() -> OnnxGraph {
+        OnnxGraph {
+            nodes: Vec::new(),
+            initializers: Vec::new(),
+            inputs: Vec::new(),
+            output: None,
+            tape: Vec::new(),
+        }
+    }
+
+    // Graph input of a variable number of values, added once however many blocks read it
+    pub fn input(&mut self, name: &str, data_type: i32) -> String {
+        if !self.inputs.iter().any(|(n, _)| n == name) {
+            self.inputs.push((name.to_string(), data_type));
+        }
+        name.to_string()
+    }
+
+    fn unique_name(&self, hint: &str) -> String {
+        format!("{}_{}", hint, self.nodes.len() + self.initializers.len())
+    }
+
+    pub fn initializer_f32(&mut self, hint: &str, dims: Vec<i64>, values: &[f32]) -> String {
+        let name = self.unique_name(hint);
+        let mut raw_data: Vec<u8> = Vec::with_capacity(values.len() * 4);
+        for v in values.iter() {
+            raw_data.extend_from_slice(&v.to_le_bytes());
+        }
+        self.initializers.push(OnnxInitializer {
+            name: name.clone(),
+            dims,
+            data_type: ONNX_FLOAT,
+            raw_data,
+        });
+        name
+    }
+
+    pub fn initializer_i64(&mut self, hint: &str, dims: Vec<i64>, values: &[i64]) -> String {
+        let name = self.unique_name(hint);
+        let mut raw_data: Vec<u8> = Vec::with_capacity(values.len() * 8);
+        for v in values.iter() {
+            raw_data.extend_from_slice(&v.to_le_bytes());
+        }
+        self.initializers.push(OnnxInitializer {
+            name: name.clone(),
+            dims,
+            data_type: ONNX_INT64,
+            raw_data,
+        });
+        name
+    }
+
+    // Adds an operator with a single output and returns the name of the output
+    pub fn node(
+        &mut self,
+        op_type: &'static str,
+        inputs: &[&str],
+        attributes: Vec<OnnxAttribute>,
+    ) -> String {
+        let output = self.unique_name(&op_type.to_lowercase());
+        self.nodes.push(OnnxNode {
+            op_type,
+            inputs: inputs.iter().map(|i| i.to_string()).collect(),
+            output: output.clone(),
+            attributes,
+        });
+        output
+    }
+
+    pub fn set_tape(&mut self, offset: usize, len: usize, name: &str) {
+        self.tape.push(TapeTensor {
+            offset,
+            len,
+            name: name.to_string(),
+        });
+    }
+
+    // Tensor of the values at the tape range, as the blocks that ran so far left them. Ranges
+    // written by several blocks (e.g. joins) are concatenated from slices of their tensors.
+    pub fn get_tape(&mut self, offset: usize, len: usize) -> Result<String, Box<dyn Error>> {
+        let mut pieces: Vec<String> = Vec::new();
+        let mut position = offset;
+        while position < offset + len {
+            // The newest tensor at the position is valid until a newer one starts
+            let newest = match self
+                .tape
+                .iter()
+                .rposition(|t| t.offset <= position && position < t.offset + t.len)
+            {
+                Some(newest) => newest,
+                None => {
+                    return Err(format!(
+                        "No block wrote tape position {} that a block reads, the graph can't be exported",
+                        position
+                    ))?
+                }
+            };
+            let mut end = (self.tape[newest].offset + self.tape[newest].len).min(offset + len);
+            for t in self.tape[newest + 1..].iter() {
+                if t.offset > position && t.offset < end {
+                    end = t.offset;
+                }
+            }
+            let (name, start) = (self.tape[newest].name.clone(), self.tape[newest].offset);
+            if position == start && end == start + self.tape[newest].len {
+                pieces.push(name);
+            } else {
+                let starts = self.initializer_i64("starts", vec![1], &[(position - start) as i64]);
+                let ends = self.initializer_i64("ends", vec![1], &[(end - start) as i64]);
+                pieces.push(self.node("Slice", &[&name, &starts, &ends], Vec::new()));
+            }
+            position = end;
+        }
+        if pieces.len() == 1 {
+            return Ok(pieces.pop().unwrap());
+        }
+        let pieces: Vec<&str> = pieces.iter().map(|p| p.as_str()).collect();
+        let joined = self.node("Concat", &pieces, vec![OnnxAttribute::Int("axis", 0)]);
+        self.set_tape(offset, len, &joined);
+        Ok(joined)
+    }
+
+    pub fn to_model_proto(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let output = match &self.output {
+            Some(output) => output,
+            None => return Err("Exported graph has no prediction output")?,
+        };
+        let mut graph = ProtoWriter::new();
+        for node in self.nodes.iter() {
+            graph.message(1, &node_proto(node));
+        }
+        graph.string(2, "fw");
+        for initializer in self.initializers.iter() {
+            graph.message(5, &tensor_proto(initializer));
+        }
+        for (name, data_type) in self.inputs.iter() {
+            graph.message(11, &value_info_proto(name, *data_type, None));
+        }
+        // The output is renamed with an Identity, so it has a stable name
+        let mut identity = ProtoWriter::new();
+        identity.string(1, output);
+        identity.string(2, PREDICTION_OUTPUT);
+        identity.string(4, "Identity");
+        graph.message(1, &identity);
+        graph.message(
+            12,
+            &value_info_proto(PREDICTION_OUTPUT, ONNX_FLOAT, Some(1)),
+        );
+
+        let mut model = ProtoWriter::new();
+        model.int64(1, ONNX_IR_VERSION);
+        model.string(2, "fw");
+        model.string(3, env!("CARGO_PKG_VERSION"));
+        model.message(7, &graph);
+        let mut opset = ProtoWriter::new();
+        opset.string(1, "");
+        opset.int64(2, ONNX_OPSET_VERSION);
+        model.message(8, &opset);
+        if model.buf.len() > ONNX_MAX_MODEL_LEN {
+            return Err(format!(
+                "Exported model has {} bytes, more than 2GB that ONNX models can have",
+                model.buf.len()
+            ))?;
+        }
+        Ok(model.buf)
+    }
+}
+
+impl Default for OnnxGraph {
+    fn default() -> Self {
+        OnnxGraph::new()
+    }
+}
+
+// Protocol buffers encoding of the few messages of onnx.proto that we need
+struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    fn new() -> ProtoWriter {
+        ProtoWriter { buf: Vec::new() }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn tag(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type) as u64);
+    }
+
+    fn int64(&mut self, field: u32, value: i64) {
+        self.tag(field, 0);
+        self.varint(value as u64);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.tag(field, 2);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, message: &ProtoWriter) {
+        self.bytes(field, &message.buf);
+    }
+}
+
+fn node_proto(node: &OnnxNode) -> ProtoWriter {
+    let mut proto = ProtoWriter::new();
+    for input in node.inputs.iter() {
+        proto.string(1, input);
+    }
+    proto.string(2, &node.output);
+    proto.string(3, &node.output);
+    proto.string(4, node.op_type);
+    for attribute in node.attributes.iter() {
+        // AttributeProto: name 1, i 3, s 4, ints 8, type 20 (INT 2, STRING 3, INTS 7)
+        let mut a = ProtoWriter::new();
+        match attribute {
+            OnnxAttribute::Int(name, value) => {
+                a.string(1, name);
+                a.int64(3, *value);
+                a.int64(20, 2);
+            }
+            OnnxAttribute::Ints(name, values) => {
+                a.string(1, name);
+                for value in values.iter() {
+                    a.int64(8, *value);
+                }
+                a.int64(20, 7);
+            }
+            OnnxAttribute::Str(name, value) => {
+                a.string(1, name);
+                a.string(4, value);
+                a.int64(20, 3);
+            }
+        }
+        proto.message(5, &a);
+    }
+    proto
+}
+
+fn tensor_proto(initializer: &OnnxInitializer) -> ProtoWriter {
+    let mut proto = ProtoWriter::new();
+    for dim in initializer.dims.iter() {
+        proto.int64(1, *dim);
+    }
+    proto.int64(2, initializer.data_type as i64);
+    proto.string(8, &initializer.name);
+    proto.bytes(9, &initializer.raw_data);
+    proto
+}
+
+// Tensor of one dimension, of the given length or of a variable one
+fn value_info_proto(name: &str, data_type: i32, len: Option<i64>) -> ProtoWriter {
+    let mut dim = ProtoWriter::new();
+    match len {
+        Some(len) => dim.int64(1, len),
+        None => dim.string(2, &format!("{}_len", name)),
+    }
+    let mut shape = ProtoWriter::new();
+    shape.message(1, &dim);
+    let mut tensor_type = ProtoWriter::new();
+    tensor_type.int64(1, data_type as i64);
+    tensor_type.message(2, &shape);
+    let mut type_proto = ProtoWriter::new();
+    type_proto.message(1, &tensor_type);
+    let mut proto = ProtoWriter::new();
+    proto.string(1, name);
+    proto.message(2, &type_proto);
+    proto
+}
+
+pub fn export_model(
+    model_filename: &str,
+    onnx_filename: &str,
+    cl: &clap::ArgMatches,
+) -> Result<(), Box<dyn Error>> {
+    let (_, _, re) = persistence::new_regressor_from_filename(model_filename, true, Some(cl))?;
+    let graph = re.to_onnx()?;
+    fs::write(onnx_filename, graph.to_model_proto()?)?;
+    log::info!(
+        "Exported {} to ONNX {}: {} operators, {} initializers",
+        model_filename,
+        onnx_filename,
+        graph.nodes.len(),
+        graph.initializers.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::model_instance::{FeatureComboDesc, FfmLayout, ModelInstance, Optimizer};
+    use crate::parser::VowpalParser;
+    use crate::regressor::Regressor;
+    use crate::vwmap::VwNamespaceMap;
+    use tract_onnx::prelude::*;
+
+    #[test]
+    fn test_protobuf_encoding() {
+        let mut proto = ProtoWriter::new();
+        proto.int64(1, 300);
+        proto.string(2, "ab");
+        assert_eq!(proto.buf, vec![0x08, 0xac, 0x02, 0x12, 0x02, b'a', b'b']);
+    }
+
+    #[test]
+    fn test_tape_pieces() {
+        let mut graph = OnnxGraph::new();
+        graph.set_tape(0, 2, "a");
+        graph.set_tape(2, 3, "b");
+        assert_eq!(graph.get_tape(0, 2).unwrap(), "a");
+        let joined = graph.get_tape(0, 5).unwrap();
+        assert_eq!(graph.nodes.last().unwrap().op_type, "Concat");
+        assert_eq!(graph.nodes.last().unwrap().inputs, vec!["a", "b"]);
+        // Joined ranges are remembered
+        assert_eq!(graph.get_tape(0, 5).unwrap(), joined);
+
+        // Newer tensors cover older ones, so a part of b is sliced out
+        graph.set_tape(4, 1, "c");
+        graph.get_tape(2, 3).unwrap();
+        let ops: Vec<&str> = graph.nodes.iter().map(|n| n.op_type).collect();
+        assert_eq!(ops, vec!["Concat", "Slice", "Concat"]);
+        assert_eq!(graph.nodes[2].inputs[1], "c");
+
+        assert!(graph.get_tape(5, 1).is_err());
+    }
+
+    #[test]
+    fn test_export_lr_ffm() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 4;
+        mi.ffm_bit_precision = 4;
+        mi.ffm_k = 2;
+        mi.feature_combo_descs.push(FeatureComboDesc {
+            namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()]],
+            weight: 1.0,
+        });
+        mi.ffm_fields = vec![vec![], vec![]];
+        let mut re = Regressor::new(&mi);
+        assert!(re.to_onnx().is_err());
+
+        re = re.immutable_regressor_without_weights(&mi).unwrap();
+        re.allocate_and_init_weights(&mi);
+        let graph = re.to_onnx().unwrap();
+        let input_names: Vec<&str> = graph.inputs.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            input_names,
+            vec![
+                "lr_hash",
+                "lr_value",
+                "lr_combo",
+                "ffm_hash",
+                "ffm_value",
+                "ffm_field"
+            ]
+        );
+        let ops: Vec<&str> = graph.nodes.iter().map(|n| n.op_type).collect();
+        assert!(ops.contains(&"OneHot"));
+        // LR and the ffm triangle are joined in front of the sigmoid
+        assert!(ops.contains(&"Concat"));
+        assert_eq!(ops.last(), Some(&"Sigmoid"));
+        assert!(graph.output.is_some());
+        let lr_weights = graph
+            .initializers
+            .iter()
+            .find(|i| i.name.starts_with("lr_weights"))
+            .unwrap();
+        assert_eq!(lr_weights.dims, vec![16]);
+
+        let model = graph.to_model_proto().unwrap();
+        // ModelProto starts with ir_version
+        assert_eq!(&model[..2], &[0x08, ONNX_IR_VERSION as u8]);
+    }
+
+    // The exported graph, run by tract, predicts what fw does
+    fn check_export_matches_predictions(ffm_layout: FfmLayout) {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\nC,featureC\n").unwrap();
+        let nd = |name: &str| vw.map_verbose_to_namespace_descriptor[name];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.optimizer = Optimizer::SGD;
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_layout = ffm_layout;
+        mi.ffm_init_width = 1.0;
+        mi.feature_combo_descs.push(FeatureComboDesc {
+            namespace_descriptors: vec![nd("featureA"), nd("featureB")],
+            weight: 1.0,
+        });
+        mi.ffm_fields = vec![vec![nd("featureA")], vec![nd("featureB"), nd("featureC")]];
+
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        for i in 0..50 {
+            let line = format!(
+                "{} |A a{} |B b{} |C c{}\n",
+                if i % 2 == 0 { "1" } else { "-1" },
+                i % 3,
+                i % 5,
+                i % 2
+            );
+            fbt.translate(pa.next_vowpal_from_bytes(line.as_bytes()).unwrap(), 0);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+        let re = re.immutable_regressor(&mi, false).unwrap();
+        let model = re.to_onnx().unwrap().to_model_proto().unwrap();
+        let mut model = tract_onnx::onnx().model_for_read(&mut &model[..]).unwrap();
+
+        // Features of the same field next to each other, one with a value
+        fbt.translate(
+            pa.next_vowpal_from_bytes(b"1 |A a1 |B b2:0.5 b3 |C c1\n")
+                .unwrap(),
+            0,
+        );
+        let fb = &fbt.feature_buffer;
+        let inputs = vec![
+            tensor1(
+                &fb.lr_buffer
+                    .iter()
+                    .map(|h| h.hash as i64)
+                    .collect::<Vec<i64>>(),
+            ),
+            tensor1(&fb.lr_buffer.iter().map(|h| h.value).collect::<Vec<f32>>()),
+            tensor1(
+                &fb.lr_buffer
+                    .iter()
+                    .map(|h| h.combo_index as i64)
+                    .collect::<Vec<i64>>(),
+            ),
+            tensor1(
+                &fb.ffm_buffer
+                    .iter()
+                    .map(|h| h.hash as i64)
+                    .collect::<Vec<i64>>(),
+            ),
+            tensor1(&fb.ffm_buffer.iter().map(|h| h.value).collect::<Vec<f32>>()),
+            tensor1(
+                &fb.ffm_buffer
+                    .iter()
+                    .map(|h| (h.contra_field_index / mi.ffm_k) as i64)
+                    .collect::<Vec<i64>>(),
+            ),
+        ];
+        for (i, input) in inputs.iter().enumerate() {
+            model
+                .set_input_fact(i, InferenceFact::dt_shape_from_tensor(input))
+                .unwrap();
+        }
+        let model = model.into_optimized().unwrap().into_runnable().unwrap();
+        let outputs = model
+            .run(inputs.into_iter().map(|t| t.into()).collect())
+            .unwrap();
+        let exported = outputs[0].as_slice::<f32>().unwrap()[0];
+        let p = re.predict(fb, &mut pb);
+        assert!(p != 0.5);
+        assert!((p - exported).abs() < 1e-5, "fw {} onnx {}", p, exported);
+    }
+
+    #[test]
+    fn test_export_matches_predictions() {
+        check_export_matches_predictions(FfmLayout::FeatureMajor);
+        check_export_matches_predictions(FfmLayout::FieldMajor);
+    }
+}
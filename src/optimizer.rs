@@ -1,3 +1,6 @@
+use rand_distr::{Distribution, Normal};
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
 use std::marker::PhantomData;
 
 pub trait OptimizerTrait: std::clone::Clone {
@@ -7,6 +10,12 @@ pub trait OptimizerTrait: std::clone::Clone {
     unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32;
     fn initial_data(&self) -> Self::PerWeightStore;
     fn get_name() -> &'static str;
+    // L1 and L2 regularization strengths, only used by optimizers that regularize on their own
+    fn init_regularization(&mut self, _l1: f32, _l2: f32) {}
+    // Scales the learning rate given to init, see model_instance::LRSchedule
+    fn set_learning_rate_multiplier(&mut self, _multiplier: f32) {}
+    // Only used by optimizers with momentum
+    fn init_momentum(&mut self, _momentum: f32) {}
 }
 
 /******************* SGD **************************/
@@ -14,6 +23,7 @@ pub trait OptimizerTrait: std::clone::Clone {
 #[derive(Clone)]
 pub struct OptimizerSGD {
     learning_rate: f32,
+    base_learning_rate: f32,
 }
 
 impl OptimizerTrait for OptimizerSGD {
@@ -24,11 +34,19 @@ impl OptimizerTrait for OptimizerSGD {
     }
 
     fn new() -> Self {
-        OptimizerSGD { learning_rate: 0.0 }
+        OptimizerSGD {
+            learning_rate: 0.0,
+            base_learning_rate: 0.0,
+        }
     }
 
     fn init(&mut self, learning_rate: f32, _power_t: f32, _initial_acc_gradient: f32) {
         self.learning_rate = learning_rate;
+        self.base_learning_rate = learning_rate;
+    }
+
+    fn set_learning_rate_multiplier(&mut self, multiplier: f32) {
+        self.learning_rate = self.base_learning_rate * multiplier;
     }
 
     #[inline(always)]
@@ -41,6 +59,68 @@ impl OptimizerTrait for OptimizerSGD {
     }
 }
 
+/******************* SGD with momentum **************************/
+// Fixed learning rate SGD that keeps a velocity of each weight, classic (Polyak) or Nesterov.
+// Velocity is in the units of updates: v = momentum * v + learning_rate * gradient
+pub const DEFAULT_MOMENTUM: f32 = 0.9;
+
+#[derive(Clone)]
+pub struct OptimizerSGDMomentum<const NESTEROV: bool> {
+    learning_rate: f32,
+    base_learning_rate: f32,
+    momentum: f32,
+}
+
+impl<const NESTEROV: bool> OptimizerTrait for OptimizerSGDMomentum<NESTEROV> {
+    type PerWeightStore = f32;
+
+    fn get_name() -> &'static str {
+        if NESTEROV {
+            "SGDNesterov"
+        } else {
+            "SGDMomentum"
+        }
+    }
+
+    fn new() -> Self {
+        OptimizerSGDMomentum {
+            learning_rate: 0.0,
+            base_learning_rate: 0.0,
+            momentum: DEFAULT_MOMENTUM,
+        }
+    }
+
+    fn init(&mut self, learning_rate: f32, _power_t: f32, _initial_acc_gradient: f32) {
+        self.learning_rate = learning_rate;
+        self.base_learning_rate = learning_rate;
+    }
+
+    fn set_learning_rate_multiplier(&mut self, multiplier: f32) {
+        self.learning_rate = self.base_learning_rate * multiplier;
+    }
+
+    fn init_momentum(&mut self, momentum: f32) {
+        self.momentum = momentum;
+    }
+
+    #[inline(always)]
+    unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32 {
+        let step = self.learning_rate * gradient;
+        let velocity = self.momentum * *data + step;
+        *data = velocity;
+        if NESTEROV {
+            // Looks ahead along the new velocity
+            self.momentum * velocity + step
+        } else {
+            velocity
+        }
+    }
+
+    fn initial_data(&self) -> Self::PerWeightStore {
+        0.0
+    }
+}
+
 /******************* Adagrad with flexible power_t  **************************/
 /* Regular Adagrad always uses sqrt (power_t = 0.5)                          */
 /* For power_t = 0.5, this is slower than simply using sqrt                  */
@@ -49,6 +129,7 @@ impl OptimizerTrait for OptimizerSGD {
 #[derive(Clone)]
 pub struct OptimizerAdagradFlex {
     learning_rate: f32,
+    base_learning_rate: f32,
     minus_power_t: f32,
     initial_acc_gradient: f32,
 }
@@ -62,6 +143,7 @@ impl OptimizerTrait for OptimizerAdagradFlex {
     fn new() -> Self {
         OptimizerAdagradFlex {
             learning_rate: 0.0,
+            base_learning_rate: 0.0,
             minus_power_t: 0.0,
             initial_acc_gradient: 0.0,
         }
@@ -69,10 +151,15 @@ impl OptimizerTrait for OptimizerAdagradFlex {
 
     fn init(&mut self, learning_rate: f32, power_t: f32, initial_acc_gradient: f32) {
         self.learning_rate = learning_rate;
+        self.base_learning_rate = learning_rate;
         self.minus_power_t = -power_t;
         self.initial_acc_gradient = initial_acc_gradient;
     }
 
+    fn set_learning_rate_multiplier(&mut self, multiplier: f32) {
+        self.learning_rate = self.base_learning_rate * multiplier;
+    }
+
     #[inline(always)]
     unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32 {
         let accumulated_gradient_squared = *data;
@@ -104,6 +191,8 @@ pub const FASTMATH_LR_LUT_SIZE: usize = 1 << FASTMATH_LR_LUT_BITS;
 #[derive(Clone, Copy)]
 pub struct OptimizerAdagradLUT {
     pub fastmath_lr_lut: [f32; FASTMATH_LR_LUT_SIZE],
+    // The learning rate is baked into the table
+    lr_multiplier: f32,
 }
 
 impl OptimizerTrait for OptimizerAdagradLUT {
@@ -115,9 +204,14 @@ impl OptimizerTrait for OptimizerAdagradLUT {
     fn new() -> Self {
         OptimizerAdagradLUT {
             fastmath_lr_lut: [0.0; FASTMATH_LR_LUT_SIZE],
+            lr_multiplier: 1.0,
         }
     }
 
+    fn set_learning_rate_multiplier(&mut self, multiplier: f32) {
+        self.lr_multiplier = multiplier;
+    }
+
     fn init(&mut self, learning_rate: f32, power_t: f32, initial_acc_gradient: f32) {
         log::info!("Calculating look-up tables for Adagrad learning rate calculation");
         let minus_power_t = -power_t;
@@ -151,7 +245,8 @@ impl OptimizerTrait for OptimizerAdagradLUT {
         let new_accumulated_gradient_squared = accumulated_gradient_squared + gradient_squared;
         *data = new_accumulated_gradient_squared;
         let key = new_accumulated_gradient_squared.to_bits() >> (31 - FASTMATH_LR_LUT_BITS);
-        let update = gradient * *self.fastmath_lr_lut.get_unchecked(key as usize);
+        let update =
+            gradient * *self.fastmath_lr_lut.get_unchecked(key as usize) * self.lr_multiplier;
         update
     }
 
@@ -161,6 +256,308 @@ impl OptimizerTrait for OptimizerAdagradLUT {
     }
 }
 
+/******************* COCOB-Backprop **************************/
+// Coin betting optimizer (Orabona & Tommasi, 2017) that needs no learning rate: each weight bets
+// a fraction of the reward it won so far in the direction of its summed negative gradients.
+// The weight is kept as an offset from its initial value in the per weight store, and the update
+// is the change of the offset, so blocks keep subtracting updates from their weights.
+pub const COCOB_ALPHA: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct COCOBData {
+    pub max_gradient: f32,
+    pub sum_abs_gradient: f32,
+    pub sum_gradient: f32,
+    pub reward: f32,
+    pub offset: f32,
+}
+
+#[derive(Clone)]
+pub struct OptimizerCOCOB {
+    alpha: f32,
+}
+
+impl OptimizerTrait for OptimizerCOCOB {
+    fn get_name() -> &'static str {
+        "COCOB"
+    }
+    type PerWeightStore = COCOBData;
+
+    fn new() -> Self {
+        OptimizerCOCOB { alpha: COCOB_ALPHA }
+    }
+
+    // There is no learning rate to set
+    fn init(&mut self, _learning_rate: f32, _power_t: f32, _initial_acc_gradient: f32) {}
+
+    #[inline(always)]
+    unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32 {
+        let abs_gradient = gradient.abs();
+        data.max_gradient = data.max_gradient.max(abs_gradient);
+        data.sum_abs_gradient += abs_gradient;
+        data.sum_gradient += gradient;
+        data.reward = (data.reward - gradient * data.offset).max(0.0);
+        let max_gradient = data.max_gradient;
+        if max_gradient == 0.0 {
+            return 0.0;
+        }
+        let offset = -data.sum_gradient
+            / (max_gradient
+                * (data.sum_abs_gradient + max_gradient).max(self.alpha * max_gradient))
+            * (max_gradient + data.reward);
+        if offset.is_nan() || offset.is_infinite() {
+            return 0.0;
+        }
+        let update = data.offset - offset;
+        data.offset = offset;
+        update
+    }
+
+    fn initial_data(&self) -> Self::PerWeightStore {
+        COCOBData::default()
+    }
+}
+
+/******************* FTRL-Proximal **************************/
+// Follow the regularized leader (McMahan et al., 2013) with per weight learning rates. Weights
+// whose summed gradients stay within l1 are exactly zero, so the LR block gets sparse.
+// Like with COCOB the weight is kept in the per weight store and the update is its change, so
+// weights have to start at zero, which they do in the LR block.
+pub const FTRL_BETA: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FTRLData {
+    pub z: f32,
+    pub n: f32, // sum of squared gradients
+    pub weight: f32,
+}
+
+#[derive(Clone)]
+pub struct OptimizerFTRL {
+    alpha: f32,
+    base_alpha: f32,
+    beta: f32,
+    l1: f32,
+    l2: f32,
+}
+
+impl OptimizerTrait for OptimizerFTRL {
+    fn get_name() -> &'static str {
+        "FTRL"
+    }
+    type PerWeightStore = FTRLData;
+
+    fn new() -> Self {
+        OptimizerFTRL {
+            alpha: 0.0,
+            base_alpha: 0.0,
+            beta: FTRL_BETA,
+            l1: 0.0,
+            l2: 0.0,
+        }
+    }
+
+    fn init(&mut self, learning_rate: f32, _power_t: f32, _initial_acc_gradient: f32) {
+        self.alpha = learning_rate;
+        self.base_alpha = learning_rate;
+    }
+
+    fn set_learning_rate_multiplier(&mut self, multiplier: f32) {
+        self.alpha = self.base_alpha * multiplier;
+    }
+
+    fn init_regularization(&mut self, l1: f32, l2: f32) {
+        self.l1 = l1;
+        self.l2 = l2;
+    }
+
+    #[inline(always)]
+    unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32 {
+        let n = data.n + gradient * gradient;
+        let sigma = (n.sqrt() - data.n.sqrt()) / self.alpha;
+        data.z += gradient - sigma * data.weight;
+        data.n = n;
+        let weight = if data.z.abs() <= self.l1 {
+            0.0
+        } else {
+            -(data.z - data.z.signum() * self.l1) / ((self.beta + n.sqrt()) / self.alpha + self.l2)
+        };
+        if weight.is_nan() || weight.is_infinite() {
+            return 0.0;
+        }
+        let update = data.weight - weight;
+        data.weight = weight;
+        update
+    }
+
+    fn initial_data(&self) -> Self::PerWeightStore {
+        FTRLData::default()
+    }
+}
+
+/******************* Adam **************************/
+// Adam (Kingma & Ba, 2014) with bias-corrected first and second moment estimates. Updates are
+// sparse, so each weight keeps its own powers of the betas for the bias correction, like lazy Adam
+// does. power_t and the initial accumulated gradient are not used.
+pub const ADAM_BETA1: f32 = 0.9;
+pub const ADAM_BETA2: f32 = 0.999;
+pub const ADAM_EPSILON: f32 = 1e-8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdamData {
+    pub m: f32,
+    pub v: f32,
+    pub beta1_power: f32, // beta1^t after t updates of the weight
+    pub beta2_power: f32,
+}
+
+#[derive(Clone)]
+pub struct OptimizerAdam {
+    learning_rate: f32,
+    base_learning_rate: f32,
+    beta1: f32,
+    beta2: f32,
+    epsilon: f32,
+}
+
+impl OptimizerTrait for OptimizerAdam {
+    fn get_name() -> &'static str {
+        "Adam"
+    }
+    type PerWeightStore = AdamData;
+
+    fn new() -> Self {
+        OptimizerAdam {
+            learning_rate: 0.0,
+            base_learning_rate: 0.0,
+            beta1: ADAM_BETA1,
+            beta2: ADAM_BETA2,
+            epsilon: ADAM_EPSILON,
+        }
+    }
+
+    fn init(&mut self, learning_rate: f32, _power_t: f32, _initial_acc_gradient: f32) {
+        self.learning_rate = learning_rate;
+        self.base_learning_rate = learning_rate;
+    }
+
+    fn set_learning_rate_multiplier(&mut self, multiplier: f32) {
+        self.learning_rate = self.base_learning_rate * multiplier;
+    }
+
+    #[inline(always)]
+    unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32 {
+        data.m = self.beta1 * data.m + (1.0 - self.beta1) * gradient;
+        data.v = self.beta2 * data.v + (1.0 - self.beta2) * gradient * gradient;
+        data.beta1_power *= self.beta1;
+        data.beta2_power *= self.beta2;
+        let m_hat = data.m / (1.0 - data.beta1_power);
+        let v_hat = data.v / (1.0 - data.beta2_power);
+        let update = self.learning_rate * m_hat / (v_hat.sqrt() + self.epsilon);
+        if update.is_nan() || update.is_infinite() {
+            return 0.0;
+        }
+        update
+    }
+
+    fn initial_data(&self) -> Self::PerWeightStore {
+        AdamData {
+            m: 0.0,
+            v: 0.0,
+            beta1_power: 1.0,
+            beta2_power: 1.0,
+        }
+    }
+}
+
+/******************* Importance aware updates **************************/
+/// Update per unit of gradient that the optimizer would make for the gradient, without changing its data.
+/// Used by --invariant to find how far an update moves the prediction.
+///
+/// # Safety
+///
+/// Same as for OptimizerTrait::calculate_update: the optimizer has to be initialized with init(),
+/// and data has to be a per weight store the optimizer keeps itself. The Adagrad lookup table is
+/// indexed by the accumulated gradient without bounds checks, so that has to stay non-negative.
+#[inline(always)]
+pub unsafe fn step_size<L: OptimizerTrait>(
+    optimizer: &L,
+    gradient: f32,
+    data: &L::PerWeightStore,
+) -> f32 {
+    if gradient == 0.0 {
+        return 0.0;
+    }
+    let mut data = data.clone();
+    optimizer.calculate_update(gradient, &mut data) / gradient
+}
+
+/******************* Gradient clipping **************************/
+// Factor that brings a gradient with the given squared L2 norm within clip (--grad_clip).
+// Each block clips the gradient of its own weights for the example, before the optimizer sees it.
+#[inline(always)]
+pub fn clip_factor(clip: f32, gradient_norm_squared: f32) -> f32 {
+    let norm = gradient_norm_squared.sqrt();
+    if norm > clip {
+        clip / norm
+    } else {
+        1.0
+    }
+}
+
+/******************* Differential privacy **************************/
+// DP-SGD style privatization of updates (--dp_clip, --dp_noise). Gradient of each example over all
+// the weights of a block is clipped to L2 norm of clip, then gaussian noise with standard deviation
+// noise_multiplier * clip is added to the gradient of each weight the example updates.
+// Noise is added only to the touched weights, which reveals which weights those were, so this is
+// a coarse approximation of DP-SGD and not a formal guarantee.
+#[derive(Clone)]
+pub struct DPGradient {
+    clip: f32,
+    normal: Normal<f32>,
+    rng: Xoshiro256PlusPlus,
+}
+
+impl DPGradient {
+    pub fn new(clip: f32, noise_multiplier: f32) -> DPGradient {
+        DPGradient {
+            clip,
+            normal: Normal::new(0.0, noise_multiplier * clip).unwrap(),
+            rng: Xoshiro256PlusPlus::seed_from_u64(rand::random()),
+        }
+    }
+
+    // Factor that brings a gradient with the given squared L2 norm within the clipping norm
+    #[inline(always)]
+    pub fn clip_factor(&self, gradient_norm_squared: f32) -> f32 {
+        clip_factor(self.clip, gradient_norm_squared)
+    }
+
+    #[inline(always)]
+    pub fn privatize(&mut self, gradient: f32, clip_factor: f32) -> f32 {
+        gradient * clip_factor + self.normal.sample(&mut self.rng)
+    }
+}
+
+// Epsilon spent after the given number of rounds (passes over the data) with privatized updates
+// of num_blocks blocks. Each example contributes to a single update of each block per round, so
+// every round is a gaussian mechanism per block. These compose in Renyi DP of order alpha to
+// alpha * rounds * num_blocks / (2 * noise_multiplier^2), which we convert to (epsilon, delta).
+pub fn dp_epsilon(noise_multiplier: f32, rounds: u32, num_blocks: u32, delta: f64) -> f64 {
+    if noise_multiplier <= 0.0 {
+        return f64::INFINITY;
+    }
+    let mechanisms = (rounds * num_blocks) as f64;
+    let sigma_squared = (noise_multiplier as f64).powi(2);
+    let mut epsilon = f64::INFINITY;
+    for i in 1..10000 {
+        let alpha = 1.0 + i as f64 / 10.0;
+        let rdp = alpha * mechanisms / (2.0 * sigma_squared);
+        epsilon = epsilon.min(rdp + (1.0 / delta).ln() / (alpha - 1.0));
+    }
+    epsilon
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -174,6 +571,35 @@ mod tests {
             let mut acc: PhantomData<()> = std::marker::PhantomData {};
             let p = l.calculate_update(0.1, &mut acc);
             assert_eq!(p, 0.1 * 0.15);
+            l.set_learning_rate_multiplier(0.5);
+            let p = l.calculate_update(0.1, &mut acc);
+            assert_eq!(p, 0.1 * 0.15 * 0.5);
+        }
+    }
+
+    #[test]
+    fn test_sgd_momentum() {
+        let mut l = OptimizerSGDMomentum::<false>::new();
+        l.init(0.1, 0.4, 0.0);
+        l.init_momentum(0.5);
+        unsafe {
+            let mut velocity = l.initial_data();
+            assert_eq!(l.calculate_update(1.0, &mut velocity), 0.1);
+            assert_eq!(l.calculate_update(1.0, &mut velocity), 0.15);
+            assert_eq!(velocity, 0.15);
+            // Keeps going for a while after gradients stop
+            assert_eq!(l.calculate_update(0.0, &mut velocity), 0.075);
+        }
+
+        let mut l = OptimizerSGDMomentum::<true>::new();
+        l.init(0.1, 0.4, 0.0);
+        l.init_momentum(0.5);
+        unsafe {
+            let mut velocity = l.initial_data();
+            assert_eq!(l.calculate_update(1.0, &mut velocity), 0.15);
+            assert_eq!(velocity, 0.1);
+            assert!((l.calculate_update(1.0, &mut velocity) - 0.175).abs() < 1e-7);
+            assert_eq!(velocity, 0.15);
         }
     }
 
@@ -217,6 +643,12 @@ mod tests {
             assert_eq!(p, 0.09375872);
             assert_eq!(acc, 0.1 * 0.1);
 
+            l.set_learning_rate_multiplier(0.5);
+            acc = 0.0;
+            let p = l.calculate_update(0.1, &mut acc);
+            assert_eq!(p, 0.09375872 * 0.5);
+            l.set_learning_rate_multiplier(1.0);
+
             acc = 0.0;
             let p = l.calculate_update(0.0, &mut acc);
             // Here we check that we don't get Inf back
@@ -225,6 +657,102 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cocob() {
+        let mut l = OptimizerCOCOB::new();
+        l.init(0.15, 0.4, 0.0);
+        unsafe {
+            let mut data = l.initial_data();
+            assert_eq!(l.calculate_update(0.0, &mut data), 0.0);
+
+            // First step: offset = 1 / (1 * max(2, 100)) * 1
+            let p = l.calculate_update(-1.0, &mut data);
+            assert_eq!(p, -0.01);
+            assert_eq!(data.offset, 0.01);
+
+            // Gradients that keep pointing the same way make the bets grow
+            let mut weight = -p;
+            let mut last_update = p;
+            for _ in 0..20 {
+                let p = l.calculate_update(-1.0, &mut data);
+                assert!(p <= last_update);
+                last_update = p;
+                weight -= p;
+            }
+            assert!((weight - data.offset).abs() < 1e-4);
+            assert!(weight > 1.0);
+
+            // Gradient changing sign takes the weight back
+            let p = l.calculate_update(1.0, &mut data);
+            assert!(p > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_ftrl() {
+        let mut l = OptimizerFTRL::new();
+        l.init(0.5, 0.4, 0.0);
+        l.init_regularization(1.0, 0.0);
+        unsafe {
+            let mut data = l.initial_data();
+            // Summed gradient within l1 keeps the weight at zero
+            assert_eq!(l.calculate_update(0.6, &mut data), 0.0);
+            assert_eq!(data.weight, 0.0);
+            assert_eq!(l.calculate_update(0.3, &mut data), 0.0);
+            assert!((data.z - 0.9).abs() < 1e-6);
+
+            // z = 1.5, n = 0.81, weight = -(1.5 - 1.0) / ((1 + sqrt(0.81)) / 0.5)
+            let p = l.calculate_update(0.6, &mut data);
+            let expected_weight = -0.5 / ((1.0 + 0.9) / 0.5);
+            assert!((data.weight - expected_weight).abs() < 1e-6);
+            assert_eq!(p, -data.weight);
+
+            // Gradients the other way take it back to exactly zero
+            let weight = data.weight;
+            let p = l.calculate_update(-0.6, &mut data);
+            assert_eq!(p, weight);
+            assert_eq!(data.weight, 0.0);
+        }
+
+        // Without regularization the first step is a plain gradient step
+        let mut l = OptimizerFTRL::new();
+        l.init(0.5, 0.4, 0.0);
+        unsafe {
+            let mut data = l.initial_data();
+            let p = l.calculate_update(0.5, &mut data);
+            assert!((p - 0.5 * 0.5 / 1.5).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_adam() {
+        let mut l = OptimizerAdam::new();
+        l.init(0.15, 0.4, 0.0);
+        unsafe {
+            let mut data = l.initial_data();
+            assert_eq!(l.calculate_update(0.0, &mut data), 0.0);
+
+            // Bias correction makes the first step the learning rate in the direction of the gradient
+            let mut data = l.initial_data();
+            let p = l.calculate_update(0.1, &mut data);
+            assert!((p - 0.15).abs() < 1e-5);
+            assert!((data.m - 0.01).abs() < 1e-7);
+            assert!((data.v - 0.00001).abs() < 1e-9);
+
+            // Steps are invariant to the scale of gradients
+            let mut data_scaled = l.initial_data();
+            let p_scaled = l.calculate_update(100.0, &mut data_scaled);
+            assert!((p_scaled - 0.15).abs() < 1e-5);
+            let p = l.calculate_update(0.1, &mut data);
+            let p_scaled = l.calculate_update(100.0, &mut data_scaled);
+            assert!((p - p_scaled).abs() < 1e-5);
+
+            // Gradient changing sign first slows the weight down
+            let p = l.calculate_update(-0.1, &mut data);
+            assert!(p > 0.0 && p < 0.15);
+        }
+    }
+
     #[test]
     fn test_adagradlut_comparison() {
         // Here we test that our implementation of LUT has small enough relative error
@@ -267,4 +795,39 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_clip_factor() {
+        assert_eq!(clip_factor(1.0, 0.25), 1.0);
+        assert_eq!(clip_factor(1.0, 1.0), 1.0);
+        assert_eq!(clip_factor(1.0, 16.0), 0.25);
+        assert_eq!(clip_factor(2.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_dp_gradient() {
+        let mut dp = DPGradient::new(1.0, 0.0);
+        assert_eq!(dp.clip_factor(0.25), 1.0);
+        assert_eq!(dp.clip_factor(4.0), 0.5);
+        assert_eq!(dp.privatize(3.0, 0.5), 1.5);
+
+        let mut dp = DPGradient::new(2.0, 0.5);
+        let n = 10000;
+        let noise: Vec<f32> = (0..n).map(|_| dp.privatize(0.0, 1.0)).collect();
+        let mean = noise.iter().sum::<f32>() / n as f32;
+        let variance = noise.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / n as f32;
+        assert!(mean.abs() < 0.05);
+        assert!((variance.sqrt() - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_dp_epsilon() {
+        assert_eq!(dp_epsilon(0.0, 1, 1, 1e-6), f64::INFINITY);
+        let epsilon = dp_epsilon(1.0, 1, 1, 1e-6);
+        // Optimal alpha of 1 + sqrt(2 * ln(1e6)) gives about 5.75
+        assert!((epsilon - 5.75).abs() < 0.01);
+        assert!(dp_epsilon(2.0, 1, 1, 1e-6) < epsilon);
+        assert!(dp_epsilon(1.0, 2, 1, 1e-6) > epsilon);
+        assert_eq!(dp_epsilon(1.0, 2, 1, 1e-6), dp_epsilon(1.0, 1, 2, 1e-6));
+    }
 }
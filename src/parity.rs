@@ -0,0 +1,1025 @@
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::feature_buffer::{
+    FeatureBuffer, HashAndValue, HashAndValueAndSeq, CONSTANT_HASH, VOWPAL_FNV_PRIME,
+};
+use crate::feature_transform_executor::default_seeds;
+use crate::feature_transform_parser::NamespaceTransform;
+use crate::model_instance::{DupPolicy, ModelInstance};
+use crate::murmur3;
+use crate::parser;
+use crate::value_ranges::is_power_of_ten;
+use crate::vwmap::{
+    F32Scaling, NamespaceDescriptor, NamespaceFormat, NamespaceType, VwNamespaceMap,
+};
+
+// Train/serve parity check of the daemon (--parity_check). A sampled fraction of requests is
+// translated again by ReferenceTranslator, a slow and straightforward implementation of the
+// parser, of the record rewrites (--dup_policy, --namespace_topk, --tenant_namespace), of the
+// stateless transforms and of the feature buffer translation, and the two feature buffers have
+// to be equal. Stateful transforms (RollingCount) have no reference, their counts depend on all
+// the requests served before, so models that use them are refused.
+
+#[derive(Clone)]
+struct ReferenceNamespace {
+    vwname: String,
+    descriptor: NamespaceDescriptor,
+    passthrough_base: u32,
+    vector_len: u32,
+    hierarchy_weights: Option<Vec<f32>>,
+    f32_scaling: Option<F32Scaling>,
+}
+
+// Features as (hash, value), by namespace index. Values of f32 namespaces are the floats.
+type NamespaceFeatures = Vec<Vec<(u32, f32)>>;
+
+#[derive(Clone)]
+enum ReferenceFunction {
+    Binner {
+        log: bool, // sqrt otherwise
+        greater_than: f32,
+        resolution: f32,
+        interpolated: bool,
+    },
+    LogRatioBinner {
+        greater_than: f32,
+        resolution: f32,
+        interpolated: bool,
+    },
+    Weight(f32),
+    Combine,
+}
+
+// A transformed namespace, features come from the from namespaces and are hashed with the seeds
+#[derive(Clone)]
+struct ReferenceTransform {
+    from: Vec<NamespaceDescriptor>,
+    function: ReferenceFunction,
+    seeds: [u32; 5],
+}
+
+#[derive(Clone)]
+pub struct ReferenceTranslator {
+    mi: ModelInstance,
+    namespaces: Vec<ReferenceNamespace>, // by namespace index
+    transforms: Vec<ReferenceTransform>, // by namespace index of transformed namespaces
+    dedup_namespaces: Vec<usize>,
+    tenant_namespaces: Vec<usize>,
+    lr_hash_mask: u32,
+    ffm_hash_mask: u32,
+    ffm_dimension_bits: u32,
+    skip_prefix: usize,
+}
+
+fn reference_error(line: &str, what: &str) -> Box<dyn Error> {
+    Box::new(IOError::new(
+        ErrorKind::Other,
+        format!("Reference translation of \"{}\": {}", line, what),
+    ))
+}
+
+fn parse_float(s: &str) -> Option<f32> {
+    match s {
+        "NONE" => Some(f32::NAN),
+        _ => s.parse().ok(),
+    }
+}
+
+// Splits "name:weight" into name and weight, weight is 1.0 when not given
+fn split_weight<'a>(token: &'a str, line: &str) -> Result<(&'a str, f32), Box<dyn Error>> {
+    match token.split_once(':') {
+        Some((name, weight)) => match parse_float(weight) {
+            Some(weight) => Ok((name, weight)),
+            None => Err(reference_error(line, &format!("bad weight of {}", token))),
+        },
+        None => Ok((token, 1.0)),
+    }
+}
+
+fn transform_hash(seed: u32, data: i32) -> u32 {
+    murmur3::hash32_with_seed(data.to_le_bytes(), seed) & parser::MASK31
+}
+
+// A float goes to the integer below it, interpolated to the integers below and above it, the
+// closer one getting more of the value. Infinities and NaN go by their bits.
+fn push_float(out: &mut Vec<(u32, f32)>, seed: u32, f: f32, value: f32, interpolated: bool) {
+    if !f.is_finite() {
+        out.push((transform_hash(seed, f.to_bits() as i32), value));
+    } else if interpolated {
+        let below = f.floor();
+        let above_part = f - below;
+        if above_part != 0.0 {
+            out.push((transform_hash(seed, below as i32 + 1), value * above_part));
+        }
+        if 1.0 - above_part != 0.0 {
+            out.push((
+                transform_hash(seed, below as i32),
+                value * (1.0 - above_part),
+            ));
+        }
+    } else {
+        out.push((transform_hash(seed, f as i32), value));
+    }
+}
+
+fn new_reference_transform(
+    transform: &NamespaceTransform,
+) -> Result<ReferenceTransform, Box<dyn Error>> {
+    let params = &transform.function_parameters;
+    let greater_than = params.first().copied().unwrap_or(0.0);
+    let resolution = params.get(1).copied().unwrap_or(1.0);
+    let binner = |log, interpolated| ReferenceFunction::Binner {
+        log,
+        greater_than,
+        resolution,
+        interpolated,
+    };
+    let log_ratio_binner = |interpolated| ReferenceFunction::LogRatioBinner {
+        greater_than,
+        resolution,
+        interpolated,
+    };
+    let function = match transform.function_name.as_str() {
+        "BinnerSqrtPlain" => binner(false, false),
+        "BinnerSqrt" => binner(false, true),
+        "BinnerLogPlain" => binner(true, false),
+        "BinnerLog" => binner(true, true),
+        "BinnerLogRatioPlain" => log_ratio_binner(false),
+        "BinnerLogRatio" => log_ratio_binner(true),
+        "Weight" => ReferenceFunction::Weight(params.first().copied().unwrap_or(1.0)),
+        "Combine" => ReferenceFunction::Combine,
+        "RollingCount" | "RollingPositiveCount" => {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--parity_check has no reference implementation of {}, its counts depend on the requests served before",
+                    transform.function_name
+                ),
+            )))
+        }
+        function_name => {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--parity_check has no reference implementation of transform {}",
+                    function_name
+                ),
+            )))
+        }
+    };
+    Ok(ReferenceTransform {
+        from: transform
+            .from_namespaces
+            .iter()
+            .map(|n| n.namespace_descriptor)
+            .collect(),
+        function,
+        seeds: default_seeds(transform.to_namespace.namespace_descriptor.namespace_index as u32),
+    })
+}
+
+impl ReferenceTranslator {
+    pub fn new(
+        mi: &ModelInstance,
+        vw: &VwNamespaceMap,
+    ) -> Result<ReferenceTranslator, Box<dyn Error>> {
+        let transforms = mi
+            .transform_namespaces
+            .v
+            .iter()
+            .map(new_reference_transform)
+            .collect::<Result<Vec<ReferenceTransform>, Box<dyn Error>>>()?;
+
+        // Duplicates are merged and tenants mixed in primitive namespaces that the model reads directly
+        let used_namespace_descriptors: Vec<&NamespaceDescriptor> = mi
+            .feature_combo_descs
+            .iter()
+            .flat_map(|combo| combo.namespace_descriptors.iter())
+            .chain(mi.ffm_fields.iter().flatten())
+            .filter(|d| d.namespace_type == NamespaceType::Primitive)
+            .collect();
+        let mut dedup_namespaces: Vec<usize> = Vec::new();
+        let mut tenant_namespaces: Vec<usize> = Vec::new();
+        let tenant_index = mi.tenant_namespace.map(|t| t.namespace_index as usize);
+        for d in used_namespace_descriptors {
+            let index = d.namespace_index as usize;
+            if d.namespace_format != NamespaceFormat::F32 && !dedup_namespaces.contains(&index) {
+                dedup_namespaces.push(index);
+            }
+            if tenant_index.is_some()
+                && tenant_index != Some(index)
+                && d.namespace_format != NamespaceFormat::Passthrough
+                && d.namespace_format != NamespaceFormat::F32Vec
+                && !tenant_namespaces.contains(&index)
+            {
+                tenant_namespaces.push(index);
+            }
+        }
+
+        let mut namespaces: Vec<ReferenceNamespace> = Vec::new();
+        for entry in vw.vw_source.entries.iter() {
+            namespaces.push(ReferenceNamespace {
+                vwname: entry.namespace_vwname.clone(),
+                descriptor: vw.map_vwname_to_namespace_descriptor
+                    [entry.namespace_vwname.as_bytes()],
+                passthrough_base: entry.namespace_passthrough_base,
+                vector_len: entry.namespace_vector_len,
+                hierarchy_weights: entry.namespace_hierarchy_weights.clone(),
+                f32_scaling: entry.namespace_f32_scaling,
+            });
+        }
+        namespaces.sort_by_key(|n| n.descriptor.namespace_index);
+
+        // FFM hashes leave the lowest bits for the k dimensions
+        let mut ffm_dimension_bits = 0;
+        while (1 << ffm_dimension_bits) < mi.ffm_k.max(mi.fm_k) {
+            ffm_dimension_bits += 1;
+        }
+        Ok(ReferenceTranslator {
+            mi: mi.clone(),
+            namespaces,
+            transforms,
+            dedup_namespaces,
+            tenant_namespaces,
+            lr_hash_mask: (1 << mi.bit_precision) - 1,
+            ffm_hash_mask: ((1 << mi.ffm_bit_precision) - 1) & !((1 << ffm_dimension_bits) - 1),
+            ffm_dimension_bits,
+            skip_prefix: vw.vw_source.namespace_skip_prefix as usize,
+        })
+    }
+
+    // Label, importance and features of each namespace of the example line
+    fn parse(&self, line: &str) -> Result<(f32, f32, NamespaceFeatures), Box<dyn Error>> {
+        let mut features: NamespaceFeatures = vec![Vec::new(); self.namespaces.len()];
+        // Label, importance and tag come before the first namespace
+        let (header, body) = line.split_at(line.find('|').unwrap_or(line.len()));
+        let mut header_tokens = header.split(' ').filter(|t| !t.is_empty());
+
+        let mut label = parser::NO_LABEL as f32;
+        let mut importance = 1.0;
+        if !header.is_empty() {
+            label = match header_tokens.next() {
+                Some(token) if token.starts_with('1') => 1.0,
+                Some(token) if token.starts_with('-') => 0.0,
+                _ => return Err(reference_error(line, "no label")),
+            };
+            if let Some(token) = header_tokens.next() {
+                if !token.starts_with('\'') {
+                    importance = match parse_float(token) {
+                        Some(importance) if importance >= 0.0 => importance,
+                        _ => return Err(reference_error(line, "bad example importance")),
+                    };
+                }
+            }
+        }
+
+        let mut namespace: Option<(&ReferenceNamespace, f32)> = None;
+        for token in body.split(' ').filter(|t| !t.is_empty()) {
+            if let Some(namespace_token) = token.strip_prefix('|') {
+                let (vwname, weight) = split_weight(namespace_token, line)?;
+                let n = match self.namespaces.iter().find(|n| n.vwname == vwname) {
+                    Some(n) => n,
+                    None => {
+                        return Err(reference_error(
+                            line,
+                            &format!("unknown namespace {}", vwname),
+                        ))
+                    }
+                };
+                // A namespace given again replaces the earlier features
+                features[n.descriptor.namespace_index as usize].truncate(0);
+                namespace = Some((n, weight));
+                continue;
+            }
+            let (n, namespace_weight) = namespace.unwrap();
+            let namespace_features = &mut features[n.descriptor.namespace_index as usize];
+            if n.descriptor.namespace_format == NamespaceFormat::F32Vec {
+                let values: Vec<Option<f32>> = token.split(',').map(parse_float).collect();
+                if !namespace_features.is_empty()
+                    || namespace_weight != 1.0
+                    || values.len() != n.vector_len as usize
+                    || values.iter().any(|v| v.is_none())
+                {
+                    return Err(reference_error(line, &format!("bad vector {}", token)));
+                }
+                for (index, value) in values.into_iter().enumerate() {
+                    namespace_features.push((index as u32, value.unwrap()));
+                }
+                continue;
+            }
+
+            let (name, feature_weight) = split_weight(token, line)?;
+            let weight = namespace_weight * feature_weight;
+            let hash = match n.descriptor.namespace_format {
+                NamespaceFormat::Passthrough => match name.parse::<u32>() {
+                    Ok(v) if v <= parser::MASK31 - n.passthrough_base => n.passthrough_base + v,
+                    _ => {
+                        return Err(reference_error(
+                            line,
+                            &format!("bad passthrough feature {}", token),
+                        ))
+                    }
+                },
+                _ => parser::feature_hash(&n.vwname, name.as_bytes()),
+            };
+            // Values of f32 namespaces are for transforms, the model sees their features with value 1.0
+            let value = if n.descriptor.namespace_format == NamespaceFormat::F32 {
+                let float_value = match name.get(self.skip_prefix..).unwrap_or("") {
+                    "" => Some(f32::NAN),
+                    float_value => parse_float(float_value),
+                };
+                match float_value {
+                    Some(float_value) if weight == 1.0 => match n.f32_scaling {
+                        Some(scaling) => scaling.apply(float_value),
+                        None => float_value,
+                    },
+                    _ => {
+                        return Err(reference_error(
+                            line,
+                            &format!("bad float feature {}", token),
+                        ))
+                    }
+                }
+            } else {
+                weight
+            };
+            namespace_features.push((hash, value));
+            // Ancestors of "a/b/c" are "a" and "a/b"
+            if let Some(hierarchy_weights) = &n.hierarchy_weights {
+                let slashes = name.match_indices('/').map(|(i, _)| i).filter(|i| *i > 0);
+                for (level, i) in slashes.enumerate() {
+                    let level_weight = hierarchy_weights
+                        .get(level)
+                        .or_else(|| hierarchy_weights.last())
+                        .unwrap_or(&1.0);
+                    namespace_features.push((
+                        parser::feature_hash(&n.vwname, &name.as_bytes()[..i]),
+                        weight * level_weight,
+                    ));
+                }
+            }
+        }
+        Ok((label, importance, features))
+    }
+
+    // Merges duplicates, truncates to top-k and mixes in the tenant, in this order
+    fn rewrite(&self, features: &mut NamespaceFeatures) {
+        if let Some(dup_policy) = self.mi.dup_policy {
+            for index in self.dedup_namespaces.iter() {
+                let mut merged: Vec<(u32, f32)> = Vec::new();
+                for (hash, value) in features[*index].iter() {
+                    match merged.iter_mut().find(|(h, _)| h == hash) {
+                        Some((_, kept)) => match dup_policy {
+                            DupPolicy::Sum => *kept += value,
+                            DupPolicy::Max => *kept = kept.max(*value),
+                            DupPolicy::First => {}
+                        },
+                        None => merged.push((*hash, *value)),
+                    }
+                }
+                features[*index] = merged;
+            }
+        }
+        for topk in self.mi.namespace_topks.iter() {
+            let namespace_features =
+                &mut features[topk.namespace_descriptor.namespace_index as usize];
+            if namespace_features.len() > topk.k as usize {
+                // Among equal weights the first given features win
+                namespace_features
+                    .sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+                namespace_features.truncate(topk.k as usize);
+            }
+        }
+        if let Some(tenant_namespace) = self.mi.tenant_namespace {
+            // Examples without a tenant use the shared weights
+            let tenant_hash = features[tenant_namespace.namespace_index as usize]
+                .first()
+                .map(|(hash, _)| *hash);
+            if let Some(tenant_hash) = tenant_hash {
+                let tenant_mix = tenant_hash.wrapping_mul(VOWPAL_FNV_PRIME);
+                for index in self.tenant_namespaces.iter() {
+                    for (hash, _) in features[*index].iter_mut() {
+                        *hash = (*hash ^ tenant_mix) & parser::MASK31;
+                    }
+                }
+            }
+        }
+    }
+
+    // Features of a namespace as the model sees them
+    fn read(&self, features: &NamespaceFeatures, d: &NamespaceDescriptor) -> Vec<(u32, f32)> {
+        if d.namespace_type == NamespaceType::Transformed {
+            return self.transform(features, &self.transforms[d.namespace_index as usize]);
+        }
+        let namespace_features = &features[d.namespace_index as usize];
+        if d.namespace_format == NamespaceFormat::F32 {
+            namespace_features
+                .iter()
+                .map(|(hash, _)| (*hash, 1.0))
+                .collect()
+        } else {
+            namespace_features.clone()
+        }
+    }
+
+    fn transform(&self, features: &NamespaceFeatures, t: &ReferenceTransform) -> Vec<(u32, f32)> {
+        let floats = |i: usize| &features[t.from[i].namespace_index as usize];
+        let mut out: Vec<(u32, f32)> = Vec::new();
+        match t.function {
+            ReferenceFunction::Binner {
+                log,
+                greater_than,
+                resolution,
+                interpolated,
+            } => {
+                for (_, x) in floats(0).iter() {
+                    if *x < greater_than {
+                        out.push((transform_hash(t.seeds[0], *x as i32), 1.0));
+                    } else {
+                        let x = x - greater_than;
+                        let binned = if log { x.ln() } else { x.sqrt() } * resolution;
+                        push_float(&mut out, t.seeds[1], binned, 1.0, interpolated);
+                    }
+                }
+            }
+            ReferenceFunction::LogRatioBinner {
+                greater_than,
+                resolution,
+                interpolated,
+            } => {
+                for (_, x1) in floats(0).iter() {
+                    for (_, x2) in floats(1).iter() {
+                        if x1 + x2 < greater_than {
+                            let seed =
+                                murmur3::hash32_with_seed((*x1 as i32).to_le_bytes(), t.seeds[1]);
+                            out.push((transform_hash(seed, *x2 as i32), 1.0));
+                        } else if *x1 == 0.0 {
+                            push_float(
+                                &mut out,
+                                t.seeds[2],
+                                (x2 - greater_than).ln(),
+                                1.0,
+                                interpolated,
+                            );
+                        } else if *x2 == 0.0 {
+                            push_float(
+                                &mut out,
+                                t.seeds[3],
+                                (x1 - greater_than).ln(),
+                                1.0,
+                                interpolated,
+                            );
+                        } else {
+                            push_float(
+                                &mut out,
+                                t.seeds[0],
+                                (x1 / x2).ln() * resolution,
+                                1.0,
+                                interpolated,
+                            );
+                        }
+                    }
+                }
+            }
+            ReferenceFunction::Weight(multiplier) => {
+                for (hash, value) in self.read(features, &t.from[0]) {
+                    out.push((transform_hash(t.seeds[0], hash as i32), value * multiplier));
+                }
+            }
+            ReferenceFunction::Combine => {
+                let mut products: Vec<(u32, f32)> = self.read(features, &t.from[0]);
+                for d in t.from[1..].iter() {
+                    let next = self.read(features, d);
+                    products = products
+                        .iter()
+                        .flat_map(|(hash, value)| {
+                            next.iter()
+                                .map(move |(hash2, value2)| (hash ^ hash2, value * value2))
+                        })
+                        .collect();
+                }
+                for (hash, value) in products {
+                    out.push((transform_hash(t.seeds[0], hash as i32), value));
+                }
+            }
+        }
+        out
+    }
+
+    pub fn translate(&self, line: &[u8]) -> Result<FeatureBuffer, Box<dyn Error>> {
+        let line = str::from_utf8(line)?.trim_end_matches('\n');
+        let (label, example_importance, mut features) = self.parse(line)?;
+        self.rewrite(&mut features);
+        let read = |d: &NamespaceDescriptor| self.read(&features, d);
+        let mut fb = FeatureBuffer {
+            label,
+            example_importance,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        };
+
+        for (combo_index, combo) in self.mi.feature_combo_descs.iter().enumerate() {
+            let mut products: Vec<(u32, f32)> = read(&combo.namespace_descriptors[0]);
+            for namespace_descriptor in combo.namespace_descriptors[1..].iter() {
+                let namespace_features = read(namespace_descriptor);
+                let mut next_products: Vec<(u32, f32)> = Vec::new();
+                for (hash, value) in products.iter() {
+                    let half_hash = hash.wrapping_mul(VOWPAL_FNV_PRIME);
+                    for (hash2, value2) in namespace_features.iter() {
+                        next_products.push((hash2 ^ half_hash, value * value2));
+                    }
+                }
+                products = next_products;
+            }
+            for (hash, value) in products {
+                fb.lr_buffer.push(HashAndValue {
+                    hash: hash & self.lr_hash_mask,
+                    value: value * combo.weight,
+                    combo_index: combo_index as u32,
+                });
+            }
+        }
+        if self.mi.add_constant_feature {
+            fb.lr_buffer.push(HashAndValue {
+                hash: CONSTANT_HASH & self.lr_hash_mask,
+                value: 1.0,
+                combo_index: self.mi.feature_combo_descs.len() as u32,
+            });
+        }
+
+        if self.mi.ffm_k > 0 || self.mi.fm_k > 0 {
+            for (field_index, field) in self.mi.ffm_fields.iter().enumerate() {
+                for namespace_descriptor in field {
+                    let shift = match namespace_descriptor.namespace_format {
+                        NamespaceFormat::Passthrough => self.ffm_dimension_bits,
+                        _ => 0,
+                    };
+                    for (hash, value) in read(namespace_descriptor).iter() {
+                        fb.ffm_buffer.push(HashAndValueAndSeq {
+                            hash: (hash << shift) & self.ffm_hash_mask,
+                            value: *value,
+                            contra_field_index: field_index as u32 * self.mi.ffm_k,
+                        });
+                    }
+                }
+            }
+        }
+
+        for dense_input in self.mi.dense_inputs.iter() {
+            let values = read(&dense_input.namespace_descriptor);
+            if values.is_empty() {
+                fb.dense_buffer
+                    .resize(fb.dense_buffer.len() + dense_input.len as usize, 0.0);
+            }
+            for (_, value) in values.iter() {
+                fb.dense_buffer
+                    .push(if value.is_nan() { 0.0 } else { *value });
+            }
+        }
+
+        for (lookup_index, lookup) in self.mi.embedding_lookups.iter().enumerate() {
+            for (hash, value) in read(&lookup.namespace_descriptor).iter() {
+                fb.embedding_buffer.push(HashAndValue {
+                    hash: *hash,
+                    value: *value,
+                    combo_index: lookup_index as u32,
+                });
+            }
+        }
+        Ok(fb)
+    }
+}
+
+fn same_value(a: f32, b: f32) -> bool {
+    a == b || (a.is_nan() && b.is_nan())
+}
+
+fn compare_buffers<T: PartialEq + std::fmt::Debug>(
+    name: &str,
+    served: &[T],
+    reference: &[T],
+    same: impl Fn(&T, &T) -> bool,
+) -> Option<String> {
+    if served.len() != reference.len() {
+        return Some(format!(
+            "{} has {} entries, reference has {}",
+            name,
+            served.len(),
+            reference.len()
+        ));
+    }
+    served
+        .iter()
+        .zip(reference.iter())
+        .position(|(s, r)| !same(s, r))
+        .map(|i| {
+            format!(
+                "{}[{}] is {:?}, reference is {:?}",
+                name, i, served[i], reference[i]
+            )
+        })
+}
+
+// Describes the first difference of the served and the reference feature buffers, example numbers are not compared
+pub fn compare_feature_buffers(
+    served: &FeatureBuffer,
+    reference: &FeatureBuffer,
+) -> Option<String> {
+    if !same_value(served.label, reference.label) {
+        return Some(format!(
+            "label is {}, reference is {}",
+            served.label, reference.label
+        ));
+    }
+    if !same_value(served.example_importance, reference.example_importance) {
+        return Some(format!(
+            "example importance is {}, reference is {}",
+            served.example_importance, reference.example_importance
+        ));
+    }
+    compare_buffers(
+        "lr_buffer",
+        &served.lr_buffer,
+        &reference.lr_buffer,
+        |s, r| s.hash == r.hash && s.combo_index == r.combo_index && same_value(s.value, r.value),
+    )
+    .or_else(|| {
+        compare_buffers(
+            "ffm_buffer",
+            &served.ffm_buffer,
+            &reference.ffm_buffer,
+            |s, r| {
+                s.hash == r.hash
+                    && s.contra_field_index == r.contra_field_index
+                    && same_value(s.value, r.value)
+            },
+        )
+    })
+    .or_else(|| {
+        compare_buffers(
+            "dense_buffer",
+            &served.dense_buffer,
+            &reference.dense_buffer,
+            |s, r| same_value(*s, *r),
+        )
+    })
+    .or_else(|| {
+        compare_buffers(
+            "embedding_buffer",
+            &served.embedding_buffer,
+            &reference.embedding_buffer,
+            |s, r| {
+                s.hash == r.hash && s.combo_index == r.combo_index && same_value(s.value, r.value)
+            },
+        )
+    })
+}
+
+// Clones share the reference and the counts, so all serving threads report together.
+// Sampling is deterministic, each clone checks every 1/fraction-th of its requests.
+#[derive(Clone)]
+pub struct ParityChecker {
+    reference: Arc<ReferenceTranslator>,
+    fraction: f64,
+    credit: f64,
+    checked: Arc<AtomicU64>,
+    mismatched: Arc<AtomicU64>,
+}
+
+impl ParityChecker {
+    pub fn new_from_cmdline(
+        cl: &clap::ArgMatches,
+        mi: &ModelInstance,
+        vw: &VwNamespaceMap,
+    ) -> Result<Option<ParityChecker>, Box<dyn Error>> {
+        match cl.value_of("parity_check") {
+            Some(fraction) => Ok(Some(ParityChecker::new(mi, vw, fraction.parse()?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn new(
+        mi: &ModelInstance,
+        vw: &VwNamespaceMap,
+        fraction: f64,
+    ) -> Result<ParityChecker, Box<dyn Error>> {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--parity_check fraction has to be in (0, 1], got {}",
+                    fraction
+                ),
+            )));
+        }
+        Ok(ParityChecker {
+            reference: Arc::new(ReferenceTranslator::new(mi, vw)?),
+            fraction,
+            credit: 0.0,
+            checked: Arc::new(AtomicU64::new(0)),
+            mismatched: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    // Whether the next request is to be checked
+    pub fn sample(&mut self) -> bool {
+        self.credit += self.fraction;
+        if self.credit >= 1.0 {
+            self.credit -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Compares the served translation of the request line with the reference, returns whether they match
+    pub fn check(&self, line: &[u8], served: &FeatureBuffer) -> bool {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        let mismatch = match self.reference.translate(line) {
+            Ok(reference) => compare_feature_buffers(served, &reference),
+            Err(e) => Some(e.to_string()),
+        };
+        match mismatch {
+            None => true,
+            Some(mismatch) => {
+                let count = self.mismatched.fetch_add(1, Ordering::Relaxed) + 1;
+                // Warn on the first one and then on every power of ten
+                if is_power_of_ten(count) {
+                    log::warn!(
+                        "{} train/serve parity mismatches, last one: {} in \"{}\"",
+                        count,
+                        mismatch,
+                        String::from_utf8_lossy(line).trim_end()
+                    );
+                }
+                false
+            }
+        }
+    }
+
+    // For serving a model whose FFM weight table was resized, the counts stay shared
+    pub fn set_ffm_bit_precision(&mut self, ffm_bit_precision: u32) {
+        let mut reference = (*self.reference).clone();
+        reference.mi.ffm_bit_precision = ffm_bit_precision;
+        reference.ffm_hash_mask =
+            ((1 << ffm_bit_precision) - 1) & !((1 << reference.ffm_dimension_bits) - 1);
+        self.reference = Arc::new(reference);
+    }
+
+    // Counters for the "stats" command
+    pub fn format_counters(&self) -> String {
+        format!(
+            "parity_check checked:{} mismatched:{}\n",
+            self.checked.load(Ordering::Relaxed),
+            self.mismatched.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::FeatureBufferTranslator;
+    use crate::feature_transform_parser::NamespaceTransformsParser;
+    use crate::model_instance::{DenseInputDesc, FeatureComboDesc, NamespaceTopK};
+    use crate::parser::VowpalParser;
+
+    fn test_model(vw: &VwNamespaceMap) -> ModelInstance {
+        let d = |vwname: &str| vw.map_vwname_to_namespace_descriptor[vwname.as_bytes()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 18;
+        mi.add_constant_feature = true;
+        for (namespace_descriptors, weight) in [
+            (vec![d("A")], 1.0),
+            (vec![d("B")], 0.5),
+            (vec![d("C")], 1.0),
+            (vec![d("H")], 1.0),
+            (vec![d("A"), d("H")], 2.0),
+        ]
+        .iter()
+        {
+            mi.feature_combo_descs.push(FeatureComboDesc {
+                namespace_descriptors: namespace_descriptors.clone(),
+                weight: *weight,
+            });
+        }
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![d("A"), d("B")], vec![d("H")]];
+        mi.dense_inputs = vec![DenseInputDesc {
+            namespace_descriptor: d("V"),
+            len: 3,
+        }];
+        mi
+    }
+
+    #[test]
+    fn test_reference_translation() {
+        let vw = VwNamespaceMap::new(
+            "A,featureA\nB,featureB,passthrough,100\nC,featureC,f32\nH,featureH,hierarchical,0.25:0.5\nV,featureV,f32vec:3\n",
+        )
+        .unwrap();
+        let mi = test_model(&vw);
+        let reference = ReferenceTranslator::new(&mi, &vw).unwrap();
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        for line in [
+            "1 |A a\n",
+            "-1 2.5 'tag |A a:2 b |B 7 |C 1.5 |H x/y/z\n",
+            "|A:0.5 a b:NONE |V 1,NONE,3 |C NONE\n",
+            "1 'tag |H p/q |A a |H r/s/t/u:3 |B 0\n",
+            "-1 |B 3:2 |V 0.5,0.25,1 |A\n",
+        ]
+        .iter()
+        {
+            fbt.translate(pa.next_vowpal_from_bytes(line.as_bytes()).unwrap(), 0);
+            let fb = reference.translate(line.as_bytes()).unwrap();
+            assert_eq!(
+                compare_feature_buffers(&fbt.feature_buffer, &fb),
+                None,
+                "{}",
+                line
+            );
+        }
+
+        // Hierarchical features have their ancestors
+        let fb = reference.translate(b"1 |H x/y/z\n").unwrap();
+        let values: Vec<f32> = fb
+            .lr_buffer
+            .iter()
+            .filter(|f| f.combo_index == 3)
+            .map(|f| f.value)
+            .collect();
+        assert_eq!(values, vec![1.0, 0.25, 0.5]);
+        assert!(reference.translate(b"1 |Z a\n").is_err());
+        assert!(reference.translate(b"1 |B -1\n").is_err());
+        assert!(reference.translate(b"1 |V 1,2\n").is_err());
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB,passthrough,100\nC,featureC,f32\nH,featureH,hierarchical\nV,featureV,f32vec:3\n").unwrap();
+        let mi = test_model(&vw);
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let line = b"1 |A a |H x/y\n";
+        fbt.translate(pa.next_vowpal_from_bytes(line).unwrap(), 0);
+
+        let mut checker = ParityChecker::new(&mi, &vw, 1.0).unwrap();
+        assert!(checker.sample());
+        assert!(checker.check(line, &fbt.feature_buffer));
+        let mut fb = fbt.feature_buffer.clone();
+        fb.example_number = 7;
+        assert!(checker.check(line, &fb));
+
+        fb.lr_buffer[1].hash ^= 1;
+        assert_eq!(
+            compare_feature_buffers(&fb, &fbt.feature_buffer).unwrap(),
+            format!(
+                "lr_buffer[1] is {:?}, reference is {:?}",
+                fb.lr_buffer[1], fbt.feature_buffer.lr_buffer[1]
+            )
+        );
+        assert!(!checker.check(line, &fb));
+        let mut fb = fbt.feature_buffer.clone();
+        fb.ffm_buffer.pop();
+        assert!(!checker.check(line, &fb));
+        let mut fb = fbt.feature_buffer.clone();
+        fb.dense_buffer[0] = f32::NAN;
+        assert!(!checker.check(line, &fb));
+        // Lines the reference can't translate are mismatches too
+        assert!(!checker.check(b"1 |Z z\n", &fbt.feature_buffer));
+        assert_eq!(
+            checker.clone().format_counters(),
+            "parity_check checked:6 mismatched:4\n"
+        );
+    }
+
+    #[test]
+    fn test_sampling_and_unsupported() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB,passthrough,100\nC,featureC,f32\nH,featureH,hierarchical\nV,featureV,f32vec:3\n").unwrap();
+        let mut mi = test_model(&vw);
+        let mut checker = ParityChecker::new(&mi, &vw, 0.25).unwrap();
+        assert_eq!((0..100).filter(|_| checker.sample()).count(), 25);
+        assert!(ParityChecker::new(&mi, &vw, 0.0).is_err());
+        assert!(ParityChecker::new(&mi, &vw, 1.5).is_err());
+
+        // Stateful transforms have no reference
+        let mut transforms = NamespaceTransformsParser::new();
+        transforms
+            .add_transform_namespace(&vw, "counted=RollingCount(featureA)(100,1000)")
+            .unwrap();
+        mi.transform_namespaces = transforms.resolve(&vw).unwrap();
+        assert!(ParityChecker::new(&mi, &vw, 0.25).is_err());
+    }
+
+    #[test]
+    fn test_reference_transforms_and_rewrites() {
+        let vw = VwNamespaceMap::new(
+            "A,featureA\nB,featureB\nC,featureC,f32\nD,featureD,f32,div:10\nT,featureT\n",
+        )
+        .unwrap();
+        let d = |vwname: &str| vw.map_vwname_to_namespace_descriptor[vwname.as_bytes()];
+        let mut transforms = NamespaceTransformsParser::new();
+        for transform in [
+            "sqrt=BinnerSqrt(featureC)(2.0,2.0)",
+            "log=BinnerLogPlain(featureD)()",
+            "ratio=BinnerLogRatio(featureC,featureD)(1.0)",
+            "weighted=Weight(featureA)(3.0)",
+            "ab=Combine(featureA,featureB)()",
+            "abw=Combine(ab,weighted,featureC)()",
+        ]
+        .iter()
+        {
+            transforms.add_transform_namespace(&vw, transform).unwrap();
+        }
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.transform_namespaces = transforms.resolve(&vw).unwrap();
+        let t = |name: &str| {
+            mi.transform_namespaces
+                .v
+                .iter()
+                .find(|t| t.to_namespace.namespace_verbose == name)
+                .unwrap()
+                .to_namespace
+                .namespace_descriptor
+        };
+        let (sqrt, log, ratio, weighted, ab, abw) = (
+            t("sqrt"),
+            t("log"),
+            t("ratio"),
+            t("weighted"),
+            t("ab"),
+            t("abw"),
+        );
+        mi.bit_precision = 18;
+        for namespace_descriptors in [
+            vec![d("A")],
+            vec![d("C")],
+            vec![sqrt],
+            vec![log],
+            vec![ratio],
+            vec![weighted],
+            vec![ab, sqrt],
+            vec![abw],
+        ]
+        .iter()
+        {
+            mi.feature_combo_descs.push(FeatureComboDesc {
+                namespace_descriptors: namespace_descriptors.clone(),
+                weight: 1.0,
+            });
+        }
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![d("A"), sqrt], vec![log, d("T")]];
+        mi.namespace_topks = vec![NamespaceTopK {
+            namespace_descriptor: d("A"),
+            k: 2,
+        }];
+        mi.tenant_namespace = Some(d("T"));
+
+        let lines = [
+            "1 |A a b a:2 c |B x y |C 3.5 0.2 NONE |D 25 0 |T t1\n",
+            "-1 |A a a:0.5 a |C 150 |D 7 |T t2 |B y\n",
+            "|A z:0.5 y:2 x |D 0 |C 0\n",
+            "1 |C 1000 |D 3 |B x\n",
+            "1 |T t1 |A a\n",
+        ];
+        for dup_policy in [DupPolicy::Sum, DupPolicy::Max, DupPolicy::First].iter() {
+            mi.dup_policy = Some(*dup_policy);
+            let reference = ReferenceTranslator::new(&mi, &vw).unwrap();
+            let mut pa = VowpalParser::new(&vw);
+            let mut fbt = FeatureBufferTranslator::new(&mi);
+            for line in lines.iter() {
+                fbt.translate(pa.next_vowpal_from_bytes(line.as_bytes()).unwrap(), 0);
+                let fb = reference.translate(line.as_bytes()).unwrap();
+                assert_eq!(
+                    compare_feature_buffers(&fbt.feature_buffer, &fb),
+                    None,
+                    "{:?} {}",
+                    dup_policy,
+                    line
+                );
+            }
+        }
+
+        // Each tenant has its own weights
+        let reference = ReferenceTranslator::new(&mi, &vw).unwrap();
+        let hashes = |line: &[u8]| -> Vec<u32> {
+            let fb = reference.translate(line).unwrap();
+            fb.lr_buffer.iter().map(|f| f.hash).collect()
+        };
+        assert_ne!(hashes(b"1 |T t1 |A a\n"), hashes(b"1 |T t2 |A a\n"));
+    }
+}
@@ -1,6 +1,7 @@
+use crate::murmur3;
 use crate::radix_tree::{NamespaceDescriptorWithHash, RadixTree};
+use crate::simd::U8x16;
 use crate::vwmap;
-use fasthash::murmur3;
 use std::error::Error;
 use std::fmt;
 use std::io::BufRead;
@@ -18,23 +19,135 @@ pub const IS_NOT_SINGLE_MASK: u32 = 1u32 << 31;
 pub const MASK31: u32 = !IS_NOT_SINGLE_MASK;
 pub const NO_FEATURES: u32 = IS_NOT_SINGLE_MASK; // null is just an exact IS_NOT_SINGLE_MASK
 pub const NO_LABEL: u32 = 0xff;
+pub const LABEL_HAS_TAG_MASK: u32 = 1u32 << 31; // set on the label when the record carries a tag at its end
+pub const LABEL_HAS_HEADS_MASK: u32 = 1u32 << 30; // set when labels of more --heads follow the features
+pub const LABEL_FLAGS_MASK: u32 = LABEL_HAS_TAG_MASK | LABEL_HAS_HEADS_MASK;
 pub const FLOAT32_ONE: u32 = 1065353216; // 1.0f32.to_bits()
 
 #[derive(Clone)]
 pub struct VowpalParser {
-    vw_map: vwmap::VwNamespaceMap,
+    pub vw_map: vwmap::VwNamespaceMap,
     map_vwname_to_namespace_descriptor: RadixTree,
+    namespace_hierarchy_weights: Vec<Option<Vec<f32>>>, // by namespace index
+    namespace_f32_scalings: Vec<Option<vwmap::F32Scaling>>, // by namespace index
     tmp_read_buf: Vec<u8>,
     pub output_buffer: Vec<u32>,
+    // Hashed features of the line, hashed murmur3::BATCH at a time once the line is scanned
+    pending_hashes: Vec<PendingHash>,
+    delimiters: Delimiters,
+    // --heads: labels of the heads after the first, from "label1,label2,..."
+    head_labels: Vec<u32>,
+}
+
+// Positions of the spaces and colons of the line, found 16 bytes at a time. The scan of the line
+// then jumps from delimiter to delimiter instead of walking each token byte by byte.
+#[derive(Clone, Default)]
+struct Delimiters {
+    positions: Vec<u32>, // ends with the length of the line
+    next: usize,         // the first position not yet passed by the scan
+}
+
+impl Delimiters {
+    fn classify(&mut self, line: &[u8]) {
+        self.positions.truncate(0);
+        self.next = 0;
+        let mut chunks = line.chunks_exact(16);
+        let mut pos = 0;
+        for chunk in &mut chunks {
+            self.push_chunk(pos, unsafe { U8x16::load(chunk.as_ptr()) });
+            pos += 16;
+        }
+        let remainder = chunks.remainder();
+        if !remainder.is_empty() {
+            let mut last_chunk = [0u8; 16];
+            last_chunk[..remainder.len()].copy_from_slice(remainder);
+            self.push_chunk(pos, unsafe { U8x16::load(last_chunk.as_ptr()) });
+        }
+        self.positions.push(line.len() as u32);
+    }
+
+    #[inline(always)]
+    fn push_chunk(&mut self, pos: usize, chunk: U8x16) {
+        let mut mask = chunk.eq_mask(0x20) | chunk.eq_mask(0x3a);
+        while mask != 0 {
+            self.positions
+                .push((pos + mask.trailing_zeros() as usize) as u32);
+            mask &= mask - 1;
+        }
+    }
+
+    // First space or colon at or after from, the line length if there is none. Calls go left to right.
+    #[inline(always)]
+    fn next_space_or_colon(&mut self, from: usize) -> usize {
+        while (self.positions[self.next] as usize) < from {
+            self.next += 1;
+        }
+        self.positions[self.next] as usize
+    }
+
+    #[inline(always)]
+    fn next_space(&mut self, line: &[u8], mut from: usize) -> usize {
+        loop {
+            let pos = self.next_space_or_colon(from);
+            if self.next + 1 == self.positions.len() || line[pos] == 0x20 {
+                return pos;
+            }
+            from = pos + 1;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PendingHash {
+    output_pos: u32,
+    start: u32,
+    len: u32,
+    seed: u32,
 }
 
 #[derive(Debug)]
 pub struct FlushCommand; // Parser returns FlushCommand to signal flush message
 #[derive(Debug)]
+pub struct StatsCommand; // Parser returns StatsCommand when the daemon is asked for its stats
+#[derive(Debug)]
+pub struct FfmGrowCommand; // Parser returns FfmGrowCommand when the daemon is asked to double its FFM weight table
+#[derive(Debug)]
+pub struct DebugCommand {
+    // Parser returns DebugCommand with the parsed record of "debug <example>" lines
+    pub record: Vec<u32>,
+}
+#[derive(Debug)]
+pub struct ExplainCommand {
+    // Parser returns ExplainCommand with the parsed record of "explain <example>" lines
+    pub record: Vec<u32>,
+}
+#[derive(Debug)]
+pub struct OutputsCommand {
+    // Parser returns OutputsCommand with the parsed record of "outputs <example>" lines
+    pub record: Vec<u32>,
+}
+#[derive(Debug)]
+pub struct BatchCommand {
+    // Parser returns BatchCommand for "batch <n>" lines, the n lines that follow are one batch
+    pub size: usize,
+}
+#[derive(Debug)]
 pub struct HogwildLoadCommand {
     // Parser returns Hogwild Load as a command
     pub filename: String,
 }
+#[derive(Debug)]
+pub struct ModelLoadCommand {
+    // Parser returns ModelLoadCommand when the daemon is asked to load and switch to another model
+    pub filename: String,
+}
+#[derive(Debug)]
+pub struct ModelVersionCommand; // Parser returns ModelVersionCommand when asked which model is served
+#[derive(Debug)]
+pub struct EvaluateCommand {
+    // Parser returns EvaluateCommand when the daemon is asked to evaluate the model on a file
+    pub filename: String,
+}
 
 impl Error for FlushCommand {}
 impl fmt::Display for FlushCommand {
@@ -43,6 +156,84 @@ impl fmt::Display for FlushCommand {
     }
 }
 
+impl Error for StatsCommand {}
+impl fmt::Display for StatsCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: a \"stats\" command from client")
+    }
+}
+
+impl Error for FfmGrowCommand {}
+impl fmt::Display for FfmGrowCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: a \"ffm_grow\" command from client")
+    }
+}
+
+impl Error for DebugCommand {}
+impl fmt::Display for DebugCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: a \"debug\" command from client")
+    }
+}
+
+impl Error for ExplainCommand {}
+impl fmt::Display for ExplainCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: an \"explain\" command from client")
+    }
+}
+
+impl Error for OutputsCommand {}
+impl fmt::Display for OutputsCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: an \"outputs\" command from client")
+    }
+}
+
+impl Error for BatchCommand {}
+impl fmt::Display for BatchCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Not really an error: a \"batch\" command from client of {} examples",
+            self.size
+        )
+    }
+}
+
+impl Error for EvaluateCommand {}
+impl fmt::Display for EvaluateCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Not really an error: an \"evaluate\" command from client to evaluate on: {}",
+            self.filename
+        )
+    }
+}
+
+impl Error for ModelLoadCommand {}
+impl fmt::Display for ModelLoadCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Not really an error: a \"model_load\" command from client to load: {}",
+            self.filename
+        )
+    }
+}
+
+impl Error for ModelVersionCommand {}
+impl fmt::Display for ModelVersionCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Not really an error: a \"model_version\" command from client"
+        )
+    }
+}
+
 impl Error for HogwildLoadCommand {}
 impl fmt::Display for HogwildLoadCommand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -57,7 +248,7 @@ impl fmt::Display for HogwildLoadCommand {
 /*
 organization of records buffer
 (u32) length of the output record
-(u32) label
+(u32) label (if most significant bit is set, the record ends with a tag, see below)
 (f32) Example importance (default: 1.0)
 (union_u u32)[number of features], where:
     -- if the most significant bit is zero
@@ -70,7 +261,9 @@ organization of records buffer
                 - the hash of the feature name (u32, bits 1-31), f32 weight of the feature)
             - if this is a f32 namespace the dynamic buffer content consists of the following pairs
                 - the hash of the feature name (31 bits of u32), f32 parsed value of the feature name)
+    -- passthrough namespaces use the binary layout, but instead of the hash they store base + integer value of the feature name
 [dynamic buffer (of u32/f32 types, exact layout depends on the above bits)]
+[optional tag: bytes of the tag packed into u32s, followed by (u32) length of the tag in bytes]
 */
 
 impl VowpalParser {
@@ -79,19 +272,46 @@ impl VowpalParser {
         for (namespace_vwname_as_bytes, namespace_descriptor) in
             vw.map_vwname_to_namespace_descriptor.iter()
         {
-            let namespace_hash_seed =
-                murmur3::hash32(str::from_utf8(&namespace_vwname_as_bytes).unwrap());
+            // Passthrough and vector namespaces don't hash, so we reuse the seed slot for their base
+            // offset and vector length
+            let namespace_hash_seed = match namespace_descriptor.namespace_format {
+                vwmap::NamespaceFormat::Passthrough => vw
+                    .vw_source
+                    .entries
+                    .iter()
+                    .find(|e| e.namespace_vwname.as_bytes() == &namespace_vwname_as_bytes[..])
+                    .map_or(0, |e| e.namespace_passthrough_base),
+                vwmap::NamespaceFormat::F32Vec => vw.get_namespace_vector_len(namespace_descriptor),
+                _ => murmur3::hash32(str::from_utf8(&namespace_vwname_as_bytes).unwrap()),
+            };
             map_vwname_to_namespace_descriptor.insert(
                 namespace_vwname_as_bytes,
                 NamespaceDescriptorWithHash::new(namespace_descriptor.clone(), namespace_hash_seed),
             );
         }
 
+        let mut namespace_hierarchy_weights: Vec<Option<Vec<f32>>> = vec![None; vw.num_namespaces];
+        let mut namespace_f32_scalings: Vec<Option<vwmap::F32Scaling>> =
+            vec![None; vw.num_namespaces];
+        for entry in vw.vw_source.entries.iter() {
+            let namespace_descriptor =
+                vw.map_vwname_to_namespace_descriptor[entry.namespace_vwname.as_bytes()];
+            namespace_hierarchy_weights[namespace_descriptor.namespace_index as usize] =
+                entry.namespace_hierarchy_weights.clone();
+            namespace_f32_scalings[namespace_descriptor.namespace_index as usize] =
+                entry.namespace_f32_scaling;
+        }
+
         let mut parser = VowpalParser {
             vw_map: (*vw).clone(),
             map_vwname_to_namespace_descriptor,
+            namespace_hierarchy_weights,
+            namespace_f32_scalings,
             tmp_read_buf: Vec::with_capacity(RECBUF_LEN),
             output_buffer: Vec::with_capacity(RECBUF_LEN * 2),
+            pending_hashes: Vec::new(),
+            delimiters: Delimiters::default(),
+            head_labels: Vec::new(),
         };
         parser.output_buffer.resize(
             (vw.num_namespaces as u32 * NAMESPACE_DESC_LEN + HEADER_LEN) as usize,
@@ -137,6 +357,27 @@ impl VowpalParser {
         }
     }
 
+    #[inline(always)]
+    pub fn parse_passthrough_or_error(
+        &self,
+        i_start: usize,
+        i_end: usize,
+        base: u32,
+    ) -> Result<u32, Box<dyn Error>> {
+        let token = unsafe { self.tmp_read_buf.get_unchecked(i_start..i_end) };
+        match unsafe { str::from_utf8_unchecked(token) }.parse::<u32>() {
+            Ok(v) if v <= MASK31 - base => Ok(base + v),
+            _ => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Failed parsing feature value to a non-negative integer index (for passthrough namespace with base {}): {}",
+                    base,
+                    String::from_utf8_lossy(token)
+                ),
+            ))),
+        }
+    }
+
     // This is a very very slow implementation, but it's ok, this is called extremely infrequently to decode a command
     pub fn parse_cmd(&self, i_start: usize, rowlen: usize) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
         let mut o: Vec<Vec<u8>> = Vec::new();
@@ -168,6 +409,17 @@ impl VowpalParser {
         return self.next_vowpal_to_size(tmp_read_buf_size);
     }
 
+    // Like next_vowpal, along with the line of the example, for naming its features (--invert_hash)
+    pub fn next_vowpal_with_line(
+        &mut self,
+        input_bufread: &mut impl BufRead,
+    ) -> Result<(&[u32], &[u8]), Box<dyn Error>> {
+        if self.next_vowpal(input_bufread)?.is_empty() {
+            return Ok((&[], &[]));
+        }
+        Ok((&self.output_buffer, &self.tmp_read_buf))
+    }
+
     pub fn next_vowpal_with_size(
         &mut self,
         input_bufread: &mut impl BufRead,
@@ -211,22 +463,248 @@ impl VowpalParser {
         return self.next_vowpal_to_size(tmp_read_buf_size);
     }
 
+    // Labels of the other heads follow the first one in the label token, separated by commas:
+    // 1 or -1, or nothing when the head has no label for the example
+    fn parse_head_labels(&mut self, label_end: usize) -> Result<(), Box<dyn Error>> {
+        let label_token = &self.tmp_read_buf[..label_end];
+        for head_label in label_token.split(|c| *c == b',').skip(1) {
+            let label = match head_label {
+                b"1" => 1,
+                b"-1" => 0,
+                b"" => NO_LABEL,
+                _ => {
+                    return Err(Box::new(IOError::new(
+                        ErrorKind::Other,
+                        format!(
+                            "Label of a head has to be 1, -1 or empty, got {:?}",
+                            String::from_utf8_lossy(head_label)
+                        ),
+                    )))
+                }
+            };
+            self.head_labels.push(label);
+        }
+        Ok(())
+    }
+
+    // The line of the last example read, as it came in
+    pub fn last_line(&self) -> &[u8] {
+        &self.tmp_read_buf
+    }
+
+    // Parses a single line that is already in memory, avoiding the BufRead machinery
+    pub fn next_vowpal_from_bytes(&mut self, line: &[u8]) -> Result<&[u32], Box<dyn Error>> {
+        if line.is_empty() {
+            return Ok(&[]);
+        }
+        self.tmp_read_buf.truncate(0);
+        self.tmp_read_buf.extend_from_slice(line);
+        self.next_vowpal_to_size(line.len())
+    }
+
+    // The hash of feature i_start..i_end goes to output_pos once hash_pending_features() runs
+    #[inline(always)]
+    fn defer_hash(&mut self, output_pos: usize, i_start: usize, i_end: usize, seed: u32) {
+        self.pending_hashes.push(PendingHash {
+            output_pos: output_pos as u32,
+            start: i_start as u32,
+            len: (i_end - i_start) as u32,
+            seed,
+        });
+    }
+
+    fn hash_pending_features(&mut self) {
+        if self.pending_hashes.is_empty() {
+            return;
+        }
+        let read_buf_len = self.tmp_read_buf.len();
+        self.tmp_read_buf
+            .resize(read_buf_len + murmur3::BATCH_PADDING, 0);
+        let mut batches = self.pending_hashes.chunks_exact(murmur3::BATCH);
+        for batch in &mut batches {
+            let mut starts = [0u32; murmur3::BATCH];
+            let mut lens = [0u32; murmur3::BATCH];
+            let mut seeds = [0u32; murmur3::BATCH];
+            for (i, pending) in batch.iter().enumerate() {
+                starts[i] = pending.start;
+                lens[i] = pending.len;
+                seeds[i] = pending.seed;
+            }
+            let hashes =
+                murmur3::hash32_with_seed_batch(&self.tmp_read_buf, &starts, &lens, &seeds);
+            for (pending, h) in batch.iter().zip(hashes.iter()) {
+                self.output_buffer[pending.output_pos as usize] = h & MASK31;
+            }
+        }
+        for pending in batches.remainder() {
+            let start = pending.start as usize;
+            self.output_buffer[pending.output_pos as usize] = murmur3::hash32_with_seed(
+                &self.tmp_read_buf[start..start + pending.len as usize],
+                pending.seed,
+            ) & MASK31;
+        }
+        self.tmp_read_buf.truncate(read_buf_len);
+        self.pending_hashes.truncate(0);
+    }
+
+    // Moves the feature written in-place to the dynamic buffer of the namespace, along with its pending hash
+    #[inline(always)]
+    fn promote_in_place_feature(&mut self, feature_output: u32, namespace_index_offset: usize) {
+        if let Some(pending) = self.pending_hashes.last_mut() {
+            if pending.output_pos as usize == namespace_index_offset {
+                pending.output_pos = self.output_buffer.len() as u32;
+            }
+        }
+        self.output_buffer.push(feature_output);
+        self.output_buffer.push(FLOAT32_ONE);
+    }
+
+    // Adds a weighted feature to the dynamic buffer of the namespace, moving the feature written
+    // in-place there first, if there is one
+    #[inline(always)]
+    unsafe fn push_weighted_feature(
+        &mut self,
+        h: u32,
+        weight: f32,
+        namespace_index_offset: usize,
+        bufpos_namespace_start: usize,
+        namespace_num_of_features: u32,
+    ) {
+        let feature_output = *self.output_buffer.get_unchecked(namespace_index_offset);
+        if namespace_num_of_features == 1 && (feature_output & IS_NOT_SINGLE_MASK) == 0 {
+            self.promote_in_place_feature(feature_output, namespace_index_offset);
+        }
+        self.output_buffer.push(h);
+        self.output_buffer.push(weight.to_bits());
+        *self.output_buffer.get_unchecked_mut(namespace_index_offset) = IS_NOT_SINGLE_MASK
+            | (((bufpos_namespace_start << 16) + self.output_buffer.len()) as u32);
+    }
+
+    // Vector namespace has a single feature "v1,v2,...", values are written as (index, value) pairs
+    #[inline(always)]
+    unsafe fn push_vector_features(
+        &mut self,
+        i_start: usize,
+        i_end: usize,
+        vector_len: u32,
+        namespace_index_offset: usize,
+        bufpos_namespace_start: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut index: u32 = 0;
+        let mut value_start = i_start;
+        for i in i_start..=i_end {
+            if i < i_end && *self.tmp_read_buf.get_unchecked(i) != 0x2c {
+                // ","
+                continue;
+            }
+            if index == vector_len {
+                break;
+            }
+            let value = self.parse_float_or_error(
+                value_start,
+                i,
+                "Failed parsing vector value to float (for f32vec namespace)",
+            )?;
+            self.output_buffer.push(index);
+            self.output_buffer.push(value.to_bits());
+            index += 1;
+            value_start = i + 1;
+        }
+        if index != vector_len || value_start <= i_end {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Vector namespace expects {} comma separated floats, got: {}",
+                    vector_len,
+                    String::from_utf8_lossy(self.tmp_read_buf.get_unchecked(i_start..i_end))
+                ),
+            )));
+        }
+        *self.output_buffer.get_unchecked_mut(namespace_index_offset) = IS_NOT_SINGLE_MASK
+            | (((bufpos_namespace_start << 16) + self.output_buffer.len()) as u32);
+        Ok(())
+    }
+
+    // For value "a/b/c" of a hierarchical namespace emits ancestors "a" and "a/b", weighted by their level
+    #[inline(always)]
+    unsafe fn push_ancestor_features(
+        &mut self,
+        i_start: usize,
+        i_end: usize,
+        weight: f32,
+        namespace_index: usize,
+        namespace_hash_seed: u32,
+        namespace_index_offset: usize,
+        bufpos_namespace_start: usize,
+        namespace_num_of_features: &mut u32,
+    ) {
+        let mut level = 0;
+        for i in i_start + 1..i_end {
+            if *self.tmp_read_buf.get_unchecked(i) != 0x2f {
+                // "/"
+                continue;
+            }
+            let level_weight = match self
+                .namespace_hierarchy_weights
+                .get_unchecked(namespace_index)
+            {
+                Some(weights) => *weights
+                    .get(level)
+                    .or_else(|| weights.last())
+                    .unwrap_or(&1.0),
+                None => 1.0,
+            };
+            let h = murmur3::hash32_with_seed(
+                self.tmp_read_buf.get_unchecked(i_start..i),
+                namespace_hash_seed,
+            ) & MASK31;
+            self.push_weighted_feature(
+                h,
+                weight * level_weight,
+                namespace_index_offset,
+                bufpos_namespace_start,
+                *namespace_num_of_features,
+            );
+            *namespace_num_of_features += 1;
+            level += 1;
+        }
+    }
+
     fn next_vowpal_to_size(&mut self, tmp_read_buf_size: usize) -> Result<&[u32], Box<dyn Error>> {
         let bufpos: usize = self.vw_map.num_namespaces + HEADER_LEN as usize;
 
         let mut current_namespace_num_of_features = 0;
+        let mut tag_start: usize;
+        let mut tag_end: usize;
 
         unsafe {
             self.output_buffer.truncate(bufpos);
             self.output_buffer.fill(NO_FEATURES);
+            self.pending_hashes.truncate(0);
+            self.head_labels.truncate(0);
 
             let p = self.tmp_read_buf.as_ptr();
             let mut i_start: usize;
             let mut i_end: usize = 0;
 
-            // first token is a label or "flush" command
+            // first token is a label or "flush", "stats", "debug", "explain", "batch", "model_version", "model_load", "hogwild_load" or "evaluate" command
             match *p.add(0) {
-                0x31 => *self.output_buffer.get_unchecked_mut(LABEL_OFFSET) = 1, // 1
+                0x31..=0x39 => {
+                    // 1, or with --oaa the class number
+                    let mut label = 0;
+                    let mut i = 0;
+                    while i < tmp_read_buf_size && (*p.add(i)).is_ascii_digit() {
+                        label = label * 10 + (*p.add(i) - 0x30) as u32;
+                        if label >= NO_LABEL {
+                            return Err(Box::new(IOError::new(
+                                ErrorKind::Other,
+                                format!("Class label has to be below {}", NO_LABEL),
+                            )));
+                        }
+                        i += 1;
+                    }
+                    *self.output_buffer.get_unchecked_mut(LABEL_OFFSET) = label;
+                }
                 0x2d => *self.output_buffer.get_unchecked_mut(LABEL_OFFSET) = 0, // -1
                 0x7c => *self.output_buffer.get_unchecked_mut(LABEL_OFFSET) = NO_LABEL, // when first character is |, this means there is no label
                 _ => {
@@ -239,7 +717,45 @@ impl VowpalParser {
                         && *p.add(4) == 0x68
                     {
                         return Err(Box::new(FlushCommand));
-                    } else if tmp_read_buf_size >= "hogwild_load ".len() {
+                    } else if tmp_read_buf_size >= 5 && self.tmp_read_buf.starts_with(b"stats") {
+                        return Err(Box::new(StatsCommand));
+                    } else if self.tmp_read_buf.starts_with(b"ffm_grow") {
+                        return Err(Box::new(FfmGrowCommand));
+                    } else if self.tmp_read_buf.starts_with(b"debug ") {
+                        // The rest of the line is an example, parsed as usual
+                        self.tmp_read_buf.drain(0.."debug ".len());
+                        let record = self
+                            .next_vowpal_to_size(tmp_read_buf_size - "debug ".len())?
+                            .to_vec();
+                        return Err(Box::new(DebugCommand { record }));
+                    } else if self.tmp_read_buf.starts_with(b"explain ") {
+                        self.tmp_read_buf.drain(0.."explain ".len());
+                        let record = self
+                            .next_vowpal_to_size(tmp_read_buf_size - "explain ".len())?
+                            .to_vec();
+                        return Err(Box::new(ExplainCommand { record }));
+                    } else if self.tmp_read_buf.starts_with(b"outputs ") {
+                        self.tmp_read_buf.drain(0.."outputs ".len());
+                        let record = self
+                            .next_vowpal_to_size(tmp_read_buf_size - "outputs ".len())?
+                            .to_vec();
+                        return Err(Box::new(OutputsCommand { record }));
+                    } else if self.tmp_read_buf.starts_with(b"model_version") {
+                        return Err(Box::new(ModelVersionCommand));
+                    } else if self.tmp_read_buf.starts_with(b"batch ") {
+                        let size = std::str::from_utf8(
+                            &self.tmp_read_buf["batch ".len()..tmp_read_buf_size],
+                        )
+                        .ok()
+                        .and_then(|size| size.trim().parse::<usize>().ok());
+                        return match size {
+                            Some(size) => Err(Box::new(BatchCommand { size })),
+                            None => Err(Box::new(IOError::new(
+                                ErrorKind::Other,
+                                "Cannot parse the size of a batch".to_string(),
+                            ))),
+                        };
+                    } else if tmp_read_buf_size >= "evaluate ".len() {
                         // THIS IS SLOW, BUT IT IS CALLED VERY RARELY
                         // IF WE WILL AVE COMMANDS CALLED MORE FREQUENTLY, WE WILL NEED A FASTER IMPLEMENTATION
                         let vecs = self.parse_cmd(0, tmp_read_buf_size)?;
@@ -250,6 +766,16 @@ impl VowpalParser {
                                 return Err(Box::new(HogwildLoadCommand {
                                     filename: filename.to_string(),
                                 }));
+                            } else if command == "model_load" {
+                                let filename = String::from_utf8_lossy(&vecs[1]);
+                                return Err(Box::new(ModelLoadCommand {
+                                    filename: filename.trim_end().to_string(),
+                                }));
+                            } else if command == "evaluate" {
+                                let filename = String::from_utf8_lossy(&vecs[1]);
+                                return Err(Box::new(EvaluateCommand {
+                                    filename: filename.trim_end().to_string(),
+                                }));
                             }
                         } else {
                             return Err(Box::new(IOError::new(
@@ -268,30 +794,29 @@ impl VowpalParser {
             };
 
             let rowlen = tmp_read_buf_size - 1; // ignore last newline byte
+            self.delimiters
+                .classify(self.tmp_read_buf.get_unchecked(..rowlen));
             if *self.output_buffer.get_unchecked(LABEL_OFFSET) == NO_LABEL {
                 *self
                     .output_buffer
                     .get_unchecked_mut(EXAMPLE_IMPORTANCE_OFFSET) = FLOAT32_ONE;
             } else {
                 // if we have a label, let's check if we also have label weight
-                while *p.add(i_end) != 0x20 && i_end < rowlen {
-                    i_end += 1;
-                } // find space
+                i_end = self.delimiters.next_space(&self.tmp_read_buf, i_end);
+                self.parse_head_labels(i_end)?;
                 while *p.add(i_end) == 0x20 && i_end < rowlen {
                     i_end += 1;
                 } // find first non-space
-                  //if next character is not "|", we assume it's a example importance
+                  //if next character is not "|" or "'" (start of a tag), we assume it's a example importance
                   //i_end +=1;
-                if *p.add(i_end) == 0x7c {
+                if *p.add(i_end) == 0x7c || *p.add(i_end) == 0x27 {
                     *self
                         .output_buffer
                         .get_unchecked_mut(EXAMPLE_IMPORTANCE_OFFSET) = FLOAT32_ONE;
                 } else {
                     // this token does not start with "|", so it has to be example importance floating point
                     i_start = i_end;
-                    while *p.add(i_end) != 0x20 && i_end < rowlen {
-                        i_end += 1;
-                    } // find end of token (space)
+                    i_end = self.delimiters.next_space(&self.tmp_read_buf, i_end); // find end of token (space)
                     let importance = self.parse_float_or_error(
                         i_start,
                         i_end,
@@ -308,12 +833,26 @@ impl VowpalParser {
                         .get_unchecked_mut(EXAMPLE_IMPORTANCE_OFFSET) = importance.to_bits();
                 }
             }
-            // Then we look for first namespace
+            // Then we look for first namespace, whatever is in front of it is a tag
+            tag_start = i_end;
             while *p.add(i_end) != 0x7c && i_end < rowlen {
                 i_end += 1;
             }
+            tag_end = i_end;
+            while tag_start < tag_end && *p.add(tag_start) == 0x20 {
+                tag_start += 1;
+            }
+            if tag_start < tag_end && *p.add(tag_start) == 0x27 {
+                // vowpal tags are optionally prefixed with "'"
+                tag_start += 1;
+            }
+            while tag_start < tag_end && *p.add(tag_end - 1) == 0x20 {
+                tag_end -= 1;
+            }
 
             let mut current_namespace_hash_seed: u32 = 0;
+            let mut current_namespace_index: usize = 0;
+            let mut current_namespace_hierarchical = false;
             let mut current_namespace_index_offset: usize = HEADER_LEN as usize;
             let mut current_namespace_format = vwmap::NamespaceFormat::Categorical;
 
@@ -327,13 +866,9 @@ impl VowpalParser {
                     i_end += 1;
                 }
                 i_start = i_end;
-                while *p.add(i_end) != 0x20 && *p.add(i_end) != 0x3a && i_end < rowlen {
-                    i_end += 1;
-                } // 0x3a = ":"
+                i_end = self.delimiters.next_space_or_colon(i_end); // 0x3a = ":"
                 let i_end_first_part = i_end;
-                while *p.add(i_end) != 0x20 && i_end < rowlen {
-                    i_end += 1;
-                }
+                i_end = self.delimiters.next_space(&self.tmp_read_buf, i_end);
 
                 if *p.add(i_start) == 0x7c {
                     // "|"
@@ -369,20 +904,44 @@ impl VowpalParser {
                         };
                     let current_namespace_descriptor =
                         current_namespace_descriptor_with_hash.descriptor;
-                    let current_namespace_index =
-                        current_namespace_descriptor.namespace_index as usize;
+                    current_namespace_index = current_namespace_descriptor.namespace_index as usize;
+                    current_namespace_hierarchical = self
+                        .namespace_hierarchy_weights
+                        .get_unchecked(current_namespace_index)
+                        .is_some();
                     current_namespace_hash_seed = current_namespace_descriptor_with_hash.hash_seed;
                     current_namespace_index_offset =
                         current_namespace_index * NAMESPACE_DESC_LEN as usize + HEADER_LEN as usize;
                     current_namespace_format = current_namespace_descriptor.namespace_format;
                     current_namespace_num_of_features = 0;
                     bufpos_namespace_start = self.output_buffer.len(); // this is only used if we will have multiple values
-                } else {
-                    // We have a feature! Let's hash it and write it to the buffer
-                    let h = murmur3::hash32_with_seed(
-                        self.tmp_read_buf.get_unchecked(i_start..i_end_first_part),
+                } else if current_namespace_format == vwmap::NamespaceFormat::F32Vec {
+                    if current_namespace_num_of_features != 0
+                        || current_namespace_weight != 1.0
+                        || i_end_first_part != i_end
+                    {
+                        return Err(Box::new(IOError::new(ErrorKind::Other, "Vector namespaces have exactly one feature, the comma separated values, and they can not have weights".to_string())));
+                    }
+                    self.push_vector_features(
+                        i_start,
+                        i_end,
                         current_namespace_hash_seed,
-                    ) & MASK31;
+                        current_namespace_index_offset,
+                        bufpos_namespace_start,
+                    )?;
+                    current_namespace_num_of_features += 1;
+                } else {
+                    // We have a feature! Let's write it to the buffer, the hash of it comes later
+                    let hashed = current_namespace_format != vwmap::NamespaceFormat::Passthrough;
+                    let h = if hashed {
+                        0
+                    } else {
+                        self.parse_passthrough_or_error(
+                            i_start,
+                            i_end_first_part,
+                            current_namespace_hash_seed,
+                        )?
+                    };
 
                     let feature_weight: f32 = if i_end_first_part != i_end {
                         // Non-empty part after ":" is namespace weight
@@ -400,10 +959,26 @@ impl VowpalParser {
                     // - if it's second feature and first one was "simple", then promote it
                     // -- and then just add feature to the end of the buffer
                     if current_namespace_num_of_features == 0
-                        && current_namespace_format == vwmap::NamespaceFormat::Categorical
+                        && current_namespace_format != vwmap::NamespaceFormat::F32
                         && current_namespace_weight == 1.0
                         && feature_weight == 1.0
                     {
+                        if hashed {
+                            if *self
+                                .output_buffer
+                                .get_unchecked(current_namespace_index_offset)
+                                != NO_FEATURES
+                            {
+                                // The namespace repeats on the line, its earlier hashes must not land here later
+                                self.hash_pending_features();
+                            }
+                            self.defer_hash(
+                                current_namespace_index_offset,
+                                i_start,
+                                i_end_first_part,
+                                current_namespace_hash_seed,
+                            );
+                        }
                         *self
                             .output_buffer
                             .get_unchecked_mut(current_namespace_index_offset) = h;
@@ -415,11 +990,18 @@ impl VowpalParser {
                             && (feature_output & IS_NOT_SINGLE_MASK) == 0
                         {
                             // We need to promote feature currently written in-place to out of place
-                            self.output_buffer.push(feature_output);
-                            self.output_buffer.push(FLOAT32_ONE);
-                            debug_assert_eq!(
-                                current_namespace_format,
-                                vwmap::NamespaceFormat::Categorical
+                            self.promote_in_place_feature(
+                                feature_output,
+                                current_namespace_index_offset,
+                            );
+                            debug_assert_ne!(current_namespace_format, vwmap::NamespaceFormat::F32);
+                        }
+                        if hashed {
+                            self.defer_hash(
+                                self.output_buffer.len(),
+                                i_start,
+                                i_end_first_part,
+                                current_namespace_hash_seed,
                             );
                         }
                         self.output_buffer.push(h);
@@ -436,6 +1018,13 @@ impl VowpalParser {
                             } else {
                                 f32::NAN
                             };
+                            let float_value = match self
+                                .namespace_f32_scalings
+                                .get_unchecked(current_namespace_index)
+                            {
+                                Some(scaling) => scaling.apply(float_value),
+                                None => float_value,
+                            };
                             self.output_buffer.push(float_value.to_bits());
                             if current_namespace_weight * feature_weight != 1.0 {
                                 return Err(Box::new(IOError::new(ErrorKind::Other, "Namespaces that are f32 can not have weight attached neither to namespace nor to a single feature (basically they can\' use :weight syntax".to_string())));
@@ -450,10 +1039,39 @@ impl VowpalParser {
                             | (((bufpos_namespace_start << 16) + self.output_buffer.len()) as u32);
                     }
                     current_namespace_num_of_features += 1;
+                    if current_namespace_hierarchical {
+                        self.push_ancestor_features(
+                            i_start,
+                            i_end_first_part,
+                            current_namespace_weight * feature_weight,
+                            current_namespace_index,
+                            current_namespace_hash_seed,
+                            current_namespace_index_offset,
+                            bufpos_namespace_start,
+                            &mut current_namespace_num_of_features,
+                        );
+                    }
                 }
                 i_end += 1;
             }
         }
+        self.hash_pending_features();
+
+        if !self.head_labels.is_empty() {
+            self.output_buffer.extend_from_slice(&self.head_labels);
+            self.output_buffer.push(self.head_labels.len() as u32);
+            self.output_buffer[LABEL_OFFSET] |= LABEL_HAS_HEADS_MASK;
+        }
+
+        if tag_start < tag_end {
+            for chunk in self.tmp_read_buf[tag_start..tag_end].chunks(4) {
+                let mut word = [0u8; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                self.output_buffer.push(u32::from_le_bytes(word));
+            }
+            self.output_buffer.push((tag_end - tag_start) as u32);
+            self.output_buffer[LABEL_OFFSET] |= LABEL_HAS_TAG_MASK;
+        }
 
         //            println!("item out {:?} {}", self.output_buffer, bufpos);
         self.output_buffer[0] = self.output_buffer.len() as u32;
@@ -461,6 +1079,63 @@ impl VowpalParser {
     }
 }
 
+// Hash of a feature of a hashed namespace, the same as the parser computes it
+pub fn feature_hash(namespace_vwname: &str, feature: &[u8]) -> u32 {
+    murmur3::hash32_with_seed(feature, murmur3::hash32(namespace_vwname)) & MASK31
+}
+
+// The parser keeps any class number, as caches are built before the model is known. Models learn
+// only from 1 and -1, or with --oaa from class numbers up to the number of classes
+pub fn check_label(record_buffer: &[u32], oaa: u32) -> Result<(), Box<dyn Error>> {
+    let label = record_buffer[LABEL_OFFSET] & !LABEL_FLAGS_MASK;
+    if label == NO_LABEL {
+        return Ok(());
+    }
+    if oaa == 0 && label > 1 {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!("Label {} has to be 1 or -1, class labels need --oaa", label),
+        )));
+    }
+    if oaa > 0 && label > oaa {
+        return Err(Box::new(IOError::new(
+            ErrorKind::Other,
+            format!("Class label {} is over --oaa {}", label, oaa),
+        )));
+    }
+    Ok(())
+}
+
+// Labels of the heads after the first, stored by the parser in front of the tag. NO_LABEL for a head
+// without a label, and none when the example line has a single label
+pub fn get_head_labels(record_buffer: &[u32]) -> &[u32] {
+    if record_buffer[LABEL_OFFSET] & LABEL_HAS_HEADS_MASK == 0 {
+        return &[];
+    }
+    let mut end = record_buffer[0] as usize;
+    if record_buffer[LABEL_OFFSET] & LABEL_HAS_TAG_MASK != 0 {
+        end -= 1 + (record_buffer[end - 1] as usize + 3) / 4;
+    }
+    let count = record_buffer[end - 1] as usize;
+    &record_buffer[end - 1 - count..end - 1]
+}
+
+// Returns the tag stored at the end of the record by the parser, if there is one
+pub fn get_tag(record_buffer: &[u32]) -> Option<Vec<u8>> {
+    if record_buffer[LABEL_OFFSET] & LABEL_HAS_TAG_MASK == 0 {
+        return None;
+    }
+    let record_len = record_buffer[0] as usize;
+    let tag_len = record_buffer[record_len - 1] as usize;
+    let tag_start = record_len - 1 - (tag_len + 3) / 4;
+    let mut tag: Vec<u8> = record_buffer[tag_start..record_len - 1]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    tag.truncate(tag_len);
+    Some(tag)
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -741,6 +1416,37 @@ C,featureC
             ]
         );
 
+        // class numbers of --oaa
+        let mut buf = str_to_cursor("12 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap()[..3], [6, 12, FLOAT32_ONE]);
+        let mut buf = str_to_cursor("7 2.0 |A a\n");
+        assert_eq!(
+            rr.next_vowpal(&mut buf).unwrap()[..3],
+            [6, 7, 2.0f32.to_bits()]
+        );
+        let mut buf = str_to_cursor("255 |A a\n");
+        assert!(rr.next_vowpal(&mut buf).is_err());
+
+        // Class numbers are rejected without --oaa and over it
+        let mut buf = str_to_cursor("2 |A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap().to_vec();
+        assert_eq!(
+            check_label(&record, 0).unwrap_err().to_string(),
+            "Label 2 has to be 1 or -1, class labels need --oaa"
+        );
+        assert!(check_label(&record, 2).is_ok());
+        let mut buf = str_to_cursor("12 |A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap().to_vec();
+        assert_eq!(
+            check_label(&record, 3).unwrap_err().to_string(),
+            "Class label 12 is over --oaa 3"
+        );
+        for line in ["1 |A a\n", "-1 |A a\n", "|A a\n"].iter() {
+            let mut buf = str_to_cursor(line);
+            let record = rr.next_vowpal(&mut buf).unwrap().to_vec();
+            assert!(check_label(&record, 0).is_ok());
+        }
+
         /* Should we support this ?
         let mut buf = str_to_cursor(" |A a\n");
         assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, NO_LABEL, FLOAT32_ONE,
@@ -817,6 +1523,41 @@ C,featureC
         let mut buf = str_to_cursor("flush");
         assert!(rr.next_vowpal(&mut buf).err().unwrap().is::<FlushCommand>());
 
+        let mut buf = str_to_cursor("stats\n");
+        assert!(rr.next_vowpal(&mut buf).err().unwrap().is::<StatsCommand>());
+
+        let mut buf = str_to_cursor("ffm_grow\n");
+        assert!(rr
+            .next_vowpal(&mut buf)
+            .err()
+            .unwrap()
+            .is::<FfmGrowCommand>());
+
+        let mut buf = str_to_cursor("debug 1 |A a\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        let debug_command = result.downcast_ref::<DebugCommand>().unwrap();
+        let mut buf = str_to_cursor("1 |A a\n");
+        assert_eq!(debug_command.record, rr.next_vowpal(&mut buf).unwrap());
+
+        let mut buf = str_to_cursor("explain |A a\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        let explain_command = result.downcast_ref::<ExplainCommand>().unwrap();
+        let mut buf = str_to_cursor("|A a\n");
+        assert_eq!(explain_command.record, rr.next_vowpal(&mut buf).unwrap());
+
+        let mut buf = str_to_cursor("outputs |A a\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        let outputs_command = result.downcast_ref::<OutputsCommand>().unwrap();
+        let mut buf = str_to_cursor("|A a\n");
+        assert_eq!(outputs_command.record, rr.next_vowpal(&mut buf).unwrap());
+
+        let mut buf = str_to_cursor("batch 12\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        assert_eq!(result.downcast_ref::<BatchCommand>().unwrap().size, 12);
+        let mut buf = str_to_cursor("batch twelve\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        assert_eq!(result.to_string(), "Cannot parse the size of a batch");
+
         // flush should return FlushCommand
         let mut buf = str_to_cursor("hogwild_load /path/to/filename");
         let result = rr.next_vowpal(&mut buf).err().unwrap();
@@ -838,6 +1579,23 @@ C,featureC
         let hogwild_command = result.downcast_ref::<HogwildLoadCommand>().unwrap();
         assert_eq!(hogwild_command.filename, "/path/to/filename");
 
+        let mut buf = str_to_cursor("model_load /path/to/model.fw\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        let model_load_command = result.downcast_ref::<ModelLoadCommand>().unwrap();
+        assert_eq!(model_load_command.filename, "/path/to/model.fw");
+
+        let mut buf = str_to_cursor("model_version\n");
+        assert!(rr
+            .next_vowpal(&mut buf)
+            .err()
+            .unwrap()
+            .is::<ModelVersionCommand>());
+
+        let mut buf = str_to_cursor("evaluate /path/to/holdout.vw\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        let evaluate_command = result.downcast_ref::<EvaluateCommand>().unwrap();
+        assert_eq!(evaluate_command.filename, "/path/to/holdout.vw");
+
         // Check for two pathological cases - command without space, and command with a space but no file
         let mut buf = str_to_cursor("hogwild_load");
         let result = rr.next_vowpal(&mut buf);
@@ -1060,6 +1818,138 @@ CC,featureC
         );
     }
 
+    #[test]
+    fn test_tags() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+
+        fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+            Cursor::new(s.as_bytes().to_vec())
+        }
+
+        let mut rr = VowpalParser::new(&vw);
+        // no tag - record is unchanged
+        let mut buf = str_to_cursor("1 |A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(record.len(), 5);
+        assert_eq!(get_tag(record), None);
+
+        // tag directly in front of the namespace
+        let mut buf = str_to_cursor("1 'tag1|A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(
+            record,
+            [
+                7,
+                1 | LABEL_HAS_TAG_MASK,
+                FLOAT32_ONE,
+                2988156968 & MASK31,
+                NO_FEATURES,
+                u32::from_le_bytes(*b"tag1"),
+                4
+            ]
+        );
+        assert_eq!(get_tag(record), Some(b"tag1".to_vec()));
+
+        // tag after importance, no quote
+        let mut buf = str_to_cursor("-1 0.5 tag_number_2 |A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(record[LABEL_OFFSET], LABEL_HAS_TAG_MASK);
+        assert_eq!(record[EXAMPLE_IMPORTANCE_OFFSET], 0.5f32.to_bits());
+        assert_eq!(get_tag(record), Some(b"tag_number_2".to_vec()));
+    }
+
+    #[test]
+    fn test_head_labels() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut rr = VowpalParser::new(&vw);
+
+        // labels of the heads follow the features, with their count
+        let record = rr.next_vowpal_from_bytes(b"1,-1,,1 |A a\n").unwrap();
+        assert_eq!(
+            record,
+            [
+                9,
+                1 | LABEL_HAS_HEADS_MASK,
+                FLOAT32_ONE,
+                2988156968 & MASK31,
+                NO_FEATURES,
+                0,
+                NO_LABEL,
+                1,
+                3
+            ]
+        );
+        assert_eq!(get_head_labels(record), [0, NO_LABEL, 1]);
+
+        // and are in front of the tag
+        let record = rr.next_vowpal_from_bytes(b"-1, 0.5 'tag1|A a\n").unwrap();
+        assert_eq!(
+            record[LABEL_OFFSET],
+            LABEL_HAS_TAG_MASK | LABEL_HAS_HEADS_MASK
+        );
+        assert_eq!(record[EXAMPLE_IMPORTANCE_OFFSET], 0.5f32.to_bits());
+        assert_eq!(get_head_labels(record), [NO_LABEL]);
+        assert_eq!(get_tag(record), Some(b"tag1".to_vec()));
+
+        let record = rr.next_vowpal_from_bytes(b"1 |A a\n").unwrap();
+        assert!(get_head_labels(record).is_empty());
+        assert!(rr.next_vowpal_from_bytes(b"1,2 |A a\n").is_err());
+    }
+
+    #[test]
+    fn test_passthrough_namespaces() {
+        let vw_map_string = r#"
+A,featureA,passthrough
+B,featureB,passthrough,1000
+C,featureC
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+
+        fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+            Cursor::new(s.as_bytes().to_vec())
+        }
+
+        let mut rr = VowpalParser::new(&vw);
+        // single value is used directly as the index, base is added
+        let mut buf = str_to_cursor("1 |A 7 |B 3\n");
+        assert_eq!(
+            rr.next_vowpal(&mut buf).unwrap(),
+            [6, 1, FLOAT32_ONE, 7, 1003, NO_FEATURES]
+        );
+
+        // multiple values and weights behave as in categorical namespaces
+        let mut buf = str_to_cursor("1 |B 3 4:2.0\n");
+        assert_eq!(
+            rr.next_vowpal(&mut buf).unwrap(),
+            [
+                10,
+                1,
+                FLOAT32_ONE,
+                NO_FEATURES,
+                nd(6, 10) | IS_NOT_SINGLE_MASK,
+                NO_FEATURES,
+                1003,
+                FLOAT32_ONE,
+                1004,
+                2.0f32.to_bits()
+            ]
+        );
+
+        // non-integer values are an error
+        let mut buf = str_to_cursor("1 |A a\n");
+        assert!(rr.next_vowpal(&mut buf).is_err());
+        let mut buf = str_to_cursor("1 |A -1\n");
+        assert!(rr.next_vowpal(&mut buf).is_err());
+    }
+
     #[test]
     fn test_cache() {
         // Test for perfect vowpal-compatible hashing
@@ -1102,6 +1992,178 @@ CC,featureC
         );
     }
 
+    #[test]
+    fn test_from_bytes() {
+        let vw = vwmap::VwNamespaceMap::new("AA,featureA\nBB,featureB\nCC,featureC\n").unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        let mut buf = Cursor::new(b"|BB b |AA:3 a:2.0 \n".to_vec());
+        let expected = rr.next_vowpal(&mut buf).unwrap().to_vec();
+        assert_eq!(
+            rr.next_vowpal_from_bytes(b"|BB b |AA:3 a:2.0 \n").unwrap(),
+            expected.as_slice()
+        );
+        // Trailing newline is optional
+        assert_eq!(
+            rr.next_vowpal_from_bytes(b"|BB b |AA:3 a:2.0 ").unwrap(),
+            expected.as_slice()
+        );
+        assert_eq!(rr.next_vowpal_from_bytes(b"").unwrap(), &[] as &[u32]);
+
+        let mut buf = Cursor::new(b"|BB b |AA:3 a:2.0 \n".to_vec());
+        let (record, line) = rr.next_vowpal_with_line(&mut buf).unwrap();
+        assert_eq!(record, expected.as_slice());
+        assert_eq!(line, b"|BB b |AA:3 a:2.0 \n");
+        assert_eq!(
+            rr.next_vowpal_with_line(&mut buf).unwrap(),
+            (&[] as &[u32], &[] as &[u8])
+        );
+    }
+
+    #[test]
+    fn test_hierarchical_namespaces() {
+        let vw_hierarchical =
+            vwmap::VwNamespaceMap::new("A,featureA\nC,featureC,hierarchical,0.25:0.5\n").unwrap();
+        let vw_flat = vwmap::VwNamespaceMap::new("A,featureA\nC,featureC\n").unwrap();
+        let mut rr = VowpalParser::new(&vw_hierarchical);
+        let mut rr_flat = VowpalParser::new(&vw_flat);
+
+        // Ancestors are emitted after the value, deeper levels than listed use the last weight
+        assert_eq!(
+            rr.next_vowpal_from_bytes(b"1 |C sports/football/premier_league/top |A a\n")
+                .unwrap(),
+            rr_flat
+                .next_vowpal_from_bytes(
+                    b"1 |C sports/football/premier_league/top sports:0.25 sports/football:0.5 sports/football/premier_league:0.5 |A a\n"
+                )
+                .unwrap()
+        );
+        // Weights of the namespace and of the feature apply to ancestors too
+        assert_eq!(
+            rr.next_vowpal_from_bytes(b"1 |C:2 sports/football:3 news\n")
+                .unwrap(),
+            rr_flat
+                .next_vowpal_from_bytes(b"1 |C:2 sports/football:3 sports:0.75 news\n")
+                .unwrap()
+        );
+        // Values without separator behave as in a normal namespace
+        assert_eq!(
+            rr.next_vowpal_from_bytes(b"1 |C sports\n").unwrap(),
+            rr_flat.next_vowpal_from_bytes(b"1 |C sports\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_batched_hashes() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut rr = VowpalParser::new(&vw);
+
+        // More features than a batch, the first one moves out of place after its hash was deferred
+        let features: Vec<&str> = "a bb ccc dddd eeeee f gg hhhhhhhhh i j"
+            .split(' ')
+            .collect();
+        let line = format!("1 |A {} |B x\n", features.join(" "));
+        let mut expected = vec![
+            0,
+            1,
+            FLOAT32_ONE,
+            IS_NOT_SINGLE_MASK | nd(5, 25),
+            feature_hash("B", b"x"),
+        ];
+        for feature in features.iter() {
+            expected.push(feature_hash("A", feature.as_bytes()));
+            expected.push(FLOAT32_ONE);
+        }
+        expected[0] = expected.len() as u32;
+        assert_eq!(
+            rr.next_vowpal_from_bytes(line.as_bytes()).unwrap(),
+            expected
+        );
+
+        // A namespace that repeats on the line replaces the earlier one
+        let repeated = rr
+            .next_vowpal_from_bytes(b"1 |A a |B x |A y z\n")
+            .unwrap()
+            .to_vec();
+        assert_eq!(
+            repeated,
+            rr.next_vowpal_from_bytes(b"1 |B x |A y z\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_delimiters() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        let compact = rr
+            .next_vowpal_from_bytes(b"1 0.5 |A:2 aaaa:3 bb ccccccccccccccccccc |B x:0.5 y\n")
+            .unwrap()
+            .to_vec();
+        // Runs of spaces and tokens across the 16 byte chunks of the scan
+        assert_eq!(
+            rr.next_vowpal_from_bytes(
+                b"1    0.5              |A:2  aaaa:3                 bb ccccccccccccccccccc |B x:0.5      y\n"
+            )
+            .unwrap(),
+            compact
+        );
+        // Without a newline, the last byte of the line is taken for it and dropped
+        assert_eq!(
+            rr.next_vowpal_from_bytes(b"1 0.5 |A:2 aaaa:3 bb ccccccccccccccccccc |B x:0.5 y z")
+                .unwrap(),
+            compact
+        );
+    }
+
+    #[test]
+    fn test_f32vec_namespace() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA,f32vec:3\nB,featureB\n").unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        assert_eq!(
+            rr.next_vowpal_from_bytes(b"1 |A 0.5,-1,NONE |B b\n")
+                .unwrap(),
+            [
+                11,
+                1,
+                FLOAT32_ONE,
+                nd(5, 11) | IS_NOT_SINGLE_MASK,
+                murmur3::hash32_with_seed(b"b", murmur3::hash32("B")) & MASK31,
+                0,
+                0.5f32.to_bits(),
+                1,
+                (-1.0f32).to_bits(),
+                2,
+                f32::NAN.to_bits(),
+            ]
+        );
+        // Missing vector has no features
+        assert_eq!(
+            rr.next_vowpal_from_bytes(b"1 |B b\n").unwrap()[HEADER_LEN as usize],
+            NO_FEATURES
+        );
+        assert!(rr.next_vowpal_from_bytes(b"1 |A 0.5,1\n").is_err());
+        assert!(rr.next_vowpal_from_bytes(b"1 |A 0.5,1,2,3\n").is_err());
+        assert!(rr.next_vowpal_from_bytes(b"1 |A 0.5,1,2,\n").is_err());
+        assert!(rr.next_vowpal_from_bytes(b"1 |A 0.5,1,x\n").is_err());
+        assert!(rr.next_vowpal_from_bytes(b"1 |A 0.5,1,2 3,4,5\n").is_err());
+        assert!(rr.next_vowpal_from_bytes(b"1 |A:2 0.5,1,2\n").is_err());
+    }
+
+    #[test]
+    fn test_f32_scaling_namespace() {
+        let vw =
+            vwmap::VwNamespaceMap::new("A,featureA,f32,div:1000\nB,featureB,f32,minmax:10:20\n")
+                .unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        let record = rr.next_vowpal_from_bytes(b"1 |A 1500 |B 15\n").unwrap();
+        assert_eq!(f32::from_bits(record[6]), 1.5);
+        assert_eq!(f32::from_bits(record[8]), 0.5);
+        // Hashes are of the unscaled values
+        assert_eq!(
+            record[5],
+            murmur3::hash32_with_seed(b"1500", murmur3::hash32("A")) & MASK31
+        );
+    }
+
     #[test]
     fn test_cache_with_fully_cached_request() {
         // Test for perfect vowpal-compatible hashing
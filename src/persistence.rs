@@ -2,12 +2,17 @@ use std::error::Error;
 use std::str;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::{CrcReader, CrcWriter};
+use std::fmt;
 use std::fs;
-use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::io::{Seek, SeekFrom};
+use std::thread;
 
+use crate::blend;
 use crate::model_instance;
+use crate::prediction_log;
 use crate::regressor;
 use crate::vwmap;
 
@@ -15,40 +20,161 @@ use crate::multithread_helpers::BoxedRegressorTrait;
 use crate::regressor::Regressor;
 
 const REGRESSOR_HEADER_MAGIC_STRING: &[u8; 4] = b"FWRE"; // Fwumious Wabbit REgressor
-const REGRESSOR_HEADER_VERSION: u32 = 6; // Change to 5: introduce namespace descriptors which changes regressor
+const REGRESSOR_HEADER_VERSION: u32 = 12; // Change to 12: FFM weights can be laid out field-major, see --ffm_layout
+
+// Oldest version that can still be read, its weights are not framed by block
+const REGRESSOR_HEADER_OLDEST_VERSION: u32 = 6;
+const REGRESSOR_FRAMED_VERSION: u32 = 7;
+const REGRESSOR_LAYOUT_VERSION: u32 = 8;
+const REGRESSOR_CHECKSUM_VERSION: u32 = 9;
+const REGRESSOR_WEIGHTS_ENCODING_VERSION: u32 = 10;
+
+// How the weights following the model instance are stored
+const WEIGHTS_ENCODING_RAW: u32 = 0;
+const WEIGHTS_ENCODING_ZSTD: u32 = 1;
+
+struct RegressorHeader {
+    version: u32,
+    layout: Option<BlockLayout>,
+    weights_encoding: u32,
+}
+
+// Model files that were damaged after they were written, e.g. truncated downloads
+#[derive(Debug, PartialEq)]
+pub enum ModelFileError {
+    Truncated {
+        filename: String,
+    },
+    ChecksumMismatch {
+        filename: String,
+        stored: u32,
+        computed: u32,
+    },
+}
+
+impl Error for ModelFileError {}
+impl fmt::Display for ModelFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelFileError::Truncated { filename } => {
+                write!(
+                    f,
+                    "Regressor file {} is truncated, it has no weights checksum",
+                    filename
+                )
+            }
+            ModelFileError::ChecksumMismatch {
+                filename,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "Weights of regressor file {} are corrupted, stored checksum {:08x}, computed {:08x}",
+                filename, stored, computed
+            ),
+        }
+    }
+}
+
+// Serialized blocks of a regressor in order, as (block type id, number of weights). Written into
+// the header, so a model whose blocks this binary builds differently is refused with a clear
+// error instead of having its weights read into wrong places.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockLayout {
+    pub blocks: Vec<(u32, u64)>,
+}
+
+impl BlockLayout {
+    pub fn new(re: &Regressor) -> BlockLayout {
+        BlockLayout {
+            blocks: re
+                .blocks_boxes
+                .iter()
+                .filter(|block| block.get_serialized_len() > 0)
+                .map(|block| {
+                    (
+                        block.get_serialized_block_id(),
+                        block.get_serialized_len() as u64,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn write(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+        output_bufwriter.write_u32::<LittleEndian>(self.blocks.len() as u32)?;
+        for (block_id, len) in self.blocks.iter() {
+            output_bufwriter.write_u32::<LittleEndian>(*block_id)?;
+            output_bufwriter.write_u64::<LittleEndian>(*len)?;
+        }
+        Ok(())
+    }
+
+    fn read(input_bufreader: &mut dyn io::Read) -> Result<BlockLayout, Box<dyn Error>> {
+        let num_blocks = input_bufreader.read_u32::<LittleEndian>()?;
+        let mut blocks: Vec<(u32, u64)> = Vec::new();
+        for _ in 0..num_blocks {
+            let block_id = input_bufreader.read_u32::<LittleEndian>()?;
+            let len = input_bufreader.read_u64::<LittleEndian>()?;
+            blocks.push((block_id, len));
+        }
+        Ok(BlockLayout { blocks })
+    }
+
+    pub fn verify(&self, expected: &BlockLayout) -> Result<(), Box<dyn Error>> {
+        if self != expected {
+            return Err(format!(
+                "Regressor blocks in the file ({}) are not the ones this binary builds from its model instance ({})",
+                self, expected
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for BlockLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let blocks: Vec<String> = self
+            .blocks
+            .iter()
+            .map(|(block_id, len)| format!("{}:{}", block_id, len))
+            .collect();
+        write!(f, "block id:weights {}", blocks.join(" "))
+    }
+}
 
 impl model_instance::ModelInstance {
     pub fn save_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
-	let serialized = serde_json::to_vec_pretty(&self)?;
-	output_bufwriter.write_u64::<LittleEndian>(serialized.len() as u64)?;
-	output_bufwriter.write_all(&serialized)?;
-	Ok(())
+        let serialized = serde_json::to_vec_pretty(&self)?;
+        output_bufwriter.write_u64::<LittleEndian>(serialized.len() as u64)?;
+        output_bufwriter.write_all(&serialized)?;
+        Ok(())
     }
     pub fn new_from_buf(
-	input_bufreader: &mut dyn io::Read,
+        input_bufreader: &mut dyn io::Read,
     ) -> Result<model_instance::ModelInstance, Box<dyn Error>> {
-	let len = input_bufreader.read_u64::<LittleEndian>()?;
-	let mi: model_instance::ModelInstance = serde_json::from_reader(input_bufreader.take(len))?;
-	Ok(mi)
+        let len = input_bufreader.read_u64::<LittleEndian>()?;
+        let mi: model_instance::ModelInstance = serde_json::from_reader(input_bufreader.take(len))?;
+        Ok(mi)
     }
 }
 
 impl vwmap::VwNamespaceMap {
     pub fn save_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
-	let serialized = serde_json::to_vec_pretty(&self.vw_source)?;
-	output_bufwriter.write_u64::<LittleEndian>(serialized.len() as u64)?;
-	output_bufwriter.write_all(&serialized)?;
-	Ok(())
+        let serialized = serde_json::to_vec_pretty(&self.vw_source)?;
+        output_bufwriter.write_u64::<LittleEndian>(serialized.len() as u64)?;
+        output_bufwriter.write_all(&serialized)?;
+        Ok(())
     }
 
     pub fn new_from_buf(
-	input_bufreader: &mut dyn io::Read,
+        input_bufreader: &mut dyn io::Read,
     ) -> Result<vwmap::VwNamespaceMap, Box<dyn Error>> {
-	let len = input_bufreader.read_u64::<LittleEndian>()?;
-	let vw_source: vwmap::VwNamespaceMapSource =
-	    serde_json::from_reader(input_bufreader.take(len))?;
-	let vw = vwmap::VwNamespaceMap::new_from_source(vw_source)?;
-	Ok(vw)
+        let len = input_bufreader.read_u64::<LittleEndian>()?;
+        let vw_source: vwmap::VwNamespaceMapSource =
+            serde_json::from_reader(input_bufreader.take(len))?;
+        let vw = vwmap::VwNamespaceMap::new_from_source(vw_source)?;
+        Ok(vw)
     }
 }
 
@@ -60,13 +186,15 @@ pub fn save_sharable_regressor_to_filename(
     quantize_weights: bool,
 ) -> Result<(), Box<dyn Error>> {
     let output_bufwriter = &mut io::BufWriter::new(
-	fs::File::create(filename)
-	    .unwrap_or_else(|_| panic!("Cannot open {} to save regressor to", filename)),
+        fs::File::create(filename)
+            .unwrap_or_else(|_| panic!("Cannot open {} to save regressor to", filename)),
     );
-    write_regressor_header(output_bufwriter)?;
+    write_regressor_header(output_bufwriter, &BlockLayout::new(&re), mi)?;
     vwmap.save_to_buf(output_bufwriter)?;
     mi.save_to_buf(output_bufwriter)?;
-    re.write_weights_to_buf(output_bufwriter, quantize_weights)?;
+    write_weights_with_checksum(output_bufwriter, mi, |writer| {
+        re.write_weights_to_buf(writer, quantize_weights)
+    })?;
     Ok(())
 }
 
@@ -78,50 +206,304 @@ pub fn save_regressor_to_filename(
     quantize_weights: bool,
 ) -> Result<(), Box<dyn Error>> {
     let output_bufwriter = &mut io::BufWriter::new(
-	fs::File::create(filename)
-	    .unwrap_or_else(|_| panic!("Cannot open {} to save regressor to", filename)),
+        fs::File::create(filename)
+            .unwrap_or_else(|_| panic!("Cannot open {} to save regressor to", filename)),
     );
-    write_regressor_header(output_bufwriter)?;
+    write_regressor_header(output_bufwriter, &BlockLayout::new(&re), mi)?;
+    vwmap.save_to_buf(output_bufwriter)?;
+    mi.save_to_buf(output_bufwriter)?;
+    write_weights_with_checksum(output_bufwriter, mi, |writer| {
+        re.write_weights_to_buf(writer, quantize_weights)
+    })?;
+    Ok(())
+}
+
+// For tools that produce weights directly in serialized form, e.g. model blending
+pub fn save_weights_buf_to_filename(
+    filename: &str,
+    mi: &model_instance::ModelInstance,
+    vwmap: &vwmap::VwNamespaceMap,
+    weights_buf: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let output_bufwriter = &mut io::BufWriter::new(fs::File::create(filename)?);
+    let layout = BlockLayout::new(&regressor::get_regressor_without_weights(mi));
+    write_regressor_header(output_bufwriter, &layout, mi)?;
     vwmap.save_to_buf(output_bufwriter)?;
     mi.save_to_buf(output_bufwriter)?;
-    re.write_weights_to_buf(output_bufwriter, quantize_weights)?;
+    write_weights_with_checksum(output_bufwriter, mi, |writer| {
+        writer.write_all(weights_buf)?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+// Periodic checkpoints of big models should not stall learning while they hit the disk.
+// The snapshot is serialized to memory on the training thread (a consistent copy that only costs
+// a memcpy), and written out on a separate thread. There is at most one write in flight; its
+// buffer is recycled for the next snapshot, so we keep at most two copies of the model around.
+pub struct BackgroundSaver {
+    filename: String,
+    pending_write: Option<thread::JoinHandle<Result<Vec<u8>, io::Error>>>,
+    spare_buffer: Vec<u8>,
+    rotation: Option<CheckpointRotation>,
+}
+
+// With --checkpoint_dir every snapshot goes to a new numbered file, of which the newest keep stay
+struct CheckpointRotation {
+    dir: String,
+    keep: usize,
+    sequence: u64,
+}
+
+// Checkpoints in dir, oldest first
+pub fn checkpoint_files(dir: &str) -> Result<Vec<(u64, String)>, io::Error> {
+    let mut checkpoints: Vec<(u64, String)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let sequence = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("checkpoint-"))
+            .and_then(|name| name.strip_suffix(".fw"))
+            .and_then(|sequence| sequence.parse::<u64>().ok());
+        if let Some(sequence) = sequence {
+            checkpoints.push((sequence, path.to_string_lossy().to_string()));
+        }
+    }
+    checkpoints.sort();
+    Ok(checkpoints)
+}
+
+fn remove_old_checkpoints(dir: &str, keep: usize) -> Result<(), io::Error> {
+    let checkpoints = checkpoint_files(dir)?;
+    for (_, filename) in checkpoints
+        .iter()
+        .take(checkpoints.len().saturating_sub(keep))
+    {
+        fs::remove_file(filename)?;
+    }
     Ok(())
 }
 
-fn write_regressor_header(output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+impl BackgroundSaver {
+    pub fn new(filename: &str) -> BackgroundSaver {
+        BackgroundSaver {
+            filename: filename.to_string(),
+            pending_write: None,
+            spare_buffer: Vec::new(),
+            rotation: None,
+        }
+    }
+
+    pub fn new_rotating(dir: &str, keep: usize) -> Result<BackgroundSaver, Box<dyn Error>> {
+        if keep == 0 {
+            return Err("At least one checkpoint has to be kept")?;
+        }
+        fs::create_dir_all(dir)?;
+        // Numbering continues after the checkpoints of previous runs
+        let sequence = checkpoint_files(dir)?
+            .last()
+            .map_or(0, |(sequence, _)| *sequence);
+        let mut saver = BackgroundSaver::new(dir);
+        saver.rotation = Some(CheckpointRotation {
+            dir: dir.to_string(),
+            keep,
+            sequence,
+        });
+        Ok(saver)
+    }
+
+    pub fn snapshot(
+        &mut self,
+        mi: &model_instance::ModelInstance,
+        vwmap: &vwmap::VwNamespaceMap,
+        re: &Regressor,
+        quantize_weights: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        // If the disk can't keep up with snapshots, training waits instead of piling up copies
+        self.wait_for_pending_write()?;
+        let mut buffer = std::mem::take(&mut self.spare_buffer);
+        buffer.truncate(0);
+        write_regressor_header(&mut buffer, &BlockLayout::new(re), mi)?;
+        vwmap.save_to_buf(&mut buffer)?;
+        mi.save_to_buf(&mut buffer)?;
+        write_weights_with_checksum(&mut buffer, mi, |writer| {
+            re.write_weights_to_buf(writer, quantize_weights)
+        })?;
+
+        if let Some(rotation) = self.rotation.as_mut() {
+            rotation.sequence += 1;
+            self.filename = format!("{}/checkpoint-{:08}.fw", rotation.dir, rotation.sequence);
+        }
+        let remove_old = self
+            .rotation
+            .as_ref()
+            .map(|rotation| (rotation.dir.clone(), rotation.keep));
+
+        // Written under a temporary name and renamed, so readers never see a half-written model
+        let filename = self.filename.clone();
+        let tmp_filename = format!("{}.tmp", self.filename);
+        self.pending_write = Some(thread::spawn(move || {
+            fs::write(&tmp_filename, &buffer)?;
+            fs::rename(&tmp_filename, &filename)?;
+            if let Some((dir, keep)) = remove_old {
+                remove_old_checkpoints(&dir, keep)?;
+            }
+            Ok(buffer)
+        }));
+        Ok(())
+    }
+
+    pub fn wait_for_pending_write(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(handle) = self.pending_write.take() {
+            match handle.join() {
+                Ok(result) => self.spare_buffer = result?,
+                Err(_) => {
+                    return Err(format!(
+                        "Snapshot writer thread for {} panicked",
+                        self.filename
+                    ))?
+                }
+            }
+            log::info!("Snapshot saved to {}", self.filename);
+        }
+        Ok(())
+    }
+}
+
+fn write_regressor_header(
+    output_bufwriter: &mut dyn io::Write,
+    layout: &BlockLayout,
+    mi: &model_instance::ModelInstance,
+) -> Result<(), Box<dyn Error>> {
     // we will write magic string FWFW
-    // And then 32 bit unsigned version of the regressor
+    // And then 32 bit unsigned version of the regressor, the layout of its blocks and how its weights are stored
     output_bufwriter.write_all(REGRESSOR_HEADER_MAGIC_STRING)?;
     output_bufwriter.write_u32::<LittleEndian>(REGRESSOR_HEADER_VERSION)?;
+    layout.write(output_bufwriter)?;
+    let weights_encoding = match mi.compress_model {
+        Some(_) => WEIGHTS_ENCODING_ZSTD,
+        None => WEIGHTS_ENCODING_RAW,
+    };
+    output_bufwriter.write_u32::<LittleEndian>(weights_encoding)?;
+    Ok(())
+}
+
+// The checksum is of the weights as stored, so it is of the compressed ones with --compress_model
+fn write_weights_with_checksum(
+    output_bufwriter: &mut dyn io::Write,
+    mi: &model_instance::ModelInstance,
+    write_weights: impl FnOnce(&mut dyn io::Write) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut crc_writer = CrcWriter::new(&mut *output_bufwriter);
+    match mi.compress_model {
+        Some(level) => {
+            let mut encoder = zstd::Encoder::new(&mut crc_writer, level)?;
+            write_weights(&mut encoder)?;
+            encoder.finish()?;
+        }
+        None => write_weights(&mut crc_writer)?,
+    }
+    let checksum = crc_writer.crc().sum();
+    output_bufwriter.write_u32::<LittleEndian>(checksum)?;
+    Ok(())
+}
+
+fn new_weights_reader<'a, R: io::BufRead + 'a>(
+    input_bufreader: &'a mut R,
+    weights_encoding: u32,
+) -> Result<Box<dyn io::Read + 'a>, Box<dyn Error>> {
+    match weights_encoding {
+        WEIGHTS_ENCODING_RAW => Ok(Box::new(input_bufreader)),
+        // The checksum follows the single zstd frame of the weights
+        WEIGHTS_ENCODING_ZSTD => Ok(Box::new(
+            zstd::Decoder::with_buffer(input_bufreader)?.single_frame(),
+        )),
+        _ => Err(format!(
+            "Unknown encoding of regressor weights: {}",
+            weights_encoding
+        ))?,
+    }
+}
+
+// Weights are followed by their crc32. It is checked in a separate pass before any weights are
+// read, so a corrupted file never gets partially loaded over the weights being served.
+fn verify_weights_checksum<R: io::BufRead + Seek>(
+    input_bufreader: &mut R,
+    filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    let weights_start = input_bufreader.stream_position()?;
+    let file_len = input_bufreader.seek(SeekFrom::End(0))?;
+    input_bufreader.seek(SeekFrom::Start(weights_start))?;
+    if file_len < weights_start + 4 {
+        return Err(ModelFileError::Truncated {
+            filename: filename.to_string(),
+        })?;
+    }
+    let mut crc_reader = CrcReader::new((&mut *input_bufreader).take(file_len - weights_start - 4));
+    io::copy(&mut crc_reader, &mut io::sink())?;
+    let computed = crc_reader.crc().sum();
+    let stored = input_bufreader.read_u32::<LittleEndian>()?;
+    if stored != computed {
+        return Err(ModelFileError::ChecksumMismatch {
+            filename: filename.to_string(),
+            stored,
+            computed,
+        })?;
+    }
+    input_bufreader.seek(SeekFrom::Start(weights_start))?;
     Ok(())
 }
 
-fn load_regressor_without_weights(
-    input_bufreader: &mut io::BufReader<File>,
+fn load_regressor_without_weights<R: io::BufRead + Seek>(
+    input_bufreader: &mut R,
+    filename: &str,
     cmd_arguments: Option<&clap::ArgMatches>,
+    immutable: bool,
 ) -> Result<
     (
-	model_instance::ModelInstance,
-	vwmap::VwNamespaceMap,
-	regressor::Regressor,
+        model_instance::ModelInstance,
+        vwmap::VwNamespaceMap,
+        regressor::Regressor,
+        RegressorHeader,
     ),
     Box<dyn Error>,
 > {
-    verify_header(input_bufreader).expect("Regressor header error");
+    let header = verify_header(input_bufreader, immutable)?;
+    let version = header.version;
     let vw = vwmap::VwNamespaceMap::new_from_buf(input_bufreader)
-	.expect("Loading vwmap from regressor failed");
+        .expect("Loading vwmap from regressor failed");
 
     let mut mi = model_instance::ModelInstance::new_from_buf(input_bufreader)
-	.expect("Loading model instance from regressor failed");
+        .expect("Loading model instance from regressor failed");
 
     if let Some(cmd_args) = cmd_arguments {
-	model_instance::ModelInstance::update_hyperparameters_from_cmd(cmd_args, &mut mi)?;
+        model_instance::ModelInstance::update_hyperparameters_from_cmd(cmd_args, &mut mi)?;
     }
 
     let mi = mi;
     let re = regressor::get_regressor_without_weights(&mi);
+    match &header.layout {
+        // Newer models may have blocks we don't know, they are skipped when weights are read
+        Some(layout) if version <= REGRESSOR_HEADER_VERSION => {
+            layout.verify(&BlockLayout::new(&re))?
+        }
+        Some(_) => {}
+        None => log::info!(
+            "Regressor file version {} has no block layout, its blocks are not verified",
+            version
+        ),
+    }
+    if version < REGRESSOR_CHECKSUM_VERSION {
+        log::info!(
+            "Regressor file version {} has no weights checksum, its weights are not verified",
+            version
+        );
+    } else if version <= REGRESSOR_HEADER_VERSION {
+        verify_weights_checksum(input_bufreader, filename)?;
+    }
 
-    Ok((mi, vw, re))
+    Ok((mi, vw, re, header))
 }
 
 pub fn new_regressor_from_filename(
@@ -130,14 +512,57 @@ pub fn new_regressor_from_filename(
     cmd_arguments: Option<&clap::ArgMatches>,
 ) -> Result<
     (
-	model_instance::ModelInstance,
-	vwmap::VwNamespaceMap,
-	regressor::Regressor,
+        model_instance::ModelInstance,
+        vwmap::VwNamespaceMap,
+        regressor::Regressor,
     ),
     Box<dyn Error>,
 > {
-    let mut input_bufreader = io::BufReader::new(fs::File::open(filename).unwrap());
-    let (mut mi, vw, mut re) = load_regressor_without_weights(&mut input_bufreader, cmd_arguments)?;
+    let mut input_bufreader = io::BufReader::new(fs::File::open(filename)?);
+    new_regressor_from_reader(&mut input_bufreader, filename, immutable, cmd_arguments)
+}
+
+// For callers that need more from the file than the regressor, filename is only for messages
+pub fn new_regressor_from_bytes(
+    bytes: &[u8],
+    filename: &str,
+    immutable: bool,
+    cmd_arguments: Option<&clap::ArgMatches>,
+) -> Result<
+    (
+        model_instance::ModelInstance,
+        vwmap::VwNamespaceMap,
+        regressor::Regressor,
+    ),
+    Box<dyn Error>,
+> {
+    let mut input_bufreader = io::Cursor::new(bytes);
+    new_regressor_from_reader(&mut input_bufreader, filename, immutable, cmd_arguments)
+}
+
+fn new_regressor_from_reader<R: io::BufRead + Seek>(
+    input_bufreader: &mut R,
+    filename: &str,
+    immutable: bool,
+    cmd_arguments: Option<&clap::ArgMatches>,
+) -> Result<
+    (
+        model_instance::ModelInstance,
+        vwmap::VwNamespaceMap,
+        regressor::Regressor,
+    ),
+    Box<dyn Error>,
+> {
+    let (mut mi, vw, mut re, header) =
+        load_regressor_without_weights(input_bufreader, filename, cmd_arguments, immutable)?;
+    let output_blend_alpha = blend::output_blend_alpha(&mi);
+    if !immutable && output_blend_alpha.is_some() {
+        return Err(format!(
+            "{} is an output blend of two models, it can only be loaded for predictions",
+            filename
+        ))?;
+    }
+    let mut weights_reader = new_weights_reader(input_bufreader, header.weights_encoding)?;
 
     // reading logic is for some reason different, so doing this again here ..
 
@@ -145,61 +570,155 @@ pub fn new_regressor_from_filename(
     let mut conversion_flag = false;
 
     if cmd_arguments.is_some() {
-	quantization_flag = mi.dequantize_weights.unwrap_or(false);
-	conversion_flag = cmd_arguments
-	    .unwrap()
-	    .is_present("convert_inference_regressor");
+        quantization_flag = mi.dequantize_weights.unwrap_or(false);
+        conversion_flag = cmd_arguments
+            .unwrap()
+            .is_present("convert_inference_regressor");
     }
 
     let weight_quantization = quantization_flag && !conversion_flag;
     log::info!(
-	"Reading weights, dequantization enabled: {}",
-	weight_quantization
+        "Reading weights, dequantization enabled: {}",
+        weight_quantization
     );
     if !immutable {
-	re.allocate_and_init_weights(&mi);
-	re.overwrite_weights_from_buf(&mut input_bufreader, weight_quantization)?;
-	Ok((mi, vw, re))
+        re.allocate_and_init_weights(&mi);
+        if header.version < REGRESSOR_FRAMED_VERSION {
+            re.overwrite_weights_from_unframed_buf(&mut weights_reader, weight_quantization)?;
+        } else {
+            re.overwrite_weights_from_buf(&mut weights_reader, weight_quantization)?;
+        }
+        Ok((mi, vw, re))
     } else {
-	mi.optimizer = model_instance::Optimizer::SGD;
-	let mut immutable_re = re.immutable_regressor_without_weights(&mi)?;
-	immutable_re.allocate_and_init_weights(&mi);
-	re.into_immutable_regressor_from_buf(
-	    &mut immutable_re,
-	    &mut input_bufreader,
-	    weight_quantization,
-	)?;
-	Ok((mi, vw, immutable_re))
+        mi.optimizer = model_instance::Optimizer::SGD;
+        mi.lr_optimizer = None;
+        mi.ffm_optimizer = None;
+        mi.nn_optimizer = None;
+        let mut immutable_re = re.immutable_regressor_without_weights(&mi)?;
+        immutable_re.allocate_and_init_weights(&mi);
+        if header.version < REGRESSOR_FRAMED_VERSION {
+            re.into_immutable_regressor_from_unframed_buf(
+                &mut immutable_re,
+                &mut weights_reader,
+                weight_quantization,
+            )?;
+        } else {
+            re.into_immutable_regressor_from_buf(
+                &mut immutable_re,
+                &mut weights_reader,
+                weight_quantization,
+            )?;
+        }
+        if let Some(alpha) = output_blend_alpha {
+            immutable_re.blend = Some(Box::new(blend::OutputBlend::new_from_buf(
+                &mut weights_reader,
+                alpha,
+                weight_quantization,
+            )?));
+        }
+        Ok((mi, vw, immutable_re))
     }
 }
 
-pub fn hogwild_load(re: &mut regressor::Regressor, filename: &str) -> Result<(), Box<dyn Error>> {
+// Model instance and namespaces of a regressor file, without reading its weights
+pub fn model_instance_from_filename(
+    filename: &str,
+) -> Result<(model_instance::ModelInstance, vwmap::VwNamespaceMap), Box<dyn Error>> {
     let mut input_bufreader = io::BufReader::new(fs::File::open(filename)?);
-    let (_, _, mut re_hw) = load_regressor_without_weights(&mut input_bufreader, None)?;
+    let (mi, vw, _, _) =
+        load_regressor_without_weights(&mut input_bufreader, filename, None, true)?;
+    Ok((mi, vw))
+}
+
+// Weights of one regressor can take the place of those of the other
+pub fn same_weights_shape(re: &regressor::Regressor, re2: &regressor::Regressor) -> bool {
+    re.blocks_boxes.len() == re2.blocks_boxes.len()
+        && re
+            .blocks_boxes
+            .iter()
+            .zip(re2.blocks_boxes.iter())
+            .all(|(block, block2)| block.get_serialized_len() == block2.get_serialized_len())
+}
+
+// Returns the version of the loaded model, the hash of the same bytes its weights were read from
+pub fn hogwild_load(re: &mut regressor::Regressor, filename: &str) -> Result<u64, Box<dyn Error>> {
+    let bytes = fs::read(filename)?;
+    let model_version = prediction_log::model_version_of_bytes(&bytes);
+    let mut input_bufreader = io::Cursor::new(bytes);
+    let (mi_hw, _, mut re_hw, header) =
+        load_regressor_without_weights(&mut input_bufreader, filename, None, re.immutable)?;
     // TODO: Here we should do safety comparison that the regressor is really the same;
-    if !re.immutable {
-	re.overwrite_weights_from_buf(&mut input_bufreader, false)?;
+    // At least its weights have to have the same shape, they would be read into wrong places otherwise
+    if !same_weights_shape(re, &re_hw) {
+        return Err(format!(
+            "Weights of {} have a different shape than the served ones (was the served FFM table grown?)",
+            filename
+        ))?;
+    }
+    if blend::output_blend_alpha(&mi_hw).is_some() != re.blend.is_some() {
+        return Err(format!(
+            "{} and the served model are not both output blends",
+            filename
+        ))?;
+    }
+    let mut weights_reader = new_weights_reader(&mut input_bufreader, header.weights_encoding)?;
+    if header.version < REGRESSOR_FRAMED_VERSION {
+        if !re.immutable {
+            re.overwrite_weights_from_unframed_buf(&mut weights_reader, false)?;
+        } else {
+            re_hw.into_immutable_regressor_from_unframed_buf(re, &mut weights_reader, false)?;
+        }
+    } else if !re.immutable {
+        re.overwrite_weights_from_buf(&mut weights_reader, false)?;
     } else {
-	re_hw.into_immutable_regressor_from_buf(re, &mut input_bufreader, false)?;
+        re_hw.into_immutable_regressor_from_buf(re, &mut weights_reader, false)?;
     }
-    Ok(())
+    if let Some(blend) = re.blend.as_mut() {
+        blend.overwrite_from_buf(&mut weights_reader)?;
+    }
+    Ok(model_version)
 }
 
-fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+// Weights of version 6 files are not framed by block, block layout is missing before version 8,
+// weights of files before version 10 are raw
+fn verify_header(
+    input_bufreader: &mut dyn io::Read,
+    immutable: bool,
+) -> Result<RegressorHeader, Box<dyn Error>> {
     let mut magic_string: [u8; 4] = [0; 4];
-    input_bufreader.read(&mut magic_string)?;
+    input_bufreader.read_exact(&mut magic_string)?;
     if &magic_string != REGRESSOR_HEADER_MAGIC_STRING {
-	return Err("Cache header does not begin with magic bytes FWFW")?;
+        return Err("Regressor header does not begin with magic bytes FWRE")?;
     }
 
     let version = input_bufreader.read_u32::<LittleEndian>()?;
-    if REGRESSOR_HEADER_VERSION != version {
-	return Err(format!(
-	    "Cache file version of this binary: {}, version of the cache file: {}",
-	    REGRESSOR_HEADER_VERSION, version
-	))?;
+    if immutable && version > REGRESSOR_HEADER_VERSION {
+        // Newer models keep the block framing, layout and weights encoding, so for inference we can still load the blocks we know about
+        log::warn!(
+            "Regressor file version {} is newer than version of this binary {}, loading it in inference-only mode",
+            version, REGRESSOR_HEADER_VERSION
+        );
+    } else if version < REGRESSOR_HEADER_OLDEST_VERSION || version > REGRESSOR_HEADER_VERSION {
+        return Err(format!(
+            "Regressor file version {} can't be read, this binary reads versions {} to {}",
+            version, REGRESSOR_HEADER_OLDEST_VERSION, REGRESSOR_HEADER_VERSION
+        ))?;
     }
-    Ok(())
+    let layout = if version >= REGRESSOR_LAYOUT_VERSION {
+        Some(BlockLayout::read(input_bufreader)?)
+    } else {
+        None
+    };
+    let weights_encoding = if version >= REGRESSOR_WEIGHTS_ENCODING_VERSION {
+        input_bufreader.read_u32::<LittleEndian>()?
+    } else {
+        WEIGHTS_ENCODING_RAW
+    };
+    Ok(RegressorHeader {
+        version,
+        layout,
+        weights_encoding,
+    })
 }
 
 #[cfg(test)]
@@ -213,6 +732,9 @@ mod tests {
     use crate::model_instance::Optimizer;
     use crate::optimizer;
     use crate::optimizer::OptimizerTrait;
+    use crate::quantization;
+    use byteorder::ByteOrder;
+    use flate2::Crc;
     use regressor::BlockTrait;
     use regressor::Regressor;
 
@@ -220,425 +742,914 @@ mod tests {
 
     #[test]
     fn save_empty_model() {
-	let vw_map_string = r#"
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        let rr = regressor::get_regressor_with_weights(&mi);
+        let dir = tempfile::tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, rr, false)
+            .unwrap();
+    }
+
+    #[test]
+    fn background_saver() {
+        let vw_map_string = r#"
 A,featureA
 B,featureB
 "#;
-	let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.bit_precision = 18;
-	mi.optimizer = model_instance::Optimizer::AdagradFlex;
-	let rr = regressor::get_regressor_with_weights(&mi);
-	let dir = tempfile::tempdir().unwrap();
-	let regressor_filepath = dir.path().join("test_regressor.fw");
-	save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, rr, false)
-	    .unwrap();
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        mi.init_acc_gradient = 0.0;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let fbuf = &lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("snapshot.fw");
+        let regressor_filename = regressor_filepath.to_str().unwrap();
+        let mut saver = BackgroundSaver::new(regressor_filename);
+
+        re.learn(fbuf, &mut pb, true);
+        saver.snapshot(&mi, &vw, &re, false).unwrap();
+        let snapshot_prediction = re.learn(fbuf, &mut pb, false);
+        // Learning continues while the snapshot is written, the snapshot has to stay as it was
+        re.learn(fbuf, &mut pb, true);
+        saver.wait_for_pending_write().unwrap();
+        let (_mi2, _vw2, mut re2) =
+            new_regressor_from_filename(regressor_filename, false, None).unwrap();
+        assert_eq!(re2.learn(fbuf, &mut pb, false), snapshot_prediction);
+
+        // Second snapshot reuses the buffer and replaces the file
+        saver.snapshot(&mi, &vw, &re, false).unwrap();
+        saver.wait_for_pending_write().unwrap();
+        let (_mi2, _vw2, mut re2) =
+            new_regressor_from_filename(regressor_filename, false, None).unwrap();
+        assert_eq!(
+            re2.learn(fbuf, &mut pb, false),
+            re.learn(fbuf, &mut pb, false)
+        );
+        assert!(!dir.path().join("snapshot.fw.tmp").exists());
+    }
+
+    #[test]
+    fn rotating_checkpoints() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 4;
+        let re = regressor::Regressor::new(&mi);
+        let dir = tempdir().unwrap();
+        let checkpoint_dir = dir.path().join("checkpoints");
+        let checkpoint_dir = checkpoint_dir.to_str().unwrap();
+
+        let mut saver = BackgroundSaver::new_rotating(checkpoint_dir, 2).unwrap();
+        for _ in 0..3 {
+            saver.snapshot(&mi, &vw, &re, false).unwrap();
+        }
+        saver.wait_for_pending_write().unwrap();
+        let sequences: Vec<u64> = checkpoint_files(checkpoint_dir)
+            .unwrap()
+            .iter()
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        assert_eq!(sequences, vec![2, 3]);
+
+        // A restarted run continues the numbering
+        let mut saver = BackgroundSaver::new_rotating(checkpoint_dir, 2).unwrap();
+        saver.snapshot(&mi, &vw, &re, false).unwrap();
+        saver.wait_for_pending_write().unwrap();
+        let checkpoints = checkpoint_files(checkpoint_dir).unwrap();
+        assert_eq!(checkpoints[1].0, 4);
+        new_regressor_from_filename(&checkpoints[1].1, false, None).unwrap();
+        assert_eq!(fs::read_dir(checkpoint_dir).unwrap().count(), 2);
     }
 
     fn lr_vec(v: Vec<feature_buffer::HashAndValue>) -> feature_buffer::FeatureBuffer {
-	feature_buffer::FeatureBuffer {
-	    label: 0.0,
-	    example_importance: 1.0,
-	    example_number: 0,
-	    lr_buffer: v,
-	    ffm_buffer: Vec::new(),
-	}
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: v,
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
     }
 
     #[test]
     fn save_load_and_test_mode_lr() {
-	let vw_map_string = r#"
+        let vw_map_string = r#"
 A,featureA
 B,featureB
 "#;
-	let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.power_t = 0.5;
-	mi.bit_precision = 18;
-	mi.optimizer = model_instance::Optimizer::AdagradFlex;
-	mi.init_acc_gradient = 0.0;
-	let mut re = regressor::Regressor::new(&mi);
-	let mut pb = re.new_portbuffer();
-
-	let fbuf = &lr_vec(vec![
-	    HashAndValue {
-		hash: 1,
-		value: 1.0,
-		combo_index: 0,
-	    },
-	    HashAndValue {
-		hash: 2,
-		value: 1.0,
-		combo_index: 0,
-	    },
-	]);
-	assert_eq!(re.learn(fbuf, &mut pb, true), 0.5);
-	assert_eq!(re.learn(fbuf, &mut pb, true), 0.45016602);
-	assert_eq!(re.learn(fbuf, &mut pb, false), 0.41731137);
-
-	let expected_result = 0.41731137;
-	assert_eq!(re.learn(fbuf, &mut pb, false), expected_result);
-
-	// Now we test conversion to fixed regressor
-	{
-	    mi.optimizer = model_instance::Optimizer::SGD;
-	    let re_fixed = re.immutable_regressor(&mi, false).unwrap();
-	    // predict with the same feature vector
-	    assert_eq!(re_fixed.predict(fbuf, &mut pb), expected_result);
-	    mi.optimizer = model_instance::Optimizer::AdagradFlex;
-	}
-	// Now we test saving and loading a) regular regressor, b) fixed regressor
-	{
-	    let dir = tempdir().unwrap();
-	    let regressor_filepath = dir.path().join("test_regressor2.fw");
-	    save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, re, false)
-		.unwrap();
-
-	    // a) load as regular regressor
-	    let (_mi2, _vw2, mut re2) =
-		new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false, None)
-		    .unwrap();
-	    assert_eq!(re2.learn(fbuf, &mut pb, false), expected_result);
-	    assert_eq!(re2.predict(fbuf, &mut pb), expected_result);
-
-	    // a) load as regular regressor, immutable
-	    let (_mi2, _vw2, mut re2) =
-		new_regressor_from_filename(regressor_filepath.to_str().unwrap(), true, None)
-		    .unwrap();
-	    assert_eq!(re2.learn(fbuf, &mut pb, false), expected_result);
-	    assert_eq!(re2.predict(fbuf, &mut pb), expected_result);
-	}
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        mi.init_acc_gradient = 0.0;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+
+        let fbuf = &lr_vec(vec![
+            HashAndValue {
+                hash: 1,
+                value: 1.0,
+                combo_index: 0,
+            },
+            HashAndValue {
+                hash: 2,
+                value: 1.0,
+                combo_index: 0,
+            },
+        ]);
+        assert_eq!(re.learn(fbuf, &mut pb, true), 0.5);
+        assert_eq!(re.learn(fbuf, &mut pb, true), 0.45016602);
+        assert_eq!(re.learn(fbuf, &mut pb, false), 0.41731137);
+
+        let expected_result = 0.41731137;
+        assert_eq!(re.learn(fbuf, &mut pb, false), expected_result);
+
+        // Now we test conversion to fixed regressor
+        {
+            mi.optimizer = model_instance::Optimizer::SGD;
+            let re_fixed = re.immutable_regressor(&mi, false).unwrap();
+            // predict with the same feature vector
+            assert_eq!(re_fixed.predict(fbuf, &mut pb), expected_result);
+            mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        }
+        // Now we test saving and loading a) regular regressor, b) fixed regressor
+        {
+            let dir = tempdir().unwrap();
+            let regressor_filepath = dir.path().join("test_regressor2.fw");
+            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, re, false)
+                .unwrap();
+
+            // a) load as regular regressor
+            let (_mi2, _vw2, mut re2) =
+                new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false, None)
+                    .unwrap();
+            assert_eq!(re2.learn(fbuf, &mut pb, false), expected_result);
+            assert_eq!(re2.predict(fbuf, &mut pb), expected_result);
+
+            // a) load as regular regressor, immutable
+            let (_mi2, _vw2, mut re2) =
+                new_regressor_from_filename(regressor_filepath.to_str().unwrap(), true, None)
+                    .unwrap();
+            assert_eq!(re2.learn(fbuf, &mut pb, false), expected_result);
+            assert_eq!(re2.predict(fbuf, &mut pb), expected_result);
+        }
     }
 
     fn ffm_fixed_init(rg: &mut Regressor) {
-	// This is a bit of black magic - we "know" that FFM is at index 1 and we downcast...
-	let block_ffm = &mut rg.blocks_boxes[1];
-	let block_ffm = block_ffm
-	    .as_any()
-	    .downcast_mut::<block_ffm::BlockFFM<optimizer::OptimizerAdagradFlex>>()
-	    .unwrap();
+        // This is a bit of black magic - we "know" that FFM is at index 1 and we downcast...
+        let block_ffm = &mut rg.blocks_boxes[1];
+        let block_ffm = block_ffm
+            .as_any()
+            .downcast_mut::<block_ffm::BlockFFM<optimizer::OptimizerAdagradFlex>>()
+            .unwrap();
 
-	for i in 0..block_ffm.get_serialized_len() {
-	    // it only happens that this matches number of weights
-	    block_ffm.weights[i] = 1.0;
-	    block_ffm.optimizer[i].optimizer_data = block_ffm.optimizer_ffm.initial_data();
-	}
+        for i in 0..block_ffm.get_serialized_len() {
+            // it only happens that this matches number of weights
+            block_ffm.weights[i] = 1.0;
+            block_ffm.optimizer[i].optimizer_data = block_ffm.optimizer_ffm.initial_data();
+        }
     }
 
     fn ffm_vec(v: Vec<feature_buffer::HashAndValueAndSeq>) -> feature_buffer::FeatureBuffer {
-	feature_buffer::FeatureBuffer {
-	    label: 0.0,
-	    example_importance: 1.0,
-	    example_number: 0,
-	    lr_buffer: Vec::new(),
-	    ffm_buffer: v,
-	}
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: v,
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
     }
 
-    #[test] #[ignore]
+    #[test]
+    #[ignore]
     fn save_load_and_test_mode_ffm() {
-	let vw_map_string = r#"
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+
+        let mut p: f32;
+
+        ffm_fixed_init(&mut re);
+        let fbuf = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 3 * 1000,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: 1,
+            },
+        ]);
+        pb.reset();
+        p = re.learn(fbuf, &mut pb, true);
+        assert_eq!(p, 0.9933072);
+        let expected_result = 0.9395168;
+        p = re.learn(fbuf, &mut pb, false);
+        assert_epsilon!(p, expected_result);
+        p = re.predict(fbuf, &mut pb);
+        assert_epsilon!(p, expected_result);
+
+        // Now we test conversion to fixed regressor
+        {
+            mi.optimizer = Optimizer::SGD;
+            let re_fixed = re.immutable_regressor(&mi, false).unwrap();
+            // predict with the same feature vector
+            mi.optimizer = Optimizer::AdagradFlex;
+            assert_epsilon!(re_fixed.predict(fbuf, &mut pb), expected_result);
+        }
+        // Now we test saving and loading a) regular regressor, b) fixed regressor
+        {
+            let dir = tempdir().unwrap();
+            let regressor_filepath = dir.path().join("test_regressor2.fw");
+            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, re, false)
+                .unwrap();
+
+            // a) load as regular regressor
+            let (_mi2, _vw2, mut re2) =
+                new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false, None)
+                    .unwrap();
+            assert_eq!(re2.get_name(), "Regressor with optimizer \"AdagradFlex\"");
+            assert_epsilon!(re2.learn(fbuf, &mut pb, false), expected_result);
+            assert_epsilon!(re2.predict(fbuf, &mut pb), expected_result);
+
+            // b) load as regular regressor, immutable
+            let (_mi2, _vw2, mut re2) =
+                new_regressor_from_filename(regressor_filepath.to_str().unwrap(), true, None)
+                    .unwrap();
+            assert_eq!(re2.get_name(), "Regressor with optimizer \"SGD\"");
+            assert_epsilon!(re2.learn(fbuf, &mut pb, false), expected_result);
+            assert_epsilon!(re2.predict(fbuf, &mut pb), expected_result);
+        }
+    }
+
+    #[test]
+    fn save_and_load_field_major_ffm() {
+        let vw_map_string = r#"
 A,featureA
 B,featureB
+C,featureC
 "#;
-	let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.bit_precision = 18;
-	mi.ffm_k = 1;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_power_t = 0.0;
-	mi.ffm_learning_rate = 0.1;
-	mi.ffm_fields = vec![vec![], vec![]];
-	mi.optimizer = Optimizer::AdagradFlex;
-	let mut re = regressor::Regressor::new(&mi);
-	let mut pb = re.new_portbuffer();
-
-	let mut p: f32;
-
-	ffm_fixed_init(&mut re);
-	let fbuf = &ffm_vec(vec![
-	    HashAndValueAndSeq {
-		hash: 1,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 3 * 1000,
-		value: 1.0,
-		contra_field_index: 0,
-	    },
-	    HashAndValueAndSeq {
-		hash: 100,
-		value: 2.0,
-		contra_field_index: 1,
-	    },
-	]);
-	pb.reset();
-	p = re.learn(fbuf, &mut pb, true);
-	assert_eq!(p, 0.9933072);
-	let expected_result = 0.9395168;
-	p = re.learn(fbuf, &mut pb, false);
-	assert_epsilon!(p, expected_result);
-	p = re.predict(fbuf, &mut pb);
-	assert_epsilon!(p, expected_result);
-
-	// Now we test conversion to fixed regressor
-	{
-	    mi.optimizer = Optimizer::SGD;
-	    let re_fixed = re.immutable_regressor(&mi, false).unwrap();
-	    // predict with the same feature vector
-	    mi.optimizer = Optimizer::AdagradFlex;
-	    assert_epsilon!(re_fixed.predict(fbuf, &mut pb), expected_result);
-	}
-	// Now we test saving and loading a) regular regressor, b) fixed regressor
-	{
-	    let dir = tempdir().unwrap();
-	    let regressor_filepath = dir.path().join("test_regressor2.fw");
-	    save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, re, false)
-		.unwrap();
-
-	    // a) load as regular regressor
-	    let (_mi2, _vw2, mut re2) =
-		new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false, None)
-		    .unwrap();
-	    assert_eq!(re2.get_name(), "Regressor with optimizer \"AdagradFlex\"");
-	    assert_epsilon!(re2.learn(fbuf, &mut pb, false), expected_result);
-	    assert_epsilon!(re2.predict(fbuf, &mut pb), expected_result);
-
-	    // b) load as regular regressor, immutable
-	    let (_mi2, _vw2, mut re2) =
-		new_regressor_from_filename(regressor_filepath.to_str().unwrap(), true, None)
-		    .unwrap();
-	    assert_eq!(re2.get_name(), "Regressor with optimizer \"SGD\"");
-	    assert_epsilon!(re2.learn(fbuf, &mut pb, false), expected_result);
-	    assert_epsilon!(re2.predict(fbuf, &mut pb), expected_result);
-	}
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![], vec![]];
+        mi.ffm_layout = model_instance::FfmLayout::FieldMajor;
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+
+        let fbuf = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 4,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 400,
+                value: 2.0,
+                contra_field_index: mi.ffm_k,
+            },
+            HashAndValueAndSeq {
+                hash: 8000,
+                value: 1.0,
+                contra_field_index: mi.ffm_k * 2,
+            },
+        ]);
+        re.learn(fbuf, &mut pb, true);
+        re.learn(fbuf, &mut pb, true);
+        let expected_result = re.predict(fbuf, &mut pb);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        save_regressor_to_filename(regressor_filepath, &mi, &vw, re, false).unwrap();
+
+        let (mi2, _vw2, mut re2) =
+            new_regressor_from_filename(regressor_filepath, false, None).unwrap();
+        assert_eq!(mi2.ffm_layout, model_instance::FfmLayout::FieldMajor);
+        let block_ffm = re2.blocks_boxes[1]
+            .as_any()
+            .downcast_mut::<block_ffm::BlockFFM<optimizer::OptimizerAdagradFlex>>()
+            .unwrap();
+        assert_eq!(block_ffm.indexing, block_ffm::FfmIndexing::new(&mi));
+        assert_eq!(re2.predict(fbuf, &mut pb), expected_result);
+
+        let (mi2, _vw2, re2) = new_regressor_from_filename(regressor_filepath, true, None).unwrap();
+        assert_eq!(mi2.ffm_layout, model_instance::FfmLayout::FieldMajor);
+        assert_eq!(re2.predict(fbuf, &mut pb), expected_result);
+
+        // The weights can't be read as another layout
+        let cmd_arguments = crate::cmdline::parse_from(vec![
+            "fw".to_string(),
+            "--ffm_layout".to_string(),
+            "feature_major".to_string(),
+        ])
+        .unwrap();
+        assert!(
+            new_regressor_from_filename(regressor_filepath, false, Some(&cmd_arguments)).is_err()
+        );
+    }
+
+    #[test]
+    fn load_newer_model_with_unknown_block() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let fbuf = &lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+        re.learn(fbuf, &mut pb, true);
+        let expected_result = re.learn(fbuf, &mut pb, false);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        save_regressor_to_filename(regressor_filepath, &mi, &vw, re, false).unwrap();
+
+        // Pretend a newer binary wrote this file: bump version and append a block we don't know
+        let mut buf = fs::read(regressor_filepath).unwrap();
+        buf[4..8].copy_from_slice(&(REGRESSOR_HEADER_VERSION + 1).to_le_bytes());
+        // skip block layout and weights encoding
+        let mut pos = 12 + 12 * LittleEndian::read_u32(&buf[8..12]) as usize + 4;
+        for _ in 0..2 {
+            // skip vwmap and model instance
+            let len = LittleEndian::read_u64(&buf[pos..pos + 8]) as usize;
+            pos += 8 + len;
+        }
+        let num_blocks = LittleEndian::read_u32(&buf[pos..pos + 4]);
+        buf[pos..pos + 4].copy_from_slice(&(num_blocks + 1).to_le_bytes());
+        buf.truncate(buf.len() - 4);
+        buf.extend_from_slice(&12345u32.to_le_bytes());
+        buf.extend_from_slice(&3u64.to_le_bytes());
+        buf.extend_from_slice(&[1, 2, 3]);
+        let mut crc = Crc::new();
+        crc.update(&buf[pos..]);
+        buf.extend_from_slice(&crc.sum().to_le_bytes());
+        fs::write(regressor_filepath, &buf).unwrap();
+
+        // inference-only load skips the unknown block
+        let (_mi2, _vw2, re2) =
+            new_regressor_from_filename(regressor_filepath, true, None).unwrap();
+        assert_eq!(re2.predict(fbuf, &mut pb), expected_result);
+
+        // we can't continue training such a model, as we would lose the unknown block
+        let mut input_bufreader = io::BufReader::new(fs::File::open(regressor_filepath).unwrap());
+        let (mi2, _, mut re3, _) =
+            load_regressor_without_weights(&mut input_bufreader, regressor_filepath, None, true)
+                .unwrap();
+        re3.allocate_and_init_weights(&mi2);
+        assert!(re3
+            .overwrite_weights_from_buf(&mut input_bufreader, false)
+            .is_err());
+    }
+
+    fn save_lr_regressor(
+        regressor_filepath: &str,
+        bit_precision: u8,
+    ) -> (
+        model_instance::ModelInstance,
+        feature_buffer::FeatureBuffer,
+        f32,
+    ) {
+        save_lr_regressor_compressed(regressor_filepath, bit_precision, None)
+    }
+
+    fn save_lr_regressor_compressed(
+        regressor_filepath: &str,
+        bit_precision: u8,
+        compress_model: Option<i32>,
+    ) -> (
+        model_instance::ModelInstance,
+        feature_buffer::FeatureBuffer,
+        f32,
+    ) {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = bit_precision;
+        mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        mi.compress_model = compress_model;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let fbuf = lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+        re.learn(&fbuf, &mut pb, true);
+        let expected_result = re.predict(&fbuf, &mut pb);
+        save_regressor_to_filename(regressor_filepath, &mi, &vw, re, false).unwrap();
+        (mi, fbuf, expected_result)
+    }
+
+    #[test]
+    fn load_previous_version_without_block_layout() {
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        let (_, fbuf, expected_result) = save_lr_regressor(regressor_filepath, 18);
+
+        // Version 7 files are the same, just without the block layout and weights encoding after the
+        // version and the checksum after the weights
+        let buf = fs::read(regressor_filepath).unwrap();
+        let layout_len = 4 + 12 * LittleEndian::read_u32(&buf[8..12]) as usize + 4;
+        let mut old_buf = buf[0..4].to_vec();
+        old_buf.extend_from_slice(&7u32.to_le_bytes());
+        old_buf.extend_from_slice(&buf[8 + layout_len..buf.len() - 4]);
+        fs::write(regressor_filepath, &old_buf).unwrap();
+
+        let (_mi2, _vw2, re2) =
+            new_regressor_from_filename(regressor_filepath, false, None).unwrap();
+        let mut pb = re2.new_portbuffer();
+        assert_eq!(re2.predict(&fbuf, &mut pb), expected_result);
+
+        // Versions older than 6 are refused
+        old_buf[4..8].copy_from_slice(&5u32.to_le_bytes());
+        fs::write(regressor_filepath, &old_buf).unwrap();
+        assert!(new_regressor_from_filename(regressor_filepath, false, None).is_err());
+    }
+
+    #[test]
+    fn load_version_6_without_block_frames() {
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        let (mi, fbuf, expected_result) = save_lr_regressor(regressor_filepath, 18);
+
+        // Version 6 files have the number of weights of all blocks, followed by the weights of
+        // each block, as written by binaries before block frames
+        let (_, vw, re) = new_regressor_from_filename(regressor_filepath, false, None).unwrap();
+        let mut old_buf: Vec<u8> = Vec::new();
+        old_buf.extend_from_slice(REGRESSOR_HEADER_MAGIC_STRING);
+        old_buf.extend_from_slice(&6u32.to_le_bytes());
+        vw.save_to_buf(&mut old_buf).unwrap();
+        mi.save_to_buf(&mut old_buf).unwrap();
+        let len: usize = re
+            .blocks_boxes
+            .iter()
+            .map(|block| block.get_serialized_len())
+            .sum();
+        let weights_start = old_buf.len();
+        old_buf.extend_from_slice(&(len as u64).to_le_bytes());
+        for block in re.blocks_boxes.iter() {
+            block.write_weights_to_buf(&mut old_buf, false).unwrap();
+        }
+        fs::write(regressor_filepath, &old_buf).unwrap();
+
+        let (_, _, mut re2) = new_regressor_from_filename(regressor_filepath, false, None).unwrap();
+        let mut pb = re2.new_portbuffer();
+        assert_eq!(re2.predict(&fbuf, &mut pb), expected_result);
+        let (_, _, re_fixed) = new_regressor_from_filename(regressor_filepath, true, None).unwrap();
+        assert_eq!(re_fixed.predict(&fbuf, &mut pb), expected_result);
+        hogwild_load(&mut re2, regressor_filepath).unwrap();
+        assert_eq!(re2.predict(&fbuf, &mut pb), expected_result);
+
+        // A length that doesn't match the model instance is refused
+        old_buf[weights_start..weights_start + 8].copy_from_slice(&(len as u64 + 1).to_le_bytes());
+        fs::write(regressor_filepath, &old_buf).unwrap();
+        assert!(new_regressor_from_filename(regressor_filepath, false, None).is_err());
+    }
+
+    #[test]
+    fn load_model_with_different_block_layout() {
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        save_lr_regressor(regressor_filepath, 18);
+
+        // Layout claims a different number of lr weights than the model instance gives
+        let mut buf = fs::read(regressor_filepath).unwrap();
+        assert_eq!(LittleEndian::read_u32(&buf[8..12]), 1);
+        assert_eq!(
+            LittleEndian::read_u32(&buf[12..16]),
+            regressor::SERIALIZED_BLOCK_ID_LR
+        );
+        assert_eq!(LittleEndian::read_u64(&buf[16..24]), 1 << 18);
+        buf[16..24].copy_from_slice(&(1u64 << 10).to_le_bytes());
+        fs::write(regressor_filepath, &buf).unwrap();
+
+        let err = new_regressor_from_filename(regressor_filepath, false, None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("1:1024"));
+        assert!(err.contains("1:262144"));
+    }
+
+    #[test]
+    fn load_corrupted_model() {
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filepath = regressor_filepath.to_str().unwrap();
+        let (mi, fbuf, expected_result) = save_lr_regressor(regressor_filepath, 18);
+        let buf = fs::read(regressor_filepath).unwrap();
+
+        // A flipped bit in the weights
+        let mut corrupted = buf.clone();
+        let len = corrupted.len();
+        corrupted[len - 100] ^= 1;
+        fs::write(regressor_filepath, &corrupted).unwrap();
+        let err = new_regressor_from_filename(regressor_filepath, true, None)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ModelFileError>(),
+            Some(ModelFileError::ChecksumMismatch { .. })
+        ));
+
+        // hogwild_load refuses it before touching the served weights
+        let mut re = regressor::Regressor::new(&mi);
+        re.allocate_and_init_weights(&mi);
+        let mut pb = re.new_portbuffer();
+        let before = re.predict(&fbuf, &mut pb);
+        assert!(hogwild_load(&mut re, regressor_filepath).is_err());
+        assert_eq!(re.predict(&fbuf, &mut pb), before);
+
+        // A truncated download
+        fs::write(regressor_filepath, &buf[0..len / 2]).unwrap();
+        assert!(new_regressor_from_filename(regressor_filepath, true, None).is_err());
+
+        fs::write(regressor_filepath, &buf).unwrap();
+        hogwild_load(&mut re, regressor_filepath).unwrap();
+        assert_eq!(re.predict(&fbuf, &mut pb), expected_result);
+    }
+
+    #[test]
+    fn save_and_load_compressed_model() {
+        let dir = tempdir().unwrap();
+        let raw_filepath = dir.path().join("raw.fw");
+        let raw_filepath = raw_filepath.to_str().unwrap();
+        let compressed_filepath = dir.path().join("compressed.fw");
+        let compressed_filepath = compressed_filepath.to_str().unwrap();
+        save_lr_regressor(raw_filepath, 18);
+        let (mi, fbuf, expected_result) =
+            save_lr_regressor_compressed(compressed_filepath, 18, Some(3));
+        // Untouched weights all have the same initial value
+        assert!(
+            fs::metadata(compressed_filepath).unwrap().len() * 10
+                < fs::metadata(raw_filepath).unwrap().len()
+        );
+
+        let (mi2, _, re2) = new_regressor_from_filename(compressed_filepath, false, None).unwrap();
+        assert_eq!(mi2.compress_model, Some(3));
+        let mut pb = re2.new_portbuffer();
+        assert_eq!(re2.predict(&fbuf, &mut pb), expected_result);
+        let (_, _, re3) = new_regressor_from_filename(compressed_filepath, true, None).unwrap();
+        assert_eq!(re3.predict(&fbuf, &mut pb), expected_result);
+
+        let mut re = regressor::Regressor::new(&mi);
+        re.allocate_and_init_weights(&mi);
+        hogwild_load(&mut re, compressed_filepath).unwrap();
+        assert_eq!(re.predict(&fbuf, &mut pb), expected_result);
+    }
+
+    #[test]
+    fn quantized_ffm_weights() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = Optimizer::AdagradFlex;
+        let fbuf = &ffm_vec(vec![
+            HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            },
+            HashAndValueAndSeq {
+                hash: 100,
+                value: 2.0,
+                contra_field_index: 1,
+            },
+        ]);
+
+        for quantization in [
+            quantization::WeightQuantization::Buckets16,
+            quantization::WeightQuantization::Fp16,
+            quantization::WeightQuantization::Int8,
+        ] {
+            mi.quantization = quantization;
+            let mut re = regressor::Regressor::new(&mi);
+            let mut pb = re.new_portbuffer();
+            ffm_fixed_init(&mut re);
+            re.learn(fbuf, &mut pb, true);
+            let expected_result = re.predict(fbuf, &mut pb);
+
+            let mut buf: Vec<u8> = Vec::new();
+            re.write_weights_to_buf(&mut buf, true).unwrap();
+            let mut mi_sgd = mi.clone();
+            mi_sgd.optimizer = Optimizer::SGD;
+            let mut re_forward = re.immutable_regressor_without_weights(&mi_sgd).unwrap();
+            re_forward.allocate_and_init_weights(&mi_sgd);
+            re.into_immutable_regressor_from_buf(&mut re_forward, &mut io::Cursor::new(buf), true)
+                .unwrap();
+            let result = re_forward.predict(fbuf, &mut pb);
+            assert!(
+                (result - expected_result).abs() < 0.01,
+                "{:?}: {} vs {}",
+                quantization,
+                result,
+                expected_result
+            );
+        }
     }
 
     fn lr_and_ffm_vec(
-	v1: Vec<feature_buffer::HashAndValue>,
-	v2: Vec<feature_buffer::HashAndValueAndSeq>,
+        v1: Vec<feature_buffer::HashAndValue>,
+        v2: Vec<feature_buffer::HashAndValueAndSeq>,
     ) -> feature_buffer::FeatureBuffer {
-	feature_buffer::FeatureBuffer {
-	    label: 0.0,
-	    example_importance: 1.0,
-	    example_number: 0,
-	    lr_buffer: v1,
-	    ffm_buffer: v2,
-	}
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: v1,
+            ffm_buffer: v2,
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
     }
 
-    #[test] #[ignore]
+    #[test]
+    #[ignore]
     fn test_hogwild_load() {
-	let vw_map_string = r#"
+        let vw_map_string = r#"
 A,featureA
 B,featureB
 "#;
-	let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
-	let mut mi = model_instance::ModelInstance::new_empty().unwrap();
-	mi.learning_rate = 0.1;
-	mi.power_t = 0.0;
-	mi.bit_precision = 18;
-	mi.ffm_k = 1;
-	mi.ffm_bit_precision = 18;
-	mi.ffm_power_t = 0.0;
-	mi.ffm_learning_rate = 0.1;
-	mi.ffm_fields = vec![vec![], vec![]];
-	mi.optimizer = Optimizer::AdagradFlex;
-
-	let mut re_1 = regressor::Regressor::new(&mi);
-	let mut re_2 = regressor::Regressor::new(&mi);
-	let mut pb_1 = re_1.new_portbuffer();
-	let mut pb_2 = re_2.new_portbuffer();
-	let mut p: f32;
-
-	ffm_fixed_init(&mut re_1);
-	ffm_fixed_init(&mut re_2);
-	let fbuf_1 = &lr_and_ffm_vec(
-	    vec![
-		HashAndValue {
-		    hash: 52,
-		    value: 0.5,
-		    combo_index: 0,
-		},
-		HashAndValue {
-		    hash: 2,
-		    value: 1.0,
-		    combo_index: 0,
-		},
-	    ],
-	    vec![
-		HashAndValueAndSeq {
-		    hash: 1,
-		    value: 0.5,
-		    contra_field_index: 0,
-		},
-		HashAndValueAndSeq {
-		    hash: 3 * 1000,
-		    value: 1.0,
-		    contra_field_index: 0,
-		},
-		HashAndValueAndSeq {
-		    hash: 101,
-		    value: 2.0,
-		    contra_field_index: 1,
-		},
-	    ],
-	);
-	let fbuf_2 = &lr_and_ffm_vec(
-	    vec![
-		HashAndValue {
-		    hash: 1,
-		    value: 1.0,
-		    combo_index: 0,
-		},
-		HashAndValue {
-		    hash: 2,
-		    value: 1.0,
-		    combo_index: 0,
-		},
-	    ],
-	    vec![
-		HashAndValueAndSeq {
-		    hash: 1,
-		    value: 1.0,
-		    contra_field_index: 0,
-		},
-		HashAndValueAndSeq {
-		    hash: 3 * 1000,
-		    value: 1.0,
-		    contra_field_index: 0,
-		},
-		HashAndValueAndSeq {
-		    hash: 100,
-		    value: 2.0,
-		    contra_field_index: 1,
-		},
-	    ],
-	);
-
-	p = re_1.learn(fbuf_1, &mut pb_1, true);
-	assert_eq!(p, 0.97068775);
-	let expected_result_1_on_1 = 0.8922257;
-	p = re_1.learn(fbuf_1, &mut pb_1, false);
-	assert_eq!(p, expected_result_1_on_1);
-	p = re_1.predict(fbuf_1, &mut pb_1);
-	assert_eq!(p, expected_result_1_on_1);
-
-	p = re_2.learn(fbuf_2, &mut pb_2, true);
-	assert_eq!(p, 0.9933072);
-	let expected_result_2_on_2 = 0.92719215;
-	p = re_2.learn(fbuf_2, &mut pb_2, false);
-	assert_eq!(p, expected_result_2_on_2);
-	p = re_2.predict(fbuf_2, &mut pb_2);
-	assert_eq!(p, expected_result_2_on_2);
-
-	p = re_2.learn(fbuf_1, &mut pb_2, false);
-	assert_eq!(p, 0.93763095);
-	let expected_result_1_on_2 = 0.93763095;
-	p = re_2.learn(fbuf_1, &mut pb_2, false);
-	assert_eq!(p, expected_result_1_on_2);
-	p = re_2.predict(fbuf_1, &mut pb_1);
-	assert_eq!(p, expected_result_1_on_2);
-
-	p = re_1.learn(fbuf_2, &mut pb_1, false);
-	assert_eq!(p, 0.98559695);
-	let expected_result_2_on_1 = 0.98559695;
-	p = re_1.learn(fbuf_2, &mut pb_1, false);
-	assert_eq!(p, expected_result_2_on_1);
-	p = re_1.predict(fbuf_2, &mut pb_2);
-	assert_eq!(p, expected_result_2_on_1);
-
-	// Now we test saving and loading a) regular regressor, b) immutable regressor
-	// FYI ... this confusing tests have actually caught bugs in the code, they are hard to maintain, but important
-	{
-	    let dir = tempdir().unwrap();
-	    let regressor_filepath_1 = dir
-		.path()
-		.join("test_regressor1.fw")
-		.to_str()
-		.unwrap()
-		.to_owned();
-	    save_regressor_to_filename(&regressor_filepath_1, &mi, &vw, re_1, false).unwrap();
-	    let regressor_filepath_2 = dir
-		.path()
-		.join("test_regressor2.fw")
-		.to_str()
-		.unwrap()
-		.to_owned();
-	    save_regressor_to_filename(&regressor_filepath_2, &mi, &vw, re_2, false).unwrap();
-
-	    // The mutable path
-	    let (_mi1, _vw1, mut new_re_1) =
-		new_regressor_from_filename(&regressor_filepath_1, false, None).unwrap();
-	    assert_eq!(
-		new_re_1.get_name(),
-		"Regressor with optimizer \"AdagradFlex\""
-	    );
-	    assert_eq!(
-		new_re_1.learn(fbuf_1, &mut pb_1, false),
-		expected_result_1_on_1
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_1, &mut pb_1), expected_result_1_on_1);
-	    assert_eq!(
-		new_re_1.learn(fbuf_2, &mut pb_1, false),
-		expected_result_2_on_1
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_1);
-	    hogwild_load(&mut new_re_1, &regressor_filepath_2).unwrap();
-	    assert_eq!(
-		new_re_1.learn(fbuf_2, &mut pb_1, false),
-		expected_result_2_on_2
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_2);
-	    hogwild_load(&mut new_re_1, &regressor_filepath_1).unwrap();
-	    assert_eq!(
-		new_re_1.learn(fbuf_1, &mut pb_1, false),
-		expected_result_1_on_1
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_1, &mut pb_1), expected_result_1_on_1);
-	    assert_eq!(
-		new_re_1.learn(fbuf_2, &mut pb_1, false),
-		expected_result_2_on_1
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_1);
-
-	    // The immutable path
-	    let (_mi1, _vw1, mut new_re_1) =
-		new_regressor_from_filename(&regressor_filepath_1, true, None).unwrap();
-	    assert_eq!(new_re_1.get_name(), "Regressor with optimizer \"SGD\"");
-	    assert_eq!(
-		new_re_1.learn(fbuf_1, &mut pb_1, false),
-		expected_result_1_on_1
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_1, &mut pb_1), expected_result_1_on_1);
-	    assert_eq!(
-		new_re_1.learn(fbuf_2, &mut pb_1, false),
-		expected_result_2_on_1
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_1);
-	    hogwild_load(&mut new_re_1, &regressor_filepath_2).unwrap();
-	    assert_eq!(
-		new_re_1.learn(fbuf_2, &mut pb_1, false),
-		expected_result_2_on_2
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_2);
-	    hogwild_load(&mut new_re_1, &regressor_filepath_1).unwrap();
-	    assert_eq!(
-		new_re_1.learn(fbuf_1, &mut pb_1, false),
-		expected_result_1_on_1
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_1, &mut pb_1), expected_result_1_on_1);
-	    assert_eq!(
-		new_re_1.learn(fbuf_2, &mut pb_1, false),
-		expected_result_2_on_1
-	    );
-	    assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_1);
-	}
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = Optimizer::AdagradFlex;
+
+        let mut re_1 = regressor::Regressor::new(&mi);
+        let mut re_2 = regressor::Regressor::new(&mi);
+        let mut pb_1 = re_1.new_portbuffer();
+        let mut pb_2 = re_2.new_portbuffer();
+        let mut p: f32;
+
+        ffm_fixed_init(&mut re_1);
+        ffm_fixed_init(&mut re_2);
+        let fbuf_1 = &lr_and_ffm_vec(
+            vec![
+                HashAndValue {
+                    hash: 52,
+                    value: 0.5,
+                    combo_index: 0,
+                },
+                HashAndValue {
+                    hash: 2,
+                    value: 1.0,
+                    combo_index: 0,
+                },
+            ],
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 0.5,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 3 * 1000,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 101,
+                    value: 2.0,
+                    contra_field_index: 1,
+                },
+            ],
+        );
+        let fbuf_2 = &lr_and_ffm_vec(
+            vec![
+                HashAndValue {
+                    hash: 1,
+                    value: 1.0,
+                    combo_index: 0,
+                },
+                HashAndValue {
+                    hash: 2,
+                    value: 1.0,
+                    combo_index: 0,
+                },
+            ],
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 3 * 1000,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 100,
+                    value: 2.0,
+                    contra_field_index: 1,
+                },
+            ],
+        );
+
+        p = re_1.learn(fbuf_1, &mut pb_1, true);
+        assert_eq!(p, 0.97068775);
+        let expected_result_1_on_1 = 0.8922257;
+        p = re_1.learn(fbuf_1, &mut pb_1, false);
+        assert_eq!(p, expected_result_1_on_1);
+        p = re_1.predict(fbuf_1, &mut pb_1);
+        assert_eq!(p, expected_result_1_on_1);
+
+        p = re_2.learn(fbuf_2, &mut pb_2, true);
+        assert_eq!(p, 0.9933072);
+        let expected_result_2_on_2 = 0.92719215;
+        p = re_2.learn(fbuf_2, &mut pb_2, false);
+        assert_eq!(p, expected_result_2_on_2);
+        p = re_2.predict(fbuf_2, &mut pb_2);
+        assert_eq!(p, expected_result_2_on_2);
+
+        p = re_2.learn(fbuf_1, &mut pb_2, false);
+        assert_eq!(p, 0.93763095);
+        let expected_result_1_on_2 = 0.93763095;
+        p = re_2.learn(fbuf_1, &mut pb_2, false);
+        assert_eq!(p, expected_result_1_on_2);
+        p = re_2.predict(fbuf_1, &mut pb_1);
+        assert_eq!(p, expected_result_1_on_2);
+
+        p = re_1.learn(fbuf_2, &mut pb_1, false);
+        assert_eq!(p, 0.98559695);
+        let expected_result_2_on_1 = 0.98559695;
+        p = re_1.learn(fbuf_2, &mut pb_1, false);
+        assert_eq!(p, expected_result_2_on_1);
+        p = re_1.predict(fbuf_2, &mut pb_2);
+        assert_eq!(p, expected_result_2_on_1);
+
+        // Now we test saving and loading a) regular regressor, b) immutable regressor
+        // FYI ... this confusing tests have actually caught bugs in the code, they are hard to maintain, but important
+        {
+            let dir = tempdir().unwrap();
+            let regressor_filepath_1 = dir
+                .path()
+                .join("test_regressor1.fw")
+                .to_str()
+                .unwrap()
+                .to_owned();
+            save_regressor_to_filename(&regressor_filepath_1, &mi, &vw, re_1, false).unwrap();
+            let regressor_filepath_2 = dir
+                .path()
+                .join("test_regressor2.fw")
+                .to_str()
+                .unwrap()
+                .to_owned();
+            save_regressor_to_filename(&regressor_filepath_2, &mi, &vw, re_2, false).unwrap();
+
+            // The mutable path
+            let (_mi1, _vw1, mut new_re_1) =
+                new_regressor_from_filename(&regressor_filepath_1, false, None).unwrap();
+            assert_eq!(
+                new_re_1.get_name(),
+                "Regressor with optimizer \"AdagradFlex\""
+            );
+            assert_eq!(
+                new_re_1.learn(fbuf_1, &mut pb_1, false),
+                expected_result_1_on_1
+            );
+            assert_eq!(new_re_1.predict(fbuf_1, &mut pb_1), expected_result_1_on_1);
+            assert_eq!(
+                new_re_1.learn(fbuf_2, &mut pb_1, false),
+                expected_result_2_on_1
+            );
+            assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_1);
+            assert_eq!(
+                hogwild_load(&mut new_re_1, &regressor_filepath_2).unwrap(),
+                prediction_log::model_version(&regressor_filepath_2).unwrap()
+            );
+            assert_eq!(
+                new_re_1.learn(fbuf_2, &mut pb_1, false),
+                expected_result_2_on_2
+            );
+            assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_2);
+            hogwild_load(&mut new_re_1, &regressor_filepath_1).unwrap();
+            assert_eq!(
+                new_re_1.learn(fbuf_1, &mut pb_1, false),
+                expected_result_1_on_1
+            );
+            assert_eq!(new_re_1.predict(fbuf_1, &mut pb_1), expected_result_1_on_1);
+            assert_eq!(
+                new_re_1.learn(fbuf_2, &mut pb_1, false),
+                expected_result_2_on_1
+            );
+            assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_1);
+
+            // The immutable path
+            let (_mi1, _vw1, mut new_re_1) =
+                new_regressor_from_filename(&regressor_filepath_1, true, None).unwrap();
+            assert_eq!(new_re_1.get_name(), "Regressor with optimizer \"SGD\"");
+            assert_eq!(
+                new_re_1.learn(fbuf_1, &mut pb_1, false),
+                expected_result_1_on_1
+            );
+            assert_eq!(new_re_1.predict(fbuf_1, &mut pb_1), expected_result_1_on_1);
+            assert_eq!(
+                new_re_1.learn(fbuf_2, &mut pb_1, false),
+                expected_result_2_on_1
+            );
+            assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_1);
+            hogwild_load(&mut new_re_1, &regressor_filepath_2).unwrap();
+            assert_eq!(
+                new_re_1.learn(fbuf_2, &mut pb_1, false),
+                expected_result_2_on_2
+            );
+            assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_2);
+            hogwild_load(&mut new_re_1, &regressor_filepath_1).unwrap();
+            assert_eq!(
+                new_re_1.learn(fbuf_1, &mut pb_1, false),
+                expected_result_1_on_1
+            );
+            assert_eq!(new_re_1.predict(fbuf_1, &mut pb_1), expected_result_1_on_1);
+            assert_eq!(
+                new_re_1.learn(fbuf_2, &mut pb_1, false),
+                expected_result_2_on_1
+            );
+            assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), expected_result_2_on_1);
+        }
     }
 }
@@ -1,8 +1,41 @@
+use std::collections::HashMap;
+
 #[derive(Clone, Debug)]
 pub struct PortBuffer {
     pub tape: Vec<f32>,
     pub observations: Vec<f32>,
     pub tape_len: usize,
+    // --minibatch: gradients that blocks accumulate until they apply them, by output offset of the block
+    pub minibatch_gradients: HashMap<usize, MinibatchGradients>,
+    // Scratch of the FFM block, sized from ffm_k and the number of fields
+    pub ffm_contra_fields: Vec<f32>,
+    pub ffm_gradients: Vec<f32>,
+    // --fm_k: sums of the embeddings times the feature values, by dimension
+    pub fm_sums: Vec<f32>,
+    // --cross_layers: inputs of the cross layers and the gradient passed down through them
+    pub cross_layers: Vec<f32>,
+    // --oaa: probabilities of the classes from the softmax block, observations get the most likely class
+    pub class_probabilities: Vec<f32>,
+    // --heads: probabilities of the heads, observations get the first one
+    pub head_predictions: Vec<f32>,
+    // --bpr: logit of the last example, and logistic(s_neg - s_pos) of the pair that is being
+    // learned, from the logits of both before either updated the weights
+    pub bpr_logit: f32,
+    pub bpr_pair_gradient: Option<f32>,
+    // --invariant: gradient of the plain update of the example at the logit, and how much the logit
+    // moves per unit of gradient, which blocks add up in forward_backward
+    pub invariant_gradient: Option<f32>,
+    pub pred_per_update: f32,
+    // --blend_mode output: port buffer of the blended model
+    pub blend: Option<Box<PortBuffer>>,
+}
+
+// Gradients of a block over the examples of the current mini-batch
+#[derive(Clone, Debug, Default)]
+pub struct MinibatchGradients {
+    pub examples: u32,
+    pub dense: Vec<f32>,         // by weight index
+    pub sparse: Vec<(u32, f32)>, // weight index and gradient, in order of the examples
 }
 
 impl PortBuffer {
@@ -11,11 +44,24 @@ impl PortBuffer {
             tape: Default::default(),
             observations: Default::default(),
             tape_len,
+            minibatch_gradients: HashMap::new(),
+            ffm_contra_fields: Vec::new(),
+            ffm_gradients: Vec::new(),
+            fm_sums: Vec::new(),
+            cross_layers: Vec::new(),
+            class_probabilities: Vec::new(),
+            head_predictions: Vec::new(),
+            bpr_logit: 0.0,
+            bpr_pair_gradient: None,
+            invariant_gradient: None,
+            pred_per_update: 0.0,
+            blend: None,
         }
     }
 
     pub fn reset(&mut self) {
         self.observations.truncate(0);
         self.tape.resize(self.tape_len, 0.0);
+        self.pred_per_update = 0.0;
     }
 }
@@ -0,0 +1,293 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Binary log of every score the daemon serves (--prediction_log), for exact offline joins of
+// served scores with outcomes. Each file starts with PREDICTION_LOG_MAGIC and
+// PREDICTION_LOG_VERSION (u32), followed by records of PREDICTION_LOG_RECORD_LEN bytes,
+// all little endian:
+//   u64 timestamp, microseconds since unix epoch
+//   u64 request hash, fnv1a_64 of the request line without the line end
+//   u64 model version, fnv1a_64 of the served model file
+//   f32 prediction
+// When a file grows over the rotation size, it is renamed to <filename>.<timestamp in micros>
+// and a new one is started.
+
+pub const PREDICTION_LOG_MAGIC: &[u8; 4] = b"FWPL";
+pub const PREDICTION_LOG_VERSION: u32 = 1;
+pub const PREDICTION_LOG_RECORD_LEN: u64 = 28;
+const PREDICTION_LOG_HEADER_LEN: u64 = 8;
+pub const DEFAULT_ROTATE_MB: u64 = 1024;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PredictionRecord {
+    pub timestamp_micros: u64,
+    pub request_hash: u64,
+    pub model_version: u64,
+    pub prediction: f32,
+}
+
+// Simple to recompute offline in any language
+pub fn fnv1a_64(hash: u64, bytes: &[u8]) -> u64 {
+    let mut hash = hash;
+    for byte in bytes.iter() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+pub fn request_hash(line: &[u8]) -> u64 {
+    let mut line = line;
+    while let Some((b'\n' | b'\r', rest)) = line.split_last() {
+        line = rest;
+    }
+    fnv1a_64(FNV_OFFSET_BASIS, line)
+}
+
+// Version identifier of a model is the hash of its file, so the same file always gets the same one
+pub fn model_version_of_bytes(bytes: &[u8]) -> u64 {
+    fnv1a_64(FNV_OFFSET_BASIS, bytes)
+}
+
+pub fn model_version(filename: &str) -> Result<u64, Box<dyn Error>> {
+    let mut file = File::open(filename)?;
+    let mut buf: Vec<u8> = vec![0; 1 << 20];
+    let mut hash = FNV_OFFSET_BASIS;
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hash = fnv1a_64(hash, &buf[..len]);
+    }
+    Ok(hash)
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+struct PredictionLogFile {
+    writer: BufWriter<File>,
+    len: u64,
+}
+
+// Shared by all worker threads of the daemon
+pub struct PredictionLog {
+    filename: String,
+    rotate_bytes: u64,
+    model_version: AtomicU64,
+    file: Mutex<PredictionLogFile>,
+}
+
+impl PredictionLog {
+    pub fn new_from_cmdline(
+        cl: &clap::ArgMatches,
+    ) -> Result<Option<PredictionLog>, Box<dyn Error>> {
+        let filename = match cl.value_of("prediction_log") {
+            Some(filename) => filename,
+            None => return Ok(None),
+        };
+        let rotate_mb = match cl.value_of("prediction_log_rotate_mb") {
+            Some(mb) => mb.parse::<u64>()?,
+            None => DEFAULT_ROTATE_MB,
+        };
+        let model_version = match cl.value_of("initial_regressor") {
+            Some(model_filename) => model_version(model_filename)?,
+            None => 0,
+        };
+        Ok(Some(PredictionLog::new(
+            filename,
+            rotate_mb << 20,
+            model_version,
+        )?))
+    }
+
+    // An existing log is rotated away first, so every file starts with a header
+    pub fn new(
+        filename: &str,
+        rotate_bytes: u64,
+        model_version: u64,
+    ) -> Result<PredictionLog, Box<dyn Error>> {
+        if rotate_bytes < PREDICTION_LOG_HEADER_LEN + PREDICTION_LOG_RECORD_LEN {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!("Prediction log rotation size {} is too small", rotate_bytes),
+            )));
+        }
+        if Path::new(filename).exists() {
+            rotate_file(filename)?;
+        }
+        log::info!(
+            "Logging predictions to {}, model version {:016x}",
+            filename,
+            model_version
+        );
+        Ok(PredictionLog {
+            filename: filename.to_string(),
+            rotate_bytes,
+            model_version: AtomicU64::new(model_version),
+            file: Mutex::new(create_file(filename)?),
+        })
+    }
+
+    pub fn model_version(&self) -> u64 {
+        self.model_version.load(Ordering::Relaxed)
+    }
+
+    // Called when the daemon starts serving another model
+    pub fn set_model_version(&self, model_version: u64) {
+        log::info!("Prediction log model version {:016x}", model_version);
+        self.model_version.store(model_version, Ordering::Relaxed);
+    }
+
+    pub fn log(&self, request_line: &[u8], prediction: f32) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.len + PREDICTION_LOG_RECORD_LEN > self.rotate_bytes {
+            file.writer.flush()?;
+            rotate_file(&self.filename)?;
+            *file = create_file(&self.filename)?;
+        }
+        file.writer.write_u64::<LittleEndian>(now_micros())?;
+        file.writer
+            .write_u64::<LittleEndian>(request_hash(request_line))?;
+        file.writer
+            .write_u64::<LittleEndian>(self.model_version())?;
+        file.writer.write_f32::<LittleEndian>(prediction)?;
+        file.len += PREDICTION_LOG_RECORD_LEN;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.lock().unwrap().writer.flush()
+    }
+}
+
+fn create_file(filename: &str) -> io::Result<PredictionLogFile> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    writer.write_all(PREDICTION_LOG_MAGIC)?;
+    writer.write_u32::<LittleEndian>(PREDICTION_LOG_VERSION)?;
+    Ok(PredictionLogFile {
+        writer,
+        len: PREDICTION_LOG_HEADER_LEN,
+    })
+}
+
+fn rotate_file(filename: &str) -> io::Result<()> {
+    let mut timestamp = now_micros();
+    while Path::new(&format!("{}.{}", filename, timestamp)).exists() {
+        timestamp += 1;
+    }
+    fs::rename(filename, format!("{}.{}", filename, timestamp))
+}
+
+// Reads a prediction log file, for offline tools
+pub fn read_records(input: &mut impl Read) -> Result<Vec<PredictionRecord>, Box<dyn Error>> {
+    let mut magic: [u8; 4] = [0; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != PREDICTION_LOG_MAGIC {
+        return Err("Prediction log does not begin with magic bytes FWPL")?;
+    }
+    let version = input.read_u32::<LittleEndian>()?;
+    if version != PREDICTION_LOG_VERSION {
+        return Err(format!(
+            "Prediction log version of this binary: {}, version of the log: {}",
+            PREDICTION_LOG_VERSION, version
+        ))?;
+    }
+    let mut records: Vec<PredictionRecord> = Vec::new();
+    loop {
+        let timestamp_micros = match input.read_u64::<LittleEndian>() {
+            Ok(timestamp_micros) => timestamp_micros,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Box::new(e)),
+        };
+        records.push(PredictionRecord {
+            timestamp_micros,
+            request_hash: input.read_u64::<LittleEndian>()?,
+            model_version: input.read_u64::<LittleEndian>()?,
+            prediction: input.read_f32::<LittleEndian>()?,
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hashes() {
+        // Reference values of FNV-1a 64
+        assert_eq!(request_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(request_hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(request_hash(b"1 |A a\n"), request_hash(b"1 |A a\r\n"));
+        assert_ne!(request_hash(b"1 |A a\n"), request_hash(b"1 |A b\n"));
+
+        let dir = tempdir().unwrap();
+        let model_filename = dir.path().join("model.fw");
+        let model_filename = model_filename.to_str().unwrap();
+        fs::write(model_filename, b"a").unwrap();
+        assert_eq!(model_version(model_filename).unwrap(), request_hash(b"a"));
+    }
+
+    #[test]
+    fn test_prediction_log() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("predictions.log");
+        let filename = filename.to_str().unwrap();
+        fs::write(filename, b"earlier log").unwrap();
+        assert!(PredictionLog::new(filename, 10, 1).is_err());
+
+        // Room for two records per file
+        let log = PredictionLog::new(filename, 8 + 2 * 28, 1).unwrap();
+        log.log(b"1 |A a\n", 0.25).unwrap();
+        log.log(b"1 |A b\n", 0.5).unwrap();
+        log.set_model_version(2);
+        log.log(b"1 |A a\n", 0.75).unwrap();
+        log.flush().unwrap();
+
+        let mut rotated: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path().to_str().unwrap().to_string())
+            .filter(|path| path != filename)
+            .collect();
+        rotated.sort();
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(fs::read(&rotated[0]).unwrap(), b"earlier log");
+
+        let records = read_records(&mut File::open(&rotated[1]).unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].request_hash, request_hash(b"1 |A a"));
+        assert_eq!(records[0].model_version, 1);
+        assert_eq!(records[0].prediction, 0.25);
+        assert_eq!(records[1].prediction, 0.5);
+        assert!(records[0].timestamp_micros <= records[1].timestamp_micros);
+
+        let records = read_records(&mut File::open(filename).unwrap()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].request_hash, request_hash(b"1 |A a"));
+        assert_eq!(records[0].model_version, 2);
+        assert_eq!(records[0].prediction, 0.75);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::multi_source::SourceMetrics;
+
+// VW style progressive validation: every example is predicted before the model learns from it, so
+// the average logloss over the predictions is an online estimate of the quality on unseen data.
+// It is reported at every power of two examples, along with the average since the last report.
+#[derive(Default)]
+pub struct ProgressiveValidation {
+    pub total: SourceMetrics,
+    since_last: SourceMetrics,
+    next_report: u64,
+}
+
+impl ProgressiveValidation {
+    pub fn new() -> ProgressiveValidation {
+        ProgressiveValidation {
+            next_report: 1,
+            ..Default::default()
+        }
+    }
+
+    // Returns the report line, when example_num is due for one
+    pub fn add_example(
+        &mut self,
+        example_num: u64,
+        record: &[u32],
+        prediction: Option<f32>,
+    ) -> Option<String> {
+        self.total.add_example(record, prediction);
+        self.since_last.add_example(record, prediction);
+        if example_num < self.next_report {
+            return None;
+        }
+        while self.next_report <= example_num {
+            self.next_report *= 2;
+        }
+        if self.since_last.evaluated == 0 {
+            return None;
+        }
+        let line = format!(
+            "Progressive validation at example {}: average logloss {:.6}, since last {:.6}",
+            example_num,
+            self.total.logloss_sum / self.total.evaluated as f64,
+            self.since_last.logloss_sum / self.since_last.evaluated as f64
+        );
+        self.since_last = SourceMetrics::default();
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser;
+
+    fn record(label: u32) -> Vec<u32> {
+        let mut record = vec![0u32; parser::HEADER_LEN as usize];
+        record[parser::LABEL_OFFSET] = label;
+        record
+    }
+
+    #[test]
+    fn test_progressive_validation() {
+        let mut pv = ProgressiveValidation::new();
+        let mut reports = Vec::new();
+        for example_num in 1..=10 {
+            if let Some(line) = pv.add_example(example_num, &record(1), Some(0.5)) {
+                reports.push((example_num, line));
+            }
+        }
+        assert_eq!(
+            reports.iter().map(|r| r.0).collect::<Vec<u64>>(),
+            vec![1, 2, 4, 8]
+        );
+        assert_eq!(
+            reports[3].1,
+            "Progressive validation at example 8: average logloss 0.693147, since last 0.693147"
+        );
+        assert_eq!(pv.total.evaluated, 10);
+
+        // Examples without a prediction or a label do not count, reports without any are skipped
+        let mut pv = ProgressiveValidation::new();
+        assert_eq!(
+            pv.add_example(1, &record(parser::NO_LABEL), Some(0.5)),
+            None
+        );
+        assert_eq!(pv.add_example(2, &record(1), None), None);
+        assert_eq!(pv.add_example(3, &record(0), Some(0.5)), None);
+        assert!(pv.add_example(4, &record(1), Some(0.5)).is_some());
+        // Starting after a resume point reports at the next power of two
+        let mut pv = ProgressiveValidation::new();
+        assert!(pv.add_example(1000, &record(1), Some(0.5)).is_some());
+        assert_eq!(pv.add_example(1001, &record(1), Some(0.5)), None);
+        assert!(pv.add_example(1024, &record(1), Some(0.5)).is_some());
+    }
+}
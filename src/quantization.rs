@@ -1,4 +1,6 @@
 use half::f16;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
 use std::io;
 
 const BY_X: usize = 2;
@@ -8,6 +10,34 @@ const MEAN_SAMPLING_RATIO: usize = 10;
 const MIN_PREC: f32 = 10_000.0;
 const MAX_PREC: f32 = 10_000.0;
 
+// How FFM and FM weights are stored with --weight_quantization, chosen with --quantize_model
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WeightQuantization {
+    Buckets16, // f16 index of one of NUM_BUCKETS equal buckets between the min and max weight
+    Fp16,
+    Int8, // scale followed by the weights divided by it, the largest weight maps to 127
+}
+
+impl Default for WeightQuantization {
+    fn default() -> Self {
+        WeightQuantization::Buckets16
+    }
+}
+
+impl WeightQuantization {
+    pub fn parse(s: &str) -> Result<WeightQuantization, Box<dyn Error>> {
+        match s {
+            "buckets16" => Ok(WeightQuantization::Buckets16),
+            "fp16" => Ok(WeightQuantization::Fp16),
+            "int8" => Ok(WeightQuantization::Int8),
+            _ => Err(format!(
+                "--quantize_model has to be one of buckets16, fp16 or int8, got: {}",
+                s
+            ))?,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct WeightStat {
     min: f32,
@@ -74,10 +104,7 @@ pub fn quantize_ffm_weights(weights: &[f32]) -> Vec<[u8; BY_X]> {
     v
 }
 
-pub fn dequantize_ffm_weights(
-    input_bufreader: &mut dyn io::Read,
-    reference_weights: &mut Vec<f32>,
-) {
+pub fn dequantize_ffm_weights(input_bufreader: &mut dyn io::Read, reference_weights: &mut [f32]) {
     let mut header: [u8; 8] = [0; 8];
     input_bufreader.read_exact(&mut header).unwrap();
 
@@ -94,6 +121,70 @@ pub fn dequantize_ffm_weights(
     }
 }
 
+pub fn write_quantized_weights(
+    quantization: WeightQuantization,
+    weights: &[f32],
+    output_bufwriter: &mut dyn io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let bytes: Vec<u8> = match quantization {
+        WeightQuantization::Buckets16 => quantize_ffm_weights(weights)
+            .into_iter()
+            .flatten()
+            .collect(),
+        WeightQuantization::Fp16 => weights
+            .iter()
+            .flat_map(|w| f16::from_f32(*w).to_le_bytes())
+            .collect(),
+        WeightQuantization::Int8 => {
+            let max_abs = weights
+                .iter()
+                .fold(0.0f32, |max_abs, w| max_abs.max(w.abs()));
+            let scale = max_abs / i8::MAX as f32;
+            let mut bytes = Vec::with_capacity(weights.len() + 4);
+            bytes.extend_from_slice(&scale.to_le_bytes());
+            for w in weights.iter() {
+                let quantized = if scale > 0.0 {
+                    (w / scale).round() as i8
+                } else {
+                    0
+                };
+                bytes.push(quantized as u8);
+            }
+            bytes
+        }
+    };
+    output_bufwriter.write_all(&bytes)?;
+    Ok(())
+}
+
+pub fn read_quantized_weights(
+    quantization: WeightQuantization,
+    input_bufreader: &mut dyn io::Read,
+    weights: &mut [f32],
+) -> Result<(), Box<dyn Error>> {
+    match quantization {
+        WeightQuantization::Buckets16 => dequantize_ffm_weights(input_bufreader, weights),
+        WeightQuantization::Fp16 => {
+            let mut bytes = vec![0u8; weights.len() * 2];
+            input_bufreader.read_exact(&mut bytes)?;
+            for (w, b) in weights.iter_mut().zip(bytes.chunks_exact(2)) {
+                *w = f16::from_le_bytes([b[0], b[1]]).to_f32();
+            }
+        }
+        WeightQuantization::Int8 => {
+            let mut scale_bytes: [u8; 4] = [0; 4];
+            input_bufreader.read_exact(&mut scale_bytes)?;
+            let scale = f32::from_le_bytes(scale_bytes);
+            let mut bytes = vec![0u8; weights.len()];
+            input_bufreader.read_exact(&mut bytes)?;
+            for (w, b) in weights.iter_mut().zip(bytes.iter()) {
+                *w = (*b as i8) as f32 * scale;
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -159,6 +250,51 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fp16_and_int8() {
+        let weights = vec![0.51, -0.12, 0.0, 0.1232, -0.6123, 0.23];
+        for (quantization, bytes_per_weight, header, allowed_eps) in [
+            (WeightQuantization::Fp16, 2, 0, 0.001),
+            (WeightQuantization::Int8, 1, 4, 0.6123 / 127.0),
+        ] {
+            let mut buf: Vec<u8> = Vec::new();
+            write_quantized_weights(quantization, &weights, &mut buf).unwrap();
+            assert_eq!(buf.len(), header + weights.len() * bytes_per_weight);
+
+            let mut dequantized = vec![1.0; weights.len()];
+            read_quantized_weights(quantization, &mut io::Cursor::new(buf), &mut dequantized)
+                .unwrap();
+            for (w, dw) in weights.iter().zip(&dequantized) {
+                assert!(
+                    (w - dw).abs() <= allowed_eps,
+                    "{:?}: {} vs {}",
+                    quantization,
+                    w,
+                    dw
+                );
+            }
+            assert_eq!(dequantized[2], 0.0);
+        }
+
+        // All zero weights don't divide by zero
+        let mut buf: Vec<u8> = Vec::new();
+        write_quantized_weights(WeightQuantization::Int8, &[0.0, 0.0], &mut buf).unwrap();
+        let mut dequantized = vec![1.0; 2];
+        read_quantized_weights(
+            WeightQuantization::Int8,
+            &mut io::Cursor::new(buf),
+            &mut dequantized,
+        )
+        .unwrap();
+        assert_eq!(dequantized, vec![0.0, 0.0]);
+
+        assert!(WeightQuantization::parse("int4").is_err());
+        assert_eq!(
+            WeightQuantization::parse("fp16").unwrap(),
+            WeightQuantization::Fp16
+        );
+    }
+
     #[test]
     #[ignore]
     fn test_performance() {
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Rate limits of the daemon, for when several teams share one daemon: examples to predict per
+// second of each connection and of each client ip, over all its connections. A request (a line,
+// a "batch" command, an http or grpc request) is admitted or limited as a whole.
+
+pub const RATE_LIMITED_RESPONSE: &str = "ERR: rate limited\n";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverLimit {
+    Queue,  // wait until the request is within the limit
+    Reject, // answer with RATE_LIMITED_RESPONSE instead of a prediction
+}
+
+impl OverLimit {
+    pub fn parse(s: &str) -> Result<OverLimit, Box<dyn Error>> {
+        match s {
+            "queue" => Ok(OverLimit::Queue),
+            "reject" => Ok(OverLimit::Reject),
+            _ => Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Unknown over limit behavior {}, expected queue or reject",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
+// Clients whose buckets are full are forgotten at most this often, they'd start with a full one
+const CLIENT_EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+
+// Holds up to a second worth of examples, so short bursts pass
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate.max(1.0),
+            last: now,
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity());
+        self.last = now;
+    }
+
+    // Requests larger than the bucket only wait for a full one, and leave it in debt
+    fn wait_time(&self, examples: usize) -> Duration {
+        let needed = (examples as f64).min(self.capacity());
+        if self.tokens >= needed {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / self.rate)
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.capacity()
+    }
+}
+
+struct ClientBuckets {
+    buckets: HashMap<Option<IpAddr>, TokenBucket>,
+    last_eviction: Instant,
+}
+
+impl ClientBuckets {
+    // Full buckets are the same as new ones, so forgetting them only frees memory
+    fn evict_idle(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_eviction) < CLIENT_EVICTION_INTERVAL {
+            return;
+        }
+        self.buckets.retain(|_, bucket| !bucket.is_full(now));
+        self.last_eviction = now;
+    }
+}
+
+#[derive(Default)]
+struct RateLimitCounters {
+    allowed: AtomicU64,
+    queued: AtomicU64,
+    rejected: AtomicU64,
+}
+
+// Shared by all worker threads of the daemon
+pub struct RateLimiter {
+    per_connection: Option<f64>,
+    per_client: Option<f64>,
+    over_limit: OverLimit,
+    clients: Mutex<ClientBuckets>,
+    counters: RateLimitCounters,
+}
+
+impl RateLimiter {
+    pub fn new_from_cmdline(
+        cl: &clap::ArgMatches,
+    ) -> Result<Option<Arc<RateLimiter>>, Box<dyn Error>> {
+        let per_connection = match cl.value_of("rate_limit_connection") {
+            Some(rate) => Some(rate.parse::<f64>()?),
+            None => None,
+        };
+        let per_client = match cl.value_of("rate_limit_client") {
+            Some(rate) => Some(rate.parse::<f64>()?),
+            None => None,
+        };
+        let over_limit = match cl.value_of("rate_limit_mode") {
+            Some(mode) => OverLimit::parse(mode)?,
+            None => OverLimit::Queue,
+        };
+        if per_connection.is_none() && per_client.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(RateLimiter::new(
+            per_connection,
+            per_client,
+            over_limit,
+        )?)))
+    }
+
+    pub fn new(
+        per_connection: Option<f64>,
+        per_client: Option<f64>,
+        over_limit: OverLimit,
+    ) -> Result<RateLimiter, Box<dyn Error>> {
+        for rate in per_connection.iter().chain(per_client.iter()) {
+            if *rate <= 0.0 {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("Rate limits have to be positive, got {}", rate),
+                )));
+            }
+        }
+        Ok(RateLimiter {
+            per_connection,
+            per_client,
+            over_limit,
+            clients: Mutex::new(ClientBuckets {
+                buckets: HashMap::new(),
+                last_eviction: Instant::now(),
+            }),
+            counters: RateLimitCounters::default(),
+        })
+    }
+
+    // Counters for the "stats" command
+    pub fn format_counters(&self) -> String {
+        format!(
+            "rate_limit allowed:{} queued:{} rejected:{}\n",
+            self.counters.allowed.load(Ordering::Relaxed),
+            self.counters.queued.load(Ordering::Relaxed),
+            self.counters.rejected.load(Ordering::Relaxed)
+        )
+    }
+}
+
+// Limits of a single connection, client is None when the peer address is not known
+pub struct ConnectionLimiter {
+    limiter: Arc<RateLimiter>,
+    client: Option<IpAddr>,
+    bucket: Option<TokenBucket>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limiter: &Arc<RateLimiter>, client: Option<IpAddr>) -> ConnectionLimiter {
+        ConnectionLimiter {
+            limiter: Arc::clone(limiter),
+            client,
+            bucket: limiter
+                .per_connection
+                .map(|rate| TokenBucket::new(rate, Instant::now())),
+        }
+    }
+
+    // Returns whether a request of that many examples can be served. Over the limit, this waits
+    // with OverLimit::Queue and returns false with OverLimit::Reject. Counters count examples.
+    pub fn admit(&mut self, examples: usize) -> bool {
+        let mut queued = false;
+        loop {
+            let now = Instant::now();
+            let wait_time = match self.limiter.per_client {
+                Some(rate) => {
+                    let mut clients = self.limiter.clients.lock().unwrap();
+                    clients.evict_idle(now);
+                    let client_bucket = clients
+                        .buckets
+                        .entry(self.client)
+                        .or_insert_with(|| TokenBucket::new(rate, now));
+                    take_tokens(
+                        self.bucket.iter_mut().chain(Some(client_bucket)),
+                        examples,
+                        now,
+                    )
+                }
+                None => take_tokens(self.bucket.iter_mut(), examples, now),
+            };
+
+            let counters = &self.limiter.counters;
+            if wait_time == Duration::from_secs(0) {
+                counters
+                    .allowed
+                    .fetch_add(examples as u64, Ordering::Relaxed);
+                return true;
+            }
+            match self.limiter.over_limit {
+                OverLimit::Reject => {
+                    counters
+                        .rejected
+                        .fetch_add(examples as u64, Ordering::Relaxed);
+                    return false;
+                }
+                OverLimit::Queue => {
+                    if !queued {
+                        counters
+                            .queued
+                            .fetch_add(examples as u64, Ordering::Relaxed);
+                        queued = true;
+                    }
+                    thread::sleep(wait_time);
+                }
+            }
+        }
+    }
+}
+
+// A request takes from all the buckets or from none, returns how long to wait when from none
+fn take_tokens<'a>(
+    buckets: impl Iterator<Item = &'a mut TokenBucket>,
+    examples: usize,
+    now: Instant,
+) -> Duration {
+    let mut buckets: Vec<&mut TokenBucket> = buckets.collect();
+    let mut wait_time = Duration::from_secs(0);
+    for bucket in buckets.iter_mut() {
+        bucket.refill(now);
+        wait_time = wait_time.max(bucket.wait_time(examples));
+    }
+    if wait_time == Duration::from_secs(0) {
+        for bucket in buckets.iter_mut() {
+            bucket.tokens -= examples as f64;
+        }
+    }
+    wait_time
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, now);
+        assert_eq!(bucket.tokens, 10.0);
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait_time(1), Duration::from_millis(100));
+        assert_eq!(bucket.wait_time(2), Duration::from_millis(200));
+        // Larger requests than the bucket wait for a full one
+        assert_eq!(bucket.wait_time(20), Duration::from_secs(1));
+        bucket.refill(now + Duration::from_millis(500));
+        assert!((bucket.tokens - 5.0).abs() < 1e-9);
+        // No more than a second worth
+        bucket.refill(now + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        assert!(RateLimiter::new(Some(0.0), None, OverLimit::Reject).is_err());
+        assert!(OverLimit::parse("drop").is_err());
+
+        // Client limit is shared by connections of the same client
+        let limiter = Arc::new(RateLimiter::new(Some(3.0), Some(4.0), OverLimit::Reject).unwrap());
+        let client: Option<IpAddr> = Some("10.0.0.1".parse().unwrap());
+        let mut connection1 = ConnectionLimiter::new(&limiter, client);
+        let mut connection2 = ConnectionLimiter::new(&limiter, client);
+        let mut other_client = ConnectionLimiter::new(&limiter, None);
+        assert!(connection1.admit(1));
+        assert!(connection1.admit(1));
+        assert!(connection1.admit(1));
+        assert!(!connection1.admit(1)); // connection limit
+        assert!(connection2.admit(1));
+        assert!(!connection2.admit(1)); // client limit
+        assert!(other_client.admit(1));
+        assert_eq!(
+            limiter.format_counters(),
+            "rate_limit allowed:5 queued:0 rejected:2\n"
+        );
+
+        let limiter = Arc::new(RateLimiter::new(Some(100.0), None, OverLimit::Queue).unwrap());
+        let mut connection = ConnectionLimiter::new(&limiter, None);
+        let start = Instant::now();
+        for _ in 0..101 {
+            assert!(connection.admit(1));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(9));
+        assert_eq!(
+            limiter.format_counters(),
+            "rate_limit allowed:101 queued:1 rejected:0\n"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_requests() {
+        // A request of several examples is admitted as a whole
+        let limiter = Arc::new(RateLimiter::new(None, Some(3.0), OverLimit::Reject).unwrap());
+        let mut connection = ConnectionLimiter::new(&limiter, None);
+        assert!(connection.admit(2));
+        assert!(!connection.admit(2));
+        assert!(connection.admit(1));
+        assert_eq!(
+            limiter.format_counters(),
+            "rate_limit allowed:3 queued:0 rejected:2\n"
+        );
+
+        // One larger than the limit passes with a full bucket, the next ones pay for it
+        let limiter = Arc::new(RateLimiter::new(Some(3.0), None, OverLimit::Reject).unwrap());
+        let mut connection = ConnectionLimiter::new(&limiter, None);
+        assert!(connection.admit(5));
+        assert!(!connection.admit(1));
+    }
+
+    #[test]
+    fn test_evict_idle_clients() {
+        let now = Instant::now();
+        let mut clients = ClientBuckets {
+            buckets: HashMap::new(),
+            last_eviction: now,
+        };
+        let client1: Option<IpAddr> = Some("10.0.0.1".parse().unwrap());
+        let client2: Option<IpAddr> = Some("10.0.0.2".parse().unwrap());
+        let mut bucket = TokenBucket::new(1.0, now);
+        bucket.tokens = 0.0;
+        clients.buckets.insert(client1, bucket);
+        clients.buckets.insert(client2, TokenBucket::new(1.0, now));
+        clients.buckets.get_mut(&client2).unwrap().tokens = 0.5;
+        // Not before the interval
+        clients.evict_idle(now + Duration::from_secs(5));
+        assert_eq!(clients.buckets.len(), 2);
+        // Both would be full by now
+        clients.evict_idle(now + CLIENT_EVICTION_INTERVAL);
+        assert!(clients.buckets.is_empty());
+
+        let mut bucket = TokenBucket::new(0.01, now);
+        bucket.tokens = 0.0;
+        clients.buckets.insert(client1, bucket);
+        clients.evict_idle(now + CLIENT_EVICTION_INTERVAL * 2);
+        assert_eq!(clients.buckets.len(), 1);
+    }
+}
@@ -4,8 +4,16 @@ use std::any::Any;
 use std::error::Error;
 use std::io;
 use std::io::Cursor;
+use std::io::Read;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::blend;
+use crate::block_cross;
+use crate::block_embedding_lookup;
 use crate::block_ffm;
+use crate::block_field_attention;
+use crate::block_fm;
 use crate::block_helpers;
 use crate::block_loss_functions;
 use crate::block_lr;
@@ -18,9 +26,19 @@ use crate::feature_buffer;
 use crate::feature_buffer::HashAndValueAndSeq;
 use crate::graph;
 use crate::model_instance;
+use crate::onnx;
 use crate::port_buffer;
-
-pub const FFM_CONTRA_BUF_LEN: usize = 41472;
+use crate::score_map::ScoreMap;
+
+// Every block that has weights is written to the model file as a frame: type id, byte length, data
+// Ids must never be reused, as older binaries use them to recognize blocks they know
+pub const SERIALIZED_BLOCK_ID_LR: u32 = 1;
+pub const SERIALIZED_BLOCK_ID_FFM: u32 = 2;
+pub const SERIALIZED_BLOCK_ID_NEURAL: u32 = 3;
+pub const SERIALIZED_BLOCK_ID_EMBEDDING_LOOKUP: u32 = 4;
+pub const SERIALIZED_BLOCK_ID_FM: u32 = 5;
+pub const SERIALIZED_BLOCK_ID_CROSS: u32 = 6;
+pub const SERIALIZED_BLOCK_ID_FIELD_ATTENTION: u32 = 7;
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct FFMFeature {
@@ -39,7 +57,7 @@ impl From<&HashAndValueAndSeq> for FFMFeature {
 
 pub enum BlockCache {
     FFM {
-        contra_fields: [f32; FFM_CONTRA_BUF_LEN],
+        contra_fields: Vec<f32>,
         features_present: FxHashSet<FFMFeature>,
         ffm: Vec<f32>,
     },
@@ -49,6 +67,16 @@ pub enum BlockCache {
     },
 }
 
+// Parts of the prediction that blocks compute separately, read from the tape after forward
+#[derive(Debug, PartialEq)]
+pub enum OutputDecomposition<'a> {
+    LRCombos(&'a [f32]),  // one sum per feature combo, the constant feature last
+    FFMFields(&'a [f32]), // fields x fields, symmetric, each half of the interaction of two fields
+    Logit(f32),           // input of the final sigmoid
+    // Output of an intermediate block, by the name of the observe block behind it
+    Block(String, &'a [f32]),
+}
+
 pub trait BlockTrait {
     fn as_any(&mut self) -> &mut dyn Any; // This enables downcasting
     fn forward_backward(
@@ -93,10 +121,48 @@ pub trait BlockTrait {
 
     fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {}
 
+    // Sizes scratch buffers that the block keeps in port buffers
+    fn init_port_buffer(&self, _pb: &mut port_buffer::PortBuffer) {}
+
+    // Hints the cpu to load the weights that forward() will read for fb, ahead of that call
+    fn prefetch(&self, _fb: &feature_buffer::FeatureBuffer) {}
+
+    // --minibatch: applies gradients accumulated in the port buffer, even of a partial mini-batch
+    fn apply_minibatch(&mut self, _pb: &mut port_buffer::PortBuffer) {}
+
+    // Replaces the learning rate schedule the block took from the model instance, between passes
+    fn set_lr_schedule(&mut self, _lr_schedule: &Option<model_instance::LRSchedule>) {}
+
+    // Fills weights that come from outside of the model, only when training starts from scratch
+    fn load_pretrained_weights(
+        &mut self,
+        _mi: &model_instance::ModelInstance,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     fn get_serialized_len(&self) -> usize {
         0
     }
 
+    // --delta_base: from now on the block keeps track of the weights it writes
+    fn track_dirty_weights(&mut self) {}
+
+    // Byte ranges of write_weights_to_buf() output written since track_dirty_weights(), None when
+    // the block doesn't keep track and all of it has to be saved
+    fn dirty_weight_ranges(&self) -> Option<Vec<Range<usize>>> {
+        None
+    }
+
+    fn get_serialized_block_id(&self) -> u32 {
+        0
+    }
+
+    // Moves the weights of a forward-only block to a memory mapped file in dir (--mmap_weights)
+    fn map_weights(&mut self, _dir: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     fn write_weights_to_buf(
         &self,
         _output_bufwriter: &mut dyn io::Write,
@@ -137,6 +203,21 @@ pub trait BlockTrait {
     ) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
+
+    fn get_output_decomposition<'a>(
+        &self,
+        _pb: &'a port_buffer::PortBuffer,
+    ) -> Option<OutputDecomposition<'a>> {
+        None
+    }
+
+    // Adds the operators of forward() to an ONNX graph, see onnx.rs
+    fn to_onnx(&self, _graph: &mut onnx::OnnxGraph) -> Result<(), Box<dyn Error>> {
+        Err(format!(
+            "Block {} can't be exported to ONNX",
+            std::any::type_name::<Self>()
+        ))?
+    }
 }
 
 pub struct Regressor {
@@ -144,6 +225,10 @@ pub struct Regressor {
     pub blocks_boxes: Vec<Box<dyn BlockTrait>>,
     pub tape_len: usize,
     pub immutable: bool,
+    graph_paranoia_pending: AtomicBool, // --graph_paranoia: port buffer still needs to be checked on first example
+    score_map: Option<ScoreMap>,
+    invariant: bool,
+    pub blend: Option<Box<blend::OutputBlend>>, // --blend_mode output: model whose predictions are blended in
 }
 
 pub fn get_regressor_without_weights(mi: &model_instance::ModelInstance) -> Regressor {
@@ -176,6 +261,10 @@ impl Regressor {
             regressor_name: format!("Regressor with optimizer \"{:?}\"", mi.optimizer),
             immutable: false,
             tape_len: usize::MAX,
+            graph_paranoia_pending: AtomicBool::new(mi.graph_paranoia),
+            score_map: mi.score_map.clone(),
+            invariant: mi.invariant,
+            blend: None,
         };
 
         let mut bg = graph::BlockGraph::new();
@@ -183,11 +272,26 @@ impl Regressor {
         let mut output = block_lr::new_lr_block(&mut bg, mi).unwrap();
 
         if mi.ffm_k > 0 {
-            let block_ffm = block_ffm::new_ffm_block(&mut bg, mi).unwrap();
+            let mut block_ffm = block_ffm::new_ffm_block(&mut bg, mi).unwrap();
+            if mi.ffm_field_attention {
+                block_ffm =
+                    block_field_attention::new_field_attention_block(&mut bg, mi, block_ffm)
+                        .unwrap();
+            }
             let triangle_ffm = block_misc::new_triangle_block(&mut bg, block_ffm).unwrap();
             output = block_misc::new_join_block(&mut bg, vec![output, triangle_ffm]).unwrap();
         }
 
+        if mi.fm_k > 0 {
+            let block_fm = block_fm::new_fm_block(&mut bg, mi).unwrap();
+            output = block_misc::new_join_block(&mut bg, vec![output, block_fm]).unwrap();
+        }
+
+        if mi.cross_layers > 0 {
+            output = block_cross::new_cross_block(&mut bg, mi, output, mi.cross_layers as usize)
+                .unwrap();
+        }
+
         if !mi.nn_config.layers.is_empty() {
             let mut join_block: Option<graph::BlockPtrOutput> = None;
             if mi.nn_config.topology == "one" {
@@ -214,6 +318,27 @@ impl Regressor {
                 .unwrap()
             }
 
+            if !mi.dense_inputs.is_empty() {
+                let dense_input = block_misc::new_dense_input_block(&mut bg, mi).unwrap();
+                output = block_misc::new_join_block(&mut bg, vec![output, dense_input]).unwrap();
+            }
+
+            for (lookup_index, lookup) in mi.embedding_lookups.iter().enumerate() {
+                let mut embedding =
+                    block_embedding_lookup::new_embedding_lookup_block(&mut bg, mi, lookup_index)
+                        .unwrap();
+                if lookup.pooling != model_instance::EmbeddingPooling::Sum {
+                    embedding = block_misc::new_pooling_block(
+                        &mut bg,
+                        embedding,
+                        lookup.pooling,
+                        lookup.dim as usize,
+                    )
+                    .unwrap();
+                }
+                output = block_misc::new_join_block(&mut bg, vec![output, embedding]).unwrap();
+            }
+
             for (layer_num, layer) in mi.nn_config.layers.iter().enumerate() {
                 let mut layer = layer.clone();
                 let activation_str: String = layer
@@ -250,6 +375,13 @@ impl Regressor {
                     );
                 }
 
+                if dropout != 0.0 && mi.nn_dropout_schedule.is_some() {
+                    panic!(
+                        "--nn_dropout_schedule can not be combined with dropout set on layer number {}",
+                        layer_num
+                    );
+                }
+
                 let activation = match &*activation_str {
                     "none" => NNActivation::None,
                     "relu" => NNActivation::Relu,
@@ -287,6 +419,7 @@ impl Regressor {
                     width,
                     init_type,
                     dropout, // dropout
+                    mi.nn_dropout_schedule,
                     maxnorm, // max norm
                     false,
                 )
@@ -303,25 +436,96 @@ impl Regressor {
                     output =
                         block_normalize::new_normalize_layer_block(&mut bg, mi, output).unwrap();
                 }
+                output = block_misc::new_observe_block(
+                    &mut bg,
+                    output,
+                    block_misc::Observe::Named(format!("nn:{}", layer_num)),
+                    None,
+                )
+                .unwrap();
             }
             // If we have split
             if join_block.is_some() {
                 output =
                     block_misc::new_join_block(&mut bg, vec![output, join_block.unwrap()]).unwrap();
             }
-            output = block_neural::new_neuron_block(
+            if mi.oaa == 0 && mi.heads == 0 {
+                output = block_neural::new_neuron_block(
+                    &mut bg,
+                    mi,
+                    output,
+                    block_neural::NeuronType::WeightedSum,
+                    block_neural::InitType::One,
+                )
+                .unwrap();
+            }
+        }
+
+        if mi.oaa > 0 {
+            // --oaa: a neuron per class, their outputs are the logits of the softmax
+            output = block_neural::new_neuronlayer_block(
                 &mut bg,
                 mi,
                 output,
                 block_neural::NeuronType::WeightedSum,
-                block_neural::InitType::One,
+                mi.oaa as usize,
+                block_neural::InitType::Xavier,
+                0.0,   // dropout
+                None,  // dropout schedule
+                0.0,   // maxnorm
+                false, // layer norm
+            )
+            .unwrap();
+            let _lossf = block_loss_functions::new_softmax_block(&mut bg, output, true).unwrap();
+        } else if mi.heads > 0 {
+            // --heads: a neuron per head, each the logit of its own logloss
+            output = block_neural::new_neuronlayer_block(
+                &mut bg,
+                mi,
+                output,
+                block_neural::NeuronType::WeightedSum,
+                mi.heads as usize,
+                block_neural::InitType::Xavier,
+                0.0,   // dropout
+                None,  // dropout schedule
+                0.0,   // maxnorm
+                false, // layer norm
+            )
+            .unwrap();
+            let _lossf = block_loss_functions::new_heads_block(
+                &mut bg,
+                output,
+                true,
+                mi.focal_gamma,
+                mi.label_smoothing,
+            )
+            .unwrap();
+        } else if mi.bpr {
+            let _lossf = block_loss_functions::new_bpr_block(
+                &mut bg,
+                output,
+                true,
+                mi.accurate_accumulation,
+            )
+            .unwrap();
+        } else {
+            // now sigmoid has a single input
+            let _lossf = block_loss_functions::new_focal_logloss_block(
+                &mut bg,
+                output,
+                true,
+                mi.accurate_accumulation,
+                mi.focal_gamma,
+                mi.label_smoothing,
             )
             .unwrap();
         }
-
-        // now sigmoid has a single input
-        let _lossf = block_loss_functions::new_logloss_block(&mut bg, output, true).unwrap();
         bg.finalize();
+        if mi.graph_paranoia {
+            if let Err(e) = bg.check_wiring() {
+                panic!("{}", e);
+            }
+        }
         rg.tape_len = bg.get_tape_size();
 
         rg.blocks_boxes = bg.take_blocks();
@@ -346,13 +550,82 @@ impl Regressor {
     }
 
     pub fn new_portbuffer(&self) -> port_buffer::PortBuffer {
-        port_buffer::PortBuffer::new(self.tape_len)
+        let mut pb = port_buffer::PortBuffer::new(self.tape_len);
+        for block in self.blocks_boxes.iter() {
+            block.init_port_buffer(&mut pb);
+        }
+        if let Some(blend) = &self.blend {
+            pb.blend = Some(Box::new(blend.member.new_portbuffer()));
+        }
+        pb
     }
 
     pub fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
         self.allocate_and_init_weights_(mi);
     }
 
+    pub fn load_pretrained_weights(
+        &mut self,
+        mi: &model_instance::ModelInstance,
+    ) -> Result<(), Box<dyn Error>> {
+        for rr in &mut self.blocks_boxes {
+            rr.load_pretrained_weights(mi)?;
+        }
+        Ok(())
+    }
+
+    // Blocks index the tape unchecked, so a port buffer made for a different graph would silently corrupt memory
+    #[inline(always)]
+    fn check_port_buffer_once(&self, pb: &port_buffer::PortBuffer) {
+        if self.graph_paranoia_pending.load(Ordering::Relaxed) {
+            self.graph_paranoia_pending.store(false, Ordering::Relaxed);
+            assert_eq!(
+                pb.tape_len, self.tape_len,
+                "Port buffer has tape of size {}, but the graph of this regressor needs {}",
+                pb.tape_len, self.tape_len
+            );
+            log::info!("Graph paranoia: port buffer of the first example matches the graph");
+        }
+    }
+
+    // --score_map is applied only where predictions are output, learning and evaluation see
+    // the probabilities
+    #[inline(always)]
+    pub fn map_score(&self, score: f32) -> f32 {
+        match &self.score_map {
+            Some(score_map) => score_map.apply(score),
+            None => score,
+        }
+    }
+
+    // --blend_mode output: blends the prediction of this model with the one of the blended model
+    #[inline(always)]
+    fn blend_prediction(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        prediction: f32,
+    ) -> f32 {
+        match &self.blend {
+            Some(blend) => {
+                let pb_member = pb
+                    .blend
+                    .as_mut()
+                    .expect("Port buffer of a blended model has to come from its new_portbuffer()");
+                pb_member.reset();
+                block_helpers::forward(&blend.member.blocks_boxes[..], fb, pb_member);
+                assert_eq!(pb_member.observations.len(), 1);
+                let prediction_member = pb_member.observations.pop().unwrap();
+                let prediction_member =
+                    blend
+                        .member
+                        .blend_prediction(fb, pb_member, prediction_member);
+                blend::blend_probabilities(prediction, prediction_member, blend.alpha)
+            }
+            None => prediction,
+        }
+    }
+
     pub fn learn(
         &mut self,
         fb: &feature_buffer::FeatureBuffer,
@@ -369,29 +642,137 @@ impl Regressor {
             return self.predict(fb, pb);
         }
 
+        self.check_port_buffer_once(pb);
+        if self.invariant {
+            // Blocks find how far the update moves the logit from the gradient of the plain update
+            pb.reset();
+            block_helpers::forward(&self.blocks_boxes[..], fb, pb);
+            let probability = pb.observations.pop().unwrap();
+            let label = if fb.label == 1.0 { 1.0 } else { 0.0 };
+            pb.invariant_gradient = Some((probability - label) * fb.example_importance);
+        }
         pb.reset(); // empty the tape
         let further_blocks = &mut self.blocks_boxes[..];
         block_helpers::forward_backward(further_blocks, fb, pb, update);
+        pb.invariant_gradient = None;
 
         assert_eq!(pb.observations.len(), 1);
 
         pb.observations.pop().unwrap()
     }
 
+    // --bpr: learns from a pair of examples, one positive and one negative, on the difference of
+    // their logits before the update
+    pub fn learn_pair(
+        &mut self,
+        fb_a: &feature_buffer::FeatureBuffer,
+        fb_b: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        pb.bpr_pair_gradient = None;
+        self.predict(fb_a, pb);
+        let logit_a = pb.bpr_logit;
+        self.predict(fb_b, pb);
+        let logit_b = pb.bpr_logit;
+        let (logit_pos, logit_neg) = if fb_a.label == 1.0 {
+            (logit_a, logit_b)
+        } else {
+            (logit_b, logit_a)
+        };
+        // Computed once, the update of the first example must not change that of the second
+        pb.bpr_pair_gradient = Some(block_loss_functions::logistic(logit_neg - logit_pos));
+        self.learn(fb_a, pb, true);
+        self.learn(fb_b, pb, true);
+        pb.bpr_pair_gradient = None;
+    }
+
+    pub fn set_lr_schedule(&mut self, lr_schedule: &Option<model_instance::LRSchedule>) {
+        for block in self.blocks_boxes.iter_mut() {
+            block.set_lr_schedule(lr_schedule);
+        }
+    }
+
+    // Gradients of the last partial mini-batch, needed before saving the model
+    pub fn apply_minibatch(&mut self, pb: &mut port_buffer::PortBuffer) {
+        for block in self.blocks_boxes.iter_mut() {
+            block.apply_minibatch(pb);
+        }
+    }
+
     pub fn predict(
         &self,
         fb: &feature_buffer::FeatureBuffer,
         pb: &mut port_buffer::PortBuffer,
     ) -> f32 {
         // TODO: we should find a way of not using unsafe
+        self.check_port_buffer_once(pb);
         pb.reset(); // empty the tape
 
         let further_blocks = &self.blocks_boxes[..];
         block_helpers::forward(further_blocks, fb, pb);
 
         assert_eq!(pb.observations.len(), 1);
+        let prediction = pb.observations.pop().unwrap();
+        self.blend_prediction(fb, pb, prediction)
+    }
 
-        pb.observations.pop().unwrap()
+    #[inline(always)]
+    fn prefetch(&self, fb: &feature_buffer::FeatureBuffer) {
+        for block in self.blocks_boxes.iter() {
+            block.prefetch(fb);
+        }
+    }
+
+    // Predictions of a batch of examples, same as predict() of each. Weights of the next example
+    // are prefetched while the blocks compute the current one
+    pub fn predict_batch(
+        &self,
+        fbs: &[feature_buffer::FeatureBuffer],
+        pb: &mut port_buffer::PortBuffer,
+    ) -> Vec<f32> {
+        self.check_port_buffer_once(pb);
+        let further_blocks = &self.blocks_boxes[..];
+        if let Some(first_fb) = fbs.first() {
+            self.prefetch(first_fb);
+        }
+
+        let mut predictions = Vec::with_capacity(fbs.len());
+        for (i, fb) in fbs.iter().enumerate() {
+            if let Some(next_fb) = fbs.get(i + 1) {
+                self.prefetch(next_fb);
+            }
+            pb.reset(); // empty the tape
+            block_helpers::forward(further_blocks, fb, pb);
+
+            assert_eq!(pb.observations.len(), 1);
+            let prediction = pb.observations.pop().unwrap();
+            predictions.push(self.blend_prediction(fb, pb, prediction));
+        }
+        predictions
+    }
+
+    // Like predict(), also returns what the blocks computed separately. Of an output blend, only
+    // the base model is decomposed
+    pub fn predict_decomposed<'a>(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &'a mut port_buffer::PortBuffer,
+    ) -> (f32, Vec<OutputDecomposition<'a>>) {
+        self.check_port_buffer_once(pb);
+        pb.reset(); // empty the tape
+
+        let further_blocks = &self.blocks_boxes[..];
+        block_helpers::forward(further_blocks, fb, pb);
+
+        assert_eq!(pb.observations.len(), 1);
+        let prediction = pb.observations.pop().unwrap();
+        let pb: &port_buffer::PortBuffer = pb;
+        let decompositions = self
+            .blocks_boxes
+            .iter()
+            .filter_map(|block| block.get_output_decomposition(pb))
+            .collect();
+        (prediction, decompositions)
     }
 
     pub fn predict_with_cache(
@@ -400,13 +781,15 @@ impl Regressor {
         pb: &mut port_buffer::PortBuffer,
         caches: &[BlockCache],
     ) -> f32 {
+        self.check_port_buffer_once(pb);
         pb.reset(); // empty the tape
 
         let further_blocks = &self.blocks_boxes[..];
         block_helpers::forward_with_cache(further_blocks, fb, pb, caches);
 
         assert_eq!(pb.observations.len(), 1);
-        pb.observations.pop().unwrap()
+        let prediction = pb.observations.pop().unwrap();
+        self.blend_prediction(fb, pb, prediction)
     }
 
     pub fn setup_cache(
@@ -422,21 +805,50 @@ impl Regressor {
         block_helpers::prepare_forward_cache(further_blocks, fb, caches.as_mut_slice());
     }
 
-    // Yeah, this is weird. I just didn't want to break the format compatibility at this point
+    pub fn serialized_block_indexes(&self) -> Vec<usize> {
+        self.blocks_boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.get_serialized_len() > 0)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn serialized_len(&self) -> u64 {
+        self.blocks_boxes
+            .iter()
+            .map(|block| block.get_serialized_len() as u64)
+            .sum()
+    }
+
+    // --delta_base: weights written from now on go to the delta
+    pub fn track_dirty_weights(&mut self) {
+        for block in self.blocks_boxes.iter_mut() {
+            block.track_dirty_weights();
+        }
+    }
+
     pub fn write_weights_to_buf(
         &self,
         output_bufwriter: &mut dyn io::Write,
         quantize_weights: bool,
     ) -> Result<(), Box<dyn Error>> {
-        let length = self
-            .blocks_boxes
-            .iter()
-            .map(|block| block.get_serialized_len())
-            .sum::<usize>() as u64;
-        output_bufwriter.write_u64::<LittleEndian>(length)?;
+        let block_indexes = self.serialized_block_indexes();
+        output_bufwriter.write_u32::<LittleEndian>(block_indexes.len() as u32)?;
         log::info!("Write Quantization enabled: {}", quantize_weights);
-        for v in &self.blocks_boxes {
-            v.write_weights_to_buf(output_bufwriter, quantize_weights)?;
+        // Quantized size isn't known upfront, so each block is serialized to memory first
+        let mut block_buf: Vec<u8> = Vec::new();
+        for i in block_indexes {
+            let block = &self.blocks_boxes[i];
+            block_buf.truncate(0);
+            block.write_weights_to_buf(&mut block_buf, quantize_weights)?;
+            output_bufwriter.write_u32::<LittleEndian>(block.get_serialized_block_id())?;
+            output_bufwriter.write_u64::<LittleEndian>(block_buf.len() as u64)?;
+            output_bufwriter.write_all(&block_buf)?;
+        }
+        // The blended model follows the blocks, readers that don't know about it stop before it
+        if let Some(blend) = &self.blend {
+            blend.write_to_buf(output_bufwriter, quantize_weights)?;
         }
         Ok(())
     }
@@ -446,28 +858,64 @@ impl Regressor {
         input_bufreader: &mut dyn io::Read,
         use_quantization: bool,
     ) -> Result<(), Box<dyn Error>> {
-        // This is a bit weird format
-        // You would expect each block to have its own sig
-        // We'll break compatibility in next release or something similar
-        let len = input_bufreader.read_u64::<LittleEndian>()?;
-        let expected_length = self
-            .blocks_boxes
+        let block_indexes = self.serialized_block_indexes();
+        let block_ids: Vec<u32> = block_indexes
             .iter()
-            .map(|block| block.get_serialized_len())
-            .sum::<usize>() as u64;
-        if len != expected_length {
-            return Err(format!(
-                "Lenghts of weights array in regressor file differ: got {}, expected {}",
-                len, expected_length
-            ))?;
+            .map(|&i| self.blocks_boxes[i].get_serialized_block_id())
+            .collect();
+        // We will save these weights again, so we can't afford to drop blocks we don't know
+        read_block_frames(input_bufreader, &block_ids, false, |frame, reader| {
+            self.blocks_boxes[block_indexes[frame]].read_weights_from_buf(reader, use_quantization)
+        })
+    }
+
+    // Weights of version 6 files, which are not framed by block
+    pub fn overwrite_weights_from_unframed_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+        use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let block_indexes = self.serialized_block_indexes();
+        read_unframed_blocks(
+            input_bufreader,
+            self.serialized_len(),
+            block_indexes.len(),
+            |i, reader| {
+                self.blocks_boxes[block_indexes[i]].read_weights_from_buf(reader, use_quantization)
+            },
+        )
+    }
+
+    // Big weight arrays of a forward-only regressor go to files in dir that are mapped into memory,
+    // processes serving the same model then share them
+    pub fn map_weights(&mut self, dir: &str) -> Result<(), Box<dyn Error>> {
+        if !self.immutable {
+            return Err("Only weights of forward-only regressors can be memory mapped")?;
         }
-        for v in &mut self.blocks_boxes {
-            v.read_weights_from_buf(input_bufreader, use_quantization)?;
+        for block in self.blocks_boxes.iter_mut() {
+            block.map_weights(dir)?;
         }
-
         Ok(())
     }
 
+    // Walks the blocks in the order they execute, each adds the operators of its forward()
+    pub fn to_onnx(&self) -> Result<onnx::OnnxGraph, Box<dyn Error>> {
+        if !self.immutable {
+            return Err("Only forward-only regressors can be exported to ONNX")?;
+        }
+        if self.score_map.is_some() {
+            return Err("Models with a score map can't be exported to ONNX")?;
+        }
+        if self.blend.is_some() {
+            return Err("Output blends of models can't be exported to ONNX")?;
+        }
+        let mut graph = onnx::OnnxGraph::new();
+        for block in self.blocks_boxes.iter() {
+            block.to_onnx(&mut graph)?;
+        }
+        Ok(graph)
+    }
+
     pub fn immutable_regressor_without_weights(
         &mut self,
         mi: &model_instance::ModelInstance,
@@ -487,28 +935,43 @@ impl Regressor {
         use_quantization: bool,
     ) -> Result<(), Box<dyn Error>> {
         // TODO Ideally we would make a copy, not based on model_instance. but this is easier at the moment
-
-        let len = input_bufreader.read_u64::<LittleEndian>()?;
-        let expected_length = self
-            .blocks_boxes
+        let block_indexes = self.serialized_block_indexes();
+        let block_ids: Vec<u32> = block_indexes
             .iter()
-            .map(|bb| bb.get_serialized_len())
-            .sum::<usize>() as u64;
-        if len != expected_length {
-            return Err(format!(
-                "Lenghts of weights array in regressor file differ: got {}, expected {}",
-                len, expected_length
-            ))?;
-        }
-        for (i, v) in &mut self.blocks_boxes.iter().enumerate() {
-            v.read_weights_from_buf_into_forward_only(
-                input_bufreader,
+            .map(|&i| self.blocks_boxes[i].get_serialized_block_id())
+            .collect();
+        // Inference-only regressor can ignore blocks written by a newer binary
+        read_block_frames(input_bufreader, &block_ids, true, |frame, reader| {
+            let i = block_indexes[frame];
+            self.blocks_boxes[i].read_weights_from_buf_into_forward_only(
+                reader,
                 &mut rg.blocks_boxes[i],
                 use_quantization,
-            )?;
-        }
+            )
+        })
+    }
 
-        Ok(())
+    // Forward-only weights of version 6 files, which are not framed by block
+    pub fn into_immutable_regressor_from_unframed_buf(
+        &mut self,
+        rg: &mut Regressor,
+        input_bufreader: &mut dyn io::Read,
+        use_quantization: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let block_indexes = self.serialized_block_indexes();
+        read_unframed_blocks(
+            input_bufreader,
+            self.serialized_len(),
+            block_indexes.len(),
+            |i, reader| {
+                let i = block_indexes[i];
+                self.blocks_boxes[i].read_weights_from_buf_into_forward_only(
+                    reader,
+                    &mut rg.blocks_boxes[i],
+                    use_quantization,
+                )
+            },
+        )
     }
 
     // Create immutable regressor from current regressor
@@ -534,12 +997,86 @@ impl Regressor {
     }
 }
 
+// Reads frames written by Regressor::write_weights_to_buf, handing each known one to read_block
+// Frames beyond the ones we know about were written by a newer binary and can be optionally skipped
+fn read_block_frames(
+    input_bufreader: &mut dyn io::Read,
+    expected_block_ids: &[u32],
+    allow_unknown_trailing: bool,
+    mut read_block: impl FnMut(usize, &mut dyn io::Read) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let num_frames = input_bufreader.read_u32::<LittleEndian>()? as usize;
+    if num_frames < expected_block_ids.len() {
+        return Err(format!(
+            "Number of blocks in regressor file differ: got {}, expected {}",
+            num_frames,
+            expected_block_ids.len()
+        ))?;
+    }
+    if num_frames > expected_block_ids.len() && !allow_unknown_trailing {
+        return Err(format!("Regressor file has {} blocks, but this binary knows only about {}. Unknown blocks can only be skipped in inference-only mode",
+                           num_frames, expected_block_ids.len()))?;
+    }
+
+    for frame in 0..num_frames {
+        let block_id = input_bufreader.read_u32::<LittleEndian>()?;
+        let len = input_bufreader.read_u64::<LittleEndian>()?;
+        let mut frame_reader = (&mut *input_bufreader).take(len);
+        if frame < expected_block_ids.len() {
+            if block_id != expected_block_ids[frame] {
+                return Err(format!(
+                    "Block {} in regressor file has type id {}, expected {}",
+                    frame, block_id, expected_block_ids[frame]
+                ))?;
+            }
+            read_block(frame, &mut frame_reader)?;
+            if frame_reader.limit() != 0 {
+                return Err(format!(
+                    "Block {} in regressor file has {} bytes more than expected",
+                    frame,
+                    frame_reader.limit()
+                ))?;
+            }
+        } else {
+            log::warn!(
+                "Skipping unknown block {} with type id {} ({} bytes) in regressor file, it was probably written by a newer version",
+                frame, block_id, len
+            );
+            io::copy(&mut frame_reader, &mut io::sink())?;
+        }
+    }
+    Ok(())
+}
+
+// Version 6 files have the number of weights of all blocks, followed by the weights of each block
+fn read_unframed_blocks(
+    input_bufreader: &mut dyn io::Read,
+    expected_len: u64,
+    num_blocks: usize,
+    mut read_block: impl FnMut(usize, &mut dyn io::Read) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let len = input_bufreader.read_u64::<LittleEndian>()?;
+    if len != expected_len {
+        return Err(format!(
+            "Lengths of weights array in regressor file differ: got {}, expected {}",
+            len, expected_len
+        ))?;
+    }
+    for i in 0..num_blocks {
+        read_block(i, input_bufreader)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::assert_epsilon;
     use crate::feature_buffer::HashAndValue;
     use crate::optimizer;
+    use crate::vwmap::{NamespaceDescriptor, NamespaceFormat, NamespaceType};
+    use std::collections::HashMap;
 
     /* LR TESTS */
     fn lr_vec(v: Vec<feature_buffer::HashAndValue>) -> feature_buffer::FeatureBuffer {
@@ -549,6 +1086,9 @@ mod tests {
             example_number: 0,
             lr_buffer: v,
             ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
         }
     }
 
@@ -593,6 +1133,120 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_learn_pair() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::AdagradFlex;
+        mi.bpr = true;
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let feature = |hash: u32| HashAndValue {
+            hash,
+            value: 1.0,
+            combo_index: 0,
+        };
+        // Both examples of the pair have feature 3
+        let mut positive = lr_vec(vec![feature(1), feature(3)]);
+        positive.label = 1.0;
+        let negative = lr_vec(vec![feature(2), feature(3)]);
+        for _ in 0..3 {
+            re.learn_pair(&negative, &positive, &mut pb);
+            // The shared weight gets +g and -g, the others move apart by the same amount
+            re.predict(&lr_vec(vec![feature(3)]), &mut pb);
+            assert_eq!(pb.bpr_logit, 0.0);
+            re.predict(&lr_vec(vec![feature(1)]), &mut pb);
+            let logit_positive = pb.bpr_logit;
+            re.predict(&lr_vec(vec![feature(2)]), &mut pb);
+            assert!(logit_positive > 0.0);
+            assert_eq!(pb.bpr_logit, -logit_positive);
+        }
+    }
+
+    #[test]
+    fn test_namespace_ttl() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let ns_desc = |i| NamespaceDescriptor {
+            namespace_index: i,
+            namespace_type: NamespaceType::Primitive,
+            namespace_format: NamespaceFormat::Categorical,
+        };
+        for i in 0..2 {
+            mi.feature_combo_descs
+                .push(model_instance::FeatureComboDesc {
+                    namespace_descriptors: vec![ns_desc(i)],
+                    weight: 1.0,
+                });
+        }
+        // Only namespace 0 decays
+        mi.namespace_ttls.push(model_instance::NamespaceTTL {
+            namespace_descriptor: ns_desc(0),
+            ttl_examples: 10,
+        });
+        let logit = |p: f32| (p / (1.0 - p)).ln();
+
+        for combo_index in 0..2 {
+            let mut re = Regressor::new(&mi);
+            let mut pb = re.new_portbuffer();
+            let mut fb = lr_vec(vec![HashAndValue {
+                hash: 1,
+                value: 1.0,
+                combo_index,
+            }]);
+            fb.example_number = 1;
+            re.learn(&fb, &mut pb, true);
+            let w = logit(re.predict(&fb, &mut pb));
+            assert!(w.abs() > 0.01);
+
+            // Last touches are saved with the weights
+            let mut buf: Vec<u8> = Vec::new();
+            re.write_weights_to_buf(&mut buf, false).unwrap();
+            let mut re = Regressor::new(&mi);
+            re.overwrite_weights_from_buf(&mut buf.as_slice(), false)
+                .unwrap();
+            let mut pb = re.new_portbuffer();
+
+            // The feature was not seen for 19 examples
+            fb.example_number = 21;
+            let w_later = logit(re.learn(&fb, &mut pb, true));
+            if combo_index == 0 {
+                assert_epsilon!(w_later, w * 0.5_f32.powf(1.9));
+            } else {
+                assert_epsilon!(w_later, w);
+            }
+        }
+    }
+
+    #[test]
+    fn test_score_map() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::SGD;
+        mi.score_map = Some(
+            ScoreMap::new_from_json(r#"{"points": [[0.0, 1.0], [0.5, 0.2], [1.0, 0.0]]}"#).unwrap(),
+        );
+        let vec_in = &lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        // Learning and predictions see the sigmoid, only the output is mapped
+        assert_eq!(re.learn(vec_in, &mut pb, true), 0.5);
+        assert_eq!(re.map_score(0.5), 0.2);
+        let p = re.predict(vec_in, &mut pb);
+        assert_eq!(p, 0.48750263);
+        assert_epsilon!(re.map_score(p), 1.0 - 1.6 * 0.48750263);
+        assert_eq!(re.learn(vec_in, &mut pb, true), p);
+    }
+
     #[test]
     fn test_power_t_zero() {
         // When power_t is zero, then all optimizers behave exactly like SGD
@@ -882,4 +1536,89 @@ mod tests {
         assert_eq!(re.learn(&fb_instance, &mut pb, true), 0.49375027);
         assert_eq!(re.learn(&fb_instance, &mut pb, true), 0.4875807);
     }
+
+    #[test]
+    fn test_graph_paranoia() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        mi.nn_config.layers = vec![HashMap::new(), HashMap::new()];
+        mi.graph_paranoia = true;
+
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let fb = lr_vec(vec![HashAndValue {
+            hash: 1,
+            value: 1.0,
+            combo_index: 0,
+        }]);
+        re.learn(&fb, &mut pb, true);
+        re.learn(&fb, &mut pb, true);
+    }
+
+    #[test]
+    fn test_predict_batch() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        mi.nn_config.layers = vec![HashMap::new()];
+
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let fbs: Vec<feature_buffer::FeatureBuffer> = (0..5u32)
+            .map(|i| {
+                let mut fb = lr_vec(vec![HashAndValue {
+                    hash: i * 7,
+                    value: 1.0,
+                    combo_index: 0,
+                }]);
+                fb.label = (i % 2) as f32;
+                fb.ffm_buffer = vec![
+                    HashAndValueAndSeq {
+                        hash: i * 1000,
+                        value: 1.0,
+                        contra_field_index: 0,
+                    },
+                    HashAndValueAndSeq {
+                        hash: 100_000 + i * 100,
+                        value: 0.5,
+                        contra_field_index: mi.ffm_k,
+                    },
+                ];
+                fb
+            })
+            .collect();
+        for _ in 0..3 {
+            for fb in fbs.iter() {
+                re.learn(fb, &mut pb, true);
+            }
+        }
+
+        let predictions = re.predict_batch(&fbs, &mut pb);
+        assert_eq!(predictions.len(), fbs.len());
+        for (fb, prediction) in fbs.iter().zip(predictions.iter()) {
+            assert_eq!(re.predict(fb, &mut pb), *prediction);
+        }
+        assert_ne!(predictions[0], predictions[1]);
+        assert!(re.predict_batch(&[], &mut pb).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "but the graph of this regressor needs")]
+    fn test_graph_paranoia_wrong_port_buffer() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.nn_config.layers = vec![HashMap::new()];
+        mi.graph_paranoia = true;
+
+        let re = Regressor::new(&mi);
+        let mut pb = port_buffer::PortBuffer::new(1);
+        re.predict(&lr_vec(vec![]), &mut pb);
+    }
 }
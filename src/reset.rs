@@ -0,0 +1,366 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::error::Error;
+use std::io::BufRead;
+use std::io::Cursor;
+use std::io::Read;
+
+use crate::block_ffm::FfmIndexing;
+use crate::buffer_handler::create_buffered_input;
+use crate::feature_buffer::FeatureBufferTranslator;
+use crate::hash_usage::TouchedBitmap;
+use crate::model_instance::ModelInstance;
+use crate::parser::VowpalParser;
+use crate::persistence;
+use crate::regressor;
+use crate::regressor::Regressor;
+use crate::vwmap::{NamespaceDescriptor, VwNamespaceMap};
+
+// Weights of the selected namespaces, found by hashing their features in the given data
+// exactly as training does. The hash of a feature can't be recovered from the model alone.
+#[derive(Clone, Debug)]
+pub struct ResetIndexes {
+    pub lr: TouchedBitmap,
+    pub ffm: TouchedBitmap,
+}
+
+fn namespace_descriptors(
+    vw: &VwNamespaceMap,
+    namespaces: &[&str],
+) -> Result<Vec<NamespaceDescriptor>, Box<dyn Error>> {
+    let mut descriptors: Vec<NamespaceDescriptor> = Vec::new();
+    for namespace in namespaces.iter() {
+        match vw
+            .map_vwname_to_namespace_descriptor
+            .get(namespace.as_bytes())
+        {
+            Some(namespace_descriptor) => descriptors.push(*namespace_descriptor),
+            None => return Err(format!("Unknown namespace to reset: {}", namespace))?,
+        }
+    }
+    Ok(descriptors)
+}
+
+// Goes through the examples and marks the weights that features of the selected namespaces use:
+// LR weights of all feature combos with a selected namespace and whole FFM embeddings
+// (towards all fields) of their features. When all namespaces of a FFM field are selected, the
+// embeddings of other features towards that field are reset too, as they were learned on it.
+pub fn collect_reset_indexes(
+    mi: &ModelInstance,
+    vw: &VwNamespaceMap,
+    namespaces: &[&str],
+    input: &mut impl BufRead,
+) -> Result<ResetIndexes, Box<dyn Error>> {
+    let selected = namespace_descriptors(vw, namespaces)?;
+    let is_selected = |namespace_descriptor: &NamespaceDescriptor| {
+        selected
+            .iter()
+            .any(|s| s.namespace_index == namespace_descriptor.namespace_index)
+    };
+
+    // The same model restricted to the selected namespaces produces only their hashes
+    let mut mi_selected = mi.clone();
+    mi_selected.add_constant_feature = false;
+    mi_selected.dense_inputs = Vec::new();
+    mi_selected
+        .feature_combo_descs
+        .retain(|combo| combo.namespace_descriptors.iter().any(&is_selected));
+    for ffm_field in mi_selected.ffm_fields.iter_mut() {
+        ffm_field.retain(|n| is_selected(n));
+    }
+    let fully_selected_fields: Vec<u32> = mi
+        .ffm_fields
+        .iter()
+        .enumerate()
+        .filter(|(_, ffm_field)| !ffm_field.is_empty() && ffm_field.iter().all(&is_selected))
+        .map(|(field_index, _)| field_index as u32)
+        .collect();
+
+    let ffm_weights_len = ffm_weights_len(mi);
+    let mut indexes = ResetIndexes {
+        lr: TouchedBitmap::new(1 << mi.bit_precision),
+        ffm: TouchedBitmap::new(ffm_weights_len),
+    };
+    let mut pa = VowpalParser::new(vw);
+    let mut fbt_selected = FeatureBufferTranslator::new(&mi_selected);
+    let mut fbt = FeatureBufferTranslator::new(mi);
+    let ffm_k = mi.ffm_k as usize;
+    let ffm_indexing = FfmIndexing::new(mi);
+    loop {
+        let record = pa.next_vowpal(input)?;
+        if record.is_empty() {
+            break;
+        }
+        fbt_selected.translate(record, 0);
+        for feature in fbt_selected.feature_buffer.lr_buffer.iter() {
+            indexes.lr.touch(feature.hash as usize);
+        }
+        for feature in fbt_selected.feature_buffer.ffm_buffer.iter() {
+            for field_index in 0..mi.ffm_fields.len() {
+                let start = ffm_indexing.index(feature.hash, field_index);
+                for index in start..start + ffm_k {
+                    indexes.ffm.touch(index);
+                }
+            }
+        }
+        if !fully_selected_fields.is_empty() {
+            fbt.translate(record, 0);
+            for feature in fbt.feature_buffer.ffm_buffer.iter() {
+                for field_index in fully_selected_fields.iter() {
+                    let start = ffm_indexing.index(feature.hash, *field_index as usize);
+                    for index in start..start + ffm_k {
+                        indexes.ffm.touch(index);
+                    }
+                }
+            }
+        }
+    }
+    Ok(indexes)
+}
+
+fn ffm_weights_len(mi: &ModelInstance) -> usize {
+    if mi.ffm_k == 0 || mi.ffm_fields.is_empty() {
+        return 0;
+    }
+    (1 << mi.ffm_bit_precision) + mi.ffm_fields.len() * mi.ffm_k as usize
+}
+
+// Copies the selected entries of a weights array (weights with their optimizer data) from the
+// freshly initialized frame
+fn reset_entries(
+    weights: &mut [u8],
+    fresh: &[u8],
+    len: usize,
+    touched: &TouchedBitmap,
+) -> Result<(), Box<dyn Error>> {
+    if len == 0 || weights.len() % len != 0 || weights.len() != fresh.len() {
+        return Err(format!(
+            "Can't reset weights, array of {} bytes doesn't hold {} weights",
+            weights.len(),
+            len
+        ))?;
+    }
+    let entry_len = weights.len() / len;
+    for index in 0..len {
+        if touched.is_touched(index) {
+            let range = index * entry_len..(index + 1) * entry_len;
+            weights[range.clone()].copy_from_slice(&fresh[range]);
+        }
+    }
+    Ok(())
+}
+
+// Both buffers are outputs of Regressor::write_weights_to_buf of the same model. LR frames are
+// arrays of weights with optimizer data, FFM frames are an array of weights followed by an array
+// of optimizer data. Other blocks are kept as they are.
+fn reset_weight_frames(
+    weights: &[u8],
+    fresh: &[u8],
+    mi: &ModelInstance,
+    indexes: &ResetIndexes,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut weights_reader = Cursor::new(weights);
+    let mut fresh_reader = Cursor::new(fresh);
+    let mut output: Vec<u8> = Vec::with_capacity(weights.len());
+    let num_blocks = weights_reader.read_u32::<LittleEndian>()?;
+    if fresh_reader.read_u32::<LittleEndian>()? != num_blocks {
+        return Err("Model has a different number of blocks than its fresh copy")?;
+    }
+    output.write_u32::<LittleEndian>(num_blocks)?;
+    let mut frame: Vec<u8> = Vec::new();
+    let mut fresh_frame: Vec<u8> = Vec::new();
+    for _ in 0..num_blocks {
+        let block_id = weights_reader.read_u32::<LittleEndian>()?;
+        let len = weights_reader.read_u64::<LittleEndian>()?;
+        let fresh_block_id = fresh_reader.read_u32::<LittleEndian>()?;
+        let fresh_len = fresh_reader.read_u64::<LittleEndian>()?;
+        if block_id != fresh_block_id || len != fresh_len {
+            return Err(format!(
+                "Model block id {} of length {} doesn't match its fresh copy: id {} of length {}",
+                block_id, len, fresh_block_id, fresh_len
+            ))?;
+        }
+        frame.resize(len as usize, 0);
+        weights_reader.read_exact(&mut frame)?;
+        fresh_frame.resize(len as usize, 0);
+        fresh_reader.read_exact(&mut fresh_frame)?;
+
+        match block_id {
+            regressor::SERIALIZED_BLOCK_ID_LR => {
+                reset_entries(&mut frame, &fresh_frame, indexes.lr.len, &indexes.lr)?;
+            }
+            regressor::SERIALIZED_BLOCK_ID_FFM => {
+                let split = ffm_weights_len(mi) * 4;
+                if split > frame.len() {
+                    return Err("FFM block is shorter than its weights")?;
+                }
+                let (ffm_weights, ffm_optimizer) = frame.split_at_mut(split);
+                let (fresh_weights, fresh_optimizer) = fresh_frame.split_at(split);
+                reset_entries(ffm_weights, fresh_weights, indexes.ffm.len, &indexes.ffm)?;
+                reset_entries(
+                    ffm_optimizer,
+                    fresh_optimizer,
+                    indexes.ffm.len,
+                    &indexes.ffm,
+                )?;
+            }
+            _ => {}
+        }
+        output.write_u32::<LittleEndian>(block_id)?;
+        output.write_u64::<LittleEndian>(len)?;
+        output.extend_from_slice(&frame);
+    }
+    Ok(output)
+}
+
+// Re-initializes the weights of the selected namespaces, as they were before training, and keeps
+// everything else. For recovering from corrupted data in a few namespaces without retraining from
+// scratch: data_filename should hold the examples with the affected features.
+pub fn reset_namespaces(
+    model_filename: &str,
+    namespaces: &[&str],
+    data_filename: &str,
+    out_filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    let (mi, vw, re) = persistence::new_regressor_from_filename(model_filename, false, None)?;
+    if mi.dequantize_weights.unwrap_or(false) {
+        return Err("Quantized models can't be reset, reset the original model instead")?;
+    }
+    let mut input = create_buffered_input(data_filename);
+    let indexes = collect_reset_indexes(&mi, &vw, namespaces, &mut input)?;
+
+    let mut weights: Vec<u8> = Vec::new();
+    re.write_weights_to_buf(&mut weights, false)?;
+    let mut fresh_weights: Vec<u8> = Vec::new();
+    Regressor::new(&mi).write_weights_to_buf(&mut fresh_weights, false)?;
+    let reset_weights = reset_weight_frames(&weights, &fresh_weights, &mi, &indexes)?;
+
+    persistence::save_weights_buf_to_filename(out_filename, &mi, &vw, &reset_weights)?;
+    log::info!(
+        "Reset namespaces {} of {} into {}: {} lr weights, {} ffm weights",
+        namespaces.join(","),
+        model_filename,
+        out_filename,
+        indexes.lr.touched,
+        indexes.ffm.touched
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::model_instance::{FeatureComboDesc, Optimizer};
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn test_model(vw: &VwNamespaceMap) -> ModelInstance {
+        let a = vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()];
+        let b = vw.map_vwname_to_namespace_descriptor[&b"B".to_vec()];
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.5;
+        mi.bit_precision = 18;
+        mi.optimizer = Optimizer::AdagradFlex;
+        for namespace_descriptors in [vec![a], vec![b], vec![a, b]].iter() {
+            mi.feature_combo_descs.push(FeatureComboDesc {
+                namespace_descriptors: namespace_descriptors.clone(),
+                weight: 1.0,
+            });
+        }
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![a], vec![b]];
+        mi
+    }
+
+    #[test]
+    fn test_collect_reset_indexes() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mi = test_model(&vw);
+        let example = "1 |A a |B b\n";
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(pa.next_vowpal_from_bytes(example.as_bytes()).unwrap(), 0);
+        let lr_hash = |combo_index| {
+            fbt.feature_buffer
+                .lr_buffer
+                .iter()
+                .find(|f| f.combo_index == combo_index)
+                .unwrap()
+                .hash as usize
+        };
+        let ffm_hash = |field_index| {
+            fbt.feature_buffer
+                .ffm_buffer
+                .iter()
+                .find(|f| f.contra_field_index == field_index * mi.ffm_k)
+                .unwrap()
+                .hash as usize
+        };
+
+        let indexes = collect_reset_indexes(&mi, &vw, &["A"], &mut example.as_bytes()).unwrap();
+        // A and A x B are reset, B is kept
+        assert!(indexes.lr.is_touched(lr_hash(0)));
+        assert!(!indexes.lr.is_touched(lr_hash(1)));
+        assert!(indexes.lr.is_touched(lr_hash(2)));
+        assert_eq!(indexes.lr.touched, 2);
+        // Whole embedding of a, embedding of b towards field of A only
+        let (a, b) = (ffm_hash(0), ffm_hash(1));
+        assert!((a..a + 4).all(|i| indexes.ffm.is_touched(i)));
+        assert!(indexes.ffm.is_touched(b) && indexes.ffm.is_touched(b + 1));
+        assert!(!indexes.ffm.is_touched(b + 2) && !indexes.ffm.is_touched(b + 3));
+        assert_eq!(indexes.ffm.touched, 6);
+
+        assert!(collect_reset_indexes(&mi, &vw, &["C"], &mut example.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_reset_namespaces() {
+        let vw = VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = test_model(&vw);
+        mi.ffm_fields = Vec::new();
+        mi.ffm_k = 0;
+        mi.add_constant_feature = false;
+        let mut pa = VowpalParser::new(&vw);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+
+        let dir = tempdir().unwrap();
+        let model_filename = dir.path().join("model.fw");
+        let data_filename = dir.path().join("data.vw");
+        let out_filename = dir.path().join("reset.fw");
+        let model_filename = model_filename.to_str().unwrap();
+        let data_filename = data_filename.to_str().unwrap();
+        let out_filename = out_filename.to_str().unwrap();
+        std::fs::File::create(data_filename)
+            .unwrap()
+            .write_all(b"1 |A a\n")
+            .unwrap();
+
+        let mut re = Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        fbt.translate(pa.next_vowpal_from_bytes(b"1 |A a |B b\n").unwrap(), 0);
+        for _ in 0..5 {
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+        fbt.translate(pa.next_vowpal_from_bytes(b"1 |B b\n").unwrap(), 0);
+        let p_b = re.predict(&fbt.feature_buffer, &mut pb);
+        persistence::save_regressor_to_filename(model_filename, &mi, &vw, re, false).unwrap();
+
+        reset_namespaces(model_filename, &["A"], data_filename, out_filename).unwrap();
+        let (_, _, mut re_reset) =
+            persistence::new_regressor_from_filename(out_filename, false, None).unwrap();
+        // B is kept, A is as if it was never trained
+        assert_eq!(re_reset.predict(&fbt.feature_buffer, &mut pb), p_b);
+        fbt.translate(pa.next_vowpal_from_bytes(b"1 |A a\n").unwrap(), 0);
+        assert_eq!(re_reset.predict(&fbt.feature_buffer, &mut pb), 0.5);
+        // Optimizer state is reset too, so learning A starts over
+        let p_reset = re_reset.learn(&fbt.feature_buffer, &mut pb, true);
+        let mut re_fresh = Regressor::new(&mi);
+        assert_eq!(re_fresh.learn(&fbt.feature_buffer, &mut pb, true), p_reset);
+        assert_eq!(
+            re_reset.predict(&fbt.feature_buffer, &mut pb),
+            re_fresh.predict(&fbt.feature_buffer, &mut pb)
+        );
+    }
+}
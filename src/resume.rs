@@ -0,0 +1,85 @@
+use crate::murmur3;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::io::Read;
+
+// Bytes at the start of the input that go into the fingerprint
+const FINGERPRINT_HEAD_LEN: u64 = 1 << 20;
+
+// Identifies the input file, so that training is not resumed onto different data:
+// its length and a hash of its beginning (of the file as stored, before decompression)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DataFingerprint {
+    pub len: u64,
+    pub head_hash: u32,
+}
+
+impl DataFingerprint {
+    pub fn new(input_filename: &str) -> Result<DataFingerprint, Box<dyn Error>> {
+        let file = File::open(input_filename)?;
+        let len = file.metadata()?.len();
+        let mut head: Vec<u8> = Vec::new();
+        file.take(FINGERPRINT_HEAD_LEN).read_to_end(&mut head)?;
+        Ok(DataFingerprint {
+            len,
+            head_hash: murmur3::hash32(&head),
+        })
+    }
+}
+
+// How far training got when a snapshot was taken. It is saved inside the snapshot
+// (--snapshot_regressor), so the snapshot and the position always match, and --resume
+// continues from there.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResumePoint {
+    pub examples: u64,
+    pub input_offset: u64, // bytes of the decompressed text input, when not reading from the cache
+    pub data_fingerprint: DataFingerprint,
+}
+
+impl ResumePoint {
+    pub fn verify(&self, input_filename: &str) -> Result<(), Box<dyn Error>> {
+        let fingerprint = DataFingerprint::new(input_filename)?;
+        if fingerprint != self.data_fingerprint {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "Can not resume, {} is not the input the snapshot was trained on (fingerprint {:?}, expected {:?})",
+                    input_filename, fingerprint, self.data_fingerprint
+                ),
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_resume_point_verify() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"1 |A a\n0 |A b\n").unwrap();
+        let filename = file.path().to_str().unwrap().to_string();
+        let resume_point = ResumePoint {
+            examples: 1,
+            input_offset: 7,
+            data_fingerprint: DataFingerprint::new(&filename).unwrap(),
+        };
+        assert_eq!(resume_point.data_fingerprint.len, 14);
+        assert!(resume_point.verify(&filename).is_ok());
+
+        // Same length, different content
+        file.as_file_mut().set_len(0).unwrap();
+        let mut file = file.reopen().unwrap();
+        file.write_all(b"1 |A a\n0 |A c\n").unwrap();
+        assert!(resume_point.verify(&filename).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::murmur3;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+// Random numbers of the model come from named streams, all derived from --seed. Each randomized
+// feature draws from its own stream, so turning one of them on or off doesn't change the
+// numbers the others get, and runs stay comparable.
+// Differential privacy noise is deliberately not one of them, it has to be unpredictable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RngStream {
+    WeightsInit,
+    Dropout,
+    MonteCarlo,
+    NegativeSampling,
+}
+
+impl RngStream {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RngStream::WeightsInit => "weights-init",
+            RngStream::Dropout => "dropout",
+            RngStream::MonteCarlo => "monte-carlo",
+            RngStream::NegativeSampling => "negative-sampling",
+        }
+    }
+}
+
+pub const DEFAULT_SEED: u64 = 0;
+
+// Stream index of blocks that exist once per model
+pub const FFM_STREAM_INDEX: u64 = 0;
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Index separates instances of the same stream, e.g. the layers of a neural network
+pub fn stream_seed(seed: u64, stream: RngStream, index: u64) -> u64 {
+    let stream_hash = murmur3::hash32(stream.name()) as u64;
+    splitmix64(splitmix64(seed ^ (stream_hash << 32)) ^ index)
+}
+
+pub fn new_stream(seed: u64, stream: RngStream, index: u64) -> Xoshiro256PlusPlus {
+    Xoshiro256PlusPlus::seed_from_u64(stream_seed(seed, stream, index))
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use rand_xoshiro::rand_core::RngCore;
+
+    #[test]
+    fn test_streams() {
+        let mut a = new_stream(1, RngStream::Dropout, 3);
+        let mut b = new_stream(1, RngStream::Dropout, 3);
+        assert_eq!(a.next_u64(), b.next_u64());
+
+        let seeds = [
+            stream_seed(1, RngStream::Dropout, 3),
+            stream_seed(2, RngStream::Dropout, 3),
+            stream_seed(1, RngStream::WeightsInit, 3),
+            stream_seed(1, RngStream::Dropout, 4),
+            stream_seed(1, RngStream::MonteCarlo, 3),
+            stream_seed(1, RngStream::NegativeSampling, 3),
+        ];
+        for (i, seed) in seeds.iter().enumerate() {
+            for other in seeds[i + 1..].iter() {
+                assert_ne!(seed, other);
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+// Piecewise linear mapping of final scores, applied after the sigmoid.
+// It is defined by a json file of the form {"points": [[0.0, 0.0], [0.5, 0.3], [1.0, 1.0]]},
+// x coordinates have to be strictly increasing. Scores outside of the covered range are clamped
+// to the value of the first/last point.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScoreMap {
+    pub points: Vec<(f32, f32)>,
+}
+
+impl ScoreMap {
+    pub fn new_from_json(data: &str) -> Result<ScoreMap, Box<dyn Error>> {
+        let sm: ScoreMap = serde_json::from_str(data)?;
+        sm.validate()?;
+        Ok(sm)
+    }
+
+    pub fn new_from_filename(filename: &str) -> Result<ScoreMap, Box<dyn Error>> {
+        let data = fs::read_to_string(filename)?;
+        ScoreMap::new_from_json(&data)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.points.is_empty() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                "Score map needs at least one point",
+            )));
+        }
+        for (x, y) in self.points.iter() {
+            if !x.is_finite() || !y.is_finite() {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("Score map points have to be finite, got: ({}, {})", x, y),
+                )));
+            }
+        }
+        for w in self.points.windows(2) {
+            if w[0].0 >= w[1].0 {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Score map x coordinates have to be strictly increasing, got {} followed by {}",
+                        w[0].0, w[1].0
+                    ),
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn apply(&self, score: f32) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if score <= first.0 {
+            return first.1;
+        }
+        if score >= last.0 {
+            return last.1;
+        }
+        // Maps are small, so linear search beats anything clever
+        let i = self.points.iter().position(|p| p.0 > score).unwrap();
+        let (x0, y0) = self.points[i - 1];
+        let (x1, y1) = self.points[i];
+        y0 + (y1 - y0) * (score - x0) / (x1 - x0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let sm =
+            ScoreMap::new_from_json(r#"{"points": [[0.1, 0.0], [0.5, 0.2], [0.9, 1.0]]}"#).unwrap();
+        assert_eq!(sm.apply(0.0), 0.0);
+        assert_eq!(sm.apply(0.1), 0.0);
+        assert!((sm.apply(0.3) - 0.1).abs() < 0.00001);
+        assert_eq!(sm.apply(0.5), 0.2);
+        assert!((sm.apply(0.7) - 0.6).abs() < 0.00001);
+        assert_eq!(sm.apply(0.9), 1.0);
+        assert_eq!(sm.apply(1.0), 1.0);
+
+        let sm = ScoreMap::new_from_json(r#"{"points": [[0.5, 0.7]]}"#).unwrap();
+        assert_eq!(sm.apply(0.2), 0.7);
+        assert_eq!(sm.apply(0.9), 0.7);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(ScoreMap::new_from_json(r#"{"points": []}"#).is_err());
+        assert!(ScoreMap::new_from_json(r#"{"points": [[0.5, 0.0], [0.5, 1.0]]}"#).is_err());
+        assert!(ScoreMap::new_from_json(r#"{"points": [[0.6, 0.0], [0.5, 1.0]]}"#).is_err());
+        assert!(ScoreMap::new_from_json(r#"[[0.0, 0.0]]"#).is_err());
+    }
+}
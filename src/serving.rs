@@ -4,25 +4,76 @@ use std::io;
 use std::io::{BufReader, BufWriter};
 use std::net;
 use std::ops::DerefMut;
+use std::path::Path;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::debug_echo::DebugEcho;
+use crate::evaluation::Evaluations;
+use crate::explain;
+use crate::explain::{Explainer, DEFAULT_EXPLAIN_TOP_K};
 use crate::feature_buffer;
+use crate::feature_transform_executor;
+use crate::frontend::{Connection, Frontend, FrontendHandle};
+use crate::golden_set::GoldenSet;
+#[cfg(feature = "grpc")]
+use crate::grpc_serving::GrpcServer;
+use crate::http_serving;
+use crate::lofo::LofoAttributor;
 use crate::model_instance;
+use crate::model_registry::{ModelRegistry, ModelVersion};
+use crate::monitoring;
+use crate::monitoring::Monitor;
 use crate::multithread_helpers::BoxedRegressorTrait;
+use crate::parity::ParityChecker;
 use crate::parser;
 use crate::persistence;
 use crate::port_buffer;
+use crate::prediction_log::PredictionLog;
+use crate::rate_limit::{ConnectionLimiter, RateLimiter, RATE_LIMITED_RESPONSE};
 use crate::regressor;
+use crate::signals;
+use crate::tls;
+use crate::tls::TlsStream;
+use crate::value_ranges::ValueRangeChecker;
 use crate::vwmap;
 
+const SIGNALS_POLL_MS: u64 = 1000;
+
 pub struct Serving {
     listening_interface: String,
+    http_listening_interface: Option<String>,
     worker_threads: Vec<thread::JoinHandle<u32>>,
-    sender: mpsc::Sender<net::TcpStream>,
-    foreground: bool,
+    sender: Option<mpsc::SyncSender<Connection>>, // dropped on shutdown, so that workers exit
+    frontend: Option<Frontend>,
+    shutdown_timeout: Duration,
+    re_fixed: BoxedRegressorTrait,
+    mi: model_instance::ModelInstance,
+    model_registry: Arc<ModelRegistry>,
+    transform_state: Option<TransformState>,
+    #[cfg(feature = "grpc")]
+    grpc_server: Option<GrpcServer>,
+}
+
+// Where the state of stateful transforms is saved, on SIGUSR1 with --signals and on shutdown
+#[derive(Clone)]
+struct TransformState {
+    executors: feature_transform_executor::TransformExecutors, // shares the state with the workers
+    filename: String,
+}
+
+impl TransformState {
+    fn save(&self) {
+        match self.executors.save_state_to_filename(&self.filename) {
+            Ok(_) => log::info!("Transform state saved to {}", self.filename),
+            Err(e) => log::warn!("Saving transform state to {} failed: {}", self.filename, e),
+        }
+    }
 }
 
 pub struct WorkerThread {
@@ -32,6 +83,22 @@ pub struct WorkerThread {
     fbt: feature_buffer::FeatureBufferTranslator,
     pa: parser::VowpalParser,
     pb: port_buffer::PortBuffer,
+    golden: Option<Arc<GoldenSet>>,
+    value_ranges: Option<ValueRangeChecker>,
+    parity: Option<ParityChecker>,
+    lofo: Option<Arc<LofoAttributor>>,
+    explainer: Arc<Explainer>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    connection_limiter: Option<ConnectionLimiter>, // of the connection being handled
+    prediction_log: Option<Arc<PredictionLog>>,
+    monitor: Option<Arc<Monitor>>,
+    evaluations: Arc<Evaluations>,
+    model_registry: Arc<ModelRegistry>,
+    model_generation: u64,
+    model_version: Arc<ModelVersion>, // of the served model
+    max_batch: usize,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    frontend: Option<FrontendHandle>, // connections that wait for their next request go back to it
 }
 
 pub trait IsEmpty {
@@ -42,6 +109,26 @@ impl IsEmpty for io::BufReader<&net::TcpStream> {
         return self.buffer().is_empty();
     }
 }
+impl IsEmpty for io::BufReader<TlsStream> {
+    fn is_empty(&mut self) -> bool {
+        return self.buffer().is_empty();
+    }
+}
+
+// Why the examples of a request were not predicted
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    TooLarge(String),
+    RateLimited,
+    BadExample(String),
+}
+
+// Why hogwild_load kept the served model
+#[derive(Debug, PartialEq)]
+pub enum HogwildLoadError {
+    Rejected(String), // by --golden_set, the connection can be used further
+    Failed(String),
+}
 
 // These are used only for unit-tests
 #[derive(Debug, PartialEq)]
@@ -50,6 +137,9 @@ pub enum ConnectionEnd {
     StreamWriteError,
     StreamFlushError,
     ParseError,
+    Idle, // answered all that was sent, to be handed back to the frontend
+    #[cfg(feature = "chaos")]
+    InjectedDrop,
 }
 
 impl WorkerThread {
@@ -59,20 +149,164 @@ impl WorkerThread {
         fbt: feature_buffer::FeatureBufferTranslator,
         pa: parser::VowpalParser,
         pb: port_buffer::PortBuffer,
-        receiver: Arc<Mutex<mpsc::Receiver<net::TcpStream>>>,
-    ) -> Result<thread::JoinHandle<u32>, Box<dyn Error>> {
-        let mut wt = WorkerThread {
+        golden: Option<Arc<GoldenSet>>,
+        value_ranges: Option<ValueRangeChecker>,
+        parity: Option<ParityChecker>,
+        lofo: Option<Arc<LofoAttributor>>,
+        explainer: Arc<Explainer>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+        prediction_log: Option<Arc<PredictionLog>>,
+        monitor: Option<Arc<Monitor>>,
+        evaluations: Arc<Evaluations>,
+        model_registry: Arc<ModelRegistry>,
+        model_version: Arc<ModelVersion>,
+        max_batch: usize,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+        frontend: Option<FrontendHandle>,
+    ) -> WorkerThread {
+        WorkerThread {
             id,
             re_fixed,
             fbt,
             pa,
             pb,
-        };
-        let thread = thread::spawn(move || {
-            wt.start(receiver);
+            golden,
+            value_ranges,
+            parity,
+            lofo,
+            explainer,
+            rate_limiter,
+            connection_limiter: None,
+            prediction_log,
+            monitor,
+            evaluations,
+            model_registry,
+            model_generation: 0,
+            model_version,
+            max_batch,
+            tls_config,
+            frontend,
+        }
+    }
+
+    pub fn spawn(
+        mut self,
+        receiver: Arc<Mutex<mpsc::Receiver<Connection>>>,
+    ) -> thread::JoinHandle<u32> {
+        thread::spawn(move || {
+            self.start(receiver);
             1u32
-        });
-        Ok(thread)
+        })
+    }
+
+    // After "model_load" or "ffm_grow" every worker thread switches to the new model before
+    // reading its next request, together with the translator published with it
+    fn switch_to_new_model(&mut self) {
+        let model_generation = self.model_registry.generation();
+        if model_generation == self.model_generation {
+            return;
+        }
+        if let Some((re_fixed, model_version, fbt)) = self.model_registry.published() {
+            let ffm_bit_precision = fbt.model_instance.ffm_bit_precision;
+            if ffm_bit_precision != self.fbt.model_instance.ffm_bit_precision {
+                if let Some(parity) = self.parity.as_mut() {
+                    parity.set_ffm_bit_precision(ffm_bit_precision);
+                }
+            }
+            self.pb = re_fixed.new_portbuffer();
+            self.re_fixed = re_fixed;
+            self.model_version = model_version;
+            self.fbt = fbt;
+        }
+        self.model_generation = model_generation;
+    }
+
+    // Whether --rate_limit_connection and --rate_limit_client let this many more predictions be
+    // served on the connection
+    fn admit(&mut self, examples: usize) -> bool {
+        match self.connection_limiter.as_mut() {
+            Some(limiter) => limiter.admit(examples),
+            None => true,
+        }
+    }
+
+    // Reads the examples of a "batch <size>" command and predicts them in one go, the response
+    // has a line for each of them. Err is the reason why the batch can't be served
+    fn predict_batch(
+        &mut self,
+        reader: &mut impl io::BufRead,
+        size: usize,
+        i: &mut u64,
+    ) -> Result<String, String> {
+        if size > self.max_batch {
+            return Err(format!(
+                "batch of {} examples is over --max_batch {}",
+                size, self.max_batch
+            ));
+        }
+        if self.lofo.is_some() {
+            return Err("batch can't be used with --lofo".to_string());
+        }
+        // The whole batch is admitted or limited, its examples are read either way
+        let admitted = self.admit(size);
+        let mut fbs: Vec<feature_buffer::FeatureBuffer> = Vec::with_capacity(size);
+        let mut lines: Vec<Vec<u8>> = Vec::new(); // for the prediction log
+        for examples_read in 0..size {
+            let buffer2 = match self.pa.next_vowpal(reader) {
+                Ok([]) => {
+                    return Err(format!(
+                        "batch ended after {} of {} examples",
+                        examples_read, size
+                    ))
+                }
+                Ok(buffer2) => buffer2,
+                Err(e) if e.is::<io::Error>() => return Err(e.to_string()),
+                Err(_) => return Err("batch can contain only examples".to_string()),
+            };
+            if admitted {
+                if let Some(checker) = &self.value_ranges {
+                    checker.observe(buffer2);
+                }
+                self.fbt.translate(buffer2, *i);
+                if let Some(parity) = self.parity.as_mut() {
+                    if parity.sample() {
+                        parity.check(self.pa.last_line(), &self.fbt.feature_buffer);
+                    }
+                }
+                fbs.push(self.fbt.feature_buffer.clone());
+                if self.prediction_log.is_some() {
+                    lines.push(self.pa.last_line().to_vec());
+                }
+            }
+            *i += 1;
+        }
+
+        let predictions = self.re_fixed.predict_batch(&fbs, &mut self.pb);
+        for (j, (p, fb)) in predictions.iter().zip(fbs.iter()).enumerate() {
+            if let Some(prediction_log) = &self.prediction_log {
+                if let Err(e) = prediction_log.log(&lines[j], self.re_fixed.map_score(*p)) {
+                    log::warn!("Writing to prediction log failed: {}", e);
+                }
+            }
+            if let Some(monitor) = &self.monitor {
+                monitor.observe_prediction(
+                    *p,
+                    if fb.label == parser::NO_LABEL as f32 {
+                        None
+                    } else {
+                        Some(fb.label == 1.0)
+                    },
+                );
+            }
+        }
+        if !admitted {
+            return Ok(RATE_LIMITED_RESPONSE.repeat(size));
+        }
+        let mut p_res = String::new();
+        for p in predictions {
+            p_res.push_str(&format!("{:.6}\n", self.re_fixed.map_score(p)));
+        }
+        Ok(p_res)
     }
 
     pub fn handle_connection(
@@ -82,16 +316,74 @@ impl WorkerThread {
     ) -> ConnectionEnd {
         let mut i = 0u64; // This is per-thread example number
         loop {
+            self.switch_to_new_model();
             let reading_result = self.pa.next_vowpal(reader);
+            #[cfg(feature = "chaos")]
+            let reading_result = chaos::parse_result(reading_result);
 
             match reading_result {
                 Ok([]) => return ConnectionEnd::EndOfStream, // EOF
                 Ok(buffer2) => {
-                    self.fbt.translate(buffer2, i);
-                    let p = self
-                        .re_fixed
-                        .predict(&(self.fbt.feature_buffer), &mut self.pb);
-                    let p_res = format!("{:.6}\n", p);
+                    let admitted = match self.connection_limiter.as_mut() {
+                        Some(limiter) => limiter.admit(1),
+                        None => true,
+                    };
+                    let p_res = if admitted {
+                        if let Some(checker) = &self.value_ranges {
+                            checker.observe(buffer2);
+                        }
+                        self.fbt.translate(buffer2, i);
+                        if let Some(parity) = self.parity.as_mut() {
+                            if parity.sample() {
+                                parity.check(self.pa.last_line(), &self.fbt.feature_buffer);
+                            }
+                        }
+                        let (p, lofo) = match &self.lofo {
+                            Some(lofo) => {
+                                lofo.predict(&self.re_fixed, &self.fbt.feature_buffer, &mut self.pb)
+                            }
+                            None => (
+                                self.re_fixed
+                                    .predict(&(self.fbt.feature_buffer), &mut self.pb),
+                                Vec::new(),
+                            ),
+                        };
+                        if let Some(prediction_log) = &self.prediction_log {
+                            if let Err(e) =
+                                prediction_log.log(self.pa.last_line(), self.re_fixed.map_score(p))
+                            {
+                                log::warn!("Writing to prediction log failed: {}", e);
+                            }
+                        }
+                        if let Some(monitor) = &self.monitor {
+                            let label = self.fbt.feature_buffer.label;
+                            monitor.observe_prediction(
+                                p,
+                                if label == parser::NO_LABEL as f32 {
+                                    None
+                                } else {
+                                    Some(label == 1.0)
+                                },
+                            );
+                        }
+                        #[cfg(feature = "chaos")]
+                        {
+                            if chaos::inject(chaos::Fault::DropConnection) {
+                                return ConnectionEnd::InjectedDrop;
+                            }
+                            chaos::slow_response();
+                        }
+                        match &self.lofo {
+                            Some(attributor) => {
+                                let lofo: Vec<f32> =
+                                    lofo.iter().map(|p| self.re_fixed.map_score(*p)).collect();
+                                attributor.format_predictions(self.re_fixed.map_score(p), &lofo)
+                            }
+                            None => format!("{:.6}\n", self.re_fixed.map_score(p)),
+                        }
+                    } else {
+                        RATE_LIMITED_RESPONSE.to_string()
+                    };
                     match writer.write_all(p_res.as_bytes()) {
                         Ok(_) => {}
                         Err(_e) => {
@@ -109,14 +401,212 @@ impl WorkerThread {
                                 return ConnectionEnd::StreamFlushError;
                             }
                         }
+                    } else if e.is::<parser::BatchCommand>() {
+                        let size = e.downcast_ref::<parser::BatchCommand>().unwrap().size;
+                        match self.predict_batch(reader, size, &mut i) {
+                            Ok(p_res) => {
+                                #[cfg(feature = "chaos")]
+                                {
+                                    if chaos::inject(chaos::Fault::DropConnection) {
+                                        return ConnectionEnd::InjectedDrop;
+                                    }
+                                    chaos::slow_response();
+                                }
+                                match writer.write_all(p_res.as_bytes()) {
+                                    Ok(_) => {}
+                                    Err(_e) => {
+                                        return ConnectionEnd::StreamWriteError;
+                                    }
+                                };
+                            }
+                            Err(e) => {
+                                // What follows can't be told apart from the examples of the batch
+                                if let Some(monitor) = &self.monitor {
+                                    monitor.observe_parse_error();
+                                }
+                                let p_res = format!("ERR: {}\n", e);
+                                match writer.write_all(p_res.as_bytes()) {
+                                    Ok(_) => match writer.flush() {
+                                        Ok(_) => {}
+                                        Err(_e) => {
+                                            return ConnectionEnd::StreamFlushError;
+                                        }
+                                    },
+                                    Err(_e) => {
+                                        return ConnectionEnd::StreamWriteError;
+                                    }
+                                };
+                                return ConnectionEnd::ParseError;
+                            }
+                        }
+                    } else if e.is::<parser::StatsCommand>() {
+                        let mut p_res = match &self.golden {
+                            Some(golden) => golden.format_history(),
+                            None => String::new(),
+                        };
+                        if let Some(limiter) = &self.rate_limiter {
+                            p_res.push_str(&limiter.format_counters());
+                        }
+                        p_res.push_str(&self.evaluations.format_history());
+                        p_res.push_str(&self.model_registry.format_status());
+                        if let Some(parity) = &self.parity {
+                            p_res.push_str(&parity.format_counters());
+                        }
+                        if let Some(monitor) = &self.monitor {
+                            p_res.push_str(&monitor.format_status());
+                        }
+                        if p_res.is_empty() {
+                            p_res = "ERR: no --golden_set given\n".to_string();
+                        }
+                        match writer.write_all(p_res.as_bytes()) {
+                            Ok(_) => {}
+                            Err(_e) => {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                        };
+                    } else if e.is::<parser::DebugCommand>() {
+                        // Commands that predict count against the rate limits like examples do
+                        let p_res = if self.admit(1) {
+                            let debug_command = e.downcast_ref::<parser::DebugCommand>().unwrap();
+                            self.fbt.translate(&debug_command.record, i);
+                            let p = self
+                                .re_fixed
+                                .predict(&(self.fbt.feature_buffer), &mut self.pb);
+                            DebugEcho::new(
+                                &debug_command.record,
+                                &self.pa.vw_map,
+                                &self.fbt.model_instance,
+                                &self.fbt.feature_buffer,
+                                self.re_fixed.map_score(p),
+                            )
+                            .to_json_line()
+                        } else {
+                            RATE_LIMITED_RESPONSE.to_string()
+                        };
+                        match writer.write_all(p_res.as_bytes()) {
+                            Ok(_) => {}
+                            Err(_e) => {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                        };
+                    } else if e.is::<parser::ExplainCommand>() {
+                        let p_res = if self.admit(1) {
+                            let explain_command =
+                                e.downcast_ref::<parser::ExplainCommand>().unwrap();
+                            self.fbt.translate(&explain_command.record, i);
+                            let (p, contributions) = self.explainer.explain(
+                                &self.re_fixed,
+                                &self.fbt.feature_buffer,
+                                &mut self.pb,
+                            );
+                            self.explainer
+                                .format_explanation(self.re_fixed.map_score(p), &contributions)
+                        } else {
+                            RATE_LIMITED_RESPONSE.to_string()
+                        };
+                        match writer.write_all(p_res.as_bytes()) {
+                            Ok(_) => {}
+                            Err(_e) => {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                        };
+                    } else if e.is::<parser::OutputsCommand>() {
+                        let p_res = if self.admit(1) {
+                            let outputs_command =
+                                e.downcast_ref::<parser::OutputsCommand>().unwrap();
+                            self.fbt.translate(&outputs_command.record, i);
+                            let (p, decompositions) = self
+                                .re_fixed
+                                .predict_decomposed(&self.fbt.feature_buffer, &mut self.pb);
+                            explain::format_outputs(self.re_fixed.map_score(p), &decompositions)
+                        } else {
+                            RATE_LIMITED_RESPONSE.to_string()
+                        };
+                        match writer.write_all(p_res.as_bytes()) {
+                            Ok(_) => {}
+                            Err(_e) => {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                        };
+                    } else if e.is::<parser::EvaluateCommand>() {
+                        let evaluate_command = e.downcast_ref::<parser::EvaluateCommand>().unwrap();
+                        // Runs in the background with the served weights, results come with "stats"
+                        let p_res = match self.evaluations.start(
+                            &evaluate_command.filename,
+                            self.re_fixed.clone(),
+                            self.fbt.clone(),
+                            self.pa.clone(),
+                        ) {
+                            Ok((id, _)) => format!("evaluate started id:{}\n", id),
+                            Err(e) => format!("ERR: evaluate failed: {}\n", e),
+                        };
+                        match writer.write_all(p_res.as_bytes()) {
+                            Ok(_) => {}
+                            Err(_e) => {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                        };
+                    } else if e.is::<parser::FfmGrowCommand>() {
+                        // Golden set examples are translated for the FFM table they were loaded with
+                        let p_res = if self.golden.is_some() {
+                            "ERR: ffm_grow can't be used with --golden_set\n".to_string()
+                        } else {
+                            match self.model_registry.start_ffm_grow(
+                                self.re_fixed.clone(),
+                                &self.fbt,
+                                &self.model_version,
+                            ) {
+                                Ok(_) => "ffm_grow started\n".to_string(),
+                                Err(e) => format!("ERR: ffm_grow failed: {}\n", e),
+                            }
+                        };
+                        match writer.write_all(p_res.as_bytes()) {
+                            Ok(_) => {}
+                            Err(_e) => {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                        };
+                    } else if e.is::<parser::ModelVersionCommand>() {
+                        let p_res = self.model_version.format();
+                        match writer.write_all(p_res.as_bytes()) {
+                            Ok(_) => {}
+                            Err(_e) => {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                        };
+                    } else if e.is::<parser::ModelLoadCommand>() {
+                        let model_load_command =
+                            e.downcast_ref::<parser::ModelLoadCommand>().unwrap();
+                        // Loads in the background, "stats" tells when the daemon switched to it
+                        let p_res = match self.model_registry.start(
+                            &model_load_command.filename,
+                            self.re_fixed.clone(),
+                            &self.fbt,
+                            &self.pa,
+                        ) {
+                            Ok(_) => "model_load started\n".to_string(),
+                            Err(e) => format!("ERR: model_load failed: {}\n", e),
+                        };
+                        match writer.write_all(p_res.as_bytes()) {
+                            Ok(_) => {}
+                            Err(_e) => {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                        };
                     } else if e.is::<parser::HogwildLoadCommand>() {
                         // FlushCommand just causes us to flush, not to break
                         let hogwild_command =
                             e.downcast_ref::<parser::HogwildLoadCommand>().unwrap();
-                        match persistence::hogwild_load(
-                            self.re_fixed.deref_mut(),
-                            &hogwild_command.filename,
-                        ) {
+                        match self.hogwild_load(&hogwild_command.filename) {
+                            Err(HogwildLoadError::Rejected(e)) => {
+                                let p_res = format!("ERR: hogwild_load rejected: {}\n", e);
+                                match writer.write_all(p_res.as_bytes()) {
+                                    Ok(_) => {}
+                                    Err(_e) => {
+                                        return ConnectionEnd::StreamWriteError;
+                                    }
+                                };
+                            }
                             Ok(_) => {
                                 let p_res = "hogwild_load success\n".to_string();
                                 match writer.write_all(p_res.as_bytes()) {
@@ -126,7 +616,7 @@ impl WorkerThread {
                                     }
                                 };
                             }
-                            Err(_e) => {
+                            Err(HogwildLoadError::Failed(_e)) => {
                                 // TODO This kind of error should fold the whole daemon...
                                 let p_res = "ERR: hogwild_load fail\n".to_string();
                                 match writer.write_all(p_res.as_bytes()) {
@@ -139,6 +629,9 @@ impl WorkerThread {
                             }
                         }
                     } else {
+                        if let Some(monitor) = &self.monitor {
+                            monitor.observe_parse_error();
+                        }
                         let p_res = format!("ERR: {}\n", e);
                         match writer.write_all(p_res.as_bytes()) {
                             Ok(_) => match writer.flush() {
@@ -164,19 +657,297 @@ impl WorkerThread {
                         return ConnectionEnd::StreamFlushError;
                     }
                 };
+                if let Some(prediction_log) = &self.prediction_log {
+                    if let Err(e) = prediction_log.flush() {
+                        log::warn!("Flushing prediction log failed: {}", e);
+                    }
+                }
+                // Requests that were sent are answered before the connection is dropped
+                if signals::shutdown_requested() {
+                    return ConnectionEnd::EndOfStream;
+                }
+                if self.frontend.is_some() {
+                    return ConnectionEnd::Idle;
+                }
             }
             i += 1;
         }
     }
 
-    pub fn start(&mut self, receiver: Arc<Mutex<mpsc::Receiver<net::TcpStream>>>) {
-        // Simple endless serving loop: receive new connection and serve it
-        // when handle_connection exits, the connection is dropped
+    // Predictions of the examples of a request of --http_port or --grpc_port, given as VW lines
+    pub fn predict_lines(&mut self, lines: &[String]) -> Result<Vec<f32>, RequestError> {
+        if lines.len() > self.max_batch {
+            return Err(RequestError::TooLarge(format!(
+                "{} examples are over --max_batch {}",
+                lines.len(),
+                self.max_batch
+            )));
+        }
+        if let Some(limiter) = self.connection_limiter.as_mut() {
+            if !limiter.admit(lines.len()) {
+                return Err(RequestError::RateLimited);
+            }
+        }
+        let mut fbs: Vec<feature_buffer::FeatureBuffer> = Vec::with_capacity(lines.len());
+        for line in lines {
+            let buffer2 = match self.pa.next_vowpal_from_bytes(line.as_bytes()) {
+                Ok(buffer2) => buffer2,
+                Err(e) => {
+                    if let Some(monitor) = &self.monitor {
+                        monitor.observe_parse_error();
+                    }
+                    return Err(RequestError::BadExample(e.to_string()));
+                }
+            };
+            if let Some(checker) = &self.value_ranges {
+                checker.observe(buffer2);
+            }
+            self.fbt.translate(buffer2, fbs.len() as u64);
+            fbs.push(self.fbt.feature_buffer.clone());
+        }
+        let predictions = self.re_fixed.predict_batch(&fbs, &mut self.pb);
+        if let Some(monitor) = &self.monitor {
+            for (p, fb) in predictions.iter().zip(fbs.iter()) {
+                monitor.observe_prediction(
+                    *p,
+                    if fb.label == parser::NO_LABEL as f32 {
+                        None
+                    } else {
+                        Some(fb.label == 1.0)
+                    },
+                );
+            }
+        }
+        let predictions: Vec<f32> = predictions
+            .into_iter()
+            .map(|p| self.re_fixed.map_score(p))
+            .collect();
+        if let Some(prediction_log) = &self.prediction_log {
+            for (line, p) in lines.iter().zip(predictions.iter()) {
+                if let Err(e) = prediction_log.log(line.as_bytes(), *p) {
+                    log::warn!("Writing to prediction log failed: {}", e);
+                }
+            }
+        }
+        Ok(predictions)
+    }
+
+    // Predictions of a gRPC request, rate limited as a connection of its own from the client
+    pub fn predict_request(
+        &mut self,
+        client: Option<net::IpAddr>,
+        lines: &[String],
+    ) -> Result<Vec<f32>, RequestError> {
+        self.switch_to_new_model();
+        self.connection_limiter = self
+            .rate_limiter
+            .as_ref()
+            .map(|limiter| ConnectionLimiter::new(limiter, client));
+        let predictions = self.predict_lines(lines);
+        self.connection_limiter = None;
+        predictions
+    }
+
+    // Replaces the weights of the served model in place, unless --golden_set rejects them
+    pub fn hogwild_load(&mut self, filename: &str) -> Result<(), HogwildLoadError> {
+        if let Some(golden) = &self.golden {
+            if let Err(e) = golden.check_candidate(&self.re_fixed, filename) {
+                log::warn!("hogwild_load rejected: {}", e);
+                return Err(HogwildLoadError::Rejected(e.to_string()));
+            }
+        }
+        #[cfg(feature = "chaos")]
+        let load_result = chaos::before_load();
+        #[cfg(not(feature = "chaos"))]
+        let load_result: Result<(), Box<dyn Error>> = Ok(());
+        let load_result = load_result
+            .and_then(|_| persistence::hogwild_load(self.re_fixed.deref_mut(), filename));
+        let model_version = match load_result {
+            Ok(model_version) => model_version,
+            Err(e) => return Err(HogwildLoadError::Failed(e.to_string())),
+        };
+        self.model_registry.model_loaded();
+        if let Some(prediction_log) = &self.prediction_log {
+            prediction_log.set_model_version(model_version);
+        }
+        if let Some(monitor) = &self.monitor {
+            match monitoring::model_time(filename) {
+                Ok(model_time) => monitor.set_model_time(model_time),
+                Err(e) => log::warn!("Can not get modification time of {}: {}", filename, e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush_prediction_log(&self) -> io::Result<()> {
+        match &self.prediction_log {
+            Some(prediction_log) => prediction_log.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // Predictions of the examples of a POST /predict body, Err is the status and the error
+    fn predict_http(
+        &mut self,
+        request: &http_serving::HttpRequest,
+    ) -> Result<String, (&'static str, String)> {
+        if request.path != "/predict" {
+            return Err((
+                "404 Not Found",
+                format!("No such endpoint {}", request.path),
+            ));
+        }
+        if request.method != "POST" {
+            return Err((
+                "405 Method Not Allowed",
+                "Use POST for /predict".to_string(),
+            ));
+        }
+        let lines = http_serving::json_to_vw_lines(&request.body)
+            .map_err(|e| ("400 Bad Request", e.to_string()))?;
+        let predictions = self.predict_lines(&lines).map_err(|e| match e {
+            RequestError::TooLarge(e) => ("413 Payload Too Large", e),
+            RequestError::RateLimited => ("429 Too Many Requests", "rate limited".to_string()),
+            RequestError::BadExample(e) => ("400 Bad Request", e),
+        })?;
+        let stats = if http_serving::wants_stats(&request.query) {
+            Some(http_serving::PredictionStats::new(&predictions))
+        } else {
+            None
+        };
+        Ok(serde_json::to_string(&http_serving::PredictResponse { predictions, stats }).unwrap())
+    }
+
+    pub fn handle_http_connection(
+        &mut self,
+        reader: &mut (impl io::BufRead + IsEmpty),
+        writer: &mut impl io::Write,
+    ) -> ConnectionEnd {
+        loop {
+            self.switch_to_new_model();
+            let request = match http_serving::read_request(reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return ConnectionEnd::EndOfStream,
+                Err(e) => {
+                    let body = http_serving::error_body(&e.to_string());
+                    return match http_serving::write_response(
+                        writer,
+                        "400 Bad Request",
+                        &body,
+                        false,
+                    ) {
+                        Ok(_) => ConnectionEnd::ParseError,
+                        Err(_e) => ConnectionEnd::StreamWriteError,
+                    };
+                }
+            };
+            let (status, body) = match self.predict_http(&request) {
+                Ok(body) => ("200 OK", body),
+                Err((status, e)) => (status, http_serving::error_body(&e)),
+            };
+            let keep_alive = request.keep_alive && !signals::shutdown_requested();
+            if http_serving::write_response(writer, status, &body, keep_alive).is_err() {
+                return ConnectionEnd::StreamWriteError;
+            }
+            if let Some(prediction_log) = &self.prediction_log {
+                if let Err(e) = prediction_log.flush() {
+                    log::warn!("Flushing prediction log failed: {}", e);
+                }
+            }
+            if !keep_alive {
+                return ConnectionEnd::EndOfStream;
+            }
+            if self.frontend.is_some() && reader.is_empty() {
+                return ConnectionEnd::Idle;
+            }
+        }
+    }
+
+    fn handle_either_connection(
+        &mut self,
+        http: bool,
+        reader: &mut (impl io::BufRead + IsEmpty),
+        writer: &mut impl io::Write,
+    ) -> ConnectionEnd {
+        if http {
+            self.handle_http_connection(reader, writer)
+        } else {
+            self.handle_connection(reader, writer)
+        }
+    }
+
+    // Serves the connection until it ends, or until it has nothing more to read when there is a
+    // frontend to hand it back to, then it is returned
+    fn serve_connection(&mut self, mut connection: Connection) -> Option<Connection> {
+        if !connection.started {
+            connection.started = true;
+            connection.limiter = self.rate_limiter.as_ref().map(|limiter| {
+                ConnectionLimiter::new(
+                    limiter,
+                    connection.tcp_stream.peer_addr().ok().map(|a| a.ip()),
+                )
+            });
+            if let Some(tls_config) = &self.tls_config {
+                let tls_stream = connection
+                    .tcp_stream
+                    .try_clone()
+                    .map_err(|e| e.into())
+                    .and_then(|tcp_stream| TlsStream::new(tls_config, tcp_stream));
+                match tls_stream {
+                    Ok(tls_stream) => connection.tls_stream = Some(tls_stream),
+                    Err(e) => {
+                        log::warn!("Setting up TLS connection failed: {}", e);
+                        return None;
+                    }
+                }
+            }
+        }
+        self.connection_limiter = connection.limiter.take();
+        let connection_end = match connection.tls_stream.clone() {
+            Some(tls_stream) => loop {
+                let mut reader = BufReader::new(tls_stream.clone());
+                let mut writer = BufWriter::new(tls_stream.clone());
+                let connection_end =
+                    self.handle_either_connection(connection.http, &mut reader, &mut writer);
+                drop(writer);
+                if connection_end != ConnectionEnd::Idle || !tls_stream.has_buffered_data() {
+                    break connection_end;
+                }
+            },
+            None => {
+                let mut reader = BufReader::new(&connection.tcp_stream);
+                let mut writer = BufWriter::new(&connection.tcp_stream);
+                self.handle_either_connection(connection.http, &mut reader, &mut writer)
+            }
+        };
+        connection.limiter = self.connection_limiter.take();
+        if connection_end == ConnectionEnd::Idle {
+            return Some(connection);
+        }
+        if let Some(tls_stream) = &connection.tls_stream {
+            tls_stream.close();
+        }
+        None
+    }
+
+    pub fn start(&mut self, receiver: Arc<Mutex<mpsc::Receiver<Connection>>>) {
+        // Simple endless serving loop: receive a connection that has a request and serve it,
+        // when it is idle it goes back to the frontend, when it ends it is dropped
         loop {
-            let tcp_stream = receiver.lock().unwrap().recv().unwrap();
-            let mut reader = BufReader::new(&tcp_stream);
-            let mut writer = BufWriter::new(&tcp_stream);
-            self.handle_connection(&mut reader, &mut writer);
+            let connection = match receiver.lock().unwrap().recv() {
+                Ok(connection) => connection,
+                Err(_) => return, // the daemon is shutting down and all connections were taken
+            };
+            if let Some(connection) = self.serve_connection(connection) {
+                if let Some(frontend) = &self.frontend {
+                    frontend.hand_back(connection);
+                }
+            }
+            if let Some(prediction_log) = &self.prediction_log {
+                if let Err(e) = prediction_log.flush() {
+                    log::warn!("Flushing prediction log failed: {}", e);
+                }
+            }
         }
     }
 }
@@ -192,16 +963,42 @@ impl Serving {
             Some(port) => port.parse().expect("Port should be integer"),
             None => 26542,
         };
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let (frontend, frontend_handle) = Frontend::new()?;
+
+        #[cfg(feature = "chaos")]
+        log::warn!(
+            "Fault injection is compiled in, configured with: {:?}",
+            crate::chaos::ChaosConfig::new_from_env()?
+        );
 
         let listening_interface = format!("127.0.0.1:{}", port);
         log::info!("Starting to listen on {}", listening_interface);
-        let mut s = Serving {
-            listening_interface,
-            worker_threads: Vec::new(),
-            sender,
-            foreground: cl.is_present("foreground"),
+        let http_listening_interface = match cl.value_of("http_port") {
+            Some(http_port) => {
+                let http_port: u16 = http_port.parse().expect("http_port should be integer");
+                log::info!("Starting to listen for HTTP on 127.0.0.1:{}", http_port);
+                Some(format!("127.0.0.1:{}", http_port))
+            }
+            None => None,
+        };
+        let grpc_listening_interface = match cl.value_of("grpc_port") {
+            Some(grpc_port) => {
+                let grpc_port: u16 = grpc_port.parse().expect("grpc_port should be integer");
+                log::info!("Starting to listen for gRPC on 127.0.0.1:{}", grpc_port);
+                Some(format!("127.0.0.1:{}", grpc_port))
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "grpc"))]
+        if grpc_listening_interface.is_some() {
+            return Err("--grpc_port needs fw built with the grpc feature")?;
+        }
+        let foreground = cl.is_present("foreground");
+        let shutdown_timeout = match cl.value_of("shutdown_timeout") {
+            Some(seconds) => {
+                Duration::from_secs(seconds.parse().expect("shutdown_timeout should be integer"))
+            }
+            None => Duration::from_secs(30),
         };
 
         let num_children = match cl.value_of("num_children") {
@@ -211,8 +1008,17 @@ impl Serving {
             None => 10,
         };
         log::info!("Number of threads {}", num_children);
+        // Connections with a request wait here when all worker threads are busy
+        let (sender, receiver) = mpsc::sync_channel(num_children as usize);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let max_batch = match cl.value_of("max_batch") {
+            Some(max_batch) => max_batch.parse().expect("max_batch should be integer"),
+            None => 1000,
+        };
+        // Bad certificates fail the start, before daemonizing
+        let tls_config = tls::new_server_config_from_cmdline(cl)?;
 
-        if !s.foreground {
+        if !foreground {
             //  let stdout = File::create("/tmp/daemon.out").unwrap();
             //  let stderr = File::create("/tmp/daemon.err").unwrap();
             let daemonize = Daemonize::new();
@@ -227,30 +1033,223 @@ impl Serving {
         let re_fixed2 = BoxedRegressorTrait::new(re_fixed);
         let pb = re_fixed2.new_portbuffer();
         let fbt = feature_buffer::FeatureBufferTranslator::new(mi);
+        if let Some(filename) = cl.value_of("transform_state") {
+            fbt.transform_executors.load_state_from_filename(filename)?;
+        } else if let Some(filename) = cl.value_of("initial_regressor") {
+            let filename = feature_transform_executor::model_transform_state_filename(filename);
+            if Path::new(&filename).exists() {
+                fbt.transform_executors
+                    .load_state_from_filename(&filename)?;
+            }
+        }
+        // Saved like at the end of training, to --transform_state or next to --final_regressor
+        let transform_state_filename = match cl.value_of("transform_state") {
+            Some(filename) => Some(filename.to_string()),
+            None => cl
+                .value_of("final_regressor")
+                .map(feature_transform_executor::model_transform_state_filename),
+        };
+        let transform_state = match transform_state_filename {
+            Some(filename) if fbt.transform_executors.has_state() => Some(TransformState {
+                executors: fbt.transform_executors.clone(),
+                filename,
+            }),
+            _ => None,
+        };
+        if let (Some(transform_state), true) = (&transform_state, cl.is_present("signals")) {
+            signals::install()?;
+            let transform_state = transform_state.clone();
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_millis(SIGNALS_POLL_MS));
+                if signals::take_snapshot_request() {
+                    transform_state.save();
+                }
+            });
+        }
         let pa = parser::VowpalParser::new(vw);
-        for i in 0..num_children {
-            let newt = WorkerThread::new(
+
+        let golden = match cl.value_of("golden_set") {
+            Some(filename) => {
+                let block_margin = match cl.value_of("golden_block_margin") {
+                    Some(margin) => Some(margin.parse::<f64>()?),
+                    None => None,
+                };
+                let golden = Arc::new(GoldenSet::new_from_filename(
+                    filename,
+                    mi,
+                    vw,
+                    block_margin,
+                )?);
+                let eval_minutes: u64 = match cl.value_of("golden_eval_minutes") {
+                    Some(minutes) => minutes.parse()?,
+                    None => 10,
+                };
+                golden.evaluate_and_record(&re_fixed2);
+                // Evaluates whatever model is served at the time, hogwild_load swaps weights in place
+                let golden2 = Arc::clone(&golden);
+                let re_golden = re_fixed2.clone();
+                thread::spawn(move || loop {
+                    thread::sleep(Duration::from_secs(eval_minutes * 60));
+                    golden2.evaluate_and_record(&re_golden);
+                });
+                Some(golden)
+            }
+            None => None,
+        };
+
+        let value_ranges = if cl.is_present("check_value_ranges") {
+            Some(ValueRangeChecker::new(&mi.value_ranges)?)
+        } else {
+            None
+        };
+        let parity = ParityChecker::new_from_cmdline(cl, mi, vw)?;
+        let lofo = if cl.is_present("lofo") {
+            Some(Arc::new(LofoAttributor::new(mi, vw)?))
+        } else {
+            None
+        };
+
+        let explain_top_k = match cl.value_of("explain_top_k") {
+            Some(top_k) => top_k.parse()?,
+            None => DEFAULT_EXPLAIN_TOP_K,
+        };
+        let explainer = Arc::new(Explainer::new(mi, vw, explain_top_k)?);
+        let rate_limiter = RateLimiter::new_from_cmdline(cl)?;
+        let prediction_log = PredictionLog::new_from_cmdline(cl)?.map(Arc::new);
+        let monitor = Monitor::new_from_cmdline(cl)?.map(Arc::new);
+        if let Some(monitor) = &monitor {
+            monitor.start()?;
+        }
+        let evaluations = Arc::new(Evaluations::new());
+        let model_registry = Arc::new(ModelRegistry::new(
+            cl.value_of("model_canary")
+                .map(|line| format!("{}\n", line)),
+            golden.clone(),
+            prediction_log.clone(),
+            monitor.clone(),
+        ));
+        let model_version = Arc::new(ModelVersion::new(
+            0,
+            cl.value_of("initial_regressor").unwrap(),
+        )?);
+
+        let new_worker = |i: u32, frontend: Option<FrontendHandle>| {
+            WorkerThread::new(
                 i,
                 re_fixed2.clone(),
                 fbt.clone(),
                 pa.clone(),
                 pb.clone(),
-                Arc::clone(&receiver),
-            )?;
-            s.worker_threads.push(newt);
+                golden.clone(),
+                value_ranges.clone(),
+                parity.clone(),
+                lofo.clone(),
+                Arc::clone(&explainer),
+                rate_limiter.clone(),
+                prediction_log.clone(),
+                monitor.clone(),
+                Arc::clone(&evaluations),
+                Arc::clone(&model_registry),
+                Arc::clone(&model_version),
+                max_batch,
+                tls_config.clone(),
+                frontend,
+            )
+        };
+        let mut worker_threads = Vec::new();
+        for i in 0..num_children {
+            let newt = new_worker(i, Some(frontend_handle.try_clone()?));
+            worker_threads.push(newt.spawn(Arc::clone(&receiver)));
+        }
+        // gRPC requests are served by workers of their own, as many as those of connections
+        #[cfg(feature = "grpc")]
+        let grpc_server = match &grpc_listening_interface {
+            Some(grpc_listening_interface) => Some(GrpcServer::bind(
+                grpc_listening_interface,
+                (0..num_children).map(|i| new_worker(i, None)).collect(),
+            )?),
+            None => None,
+        };
+        Ok(Serving {
+            listening_interface,
+            http_listening_interface,
+            worker_threads,
+            sender: Some(sender),
+            frontend: Some(frontend),
+            shutdown_timeout,
+            re_fixed: re_fixed2,
+            mi: mi.clone(),
+            model_registry,
+            transform_state,
+            #[cfg(feature = "grpc")]
+            grpc_server,
+        })
+    }
+
+    // The model served at the time, as grown by "ffm_grow", switched to by "model_load" or
+    // replaced by "hogwild_load"
+    pub fn served_model(&self) -> (BoxedRegressorTrait, model_instance::ModelInstance) {
+        match self.model_registry.published() {
+            Some((re_fixed, _, fbt)) => (re_fixed, fbt.model_instance),
+            None => (self.re_fixed.clone(), self.mi.clone()),
         }
-        Ok(s)
     }
 
+    // Returns after SIGTERM, once the requests sent until then are answered or
+    // --shutdown_timeout has passed
     pub fn serve(&mut self) -> Result<(), Box<dyn Error>> {
-        let listener = net::TcpListener::bind(&self.listening_interface)
-            .expect("Cannot bind to the interface");
+        signals::install_shutdown()?;
+        let sender = self.sender.take().unwrap();
+        let frontend = self.frontend.take().unwrap();
+        let mut listeners = vec![(
+            net::TcpListener::bind(&self.listening_interface)
+                .expect("Cannot bind to the interface"),
+            false,
+        )];
+        if let Some(http_listening_interface) = &self.http_listening_interface {
+            let http_listener = net::TcpListener::bind(http_listening_interface)
+                .expect("Cannot bind to the HTTP interface");
+            listeners.push((http_listener, true));
+        }
+
+        // It stops on the same shutdown request and is drained like the workers
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_server) = self.grpc_server.take() {
+            self.worker_threads.push(grpc_server.start());
+        }
+
         log::info!("Bind done, deamonizing and calling accept");
-        for stream in listener.incoming() {
-            self.sender.send(stream?)?;
+        frontend.run(&listeners, &sender)?;
+        drop(listeners);
+
+        log::info!("Shutting down, draining connections");
+        // Workers exit once they took all connections from the channel and served them
+        drop(sender);
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while !self.worker_threads.iter().all(|t| t.is_finished()) {
+            if Instant::now() > deadline {
+                log::warn!(
+                    "Connections still open after --shutdown_timeout of {} seconds, dropping them",
+                    self.shutdown_timeout.as_secs()
+                );
+                self.save_transform_state();
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
         }
+        for worker_thread in self.worker_threads.drain(..) {
+            worker_thread.join().unwrap();
+        }
+        log::info!("All connections drained");
+        self.save_transform_state();
         Ok(())
     }
+
+    fn save_transform_state(&self) {
+        if let Some(transform_state) = &self.transform_state {
+            transform_state.save();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -258,9 +1257,11 @@ mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
     use crate::feature_buffer;
+    use crate::model_registry::ModelLoadStatus;
     use crate::regressor;
     use mockstream::{FailingMockStream, SharedMockStream};
     use std::io::ErrorKind;
+    use std::io::Write;
     use std::str;
     use tempfile::tempdir;
 
@@ -299,6 +1300,26 @@ C,featureC
             pa,
             re_fixed,
             pb,
+            golden: None,
+            value_ranges: None,
+            parity: None,
+            lofo: None,
+            explainer: Arc::new(Explainer::new(&mi, &vw, 2).unwrap()),
+            rate_limiter: None,
+            connection_limiter: None,
+            prediction_log: None,
+            monitor: None,
+            evaluations: Arc::new(Evaluations::new()),
+            model_registry: Arc::new(ModelRegistry::new(None, None, None, None)),
+            model_generation: 0,
+            model_version: Arc::new(ModelVersion {
+                generation: 0,
+                filename: "model.fw".to_string(),
+                checksum: 0,
+            }),
+            max_batch: 2,
+            tls_config: None,
+            frontend: None,
         };
 
         {
@@ -326,6 +1347,81 @@ C,featureC
             let x = mocked_stream.pop_bytes_written();
             assert_eq!(x, b"0.500000\n");
 
+            mocked_stream.push_bytes_to_read(b"debug 1 |A 0\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+            assert!(x.starts_with(
+                "{\"label\":1,\"importance\":1.0,\"namespaces\":[{\"name\":\"featureA\""
+            ));
+            assert!(x.ends_with("\"prediction\":0.5}\n"));
+
+            mocked_stream.push_bytes_to_read(b"explain |A 0\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            // Weights are all zero, so nothing contributes
+            assert_eq!(str::from_utf8(&x).unwrap(), "0.500000\n");
+
+            mocked_stream.push_bytes_to_read(b"outputs |A 0\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x).unwrap(),
+                "0.500000 margin:0.000000 lr:0.000000\n"
+            );
+
+            mocked_stream.push_bytes_to_read(b"model_version\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x).unwrap(),
+                "model_version generation:0 checksum:0000000000000000 filename:model.fw\n"
+            );
+
+            mocked_stream.push_bytes_to_read(b"batch 2\n|A 0\n1 |B 0\nstats\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+            assert!(x.starts_with("0.500000\n0.500000\n"));
+
+            mocked_stream.push_bytes_to_read(b"batch 3\n|A 0\n|A 0\n|A 0\n");
+            assert_eq!(
+                ConnectionEnd::ParseError,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x).unwrap(),
+                "ERR: batch of 3 examples is over --max_batch 2\n"
+            );
+
+            let mut mocked_stream = SharedMockStream::new();
+            let mut reader = BufReader::new(mocked_stream.clone());
+            let mut writer = BufWriter::new(mocked_stream.clone());
+            mocked_stream.push_bytes_to_read(b"batch 2\n|A 0\n");
+            assert_eq!(
+                ConnectionEnd::ParseError,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x).unwrap(),
+                "ERR: batch ended after 1 of 2 examples\n"
+            );
+
             mocked_stream.push_bytes_to_read(b"! exclamation mark is not a valid label");
             assert_eq!(
                 ConnectionEnd::ParseError,
@@ -335,6 +1431,65 @@ C,featureC
             assert_eq!(&x[..], &b"ERR: Cannot parse an example\n"[..]);
         }
 
+        {
+            // RATE LIMITED CONNECTION
+            let limiter = Arc::new(
+                RateLimiter::new(Some(1.0), None, crate::rate_limit::OverLimit::Reject).unwrap(),
+            );
+            newt.rate_limiter = Some(Arc::clone(&limiter));
+            newt.connection_limiter = Some(ConnectionLimiter::new(&limiter, None));
+            let mut mocked_stream = SharedMockStream::new();
+            let mut reader = BufReader::new(mocked_stream.clone());
+            let mut writer = BufWriter::new(mocked_stream.clone());
+            mocked_stream.push_bytes_to_read(b"|A 0\n|A 0\nstats\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x).unwrap(),
+                "0.500000\nERR: rate limited\nrate_limit allowed:1 queued:0 rejected:1\n"
+            );
+
+            // Batches are admitted as a whole, the first one leaves the bucket in debt
+            let limiter = Arc::new(
+                RateLimiter::new(Some(1.0), None, crate::rate_limit::OverLimit::Reject).unwrap(),
+            );
+            newt.rate_limiter = Some(Arc::clone(&limiter));
+            newt.connection_limiter = Some(ConnectionLimiter::new(&limiter, None));
+            mocked_stream.push_bytes_to_read(b"batch 2\n|A 0\n|A 0\nbatch 2\n|A 0\n|A 0\nstats\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x).unwrap(),
+                "0.500000\n0.500000\nERR: rate limited\nERR: rate limited\nrate_limit allowed:2 queued:0 rejected:2\n"
+            );
+
+            // Commands that predict are limited like examples
+            let limiter = Arc::new(
+                RateLimiter::new(Some(1.0), None, crate::rate_limit::OverLimit::Reject).unwrap(),
+            );
+            newt.rate_limiter = Some(Arc::clone(&limiter));
+            newt.connection_limiter = Some(ConnectionLimiter::new(&limiter, None));
+            mocked_stream
+                .push_bytes_to_read(b"|A 0\ndebug |A 0\nexplain |A 0\noutputs |A 0\nstats\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x).unwrap(),
+                "0.500000\nERR: rate limited\nERR: rate limited\nERR: rate limited\nrate_limit allowed:1 queued:0 rejected:3\n"
+            );
+            newt.rate_limiter = None;
+            newt.connection_limiter = None;
+        }
+
         // Non Working stream test
 
         {
@@ -358,6 +1513,88 @@ C,featureC
         }
     }
 
+    #[test]
+    fn test_handle_http_connection() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        let mut re = regressor::Regressor::new(&mi);
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_fixed =
+            BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi, false).unwrap()));
+        let fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let pa = parser::VowpalParser::new(&vw);
+        let pb = re_fixed.new_portbuffer();
+
+        let mut newt = WorkerThread {
+            id: 1,
+            fbt,
+            pa,
+            re_fixed,
+            pb,
+            golden: None,
+            value_ranges: None,
+            parity: None,
+            lofo: None,
+            explainer: Arc::new(Explainer::new(&mi, &vw, 2).unwrap()),
+            rate_limiter: None,
+            connection_limiter: None,
+            prediction_log: None,
+            monitor: None,
+            evaluations: Arc::new(Evaluations::new()),
+            model_registry: Arc::new(ModelRegistry::new(None, None, None, None)),
+            model_generation: 0,
+            model_version: Arc::new(ModelVersion {
+                generation: 0,
+                filename: "model.fw".to_string(),
+                checksum: 0,
+            }),
+            max_batch: 2,
+            tls_config: None,
+            frontend: None,
+        };
+
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        let body = r#"[{"A": {"a": 1}}, {"B": {"b": 2}}]"#;
+        mocked_stream.push_bytes_to_read(
+            format!(
+                "POST /predict?stats=true HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        );
+        assert_eq!(
+            ConnectionEnd::EndOfStream,
+            newt.handle_http_connection(&mut reader, &mut writer)
+        );
+        let x = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+        assert!(x.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(x.ends_with(
+            "\r\n\r\n{\"predictions\":[0.5,0.5],\"stats\":{\"count\":2,\"mean\":0.5,\"min\":0.5,\"max\":0.5}}"
+        ));
+
+        let body = r#"{"C": {"c": 1}}"#;
+        mocked_stream.push_bytes_to_read(
+            format!(
+                "POST /predict HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET /stats HTTP/1.1\r\nConnection: close\r\n\r\n",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        );
+        assert_eq!(
+            ConnectionEnd::EndOfStream,
+            newt.handle_http_connection(&mut reader, &mut writer)
+        );
+        let x = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+        assert!(x.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(x.contains("HTTP/1.1 404 Not Found\r\n"));
+        assert!(x.ends_with("{\"error\":\"No such endpoint /stats\"}"));
+    }
+
     #[test]
     fn test_hogwild() {
         let vw_map_string = r#"
@@ -377,8 +1614,6 @@ C,featureC
         mi.ffm_fields = vec![vec![], vec![]];
         mi.optimizer = model_instance::Optimizer::AdagradLUT;
         let re_1 = regressor::Regressor::new(&mi);
-        mi.optimizer = model_instance::Optimizer::SGD;
-        let re_2 = regressor::Regressor::new(&mi);
 
         let dir = tempdir().unwrap();
         let regressor_filepath_1 = dir
@@ -387,9 +1622,13 @@ C,featureC
             .to_str()
             .unwrap()
             .to_owned();
+        // model file has to describe the optimizer its weights were written with
         persistence::save_regressor_to_filename(&regressor_filepath_1, &mi, &vw, re_1, false)
             .unwrap();
 
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_2 = regressor::Regressor::new(&mi);
+
         let regressor_filepath_2 = dir
             .path()
             .join("test_regressor2.fw")
@@ -415,6 +1654,26 @@ C,featureC
             pa,
             re_fixed,
             pb,
+            golden: None,
+            value_ranges: None,
+            parity: None,
+            lofo: None,
+            explainer: Arc::new(Explainer::new(&mi, &vw, 2).unwrap()),
+            rate_limiter: None,
+            connection_limiter: None,
+            prediction_log: None,
+            monitor: None,
+            evaluations: Arc::new(Evaluations::new()),
+            model_registry: Arc::new(ModelRegistry::new(None, None, None, None)),
+            model_generation: 0,
+            model_version: Arc::new(ModelVersion {
+                generation: 0,
+                filename: "model.fw".to_string(),
+                checksum: 0,
+            }),
+            max_batch: 2,
+            tls_config: None,
+            frontend: None,
         };
 
         {
@@ -466,4 +1725,320 @@ C,featureC
             assert_eq!(str::from_utf8(&x), str::from_utf8(b""));
         }
     }
+
+    #[test]
+    fn test_hogwild_load_rejected() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A"[..]]],
+                weight: 1.0,
+            });
+
+        // The model to load learned the opposite of the golden set
+        let mut re_worse = regressor::Regressor::new(&mi);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut pb = re_worse.new_portbuffer();
+        for _ in 0..10 {
+            fbt.translate(pa.next_vowpal_from_bytes(b"-1 |A a\n").unwrap(), 0);
+            re_worse.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("worse.fw").to_str().unwrap().to_owned();
+        persistence::save_regressor_to_filename(&regressor_filepath, &mi, &vw, re_worse, false)
+            .unwrap();
+        let golden_filepath = dir.path().join("golden.vw").to_str().unwrap().to_owned();
+        std::fs::write(&golden_filepath, "1 |A a\n").unwrap();
+        let golden = GoldenSet::new_from_filename(&golden_filepath, &mi, &vw, Some(0.01)).unwrap();
+
+        let mut re = regressor::Regressor::new(&mi);
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_fixed =
+            BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi, false).unwrap()));
+        let pb = re_fixed.new_portbuffer();
+        let mut newt = WorkerThread {
+            id: 1,
+            fbt: feature_buffer::FeatureBufferTranslator::new(&mi),
+            pa: parser::VowpalParser::new(&vw),
+            re_fixed,
+            pb,
+            golden: Some(Arc::new(golden)),
+            value_ranges: None,
+            parity: None,
+            lofo: None,
+            explainer: Arc::new(Explainer::new(&mi, &vw, 2).unwrap()),
+            rate_limiter: None,
+            connection_limiter: None,
+            prediction_log: None,
+            monitor: None,
+            evaluations: Arc::new(Evaluations::new()),
+            model_registry: Arc::new(ModelRegistry::new(None, None, None, None)),
+            model_generation: 0,
+            model_version: Arc::new(ModelVersion {
+                generation: 0,
+                filename: "model.fw".to_string(),
+                checksum: 0,
+            }),
+            max_batch: 2,
+            tls_config: None,
+            frontend: None,
+        };
+
+        // The reply is flushed while the connection is still open, and the model stays
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream
+            .push_bytes_to_read(format!("hogwild_load {}", &regressor_filepath).as_bytes());
+        assert_eq!(
+            ConnectionEnd::EndOfStream,
+            newt.handle_connection(&mut reader, &mut writer)
+        );
+        let x = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+        assert!(x.starts_with("ERR: hogwild_load rejected: "));
+        assert!(x.ends_with("\n"));
+        mocked_stream.push_bytes_to_read(b"|A a\n");
+        newt.handle_connection(&mut reader, &mut writer);
+        assert_eq!(mocked_stream.pop_bytes_written(), b"0.500000\n");
+    }
+
+    #[test]
+    fn test_ffm_grow() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let a = vw.map_vwname_to_namespace_descriptor[&b"A"[..]];
+        let b = vw.map_vwname_to_namespace_descriptor[&b"B"[..]];
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 10;
+        mi.ffm_fields = vec![vec![a], vec![b]];
+        mi.optimizer = model_instance::Optimizer::SGD;
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![a],
+                weight: 1.0,
+            });
+
+        // FFM weights are initialized randomly, so predictions of the examples differ
+        let mut re = regressor::Regressor::new(&mi);
+        let re_fixed =
+            BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi, false).unwrap()));
+        let model_registry = Arc::new(ModelRegistry::new(None, None, None, None));
+        let new_worker = |id: u32| WorkerThread {
+            id,
+            fbt: feature_buffer::FeatureBufferTranslator::new(&mi),
+            pa: parser::VowpalParser::new(&vw),
+            re_fixed: re_fixed.clone(),
+            pb: re_fixed.new_portbuffer(),
+            golden: None,
+            value_ranges: None,
+            parity: None,
+            lofo: None,
+            explainer: Arc::new(Explainer::new(&mi, &vw, 2).unwrap()),
+            rate_limiter: None,
+            connection_limiter: None,
+            prediction_log: None,
+            monitor: None,
+            evaluations: Arc::new(Evaluations::new()),
+            model_registry: Arc::clone(&model_registry),
+            model_generation: 0,
+            model_version: Arc::new(ModelVersion {
+                generation: 0,
+                filename: "model.fw".to_string(),
+                checksum: 0,
+            }),
+            max_batch: 2,
+            tls_config: None,
+            frontend: None,
+        };
+        let mut newt1 = new_worker(1);
+        let mut newt2 = new_worker(2);
+        let lines: String = (0..20)
+            .map(|i| format!("|A a{} |B b{}\n", i, i % 3))
+            .collect();
+
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream.push_bytes_to_read(lines.as_bytes());
+        newt1.handle_connection(&mut reader, &mut writer);
+        let predictions = mocked_stream.pop_bytes_written();
+        assert_eq!(predictions.iter().filter(|c| **c == b'\n').count(), 20);
+
+        mocked_stream.push_bytes_to_read(b"ffm_grow\n");
+        newt1.handle_connection(&mut reader, &mut writer);
+        assert_eq!(mocked_stream.pop_bytes_written(), b"ffm_grow started\n");
+        for _ in 0..1000 {
+            match model_registry.status() {
+                Some(ModelLoadStatus::Growing(_)) => thread::sleep(Duration::from_millis(10)),
+                _ => break,
+            }
+        }
+        assert_eq!(model_registry.status(), Some(ModelLoadStatus::Grown(11, 1)));
+
+        // Both worker threads switch to the grown table and predict the same as before
+        for newt in [&mut newt1, &mut newt2].iter_mut() {
+            mocked_stream.push_bytes_to_read(lines.as_bytes());
+            newt.handle_connection(&mut reader, &mut writer);
+            assert_eq!(mocked_stream.pop_bytes_written(), predictions);
+            assert_eq!(newt.fbt.model_instance.ffm_bit_precision, 11);
+        }
+        mocked_stream.push_bytes_to_read(b"stats\nmodel_version\n");
+        newt2.handle_connection(&mut reader, &mut writer);
+        assert_eq!(
+            str::from_utf8(&mocked_stream.pop_bytes_written()).unwrap(),
+            "ffm_grow ffm_bit_precision:11 generation:1 done\n\
+             model_version generation:1 checksum:0000000000000000 filename:model.fw\n"
+        );
+
+        // Now models trained with the larger table can be loaded, but not those of the old one
+        let dir = tempdir().unwrap();
+        let mut mi_grown = mi.clone();
+        mi_grown.ffm_bit_precision = 11;
+        let grown_filepath = dir.path().join("grown.fw").to_str().unwrap().to_owned();
+        persistence::save_regressor_to_filename(
+            &grown_filepath,
+            &mi_grown,
+            &vw,
+            regressor::Regressor::new(&mi_grown),
+            false,
+        )
+        .unwrap();
+        let old_filepath = dir.path().join("old.fw").to_str().unwrap().to_owned();
+        persistence::save_regressor_to_filename(
+            &old_filepath,
+            &mi,
+            &vw,
+            regressor::Regressor::new(&mi),
+            false,
+        )
+        .unwrap();
+        mocked_stream.push_bytes_to_read(format!("hogwild_load {}", &grown_filepath).as_bytes());
+        newt2.handle_connection(&mut reader, &mut writer);
+        assert_eq!(mocked_stream.pop_bytes_written(), b"hogwild_load success\n");
+        mocked_stream.push_bytes_to_read(format!("hogwild_load {}", &old_filepath).as_bytes());
+        assert_eq!(
+            ConnectionEnd::StreamWriteError,
+            newt2.handle_connection(&mut reader, &mut writer)
+        );
+        writer.flush().unwrap();
+        assert_eq!(
+            mocked_stream.pop_bytes_written(),
+            b"ERR: hogwild_load fail\n"
+        );
+
+        // The grown table can grow further
+        mocked_stream.push_bytes_to_read(b"ffm_grow\n");
+        newt2.handle_connection(&mut reader, &mut writer);
+        assert_eq!(mocked_stream.pop_bytes_written(), b"ffm_grow started\n");
+        for _ in 0..1000 {
+            match model_registry.status() {
+                Some(ModelLoadStatus::Growing(_)) => thread::sleep(Duration::from_millis(10)),
+                _ => break,
+            }
+        }
+        assert_eq!(model_registry.status(), Some(ModelLoadStatus::Grown(12, 2)));
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn test_hogwild_load_failure_keeps_served_model() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        mi.feature_combo_descs
+            .push(model_instance::FeatureComboDesc {
+                namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A"[..]]],
+                weight: 1.0,
+            });
+
+        // The model to load has learned, the served one has not
+        let mut re_trained = regressor::Regressor::new(&mi);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut pb = re_trained.new_portbuffer();
+        for _ in 0..10 {
+            fbt.translate(pa.next_vowpal_from_bytes(b"1 |A a\n").unwrap(), 0);
+            re_trained.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("trained.fw").to_str().unwrap().to_owned();
+        persistence::save_regressor_to_filename(&regressor_filepath, &mi, &vw, re_trained, false)
+            .unwrap();
+
+        let mut re = regressor::Regressor::new(&mi);
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_fixed =
+            BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi, false).unwrap()));
+        let pb = re_fixed.new_portbuffer();
+        let mut newt = WorkerThread {
+            id: 1,
+            fbt: feature_buffer::FeatureBufferTranslator::new(&mi),
+            pa: parser::VowpalParser::new(&vw),
+            re_fixed,
+            pb,
+            golden: None,
+            value_ranges: None,
+            parity: None,
+            lofo: None,
+            explainer: Arc::new(Explainer::new(&mi, &vw, 2).unwrap()),
+            rate_limiter: None,
+            connection_limiter: None,
+            prediction_log: None,
+            monitor: None,
+            evaluations: Arc::new(Evaluations::new()),
+            model_registry: Arc::new(ModelRegistry::new(None, None, None, None)),
+            model_generation: 0,
+            model_version: Arc::new(ModelVersion {
+                generation: 0,
+                filename: "model.fw".to_string(),
+                checksum: 0,
+            }),
+            max_batch: 2,
+            tls_config: None,
+            frontend: None,
+        };
+
+        // Each request on a new connection, a failed load closes it
+        let request = |newt: &mut WorkerThread, line: &str| {
+            let mut mocked_stream = SharedMockStream::new();
+            let mut reader = BufReader::new(mocked_stream.clone());
+            let mut writer = BufWriter::new(mocked_stream.clone());
+            mocked_stream.push_bytes_to_read(line.as_bytes());
+            let end = newt.handle_connection(&mut reader, &mut writer);
+            drop(writer);
+            (
+                end,
+                String::from_utf8(mocked_stream.pop_bytes_written()).unwrap(),
+            )
+        };
+        let predict = |newt: &mut WorkerThread| request(newt, "|A a\n").1;
+        let load = format!("hogwild_load {}", &regressor_filepath);
+        assert_eq!(predict(&mut newt), "0.500000\n");
+
+        // An injected failure leaves the weights as they were
+        chaos::set_thread_config(chaos::ChaosConfig {
+            load_fail_rate: 1.0,
+            ..chaos::ChaosConfig::default()
+        });
+        assert_eq!(request(&mut newt, &load).0, ConnectionEnd::StreamWriteError);
+        assert_eq!(predict(&mut newt), "0.500000\n");
+
+        chaos::set_thread_config(chaos::ChaosConfig::default());
+        assert_eq!(request(&mut newt, &load).1, "hogwild_load success\n");
+        assert_ne!(predict(&mut newt), "0.500000\n");
+    }
 }
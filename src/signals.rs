@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::io::Error as IOError;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Operating a running trainer with signals (--signals): SIGUSR1 asks for a snapshot and a flush of
+// the outputs, SIGUSR2 switches learning off and on again. Handlers only flip atomic flags, the
+// training loop looks at them between examples.
+// On SIGTERM the daemon stops accepting connections and drains the ones it has.
+
+static SNAPSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static LEARNING_PAUSED: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigusr1(_signum: libc::c_int) {
+    SNAPSHOT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_sigusr2(_signum: libc::c_int) {
+    LEARNING_PAUSED.fetch_xor(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_sigterm(_signum: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn install_handlers(
+    handlers: &[(libc::c_int, extern "C" fn(libc::c_int))],
+) -> Result<(), Box<dyn Error>> {
+    for (signum, handler) in handlers {
+        // Safe since the handlers only touch atomics
+        if unsafe { libc::signal(*signum, *handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(Box::new(IOError::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+pub fn install() -> Result<(), Box<dyn Error>> {
+    install_handlers(&[
+        (libc::SIGUSR1, handle_sigusr1 as extern "C" fn(libc::c_int)),
+        (libc::SIGUSR2, handle_sigusr2 as extern "C" fn(libc::c_int)),
+    ])
+}
+
+pub fn install_shutdown() -> Result<(), Box<dyn Error>> {
+    install_handlers(&[(libc::SIGTERM, handle_sigterm as extern "C" fn(libc::c_int))])
+}
+
+// True once after each SIGUSR1
+pub fn take_snapshot_request() -> bool {
+    SNAPSHOT_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+pub fn learning_paused() -> bool {
+    LEARNING_PAUSED.load(Ordering::SeqCst)
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_signals() {
+        install().unwrap();
+        assert!(!take_snapshot_request());
+        assert!(!learning_paused());
+        unsafe {
+            libc::raise(libc::SIGUSR1);
+        }
+        assert!(take_snapshot_request());
+        assert!(!take_snapshot_request());
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+        }
+        assert!(learning_paused());
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+        }
+        assert!(!learning_paused());
+    }
+}
@@ -0,0 +1,345 @@
+// Portable 4 lane f32 vector and prefetch for the hot loops of the blocks, 16 byte compares for the parser.
+// It maps to SSE on x86_64, NEON on aarch64 and plain arrays elsewhere, so the blocks build on any target.
+// std::simd would cover this, but it is not available on stable rust.
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::aarch64::*;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::*;
+
+use std::ops::{Add, Mul};
+
+pub const LANES: usize = 4;
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy)]
+pub struct F32x4(__m128);
+
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone, Copy)]
+pub struct F32x4(float32x4_t);
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[derive(Clone, Copy)]
+pub struct F32x4([f32; LANES]);
+
+// load() and store() are unaligned, a.mul_add(b, acc) is a * b + acc
+#[cfg(target_arch = "x86_64")]
+impl F32x4 {
+    #[inline(always)]
+    pub fn splat(value: f32) -> F32x4 {
+        unsafe { F32x4(_mm_set1_ps(value)) }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` has to point to 4 readable f32, with no alignment requirement. SSE is part of x86_64,
+    /// so no target feature has to be checked.
+    #[inline(always)]
+    pub unsafe fn load(ptr: *const f32) -> F32x4 {
+        F32x4(_mm_loadu_ps(ptr))
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` has to point to 4 writable f32, with no alignment requirement.
+    #[inline(always)]
+    pub unsafe fn store(self, ptr: *mut f32) {
+        _mm_storeu_ps(ptr, self.0)
+    }
+
+    // Fused only when fma is enabled at compile time
+    #[inline(always)]
+    #[cfg(target_feature = "fma")]
+    pub fn mul_add(self, other: F32x4, acc: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_fmadd_ps(self.0, other.0, acc.0)) }
+    }
+
+    #[inline(always)]
+    #[cfg(not(target_feature = "fma"))]
+    pub fn mul_add(self, other: F32x4, acc: F32x4) -> F32x4 {
+        self * other + acc
+    }
+
+    #[inline(always)]
+    pub fn reduce_sum(self) -> f32 {
+        unsafe {
+            let r2 = _mm_add_ps(self.0, _mm_movehl_ps(self.0, self.0));
+            // Add 2 lower values into the final result
+            let r1 = _mm_add_ss(r2, _mm_movehdup_ps(r2));
+            // Return the lowest lane of the result vector.
+            // The intrinsic below compiles into noop, modern compilers return floats in the lowest lane of xmm0 register.
+            _mm_cvtss_f32(r1)
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Add for F32x4 {
+    type Output = F32x4;
+
+    #[inline(always)]
+    fn add(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_add_ps(self.0, other.0)) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Mul for F32x4 {
+    type Output = F32x4;
+
+    #[inline(always)]
+    fn mul(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_mul_ps(self.0, other.0)) }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl F32x4 {
+    #[inline(always)]
+    pub fn splat(value: f32) -> F32x4 {
+        unsafe { F32x4(vdupq_n_f32(value)) }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` has to point to 4 readable f32, with no alignment requirement. NEON is part of
+    /// aarch64, so no target feature has to be checked.
+    #[inline(always)]
+    pub unsafe fn load(ptr: *const f32) -> F32x4 {
+        F32x4(vld1q_f32(ptr))
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` has to point to 4 writable f32, with no alignment requirement.
+    #[inline(always)]
+    pub unsafe fn store(self, ptr: *mut f32) {
+        vst1q_f32(ptr, self.0)
+    }
+
+    #[inline(always)]
+    pub fn mul_add(self, other: F32x4, acc: F32x4) -> F32x4 {
+        unsafe { F32x4(vfmaq_f32(acc.0, self.0, other.0)) }
+    }
+
+    #[inline(always)]
+    pub fn reduce_sum(self) -> f32 {
+        unsafe { vaddvq_f32(self.0) }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Add for F32x4 {
+    type Output = F32x4;
+
+    #[inline(always)]
+    fn add(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(vaddq_f32(self.0, other.0)) }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Mul for F32x4 {
+    type Output = F32x4;
+
+    #[inline(always)]
+    fn mul(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(vmulq_f32(self.0, other.0)) }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl F32x4 {
+    #[inline(always)]
+    pub fn splat(value: f32) -> F32x4 {
+        F32x4([value; LANES])
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` has to point to 4 readable f32, with no alignment requirement.
+    #[inline(always)]
+    pub unsafe fn load(ptr: *const f32) -> F32x4 {
+        F32x4(ptr.cast::<[f32; LANES]>().read_unaligned())
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` has to point to 4 writable f32, with no alignment requirement.
+    #[inline(always)]
+    pub unsafe fn store(self, ptr: *mut f32) {
+        ptr.cast::<[f32; LANES]>().write_unaligned(self.0)
+    }
+
+    #[inline(always)]
+    pub fn mul_add(self, other: F32x4, acc: F32x4) -> F32x4 {
+        self * other + acc
+    }
+
+    #[inline(always)]
+    pub fn reduce_sum(self) -> f32 {
+        (self.0[0] + self.0[2]) + (self.0[1] + self.0[3])
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl Add for F32x4 {
+    type Output = F32x4;
+
+    #[inline(always)]
+    fn add(self, other: F32x4) -> F32x4 {
+        let mut r = self.0;
+        for (r, o) in r.iter_mut().zip(other.0.iter()) {
+            *r += o;
+        }
+        F32x4(r)
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl Mul for F32x4 {
+    type Output = F32x4;
+
+    #[inline(always)]
+    fn mul(self, other: F32x4) -> F32x4 {
+        let mut r = self.0;
+        for (r, o) in r.iter_mut().zip(other.0.iter()) {
+            *r *= o;
+        }
+        F32x4(r)
+    }
+}
+
+// 16 bytes, compared to a byte at once. eq_mask() has bit i set when byte i matches, like movemask on SSE2.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy)]
+pub struct U8x16(__m128i);
+
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone, Copy)]
+pub struct U8x16(uint8x16_t);
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[derive(Clone, Copy)]
+pub struct U8x16([u8; 16]);
+
+#[cfg(target_arch = "x86_64")]
+impl U8x16 {
+    /// # Safety
+    ///
+    /// `ptr` has to point to 16 readable bytes, with no alignment requirement. SSE2 is part of
+    /// x86_64, so no target feature has to be checked.
+    #[inline(always)]
+    pub unsafe fn load(ptr: *const u8) -> U8x16 {
+        U8x16(_mm_loadu_si128(ptr as *const __m128i))
+    }
+
+    #[inline(always)]
+    pub fn eq_mask(self, byte: u8) -> u16 {
+        unsafe { _mm_movemask_epi8(_mm_cmpeq_epi8(self.0, _mm_set1_epi8(byte as i8))) as u16 }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl U8x16 {
+    /// # Safety
+    ///
+    /// `ptr` has to point to 16 readable bytes, with no alignment requirement. NEON is part of
+    /// aarch64, so no target feature has to be checked.
+    #[inline(always)]
+    pub unsafe fn load(ptr: *const u8) -> U8x16 {
+        U8x16(vld1q_u8(ptr))
+    }
+
+    // NEON has no movemask, matching bytes keep their bit of the half and the halves are summed
+    #[inline(always)]
+    pub fn eq_mask(self, byte: u8) -> u16 {
+        const BITS: [u8; 16] = [1, 2, 4, 8, 16, 32, 64, 128, 1, 2, 4, 8, 16, 32, 64, 128];
+        unsafe {
+            let bits = vandq_u8(vceqq_u8(self.0, vdupq_n_u8(byte)), vld1q_u8(BITS.as_ptr()));
+            vaddv_u8(vget_low_u8(bits)) as u16 | (vaddv_u8(vget_high_u8(bits)) as u16) << 8
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl U8x16 {
+    /// # Safety
+    ///
+    /// `ptr` has to point to 16 readable bytes, with no alignment requirement.
+    #[inline(always)]
+    pub unsafe fn load(ptr: *const u8) -> U8x16 {
+        U8x16(ptr.cast::<[u8; 16]>().read_unaligned())
+    }
+
+    #[inline(always)]
+    pub fn eq_mask(self, byte: u8) -> u16 {
+        let mut mask = 0;
+        for (i, b) in self.0.iter().enumerate() {
+            mask |= ((*b == byte) as u16) << i;
+        }
+        mask
+    }
+}
+
+// Hints the cpu to bring the cache line of value into L1
+#[inline(always)]
+#[cfg(target_arch = "x86_64")]
+pub fn prefetch(value: &f32) {
+    unsafe { _mm_prefetch(value as *const f32 as *const i8, _MM_HINT_T0) }
+}
+
+#[inline(always)]
+#[cfg(target_arch = "aarch64")]
+pub fn prefetch(value: &f32) {
+    unsafe {
+        core::arch::asm!(
+            "prfm pldl1keep, [{0}]",
+            in(reg) value as *const f32,
+            options(nostack, readonly, preserves_flags)
+        )
+    }
+}
+
+#[inline(always)]
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn prefetch(_value: &f32) {}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_f32x4() {
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0];
+        let b = [0.5, -1.0, 2.0, 0.25];
+        let mut out = [0.0; 5];
+        unsafe {
+            // Unaligned
+            let va = F32x4::load(a.as_ptr().add(1));
+            let vb = F32x4::load(b.as_ptr());
+            (va + vb).store(out.as_mut_ptr().add(1));
+            assert_eq!(out, [0.0, 2.5, 2.0, 6.0, 5.25]);
+            (va * vb).store(out.as_mut_ptr());
+            assert_eq!(out[..4], [1.0, -3.0, 8.0, 1.25]);
+            va.mul_add(vb, F32x4::splat(1.0)).store(out.as_mut_ptr());
+            assert_eq!(out[..4], [2.0, -2.0, 9.0, 2.25]);
+            assert_eq!(va.reduce_sum(), 14.0);
+        }
+        prefetch(&a[4]);
+    }
+
+    #[test]
+    fn test_u8x16() {
+        let bytes = b"_a b:c|d  e:f|g h_";
+        unsafe {
+            let v = U8x16::load(bytes.as_ptr().add(1));
+            assert_eq!(v.eq_mask(b' '), 0b0100_0001_1000_0010);
+            assert_eq!(v.eq_mask(b':'), 0b0000_0100_0000_1000);
+            assert_eq!(v.eq_mask(b'|'), 0b0001_0000_0010_0000);
+            assert_eq!(v.eq_mask(b'x'), 0);
+        }
+    }
+}
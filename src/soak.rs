@@ -0,0 +1,474 @@
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use crate::feature_buffer::FeatureBufferTranslator;
+use crate::hogwild;
+use crate::hogwild::HogwildTrainer;
+use crate::model_instance::ModelInstance;
+use crate::multithread_helpers::BoxedRegressorTrait;
+use crate::parser::VowpalParser;
+use crate::persistence;
+use crate::regressor;
+use crate::vwmap::VwNamespaceMap;
+
+const NAMESPACE_NAMES: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+// Drift is expressed as fraction of hidden weights that change every DRIFT_PERIOD examples
+const DRIFT_PERIOD: f64 = 100_000.0;
+// Examples that are kept aside to verify predictions on
+const PROBE_SIZE: usize = 100;
+// How often we look at the clock
+const CLOCK_CHECK_EVERY: u64 = 1000;
+
+pub struct SoakConfig {
+    pub duration: Duration,
+    pub report_interval: Duration,
+    pub cardinalities: Vec<u32>,
+    pub drift: f32,
+    pub hogwild_threads: u32,      // 0 means training on the main thread
+    pub min_throughput_ratio: f32, // compared to the first interval
+    pub max_rss_growth_mb: f64,    // compared to the end of the first interval
+    pub seed: u64,
+}
+
+impl SoakConfig {
+    pub fn new_from_cmdline(cl: &clap::ArgMatches) -> Result<SoakConfig, Box<dyn Error>> {
+        let hours: f64 = cl.value_of("soak_hours").unwrap().parse()?;
+        let report_seconds: f64 = match cl.value_of("soak_report_seconds") {
+            Some(val) => val.parse()?,
+            None => 60.0,
+        };
+        let cardinalities = match cl.value_of("soak_cardinalities") {
+            Some(val) => val
+                .split(',')
+                .map(|s| s.parse::<u32>())
+                .collect::<Result<Vec<u32>, _>>()?,
+            None => vec![10, 1000, 100_000],
+        };
+        if cardinalities.is_empty()
+            || cardinalities.len() > NAMESPACE_NAMES.len()
+            || cardinalities.contains(&0)
+        {
+            return Err(format!(
+                "--soak_cardinalities needs between 1 and {} positive numbers",
+                NAMESPACE_NAMES.len()
+            ))?;
+        }
+        let drift: f32 = match cl.value_of("soak_drift") {
+            Some(val) => val.parse()?,
+            None => 0.01,
+        };
+        let hogwild_threads = if cl.is_present("hogwild_training") {
+            match cl.value_of("hogwild_threads") {
+                Some(threads) => threads.parse()?,
+                None => 16,
+            }
+        } else {
+            0
+        };
+        Ok(SoakConfig {
+            duration: Duration::from_secs_f64(hours * 3600.0),
+            report_interval: Duration::from_secs_f64(report_seconds),
+            cardinalities,
+            drift,
+            hogwild_threads,
+            min_throughput_ratio: 0.5,
+            max_rss_growth_mb: 512.0,
+            seed: 0,
+        })
+    }
+}
+
+// Namespaces A, B, C, ... one per cardinality, so model flags can refer to them as usual
+pub fn soak_namespace_map(cardinalities: &[u32]) -> Result<VwNamespaceMap, Box<dyn Error>> {
+    let mut csv = String::new();
+    for name in NAMESPACE_NAMES.iter().take(cardinalities.len()) {
+        let name = *name as char;
+        writeln!(csv, "{},soak_{}", name, name)?;
+    }
+    VwNamespaceMap::new(&csv)
+}
+
+fn mix64(mut x: u64) -> u64 {
+    // splitmix64 finalizer
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn unit_from_hash(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Generates vowpal lines whose labels follow a hidden logistic model with drifting weights
+pub struct TrafficGenerator {
+    rng: Xoshiro256PlusPlus,
+    cardinalities: Vec<u32>,
+    drift: f64,
+    seed: u64,
+    pub example_number: u64,
+    line: String,
+}
+
+impl TrafficGenerator {
+    pub fn new(cardinalities: &[u32], drift: f32, seed: u64) -> TrafficGenerator {
+        TrafficGenerator {
+            rng: Xoshiro256PlusPlus::seed_from_u64(seed),
+            cardinalities: cardinalities.to_vec(),
+            drift: drift as f64,
+            seed,
+            example_number: 0,
+            line: String::new(),
+        }
+    }
+
+    pub fn hidden_weight(&self, namespace: usize, value: u32) -> f32 {
+        let key = self.seed ^ ((namespace as u64) << 32 | value as u64);
+        // Each value changes at its own phase, so drift is spread evenly over time
+        let phase = unit_from_hash(mix64(key));
+        let generation = (self.example_number as f64 * self.drift / DRIFT_PERIOD + phase) as u64;
+        let u = unit_from_hash(mix64(mix64(key) ^ generation.wrapping_add(1)));
+        (u * 2.0 - 1.0) as f32
+    }
+
+    pub fn next_line(&mut self) -> &str {
+        self.example_number += 1;
+        let mut values = Vec::with_capacity(self.cardinalities.len());
+        let mut logit = 0.0;
+        for (namespace, cardinality) in self.cardinalities.iter().enumerate() {
+            // Skewed towards low values, like real world id popularity
+            let u: f64 = self.rng.gen();
+            let value = ((*cardinality as f64) * u * u * u) as u32;
+            logit += self.hidden_weight(namespace, value);
+            values.push(value);
+        }
+        let p = 1.0 / (1.0 + (-logit).exp());
+        let label = if self.rng.gen::<f32>() < p { "1" } else { "-1" };
+
+        self.line.truncate(0);
+        self.line.push_str(label);
+        for (namespace, value) in values.iter().enumerate() {
+            write!(
+                self.line,
+                " |{} {}",
+                NAMESPACE_NAMES[namespace] as char, value
+            )
+            .unwrap();
+        }
+        self.line.push('\n');
+        &self.line
+    }
+}
+
+#[derive(Default)]
+pub struct SoakReport {
+    pub examples: u64,
+    pub intervals: u64,
+    pub first_throughput: f64,
+    pub last_throughput: f64,
+    pub first_rss_mb: Option<f64>,
+    pub last_rss_mb: Option<f64>,
+    pub checkpoints_verified: u64,
+    pub failures: Vec<String>,
+}
+
+impl fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Soak test summary:")?;
+        writeln!(f, "  examples:              {}", self.examples)?;
+        writeln!(f, "  intervals:             {}", self.intervals)?;
+        writeln!(
+            f,
+            "  throughput (ex/s):     first {:.0}, last {:.0}",
+            self.first_throughput, self.last_throughput
+        )?;
+        match (self.first_rss_mb, self.last_rss_mb) {
+            (Some(first), Some(last)) => writeln!(
+                f,
+                "  rss (MB):              first {:.1}, last {:.1}",
+                first, last
+            )?,
+            _ => writeln!(f, "  rss (MB):              not available")?,
+        }
+        writeln!(f, "  checkpoints verified:  {}", self.checkpoints_verified)?;
+        if self.failures.is_empty() {
+            write!(f, "  result:                PASS")
+        } else {
+            writeln!(f, "  result:                FAIL")?;
+            for failure in self.failures.iter() {
+                writeln!(f, "    {}", failure)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn current_rss_mb() -> Option<f64> {
+    // Only available on linux, memory checks are skipped elsewhere
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages: f64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(resident_pages * 4096.0 / (1024.0 * 1024.0))
+}
+
+fn new_trainer(
+    cfg: &SoakConfig,
+    sharable_regressor: &BoxedRegressorTrait,
+    fbt: &FeatureBufferTranslator,
+) -> HogwildTrainer {
+    if cfg.hogwild_threads > 0 {
+        HogwildTrainer::new(
+            sharable_regressor.clone(),
+            fbt,
+            cfg.hogwild_threads,
+            hogwild::DEFAULT_QUEUE_CAPACITY,
+            hogwild::QueueFull::Block,
+        )
+    } else {
+        HogwildTrainer::default()
+    }
+}
+
+pub fn run_soak(
+    cfg: &SoakConfig,
+    mi: &ModelInstance,
+    vw: &VwNamespaceMap,
+) -> Result<SoakReport, Box<dyn Error>> {
+    let mut sharable_regressor =
+        BoxedRegressorTrait::new(Box::new(regressor::get_regressor_with_weights(mi)));
+    let mut pb = sharable_regressor.new_portbuffer();
+    let mut fbt = FeatureBufferTranslator::new(mi);
+    let mut pa = VowpalParser::new(vw);
+    let mut generator = TrafficGenerator::new(&cfg.cardinalities, cfg.drift, cfg.seed);
+
+    let mut probe_records: Vec<Vec<u32>> = Vec::with_capacity(PROBE_SIZE);
+    for _ in 0..PROBE_SIZE {
+        let line = generator.next_line();
+        probe_records.push(pa.next_vowpal(&mut Cursor::new(line.as_bytes()))?.to_vec());
+    }
+
+    let checkpoint_path = std::env::temp_dir().join(format!("fw_soak_{}.fw", std::process::id()));
+    let checkpoint_filename = checkpoint_path.to_str().unwrap().to_string();
+    let mut saver = persistence::BackgroundSaver::new(&checkpoint_filename);
+
+    let mut trainer = new_trainer(cfg, &sharable_regressor, &fbt);
+
+    let mut report = SoakReport::default();
+    let start = Instant::now();
+    let mut interval_start = Instant::now();
+    let mut interval_examples: u64 = 0;
+    let mut interval_logloss: f64 = 0.0;
+    loop {
+        let line = generator.next_line();
+        let record = pa.next_vowpal(&mut Cursor::new(line.as_bytes()))?;
+        report.examples += 1;
+        interval_examples += 1;
+
+        if cfg.hogwild_threads > 0 {
+            trainer.digest_example(record.to_vec())?;
+        } else {
+            fbt.translate(record, report.examples);
+            let label = fbt.feature_buffer.label;
+            let prediction = sharable_regressor.learn(&fbt.feature_buffer, &mut pb, true);
+            if !prediction.is_finite() {
+                report.failures.push(format!(
+                    "Non-finite prediction {} at example {}",
+                    prediction, report.examples
+                ));
+                break;
+            }
+            let p = (prediction as f64).clamp(1e-15, 1.0 - 1e-15);
+            interval_logloss -= if label > 0.5 { p.ln() } else { (1.0 - p).ln() };
+        }
+
+        if report.examples % CLOCK_CHECK_EVERY != 0 {
+            continue;
+        }
+        let finished = start.elapsed() >= cfg.duration;
+        if !finished && interval_start.elapsed() < cfg.report_interval {
+            continue;
+        }
+
+        // Verification happens with training paused, so it does not count against throughput
+        std::mem::take(&mut trainer).block_until_workers_finished(None)?;
+        let throughput = interval_examples as f64 / interval_start.elapsed().as_secs_f64();
+        report.intervals += 1;
+        report.last_throughput = throughput;
+        if report.intervals == 1 {
+            report.first_throughput = throughput;
+        } else if throughput < report.first_throughput * cfg.min_throughput_ratio as f64 {
+            report.failures.push(format!(
+                "Throughput of interval {} dropped to {:.0} ex/s from {:.0} ex/s",
+                report.intervals, throughput, report.first_throughput
+            ));
+        }
+
+        let mut probe_predictions = Vec::with_capacity(PROBE_SIZE);
+        for record in probe_records.iter() {
+            fbt.translate(record, 0);
+            probe_predictions.push(sharable_regressor.predict(&fbt.feature_buffer, &mut pb));
+        }
+        if probe_predictions.iter().any(|p| !p.is_finite()) {
+            report.failures.push(format!(
+                "Non-finite probe prediction in interval {}",
+                report.intervals
+            ));
+        }
+
+        saver.snapshot(mi, vw, &sharable_regressor, false)?;
+        saver.wait_for_pending_write()?;
+        let (_, _, restored) =
+            persistence::new_regressor_from_filename(&checkpoint_filename, false, None)?;
+        let mut restored_pb = restored.new_portbuffer();
+        let mut restored_matches = true;
+        for (record, expected) in probe_records.iter().zip(probe_predictions.iter()) {
+            fbt.translate(record, 0);
+            let restored_prediction = restored.predict(&fbt.feature_buffer, &mut restored_pb);
+            if restored_prediction.to_bits() != expected.to_bits() {
+                restored_matches = false;
+            }
+        }
+        if restored_matches {
+            report.checkpoints_verified += 1;
+        } else {
+            report.failures.push(format!(
+                "Restored checkpoint predicts differently than the live model in interval {}",
+                report.intervals
+            ));
+        }
+
+        // Measured after the checkpoint, which keeps a serialized copy of the model around
+        report.last_rss_mb = current_rss_mb();
+        if report.intervals == 1 {
+            report.first_rss_mb = report.last_rss_mb;
+        }
+        if let (Some(first), Some(last)) = (report.first_rss_mb, report.last_rss_mb) {
+            if last - first > cfg.max_rss_growth_mb {
+                report.failures.push(format!(
+                    "Memory grew by {:.1} MB in interval {}",
+                    last - first,
+                    report.intervals
+                ));
+            }
+        }
+
+        log::info!(
+            "Soak interval {}: examples {}, {:.0} ex/s, logloss {:.5}, rss {} MB",
+            report.intervals,
+            report.examples,
+            throughput,
+            interval_logloss / interval_examples as f64,
+            report
+                .last_rss_mb
+                .map_or("n/a".to_string(), |rss| format!("{:.1}", rss))
+        );
+
+        if finished || !report.failures.is_empty() {
+            break;
+        }
+        trainer = new_trainer(cfg, &sharable_regressor, &fbt);
+        interval_start = Instant::now();
+        interval_examples = 0;
+        interval_logloss = 0.0;
+    }
+    std::mem::take(&mut trainer).block_until_workers_finished(None)?;
+    let _ = fs::remove_file(&checkpoint_filename);
+    Ok(report)
+}
+
+pub fn run_soak_from_cmdline(cl: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let cfg = SoakConfig::new_from_cmdline(cl)?;
+    let vw = soak_namespace_map(&cfg.cardinalities)?;
+    let mi = ModelInstance::new_from_cmdline(cl, &vw)?;
+    log::info!(
+        "Starting soak test for {:.2?} with namespace cardinalities {:?}",
+        cfg.duration,
+        cfg.cardinalities
+    );
+    let report = run_soak(&cfg, &mi, &vw)?;
+    log::info!("{}", report);
+    if !report.failures.is_empty() {
+        return Err(format!("Soak test failed: {}", report.failures.join("; ")))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::model_instance::Optimizer;
+
+    #[test]
+    fn test_generator() {
+        let mut g1 = TrafficGenerator::new(&[10, 1000], 0.0, 1);
+        let mut g2 = TrafficGenerator::new(&[10, 1000], 0.0, 1);
+        for _ in 0..100 {
+            assert_eq!(g1.next_line(), g2.next_line());
+        }
+        let line = g1.next_line().to_string();
+        assert!(line.starts_with("1 |A ") || line.starts_with("-1 |A "));
+        assert!(line.contains(" |B "));
+
+        // Without drift hidden weights never change, with full drift all of them change every period
+        let w = g1.hidden_weight(1, 7);
+        g1.example_number += 10 * DRIFT_PERIOD as u64;
+        assert_eq!(g1.hidden_weight(1, 7), w);
+        let mut g3 = TrafficGenerator::new(&[10, 1000], 1.0, 1);
+        let changed = (0..100)
+            .filter(|v| {
+                let w = g3.hidden_weight(1, *v);
+                g3.example_number += DRIFT_PERIOD as u64 / 2;
+                let changed = g3.hidden_weight(1, *v) != w;
+                g3.example_number -= DRIFT_PERIOD as u64 / 2;
+                changed
+            })
+            .count();
+        assert!(changed > 30 && changed < 70, "changed: {}", changed);
+    }
+
+    fn soak_config(hogwild_threads: u32) -> SoakConfig {
+        SoakConfig {
+            duration: Duration::from_millis(300),
+            report_interval: Duration::from_millis(100),
+            cardinalities: vec![10, 1000],
+            drift: 0.1,
+            hogwild_threads,
+            min_throughput_ratio: 0.0, // timing on shared test machines is too noisy
+            max_rss_growth_mb: 512.0,
+            seed: 0,
+        }
+    }
+
+    fn soak_model(vw: &VwNamespaceMap) -> ModelInstance {
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.optimizer = Optimizer::AdagradLUT;
+        mi.learning_rate = 0.1;
+        mi.init_acc_gradient = 0.0;
+        mi.feature_combo_descs
+            .push(mi.create_feature_combo_desc(vw, "A").unwrap());
+        mi.feature_combo_descs
+            .push(mi.create_feature_combo_desc(vw, "B").unwrap());
+        mi
+    }
+
+    #[test]
+    fn test_short_soak() {
+        for hogwild_threads in [0, 2].iter() {
+            let cfg = soak_config(*hogwild_threads);
+            let vw = soak_namespace_map(&cfg.cardinalities).unwrap();
+            let mi = soak_model(&vw);
+
+            let report = run_soak(&cfg, &mi, &vw).unwrap();
+            assert!(report.failures.is_empty(), "{}", report);
+            assert!(report.examples > 0);
+            assert!(report.intervals >= 2);
+            assert_eq!(report.checkpoints_verified, report.intervals);
+        }
+    }
+}
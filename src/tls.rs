@@ -0,0 +1,132 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::error::Error;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+// TLS termination of daemon connections (--tls_cert, --tls_key), of both the TCP protocol and
+// --http_port. The handshake happens in the worker thread on the first read of the connection,
+// so a slow client doesn't hold up accepting others.
+
+pub fn new_server_config_from_cmdline(
+    cl: &clap::ArgMatches,
+) -> Result<Option<Arc<ServerConfig>>, Box<dyn Error>> {
+    match (cl.value_of("tls_cert"), cl.value_of("tls_key")) {
+        (Some(cert_filename), Some(key_filename)) => {
+            log::info!("Serving over TLS with certificate {}", cert_filename);
+            Ok(Some(new_server_config(cert_filename, key_filename)?))
+        }
+        (None, None) => Ok(None),
+        _ => Err("--tls_cert and --tls_key have to be given together")?,
+    }
+}
+
+// Certificate chain and private key, both PEM
+pub fn new_server_config(
+    cert_filename: &str,
+    key_filename: &str,
+) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+    let certs = CertificateDer::pem_file_iter(cert_filename)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Can not read TLS certificates of {}: {}", cert_filename, e))?;
+    if certs.is_empty() {
+        return Err(format!("No TLS certificates in {}", cert_filename))?;
+    }
+    let key = PrivateKeyDer::from_pem_file(key_filename)
+        .map_err(|e| format!("Can not read TLS private key of {}: {}", key_filename, e))?;
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+// A TLS connection that the reader and the writer of a worker thread share, like the two
+// references to the TcpStream of plain connections. It goes back to the frontend with the
+// connection while it waits for its next request
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<StreamOwned<ServerConnection, TcpStream>>>);
+
+impl TlsStream {
+    pub fn new(
+        config: &Arc<ServerConfig>,
+        tcp_stream: TcpStream,
+    ) -> Result<TlsStream, Box<dyn Error>> {
+        let connection = ServerConnection::new(Arc::clone(config))?;
+        Ok(TlsStream(Arc::new(Mutex::new(StreamOwned::new(
+            connection, tcp_stream,
+        )))))
+    }
+
+    // Requests that were already read from the socket and decrypted, poll(2) doesn't see them
+    pub fn has_buffered_data(&self) -> bool {
+        match self.0.lock().unwrap().conn.process_new_packets() {
+            Ok(io_state) => io_state.plaintext_bytes_to_read() > 0,
+            Err(_) => true, // for the reader to get the error
+        }
+    }
+
+    // Tells the client that nothing more comes, after the last response was written
+    pub fn close(&self) {
+        let mut stream = self.0.lock().unwrap();
+        stream.conn.send_close_notify();
+        let _ = stream.flush();
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::cmdline;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_new_server_config() {
+        let dir = tempdir().unwrap();
+        let cert_filename = dir.path().join("cert.pem");
+        let cert_filename = cert_filename.to_str().unwrap();
+        let key_filename = dir.path().join("key.pem");
+        let key_filename = key_filename.to_str().unwrap();
+
+        let e = new_server_config(cert_filename, key_filename).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("Can not read TLS certificates of"));
+
+        fs::write(cert_filename, "not a certificate\n").unwrap();
+        let e = new_server_config(cert_filename, key_filename).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("No TLS certificates in {}", cert_filename)
+        );
+
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        let cl =
+            cmdline::parse_from(args(&["fw", "--daemon", "--tls_cert", cert_filename])).unwrap();
+        assert!(new_server_config_from_cmdline(&cl).is_err());
+        let cl = cmdline::parse_from(args(&["fw", "--daemon"])).unwrap();
+        assert!(new_server_config_from_cmdline(&cl).unwrap().is_none());
+    }
+}
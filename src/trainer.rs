@@ -0,0 +1,326 @@
+use std::error::Error;
+use std::io::BufRead;
+
+use crate::cmdline;
+use crate::feature_buffer::FeatureBufferTranslator;
+use crate::golden_set::{GoldenMetrics, MetricsAccumulator};
+use crate::model_instance::ModelInstance;
+use crate::parser;
+use crate::parser::VowpalParser;
+use crate::persistence;
+use crate::persistence::BackgroundSaver;
+use crate::port_buffer::PortBuffer;
+use crate::regressor;
+use crate::regressor::Regressor;
+use crate::vwmap::VwNamespaceMap;
+
+// Training from within a Rust service, instead of running the fw binary and parsing its logs.
+// The service is called back as training goes and can stop it early.
+
+pub struct TrainerConfig {
+    // Model options as given to the fw binary, e.g. "--keep A --keep B -l 0.1 --ffm_k 4".
+    // With --initial_regressor training continues from that model.
+    pub options: String,
+    // Contents of vw_namespace_map.csv, not needed when continuing from a model
+    pub vw_namespace_map: Option<String>,
+    pub metrics_window: u64, // examples in each metrics window, 0 for no windows
+    pub checkpoint_filename: Option<String>,
+    pub checkpoint_every: u64, // examples
+}
+
+impl TrainerConfig {
+    pub fn new(options: &str, vw_namespace_map: Option<&str>) -> TrainerConfig {
+        TrainerConfig {
+            options: options.to_string(),
+            vw_namespace_map: vw_namespace_map.map(|s| s.to_string()),
+            metrics_window: 0,
+            checkpoint_filename: None,
+            checkpoint_every: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrainerControl {
+    Continue,
+    Stop,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsWindow {
+    pub first_example: u64, // example numbers start with 1
+    pub last_example: u64,
+    pub metrics: Option<GoldenMetrics>, // of labeled examples, None when there were none
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainingSummary {
+    pub examples: u64, // over all train_stream calls
+    pub stopped: bool, // by a callback
+}
+
+// All hooks do nothing by default
+pub trait TrainerCallbacks {
+    // label is None for examples without one, those are only predicted
+    fn on_example(
+        &mut self,
+        _example_num: u64,
+        _prediction: f32,
+        _label: Option<bool>,
+    ) -> TrainerControl {
+        TrainerControl::Continue
+    }
+
+    fn on_checkpoint(&mut self, _filename: &str, _example_num: u64) {}
+
+    fn on_metrics_window(&mut self, _window: &MetricsWindow) -> TrainerControl {
+        TrainerControl::Continue
+    }
+
+    fn on_finished(&mut self, _summary: &TrainingSummary) {}
+}
+
+pub struct Trainer {
+    pub mi: ModelInstance,
+    pub vw: VwNamespaceMap,
+    pub re: Regressor,
+    fbt: FeatureBufferTranslator,
+    pa: VowpalParser,
+    pb: PortBuffer,
+    config: TrainerConfig,
+    example_num: u64,
+    checkpoint_saver: Option<BackgroundSaver>,
+}
+
+impl Trainer {
+    pub fn new(config: TrainerConfig) -> Result<Trainer, Box<dyn Error>> {
+        let mut args = vec!["fw".to_string()];
+        args.extend(shellwords::split(&config.options)?);
+        let cl = cmdline::parse_from(args)?;
+        let (mi, vw, re) = match cl.value_of("initial_regressor") {
+            Some(filename) => persistence::new_regressor_from_filename(filename, false, Some(&cl))?,
+            None => {
+                let vw = match config.vw_namespace_map.as_ref() {
+                    Some(vw_namespace_map) => VwNamespaceMap::new(vw_namespace_map)?,
+                    None => {
+                        return Err("Trainer needs a vw namespace map or an initial regressor")?
+                    }
+                };
+                let mi = ModelInstance::new_from_cmdline(&cl, &vw)?;
+                let mut re = regressor::get_regressor_with_weights(&mi);
+                re.load_pretrained_weights(&mi)?;
+                (mi, vw, re)
+            }
+        };
+        if config.checkpoint_filename.is_some() && config.checkpoint_every == 0 {
+            return Err("Checkpoints need a positive number of examples between them")?;
+        }
+        Ok(Trainer {
+            fbt: FeatureBufferTranslator::new(&mi),
+            pa: VowpalParser::new(&vw),
+            pb: re.new_portbuffer(),
+            checkpoint_saver: config
+                .checkpoint_filename
+                .as_deref()
+                .map(BackgroundSaver::new),
+            config,
+            example_num: 0,
+            mi,
+            vw,
+            re,
+        })
+    }
+
+    pub fn example_num(&self) -> u64 {
+        self.example_num
+    }
+
+    // Trains on examples in vowpal format until the end of the source or until a callback stops it.
+    // Can be called again with further sources.
+    pub fn train_stream(
+        &mut self,
+        source: &mut impl BufRead,
+        callbacks: &mut dyn TrainerCallbacks,
+    ) -> Result<TrainingSummary, Box<dyn Error>> {
+        let mut window_metrics = MetricsAccumulator::default();
+        let mut window_first_example = self.example_num + 1;
+        let mut stopped = false;
+        while !stopped {
+            let record = self.pa.next_vowpal(source)?;
+            if record.is_empty() {
+                break;
+            }
+            parser::check_label(record, self.fbt.model_instance.oaa)?;
+            self.example_num += 1;
+            let label = match record[parser::LABEL_OFFSET] & !parser::LABEL_FLAGS_MASK {
+                parser::NO_LABEL => None,
+                label => Some(label == 1),
+            };
+            self.fbt.translate(record, self.example_num);
+            let prediction = self
+                .re
+                .learn(&self.fbt.feature_buffer, &mut self.pb, label.is_some());
+            if let Some(label) = label {
+                window_metrics.add(prediction, label);
+            }
+            stopped =
+                callbacks.on_example(self.example_num, prediction, label) == TrainerControl::Stop;
+
+            if self.config.metrics_window > 0 && self.example_num % self.config.metrics_window == 0
+            {
+                let window = MetricsWindow {
+                    first_example: window_first_example,
+                    last_example: self.example_num,
+                    metrics: if window_metrics.is_empty() {
+                        None
+                    } else {
+                        Some(window_metrics.metrics())
+                    },
+                };
+                window_metrics = MetricsAccumulator::default();
+                window_first_example = self.example_num + 1;
+                if callbacks.on_metrics_window(&window) == TrainerControl::Stop {
+                    stopped = true;
+                }
+            }
+
+            if let Some(saver) = self.checkpoint_saver.as_mut() {
+                if self.example_num % self.config.checkpoint_every == 0 {
+                    self.re.apply_minibatch(&mut self.pb);
+                    saver.snapshot(&self.mi, &self.vw, &self.re, false)?;
+                    saver.wait_for_pending_write()?;
+                    callbacks.on_checkpoint(
+                        self.config.checkpoint_filename.as_deref().unwrap(),
+                        self.example_num,
+                    );
+                }
+            }
+        }
+        self.re.apply_minibatch(&mut self.pb);
+        let summary = TrainingSummary {
+            examples: self.example_num,
+            stopped,
+        };
+        callbacks.on_finished(&summary);
+        Ok(summary)
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        // Training from the saved model continues the learning rate schedule
+        let mut mi = self.mi.clone();
+        if let Some(schedule) = mi.lr_schedule.as_mut() {
+            schedule.examples_done += self.example_num;
+        }
+        // BackgroundSaver writes under a temporary name first
+        let mut saver = BackgroundSaver::new(filename);
+        saver.snapshot(&mi, &self.vw, &self.re, false)?;
+        saver.wait_for_pending_write()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    #[derive(Default)]
+    struct Recorder {
+        examples: Vec<(u64, Option<bool>)>,
+        windows: Vec<MetricsWindow>,
+        checkpoints: Vec<u64>,
+        finished: Vec<TrainingSummary>,
+        stop_at: u64,
+    }
+
+    impl TrainerCallbacks for Recorder {
+        fn on_example(
+            &mut self,
+            example_num: u64,
+            _prediction: f32,
+            label: Option<bool>,
+        ) -> TrainerControl {
+            self.examples.push((example_num, label));
+            if example_num == self.stop_at {
+                TrainerControl::Stop
+            } else {
+                TrainerControl::Continue
+            }
+        }
+
+        fn on_checkpoint(&mut self, _filename: &str, example_num: u64) {
+            self.checkpoints.push(example_num);
+        }
+
+        fn on_metrics_window(&mut self, window: &MetricsWindow) -> TrainerControl {
+            self.windows.push(window.clone());
+            TrainerControl::Continue
+        }
+
+        fn on_finished(&mut self, summary: &TrainingSummary) {
+            self.finished.push(summary.clone());
+        }
+    }
+
+    #[test]
+    fn test_trainer() {
+        let dir = tempdir().unwrap();
+        let checkpoint_filename = dir.path().join("checkpoint.fw");
+        let checkpoint_filename = checkpoint_filename.to_str().unwrap();
+        assert!(Trainer::new(TrainerConfig::new("--keep A", None)).is_err());
+
+        let mut config = TrainerConfig::new("--keep A -l 0.1", Some("A,featureA\n"));
+        config.metrics_window = 4;
+        config.checkpoint_filename = Some(checkpoint_filename.to_string());
+        config.checkpoint_every = 5;
+        let mut trainer = Trainer::new(config).unwrap();
+        let mut data = String::new();
+        for _ in 0..5 {
+            data.push_str("1 |A a\n-1 |A b\n");
+        }
+        data.push_str("|A a\n");
+        let mut recorder = Recorder::default();
+        let summary = trainer
+            .train_stream(&mut Cursor::new(data.as_bytes()), &mut recorder)
+            .unwrap();
+        assert_eq!(
+            summary,
+            TrainingSummary {
+                examples: 11,
+                stopped: false
+            }
+        );
+        assert_eq!(recorder.finished, vec![summary]);
+        assert_eq!(recorder.examples.len(), 11);
+        assert_eq!(recorder.examples[1], (2, Some(false)));
+        assert_eq!(recorder.examples[10], (11, None));
+        assert_eq!(recorder.checkpoints, vec![5, 10]);
+        assert_eq!(recorder.windows.len(), 2);
+        assert_eq!(recorder.windows[1].first_example, 5);
+        assert_eq!(recorder.windows[1].last_example, 8);
+        let metrics = recorder.windows[1].metrics.unwrap();
+        assert_eq!(metrics.examples, 4);
+        assert_eq!(metrics.auc, 1.0);
+
+        // Checkpoints can be served and trained further
+        let (_, _, re) =
+            persistence::new_regressor_from_filename(checkpoint_filename, true, None).unwrap();
+        assert_eq!(re.blocks_boxes.len(), trainer.re.blocks_boxes.len());
+        let mut trainer = Trainer::new(TrainerConfig::new(
+            &format!("-i {}", checkpoint_filename),
+            None,
+        ))
+        .unwrap();
+        let mut recorder = Recorder {
+            stop_at: 3,
+            ..Recorder::default()
+        };
+        let summary = trainer
+            .train_stream(&mut Cursor::new(data.as_bytes()), &mut recorder)
+            .unwrap();
+        assert!(summary.stopped);
+        assert_eq!(summary.examples, 3);
+        assert_eq!(trainer.example_num(), 3);
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::parser;
+use crate::vwmap::{NamespaceDescriptor, NamespaceFormat, VwNamespaceMap};
+
+// Ranges of values of f32 namespaces seen in training (--record_value_ranges), saved with the model,
+// so that serving and testing can count values outside of them (--check_value_ranges).
+// Values are checked after the scaling declared in vw_namespace_map.csv, so a silent change of units
+// upstream (ms instead of s) shows as values out of range.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ValueRange {
+    pub namespace_descriptor: NamespaceDescriptor,
+    pub min: f32,
+    pub max: f32,
+}
+
+pub fn is_power_of_ten(n: u64) -> bool {
+    let mut power: u64 = 1;
+    while power < n {
+        power = power.saturating_mul(10);
+    }
+    power == n
+}
+
+// Calls f with each value of the f32 namespace in the record, values of features without one (NaN) are skipped
+#[inline(always)]
+fn for_each_value(
+    record: &[u32],
+    namespace_descriptor: &NamespaceDescriptor,
+    mut f: impl FnMut(f32),
+) {
+    let token = record[namespace_descriptor.namespace_index as usize + parser::HEADER_LEN as usize];
+    if (token & parser::IS_NOT_SINGLE_MASK) == 0 || token == parser::NO_FEATURES {
+        return;
+    }
+    let start = ((token >> 16) & 0x3fff) as usize;
+    let end = (token & 0xffff) as usize;
+    for value_offset in (start + 1..end).step_by(2) {
+        let value = f32::from_bits(record[value_offset]);
+        if !value.is_nan() {
+            f(value);
+        }
+    }
+}
+
+pub struct ValueRangeRecorder {
+    ranges: Vec<ValueRange>,
+}
+
+impl ValueRangeRecorder {
+    // Ranges recorded before (by the initial regressor) are extended
+    pub fn new(vw: &VwNamespaceMap, recorded: &[ValueRange]) -> ValueRangeRecorder {
+        let mut ranges: Vec<ValueRange> = Vec::new();
+        for entry in vw.vw_source.entries.iter() {
+            let namespace_descriptor =
+                vw.map_vwname_to_namespace_descriptor[entry.namespace_vwname.as_bytes()];
+            if namespace_descriptor.namespace_format != NamespaceFormat::F32 {
+                continue;
+            }
+            ranges.push(
+                match recorded
+                    .iter()
+                    .find(|r| r.namespace_descriptor == namespace_descriptor)
+                {
+                    Some(range) => *range,
+                    None => ValueRange {
+                        namespace_descriptor,
+                        min: f32::INFINITY,
+                        max: f32::NEG_INFINITY,
+                    },
+                },
+            );
+        }
+        ValueRangeRecorder { ranges }
+    }
+
+    pub fn observe(&mut self, record: &[u32]) {
+        for range in self.ranges.iter_mut() {
+            let (mut min, mut max) = (range.min, range.max);
+            for_each_value(record, &range.namespace_descriptor, |value| {
+                min = min.min(value);
+                max = max.max(value);
+            });
+            range.min = min;
+            range.max = max;
+        }
+    }
+
+    // Ranges of namespaces that had at least one value
+    pub fn ranges(&self) -> Vec<ValueRange> {
+        self.ranges
+            .iter()
+            .filter(|r| r.min <= r.max)
+            .copied()
+            .collect()
+    }
+}
+
+// Clones share the counts, so all serving threads report together
+#[derive(Clone)]
+pub struct ValueRangeChecker {
+    ranges: Vec<ValueRange>,
+    values: Arc<AtomicU64>,
+    out_of_range: Arc<Vec<AtomicU64>>, // by range
+}
+
+impl ValueRangeChecker {
+    pub fn new(ranges: &[ValueRange]) -> Result<ValueRangeChecker, Box<dyn Error>> {
+        if ranges.is_empty() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                "--check_value_ranges needs a model trained with --record_value_ranges",
+            )));
+        }
+        Ok(ValueRangeChecker {
+            ranges: ranges.to_vec(),
+            values: Arc::new(AtomicU64::new(0)),
+            out_of_range: Arc::new(ranges.iter().map(|_| AtomicU64::new(0)).collect()),
+        })
+    }
+
+    pub fn observe(&self, record: &[u32]) {
+        for (range, out_of_range) in self.ranges.iter().zip(self.out_of_range.iter()) {
+            for_each_value(record, &range.namespace_descriptor, |value| {
+                self.values.fetch_add(1, Ordering::Relaxed);
+                if value < range.min || value > range.max {
+                    let count = out_of_range.fetch_add(1, Ordering::Relaxed) + 1;
+                    // Warn on the first one and then on every power of ten
+                    if is_power_of_ten(count) {
+                        log::warn!(
+                            "{} values of namespace {} out of the training range [{}, {}], last one: {}",
+                            count,
+                            range.namespace_descriptor.namespace_index,
+                            range.min,
+                            range.max,
+                            value
+                        );
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn out_of_range_counts(&self) -> Vec<u64> {
+        self.out_of_range
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+impl fmt::Display for ValueRangeChecker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "values checked: {}", self.values.load(Ordering::Relaxed))?;
+        for (range, count) in self.ranges.iter().zip(self.out_of_range_counts()) {
+            write!(
+                f,
+                ", namespace {} [{}, {}] out of range: {}",
+                range.namespace_descriptor.namespace_index, range.min, range.max, count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser::VowpalParser;
+
+    #[test]
+    fn test_value_ranges() {
+        let vw = VwNamespaceMap::new("A,featureA,f32\nB,featureB\nC,featureC,f32\n").unwrap();
+        let mut pa = VowpalParser::new(&vw);
+        let mut recorder = ValueRangeRecorder::new(&vw, &[]);
+        for line in ["1 |A 2.0 |B b\n", "1 |A 7.5 -1.0 |B b\n", "1 |A |B b\n"].iter() {
+            recorder.observe(pa.next_vowpal_from_bytes(line.as_bytes()).unwrap());
+        }
+        // C had no values
+        let ranges = recorder.ranges();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].min, ranges[0].max), (-1.0, 7.5));
+
+        // Recording continues from the earlier ranges
+        let mut recorder = ValueRangeRecorder::new(&vw, &ranges);
+        recorder.observe(pa.next_vowpal_from_bytes(b"1 |A 10.0\n").unwrap());
+        assert_eq!(recorder.ranges()[0].max, 10.0);
+
+        assert!(ValueRangeChecker::new(&[]).is_err());
+        let checker = ValueRangeChecker::new(&ranges).unwrap();
+        let cloned = checker.clone();
+        checker.observe(pa.next_vowpal_from_bytes(b"1 |A 3.0 7000.0\n").unwrap());
+        cloned.observe(pa.next_vowpal_from_bytes(b"1 |A -2.0 |C 1.0\n").unwrap());
+        assert_eq!(checker.out_of_range_counts(), vec![2]);
+        assert_eq!(
+            format!("{}", checker),
+            "values checked: 3, namespace 0 [-1, 7.5] out of range: 2"
+        );
+    }
+}
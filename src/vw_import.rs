@@ -0,0 +1,155 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+// Warm start from a linear Vowpal Wabbit model (--vw_initial_regressor). Its weights are put at
+// the same indexes of the LR block, which is right as long as both hash features the same way,
+// i.e. the same namespaces, --hash_seed 0 and the same --bit_precision.
+// Only text models of --readable_model (or --invert_hash) are read, their format is the same
+// across VW versions, unlike the one of binary regressors.
+
+#[derive(Debug, PartialEq)]
+pub struct VwReadableModel {
+    pub bits: u8,
+    pub weights: Vec<(usize, f32)>,
+}
+
+pub fn read_vw_readable_model(filename: &str) -> Result<VwReadableModel, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(filename)?);
+    parse_vw_readable_model(reader, filename)
+}
+
+pub fn parse_vw_readable_model(
+    reader: impl BufRead,
+    filename: &str,
+) -> Result<VwReadableModel, Box<dyn Error>> {
+    let mut lines = reader.lines();
+    match lines.next() {
+        Some(Ok(line)) if line.starts_with("Version ") => {}
+        _ => {
+            return Err(format!(
+                "{} is not a VW model saved with --readable_model, binary VW regressors are not supported",
+                filename
+            ))?
+        }
+    }
+    let mut bits: Option<u8> = None;
+    let mut weights: Vec<(usize, f32)> = Vec::new();
+    for line in lines {
+        let line = line?;
+        if bits.is_none() {
+            if let Some(value) = line.strip_prefix("bits:") {
+                bits = Some(value.trim().parse()?);
+            }
+            continue;
+        }
+        // index:weight, or feature:index:weight with --invert_hash. With --save_resume the
+        // weight is followed by the state of the optimizer, which we don't take over
+        let mut fields = line.rsplitn(3, ':');
+        let weight = fields.next().and_then(|f| f.split_whitespace().next());
+        let index = fields.next();
+        if let (Some(index), Some(weight)) = (index, weight) {
+            if let (Ok(index), Ok(weight)) = (index.parse::<usize>(), weight.parse::<f32>()) {
+                weights.push((index, weight));
+            }
+        }
+    }
+    match bits {
+        Some(bits) => Ok(VwReadableModel { bits, weights }),
+        None => Err(format!("VW model {} has no bits in its header", filename))?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::block_loss_functions::logistic;
+    use crate::feature_buffer;
+    use crate::feature_buffer::HashAndValue;
+    use crate::model_instance::ModelInstance;
+    use crate::regressor::Regressor;
+    use std::io::{Cursor, Write};
+    use tempfile::tempdir;
+
+    const READABLE_MODEL: &str = "Version 9.8.0
+Id
+Min label:0
+Max label:1
+bits:18
+lda:0
+0 ngram:
+0 skip:
+options: --hash_seed 0 --link logistic
+Checksum: 2033437909
+event_sum 0
+action_sum 0
+:0
+1:0.5
+2:0.25 0.1 1
+A^x:3:-1
+";
+
+    fn lr_vec(v: Vec<HashAndValue>) -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: v,
+            ffm_buffer: Vec::new(),
+            dense_buffer: Vec::new(),
+            embedding_buffer: Vec::new(),
+            head_labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_readable_model() {
+        let model = parse_vw_readable_model(Cursor::new(READABLE_MODEL), "model.txt").unwrap();
+        assert_eq!(
+            model,
+            VwReadableModel {
+                bits: 18,
+                weights: vec![(1, 0.5), (2, 0.25), (3, -1.0)],
+            }
+        );
+
+        let binary = Cursor::new(vec![6, 0, 0, 0, b'9', b'.', b'8', b'.', b'0', 0]);
+        assert!(parse_vw_readable_model(binary, "model.vw").is_err());
+        let no_bits = Cursor::new("Version 9.8.0\n:0\n1:0.5\n");
+        assert!(parse_vw_readable_model(no_bits, "model.txt").is_err());
+    }
+
+    #[test]
+    fn test_warm_start_lr() {
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("model.txt");
+        File::create(&filename)
+            .unwrap()
+            .write_all(READABLE_MODEL.as_bytes())
+            .unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 18;
+        mi.vw_initial_regressor = Some(filename.to_str().unwrap().to_string());
+        let mut re = Regressor::new(&mi);
+        re.load_pretrained_weights(&mi).unwrap();
+        let mut pb = re.new_portbuffer();
+        let fbuf = &lr_vec(vec![
+            HashAndValue {
+                hash: 1,
+                value: 1.0,
+                combo_index: 0,
+            },
+            HashAndValue {
+                hash: 2,
+                value: 2.0,
+                combo_index: 0,
+            },
+        ]);
+        assert_eq!(re.predict(fbuf, &mut pb), logistic(1.0));
+
+        mi.bit_precision = 17;
+        let mut re = Regressor::new(&mi);
+        assert!(re.load_pretrained_weights(&mi).is_err());
+    }
+}
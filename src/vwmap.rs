@@ -1,3 +1,4 @@
+use crate::parser;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
@@ -7,6 +8,9 @@ use std::io::Error as IOError;
 use std::io::ErrorKind;
 use std::path::PathBuf;
 
+// Vector features are stored as (index, value) pairs within a record, which can not be longer than 64k u32s
+pub const MAX_NAMESPACE_VECTOR_LEN: u32 = 4096;
+
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Eq)]
 pub enum NamespaceType {
     Primitive = 0,
@@ -17,6 +21,39 @@ pub enum NamespaceType {
 pub enum NamespaceFormat {
     Categorical = 0, // categorical (binary) features encoding (we have the hash and weight of each feature, value of the feature is assumed to be 1.0 (binary))
     F32 = 1, // f32 features encoding (we have the hash and value of each feature, weight is assumed to be 1.0)
+    Passthrough = 2, // categorical encoding, but the feature value is a small integer used directly (plus base) as the feature index - no hashing
+    F32Vec = 3, // fixed length vector of floats given as a single comma separated feature, values are dense inputs at indices 0..len - no hashing
+}
+
+// Scaling of values of f32 namespaces, applied by the parser, so training and serving always agree
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum F32Scaling {
+    Divide(f32),
+    MinMax(f32, f32), // [min, max] is mapped to [0, 1], values outside of it are not clamped
+}
+
+impl F32Scaling {
+    // Parses "div:1000" or "minmax:0:3600"
+    pub fn parse(s: &str) -> Option<F32Scaling> {
+        let vsplit: Vec<&str> = s.split(':').collect();
+        let params: Vec<f32> = vsplit[1..].iter().filter_map(|p| p.parse().ok()).collect();
+        if params.len() != vsplit.len() - 1 || params.iter().any(|p| !p.is_finite()) {
+            return None;
+        }
+        match (vsplit[0], params.as_slice()) {
+            ("div", [divisor]) if *divisor != 0.0 => Some(F32Scaling::Divide(*divisor)),
+            ("minmax", [min, max]) if min < max => Some(F32Scaling::MinMax(*min, *max)),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn apply(&self, value: f32) -> f32 {
+        match self {
+            F32Scaling::Divide(divisor) => value / divisor,
+            F32Scaling::MinMax(min, max) => (value - min) / (max - min),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Copy)]
@@ -36,15 +73,23 @@ pub struct VwNamespaceMap {
 }
 
 // this is serializible source from which VwNamespaceMap can be constructed
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct VwNamespaceMapEntry {
     pub namespace_vwname: std::string::String,
     namespace_verbose: std::string::String,
     namespace_index: u16,
     namespace_format: NamespaceFormat,
+    #[serde(default)]
+    pub namespace_passthrough_base: u32, // only used by passthrough namespaces: offset added to the feature value
+    #[serde(default)]
+    pub namespace_hierarchy_weights: Option<Vec<f32>>, // only used by hierarchical namespaces: weights of ancestor levels, from the root
+    #[serde(default)]
+    pub namespace_vector_len: u32, // only used by f32vec namespaces: number of floats in each example
+    #[serde(default)]
+    pub namespace_f32_scaling: Option<F32Scaling>, // only used by f32 namespaces
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct VwNamespaceMapSource {
     pub namespace_skip_prefix: u32,
     pub entries: Vec<VwNamespaceMapEntry>,
@@ -132,11 +177,55 @@ impl VwNamespaceMap {
             }
 
             let name_str = &record[1];
+            // Vector namespaces are given as f32vec:len
+            let namespace_vector_len = match record.get(2).and_then(|t| t.strip_prefix("f32vec:")) {
+                Some(len_str) => match len_str.parse::<u32>() {
+                    Ok(len) if len > 0 && len <= MAX_NAMESPACE_VECTOR_LEN => len,
+                    _ => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Vector length of the feature {} in vw_namespace_map.csv has to be an integer between 1 and {}, got: \"{}\"", name_str, MAX_NAMESPACE_VECTOR_LEN, len_str))))
+                },
+                None => 0,
+            };
             let namespace_format = match &record.get(2) {
+                _ if namespace_vector_len > 0 => NamespaceFormat::F32Vec,
                 Some("f32") => NamespaceFormat::F32,
+                Some("passthrough") => NamespaceFormat::Passthrough,
+                Some("hierarchical") => NamespaceFormat::Categorical,
                 Some("") => NamespaceFormat::Categorical,
                 None => NamespaceFormat::Categorical,
-                Some(unknown_type) => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown type used for the feature in vw_namespace_map.csv: \"{}\". Only \"f32\" is possible.", unknown_type))))
+                Some(unknown_type) => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown type used for the feature in vw_namespace_map.csv: \"{}\". Only \"f32\", \"f32vec:len\", \"passthrough\" and \"hierarchical\" are possible.", unknown_type))))
+            };
+
+            // Passthrough namespaces can have an optional fourth column: the base added to each value
+            let namespace_passthrough_base = match (namespace_format, record.get(3)) {
+                (NamespaceFormat::Passthrough, Some(base_str)) if !base_str.is_empty() => {
+                    match base_str.parse::<u32>() {
+                        Ok(base) if base <= parser::MASK31 => base,
+                        _ => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Passthrough base for the feature {} in vw_namespace_map.csv has to be an integer between 0 and {}, got: \"{}\"", name_str, parser::MASK31, base_str))))
+                    }
+                }
+                _ => 0,
+            };
+
+            // F32 namespaces can have an optional fourth column: scaling of values, div:x or minmax:min:max
+            let namespace_f32_scaling = match (namespace_format, record.get(3)) {
+                (NamespaceFormat::F32, Some(scaling_str)) if !scaling_str.is_empty() => {
+                    match F32Scaling::parse(scaling_str) {
+                        Some(scaling) => Some(scaling),
+                        None => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Scaling of the feature {} in vw_namespace_map.csv has to be div:divisor or minmax:min:max, got: \"{}\"", name_str, scaling_str))))
+                    }
+                }
+                _ => None,
+            };
+
+            // Hierarchical namespaces are categorical, values like "sports/football" also emit their ancestor
+            // "sports". Optional fourth column has colon separated weights of ancestors, from the root
+            let namespace_hierarchy_weights = if record.get(2) == Some("hierarchical") {
+                Some(parse_hierarchy_weights(
+                    name_str,
+                    record.get(3).unwrap_or(""),
+                )?)
+            } else {
+                None
             };
 
             vw_source.entries.push(VwNamespaceMapEntry {
@@ -144,11 +233,35 @@ impl VwNamespaceMap {
                 namespace_verbose: name_str.to_string(),
                 namespace_index: i as u16,
                 namespace_format,
+                namespace_passthrough_base,
+                namespace_hierarchy_weights,
+                namespace_vector_len,
+                namespace_f32_scaling,
             });
         }
 
         VwNamespaceMap::new_from_source(vw_source)
     }
+
+    // Length of the vectors of an f32vec namespace, 0 for other namespaces
+    pub fn get_namespace_vector_len(&self, namespace_descriptor: &NamespaceDescriptor) -> u32 {
+        self.vw_source
+            .entries
+            .iter()
+            .find(|e| e.namespace_index == namespace_descriptor.namespace_index)
+            .map_or(0, |e| e.namespace_vector_len)
+    }
+}
+
+fn parse_hierarchy_weights(name_str: &str, weights_str: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+    let mut weights: Vec<f32> = Vec::new();
+    for weight_str in weights_str.split(':').filter(|s| !s.is_empty()) {
+        match weight_str.parse::<f32>() {
+            Ok(weight) if weight.is_finite() => weights.push(weight),
+            _ => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Hierarchy weights for the feature {} in vw_namespace_map.csv have to be colon separated numbers, got: \"{}\"", name_str, weights_str))))
+        }
+    }
+    Ok(weights)
 }
 
 #[cfg(test)]
@@ -172,7 +285,11 @@ C,featureC
                 namespace_vwname: "A".to_string(),
                 namespace_verbose: "featureA".to_string(),
                 namespace_index: 0,
-                namespace_format: NamespaceFormat::Categorical
+                namespace_format: NamespaceFormat::Categorical,
+                namespace_passthrough_base: 0,
+                namespace_hierarchy_weights: None,
+                namespace_vector_len: 0,
+                namespace_f32_scaling: None,
             }
         );
 
@@ -182,7 +299,11 @@ C,featureC
                 namespace_vwname: "B".to_string(),
                 namespace_verbose: "featureB".to_string(),
                 namespace_index: 1,
-                namespace_format: NamespaceFormat::Categorical
+                namespace_format: NamespaceFormat::Categorical,
+                namespace_passthrough_base: 0,
+                namespace_hierarchy_weights: None,
+                namespace_vector_len: 0,
+                namespace_f32_scaling: None,
             }
         );
 
@@ -192,7 +313,11 @@ C,featureC
                 namespace_vwname: "C".to_string(),
                 namespace_verbose: "featureC".to_string(),
                 namespace_index: 2,
-                namespace_format: NamespaceFormat::Categorical
+                namespace_format: NamespaceFormat::Categorical,
+                namespace_passthrough_base: 0,
+                namespace_hierarchy_weights: None,
+                namespace_vector_len: 0,
+                namespace_f32_scaling: None,
             }
         );
     }
@@ -208,7 +333,11 @@ C,featureC
                     namespace_vwname: "A".to_string(),
                     namespace_verbose: "featureA".to_string(),
                     namespace_index: 0,
-                    namespace_format: NamespaceFormat::F32
+                    namespace_format: NamespaceFormat::F32,
+                    namespace_passthrough_base: 0,
+                    namespace_hierarchy_weights: None,
+                    namespace_vector_len: 0,
+                    namespace_f32_scaling: None,
                 }
             );
             assert_eq!(vw.vw_source.namespace_skip_prefix, 2);
@@ -217,7 +346,112 @@ C,featureC
             let vw_map_string = "A,featureA,blah\n";
             let result = VwNamespaceMap::new(vw_map_string);
             assert!(result.is_err());
-            assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Unknown type used for the feature in vw_namespace_map.csv: \\\"blah\\\". Only \\\"f32\\\" is possible.\" })");
+            assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Unknown type used for the feature in vw_namespace_map.csv: \\\"blah\\\". Only \\\"f32\\\", \\\"f32vec:len\\\", \\\"passthrough\\\" and \\\"hierarchical\\\" are possible.\" })");
         }
     }
+
+    #[test]
+    fn test_passthrough() {
+        let vw_map_string = "A,featureA,passthrough\nB,featureB,passthrough,1000\nC,featureC\n";
+        let vw = VwNamespaceMap::new(vw_map_string).unwrap();
+        assert_eq!(
+            vw.vw_source.entries[0],
+            VwNamespaceMapEntry {
+                namespace_vwname: "A".to_string(),
+                namespace_verbose: "featureA".to_string(),
+                namespace_index: 0,
+                namespace_format: NamespaceFormat::Passthrough,
+                namespace_passthrough_base: 0,
+                namespace_hierarchy_weights: None,
+                namespace_vector_len: 0,
+                namespace_f32_scaling: None,
+            }
+        );
+        assert_eq!(
+            vw.vw_source.entries[1],
+            VwNamespaceMapEntry {
+                namespace_vwname: "B".to_string(),
+                namespace_verbose: "featureB".to_string(),
+                namespace_index: 1,
+                namespace_format: NamespaceFormat::Passthrough,
+                namespace_passthrough_base: 1000,
+                namespace_hierarchy_weights: None,
+                namespace_vector_len: 0,
+                namespace_f32_scaling: None,
+            }
+        );
+        assert_eq!(vw.vw_source.entries[2].namespace_passthrough_base, 0);
+
+        let result = VwNamespaceMap::new("A,featureA,passthrough,-5\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_hierarchical() {
+        let vw_map_string =
+            "A,featureA,hierarchical,0.25:0.5\nB,featureB,hierarchical\nC,featureC\n";
+        let vw = VwNamespaceMap::new(vw_map_string).unwrap();
+        assert_eq!(
+            vw.vw_source.entries[0].namespace_hierarchy_weights,
+            Some(vec![0.25, 0.5])
+        );
+        assert_eq!(
+            vw.vw_source.entries[0].namespace_format,
+            NamespaceFormat::Categorical
+        );
+        assert_eq!(
+            vw.vw_source.entries[1].namespace_hierarchy_weights,
+            Some(vec![])
+        );
+        assert_eq!(vw.vw_source.entries[2].namespace_hierarchy_weights, None);
+
+        let result = VwNamespaceMap::new("A,featureA,hierarchical,0.25:x\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_f32_scaling() {
+        let vw = VwNamespaceMap::new(
+            "A,featureA,f32,div:1000\nB,featureB,f32,minmax:10:20\nC,featureC,f32\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vw.vw_source.entries[0].namespace_f32_scaling,
+            Some(F32Scaling::Divide(1000.0))
+        );
+        assert_eq!(
+            vw.vw_source.entries[1].namespace_f32_scaling,
+            Some(F32Scaling::MinMax(10.0, 20.0))
+        );
+        assert_eq!(vw.vw_source.entries[2].namespace_f32_scaling, None);
+        assert_eq!(F32Scaling::Divide(1000.0).apply(1500.0), 1.5);
+        assert_eq!(F32Scaling::MinMax(10.0, 20.0).apply(15.0), 0.5);
+        assert_eq!(F32Scaling::MinMax(10.0, 20.0).apply(30.0), 2.0);
+
+        for scaling in ["div:0", "div", "div:x", "minmax:5:5", "minmax:1", "log:2"].iter() {
+            assert!(VwNamespaceMap::new(&format!("A,featureA,f32,{}\n", scaling)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_f32vec() {
+        let vw = VwNamespaceMap::new("A,featureA,f32vec:3\nB,featureB\n").unwrap();
+        assert_eq!(
+            vw.vw_source.entries[0].namespace_format,
+            NamespaceFormat::F32Vec
+        );
+        assert_eq!(vw.vw_source.entries[0].namespace_vector_len, 3);
+        assert_eq!(
+            vw.get_namespace_vector_len(&vw.map_verbose_to_namespace_descriptor["featureA"]),
+            3
+        );
+        assert_eq!(
+            vw.get_namespace_vector_len(&vw.map_verbose_to_namespace_descriptor["featureB"]),
+            0
+        );
+
+        assert!(VwNamespaceMap::new("A,featureA,f32vec:0\n").is_err());
+        assert!(VwNamespaceMap::new("A,featureA,f32vec:\n").is_err());
+        assert!(VwNamespaceMap::new("A,featureA,f32vec:100000\n").is_err());
+    }
 }
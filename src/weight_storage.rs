@@ -0,0 +1,330 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+
+use crate::prediction_log;
+
+// Weights of FFM and neural blocks. Normally they are owned, but weights of forward-only regressors
+// can be moved into a file that is mapped into memory (--mmap_weights), so that all processes
+// serving the same model share one physical copy of them through the page cache.
+// The mapping is private, mapped weights are never written: a reload (hogwild_load) maps a file
+// with the new weights in place of the old one.
+// Processes hold a shared lock on the files they map, files that nobody holds are removed.
+pub struct WeightStorage {
+    // Always points to the weights, so access costs the same as that of a Vec
+    ptr: *mut f32,
+    len: usize,
+    owned: Vec<f32>,
+    mapped: Option<MappedFile>,
+}
+
+struct MappedFile {
+    dir: PathBuf,
+    _locked: fs::File, // closing it releases the lock
+}
+
+// The weights are shared between threads the same way as Vec<f32> of the blocks were
+unsafe impl Send for WeightStorage {}
+unsafe impl Sync for WeightStorage {}
+
+impl WeightStorage {
+    pub fn new() -> WeightStorage {
+        WeightStorage::from(Vec::new())
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.mapped.is_some()
+    }
+
+    // Weights are written to a file in directory dir named by their hash, unless another process
+    // has already written it, and the file is mapped in place of them
+    pub fn map_through_file(&mut self, dir: &str) -> Result<(), Box<dyn Error>> {
+        if self.mapped.is_some() || self.len == 0 {
+            return Ok(());
+        }
+        let bytes = unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len * 4) };
+        let (ptr, mapped) = map_weights_file(Path::new(dir), bytes)?;
+        self.ptr = ptr;
+        self.mapped = Some(mapped);
+        self.owned = Vec::new();
+        remove_unreferenced_files(Path::new(dir));
+        Ok(())
+    }
+
+    // read() fills the weights. Mapped weights are read into memory instead, and a file with them
+    // is mapped at the address of the old one, so readers never see unmapped memory
+    pub fn overwrite(
+        &mut self,
+        read: impl FnOnce(&mut [f32]) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let dir = match &self.mapped {
+            Some(mapped) => mapped.dir.clone(),
+            None => return read(self),
+        };
+        let mut weights = vec![0.0; self.len];
+        read(&mut weights)?;
+        let bytes = unsafe { slice::from_raw_parts(weights.as_ptr() as *const u8, self.len * 4) };
+        let (ptr, mapped) = map_weights_file(&dir, bytes)?;
+        let moved = unsafe {
+            libc::mremap(
+                ptr as *mut libc::c_void,
+                self.len * 4,
+                self.len * 4,
+                libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+                self.ptr as *mut libc::c_void,
+            )
+        };
+        if moved == libc::MAP_FAILED {
+            let error = io::Error::last_os_error();
+            unsafe {
+                libc::munmap(ptr as *mut libc::c_void, self.len * 4);
+            }
+            return Err(error)?;
+        }
+        self.mapped = Some(mapped);
+        remove_unreferenced_files(&dir);
+        Ok(())
+    }
+}
+
+fn weights_filename(dir: &Path, bytes: &[u8]) -> PathBuf {
+    dir.join(format!(
+        "{:016x}-{}.weights",
+        prediction_log::model_version_of_bytes(bytes),
+        bytes.len() / 4
+    ))
+}
+
+// Written under a process specific temporary name and renamed, so that concurrently starting
+// processes never map a half-written file
+fn write_weights_file(filename: &Path, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp_filename = filename.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp_filename, bytes)?;
+    fs::rename(&tmp_filename, filename)?;
+    Ok(())
+}
+
+fn lock(file: &fs::File, operation: libc::c_int) -> bool {
+    unsafe { libc::flock(file.as_raw_fd(), operation) == 0 }
+}
+
+fn map_file(filename: &Path, len: usize) -> Result<(*mut f32, fs::File), Box<dyn Error>> {
+    let file = fs::File::open(filename)?;
+    if !lock(&file, libc::LOCK_SH) {
+        return Err(io::Error::last_os_error())?;
+    }
+    if file.metadata()?.len() != len as u64 {
+        return Err(format!(
+            "Mapped weights file {} has a different size than the weights",
+            filename.display()
+        ))?;
+    }
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error())?;
+    }
+    Ok((ptr as *mut f32, file))
+}
+
+// Maps the file of dir with contents bytes, written first when no process has written it yet
+fn map_weights_file(dir: &Path, bytes: &[u8]) -> Result<(*mut f32, MappedFile), Box<dyn Error>> {
+    let filename = weights_filename(dir, bytes);
+    if !filename.exists() {
+        write_weights_file(&filename, bytes)?;
+    }
+    // Another process may have just removed it as unreferenced
+    let (ptr, file) = match map_file(&filename, bytes.len()) {
+        Ok(mapped) => mapped,
+        Err(_) => {
+            write_weights_file(&filename, bytes)?;
+            map_file(&filename, bytes.len())?
+        }
+    };
+    // The name is just a hash, the contents have to be the weights
+    let (ptr, file) = if unsafe { slice::from_raw_parts(ptr as *const u8, bytes.len()) } == bytes {
+        (ptr, file)
+    } else {
+        log::warn!(
+            "Mapped weights file {} has different weights, writing it again",
+            filename.display()
+        );
+        unsafe {
+            libc::munmap(ptr as *mut libc::c_void, bytes.len());
+        }
+        drop(file);
+        write_weights_file(&filename, bytes)?;
+        let (ptr, file) = map_file(&filename, bytes.len())?;
+        if unsafe { slice::from_raw_parts(ptr as *const u8, bytes.len()) } != bytes {
+            unsafe {
+                libc::munmap(ptr as *mut libc::c_void, bytes.len());
+            }
+            return Err(format!(
+                "Mapped weights file {} keeps changing under us",
+                filename.display()
+            ))?;
+        }
+        (ptr, file)
+    };
+    log::info!(
+        "Mapped {} weights from {}",
+        bytes.len() / 4,
+        filename.display()
+    );
+    Ok((
+        ptr,
+        MappedFile {
+            dir: dir.to_path_buf(),
+            _locked: file,
+        },
+    ))
+}
+
+// Weights files of dir that no process holds a lock on are not mapped anywhere
+fn remove_unreferenced_files(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Couldn't list mapped weights in {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let filename = entry.path();
+        if filename.extension() != Some(OsStr::new("weights")) {
+            continue;
+        }
+        if let Ok(file) = fs::File::open(&filename) {
+            if lock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+                match fs::remove_file(&filename) {
+                    Ok(()) => log::info!("Removed unreferenced {}", filename.display()),
+                    Err(e) => log::warn!("Couldn't remove {}: {}", filename.display(), e),
+                }
+            }
+        }
+    }
+}
+
+impl Default for WeightStorage {
+    fn default() -> Self {
+        WeightStorage::new()
+    }
+}
+
+impl From<Vec<f32>> for WeightStorage {
+    fn from(mut owned: Vec<f32>) -> Self {
+        // Moving the Vec doesn't move its buffer, so the pointer stays valid
+        WeightStorage {
+            ptr: owned.as_mut_ptr(),
+            len: owned.len(),
+            owned,
+            mapped: None,
+        }
+    }
+}
+
+impl Deref for WeightStorage {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for WeightStorage {
+    fn deref_mut(&mut self) -> &mut [f32] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for WeightStorage {
+    fn drop(&mut self) {
+        if let Some(mapped) = self.mapped.take() {
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len * 4);
+            }
+            let dir = mapped.dir.clone();
+            drop(mapped);
+            remove_unreferenced_files(&dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_map_through_file() {
+        let dir = tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        let mut weights = WeightStorage::from(vec![1.0, 2.0, 3.0]);
+        assert!(!weights.is_mapped());
+        weights.map_through_file(dir).unwrap();
+        assert!(weights.is_mapped());
+        assert_eq!(&weights[..], &[1.0, 2.0, 3.0]);
+
+        // Another process with the same weights maps the same file
+        let mut weights2 = WeightStorage::from(vec![1.0, 2.0, 3.0]);
+        weights2.map_through_file(dir).unwrap();
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+
+        // Writes stay private to the process that made them
+        weights2[1] = 5.0;
+        assert_eq!(&weights2[..], &[1.0, 5.0, 3.0]);
+        assert_eq!(&weights[..], &[1.0, 2.0, 3.0]);
+
+        // Reloads map a file with the new weights at the same address, the old file stays for
+        // as long as it is mapped
+        let address = weights.as_ptr();
+        weights
+            .overwrite(|w| {
+                w.copy_from_slice(&[4.0, 5.0, 6.0]);
+                Ok(())
+            })
+            .unwrap();
+        assert!(weights.is_mapped());
+        assert_eq!(weights.as_ptr(), address);
+        assert_eq!(&weights[..], &[4.0, 5.0, 6.0]);
+        assert_eq!(&weights2[..], &[1.0, 5.0, 3.0]);
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 2);
+        assert!(weights.overwrite(|_| Err("no weights")?).is_err());
+        assert_eq!(&weights[..], &[4.0, 5.0, 6.0]);
+        drop(weights2);
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+
+        // A file with other contents under the name of the weights is written again, files
+        // nobody maps are removed
+        let mut weights3 = WeightStorage::from(vec![7.0, 8.0, 9.0]);
+        let bytes = unsafe { slice::from_raw_parts(weights3.as_ptr() as *const u8, 12) };
+        fs::write(weights_filename(Path::new(dir), bytes), [0u8; 12]).unwrap();
+        fs::write(Path::new(dir).join("0-3.weights"), [0u8; 12]).unwrap();
+        weights3.map_through_file(dir).unwrap();
+        assert_eq!(&weights3[..], &[7.0, 8.0, 9.0]);
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 2);
+        drop(weights);
+        drop(weights3);
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+
+        let mut empty = WeightStorage::new();
+        empty.map_through_file(dir).unwrap();
+        assert!(!empty.is_mapped());
+        assert_eq!(empty.len(), 0);
+    }
+}